@@ -0,0 +1,558 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::Duration;
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+use crate::client::Client;
+use crate::WritableEvent;
+
+///
+/// Where `--docker` looks for containers' json-file logs, unless it's told otherwise
+///
+pub const DOCKER_CONTAINERS_DIRECTORY: &str = "/var/lib/docker/containers";
+
+///
+/// How much of a Docker log file we read in one go: a busy container gets caught up over a few batches, not all at once
+///
+const MAX_READ_BYTES: u64 = 4 * 1024 * 1024;
+
+///
+/// journalctl lines waiting to be sent: if the server's down for long enough, journalctl waits (the journal keeps them, not us)
+///
+const MAX_JOURNAL_BACKLOG: usize = 10_000;
+
+///
+/// A Docker log line longer than 16KiB gets split into pieces (every one but the last without its newline):
+/// we put them back together, up to here
+///
+const MAX_LINE_BYTES: usize = 1024 * 1024;
+
+///
+/// journald's fields that go on the front of the line as key=value
+///
+const JOURNAL_FIELDS: [(&str, &str); 3] = [("_SYSTEMD_UNIT", "unit"), ("SYSLOG_IDENTIFIER", "ident"), ("_PID", "pid")];
+
+///
+/// What `logmunch agent` tails, and where it keeps track of how far it's got
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentSettings{
+    pub journald: bool,
+    /// journal files to read instead of the system journal (journalctl -D)
+    pub journal_directory: Option<String>,
+    /// the containers directory, for Docker's json-file logs
+    pub docker: Option<String>,
+    pub checkpoint: String,
+    /// the host for Docker logs (journald's entries know their own)
+    pub host: String,
+    /// on the very first run (no checkpoint yet), send everything that's already there, instead of starting from now
+    pub backfill: bool,
+    pub batch_size: usize,
+    /// how long to wait when there's nothing new
+    pub poll: Duration,
+}
+
+impl Default for AgentSettings{
+    fn default() -> Self {
+        AgentSettings{
+            journald: false,
+            journal_directory: None,
+            docker: None,
+            checkpoint: "logmunch-agent.json".to_string(),
+            host: local_hostname(),
+            backfill: false,
+            batch_size: 1000,
+            poll: Duration::from_millis(500),
+        }
+    }
+}
+
+fn local_hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname").ok()
+        .or(std::env::var("HOSTNAME").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or("unknown".to_string())
+}
+
+///
+/// How far into a file we've sent. The inode is how we notice Docker's rotated it out from under us.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilePosition{
+    pub inode: u64,
+    pub offset: u64,
+}
+
+///
+/// Everything the server has said yes to, so a restarted agent picks up where it left off:
+/// it only moves forward once a batch is in, so a crash means sending a batch twice, never losing one
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint{
+    #[serde(default)]
+    pub journald_cursor: Option<String>,
+    /// Docker log file path -> position
+    #[serde(default)]
+    pub docker: BTreeMap<String, FilePosition>,
+}
+
+impl Checkpoint{
+    ///
+    /// None if there isn't one yet
+    ///
+    pub fn load(path: &str) -> Result<Option<Checkpoint>> {
+        match std::fs::read_to_string(path){
+            Ok(json) => Ok(Some(serde_json::from_str(&json).map_err(|e| anyhow::anyhow!("{} isn't an agent checkpoint: {}", path, e))?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(anyhow::anyhow!("Can't read {}: {}", path, e)),
+        }
+    }
+
+    ///
+    /// Written next to the old one and renamed over it, so there's always a whole checkpoint there
+    ///
+    pub fn save(&self, path: &str) -> Result<()> {
+        let temporary = format!("{}.tmp", path);
+        let mut file = std::fs::File::create(&temporary).map_err(|e| anyhow::anyhow!("Can't write {}: {}", temporary, e))?;
+        std::io::Write::write_all(&mut file, serde_json::to_string(self)?.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+///
+/// One line of `journalctl -o json` as an event, and its cursor. MESSAGE comes as a string, or as an array of bytes
+/// when it isn't valid UTF-8; entries without one still have a cursor, so they come back as None.
+/// The unit, syslog identifier, pid and PRIORITY (as `level=`) go on the front as key=value.
+///
+pub fn journal_event(line: &str) -> Result<(Option<WritableEvent>, String)> {
+    let entry: serde_json::Map<String, serde_json::Value> = serde_json::from_str(line)?;
+    let field = |name: &str| entry.get(name).and_then(|value| value.as_str());
+    let cursor = field("__CURSOR").ok_or_else(|| anyhow::anyhow!("A journal entry without a __CURSOR"))?.to_string();
+    let message = match entry.get("MESSAGE"){
+        Some(serde_json::Value::String(message)) => message.clone(),
+        Some(serde_json::Value::Array(bytes)) => String::from_utf8_lossy(&bytes.iter().filter_map(|byte| byte.as_u64()).map(|byte| byte as u8).collect::<Vec<u8>>()).into_owned(),
+        _ => return Ok((None, cursor)),
+    };
+    let time = field("__REALTIME_TIMESTAMP").and_then(|micros| micros.parse::<i64>().ok())
+        .ok_or_else(|| anyhow::anyhow!("A journal entry without a __REALTIME_TIMESTAMP"))?;
+
+    let mut fields = BTreeMap::new();
+    for (name, key) in JOURNAL_FIELDS {
+        if let Some(value) = field(name) {
+            fields.insert(key.to_string(), value.to_string());
+        }
+    }
+    if let Some(level) = field("PRIORITY").and_then(|priority| priority.parse().ok()).and_then(crate::gelf::level_name) {
+        fields.insert("level".to_string(), level.to_string());
+    }
+    fields.insert("host".to_string(), field("_HOSTNAME").unwrap_or("unknown").to_string());
+    let (host, prefix) = crate::loki::flatten(&fields);
+    let message = message.trim_end();
+    Ok((Some(WritableEvent{
+        event: if prefix.is_empty() { message.to_string() } else { format!("{} {}", prefix, message) },
+        time,
+        host,
+    }), cursor))
+}
+
+///
+/// `journalctl -o json -f`, from just after the checkpoint's cursor (or from now, or from the start, with --backfill),
+/// its lines handed over on a channel by a thread of its own
+///
+struct Journal{
+    child: Child,
+    lines: Receiver<String>,
+}
+
+impl Journal{
+    fn follow(settings: &AgentSettings, cursor: Option<&str>, first_run: bool) -> Result<Journal> {
+        let mut command = Command::new("journalctl");
+        command.args(["-o", "json", "-f"]);
+        if let Some(directory) = &settings.journal_directory {
+            command.args(["-D", directory]);
+        }
+        match cursor{
+            Some(cursor) => { command.args(["--after-cursor", cursor]); },
+            None if first_run && settings.backfill => { command.arg("--no-tail"); },
+            None => { command.args(["-n", "0"]); },
+        }
+        let mut child = command.stdout(Stdio::piped()).spawn().map_err(|e| anyhow::anyhow!("Can't run journalctl: {}", e))?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow::anyhow!("journalctl without a stdout"))?;
+        let (sender, lines) = std::sync::mpsc::sync_channel(MAX_JOURNAL_BACKLOG);
+        std::thread::Builder::new().name("agent-journald".to_string()).spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(|line| line.ok()) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        })?;
+        Ok(Journal{ child, lines })
+    }
+
+    ///
+    /// Up to `limit` events, and the cursor of the last entry taken (even one that wasn't an event)
+    ///
+    fn take(&mut self, limit: usize) -> Result<(Vec<WritableEvent>, Option<String>)> {
+        let mut events = Vec::new();
+        let mut cursor = None;
+        while events.len() < limit {
+            let line = match self.lines.try_recv(){
+                Ok(line) => line,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Err(anyhow::anyhow!("journalctl stopped ({})", self.child.wait()?)),
+            };
+            match journal_event(&line){
+                Ok((event, entry_cursor)) => {
+                    events.extend(event);
+                    cursor = Some(entry_cursor);
+                },
+                Err(e) => tracing::warn!("Skipping a journal entry we couldn't read: {}", e),
+            }
+        }
+        Ok((events, cursor))
+    }
+}
+
+impl Drop for Journal{
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+///
+/// One line of Docker's json-file log: `{"log":"hello\n","stream":"stdout","time":"2024-03-16T04:21:27.123456789Z"}`
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct DockerLine{
+    pub log: String,
+    #[serde(default)]
+    pub stream: String,
+    pub time: String,
+}
+
+impl DockerLine{
+    ///
+    /// The last piece of a line: Docker splits long ones, and only the last piece ends with a newline
+    ///
+    pub fn complete(&self) -> bool {
+        self.log.ends_with('\n')
+    }
+
+    ///
+    /// `container=web stream=stderr <the line>`
+    ///
+    pub fn to_writable_event(&self, log: &str, container: &str, host: &str) -> Result<WritableEvent> {
+        let time = chrono::DateTime::parse_from_rfc3339(&self.time)
+            .map_err(|_| anyhow::anyhow!("'{}' isn't an RFC 3339 timestamp", self.time))?
+            .timestamp_micros();
+        let mut fields = BTreeMap::from([("container".to_string(), container.to_string()), ("host".to_string(), host.to_string())]);
+        if !self.stream.is_empty() {
+            fields.insert("stream".to_string(), self.stream.clone());
+        }
+        let (host, prefix) = crate::loki::flatten(&fields);
+        Ok(WritableEvent{
+            event: format!("{} {}", prefix, log.trim_end_matches(['\n', '\r'])),
+            time,
+            host,
+        })
+    }
+}
+
+///
+/// The container's name, out of the config.v2.json next to its log (without the leading slash), or the short id if that's not there
+///
+fn container_name(container_directory: &std::path::Path) -> String {
+    let id = container_directory.file_name().map(|id| id.to_string_lossy().into_owned()).unwrap_or_default();
+    std::fs::read_to_string(container_directory.join("config.v2.json")).ok()
+        .and_then(|config| serde_json::from_str::<serde_json::Value>(&config).ok())
+        .and_then(|config| config.get("Name").and_then(|name| name.as_str()).map(|name| name.trim_start_matches('/').to_string()))
+        .filter(|name| !name.is_empty())
+        .unwrap_or(id.chars().take(12).collect())
+}
+
+///
+/// One container's `<id>-json.log`. `read` keeps going from where the last one stopped; `committed` is where a restart
+/// should start again, which is never in the middle of a split line.
+///
+#[derive(Debug)]
+struct DockerTail{
+    path: String,
+    container: String,
+    position: FilePosition,
+    committed: FilePosition,
+    partial: String,
+}
+
+impl DockerTail{
+    fn new(path: &str, container: String, position: FilePosition) -> DockerTail {
+        DockerTail{ path: path.to_string(), container, position, committed: position, partial: String::new() }
+    }
+
+    ///
+    /// If the file we were reading has been rotated (it's the `.1` now), the rest of that comes first: then the new one, from the top.
+    /// A file that got shorter was truncated, so that starts from the top too.
+    ///
+    fn take(&mut self, host: &str, limit: usize) -> Result<Vec<WritableEvent>> {
+        let current = match std::fs::metadata(&self.path){
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        if current.ino() == self.position.inode {
+            if current.len() < self.position.offset {
+                self.restart(current.ino());
+            }
+            return self.read(&self.path.clone(), host, limit);
+        }
+
+        let rotated = format!("{}.1", self.path);
+        let mut events = Vec::new();
+        if self.position.inode != 0 && std::fs::metadata(&rotated).is_ok_and(|metadata| metadata.ino() == self.position.inode) {
+            events = self.read(&rotated, host, limit)?;
+            let finished = std::fs::metadata(&rotated).is_ok_and(|metadata| metadata.len() <= self.position.offset);
+            if !finished || events.len() >= limit {
+                return Ok(events);
+            }
+        }
+        self.restart(current.ino());
+        events.extend(self.read(&self.path.clone(), host, limit - events.len())?);
+        Ok(events)
+    }
+
+    fn restart(&mut self, inode: u64) {
+        self.position = FilePosition{ inode, offset: 0 };
+        self.committed = self.position;
+        self.partial.clear();
+    }
+
+    fn read(&mut self, path: &str, host: &str, limit: usize) -> Result<Vec<WritableEvent>> {
+        let mut file = std::fs::File::open(path)?;
+        file.seek(SeekFrom::Start(self.position.offset))?;
+        let mut reader = BufReader::new(file.take(MAX_READ_BYTES));
+        let mut events = Vec::new();
+        let mut line = String::new();
+        while events.len() < limit {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            // half a line: Docker's still writing it, so it's left for next time
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            self.position.offset += read as u64;
+            let docker_line: DockerLine = match serde_json::from_str(&line){
+                Ok(docker_line) => docker_line,
+                Err(e) => {
+                    tracing::warn!("Skipping a line of {} we couldn't read: {}", path, e);
+                    continue;
+                }
+            };
+            if self.partial.len() < MAX_LINE_BYTES {
+                self.partial.push_str(&docker_line.log);
+            }
+            if !docker_line.complete() {
+                continue;
+            }
+            let log = std::mem::take(&mut self.partial);
+            match docker_line.to_writable_event(&log, &self.container, host){
+                Ok(event) => events.push(event),
+                Err(e) => tracing::warn!("Skipping a line of {}: {}", path, e),
+            }
+            self.committed = self.position;
+        }
+        if self.partial.is_empty() {
+            self.committed = self.position;
+        }
+        Ok(events)
+    }
+}
+
+///
+/// Every `<containers>/<id>/<id>-json.log` there is
+///
+fn docker_logs(containers_directory: &str) -> Result<Vec<(String, String)>> {
+    let mut logs = Vec::new();
+    for entry in std::fs::read_dir(containers_directory).map_err(|e| anyhow::anyhow!("Can't read {}: {}", containers_directory, e))? {
+        let directory = entry?.path();
+        let Some(id) = directory.file_name().map(|id| id.to_string_lossy().into_owned()) else { continue };
+        let path = directory.join(format!("{}-json.log", id));
+        if path.is_file() {
+            logs.push((path.to_string_lossy().into_owned(), container_name(&directory)));
+        }
+    }
+    logs.sort();
+    Ok(logs)
+}
+
+///
+/// The Docker side of the agent: a DockerTail for every container, picking up new ones as they start and
+/// forgetting removed ones
+///
+struct Docker{
+    directory: String,
+    tails: BTreeMap<String, DockerTail>,
+}
+
+impl Docker{
+    ///
+    /// Containers in the checkpoint carry on from there. The rest start at the end of what they've already written
+    /// the first time the agent runs (unless it's backfilling), and from the top after that: they're new.
+    ///
+    fn new(directory: &str, checkpoint: &Checkpoint, first_run: bool, backfill: bool) -> Result<Docker> {
+        let mut docker = Docker{ directory: directory.to_string(), tails: BTreeMap::new() };
+        for (path, container) in docker_logs(directory)? {
+            let position = match checkpoint.docker.get(&path){
+                Some(position) => *position,
+                None if first_run && !backfill => std::fs::metadata(&path).map(|metadata| FilePosition{ inode: metadata.ino(), offset: metadata.len() })?,
+                None => FilePosition::default(),
+            };
+            docker.tails.insert(path.clone(), DockerTail::new(&path, container, position));
+        }
+        Ok(docker)
+    }
+
+    fn rescan(&mut self) -> Result<()> {
+        let logs = docker_logs(&self.directory)?;
+        self.tails.retain(|path, _| logs.iter().any(|(log, _)| log == path));
+        for (path, container) in logs {
+            self.tails.entry(path.clone()).or_insert_with(|| DockerTail::new(&path, container, FilePosition::default()));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, host: &str, limit: usize) -> Result<Vec<WritableEvent>> {
+        let mut events = Vec::new();
+        for tail in self.tails.values_mut() {
+            if events.len() >= limit {
+                break;
+            }
+            events.extend(tail.take(host, limit - events.len())?);
+        }
+        Ok(events)
+    }
+
+    fn positions(&self) -> BTreeMap<String, FilePosition> {
+        self.tails.iter().map(|(path, tail)| (path.clone(), tail.committed)).collect()
+    }
+}
+
+///
+/// Tail journald and/or Docker and send it all to `client`, forever: a batch at a time, checkpointing after every one the server takes.
+/// A batch the server won't take (after the client's own retries) gets tried again until it does: nothing's skipped.
+///
+pub fn run(client: &Client, settings: &AgentSettings) -> Result<()> {
+    if !settings.journald && settings.docker.is_none() {
+        return Err(anyhow::anyhow!("Nothing to tail: pass --journald, --docker, or both"));
+    }
+    let loaded = Checkpoint::load(&settings.checkpoint)?;
+    let first_run = loaded.is_none();
+    let mut checkpoint = loaded.unwrap_or_default();
+    let mut journal = match settings.journald{
+        true => Some(Journal::follow(settings, checkpoint.journald_cursor.as_deref(), first_run)?),
+        false => None,
+    };
+    let mut docker = settings.docker.as_deref().map(|directory| Docker::new(directory, &checkpoint, first_run, settings.backfill)).transpose()?;
+    let sources: Vec<String> = [settings.journald.then(|| "journald".to_string()), settings.docker.as_ref().map(|directory| format!("docker ({})", directory))]
+        .into_iter().flatten().collect();
+    tracing::info!("agent: tailing {}, checkpointing in {}", sources.join(" and "), settings.checkpoint);
+
+    // the first run saves a checkpoint straight away, even an empty one: the next run isn't the first
+    let mut saved = if first_run { None } else { Some(checkpoint.clone()) };
+    let mut polls: u64 = 0;
+    loop{
+        let mut events = Vec::new();
+        if let Some(journal) = journal.as_mut() {
+            let (journal_events, cursor) = journal.take(settings.batch_size)?;
+            events.extend(journal_events);
+            if cursor.is_some() {
+                checkpoint.journald_cursor = cursor;
+            }
+        }
+        if let Some(docker) = docker.as_mut() {
+            // new containers get noticed every few seconds, not on every poll
+            if polls.is_multiple_of(10) {
+                docker.rescan()?;
+            }
+            events.extend(docker.take(&settings.host, settings.batch_size.saturating_sub(events.len()))?);
+            checkpoint.docker = docker.positions();
+        }
+        polls += 1;
+
+        while let Err(e) = client.ingest(&events) {
+            tracing::warn!("agent: {} events didn't go in ({}): trying again", events.len(), e);
+            std::thread::sleep(settings.poll.max(Duration::from_secs(5)));
+        }
+        if saved.as_ref() != Some(&checkpoint) {
+            checkpoint.save(&settings.checkpoint)?;
+            saved = Some(checkpoint.clone());
+        }
+        if events.len() < settings.batch_size {
+            std::thread::sleep(settings.poll);
+        }
+    }
+}
+
+#[test]
+fn test_journal_event() -> Result<()> {
+    let (event, cursor) = journal_event(r#"{"__CURSOR":"s=1;i=2","__REALTIME_TIMESTAMP":"1710562887123456","_HOSTNAME":"web-1","_SYSTEMD_UNIT":"nginx.service","SYSLOG_IDENTIFIER":"nginx","_PID":"812","PRIORITY":"3","MESSAGE":"upstream timed out\n"}"#)?;
+    assert_eq!(cursor, "s=1;i=2");
+    assert_eq!(event, Some(WritableEvent::new("ident=nginx level=error pid=812 unit=nginx.service upstream timed out", 1710562887123456, "web-1")));
+
+    // not UTF-8, so journald sends the bytes
+    let (event, _) = journal_event(r#"{"__CURSOR":"s=1;i=3","__REALTIME_TIMESTAMP":"1710562887000000","_HOSTNAME":"web-1","MESSAGE":[104,105,255]}"#)?;
+    assert_eq!(event.map(|event| event.event), Some("hi\u{fffd}".to_string()));
+    let (event, cursor) = journal_event(r#"{"__CURSOR":"s=1;i=4","__REALTIME_TIMESTAMP":"1710562887000000"}"#)?;
+    assert_eq!((event, cursor.as_str()), (None, "s=1;i=4"));
+    assert!(journal_event(r#"{"MESSAGE":"no cursor"}"#).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_docker_tail() -> Result<()> {
+    use std::io::Write;
+    let directory = crate::minute::test_data_directory("agent_docker");
+    let container = format!("{}/abcdef1234567890", directory);
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&container)?;
+    std::fs::write(format!("{}/config.v2.json", container), r#"{"Name":"/web"}"#)?;
+    let path = format!("{}/abcdef1234567890-json.log", container);
+    let append = |lines: &str| -> Result<()> { Ok(std::fs::File::options().create(true).append(true).open(&path)?.write_all(lines.as_bytes())?) };
+    append("{\"log\":\"before\\n\",\"stream\":\"stdout\",\"time\":\"2024-03-16T04:21:27.000000001Z\"}\n")?;
+
+    // the first run starts at the end of what's there
+    let mut docker = Docker::new(&directory, &Checkpoint::default(), true, false)?;
+    assert_eq!(docker.take("box", 100)?, vec![]);
+    append("{\"log\":\"hello\\n\",\"stream\":\"stderr\",\"time\":\"2024-03-16T04:21:27.5Z\"}\n{\"log\":\"split \",\"stream\":\"stdout\",\"time\":\"2024-03-16T04:21:28Z\"}\n")?;
+    assert_eq!(docker.take("box", 100)?, vec![WritableEvent::new("container=web stream=stderr hello", 1710562887500000, "box")]);
+    // the checkpoint stops before the first half of the split line, so a restart reads it again
+    let checkpoint = Checkpoint{ journald_cursor: None, docker: docker.positions() };
+    append("{\"log\":\"line\\n\",\"stream\":\"stdout\",\"time\":\"2024-03-16T04:21:28Z\"}\n{\"log\":\"half")?;
+    let expected = vec![WritableEvent::new("container=web stream=stdout split line", 1710562888000000, "box")];
+    assert_eq!(docker.take("box", 100)?, expected);
+    assert_eq!(Docker::new(&directory, &checkpoint, false, false)?.take("box", 100)?, expected);
+
+    // rotated: the rest of the old file, then the new one
+    append(" done\\n\",\"stream\":\"stdout\",\"time\":\"2024-03-16T04:21:29Z\"}\n")?;
+    std::fs::rename(&path, format!("{}.1", path))?;
+    append("{\"log\":\"new file\\n\",\"stream\":\"stdout\",\"time\":\"2024-03-16T04:21:30Z\"}\n")?;
+    let events: Vec<String> = docker.take("box", 100)?.into_iter().map(|event| event.event).collect();
+    assert_eq!(events, vec!["container=web stream=stdout half done", "container=web stream=stdout new file"]);
+
+    let checkpoint_path = format!("{}/checkpoint.json", directory);
+    assert_eq!(Checkpoint::load(&checkpoint_path)?, None);
+    let checkpoint = Checkpoint{ journald_cursor: Some("s=1;i=4".to_string()), docker: docker.positions() };
+    checkpoint.save(&checkpoint_path)?;
+    assert_eq!(Checkpoint::load(&checkpoint_path)?, Some(checkpoint));
+    // a container that's been removed is forgotten
+    std::fs::remove_dir_all(&container)?;
+    docker.rescan()?;
+    assert!(docker.positions().is_empty());
+    Ok(())
+}
@@ -0,0 +1,434 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+use crate::host_rules::HostRules;
+use crate::minute_db::{MinuteDB, SearchOptions, StatsBy, ValueCount};
+use crate::replication::Standby;
+use crate::tenant::Tenants;
+
+///
+/// How often the scheduler looks for alerts that are due
+///
+const TICK_SECONDS: u64 = 10;
+
+///
+/// How many of the noisiest hosts go in a notification
+///
+const TOP_HOSTS: usize = 5;
+
+const WEBHOOK_TIMEOUT_SECONDS: u64 = 10;
+
+fn default_every_minutes() -> u64 {
+    5
+}
+
+///
+/// What the webhook gets: a Slack message (`{"text": ...}`, which Mattermost, Discord's /slack endpoint and friends take too),
+/// or a PagerDuty Events API v2 event (with the alert's routing_key), which gets resolved when the alert stops firing
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat{
+    #[default]
+    Slack,
+    Pagerduty,
+}
+
+///
+/// A saved search that gets run every `every_minutes` against the last `window_minutes` (every_minutes, if it's left out),
+/// and fires when more than `above` logs match, or fewer than `below` (`"below": 1` is "nothing at all"). It fires once when
+/// it crosses the threshold, and once more (resolved) when it's back: not every time it's checked in between.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Alert{
+    pub name: String,
+    pub search: String,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default = "default_every_minutes")]
+    pub every_minutes: u64,
+    #[serde(default)]
+    pub window_minutes: Option<u64>,
+    #[serde(default)]
+    pub above: Option<u64>,
+    #[serde(default)]
+    pub below: Option<u64>,
+    pub webhook: String,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    #[serde(default)]
+    pub routing_key: Option<String>,
+}
+
+impl Alert{
+    fn window_minutes(&self) -> u64 {
+        self.window_minutes.unwrap_or(self.every_minutes)
+    }
+
+    fn breached(&self, count: u64) -> bool {
+        self.above.is_some_and(|above| count > above) || self.below.is_some_and(|below| count < below)
+    }
+
+    fn threshold(&self) -> String {
+        match (self.above, self.below){
+            (Some(above), _) => format!("more than {}", above),
+            (None, Some(below)) => format!("fewer than {}", below),
+            (None, None) => "no threshold".to_string(),
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            return Err(anyhow::anyhow!("every alert needs a name"));
+        }
+        crate::search_token::Search::parse(&self.search).map_err(|e| anyhow::anyhow!("alert '{}' has a bad search: {}", self.name, e))?;
+        if self.every_minutes == 0 || self.window_minutes() == 0 {
+            return Err(anyhow::anyhow!("alert '{}' has to run every minute or less often, over at least a minute", self.name));
+        }
+        if self.above.is_some() == self.below.is_some() {
+            return Err(anyhow::anyhow!("alert '{}' needs one of above or below", self.name));
+        }
+        if self.format == WebhookFormat::Pagerduty && self.routing_key.is_none() {
+            return Err(anyhow::anyhow!("alert '{}' goes to PagerDuty, so it needs a routing_key", self.name));
+        }
+        Ok(())
+    }
+
+    ///
+    /// What the webhook gets, when the alert starts firing (or, with `firing` false, when it stops)
+    ///
+    pub fn payload(&self, count: u64, top_hosts: &[ValueCount], firing: bool) -> serde_json::Value {
+        let summary = match firing{
+            true => format!("{}: {} logs matched `{}` in the last {} minutes ({})", self.name, count, self.search, self.window_minutes(), self.threshold()),
+            false => format!("{} is back to normal: {} logs matched `{}` in the last {} minutes", self.name, count, self.search, self.window_minutes()),
+        };
+        match self.format{
+            WebhookFormat::Slack => {
+                let hosts = top_hosts.iter().map(|host| format!("{} ({})", host.value, host.count)).collect::<Vec<String>>().join(", ");
+                let text = match hosts.is_empty(){
+                    true => format!("[logmunch] {}", summary),
+                    false => format!("[logmunch] {}\nbusiest hosts: {}", summary, hosts),
+                };
+                serde_json::json!({ "text": text })
+            },
+            WebhookFormat::Pagerduty => {
+                let dedup_key = format!("logmunch-{}-{}", self.tenant.as_deref().unwrap_or("default"), self.name);
+                match firing{
+                    true => serde_json::json!({
+                        "routing_key": self.routing_key,
+                        "event_action": "trigger",
+                        "dedup_key": dedup_key,
+                        "payload": {
+                            "summary": summary,
+                            "source": "logmunch",
+                            "severity": "error",
+                            "custom_details": {
+                                "search": self.search,
+                                "count": count,
+                                "window_minutes": self.window_minutes(),
+                                "threshold": self.threshold(),
+                                "top_hosts": top_hosts,
+                            },
+                        },
+                    }),
+                    false => serde_json::json!({
+                        "routing_key": self.routing_key,
+                        "event_action": "resolve",
+                        "dedup_key": dedup_key,
+                    }),
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct AlertsConfig{
+    #[serde(default)]
+    pub alerts: Vec<Alert>,
+}
+
+///
+/// How an alert's doing, for GET /admin/alerts: times are seconds since the epoch
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct AlertStatus{
+    pub name: String,
+    pub tenant: Option<String>,
+    pub firing: bool,
+    pub last_checked: Option<i64>,
+    pub last_count: Option<u64>,
+    pub last_fired: Option<i64>,
+    pub last_error: Option<String>,
+}
+
+///
+/// Where notifications go: a POST to the alert's webhook, unless it's a test
+///
+pub trait Notifier: Send + Sync {
+    fn send(&self, url: &str, payload: &serde_json::Value) -> Result<()>;
+}
+
+pub struct WebhookNotifier;
+
+impl Notifier for WebhookNotifier{
+    fn send(&self, url: &str, payload: &serde_json::Value) -> Result<()> {
+        ureq::post(url)
+            .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECONDS))
+            .set("Content-Type", "application/json")
+            .send_string(&payload.to_string())
+            .map_err(|e| anyhow::anyhow!("Error sending to {}: {}", url, e))?;
+        Ok(())
+    }
+}
+
+///
+/// Every alert in ALERTS (a JSON file: `{"alerts": [{"name": "checkout errors", "search": "level=error service=checkout",
+/// "every_minutes": 5, "above": 10, "webhook": "https://hooks.slack.com/services/..."}]}`), and how each of them is doing.
+/// See Alert for the rest of what an alert can say. The scheduler (run_loop) checks them on a thread of its own.
+///
+pub struct Alerts{
+    alerts: Vec<Alert>,
+    statuses: Mutex<Vec<AlertStatus>>,
+    notifier: Box<dyn Notifier>,
+    host_rules: Arc<HostRules>,
+}
+
+impl Alerts{
+    pub fn new(config: AlertsConfig, notifier: Box<dyn Notifier>, host_rules: Arc<HostRules>) -> Result<Alerts> {
+        for (i, alert) in config.alerts.iter().enumerate() {
+            alert.check()?;
+            if config.alerts[..i].iter().any(|other| other.name == alert.name && other.tenant == alert.tenant) {
+                return Err(anyhow::anyhow!("there's more than one alert called '{}'", alert.name));
+            }
+        }
+        let statuses = config.alerts.iter().map(|alert| AlertStatus{
+            name: alert.name.clone(),
+            tenant: alert.tenant.clone(),
+            ..Default::default()
+        }).collect();
+        Ok(Alerts{
+            alerts: config.alerts,
+            statuses: Mutex::new(statuses),
+            notifier,
+            host_rules,
+        })
+    }
+
+    pub fn from_config(config: &crate::config::Config, host_rules: Arc<HostRules>) -> Result<Alerts> {
+        let config = match &config.alerts{
+            Some(path) => Self::load(path)?,
+            None => AlertsConfig::default(),
+        };
+        Self::new(config, Box::new(WebhookNotifier), host_rules)
+    }
+
+    pub fn load(path: &str) -> Result<AlertsConfig> {
+        let contents = fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Could not read alerts from {}: {}", path, e))?;
+        serde_json::from_str(&contents).map_err(|e| anyhow::anyhow!("Could not parse alerts in {}: {}", path, e))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.alerts.is_empty()
+    }
+
+    ///
+    /// Every alert's tenant has to be one we've got
+    ///
+    pub fn check_tenants(&self, tenants: &Tenants) -> Result<()> {
+        for alert in &self.alerts {
+            if tenants.get(alert.tenant.as_deref()).is_none() {
+                return Err(anyhow::anyhow!("alert '{}' is for tenant '{}', and there's no such tenant", alert.name, alert.tenant.as_deref().unwrap_or_default()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn status(&self) -> Vec<AlertStatus> {
+        self.statuses.lock().unwrap().clone()
+    }
+
+    ///
+    /// How many logs matched the alert's search over its window, up to `now`, and which hosts they came from
+    ///
+    fn count(&self, alert: &Alert, minute_db: &MinuteDB, now: i64) -> Result<(u64, Vec<ValueCount>)> {
+        let search = crate::search_token::Search::parse(&self.host_rules.rewrite_search(&alert.search))?;
+        let options = SearchOptions{
+            from: Some(now - alert.window_minutes() as i64 * 60),
+            to: Some(now),
+            ..Default::default()
+        };
+        let (result, _) = minute_db.stats(search, &options, &StatsBy::Host, TOP_HOSTS)?;
+        Ok((result.total, result.top))
+    }
+
+    ///
+    /// Run every alert that's due at `now` (seconds since the epoch) against its tenant's MinuteDB (`minute_dbs` finds it by name),
+    /// and send whatever crossed its threshold, either way. A notification that doesn't go through gets another try next time:
+    /// the alert doesn't change state until it does. Returns how many alerts were checked.
+    ///
+    pub fn check_due<F: Fn(Option<&str>) -> Option<Arc<MinuteDB>>>(&self, minute_dbs: F, now: i64) -> usize {
+        let mut checked = 0;
+        for (i, alert) in self.alerts.iter().enumerate() {
+            let status = self.statuses.lock().unwrap()[i].clone();
+            if status.last_checked.is_some_and(|last_checked| now - last_checked < alert.every_minutes as i64 * 60) {
+                continue;
+            }
+            checked += 1;
+            let mut status = AlertStatus{
+                last_checked: Some(now),
+                ..status
+            };
+            let count = match minute_dbs(alert.tenant.as_deref()){
+                Some(minute_db) => self.count(alert, &minute_db, now),
+                None => Err(anyhow::anyhow!("There's no tenant called '{}'", alert.tenant.as_deref().unwrap_or_default())),
+            };
+            match count{
+                Ok((count, top_hosts)) => {
+                    status.last_count = Some(count);
+                    status.last_error = None;
+                    let breached = alert.breached(count);
+                    if breached != status.firing {
+                        match self.notifier.send(&alert.webhook, &alert.payload(count, &top_hosts, breached)){
+                            Ok(_) => {
+                                tracing::info!("Alert '{}' {}: {} logs in the last {} minutes", alert.name, if breached { "fired" } else { "resolved" }, count, alert.window_minutes());
+                                status.firing = breached;
+                                if breached {
+                                    status.last_fired = Some(now);
+                                }
+                            },
+                            Err(e) => {
+                                tracing::error!("Error notifying for alert '{}': {}", alert.name, e);
+                                status.last_error = Some(e.to_string());
+                            },
+                        }
+                    }
+                },
+                Err(e) => {
+                    tracing::error!("Error checking alert '{}': {}", alert.name, e);
+                    status.last_error = Some(e.to_string());
+                },
+            }
+            self.statuses.lock().unwrap()[i] = status;
+        }
+        checked
+    }
+
+    ///
+    /// Check alerts as they come due, forever. A standby's searches would only be a copy of its primary's,
+    /// so it leaves alerting to the primary until it's promoted.
+    ///
+    pub fn run_loop(&self, tenants: &Tenants, standby: Option<&Standby>) {
+        loop {
+            if standby.is_none_or(|standby| standby.accepts_ingest()) {
+                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+                self.check_due(|name| tenants.get(name).map(|tenant| tenant.minute_db.clone()), now);
+            }
+            std::thread::sleep(Duration::from_secs(TICK_SECONDS));
+        }
+    }
+}
+
+#[cfg(test)]
+struct TestNotifier(Arc<Mutex<Vec<(String, serde_json::Value)>>>, Arc<std::sync::atomic::AtomicBool>);
+
+#[cfg(test)]
+impl Notifier for TestNotifier{
+    fn send(&self, url: &str, payload: &serde_json::Value) -> Result<()> {
+        if self.1.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err(anyhow::anyhow!("webhook's down"));
+        }
+        self.0.lock().unwrap().push((url.to_string(), payload.clone()));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_alert_config() -> Result<()> {
+    let config: AlertsConfig = serde_json::from_str(r#"{"alerts": [
+        {"name": "errors", "search": "level=error", "above": 10, "webhook": "http://hooks/slack"},
+        {"name": "quiet", "search": "heartbeat", "every_minutes": 1, "window_minutes": 10, "below": 1, "webhook": "http://hooks/pd", "format": "pagerduty", "routing_key": "abc"}
+    ]}"#)?;
+    assert_eq!(config.alerts[0].every_minutes, 5);
+    assert_eq!(config.alerts[0].window_minutes(), 5);
+    assert_eq!(config.alerts[1].window_minutes(), 10);
+    assert!(config.alerts[0].breached(11) && !config.alerts[0].breached(10));
+    assert!(config.alerts[1].breached(0) && !config.alerts[1].breached(1));
+    assert!(Alerts::new(config.clone(), Box::new(WebhookNotifier), Arc::new(HostRules::default())).is_ok());
+
+    let bad = |change: fn(&mut Alert)| {
+        let mut alert = config.alerts[0].clone();
+        change(&mut alert);
+        Alerts::new(AlertsConfig{ alerts: vec![alert] }, Box::new(WebhookNotifier), Arc::new(HostRules::default())).is_err()
+    };
+    assert!(bad(|alert| alert.above = None));
+    assert!(bad(|alert| alert.below = Some(1)));
+    assert!(bad(|alert| alert.every_minutes = 0));
+    assert!(bad(|alert| alert.search = "(unclosed".to_string()));
+    assert!(bad(|alert| alert.format = WebhookFormat::Pagerduty));
+    let twice = AlertsConfig{ alerts: vec![config.alerts[0].clone(), config.alerts[0].clone()] };
+    assert!(Alerts::new(twice, Box::new(WebhookNotifier), Arc::new(HostRules::default())).is_err());
+
+    let trigger = config.alerts[1].payload(0, &[], true);
+    assert_eq!((trigger["event_action"].as_str(), trigger["dedup_key"].as_str()), (Some("trigger"), Some("logmunch-default-quiet")));
+    assert_eq!(config.alerts[1].payload(3, &[], false)["event_action"], "resolve");
+    let slack = config.alerts[0].payload(42, &[ValueCount{ value: "web-1".to_string(), count: 40 }], true);
+    assert_eq!(slack["text"], "[logmunch] errors: 42 logs matched `level=error` in the last 5 minutes (more than 10)\nbusiest hosts: web-1 (40)");
+    Ok(())
+}
+
+#[test]
+fn test_alerts_fire_and_resolve() -> Result<()> {
+    use crate::minute::Minute;
+
+    let data_directory = crate::minute::test_data_directory("alerts");
+    let now = 1710562887;
+    let minute_id = crate::minute_id::MinuteId::from_timestamp(now);
+    let mut writer = Minute::new(minute_id.day, minute_id.hour, minute_id.minute, "1-0", &data_directory, true)?;
+    writer.write_second((0..20).map(|n| crate::WritableEvent::new(&format!("level=error checkout failed {}", n), now * 1000000, &format!("web-{}", n % 2))).collect())?;
+    writer.seal()?;
+    drop(writer);
+
+    let minute_db = crate::minute_db::MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, u64::MAX, None), None, None);
+    minute_db.apply_scan(&crate::catalog::FileInfo::scan(&data_directory)?, 0)?;
+    let minute_db = Arc::new(minute_db);
+    let minute_dbs = |name: Option<&str>| name.is_none().then(|| minute_db.clone());
+
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let down = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let config: AlertsConfig = serde_json::from_str(r#"{"alerts": [
+        {"name": "checkout", "search": "checkout failed", "every_minutes": 5, "window_minutes": 15, "above": 10, "webhook": "http://hooks/slack"}
+    ]}"#)?;
+    let alerts = Alerts::new(config, Box::new(TestNotifier(sent.clone(), down.clone())), Arc::new(HostRules::default()))?;
+
+    // the webhook's down: it'll try again next time
+    down.store(true, std::sync::atomic::Ordering::SeqCst);
+    assert_eq!(alerts.check_due(minute_dbs, now + 1), 1);
+    assert!(!alerts.status()[0].firing);
+    assert!(alerts.status()[0].last_error.is_some());
+
+    down.store(false, std::sync::atomic::Ordering::SeqCst);
+    // not due yet
+    assert_eq!(alerts.check_due(minute_dbs, now + 60), 0);
+    assert_eq!(alerts.check_due(minute_dbs, now + 301), 1);
+    let status = alerts.status()[0].clone();
+    assert_eq!((status.firing, status.last_count, status.last_fired, status.last_error), (true, Some(20), Some(now + 301), None));
+    assert_eq!(sent.lock().unwrap().len(), 1);
+    assert!(sent.lock().unwrap()[0].1["text"].as_str().unwrap().contains("web-0 (10), web-1 (10)"));
+
+    // still over the threshold: nothing new to say
+    assert_eq!(alerts.check_due(minute_dbs, now + 601), 1);
+    assert_eq!(sent.lock().unwrap().len(), 1);
+
+    // the window's moved past the errors
+    assert_eq!(alerts.check_due(minute_dbs, now + 1201), 1);
+    assert!(!alerts.status()[0].firing);
+    assert_eq!(alerts.status()[0].last_count, Some(0));
+    assert!(sent.lock().unwrap()[1].1["text"].as_str().unwrap().contains("back to normal"));
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
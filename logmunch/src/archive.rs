@@ -0,0 +1,488 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::fs;
+use std::io::{Read, Write};
+use std::sync::RwLock;
+use std::time::SystemTime;
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Digest};
+
+use crate::catalog::FileInfo;
+use crate::minute_id::MinuteId;
+
+///
+/// Somewhere we can put minutes once we're done with them locally.
+///
+pub trait ObjectStore: Send + Sync {
+    fn put(&self, key: &str, data: Vec<u8>) -> Result<()>;
+    fn get(&self, key: &str) -> Result<Vec<u8>>;
+}
+
+///
+/// Where we archive to, and whether we write Parquet too (see Archiver::from_settings)
+///
+#[derive(Clone, PartialEq)]
+pub struct ArchiveSettings{
+    pub store: StoreSettings,
+    pub parquet_columns: Option<Vec<String>>,
+}
+
+#[derive(Clone, PartialEq)]
+pub enum StoreSettings{
+    Directory(String),
+    S3{
+        endpoint: String,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+    },
+}
+
+impl ArchiveSettings{
+    ///
+    /// archive_directory archives to a local directory, archive_s3_bucket (and the rest of the archive_s3_ settings) archives to S3,
+    /// and if neither is set, we don't archive at all. archive_parquet puts a Parquet copy of each minute next to it.
+    ///
+    pub fn from_config(config: &crate::config::Config) -> Option<ArchiveSettings> {
+        let store = match (&config.archive_directory, &config.archive_s3_bucket){
+            (Some(directory), _) => StoreSettings::Directory(directory.clone()),
+            (None, Some(bucket)) => StoreSettings::S3{
+                endpoint: config.archive_s3_endpoint.clone().unwrap_or(format!("https://s3.{}.amazonaws.com", config.archive_s3_region)),
+                bucket: bucket.clone(),
+                region: config.archive_s3_region.clone(),
+                // Config::validate won't let a bucket through without them
+                access_key: config.aws_access_key_id.clone().unwrap_or_default(),
+                secret_key: config.aws_secret_access_key.clone().unwrap_or_default(),
+            },
+            (None, None) => return None,
+        };
+        Some(ArchiveSettings{
+            store,
+            parquet_columns: config.archive_parquet.then(|| config.archive_parquet_columns.clone()),
+        })
+    }
+}
+
+///
+/// An ObjectStore that's just another directory (an NFS mount, a big slow disk, or a test)
+///
+pub struct DirectoryStore{
+    directory: String,
+}
+
+impl DirectoryStore{
+    pub fn new(directory: &str) -> DirectoryStore {
+        DirectoryStore{
+            directory: directory.to_string(),
+        }
+    }
+}
+
+impl ObjectStore for DirectoryStore{
+    fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let path = format!("{}/{}", self.directory, key);
+        if let Some(parent) = std::path::Path::new(&path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        Ok(fs::read(format!("{}/{}", self.directory, key))?)
+    }
+}
+
+///
+/// An ObjectStore that speaks the S3 API (AWS, or any of the many S3-compatible stores: minio, R2, etc)
+/// Requests are path-style (`{endpoint}/{bucket}/{key}`) and signed with AWS Signature Version 4.
+///
+pub struct S3Store{
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+impl S3Store{
+    pub fn new(endpoint: &str, bucket: &str, region: &str, access_key: &str, secret_key: &str) -> S3Store {
+        S3Store{
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+        }
+    }
+
+    fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take a key of any size");
+        mac.update(data.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    pub fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+        let k_date = Self::hmac(format!("AWS4{}", secret_key).as_bytes(), date);
+        let k_region = Self::hmac(&k_date, region);
+        let k_service = Self::hmac(&k_region, service);
+        Self::hmac(&k_service, "aws4_request")
+    }
+
+    ///
+    /// `20150830T123600Z`, the only date format SigV4 will accept
+    ///
+    pub fn amz_date(timestamp: i64) -> String {
+        // (Howard Hinnant's days-to-civil algorithm, so we don't need a whole date library for one string)
+        let days = timestamp.div_euclid(86400);
+        let seconds = timestamp.rem_euclid(86400);
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, seconds / 3600, (seconds % 3600) / 60, seconds % 60)
+    }
+
+    fn uri_encode(segment: &str) -> String {
+        let mut encoded = String::new();
+        for byte in segment.bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+                _ => encoded.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+        encoded
+    }
+
+    ///
+    /// Returns the (url, headers) for a signed request
+    ///
+    fn sign(&self, method: &str, key: &str, payload: &[u8], timestamp: i64) -> Result<(String, Vec<(String, String)>)> {
+        let host = self.endpoint.split("://").last().unwrap_or(&self.endpoint).to_string();
+        let path = format!("/{}/{}", Self::uri_encode(&self.bucket), key.split('/').map(Self::uri_encode).collect::<Vec<String>>().join("/"));
+
+        let amz_date = Self::amz_date(timestamp);
+        let date = &amz_date[0..8];
+        let payload_hash = hex::encode(Sha256::digest(payload));
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!("{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, host, payload_hash, amz_date, signed_headers, payload_hash);
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex::encode(Sha256::digest(canonical_request.as_bytes())));
+        let signature = hex::encode(Self::hmac(&Self::signing_key(&self.secret_key, date, &self.region, "s3"), &string_to_sign));
+
+        let authorization = format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", self.access_key, scope, signed_headers, signature);
+
+        Ok((format!("{}{}", self.endpoint, path), vec![
+            ("x-amz-content-sha256".to_string(), payload_hash),
+            ("x-amz-date".to_string(), amz_date),
+            ("Authorization".to_string(), authorization),
+        ]))
+    }
+}
+
+impl ObjectStore for S3Store{
+    fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+        let (url, headers) = self.sign("PUT", key, &data, timestamp)?;
+        let mut request = ureq::put(&url);
+        for (name, value) in headers {
+            request = request.set(&name, &value);
+        }
+        request.send_bytes(&data).map_err(|e| anyhow::anyhow!("Error uploading {} to S3: {}", key, e))?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+        let (url, headers) = self.sign("GET", key, &[], timestamp)?;
+        let mut request = ureq::get(&url);
+        for (name, value) in headers {
+            request = request.set(&name, &value);
+        }
+        let response = request.call().map_err(|e| anyhow::anyhow!("Error downloading {} from S3: {}", key, e))?;
+        let mut data = Vec::new();
+        response.into_reader().read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+///
+/// A line in the archive index: one minute that we've shipped off to the object store.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveEntry{
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub unique_id: String,
+    pub key: String,
+    pub size_bytes: u64,
+    pub compressed_bytes: u64,
+    pub archived_at: i64,
+    /// where its Parquet copy went (see ARCHIVE_PARQUET), if it got one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parquet_key: Option<String>,
+}
+
+impl ArchiveEntry{
+    pub fn to_minute_id(&self) -> MinuteId {
+        MinuteId::new(self.day, self.hour, self.minute, &self.unique_id)
+    }
+}
+
+///
+/// Before retention deletes a minute, the Archiver compresses it (zstd) and ships it to an ObjectStore,
+/// then writes down that it did so in an index (one JSON line per minute), so that we can get it back later.
+///
+pub struct Archiver{
+    store: Box<dyn ObjectStore>,
+    key_prefix: String,
+    index_path: String,
+    index: RwLock<BTreeMap<MinuteId, ArchiveEntry>>,
+    /// write a Parquet copy of every minute too, with these fields as columns of their own (see set_parquet)
+    parquet_columns: Option<Vec<String>>,
+}
+
+const ZSTD_LEVEL: i32 = 3;
+
+impl Archiver{
+    pub fn new(store: Box<dyn ObjectStore>, archive_directory: &str) -> Result<Archiver> {
+        fs::create_dir_all(archive_directory)?;
+        let index_path = format!("{}/index.jsonl", archive_directory);
+
+        let mut index = BTreeMap::new();
+        if let Ok(contents) = fs::read_to_string(&index_path) {
+            for line in contents.lines() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<ArchiveEntry>(line){
+                    Ok(entry) => {
+                        index.insert(entry.to_minute_id(), entry);
+                    },
+                    Err(e) => tracing::warn!("Error reading archive index line {:?}: {}", line, e)
+                }
+            }
+        }
+
+        Ok(Archiver{
+            store,
+            key_prefix: String::new(),
+            index_path,
+            index: RwLock::new(index),
+            parquet_columns: None,
+        })
+    }
+
+    ///
+    /// The archive `settings` describe, with its index (and whatever else we keep locally) in `archive_directory`
+    ///
+    pub fn from_settings(settings: &ArchiveSettings, archive_directory: &str) -> Result<Archiver> {
+        let store: Box<dyn ObjectStore> = match &settings.store{
+            StoreSettings::Directory(directory) => Box::new(DirectoryStore::new(directory)),
+            StoreSettings::S3{ endpoint, bucket, region, access_key, secret_key } => Box::new(S3Store::new(endpoint, bucket, region, access_key, secret_key)),
+        };
+        let mut archiver = Self::new(store, archive_directory)?;
+        archiver.set_parquet(settings.parquet_columns.clone());
+        Ok(archiver)
+    }
+
+    ///
+    /// Put every key we archive under `key_prefix`, so that several of us (tenants, say) can share a bucket
+    ///
+    pub fn set_key_prefix(&mut self, key_prefix: &str) {
+        self.key_prefix = key_prefix.to_string();
+    }
+
+    ///
+    /// Also write every minute we archive as Parquet (see parquet_export), with `columns` pulled out of the messages,
+    /// for whoever wants to point DuckDB or Spark at the bucket. None stops.
+    ///
+    pub fn set_parquet(&mut self, columns: Option<Vec<String>>) {
+        self.parquet_columns = columns;
+    }
+
+    pub fn key_for(minute_id: &MinuteId) -> String {
+        format!("minutes/{}/{}/{}-{}.db.zst", minute_id.day, minute_id.hour, minute_id.minute, minute_id.unique_id)
+    }
+
+    ///
+    /// Partitioned the way Hive does it, so `read_parquet('.../parquet/*/*/*.parquet', hive_partitioning = true)` gets day and hour columns for free
+    ///
+    pub fn parquet_key_for(minute_id: &MinuteId) -> String {
+        format!("parquet/day={}/hour={}/{}-{}.parquet", minute_id.day, minute_id.hour, minute_id.minute, minute_id.unique_id)
+    }
+
+    ///
+    /// The minute at `path` as Parquet, into the store: the minute itself is what matters, so this failing doesn't stop it being archived
+    ///
+    fn archive_parquet(&self, minute_id: &MinuteId, path: &str, columns: &[String]) -> Option<String> {
+        let key = format!("{}{}", self.key_prefix, Self::parquet_key_for(minute_id));
+        let archived = crate::minute::Minute::open_read_only(path, minute_id.clone())
+            .and_then(|minute| minute.search(&crate::search_token::Search::new("")))
+            .and_then(|logs| crate::parquet_export::encode_minute(logs, columns))
+            .and_then(|parquet| self.store.put(&key, parquet));
+        match archived{
+            Ok(()) => Some(key),
+            Err(e) => {
+                tracing::error!("Error archiving {} as Parquet, archiving it without: {}", minute_id, e);
+                None
+            }
+        }
+    }
+
+    ///
+    /// Compress and upload the minute at `path`. Only once this succeeds is it safe to delete the local copy.
+    ///
+    pub fn archive(&self, file: &FileInfo, path: &str) -> Result<ArchiveEntry> {
+        let minute_id = file.to_minute_id();
+        let data = fs::read(path)?;
+        let compressed = zstd::encode_all(data.as_slice(), ZSTD_LEVEL)?;
+        let key = format!("{}{}", self.key_prefix, Self::key_for(&minute_id));
+
+        let compressed_bytes = compressed.len() as u64;
+        self.store.put(&key, compressed)?;
+        let parquet_key = self.parquet_columns.as_ref().and_then(|columns| self.archive_parquet(&minute_id, path, columns));
+
+        let entry = ArchiveEntry{
+            day: minute_id.day,
+            hour: minute_id.hour,
+            minute: minute_id.minute,
+            unique_id: minute_id.unique_id.clone(),
+            key: key.clone(),
+            size_bytes: data.len() as u64,
+            compressed_bytes,
+            archived_at: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64,
+            parquet_key,
+        };
+
+        let mut index = self.index.write().unwrap();
+        let mut index_file = fs::OpenOptions::new().create(true).append(true).open(&self.index_path)?;
+        writeln!(index_file, "{}", serde_json::to_string(&entry)?)?;
+        index.insert(minute_id, entry.clone());
+
+        Ok(entry)
+    }
+
+    ///
+    /// Download and decompress an archived minute, writing it out to `path`.
+    ///
+    pub fn restore(&self, minute_id: &MinuteId, path: &str) -> Result<()> {
+        let key = match self.index.read().unwrap().get(minute_id){
+            Some(entry) => entry.key.clone(),
+            None => return Err(anyhow::anyhow!("Minute {} was never archived", minute_id)),
+        };
+        let compressed = self.store.get(&key)?;
+        let data = zstd::decode_all(compressed.as_slice())?;
+        if let Some(parent) = std::path::Path::new(path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    pub fn is_archived(&self, minute_id: &MinuteId) -> bool {
+        self.index.read().unwrap().contains_key(minute_id)
+    }
+
+    pub fn archived_in_range(&self, start: Bound<MinuteId>, end: Bound<MinuteId>) -> Vec<MinuteId> {
+        self.index.read().unwrap().range((start, end)).map(|(minute_id, _)| minute_id.clone()).collect()
+    }
+}
+
+#[test]
+fn test_signing_key() {
+    // straight out of the AWS SigV4 documentation
+    let key = S3Store::signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20150830", "us-east-1", "iam");
+    assert_eq!(hex::encode(key), "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9");
+}
+
+#[test]
+fn test_amz_date() {
+    assert_eq!(S3Store::amz_date(0), "19700101T000000Z");
+    assert_eq!(S3Store::amz_date(1440938160), "20150830T123600Z");
+    assert_eq!(S3Store::amz_date(1709251199), "20240229T235959Z");
+}
+
+#[test]
+fn test_archive_settings() {
+    assert!(ArchiveSettings::from_config(&crate::config::Config::default()).is_none());
+
+    let settings = ArchiveSettings::from_config(&crate::config::Config{
+        archive_s3_bucket: Some("minutes".to_string()),
+        archive_s3_region: "ca-central-1".to_string(),
+        aws_access_key_id: Some("AKIA".to_string()),
+        aws_secret_access_key: Some("sekrit".to_string()),
+        ..crate::config::Config::default()
+    }).unwrap();
+    assert!(settings.parquet_columns.is_none());
+    match settings.store{
+        StoreSettings::S3{ endpoint, bucket, .. } => assert_eq!((endpoint.as_str(), bucket.as_str()), ("https://s3.ca-central-1.amazonaws.com", "minutes")),
+        StoreSettings::Directory(_) => panic!("that's a bucket"),
+    }
+}
+
+#[test]
+fn test_archive_and_restore() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("archive");
+    {
+        let mut minute = crate::minute::Minute::new(1, 2, 3, "archived", &data_directory, true)?;
+        let mut test_data_source = crate::minute::TestData::new();
+        let mut test_data = Vec::new();
+        for _ in 0..100 {
+            test_data.push(crate::minute::generate_test_data(&mut test_data_source));
+        }
+        minute.write_second(test_data)?;
+        minute.seal()?;
+    }
+    let files = FileInfo::scan(&data_directory)?;
+    assert_eq!(files.len(), 1);
+
+    let store = DirectoryStore::new(&format!("{}-bucket", data_directory));
+    let archiver = Archiver::new(Box::new(store), &format!("{}-archive", data_directory))?;
+    let path = format!("{}{}", data_directory, files[0].path);
+    let entry = archiver.archive(&files[0], &path)?;
+    assert!(entry.compressed_bytes < entry.size_bytes);
+    assert!(archiver.is_archived(&MinuteId::new(1, 2, 3, "archived")));
+
+    // the index survives a restart
+    let store = DirectoryStore::new(&format!("{}-bucket", data_directory));
+    let archiver = Archiver::new(Box::new(store), &format!("{}-archive", data_directory))?;
+    assert!(archiver.is_archived(&MinuteId::new(1, 2, 3, "archived")));
+
+    assert_eq!(entry.parquet_key, None);
+
+    // with a Parquet copy alongside
+    let settings = ArchiveSettings::from_config(&crate::config::Config{
+        archive_directory: Some(format!("{}-bucket", data_directory)),
+        archive_parquet: true,
+        archive_parquet_columns: vec!["user_id".to_string()],
+        ..crate::config::Config::default()
+    }).unwrap();
+    let archiver = Archiver::from_settings(&settings, &format!("{}-archive", data_directory))?;
+    let entry = archiver.archive(&files[0], &path)?;
+    assert_eq!(entry.parquet_key.as_deref(), Some("parquet/day=1/hour=2/3-archived.parquet"));
+    let parquet = fs::File::open(format!("{}-bucket/parquet/day=1/hour=2/3-archived.parquet", data_directory))?;
+    let reader = parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(parquet)?;
+    assert_eq!(reader.metadata().file_metadata().num_rows(), 100);
+
+    let restored_directory = format!("{}-restored", data_directory);
+    archiver.restore(&MinuteId::new(1, 2, 3, "archived"), &format!("{}/1/2/3-archived.db", restored_directory))?;
+    let minute = crate::minute::Minute::new(1, 2, 3, "archived", &restored_directory, false)?;
+    assert!(minute.is_sealed()?);
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use std::sync::Arc;
+use anyhow::Result;
+use arrow_array::{ArrayRef, Int64Array, RecordBatch, StringArray, TimestampMicrosecondArray};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+
+use crate::enrich::EnrichedLog;
+use crate::minute::Log;
+
+///
+/// Rows per record batch: readers can start on the first batch before the last one has arrived
+///
+pub(crate) const BATCH_ROWS: usize = 8192;
+
+pub const CONTENT_TYPE: (&str, &str) = ("application", "vnd.apache.arrow.stream");
+
+fn log_fields() -> Vec<Field> {
+    vec![
+        Field::new("id", DataType::Int64, false),
+        Field::new("time", DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())), false),
+        Field::new("host", DataType::Utf8, false),
+        Field::new("message", DataType::Utf8, false),
+        Field::new("shard", DataType::Utf8, true),
+    ]
+}
+
+fn log_columns<'a>(logs: impl Iterator<Item = &'a Log> + Clone) -> Vec<ArrayRef> {
+    vec![
+        Arc::new(Int64Array::from_iter_values(logs.clone().map(|log| log.id))),
+        Arc::new(TimestampMicrosecondArray::from_iter_values(logs.clone().map(|log| log.time)).with_timezone("UTC")),
+        Arc::new(StringArray::from_iter_values(logs.clone().map(|log| log.host.as_str()))),
+        Arc::new(StringArray::from_iter_values(logs.clone().map(|log| log.message.as_str()))),
+        Arc::new(logs.map(|log| log.shard.as_deref()).collect::<StringArray>()),
+    ]
+}
+
+fn write_stream<T>(schema: Schema, rows: &[T], columns: impl Fn(&[T]) -> Vec<ArrayRef>) -> Result<Vec<u8>> {
+    let schema = Arc::new(schema);
+    let mut writer = StreamWriter::try_new(Vec::new(), &schema)?;
+    for chunk in rows.chunks(BATCH_ROWS) {
+        writer.write(&RecordBatch::try_new(schema.clone(), columns(chunk))?)?;
+    }
+    writer.finish()?;
+    Ok(writer.into_inner()?)
+}
+
+///
+/// `?format=arrow`: the same logs as `?fields=raw`, as an Arrow IPC stream (one column per field, times in UTC microseconds)
+///
+pub fn encode_logs(logs: &[Log]) -> Result<Vec<u8>> {
+    write_stream(Schema::new(log_fields()), logs, |chunk| log_columns(chunk.iter()))
+}
+
+pub(crate) fn enriched_fields() -> Vec<Field> {
+    let mut fields = log_fields();
+    fields.push(Field::new("level", DataType::Utf8, true));
+    fields.push(Field::new("fields", DataType::Utf8, false));
+    fields.push(Field::new("minute_id", DataType::Utf8, true));
+    fields
+}
+
+pub(crate) fn enriched_columns(chunk: &[EnrichedLog]) -> Vec<ArrayRef> {
+    let mut columns = log_columns(chunk.iter().map(|enriched| &enriched.log));
+    columns.push(Arc::new(chunk.iter().map(|enriched| enriched.level.as_deref()).collect::<StringArray>()));
+    columns.push(Arc::new(StringArray::from_iter_values(chunk.iter().map(|enriched| serde_json::to_string(&enriched.fields).unwrap_or_default()))));
+    columns.push(Arc::new(chunk.iter().map(|enriched| enriched.minute_id.as_deref()).collect::<StringArray>()));
+    columns
+}
+
+///
+/// `?format=arrow&fields=all`: `fields` comes along as a JSON object per row (polars: `.str.json_decode()`)
+///
+pub fn encode_enriched(logs: &[EnrichedLog]) -> Result<Vec<u8>> {
+    write_stream(Schema::new(enriched_fields()), logs, enriched_columns)
+}
+
+#[test]
+fn test_encode_logs() -> Result<()> {
+    use arrow_array::Array;
+
+    let logs: Vec<Log> = (0..10000).map(|n| Log{
+        id: n,
+        message: format!("level=info n={}", n),
+        time: 1700000000000000 + n,
+        host: "web-1".to_string(),
+        shard: if n % 2 == 0 { Some("1-0".to_string()) } else { None },
+        event_id: None,
+        minute_id: None,
+    }).collect();
+
+    let bytes = encode_logs(&logs)?;
+    let reader = arrow_ipc::reader::StreamReader::try_new(std::io::Cursor::new(bytes), None)?;
+    let batches = reader.collect::<std::result::Result<Vec<RecordBatch>, _>>()?;
+    assert_eq!(batches.len(), 2);
+    assert_eq!(batches.iter().map(|batch| batch.num_rows()).sum::<usize>(), 10000);
+    let messages = batches[1].column_by_name("message").unwrap().as_any().downcast_ref::<StringArray>().unwrap();
+    assert_eq!(messages.value(0), format!("level=info n={}", BATCH_ROWS));
+    assert!(batches[0].column_by_name("shard").unwrap().is_null(1));
+
+    let enriched: Vec<EnrichedLog> = logs.into_iter().take(3).map(EnrichedLog::new).collect();
+    let bytes = encode_enriched(&enriched)?;
+    let reader = arrow_ipc::reader::StreamReader::try_new(std::io::Cursor::new(bytes), None)?;
+    let batches = reader.collect::<std::result::Result<Vec<RecordBatch>, _>>()?;
+    let levels = batches[0].column_by_name("level").unwrap().as_any().downcast_ref::<StringArray>().unwrap();
+    assert_eq!(levels.value(2), "info");
+    let fields = batches[0].column_by_name("fields").unwrap().as_any().downcast_ref::<StringArray>().unwrap();
+    assert_eq!(fields.value(2), r#"{"level":"info","n":"2"}"#);
+    Ok(())
+}
@@ -0,0 +1,475 @@
+use std::io::Write;
+use std::net::{TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use anyhow::Result;
+use crossbeam::channel::{bounded, Receiver, Sender};
+use rocket::fairing::AdHoc;
+use rocket::Request;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+///
+/// How many audit events can be waiting for the exporter before we start dropping them
+/// (a search never waits on the SIEM)
+///
+const AUDIT_QUEUE_EVENTS: usize = 10000;
+const MAX_BATCH_EVENTS: usize = 500;
+const SEND_ATTEMPTS: u32 = 3;
+const RETRY_SECONDS: u64 = 5;
+
+///
+/// Who did what, from where, to which tenant, and how it went: one search, or one admin action.
+///
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AuditEvent{
+    /// milliseconds since the epoch
+    pub time: i64,
+    /// search, histogram, stats, explain, check, loki_query, loki_labels, import, redact or admin
+    pub action: String,
+    /// success or failure (failure includes being turned away)
+    pub outcome: String,
+    pub status: u16,
+    /// "token:" and the start of a hash of their token (never the token itself), or "anonymous"
+    pub actor: String,
+    /// the address on the other end of the socket
+    pub source_ip: Option<String>,
+    /// whatever the X-Real-IP header claimed: a proxy's word for it, or anybody's, so don't trust it for anything
+    pub forwarded_for: Option<String>,
+    pub tenant: Option<String>,
+    pub method: String,
+    pub path: String,
+    /// the search, if there was one
+    pub query: Option<String>,
+    /// the time range searched, in seconds since the epoch
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+impl AuditEvent{
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    ///
+    /// ArcSight's Common Event Format: most SIEMs will take it without a custom parser
+    ///
+    pub fn to_cef(&self) -> String {
+        let severity = match (self.action.as_str(), self.outcome.as_str()){
+            (_, "failure") => 6,
+            ("admin", _) | ("import", _) | ("redact", _) | ("export", _) => 5,
+            _ => 3,
+        };
+        let mut extensions = vec![
+            format!("rt={}", self.time),
+            format!("act={}", cef_value(&self.action)),
+            format!("outcome={}", cef_value(&self.outcome)),
+            format!("suser={}", cef_value(&self.actor)),
+            format!("requestMethod={}", cef_value(&self.method)),
+            format!("request={}", cef_value(&self.path)),
+            format!("cn3Label=status cn3={}", self.status),
+        ];
+        if let Some(source_ip) = &self.source_ip {
+            extensions.push(format!("src={}", cef_value(source_ip)));
+        }
+        if let Some(forwarded_for) = &self.forwarded_for {
+            extensions.push(format!("cs3Label=unverifiedForwardedFor cs3={}", cef_value(forwarded_for)));
+        }
+        if let Some(tenant) = &self.tenant {
+            extensions.push(format!("cs1Label=tenant cs1={}", cef_value(tenant)));
+        }
+        if let Some(query) = &self.query {
+            extensions.push(format!("cs2Label=query cs2={}", cef_value(query)));
+        }
+        if let Some(from) = self.from {
+            extensions.push(format!("cn1Label=from cn1={}", from));
+        }
+        if let Some(to) = self.to {
+            extensions.push(format!("cn2Label=to cn2={}", to));
+        }
+        format!("CEF:0|logmunch|logmunch|{}|{}|{}|{}|{}",
+            cef_header(env!("CARGO_PKG_VERSION")),
+            cef_header(&self.action),
+            cef_header(&format!("{} {}", self.method, self.path)),
+            severity,
+            extensions.join(" "))
+    }
+}
+
+fn cef_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|").replace(['\r', '\n'], " ")
+}
+
+fn cef_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('=', "\\=").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+///
+/// Which requests get audited, and what we call them: anything that reads logs, or changes anything
+///
+pub fn action(method: &str, segments: &[&str]) -> Option<&'static str> {
+    match segments{
+        ["search", _] | ["search", _, "stream"] => Some("search"),
+        ["search", _, "export"] => Some("export"),
+        ["search", _, "histogram"] => Some("histogram"),
+        ["search", _, "stats"] => Some("stats"),
+        ["search", _, "explain"] => Some("explain"),
+        ["loki", "api", "v1", "query_range"] => Some("loki_query"),
+        ["loki", "api", "v1", "labels"] | ["loki", "api", "v1", "label", ..] => Some("loki_labels"),
+        ["grafana", ..] => Some("grafana"),
+        ["services", "search", "jobs", "export"] | ["servicesNS", _, _, "search", "jobs", "export"] => Some("splunk_export"),
+        ["api", "v1", "check"] => Some("check"),
+        ["api", "v1", "cardinality"] => Some("cardinality"),
+        ["hosts"] => Some("hosts"),
+        ["log", _] => Some("log"),
+        ["api", "v1", "import"] => Some("import"),
+        ["api", "v1", "markers", ..] => Some("markers"),
+        ["logs"] if method == "DELETE" => Some("redact"),
+        ["export"] => Some("export"),
+        ["admin", ..] if method != "OPTIONS" => Some("admin"),
+        _ => None,
+    }
+}
+
+///
+/// Enough to tell one caller from another in the SIEM, without handing it anybody's token
+///
+pub fn actor(token: Option<&str>) -> String {
+    match token{
+        Some(token) => format!("token:{}", &hex::encode(Sha256::digest(token.as_bytes()))[..12]),
+        None => "anonymous".to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditFormat{
+    Json,
+    Cef,
+}
+
+impl AuditFormat{
+    pub fn parse(format: &str) -> Result<AuditFormat> {
+        match format{
+            "json" => Ok(AuditFormat::Json),
+            "cef" => Ok(AuditFormat::Cef),
+            other => Err(anyhow::anyhow!("AUDIT_FORMAT must be 'json' or 'cef', not '{}'", other)),
+        }
+    }
+
+    pub fn format(&self, event: &AuditEvent) -> String {
+        match self{
+            AuditFormat::Json => event.to_json(),
+            AuditFormat::Cef => event.to_cef(),
+        }
+    }
+}
+
+///
+/// Somewhere the audit trail goes: each line is one event, already formatted
+///
+pub trait AuditSink: Send + Sync {
+    fn send(&self, lines: &[String]) -> Result<()>;
+}
+
+///
+/// RFC 5424 syslog, over UDP (one datagram per event) or TCP (newline-framed)
+///
+pub struct SyslogSink{
+    address: String,
+    tcp: bool,
+    hostname: String,
+}
+
+impl SyslogSink{
+    ///
+    /// `udp://siem:514`, `tcp://siem:601`, or just `siem:514` (that's UDP)
+    ///
+    pub fn parse(target: &str) -> Result<SyslogSink> {
+        let (tcp, address) = match target.split_once("://"){
+            Some(("udp", address)) => (false, address),
+            Some(("tcp", address)) => (true, address),
+            Some((scheme, _)) => return Err(anyhow::anyhow!("AUDIT_SYSLOG should be udp:// or tcp://, not {}://", scheme)),
+            None => (false, target),
+        };
+        if !address.contains(':') {
+            return Err(anyhow::anyhow!("AUDIT_SYSLOG needs a port: {}", target));
+        }
+        Ok(SyslogSink{
+            address: address.to_string(),
+            tcp,
+            hostname: std::env::var("HOSTNAME").unwrap_or("-".to_string()),
+        })
+    }
+
+    fn frame(&self, line: &str) -> String {
+        // facility local0, severity notice
+        let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        format!("<133>1 {} {} logmunch - audit - {}", timestamp, self.hostname, line)
+    }
+}
+
+impl AuditSink for SyslogSink{
+    fn send(&self, lines: &[String]) -> Result<()> {
+        if self.tcp {
+            let mut stream = TcpStream::connect(&self.address)?;
+            stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+            for line in lines {
+                stream.write_all(format!("{}\n", self.frame(line)).as_bytes())?;
+            }
+            stream.flush()?;
+        }
+        else {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            for line in lines {
+                socket.send_to(self.frame(line).as_bytes(), &self.address)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// POSTs each batch to a URL: a JSON array of events, or one CEF line per event
+///
+pub struct WebhookSink{
+    url: String,
+    token: Option<String>,
+    format: AuditFormat,
+}
+
+impl AuditSink for WebhookSink{
+    fn send(&self, lines: &[String]) -> Result<()> {
+        let (content_type, body) = match self.format{
+            AuditFormat::Json => ("application/json", format!("[{}]", lines.join(","))),
+            AuditFormat::Cef => ("text/plain", lines.join("\n")),
+        };
+        let mut request = ureq::post(&self.url).set("Content-Type", content_type);
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+        request.send_string(&body).map_err(|e| anyhow::anyhow!("Error sending audit events to {}: {}", self.url, e))?;
+        Ok(())
+    }
+}
+
+///
+/// The audit trail of searches and admin actions, on its way out to a SIEM.
+/// Recording never blocks: events queue up for the exporter thread (see export_loop), which sends them in batches.
+/// If the SIEM is down for long enough that the queue fills, we drop events and say so, rather than slowing searches down.
+///
+pub struct AuditLog{
+    format: AuditFormat,
+    sink: Box<dyn AuditSink>,
+    sender: Sender<AuditEvent>,
+    receiver: Receiver<AuditEvent>,
+    dropped: AtomicU64,
+}
+
+impl AuditLog{
+    pub fn new(sink: Box<dyn AuditSink>, format: AuditFormat) -> AuditLog {
+        let (sender, receiver) = bounded(AUDIT_QUEUE_EVENTS);
+        AuditLog{
+            format,
+            sink,
+            sender,
+            receiver,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    ///
+    /// audit_syslog (udp://host:514 or tcp://host:601) or audit_webhook (a URL, with audit_webhook_token as a bearer token if it wants one):
+    /// with neither, there's no audit export. audit_format is json (the default) or cef.
+    ///
+    pub fn from_config(config: &crate::config::Config) -> Result<Option<AuditLog>> {
+        let format = AuditFormat::parse(&config.audit_format)?;
+        let sink: Box<dyn AuditSink> = match (&config.audit_syslog, &config.audit_webhook){
+            (Some(_), Some(_)) => return Err(anyhow::anyhow!("Set audit_syslog or audit_webhook, not both")),
+            (Some(target), None) => Box::new(SyslogSink::parse(target)?),
+            (None, Some(url)) => Box::new(WebhookSink{
+                url: url.clone(),
+                token: config.audit_webhook_token.clone(),
+                format,
+            }),
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(AuditLog::new(sink, format)))
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        if self.sender.try_send(event).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    ///
+    /// Send everything that's waiting, in batches. False if there was nothing to send.
+    ///
+    pub fn export(&self) -> bool {
+        let batch: Vec<String> = self.receiver.try_iter().take(MAX_BATCH_EVENTS)
+            .map(|event| self.format.format(&event))
+            .collect();
+        if batch.is_empty() {
+            return false;
+        }
+        for attempt in 1..=SEND_ATTEMPTS {
+            match self.sink.send(&batch){
+                Ok(_) => break,
+                Err(e) if attempt < SEND_ATTEMPTS => {
+                    tracing::warn!("Error exporting {} audit events (trying again in {}s): {}", batch.len(), RETRY_SECONDS, e);
+                    std::thread::sleep(Duration::from_secs(RETRY_SECONDS));
+                },
+                Err(e) => {
+                    tracing::error!("Error exporting {} audit events, giving up on them: {}", batch.len(), e);
+                    self.dropped.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                },
+            }
+        }
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            tracing::warn!("dropped {} audit events (the audit export couldn't keep up)", dropped);
+        }
+        true
+    }
+
+    pub fn export_loop(&self) {
+        loop {
+            if !self.export() {
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+///
+/// Records every audited request (see `action`) once the response is ready, including the ones that got turned away.
+///
+pub fn fairing(audit: Arc<AuditLog>) -> AdHoc {
+    AdHoc::on_response("Audit Log", move |request, response| {
+        let audit = audit.clone();
+        Box::pin(async move {
+            let segments: Vec<&str> = request.uri().path().segments().collect();
+            let action = match action(request.method().as_str(), &segments){
+                Some(action) => action,
+                None => return,
+            };
+            let status = response.status().code;
+            let tenant = request.guard::<crate::tenant::CallerTenant>().await.succeeded()
+                .map(|tenant| tenant.0.name.clone().unwrap_or("default".to_string()));
+            audit.record(AuditEvent{
+                time: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as i64,
+                action: action.to_string(),
+                outcome: if status < 400 { "success" } else { "failure" }.to_string(),
+                status,
+                actor: actor(request.headers().get_one("Authorization").and_then(crate::auth::ApiToken::parse).as_deref()),
+                source_ip: crate::auth::peer_ip(request).map(|ip| ip.to_string()),
+                forwarded_for: request.headers().get_one("X-Real-IP").map(|ip| ip.to_string()),
+                tenant,
+                method: request.method().as_str().to_string(),
+                path: request.uri().path().to_string(),
+                query: query(request, &segments),
+                from: time_param(request, &["from", "start"]),
+                to: time_param(request, &["to", "end"]),
+            });
+        })
+    })
+}
+
+fn query(request: &Request<'_>, segments: &[&str]) -> Option<String> {
+    match segments{
+        ["search", search, ..] => Some(search.to_string()),
+        // a redaction's query is usually the secret it's there to get rid of: it shouldn't end up in the SIEM, too
+        ["logs"] => None,
+        _ => request.query_value::<&str>("query").and_then(|query| query.ok()).map(|query| query.to_string()),
+    }
+}
+
+fn time_param(request: &Request<'_>, names: &[&str]) -> Option<i64> {
+    names.iter()
+        .find_map(|name| request.query_value::<&str>(name).and_then(|value| value.ok()))
+        .and_then(|value| crate::loki::parse_timestamp_seconds(value).ok())
+}
+
+#[cfg(test)]
+struct TestSink(std::sync::Mutex<Vec<String>>);
+
+#[cfg(test)]
+impl AuditSink for Arc<TestSink>{
+    fn send(&self, lines: &[String]) -> Result<()> {
+        self.0.lock().unwrap().extend(lines.iter().cloned());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_audit() -> Result<()> {
+    let event = AuditEvent{
+        time: 1710562887000,
+        action: "search".to_string(),
+        outcome: "success".to_string(),
+        status: 200,
+        actor: actor(Some("sekrit")),
+        source_ip: Some("10.0.0.7".to_string()),
+        forwarded_for: Some("127.0.0.1".to_string()),
+        tenant: Some("checkout".to_string()),
+        method: "GET".to_string(),
+        path: "/search/user=42 | level=error".to_string(),
+        query: Some("user=42 | level=error".to_string()),
+        from: Some(1710562800),
+        to: None,
+    };
+    assert!(event.actor.starts_with("token:"));
+    assert!(!event.actor.contains("sekrit"));
+    assert_eq!(actor(None), "anonymous");
+
+    let cef = event.to_cef();
+    assert!(cef.starts_with("CEF:0|logmunch|logmunch|"));
+    assert!(cef.contains("|search|GET /search/user=42 \\| level=error|3|rt=1710562887000 act=search"));
+    assert!(cef.contains("cs2Label=query cs2=user\\=42 | level\\=error"));
+    assert!(cef.contains("src=10.0.0.7"));
+    assert!(cef.contains("cs3Label=unverifiedForwardedFor cs3=127.0.0.1"));
+    assert!(cef.contains("cn1Label=from cn1=1710562800"));
+    assert!(!cef.contains("cn2Label"));
+    let json: serde_json::Value = serde_json::from_str(&event.to_json())?;
+    assert_eq!(json["tenant"], "checkout");
+
+    assert_eq!(action("GET", &["search", "error"]), Some("search"));
+    assert_eq!(action("GET", &["search", "error", "stats"]), Some("stats"));
+    assert_eq!(action("GET", &["search", "error", "stream"]), Some("search"));
+    assert_eq!(action("GET", &["search", "error", "explain"]), Some("explain"));
+    assert_eq!(action("DELETE", &["admin", "minutes"]), Some("admin"));
+    assert_eq!(action("DELETE", &["logs"]), Some("redact"));
+    assert_eq!(action("GET", &["export"]), Some("export"));
+    assert_eq!(action("GET", &["search", "error", "export"]), Some("export"));
+    assert_eq!(action("POST", &["grafana", "query"]), Some("grafana"));
+    assert_eq!(action("POST", &["servicesNS", "admin", "search", "search", "jobs", "export"]), Some("splunk_export"));
+    assert_eq!(action("POST", &["api", "v1", "markers"]), Some("markers"));
+    assert_eq!(action("GET", &["api", "v1", "cardinality"]), Some("cardinality"));
+    assert_eq!(action("GET", &["hosts"]), Some("hosts"));
+    assert_eq!(action("GET", &["log", "19798-4-21-1-0:1710562887000012"]), Some("log"));
+    assert_eq!(action("POST", &["services", "collector", "event", "1.0"]), None);
+
+    let sink = Arc::new(TestSink(std::sync::Mutex::new(Vec::new())));
+    let audit = AuditLog::new(Box::new(sink.clone()), AuditFormat::Cef);
+    assert!(!audit.export());
+    audit.record(event.clone());
+    audit.record(event.clone());
+    assert!(audit.export());
+    assert_eq!(sink.0.lock().unwrap().len(), 2);
+
+    // syslog, over UDP
+    let listener = UdpSocket::bind("127.0.0.1:0")?;
+    listener.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let syslog = SyslogSink::parse(&format!("udp://{}", listener.local_addr()?))?;
+    syslog.send(&[AuditFormat::Json.format(&event)])?;
+    let mut buffer = [0u8; 4096];
+    let n = listener.recv(&mut buffer)?;
+    let datagram = String::from_utf8_lossy(&buffer[..n]);
+    assert!(datagram.starts_with("<133>1 "));
+    assert!(datagram.ends_with(&event.to_json()));
+
+    assert!(SyslogSink::parse("siem.example.com").is_err());
+    assert!(SyslogSink::parse("http://siem.example.com:514").is_err());
+    Ok(())
+}
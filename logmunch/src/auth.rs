@@ -0,0 +1,445 @@
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::http::Status;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::net::IpAddr;
+use std::sync::Arc;
+use anyhow::Result;
+use serde::Deserialize;
+
+///
+/// Whatever token the caller sent us, if any.
+/// Splunk forwarders send `Authorization: Splunk <token>`, everybody else sends `Authorization: Bearer <token>`
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiToken(pub Option<String>);
+
+impl ApiToken{
+    pub fn parse(header: &str) -> Option<String> {
+        let header = header.trim();
+        let token = match header.split_once(' '){
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("splunk") || scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+            Some(_) => return None,
+            None if header.eq_ignore_ascii_case("splunk") || header.eq_ignore_ascii_case("bearer") => return None,
+            None => header,
+        };
+        if token.is_empty() {
+            return None;
+        }
+        Some(token.to_string())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiToken {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = request.headers().get_one("Authorization").and_then(ApiToken::parse);
+        Outcome::Success(ApiToken(token))
+    }
+}
+
+///
+/// What an API key lets you do. A key can have any of them, and having one doesn't get you any of the others.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope{
+    /// send us logs (HEC, OTLP, Loki push, text uploads, imports, markers)
+    Ingest,
+    /// search, and everything else that reads logs (histograms, stats, checks, cardinality, Loki queries)
+    Search,
+    /// the /admin endpoints: deleting minutes, labels, replication, profiles...
+    Admin,
+}
+
+///
+/// API keys, and what each of them is for, so that developers can have a key that searches and nothing else.
+/// A scope that no key has stays the way it's always been: search is open to anybody, ingest goes by INGEST_TOKENS,
+/// admin by ADMIN_TOKEN (both of which still work alongside the keys). Once any key has a scope, that scope takes a key
+/// with it (or, for ingest and admin, the old token). Keys are bearer tokens like any other, so TENANTS and TOKEN_POLICIES
+/// can name them too.
+///
+/// API_KEYS points at a JSON file that looks like:
+/// {
+///     "dev-search-key": ["search"],
+///     "shipper-key": ["ingest"],
+///     "ops-key": ["search", "admin"]
+/// }
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApiKeys{
+    keys: HashMap<String, BTreeSet<Scope>>,
+}
+
+impl ApiKeys{
+    pub fn from_config(config: &crate::config::Config) -> Result<ApiKeys> {
+        match &config.api_keys{
+            Some(path) => Self::load(path),
+            None => Ok(ApiKeys::default()),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<ApiKeys> {
+        let contents = fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Could not read API keys from {}: {}", path, e))?;
+        Self::parse(&contents).map_err(|e| anyhow::anyhow!("Could not parse API keys in {}: {}", path, e))
+    }
+
+    pub fn parse(json: &str) -> Result<ApiKeys> {
+        let keys: HashMap<String, BTreeSet<Scope>> = serde_json::from_str(json)?;
+        for (key, scopes) in &keys {
+            if key.trim().is_empty() || key.trim() != key {
+                return Err(anyhow::anyhow!("API keys can't be blank, or start or end with spaces"));
+            }
+            if scopes.is_empty() {
+                return Err(anyhow::anyhow!("API key {}... doesn't have any scopes: give it ingest, search or admin", key.chars().take(4).collect::<String>()));
+            }
+        }
+        Ok(ApiKeys{ keys })
+    }
+
+    ///
+    /// Does any key have this scope (and so, does this scope need one)?
+    ///
+    pub fn guards(&self, scope: Scope) -> bool {
+        self.keys.values().any(|scopes| scopes.contains(&scope))
+    }
+
+    pub fn allows(&self, token: Option<&str>, scope: Scope) -> bool {
+        token.and_then(|token| self.keys.get(token)).is_some_and(|scopes| scopes.contains(&scope))
+    }
+
+    ///
+    /// Every key with this scope
+    ///
+    pub fn with_scope(&self, scope: Scope) -> impl Iterator<Item = &String> {
+        self.keys.iter().filter(move |(_, scopes)| scopes.contains(&scope)).map(|(key, _)| key)
+    }
+}
+
+fn api_keys<'r>(request: &'r Request<'_>) -> Option<&'r ApiKeys> {
+    request.rocket().state::<Arc<ApiKeys>>().map(|api_keys| api_keys.as_ref())
+}
+
+///
+/// Only lets the request through if it carries ADMIN_TOKEN, or an API key with the admin scope.
+/// If there's neither configured, nobody gets in: the admin endpoints are off.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdminToken;
+
+impl AdminToken{
+    pub fn check(admin_token: Option<&str>, api_keys: &ApiKeys, token: Option<&str>) -> Result<AdminToken, Status> {
+        if api_keys.allows(token, Scope::Admin) {
+            return Ok(AdminToken);
+        }
+        match (admin_token, token){
+            (None, _) if !api_keys.guards(Scope::Admin) => Err(Status::Forbidden),
+            (Some(admin_token), Some(token)) if admin_token == token => Ok(AdminToken),
+            _ => Err(Status::Unauthorized),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminToken {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let admin_token = request.rocket().state::<Arc<crate::config::Config>>().and_then(|config| config.admin_token.as_deref());
+        let token = request.headers().get_one("Authorization").and_then(ApiToken::parse);
+        let no_keys = ApiKeys::default();
+        match AdminToken::check(admin_token, api_keys(request).unwrap_or(&no_keys), token.as_deref()){
+            Ok(admin) => Outcome::Success(admin),
+            Err(status) => Outcome::Error((status, ())),
+        }
+    }
+}
+
+///
+/// A network, like 10.0.0.0/8 or fd00::/8 (a bare address is a network of one)
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cidr{
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr{
+    pub fn parse(cidr: &str) -> Result<Cidr> {
+        let cidr = cidr.trim();
+        let (address, prefix) = match cidr.split_once('/'){
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (cidr, None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| anyhow::anyhow!("'{}' isn't an IP address", address))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix{
+            Some(prefix) => prefix.parse::<u32>().map_err(|_| anyhow::anyhow!("'{}' isn't a prefix length", prefix))?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(anyhow::anyhow!("/{} is too long a prefix for {}", prefix, network));
+        }
+        Ok(Cidr{ network, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // an IPv4 client on a dual-stack socket shows up as ::ffff:a.b.c.d
+        let ip = match ip{
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*ip),
+            IpAddr::V4(_) => *ip,
+        };
+        match (self.network, ip){
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = if self.prefix == 0 { 0 } else { u32::MAX << (32 - self.prefix) };
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = if self.prefix == 0 { 0 } else { u128::MAX << (128 - self.prefix) };
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+///
+/// Who's allowed to send us logs. With no INGEST_TOKENS, anybody is (that's how it's always been);
+/// with INGEST_TOKENS, you need one of them, unless you're coming from one of the INGEST_TRUSTED_CIDRS
+/// (a sidecar collector on localhost, say, or the pod network).
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IngestPolicy{
+    pub tokens: HashSet<String>,
+    pub trusted: Vec<Cidr>,
+}
+
+impl IngestPolicy{
+    ///
+    /// ingest_tokens, and ingest_trusted_cidrs (localhost, unless you say otherwise)
+    ///
+    pub fn from_config(config: &crate::config::Config) -> Result<IngestPolicy> {
+        let tokens = config.ingest_tokens.iter().cloned().collect();
+        let trusted = config.ingest_trusted_cidrs.iter()
+            .map(|cidr| Cidr::parse(cidr))
+            .collect::<Result<Vec<Cidr>>>()?;
+        Ok(IngestPolicy{ tokens, trusted })
+    }
+
+    ///
+    /// API keys with the ingest scope are ingest tokens too
+    ///
+    pub fn with_api_keys(mut self, api_keys: &ApiKeys) -> IngestPolicy {
+        self.tokens.extend(api_keys.with_scope(Scope::Ingest).cloned());
+        self
+    }
+
+    pub fn allows(&self, ip: Option<IpAddr>, token: Option<&str>) -> bool {
+        if self.tokens.is_empty() {
+            return true;
+        }
+        if let Some(token) = token {
+            if self.tokens.contains(token) {
+                return true;
+            }
+        }
+        match ip{
+            Some(ip) => self.trusted.iter().any(|cidr| cidr.contains(&ip)),
+            None => false,
+        }
+    }
+}
+
+///
+/// The address of whoever is actually on the other end of the socket.
+/// Not Rocket's client_ip(): that believes X-Real-IP, and anybody can send us one of those
+///
+pub fn peer_ip(request: &Request<'_>) -> Option<IpAddr> {
+    request.remote().map(|remote| remote.ip())
+}
+
+///
+/// Only lets the request through if the IngestPolicy says this caller can send us logs (401 if not), and we aren't a standby (503 until we're promoted).
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IngestAllowed;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IngestAllowed {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = request.headers().get_one("Authorization").and_then(ApiToken::parse);
+        let allowed = match request.rocket().state::<Arc<IngestPolicy>>(){
+            Some(ingest_policy) => ingest_policy.allows(peer_ip(request), token.as_deref()),
+            None => true,
+        };
+        // a standby that hasn't been promoted yet only takes minutes from its primary (see replication::Standby)
+        let standing_by = request.rocket().state::<Arc<crate::replication::Standby>>().is_some_and(|standby| !standby.accepts_ingest());
+        if standing_by {
+            Outcome::Error((Status::ServiceUnavailable, ()))
+        }
+        else if allowed {
+            Outcome::Success(IngestAllowed)
+        }
+        else {
+            Outcome::Error((Status::Unauthorized, ()))
+        }
+    }
+}
+
+///
+/// Only lets the request through if it can search: anybody can, unless there are API keys with the search scope,
+/// and then it takes one of them (401 if not)
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchAllowed;
+
+impl SearchAllowed{
+    pub fn check(api_keys: &ApiKeys, token: Option<&str>) -> Result<SearchAllowed, Status> {
+        match !api_keys.guards(Scope::Search) || api_keys.allows(token, Scope::Search){
+            true => Ok(SearchAllowed),
+            false => Err(Status::Unauthorized),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SearchAllowed {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(api_keys) = api_keys(request) else {
+            return Outcome::Success(SearchAllowed);
+        };
+        let token = request.headers().get_one("Authorization").and_then(ApiToken::parse);
+        match SearchAllowed::check(api_keys, token.as_deref()){
+            Ok(allowed) => Outcome::Success(allowed),
+            Err(status) => Outcome::Error((status, ())),
+        }
+    }
+}
+
+#[test]
+fn test_parse_token() {
+    assert_eq!(ApiToken::parse("Splunk SPLUNK-TOKEN-GOES-HERE"), Some("SPLUNK-TOKEN-GOES-HERE".to_string()));
+    assert_eq!(ApiToken::parse("Bearer abc123"), Some("abc123".to_string()));
+    assert_eq!(ApiToken::parse("abc123"), Some("abc123".to_string()));
+    assert_eq!(ApiToken::parse("Basic dXNlcjpwYXNz"), None);
+    assert_eq!(ApiToken::parse("Bearer "), None);
+    assert_eq!(ApiToken::parse(""), None);
+}
+
+#[test]
+fn test_admin_token() {
+    let no_keys = ApiKeys::default();
+    assert_eq!(AdminToken::check(None, &no_keys, Some("anything")), Err(Status::Forbidden));
+    assert_eq!(AdminToken::check(Some("sekrit"), &no_keys, Some("sekrit")), Ok(AdminToken));
+    assert_eq!(AdminToken::check(Some("sekrit"), &no_keys, Some("guess")), Err(Status::Unauthorized));
+    assert_eq!(AdminToken::check(Some("sekrit"), &no_keys, None), Err(Status::Unauthorized));
+}
+
+#[test]
+fn test_api_keys() -> Result<()> {
+    let api_keys = ApiKeys::parse(r#"{
+        "dev-search-key": ["search"],
+        "shipper-key": ["ingest"],
+        "ops-key": ["search", "admin"]
+    }"#)?;
+
+    // a search-only key searches, and that's all
+    assert_eq!(SearchAllowed::check(&api_keys, Some("dev-search-key")), Ok(SearchAllowed));
+    assert_eq!(SearchAllowed::check(&api_keys, Some("shipper-key")), Err(Status::Unauthorized));
+    assert_eq!(SearchAllowed::check(&api_keys, None), Err(Status::Unauthorized));
+    assert_eq!(AdminToken::check(None, &api_keys, Some("dev-search-key")), Err(Status::Unauthorized));
+    assert_eq!(AdminToken::check(None, &api_keys, Some("ops-key")), Ok(AdminToken));
+    // ADMIN_TOKEN still works alongside admin keys
+    assert_eq!(AdminToken::check(Some("sekrit"), &api_keys, Some("sekrit")), Ok(AdminToken));
+
+    let ingest = IngestPolicy::default().with_api_keys(&api_keys);
+    assert!(ingest.allows(Some("8.8.8.8".parse()?), Some("shipper-key")));
+    assert!(!ingest.allows(Some("8.8.8.8".parse()?), Some("dev-search-key")));
+
+    // with no search keys, search is open like it's always been
+    let ingest_only = ApiKeys::parse(r#"{"shipper-key": ["ingest"]}"#)?;
+    assert_eq!(SearchAllowed::check(&ingest_only, None), Ok(SearchAllowed));
+    assert_eq!(AdminToken::check(None, &ingest_only, Some("shipper-key")), Err(Status::Forbidden));
+
+    assert!(ApiKeys::parse(r#"{"key": ["retention"]}"#).is_err());
+    assert!(ApiKeys::parse(r#"{"key": []}"#).is_err());
+    assert!(ApiKeys::parse(r#"{"": ["search"]}"#).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_cidr() -> Result<()> {
+    let pods = Cidr::parse("10.42.0.0/16")?;
+    assert!(pods.contains(&"10.42.7.1".parse()?));
+    assert!(!pods.contains(&"10.43.0.1".parse()?));
+    assert!(pods.contains(&"::ffff:10.42.0.9".parse()?));
+    assert!(!pods.contains(&"fd00::1".parse()?));
+
+    assert!(Cidr::parse("::1")?.contains(&"::1".parse()?));
+    assert!(Cidr::parse("0.0.0.0/0")?.contains(&"8.8.8.8".parse()?));
+    assert!(Cidr::parse("10.0.0.0/33").is_err());
+    assert!(Cidr::parse("localhost").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_ingest_policy() -> Result<()> {
+    let open = IngestPolicy::default();
+    assert!(open.allows(Some("8.8.8.8".parse()?), None));
+
+    let policy = IngestPolicy{
+        tokens: ["sekrit".to_string()].into_iter().collect(),
+        trusted: vec![Cidr::parse("127.0.0.0/8")?],
+    };
+    assert!(policy.allows(Some("127.0.0.1".parse()?), None));
+    assert!(policy.allows(Some("8.8.8.8".parse()?), Some("sekrit")));
+    assert!(!policy.allows(Some("8.8.8.8".parse()?), Some("guess")));
+    assert!(!policy.allows(None, None));
+    Ok(())
+}
+
+#[cfg(test)]
+#[post("/ingest")]
+fn guarded_ingest(_allowed: IngestAllowed) -> &'static str {
+    "ok"
+}
+
+#[test]
+fn test_ingest_allowed_ignores_real_ip() -> Result<()> {
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    let policy = IngestPolicy{
+        tokens: ["sekrit".to_string()].into_iter().collect(),
+        trusted: vec![Cidr::parse("127.0.0.0/8")?],
+    };
+    let rocket = rocket::build()
+        .manage(Arc::new(policy))
+        .mount("/", routes![guarded_ingest]);
+    let client = Client::tracked(rocket)?;
+
+    // claiming to be local doesn't make you local
+    let spoofed = client.post("/ingest")
+        .remote("8.8.8.8:1234".parse()?)
+        .header(Header::new("X-Real-IP", "127.0.0.1"))
+        .dispatch();
+    assert_eq!(spoofed.status(), Status::Unauthorized);
+
+    let local = client.post("/ingest")
+        .remote("127.0.0.1:1234".parse()?)
+        .dispatch();
+    assert_eq!(local.status(), Status::Ok);
+
+    let with_token = client.post("/ingest")
+        .remote("8.8.8.8:1234".parse()?)
+        .header(Header::new("Authorization", "Bearer sekrit"))
+        .dispatch();
+    assert_eq!(with_token.status(), Status::Ok);
+    Ok(())
+}
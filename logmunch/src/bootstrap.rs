@@ -0,0 +1,143 @@
+use std::fs;
+use std::time::SystemTime;
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+///
+/// Bump this when the layout of the data directory itself changes (which directories live where, what's in metadata/).
+///
+pub const DATA_DIRECTORY_VERSION: u32 = 1;
+
+///
+/// Written to metadata/data_directory.json the first time we boot into a data directory,
+/// so that a different logmunch pointed at it later knows what it's looking at.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Marker{
+    pub data_directory_version: u32,
+    pub minute_format_version: u32,
+    pub build_version: String,
+    pub created_at: i64,
+}
+
+///
+/// Everywhere we keep things, under one DATA_DIRECTORY:
+///  - minutes/: the minute files themselves
+///  - metadata/: small bookkeeping files (like the marker)
+///  - archive/: the archive index (the archived minutes go wherever ARCHIVE_DIRECTORY / ARCHIVE_S3_BUCKET says)
+///  - scratch/: rehydrated minutes, thrown away on every boot
+///  - journal/: the ingest journal, if it's on (see ingest_journal)
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataDirectory{
+    pub root: String,
+    pub minutes: String,
+    pub metadata: String,
+    pub archive: String,
+    pub scratch: String,
+    pub journal: String,
+}
+
+impl DataDirectory{
+    pub fn new(root: &str) -> DataDirectory {
+        let root = root.trim_end_matches('/');
+        DataDirectory{
+            root: root.to_string(),
+            minutes: format!("{}/minutes", root),
+            metadata: format!("{}/metadata", root),
+            archive: format!("{}/archive", root),
+            scratch: format!("{}/scratch", root),
+            journal: format!("{}/journal", root),
+        }
+    }
+
+    ///
+    /// Make sure we can actually run out of `root` before we start: every directory exists and is writable,
+    /// there's some disk to write to, and nobody's pointed us at a data directory from an incompatible version.
+    /// Everything that goes wrong here comes back as an error that says what to do about it.
+    ///
+    pub fn bootstrap(root: &str, min_free_bytes: u64, disk_budget_bytes: u64) -> Result<DataDirectory> {
+        let data_directory = Self::new(root);
+
+        for directory in [&data_directory.root, &data_directory.minutes, &data_directory.metadata, &data_directory.archive, &data_directory.scratch] {
+            fs::create_dir_all(directory).map_err(|e| anyhow::anyhow!("Can't create {}: {} (set DATA_DIRECTORY somewhere logmunch is allowed to write)", directory, e))?;
+            Self::check_writable(directory)?;
+        }
+
+        let available = fs2::available_space(&data_directory.root).map_err(|e| anyhow::anyhow!("Can't tell how much space is free on {}: {}", data_directory.root, e))?;
+        if available < min_free_bytes {
+            return Err(anyhow::anyhow!(
+                "Only {} MB free on {}, and we need at least {} MB: free up some disk, or lower MIN_FREE_DISK_MB",
+                available / 1000000, data_directory.root, min_free_bytes / 1000000));
+        }
+        if available < disk_budget_bytes {
+            tracing::warn!("MINUTE_DB_DISK_GB allows {} MB of minutes, but there's only {} MB free on {}: we'll fill the disk before retention kicks in",
+                disk_budget_bytes / 1000000, available / 1000000, data_directory.root);
+        }
+
+        data_directory.check_marker()?;
+
+        Ok(data_directory)
+    }
+
+    fn check_writable(directory: &str) -> Result<()> {
+        let test_path = format!("{}/.logmunch-write-test", directory);
+        fs::write(&test_path, b"ok").map_err(|e| anyhow::anyhow!("Can't write to {}: {} (check its permissions and ownership)", directory, e))?;
+        fs::remove_file(&test_path)?;
+        Ok(())
+    }
+
+    fn marker_path(&self) -> String {
+        format!("{}/data_directory.json", self.metadata)
+    }
+
+    fn check_marker(&self) -> Result<()> {
+        let marker_path = self.marker_path();
+        match fs::read_to_string(&marker_path){
+            Ok(contents) => {
+                let marker: Marker = serde_json::from_str(&contents).map_err(|e| anyhow::anyhow!("{} is corrupt ({}): if this really is a logmunch data directory, delete it and we'll write a new one", marker_path, e))?;
+                if marker.data_directory_version != DATA_DIRECTORY_VERSION {
+                    return Err(anyhow::anyhow!(
+                        "{} was created by logmunch {} with data directory version {}, but this is version {}: point DATA_DIRECTORY somewhere else, or run a matching build",
+                        self.root, marker.build_version, marker.data_directory_version, DATA_DIRECTORY_VERSION));
+                }
+                Ok(())
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let marker = Marker{
+                    data_directory_version: DATA_DIRECTORY_VERSION,
+                    minute_format_version: crate::handshake::MINUTE_FORMAT_VERSION,
+                    build_version: env!("CARGO_PKG_VERSION").to_string(),
+                    created_at: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64,
+                };
+                fs::write(&marker_path, serde_json::to_string_pretty(&marker)?)?;
+                Ok(())
+            },
+            Err(e) => Err(anyhow::anyhow!("Can't read {}: {}", marker_path, e)),
+        }
+    }
+}
+
+#[test]
+fn test_bootstrap() -> Result<()> {
+    let root = crate::minute::test_data_directory("bootstrap");
+    let data_directory = DataDirectory::bootstrap(&root, 0, 0)?;
+    assert!(fs::metadata(&data_directory.minutes)?.is_dir());
+    assert!(fs::metadata(&data_directory.scratch)?.is_dir());
+    assert!(fs::metadata(data_directory.marker_path())?.is_file());
+
+    // booting again is fine
+    DataDirectory::bootstrap(&root, 0, 0)?;
+
+    // not enough disk
+    assert!(DataDirectory::bootstrap(&root, u64::MAX, 0).is_err());
+
+    // a data directory from the future
+    let mut marker: Marker = serde_json::from_str(&fs::read_to_string(data_directory.marker_path())?)?;
+    marker.data_directory_version = DATA_DIRECTORY_VERSION + 1;
+    fs::write(data_directory.marker_path(), serde_json::to_string(&marker)?)?;
+    let err = DataDirectory::bootstrap(&root, 0, 0).unwrap_err();
+    assert!(err.to_string().contains("data directory version"));
+
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+///
+/// 2^12 registers: about 1.6% error, and 4KB per field per minute
+///
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+///
+/// A HyperLogLog sketch: how many different values went into it, give or take a couple of percent, in a fixed 4KB.
+/// Every minute gets one per field in `cardinality_fields` when it's sealed, and sketches from any number of minutes
+/// merge into one that counts all of them, so "how many different users" over a day never has to look at a single row.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HyperLogLog{
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog{
+    fn default() -> HyperLogLog {
+        HyperLogLog::new()
+    }
+}
+
+impl HyperLogLog{
+    pub fn new() -> HyperLogLog {
+        HyperLogLog{
+            registers: vec![0; REGISTERS],
+        }
+    }
+
+    pub fn insert(&mut self, value: &str) {
+        let hash = hash(value);
+        let register = (hash >> (64 - PRECISION)) as usize;
+        // the first PRECISION bits picked the register: the rest are the coin flips, and we keep the longest run of tails
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+        if rank > self.registers[register] {
+            self.registers[register] = rank;
+        }
+    }
+
+    ///
+    /// Fold another sketch into this one: afterwards, it counts everything either of them had seen
+    ///
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*other);
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|register| 2f64.powi(-(*register as i32))).sum();
+        let estimate = alpha * m * m / sum;
+        let empty = self.registers.iter().filter(|register| **register == 0).count();
+        // with only a handful of values, most registers are still empty, and counting those is more accurate
+        if estimate <= 2.5 * m && empty > 0 {
+            return (m * (m / empty as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(postcard::to_allocvec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<HyperLogLog> {
+        let sketch: HyperLogLog = postcard::from_bytes(bytes)?;
+        if sketch.registers.len() != REGISTERS {
+            return Err(anyhow::anyhow!("a sketch should have {} registers, not {}", REGISTERS, sketch.registers.len()));
+        }
+        Ok(sketch)
+    }
+
+    ///
+    /// How far off the estimate usually is, as a fraction of it
+    ///
+    pub fn standard_error() -> f64 {
+        1.04 / (REGISTERS as f64).sqrt()
+    }
+}
+
+///
+/// The sketches live on disk, so this has to hash the same way forever: FNV-1a (fxhash collides on strings that only differ
+/// by a digit or two, which is exactly what ids look like), then a finalizer so that the high bits, which pick the register, get mixed too
+///
+fn hash(value: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in value.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+///
+/// What `GET /api/v1/cardinality` says: about how many different values `field` had between `from` and `to`
+///
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cardinality{
+    pub field: String,
+    pub estimate: u64,
+    /// the estimate is usually within this fraction of the real count
+    pub standard_error: f64,
+    /// minutes (shards, really) whose sketches went into the estimate
+    pub minutes: usize,
+    /// minutes in range that don't have a sketch for the field (sealed before it was in cardinality_fields, archived,
+    /// or not sealed yet): whatever's in them isn't counted
+    pub minutes_without_sketch: usize,
+}
+
+#[test]
+fn test_hyperloglog() {
+    let mut sketch = HyperLogLog::new();
+    assert_eq!(sketch.estimate(), 0);
+    for _ in 0..3 {
+        for user in 0..10 {
+            sketch.insert(&format!("user-{}", user));
+        }
+    }
+    assert_eq!(sketch.estimate(), 10);
+
+    let within = |estimate: u64, actual: f64| (estimate as f64 - actual).abs() / actual < HyperLogLog::standard_error() * 3.0;
+    let mut big = HyperLogLog::new();
+    for user in 0..100000 {
+        big.insert(&format!("user-{}", user));
+    }
+    assert!(within(big.estimate(), 100000.0), "{}", big.estimate());
+
+    // two minutes with some of the same users in them
+    let mut first = HyperLogLog::new();
+    let mut second = HyperLogLog::new();
+    for user in 0..30000 {
+        first.insert(&format!("user-{}", user));
+    }
+    for user in 20000..50000 {
+        second.insert(&format!("user-{}", user));
+    }
+    first.merge(&second);
+    assert!(within(first.estimate(), 50000.0), "{}", first.estimate());
+
+    assert_eq!(HyperLogLog::from_bytes(&first.to_bytes().unwrap()).unwrap(), first);
+    assert!(HyperLogLog::from_bytes(&postcard::to_allocvec(&HyperLogLog{ registers: vec![0; 10] }).unwrap()).is_err());
+
+    // sketches that are already on disk have to keep meaning the same thing
+    assert_eq!(hash("user-42"), 0x7a1596f0e94b5793);
+}
+
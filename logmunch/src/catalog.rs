@@ -0,0 +1,543 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, MAIN_SEPARATOR};
+use walkdir::WalkDir;
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+use std::time::{Duration, SystemTime};
+
+///
+/// The files that sit next to a minute: SQLite's `-wal` and `-journal` while somebody's writing to it, `-shm` along with the WAL,
+/// and our own `-bloom` once it's sealed (see segment::bloom_sidecar_path). They belong to a minute, but they aren't one.
+///
+const SIDECARS: [&str; 4] = ["-wal", "-shm", "-journal", "-bloom"];
+
+///
+/// One minute file in a data directory, `{day}/{hour}/{minute}-{unique_id}.db`: the catalog is a list of these.
+/// `scan` makes the list, `enforce_retention` deletes (or archives) whatever retention says has to go.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo{
+    pub path: String,
+    pub size_bytes: u64,
+    /// when the file was last written to, in seconds since the epoch: NOT when its logs happened (see sort_key)
+    pub modified_at: i64,
+    pub day: i32,
+    pub hour: i32,
+    pub minute: i32,
+    /// when the minute's logs happened, in seconds since the epoch: a minute that was backfilled today still sorts where its logs belong
+    pub sort_key: i64,
+    pub unique_id: String,
+}
+
+impl FileInfo{
+
+    pub fn to_minute_id(&self) -> crate::minute_id::MinuteId{
+        crate::minute_id::MinuteId::new(self.day as u32, self.hour as u32, self.minute as u32, &self.unique_id)
+    }
+
+    ///
+    /// The start of the minute, in seconds since the epoch
+    ///
+    pub fn event_time(day: i32, hour: i32, minute: i32) -> i64 {
+        day as i64 * 86400 + hour as i64 * 3600 + minute as i64 * 60
+    }
+
+    ///
+    /// `{day}/{hour}/{minute}-{unique_id}.db`, relative to the data directory, in whatever separators this platform uses
+    ///
+    fn parse_path(path: &Path) -> Result<(i32, i32, i32, String)>{
+        let mut names = Vec::new();
+        for component in path.components() {
+            match component{
+                Component::Normal(name) => names.push(name.to_str().ok_or_else(|| anyhow::anyhow!("{} isn't UTF-8", path.display()))?),
+                // the separator at the start of FileInfo.path
+                Component::RootDir => {},
+                _ => return Err(anyhow::anyhow!("{} isn't a path to a minute", path.display())),
+            }
+        }
+        let [day, hour, file] = names[..] else {
+            return Err(anyhow::anyhow!("{} isn't day/hour/minute-id.db", path.display()));
+        };
+        let Some((minute, unique_id)) = file.strip_suffix(".db").and_then(|name| name.split_once('-')) else {
+            return Err(anyhow::anyhow!("{} isn't day/hour/minute-id.db", path.display()));
+        };
+        Ok((day.parse::<i32>()?, hour.parse::<i32>()?, minute.parse::<i32>()?, unique_id.to_string()))
+    }
+
+    ///
+    /// What goes in FileInfo.path: where the file is, relative to the data directory, starting with a separator
+    /// (so that data_directory + path is the whole thing)
+    ///
+    fn relative_path(data_directory: &str, path: &Path) -> Option<String>{
+        let relative = path.strip_prefix(data_directory).ok()?;
+        Some(format!("{}{}", MAIN_SEPARATOR, relative.to_str()?))
+    }
+
+    ///
+    /// List every minute file in the data directory, including ones that still have a WAL hanging off of them
+    /// (this is what crash recovery wants: a writer that died mid-minute leaves its -wal file behind).
+    /// SQLite's sidecar files and swap files are skipped, and so is anything that isn't where a minute would be.
+    ///
+    pub fn scan(data_directory: &str) -> Result<Vec<FileInfo>>{
+        Self::walk(data_directory, None)
+    }
+
+    ///
+    /// List every minute that still has a WAL file next to it (size_bytes is the WAL's size)
+    ///
+    pub fn scan_wal_files(data_directory: &str) -> Result<Vec<FileInfo>>{
+        Self::walk(data_directory, Some("-wal"))
+    }
+
+    ///
+    /// Minutes, or (with `sidecar`) the minutes that have that sidecar file next to them
+    ///
+    fn walk(data_directory: &str, sidecar: Option<&str>) -> Result<Vec<FileInfo>>{
+        let mut files = Vec::new();
+
+        for entry in WalkDir::new(data_directory){
+            let entry = match entry{
+                Ok(entry) => entry,
+                Err(e) => {
+                    tracing::warn!("Error walking the minutes directory: {}", e);
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let Some(path) = Self::relative_path(data_directory, entry.path()) else {
+                continue;
+            };
+            let minute_path = match (sidecar, Self::sidecar_of(&path)){
+                (None, None) => path,
+                (Some(wanted), Some((minute_path, sidecar))) if sidecar == wanted => minute_path.to_string(),
+                _ => continue,
+            };
+            if minute_path.ends_with(".swp") {
+                continue;
+            }
+            match Self::parse_path(Path::new(&minute_path)){
+                Ok(parsed) => files.push(Self::from_metadata(minute_path, parsed, &entry.metadata()?)),
+                Err(e) => {
+                    tracing::warn!("Skipping a file that isn't a minute: {}", e);
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    fn from_metadata(path: String, (day, hour, minute, unique_id): (i32, i32, i32, String), metadata: &fs::Metadata) -> FileInfo{
+        let modified_at = metadata.modified().ok()
+            .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs() as i64)
+            .unwrap_or(0);
+        FileInfo{
+            path,
+            size_bytes: metadata.len(),
+            modified_at,
+            day,
+            hour,
+            minute,
+            sort_key: Self::event_time(day, hour, minute),
+            unique_id,
+        }
+    }
+
+    ///
+    /// If `path` is one of SQLite's sidecar files, the minute it belongs to and which sidecar it is
+    ///
+    fn sidecar_of(path: &str) -> Option<(&str, &'static str)>{
+        SIDECARS.iter().find_map(|sidecar| path.strip_suffix(sidecar).map(|minute_path| (minute_path, *sidecar)))
+    }
+
+    ///
+    /// Which minute a file belongs to (`{day}/{hour}/...`, relative to the data directory): the minute's own file or one of its sidecars.
+    /// None for swap files, and for anything that isn't where a minute would be.
+    ///
+    pub fn minute_id_of(path: &str) -> Option<crate::minute_id::MinuteId>{
+        let minute_path = Self::sidecar_of(path).map_or(path, |(minute_path, _)| minute_path);
+        if minute_path.ends_with(".swp") {
+            return None;
+        }
+        let (day, hour, minute, unique_id) = Self::parse_path(Path::new(minute_path)).ok()?;
+        Some(crate::minute_id::MinuteId::new(day as u32, hour as u32, minute as u32, &unique_id))
+    }
+
+    ///
+    /// Delete whatever falls outside of the retention policy (archiving it first, if there's an archiver) and take it out of `files`.
+    /// Returns what was deleted. Anything we couldn't archive stays, on disk and in `files`: we'll try again on the next pass.
+    ///
+    pub fn enforce_retention(data_directory: &str, files: &mut Vec<FileInfo>, policy: &crate::retention::RetentionPolicy, archiver: Option<&crate::archive::Archiver>) -> Result<Vec<FileInfo>>{
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+        let mut deleted = Vec::new();
+        // the policy sorts them: most recent first, oldest last
+        for file in policy.apply(files, now){
+            let path = format!("{}{}", data_directory, file.path);
+            if let Some(archiver) = archiver {
+                if let Err(e) = archiver.archive(&file, &path) {
+                    tracing::error!("Error archiving {}: {}", path, e);
+                    files.push(file);
+                    continue;
+                }
+            }
+            Self::remove_file(path.as_str());
+            // minutes sealed before there were bloom sidecars don't have one
+            let _ = fs::remove_file(crate::segment::bloom_sidecar_path(&path));
+            deleted.push(file);
+        }
+        Ok(deleted)
+    }
+
+    ///
+    /// Remove a file from the filesystem.
+    ///
+    fn remove_file(path: &str){
+        match fs::remove_file(path){
+            Ok(_) => {},
+            Err(e) => {
+                tracing::error!("Error removing {}: {}", path, e);
+            }
+        }
+    }
+
+}
+
+///
+/// Everything `FileInfo::scan` would find, kept up to date without looking at every file every time:
+/// an hour directory only changes its modification time when a file shows up in it or goes away,
+/// so `refresh` lists the day and hour directories, and only reads (and stats the files in) the hours that changed.
+/// An hour that had a minute being written in it (a -wal or -journal next to it) gets read every time, since sealing a minute
+/// changes the minute without changing its directory.
+///
+/// It's saved (as json, to `path`) every time it changes, so there's a record of what the read loop thinks is on disk.
+/// It's never loaded back, though: a new Catalog reads everything, so every startup is a scan from scratch.
+///
+pub struct Catalog{
+    data_directory: String,
+    path: Option<String>,
+    /// "{day}/{hour}" -> what was in it
+    hours: BTreeMap<String, CatalogHour>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CatalogHour{
+    /// the directory's modification time when we read it, in nanoseconds since the epoch
+    modified_at: u64,
+    /// somebody was writing a minute in here
+    busy: bool,
+    files: Vec<FileInfo>,
+}
+
+///
+/// A directory that's been modified this recently gets read again regardless: some filesystems only keep modification times to the second,
+/// and a file that showed up in the same second as our last look wouldn't change anything.
+///
+const CATALOG_SETTLE: Duration = Duration::from_secs(2);
+
+impl Catalog{
+    pub fn new(data_directory: &str, path: Option<String>) -> Catalog{
+        Catalog{
+            data_directory: data_directory.to_string(),
+            path,
+            hours: BTreeMap::new(),
+        }
+    }
+
+    ///
+    /// Catch up with the data directory, and list every minute in it (like `FileInfo::scan`)
+    ///
+    pub fn refresh(&mut self) -> Result<Vec<FileInfo>>{
+        let settled = SystemTime::now() - CATALOG_SETTLE;
+        let mut hours = BTreeMap::new();
+        let mut changed = false;
+        for day in Self::directories(Path::new(&self.data_directory))? {
+            for hour in Self::directories(&Path::new(&self.data_directory).join(&day))? {
+                let key = format!("{}/{}", day, hour);
+                let directory = Path::new(&self.data_directory).join(&day).join(&hour);
+                let modified = match fs::metadata(&directory).and_then(|metadata| metadata.modified()){
+                    Ok(modified) => modified,
+                    // retention emptied it out from under us
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                let modified_at = modified.duration_since(SystemTime::UNIX_EPOCH).map(|modified| modified.as_nanos() as u64).unwrap_or(0);
+                match self.hours.remove(&key){
+                    Some(known) if known.modified_at == modified_at && !known.busy && modified < settled => {
+                        hours.insert(key, known);
+                    },
+                    known => {
+                        let Some(hour) = self.read_hour(&directory, modified_at)? else {
+                            continue;
+                        };
+                        changed |= known.as_ref() != Some(&hour);
+                        hours.insert(key, hour);
+                    }
+                }
+            }
+        }
+        // whatever's left is gone
+        changed |= !self.hours.is_empty();
+        self.hours = hours;
+
+        if changed {
+            if let Err(e) = self.save() {
+                tracing::error!("Error saving the catalog of {}: {}", self.data_directory, e);
+            }
+        }
+        Ok(self.hours.values().flat_map(|hour| hour.files.iter().cloned()).collect())
+    }
+
+    ///
+    /// The names of the directories in `directory`
+    ///
+    fn directories(directory: &Path) -> Result<Vec<String>>{
+        let mut names = Vec::new();
+        let entries = match fs::read_dir(directory){
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(names),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                if let Some(name) = entry.file_name().to_str() {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    ///
+    /// Every minute in an hour directory: None if the directory's gone
+    ///
+    fn read_hour(&self, directory: &Path, modified_at: u64) -> Result<Option<CatalogHour>>{
+        let entries = match fs::read_dir(directory){
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut hour = CatalogHour{
+            modified_at,
+            busy: false,
+            files: Vec::new(),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let Some(path) = FileInfo::relative_path(&self.data_directory, &entry.path()) else {
+                continue;
+            };
+            if let Some((_, sidecar)) = FileInfo::sidecar_of(&path) {
+                hour.busy |= matches!(sidecar, "-wal" | "-journal");
+                continue;
+            }
+            if path.ends_with(".swp") {
+                continue;
+            }
+            let Ok(parsed) = FileInfo::parse_path(Path::new(&path)) else {
+                continue;
+            };
+            match entry.metadata(){
+                Ok(metadata) if metadata.is_file() => hour.files.push(FileInfo::from_metadata(path, parsed, &metadata)),
+                Ok(_) => {},
+                // deleted since we listed it
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e.into()),
+            }
+        }
+        hour.files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Some(hour))
+    }
+
+    fn save(&self) -> Result<()>{
+        if let Some(path) = &self.path {
+            // write it next door and move it over, so nobody reads half of it
+            let temporary = format!("{}.tmp", path);
+            fs::write(&temporary, serde_json::to_string(&self.hours)?)?;
+            fs::rename(&temporary, path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn prep_test_directory(data_directory: &str){
+    let _ = fs::remove_dir_all(data_directory);
+    fs::create_dir_all(data_directory).unwrap();
+
+    let mut writer = crate::minute::ShardedMinute::new(1, data_directory.to_string(), 1 );
+    let mut other_writer = crate::minute::Minute::new(1, 1, 1, "borp", data_directory, true ).unwrap();
+    let mut other_other_writer = crate::minute::Minute::new(2, 3, 4, "borp", data_directory, true ).unwrap();
+
+    let mut test_data_source = crate::minute::TestData::new();
+    let mut test_data = Vec::new();
+    for _ in 0..1000 {
+        let data = crate::minute::generate_test_data(&mut test_data_source);
+        test_data.push(data);
+    }
+    other_writer.write_second(test_data).unwrap();
+    other_writer.seal().unwrap();
+
+    let mut test_data_source = crate::minute::TestData::new();
+    let mut test_data = Vec::new();
+    for _ in 0..1000 {
+        let data = crate::minute::generate_test_data(&mut test_data_source);
+        test_data.push(data);
+    }
+    other_other_writer.write_second(test_data).unwrap();
+    other_other_writer.seal().unwrap();
+
+    let mut test_data_source = crate::minute::TestData::new();
+    let mut test_data = Vec::new();
+    for _ in 0..1000 {
+        let data = crate::minute::generate_test_data(&mut test_data_source);
+        test_data.push(data);
+    }
+    writer.write(test_data).unwrap();
+    writer.seal().unwrap();
+}
+
+#[test]
+fn test_directory_scan(){
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u32;
+    let test_directory = format!("./test_data/test_reader_{}", timestamp);
+
+    prep_test_directory(&test_directory);
+
+    let policy = crate::retention::RetentionPolicy::new(5, 10000000, None);
+    let mut files = FileInfo::scan(&test_directory).unwrap();
+    assert!(FileInfo::enforce_retention(&test_directory, &mut files, &policy, None).unwrap().is_empty());
+
+    assert_eq!(files.len(), 3);
+    assert_eq!(files[1].day, 2);
+    assert_eq!(files[1].hour, 3);
+    assert_eq!(files[1].minute, 4);
+    assert_eq!(files[1].unique_id, "borp");
+    assert_eq!(files[2].day, 1);
+    assert_eq!(files[2].hour, 1);
+    assert_eq!(files[2].minute, 1);
+    assert_eq!(files[2].unique_id, "borp");
+
+    // the oldest one goes
+    let policy = crate::retention::RetentionPolicy::new(2, 10000000, None);
+    let deleted = FileInfo::enforce_retention(&test_directory, &mut files, &policy, None).unwrap();
+    assert_eq!(deleted.len(), 1);
+    assert_eq!((deleted[0].day, deleted[0].hour, deleted[0].minute), (1, 1, 1));
+    assert_eq!(files.len(), 2);
+    assert_eq!(FileInfo::scan(&test_directory).unwrap().len(), 2);
+}
+
+#[test]
+fn test_scan_skips_sidecars(){
+    let data_directory = crate::minute::test_data_directory("scan_sidecars");
+    fs::create_dir_all(format!("{}/1/2", data_directory)).unwrap();
+    for file in ["3-1-0.db", "3-1-0.db-wal", "3-1-0.db-shm", "4-1-0.db", "4-1-0.db-journal", "5-1-0.db.swp", "5-wal.db", "notes.txt"] {
+        fs::write(format!("{}/1/2/{}", data_directory, file), "").unwrap();
+    }
+
+    // the minutes, and only the minutes (even the one whose unique id is "wal")
+    let mut minutes: Vec<(i32, String)> = FileInfo::scan(&data_directory).unwrap().into_iter().map(|file| (file.minute, file.unique_id)).collect();
+    minutes.sort();
+    assert_eq!(minutes, vec![(3, "1-0".to_string()), (4, "1-0".to_string()), (5, "wal".to_string())]);
+
+    let wal_files = FileInfo::scan_wal_files(&data_directory).unwrap();
+    assert_eq!(wal_files.len(), 1);
+    assert_eq!((wal_files[0].minute, wal_files[0].path.as_str()), (3, format!("{}1{}2{}3-1-0.db", MAIN_SEPARATOR, MAIN_SEPARATOR, MAIN_SEPARATOR).as_str()));
+
+    fs::remove_dir_all(&data_directory).unwrap();
+}
+
+#[test]
+fn test_parse_path(){
+    let path: std::path::PathBuf = [std::path::MAIN_SEPARATOR_STR, "20000", "13", "59-1-0.db"].iter().collect();
+    assert_eq!(FileInfo::parse_path(&path).unwrap(), (20000, 13, 59, "1-0".to_string()));
+    let path: std::path::PathBuf = ["20000", "13", "7-borp.db"].iter().collect();
+    assert_eq!(FileInfo::parse_path(&path).unwrap(), (20000, 13, 7, "borp".to_string()));
+
+    for path in [
+        vec!["20000", "13"],
+        vec!["extra", "20000", "13", "59-1-0.db"],
+        vec!["20000", "13", "59-1-0.db-wal"],
+        vec!["20000", "13", "59.db"],
+        vec!["day", "13", "59-1-0.db"],
+        vec!["20000", "..", "59-1-0.db"],
+    ] {
+        let path: std::path::PathBuf = path.iter().collect();
+        assert!(FileInfo::parse_path(&path).is_err(), "{}", path.display());
+    }
+
+    let minute_id = crate::minute_id::MinuteId::new(20000, 13, 59, "1-0");
+    assert_eq!(FileInfo::minute_id_of("20000/13/59-1-0.db"), Some(minute_id.clone()));
+    assert_eq!(FileInfo::minute_id_of("20000/13/59-1-0.db-wal"), Some(minute_id));
+    assert_eq!(FileInfo::minute_id_of("20000/13/59-1-0.db.swp"), None);
+    assert_eq!(FileInfo::minute_id_of("20000/13"), None);
+}
+
+#[test]
+fn test_write_scan_search() -> Result<()>{
+    // the data directory is given with a separator on the end, which the scan shouldn't care about
+    let data_directory = format!("{}{}", crate::minute::test_data_directory("write_scan_search"), MAIN_SEPARATOR);
+    let mut writer = crate::minute::ShardedMinute::new(1, data_directory.clone(), 1);
+    writer.write(vec![crate::WritableEvent::new("a round trip through the filesystem", 0, "localhost")])?;
+    writer.force_seal()?;
+    drop(writer);
+
+    let mut files = FileInfo::scan(&data_directory)?;
+    FileInfo::enforce_retention(&data_directory, &mut files, &crate::retention::RetentionPolicy::new(10, u64::MAX, None), None)?;
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].unique_id, "1-0");
+    assert!(fs::metadata(format!("{}{}", data_directory, files[0].path)).is_ok());
+
+    let minute_db = crate::minute_db::MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, u64::MAX, None), None, None);
+    assert_eq!(minute_db.apply_scan(&files, 0)?, (0, 1));
+    let results = minute_db.search(crate::search_token::Search::new("round trip"), &crate::minute_db::SearchOptions::default())?;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].message, "a round trip through the filesystem");
+
+    fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_catalog() -> Result<()>{
+    let data_directory = crate::minute::test_data_directory("catalog");
+    let catalog_path = format!("{}.json", data_directory);
+    let sorted = |mut files: Vec<FileInfo>| {
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    };
+    prep_test_directory(&data_directory);
+    let mut catalog = Catalog::new(&data_directory, Some(catalog_path.clone()));
+    let files = catalog.refresh()?;
+    assert_eq!(files.len(), 3);
+    assert_eq!(sorted(files), sorted(FileInfo::scan(&data_directory)?));
+    let saved: BTreeMap<String, CatalogHour> = serde_json::from_str(&fs::read_to_string(&catalog_path)?)?;
+    assert_eq!(saved, catalog.hours);
+
+    // a minute that's being written to, and then gets sealed
+    let mut minute = crate::minute::Minute::new(1, 1, 2, "borp", &data_directory, true)?;
+    minute.write_second(vec![crate::WritableEvent::new("one more", 0, "localhost")])?;
+    let files = catalog.refresh()?;
+    assert_eq!(files.len(), 4);
+    assert!(catalog.hours["1/1"].busy);
+    minute.seal()?;
+    drop(minute);
+    assert_eq!(sorted(catalog.refresh()?), sorted(FileInfo::scan(&data_directory)?));
+    assert!(!catalog.hours["1/1"].busy);
+
+    // gone, and so is its hour
+    fs::remove_dir_all(format!("{}/2/3", data_directory))?;
+    let files = catalog.refresh()?;
+    assert_eq!(files.len(), 3);
+    assert!(!catalog.hours.contains_key("2/3"));
+    let saved: BTreeMap<String, CatalogHour> = serde_json::from_str(&fs::read_to_string(&catalog_path)?)?;
+    assert_eq!(saved, catalog.hours);
+
+    fs::remove_dir_all(&data_directory)?;
+    fs::remove_file(&catalog_path)?;
+    Ok(())
+}
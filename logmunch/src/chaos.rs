@@ -0,0 +1,159 @@
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+///
+/// Failure injection, for testing how we hold up when storage goes wrong: build with `--features chaos`, arm a fault
+/// (POST /admin/chaos, or `arm`), and the next time the writer or the read loop gets to that spot, it fails, or waits, or loses a file.
+/// Without the feature there's nothing to arm, and every hook is an empty function.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultKind{
+    /// writing a batch of events to a minute fails, as if the disk were full (the connection gets thrown away, like any failed write)
+    Commit,
+    /// sealing a minute takes `delay_ms` longer
+    SealDelay,
+    /// a minute gets deleted right after it's sealed
+    DropFile,
+    /// the read loop sleeps for `delay_ms` every time around
+    ReadLoopStall,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fault{
+    pub kind: FaultKind,
+    /// how many times it goes off before it disarms itself: None is until it's cleared
+    #[serde(default)]
+    pub times: Option<u32>,
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// only minutes under this directory (one tenant's, or one test's): None is everywhere
+    #[serde(default)]
+    pub scope: Option<String>,
+}
+
+impl Fault{
+    pub fn new(kind: FaultKind) -> Fault {
+        Fault{
+            kind,
+            times: None,
+            delay_ms: 0,
+            scope: None,
+        }
+    }
+}
+
+#[cfg(feature = "chaos")]
+static FAULTS: std::sync::Mutex<Vec<Fault>> = std::sync::Mutex::new(Vec::new());
+
+#[cfg(feature = "chaos")]
+pub fn arm(fault: Fault) {
+    tracing::warn!("Chaos: armed {:?}", fault);
+    FAULTS.lock().unwrap().push(fault);
+}
+
+#[cfg(feature = "chaos")]
+pub fn armed() -> Vec<Fault> {
+    FAULTS.lock().unwrap().clone()
+}
+
+///
+/// Disarm everything (with a `scope`, just the faults with that scope): how many that was
+///
+#[cfg(feature = "chaos")]
+pub fn clear(scope: Option<&str>) -> usize {
+    let mut faults = FAULTS.lock().unwrap();
+    let before = faults.len();
+    faults.retain(|fault| scope.is_some_and(|scope| fault.scope.as_deref() != Some(scope)));
+    before - faults.len()
+}
+
+///
+/// Is a `kind` fault armed for `path`? If it is, that's one of its `times` used up
+///
+#[cfg(feature = "chaos")]
+fn trigger(kind: FaultKind, path: &str) -> Option<Fault> {
+    let mut faults = FAULTS.lock().unwrap();
+    let i = faults.iter().position(|fault| fault.kind == kind && fault.scope.as_deref().is_none_or(|scope| path.starts_with(scope)))?;
+    let fault = faults[i].clone();
+    match &mut faults[i].times{
+        Some(1) => { faults.remove(i); },
+        Some(times) => *times -= 1,
+        None => {},
+    }
+    Some(fault)
+}
+
+///
+/// An error, if a `kind` fault is armed for `path`
+///
+#[cfg(feature = "chaos")]
+pub fn fail(kind: FaultKind, path: &str) -> Result<()> {
+    match trigger(kind, path){
+        Some(_) => Err(anyhow::anyhow!("chaos: {:?} failed for {}", kind, path)),
+        None => Ok(()),
+    }
+}
+
+///
+/// Wait `delay_ms`, if a `kind` fault is armed for `path`
+///
+#[cfg(feature = "chaos")]
+pub fn delay(kind: FaultKind, path: &str) {
+    if let Some(fault) = trigger(kind, path) {
+        std::thread::sleep(std::time::Duration::from_millis(fault.delay_ms));
+    }
+}
+
+///
+/// Delete the file at `path`, if a DropFile fault is armed for it
+///
+#[cfg(feature = "chaos")]
+pub fn drop_file(path: &str) {
+    if trigger(FaultKind::DropFile, path).is_some() {
+        tracing::warn!("Chaos: dropping {}", path);
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(not(feature = "chaos"))]
+#[inline(always)]
+pub fn fail(_kind: FaultKind, _path: &str) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(feature = "chaos"))]
+#[inline(always)]
+pub fn delay(_kind: FaultKind, _path: &str) {}
+
+#[cfg(not(feature = "chaos"))]
+#[inline(always)]
+pub fn drop_file(_path: &str) {}
+
+#[cfg(feature = "chaos")]
+#[test]
+fn test_chaos() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("chaos");
+    let mut writer = crate::minute::ShardedMinute::new(1, data_directory.clone(), 1);
+    let event = || crate::WritableEvent::new("chaos monkey", 0, "localhost");
+
+    // other tests run alongside this one: everything's scoped to our directory
+    arm(Fault{ times: Some(2), scope: Some(data_directory.clone()), ..Fault::new(FaultKind::Commit) });
+    assert!(fail(FaultKind::Commit, "./somebody/else").is_ok());
+    assert!(writer.write(vec![event()]).is_err());
+    assert!(writer.write(vec![event()]).is_err());
+    // used up
+    writer.write(vec![event()])?;
+    assert!(armed().iter().all(|fault| fault.scope.as_deref() != Some(data_directory.as_str())));
+
+    arm(Fault{ scope: Some(data_directory.clone()), ..Fault::new(FaultKind::DropFile) });
+    arm(Fault{ delay_ms: 50, scope: Some(data_directory.clone()), ..Fault::new(FaultKind::SealDelay) });
+    let started = std::time::Instant::now();
+    writer.force_seal()?;
+    assert!(started.elapsed() >= std::time::Duration::from_millis(50));
+    assert!(crate::catalog::FileInfo::scan(&data_directory)?.is_empty());
+    assert_eq!(clear(Some(&data_directory)), 2);
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use anyhow::Result;
+use rocket::serde::json::Json;
+use serde::Serialize;
+
+///
+/// Nagios's (and everybody who copied Nagios's) idea of how things are going
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum CheckStatus{
+    #[serde(rename = "OK")]
+    Ok,
+    #[serde(rename = "WARN")]
+    Warn,
+    #[serde(rename = "CRIT")]
+    Crit,
+}
+
+impl CheckStatus{
+    pub fn name(&self) -> &'static str {
+        match self{
+            CheckStatus::Ok => "OK",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Crit => "CRIT",
+        }
+    }
+
+    ///
+    /// What a Nagios plugin would exit with
+    ///
+    pub fn exit_code(&self) -> i32 {
+        match self{
+            CheckStatus::Ok => 0,
+            CheckStatus::Warn => 1,
+            CheckStatus::Crit => 2,
+        }
+    }
+}
+
+///
+/// Too many matching logs is a problem: `warn` or more is WARN, `crit` or more is CRIT. Leave either out and it never fires.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Thresholds{
+    pub warn: Option<u64>,
+    pub crit: Option<u64>,
+}
+
+impl Thresholds{
+    pub fn new(warn: Option<u64>, crit: Option<u64>) -> Result<Thresholds> {
+        if let (Some(warn), Some(crit)) = (warn, crit) {
+            if warn > crit {
+                return Err(anyhow::anyhow!("warn ({}) can't be more than crit ({})", warn, crit));
+            }
+        }
+        Ok(Thresholds{ warn, crit })
+    }
+
+    pub fn status(&self, count: u64) -> CheckStatus {
+        match (self.warn, self.crit){
+            (_, Some(crit)) if count >= crit => CheckStatus::Crit,
+            (Some(warn), _) if count >= warn => CheckStatus::Warn,
+            _ => CheckStatus::Ok,
+        }
+    }
+}
+
+///
+/// What `/api/v1/check` says: the status, the count it's based on, and a one-line summary with Nagios perfdata on the end
+///
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CheckResult{
+    pub status: CheckStatus,
+    pub count: u64,
+    pub query: String,
+    pub window_seconds: i64,
+    #[serde(flatten)]
+    pub thresholds: Thresholds,
+    pub message: String,
+}
+
+impl CheckResult{
+    pub fn new(query: &str, window: &str, window_seconds: i64, thresholds: Thresholds, count: u64) -> CheckResult {
+        let status = thresholds.status(count);
+        let threshold = |threshold: Option<u64>| threshold.map(|threshold| threshold.to_string()).unwrap_or_default();
+        let message = format!("{} - {} logs matching '{}' in the last {} | count={};{};{};0",
+            status.name(), count, query, window, count, threshold(thresholds.warn), threshold(thresholds.crit));
+        CheckResult{
+            status,
+            count,
+            query: query.to_string(),
+            window_seconds,
+            thresholds,
+            message,
+        }
+    }
+}
+
+#[derive(Responder)]
+pub enum CheckResponse{
+    Json(Json<CheckResult>),
+    Text(String),
+}
+
+#[test]
+fn test_check() -> Result<()> {
+    let thresholds = Thresholds::new(Some(10), Some(100))?;
+    assert_eq!(thresholds.status(0), CheckStatus::Ok);
+    assert_eq!(thresholds.status(9), CheckStatus::Ok);
+    assert_eq!(thresholds.status(10), CheckStatus::Warn);
+    assert_eq!(thresholds.status(100), CheckStatus::Crit);
+    assert_eq!(Thresholds::new(None, Some(1))?.status(5), CheckStatus::Crit);
+    assert_eq!(Thresholds::new(None, None)?.status(5), CheckStatus::Ok);
+    assert!(Thresholds::new(Some(100), Some(10)).is_err());
+
+    let result = CheckResult::new("level=error", "5m", 300, thresholds, 12);
+    assert_eq!(result.status, CheckStatus::Warn);
+    assert_eq!(result.status.exit_code(), 1);
+    assert_eq!(result.message, "WARN - 12 logs matching 'level=error' in the last 5m | count=12;10;100;0");
+    let json = serde_json::to_value(&result)?;
+    assert_eq!(json["status"], "WARN");
+    assert_eq!(json["warn"], 10);
+    Ok(())
+}
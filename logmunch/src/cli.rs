@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+use anyhow::Result;
+
+use crate::agent::AgentSettings;
+use crate::client::{Backoff, Client};
+use crate::import::{Import, ImportFormat, ImportSettings};
+use crate::minute_db::{SearchOptions, SortOrder};
+use crate::offline::OfflineDirectory;
+use crate::search_token::Search;
+use crate::soak::SoakSettings;
+
+///
+/// `logmunch <command>` runs one of these against a data directory (or, for soak and agent, a running server) instead of starting the server
+///
+pub const COMMANDS: [&str; 8] = ["query", "histogram", "minutes", "verify", "import", "export", "soak", "agent"];
+
+const USAGE: &str = "usage:
+    logmunch query <search> [--from <time>] [--to <time>] [--limit <n>] [--order asc|desc] [--json]
+    logmunch histogram <search> [--bucket 1m] [--from <time>] [--to <time>]
+    logmunch minutes [--json]
+    logmunch verify
+    logmunch import <file|dir> [--host <host>] [--format text|jsonl] [--timestamp-regex <regex>] [--timestamp-format <chrono format>] [--timestamp-field <field>]
+    logmunch export <file.parquet> [--query <search>] [--from <time>] [--to <time>] [--columns <field,field>] [--json]
+    logmunch soak <server url> [--rate <events/s>] [--duration 1h] [--settle 3m] [--token <token>] [--json]
+    logmunch agent <server url> [--journald] [--journal-directory <dir>] [--docker] [--docker-directory <dir>] [--checkpoint <file>] [--host <host>] [--backfill] [--token <token>]
+
+every command but soak and agent takes --data-directory <dir> (default: DATA_DIRECTORY, or data_directory in logmunch.toml) and --tenant <name>.
+times are seconds since the epoch, timestamps (2024-03-16T04:21:27Z), or -<duration> ago (-15m, -2h, -1d).";
+
+pub fn is_command(arg: Option<&String>) -> bool {
+    arg.is_some_and(|arg| COMMANDS.contains(&arg.as_str()))
+}
+
+///
+/// `args` doesn't include the program name. Returns the exit code: 0 for fine, 1 for problems found, 2 for a bad command line.
+///
+pub fn run(args: &[String]) -> i32 {
+    let command = match Command::parse(args){
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return 2;
+        }
+    };
+    match command.run(){
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Command{
+    name: String,
+    positional: Vec<String>,
+    flags: HashMap<String, String>,
+}
+
+///
+/// Flags that don't take a value
+///
+const SWITCHES: [&str; 4] = ["json", "journald", "docker", "backfill"];
+
+impl Command{
+    fn parse(args: &[String]) -> Result<Command> {
+        let name = args.first().filter(|name| COMMANDS.contains(&name.as_str()))
+            .ok_or_else(|| anyhow::anyhow!("which command? ({})", COMMANDS.join(", ")))?
+            .to_string();
+        let mut positional = Vec::new();
+        let mut flags = HashMap::new();
+        let mut args = args[1..].iter();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--"){
+                Some(flag) if SWITCHES.contains(&flag) => { flags.insert(flag.to_string(), "true".to_string()); },
+                Some(flag) => {
+                    let value = args.next().ok_or_else(|| anyhow::anyhow!("--{} needs a value", flag))?;
+                    flags.insert(flag.to_string(), value.to_string());
+                },
+                None => positional.push(arg.to_string()),
+            }
+        }
+        let expected = match name.as_str(){
+            "query" | "histogram" | "import" | "export" | "soak" | "agent" => 1,
+            _ => 0,
+        };
+        if positional.len() != expected {
+            return Err(anyhow::anyhow!("{} takes {} argument(s), not {}: quote your search, or your path", name, expected, positional.len()));
+        }
+        Ok(Command{ name, positional, flags })
+    }
+
+    fn flag(&self, name: &str) -> Option<&str> {
+        self.flags.get(name).map(|value| value.as_str())
+    }
+
+    fn data_directory(&self) -> Result<String> {
+        let data_directory = match self.flag("data-directory"){
+            Some(data_directory) => data_directory.to_string(),
+            None => crate::config::Config::load()?.data_directory,
+        };
+        Ok(match self.flag("tenant"){
+            Some(tenant) => format!("{}/{}", data_directory.trim_end_matches('/'), tenant),
+            None => data_directory,
+        })
+    }
+
+    fn search_options(&self, now: i64) -> Result<SearchOptions> {
+        let mut options = SearchOptions{
+            from: self.flag("from").map(|time| parse_time(time, now)).transpose()?,
+            to: self.flag("to").map(|time| parse_time(time, now)).transpose()?,
+            ..SearchOptions::default()
+        };
+        if let Some(limit) = self.flag("limit") {
+            options.limit = limit.parse().map_err(|_| anyhow::anyhow!("--limit should be a number, not '{}'", limit))?;
+        }
+        if let Some(order) = self.flag("order") {
+            options.order = SortOrder::parse(order)?;
+        }
+        Ok(options)
+    }
+
+    fn import_settings(&self) -> Result<ImportSettings> {
+        Ok(ImportSettings{
+            format: self.flag("format").map(ImportFormat::parse).transpose()?,
+            host: self.flag("host").map(|host| host.to_string()),
+            timestamp_regex: self.flag("timestamp-regex").map(|regex| regex.to_string()),
+            timestamp_format: self.flag("timestamp-format").map(|format| format.to_string()),
+            timestamp_field: self.flag("timestamp-field").map(|field| field.to_string()),
+        })
+    }
+
+    ///
+    /// Unlike everything else here, this writes: the data directory gets made if it isn't there yet.
+    /// A running server picks up the new minutes on its own, within a few seconds.
+    ///
+    fn import(&self) -> Result<i32> {
+        let config = crate::config::Config::load()?;
+        let settings = self.import_settings()?;
+        let data_directory = crate::bootstrap::DataDirectory::bootstrap(&self.data_directory()?, config.min_free_disk_bytes(), 0)?;
+        let mut import = Import::from_config(&config, &data_directory.minutes);
+        import.set_host_rules(std::sync::Arc::new(crate::host_rules::HostRules::from_config(&config)?));
+        import.read_path(&self.positional[0], &settings)?;
+        let report = import.finish()?;
+        if self.flag("json").is_some() {
+            println!("{}", serde_json::to_string(&report)?);
+        }
+        else {
+            println!("imported {} events from {} files into {} minutes ({} without a timestamp of their own, {} skipped), {} to {}",
+                report.events, report.files, report.minutes,
+                report.events - report.timestamped, report.skipped,
+                report.earliest.map(format_time).unwrap_or("-".to_string()),
+                report.latest.map(format_time).unwrap_or("-".to_string()));
+        }
+        Ok(0)
+    }
+
+    ///
+    /// Every sealed minute in range (everything, without --from and --to) as one Parquet file: see parquet_export
+    ///
+    fn export(&self, directory: &OfflineDirectory, now: i64) -> Result<i32> {
+        let options = self.search_options(now)?;
+        let columns: Vec<String> = self.flag("columns").unwrap_or_default().split(',')
+            .map(|column| column.trim().to_string()).filter(|column| !column.is_empty()).collect();
+        let search = Search::parse(self.flag("query").unwrap_or_default())?;
+        let (minute_db, _unsealed) = directory.minute_db()?;
+        let file = std::fs::File::create(&self.positional[0]).map_err(|e| anyhow::anyhow!("Can't write {}: {}", self.positional[0], e))?;
+        let mut export = crate::parquet_export::ParquetExport::new(std::io::BufWriter::new(file), &columns)?;
+        minute_db.export(&search, options.from.unwrap_or(i64::MIN), options.to.unwrap_or(i64::MAX), &mut export)?;
+        let (writer, report) = export.finish()?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        warn_unsealed(report.skipped);
+        if self.flag("json").is_some() {
+            println!("{}", serde_json::to_string(&report)?);
+        }
+        else {
+            println!("exported {} logs from {} minutes to {}", report.logs, report.minutes, self.positional[0]);
+        }
+        Ok(0)
+    }
+
+    fn soak_settings(&self) -> Result<SoakSettings> {
+        let mut settings = SoakSettings::default();
+        if let Some(rate) = self.flag("rate") {
+            settings.rate = rate.parse().ok().filter(|rate| *rate > 0).ok_or_else(|| anyhow::anyhow!("--rate should be a number of events a second, not '{}'", rate))?;
+        }
+        if let Some(duration) = self.flag("duration") {
+            settings.duration = Duration::from_secs(crate::minute_db::parse_bucket(duration)? as u64);
+        }
+        if let Some(settle) = self.flag("settle") {
+            settings.settle = Duration::from_secs(crate::minute_db::parse_bucket(settle)? as u64);
+        }
+        Ok(settings)
+    }
+
+    ///
+    /// This one doesn't go near a data directory: it's a client of a running server (see soak::run).
+    /// Exits 1 if anything the server accepted went missing, or came back twice.
+    ///
+    fn soak(&self) -> Result<i32> {
+        let settings = self.soak_settings()?;
+        let mut client = Client::new(&self.positional[0]);
+        if let Some(token) = self.flag("token") {
+            client = client.with_token(token);
+        }
+        let report = crate::soak::run(&client, &settings)?;
+        if self.flag("json").is_some() {
+            println!("{}", serde_json::to_string(&report)?);
+        }
+        else {
+            println!("{}", report);
+        }
+        Ok(if report.ok() { 0 } else { 1 })
+    }
+
+    fn agent_settings(&self) -> Result<AgentSettings> {
+        let mut settings = AgentSettings{
+            journald: self.flag("journald").is_some() || self.flag("journal-directory").is_some(),
+            journal_directory: self.flag("journal-directory").map(|directory| directory.to_string()),
+            docker: (self.flag("docker").is_some() || self.flag("docker-directory").is_some())
+                .then(|| self.flag("docker-directory").unwrap_or(crate::agent::DOCKER_CONTAINERS_DIRECTORY).to_string()),
+            backfill: self.flag("backfill").is_some(),
+            ..AgentSettings::default()
+        };
+        if let Some(checkpoint) = self.flag("checkpoint") {
+            settings.checkpoint = checkpoint.to_string();
+        }
+        if let Some(host) = self.flag("host") {
+            settings.host = host.to_string();
+        }
+        if !settings.journald && settings.docker.is_none() {
+            return Err(anyhow::anyhow!("agent needs something to tail: --journald, --docker, or both"));
+        }
+        Ok(settings)
+    }
+
+    ///
+    /// Like soak, a client of a running server: this one doesn't stop (see agent::run), so it only exits when something's badly wrong.
+    /// The client never gives up on a busy server, since giving up would mean dropping logs.
+    ///
+    fn agent(&self) -> Result<i32> {
+        let settings = self.agent_settings()?;
+        let mut client = Client::new(&self.positional[0]).with_backoff(Backoff{ max_attempts: u32::MAX, ..Backoff::default() });
+        if let Some(token) = self.flag("token") {
+            client = client.with_token(token);
+        }
+        tracing::info!("agent: sending to {}", self.positional[0]);
+        crate::agent::run(&client, &settings)?;
+        Ok(0)
+    }
+
+    fn run(&self) -> Result<i32> {
+        if self.name == "import" {
+            return self.import();
+        }
+        if self.name == "soak" {
+            return self.soak();
+        }
+        if self.name == "agent" {
+            return self.agent();
+        }
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+        let directory = OfflineDirectory::open(&self.data_directory()?)?;
+        let json = self.flag("json").is_some();
+
+        match self.name.as_str(){
+            "query" => {
+                let options = self.search_options(now)?;
+                let (minute_db, unsealed) = directory.minute_db()?;
+                warn_unsealed(unsealed);
+                let (logs, stats) = minute_db.search_with_stats(Search::parse(&self.positional[0])?, &options)?;
+                for log in &logs {
+                    if json {
+                        println!("{}", serde_json::to_string(log)?);
+                    }
+                    else {
+                        println!("{} {} {}", format_time(log.time / 1000000), log.host, log.message);
+                    }
+                }
+                eprintln!("{} logs ({} minutes considered, {} scanned)", logs.len(), stats.minutes_considered, stats.minutes_scanned);
+                Ok(0)
+            },
+            "histogram" => {
+                let options = self.search_options(now)?;
+                let bucket_seconds = crate::minute_db::parse_bucket(self.flag("bucket").unwrap_or("1m"))?;
+                let (minute_db, unsealed) = directory.minute_db()?;
+                warn_unsealed(unsealed);
+                let (buckets, _stats) = minute_db.histogram(Search::parse(&self.positional[0])?, &options, bucket_seconds)?;
+                for bucket in buckets {
+                    println!("{}\t{}", format_time(bucket.time), bucket.count);
+                }
+                Ok(0)
+            },
+            "minutes" => {
+                for minute in directory.minutes()? {
+                    if json {
+                        println!("{}", serde_json::to_string(&minute)?);
+                    }
+                    else {
+                        println!("{}\t{}\t{} bytes\t{} events\t{}",
+                            minute.minute_id,
+                            format_time(minute.time),
+                            minute.size_bytes,
+                            minute.events.map(|events| events.to_string()).unwrap_or("?".to_string()),
+                            if minute.sealed { "sealed" } else { "UNSEALED" });
+                    }
+                }
+                Ok(0)
+            },
+            "verify" => {
+                let reports = directory.verify()?;
+                let bad: Vec<_> = reports.iter().filter(|report| !report.problems.is_empty()).collect();
+                for report in &bad {
+                    println!("{}: {}", report.minute_id, report.problems.join("; "));
+                }
+                println!("verified {} minutes: {} with problems", reports.len(), bad.len());
+                Ok(if bad.is_empty() { 0 } else { 1 })
+            },
+            "export" => self.export(&directory, now),
+            other => Err(anyhow::anyhow!("unknown command {}", other)),
+        }
+    }
+}
+
+fn warn_unsealed(unsealed: usize) {
+    if unsealed > 0 {
+        eprintln!("warning: {} unsealed minutes can't be searched (start logmunch on this directory to seal them)", unsealed);
+    }
+}
+
+fn format_time(seconds: i64) -> String {
+    chrono::DateTime::from_timestamp(seconds, 0).map(|time| time.to_rfc3339()).unwrap_or(seconds.to_string())
+}
+
+///
+/// Seconds since the epoch, out of whatever people are likely to type
+///
+fn parse_time(time: &str, now: i64) -> Result<i64> {
+    if let Ok(seconds) = time.parse::<i64>() {
+        return Ok(seconds);
+    }
+    if let Some(ago) = time.strip_prefix('-') {
+        return Ok(now - crate::minute_db::parse_bucket(ago)?);
+    }
+    crate::text_ingest::parse_timestamp(time).map(|micros| micros / 1000000)
+        .ok_or_else(|| anyhow::anyhow!("'{}' isn't a time we understand", time))
+}
+
+#[test]
+fn test_parse_command() -> Result<()> {
+    let args = |args: &str| args.split(' ').map(|arg| arg.to_string()).collect::<Vec<String>>();
+
+    let command = Command::parse(&args("query error --from -15m --json --limit 5"))?;
+    assert_eq!(command.positional, vec!["error".to_string()]);
+    assert_eq!(command.flag("from"), Some("-15m"));
+    assert_eq!(command.flag("json"), Some("true"));
+    let options = command.search_options(10000)?;
+    assert_eq!(options.from, Some(10000 - 900));
+    assert_eq!(options.to, None);
+    assert_eq!(options.limit, 5);
+
+    assert!(Command::parse(&args("minutes")).is_ok());
+    assert_eq!(Command::parse(&args("export logs.parquet --columns user_id,status"))?.flag("columns"), Some("user_id,status"));
+    assert!(Command::parse(&args("export")).is_err());
+    let command = Command::parse(&args("import logs/ --format jsonl --timestamp-field when"))?;
+    assert_eq!(command.positional, vec!["logs/".to_string()]);
+    let settings = command.import_settings()?;
+    assert_eq!(settings.format, Some(ImportFormat::Jsonl));
+    assert_eq!(settings.timestamp_field.as_deref(), Some("when"));
+    assert!(Command::parse(&args("import logs/ --format xml"))?.import_settings().is_err());
+    let settings = Command::parse(&args("soak http://localhost:8000 --rate 50 --duration 6h"))?.soak_settings()?;
+    assert_eq!((settings.rate, settings.duration, settings.settle), (50, Duration::from_secs(6 * 3600), Duration::from_secs(180)));
+    assert!(Command::parse(&args("soak http://localhost:8000 --rate 0"))?.soak_settings().is_err());
+    assert!(Command::parse(&args("soak")).is_err());
+    let settings = Command::parse(&args("agent http://localhost:8000 --docker --journald --checkpoint /var/lib/agent.json --host box"))?.agent_settings()?;
+    assert_eq!((settings.journald, settings.docker.as_deref(), settings.checkpoint.as_str(), settings.host.as_str()),
+        (true, Some(crate::agent::DOCKER_CONTAINERS_DIRECTORY), "/var/lib/agent.json", "box"));
+    assert_eq!(Command::parse(&args("agent http://localhost:8000 --docker-directory /tmp/containers"))?.agent_settings()?.docker.as_deref(), Some("/tmp/containers"));
+    assert!(Command::parse(&args("agent http://localhost:8000"))?.agent_settings().is_err());
+    assert!(Command::parse(&args("query")).is_err());
+    assert!(Command::parse(&args("query error --limit")).is_err());
+    assert!(Command::parse(&args("serve")).is_err());
+    assert!(!is_command(Some(&"serve".to_string())));
+
+    assert_eq!(parse_time("1710562887", 0)?, 1710562887);
+    assert_eq!(parse_time("2024-03-16T04:21:27Z", 0)?, 1710562887);
+    assert_eq!(parse_time("-1h", 7200)?, 3600);
+    assert!(parse_time("last tuesday", 0).is_err());
+    Ok(())
+}
@@ -0,0 +1,634 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::time::Duration;
+use anyhow::Result;
+use rocket::tokio;
+use serde::de::DeserializeOwned;
+
+use crate::WritableEvent;
+use crate::cardinality::Cardinality;
+use crate::federation::PeerStatus;
+use crate::handshake::Handshake;
+use crate::markers::{Marker, NewMarker};
+use crate::minute::Log;
+use crate::minute_db::{DeleteReport, Explanation, HistogramBucket, Hosts, SearchStats, SortOrder, StatsResult};
+use crate::minute_id::MinuteId;
+use crate::minute_labels::{LabelReport, MinuteLabel};
+use crate::replication::{Manifest, ReplicationStatus};
+use crate::write_stats::WriteAmplificationReport;
+
+///
+/// The server only takes 10MiB of HEC at a time: ingest splits bigger batches up so they stay under this
+///
+const MAX_INGEST_BATCH_BYTES: usize = 8 * 1024 * 1024;
+
+///
+/// How long to keep trying when the server's busy (503, 429) or can't be reached: `initial`, doubling every attempt up to `max`.
+/// If the server says how long to wait (Retry-After), we wait that long instead.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backoff{
+    /// 1 means don't retry at all
+    pub max_attempts: u32,
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff{
+    fn default() -> Self {
+        Backoff{
+            max_attempts: 5,
+            initial: Duration::from_millis(200),
+            max: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Backoff{
+    ///
+    /// How long to wait after `attempt` (counting from 1) didn't work out
+    ///
+    fn delay(&self, attempt: u32, retry_after_seconds: Option<u64>) -> Duration {
+        match retry_after_seconds{
+            Some(seconds) => Duration::from_secs(seconds).min(self.max),
+            None => self.initial.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1))).min(self.max),
+        }
+    }
+}
+
+///
+/// Everything about a search that isn't the search string: the query parameters of /search/<search> and friends.
+/// from/to are seconds since the epoch. Leave anything out and the server (and your token's policy) decides.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchQuery{
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub max_per_minute: Option<usize>,
+    pub order: Option<SortOrder>,
+    pub case_sensitive: bool,
+    pub whole_word: bool,
+}
+
+impl SearchQuery{
+    fn parameters(&self) -> Vec<(&'static str, String)> {
+        let mut parameters = Vec::new();
+        let numbers = [("from", self.from), ("to", self.to), ("limit", self.limit.map(|n| n as i64)),
+            ("offset", self.offset.map(|n| n as i64)), ("max_per_minute", self.max_per_minute.map(|n| n as i64))];
+        for (name, value) in numbers {
+            if let Some(value) = value {
+                parameters.push((name, value.to_string()));
+            }
+        }
+        match self.order{
+            Some(SortOrder::Ascending) => parameters.push(("order", "asc".to_string())),
+            Some(SortOrder::Descending) => parameters.push(("order", "desc".to_string())),
+            None => {},
+        }
+        if self.case_sensitive {
+            parameters.push(("case_sensitive", "true".to_string()));
+        }
+        if self.whole_word {
+            parameters.push(("whole_word", "true".to_string()));
+        }
+        parameters
+    }
+}
+
+///
+/// A Rust client for the HTTP API, so services don't have to hand-roll requests to it:
+///
+/// ```no_run
+/// use logmunch::client::{Client, SearchQuery};
+///
+/// let client = Client::new("http://logmunch:8000").with_token("abc123");
+/// client.ingest(&[logmunch::WritableEvent::new("level=error checkout failed", 1710562887000000, "web-1")])?;
+/// let logs = client.search("checkout failed", &SearchQuery{ from: Some(1710562800), ..Default::default() })?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// Every call has an `_async` twin for async code (they run the call on tokio's blocking pool, same as MinuteDB::search_async).
+/// Busy servers (503, 429) and dropped connections get retried with backoff (see Backoff); anything else that isn't a 2xx is an error
+/// with whatever the server said about it.
+///
+#[derive(Clone)]
+pub struct Client{
+    base_url: String,
+    /// sent as `Authorization: Bearer <token>`: an admin token for the admin calls
+    token: Option<String>,
+    /// the tenancy header and which tenant to ask for (see tenant::Tenancy)
+    tenant: Option<(String, String)>,
+    /// anything else to send with every request (a federated search's traceparent, say)
+    headers: Vec<(String, String)>,
+    backoff: Backoff,
+    agent: ureq::Agent,
+}
+
+impl Client{
+    pub fn new(base_url: &str) -> Client {
+        Client{
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: None,
+            tenant: None,
+            headers: Vec::new(),
+            backoff: Backoff::default(),
+            agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(60)).build(),
+        }
+    }
+
+    pub fn with_token(mut self, token: &str) -> Client {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    pub fn with_tenant(mut self, header: &str, tenant: &str) -> Client {
+        self.tenant = Some((header.to_string(), tenant.to_string()));
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Client {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Client {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    ///
+    /// Give up on a call that takes longer than this (60 seconds, otherwise)
+    ///
+    pub fn with_timeout(mut self, timeout: Duration) -> Client {
+        self.agent = ureq::AgentBuilder::new().timeout(timeout).build();
+        self
+    }
+
+    fn url(&self, path: &str, parameters: &[(&str, String)]) -> String {
+        let mut url = format!("{}{}", self.base_url, path);
+        for (i, (name, value)) in parameters.iter().enumerate() {
+            url.push(if i == 0 { '?' } else { '&' });
+            url.push_str(name);
+            url.push('=');
+            url.push_str(&encode(value));
+        }
+        url
+    }
+
+    ///
+    /// Send a request (again and again, if the server's busy), and hand back the body of the first 2xx
+    ///
+    fn call(&self, method: &str, url: &str, body: Option<&str>) -> Result<String> {
+        Ok(self.send(method, url, body)?.into_string()?)
+    }
+
+    ///
+    /// `call`, for when the body isn't text (or is too big to want in memory all at once)
+    ///
+    fn send(&self, method: &str, url: &str, body: Option<&str>) -> Result<ureq::Response> {
+        let mut attempt = 0;
+        loop{
+            attempt += 1;
+            let mut request = self.agent.request(method, url);
+            if let Some(token) = &self.token {
+                request = request.set("Authorization", &format!("Bearer {}", token));
+            }
+            if let Some((header, tenant)) = &self.tenant {
+                request = request.set(header, tenant);
+            }
+            for (name, value) in &self.headers {
+                request = request.set(name, value);
+            }
+            let result = match body{
+                Some(body) => request.set("Content-Type", "application/json").send_string(body),
+                None => request.call(),
+            };
+            let retry_after = match result{
+                Ok(response) => return Ok(response),
+                Err(ureq::Error::Status(status @ (429 | 503), response)) if attempt < self.backoff.max_attempts => {
+                    let retry_after = response.header("Retry-After").and_then(|seconds| seconds.parse().ok());
+                    tracing::warn!("{} {} was turned away ({}): trying again", method, url, status);
+                    retry_after
+                },
+                Err(ureq::Error::Status(status, response)) => {
+                    let message = response.into_string().unwrap_or_default();
+                    return Err(anyhow::anyhow!("{} {} failed ({}): {}", method, url, status, message));
+                },
+                Err(ureq::Error::Transport(transport)) if attempt < self.backoff.max_attempts => {
+                    tracing::warn!("Couldn't reach {} ({}): trying again", url, transport);
+                    None
+                },
+                Err(ureq::Error::Transport(transport)) => return Err(anyhow::anyhow!("Couldn't reach {}: {}", url, transport)),
+            };
+            std::thread::sleep(self.backoff.delay(attempt, retry_after));
+        }
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str, parameters: &[(&str, String)]) -> Result<T> {
+        Ok(serde_json::from_str(&self.call("GET", &self.url(path, parameters), None)?)?)
+    }
+
+    ///
+    /// Run a call on tokio's blocking pool, for the `_async` versions
+    ///
+    async fn blocking<T: Send + 'static>(&self, call: impl FnOnce(Client) -> Result<T> + Send + 'static) -> Result<T> {
+        let client = self.clone();
+        tokio::task::spawn_blocking(move || call(client)).await?
+    }
+
+    ///
+    /// Send events to the HEC endpoint, in as many batches as it takes. If a batch fails, the ones before it are already in.
+    ///
+    pub fn ingest(&self, events: &[WritableEvent]) -> Result<()> {
+        for batch in hec_batches(events, MAX_INGEST_BATCH_BYTES) {
+            self.call("POST", &self.url("/services/collector/event/1.0", &[]), Some(&batch))?;
+        }
+        Ok(())
+    }
+
+    pub async fn ingest_async(&self, events: Vec<WritableEvent>) -> Result<()> {
+        self.blocking(move |client| client.ingest(&events)).await
+    }
+
+    pub fn search(&self, search: &str, query: &SearchQuery) -> Result<Vec<Log>> {
+        self.get(&format!("/search/{}", encode(search)), &query.parameters())
+    }
+
+    pub async fn search_async(&self, search: &str, query: SearchQuery) -> Result<Vec<Log>> {
+        let search = search.to_string();
+        self.blocking(move |client| client.search(&search, &query)).await
+    }
+
+    ///
+    /// `search`, along with where the server says the time went (X-Logmunch-Trace: one SearchStats per node it searched)
+    ///
+    pub fn search_with_stats(&self, search: &str, query: &SearchQuery) -> Result<(Vec<Log>, Vec<SearchStats>)> {
+        let response = self.send("GET", &self.url(&format!("/search/{}", encode(search)), &query.parameters()), None)?;
+        let stats = match response.header("X-Logmunch-Trace"){
+            Some(trace) => serde_json::from_str(trace)?,
+            None => Vec::new(),
+        };
+        Ok((serde_json::from_str(&response.into_string()?)?, stats))
+    }
+
+    pub async fn search_with_stats_async(&self, search: &str, query: SearchQuery) -> Result<(Vec<Log>, Vec<SearchStats>)> {
+        let search = search.to_string();
+        self.blocking(move |client| client.search_with_stats(&search, &query)).await
+    }
+
+    ///
+    /// `bucket` is like "1m" or "1h" (the server's default is 1m)
+    ///
+    pub fn histogram(&self, search: &str, bucket: Option<&str>, query: &SearchQuery) -> Result<Vec<HistogramBucket>> {
+        let mut parameters = query.parameters();
+        if let Some(bucket) = bucket {
+            parameters.push(("bucket", bucket.to_string()));
+        }
+        self.get(&format!("/search/{}/histogram", encode(search)), &parameters)
+    }
+
+    pub async fn histogram_async(&self, search: &str, bucket: Option<&str>, query: SearchQuery) -> Result<Vec<HistogramBucket>> {
+        let (search, bucket) = (search.to_string(), bucket.map(str::to_string));
+        self.blocking(move |client| client.histogram(&search, bucket.as_deref(), &query)).await
+    }
+
+    ///
+    /// `by` is "host", "level", or any key=value field
+    ///
+    pub fn stats(&self, search: &str, by: &str, top: Option<usize>, query: &SearchQuery) -> Result<StatsResult> {
+        let mut parameters = query.parameters();
+        parameters.push(("by", by.to_string()));
+        if let Some(top) = top {
+            parameters.push(("top", top.to_string()));
+        }
+        self.get(&format!("/search/{}/stats", encode(search)), &parameters)
+    }
+
+    pub async fn stats_async(&self, search: &str, by: &str, top: Option<usize>, query: SearchQuery) -> Result<StatsResult> {
+        let (search, by) = (search.to_string(), by.to_string());
+        self.blocking(move |client| client.stats(&search, &by, top, &query)).await
+    }
+
+    pub fn explain(&self, search: &str, query: &SearchQuery) -> Result<Explanation> {
+        self.get(&format!("/search/{}/explain", encode(search)), &query.parameters())
+    }
+
+    ///
+    /// About how many different values `field` had from `from` to `to` (seconds since the epoch): the server has to be
+    /// keeping sketches of it (cardinality_fields)
+    ///
+    pub fn cardinality(&self, field: &str, from: Option<i64>, to: Option<i64>) -> Result<Cardinality> {
+        let mut parameters = vec![("field", field.to_string())];
+        parameters.extend(from.map(|from| ("from", from.to_string())));
+        parameters.extend(to.map(|to| ("to", to.to_string())));
+        self.get("/api/v1/cardinality", &parameters)
+    }
+
+    pub async fn cardinality_async(&self, field: &str, from: Option<i64>, to: Option<i64>) -> Result<Cardinality> {
+        let field = field.to_string();
+        self.blocking(move |client| client.cardinality(&field, from, to)).await
+    }
+
+    ///
+    /// Every host that sent logs from `from` to `to` (seconds since the epoch), busiest first
+    ///
+    pub fn hosts(&self, from: Option<i64>, to: Option<i64>) -> Result<Hosts> {
+        let mut parameters = Vec::new();
+        parameters.extend(from.map(|from| ("from", from.to_string())));
+        parameters.extend(to.map(|to| ("to", to.to_string())));
+        self.get("/hosts", &parameters)
+    }
+
+    pub async fn hosts_async(&self, from: Option<i64>, to: Option<i64>) -> Result<Hosts> {
+        self.blocking(move |client| client.hosts(from, to)).await
+    }
+
+    ///
+    /// One log, by the event_id a search gave it
+    ///
+    pub fn log(&self, event_id: &str) -> Result<Log> {
+        self.get(&format!("/log/{}", encode(event_id)), &[])
+    }
+
+    pub async fn log_async(&self, event_id: &str) -> Result<Log> {
+        let event_id = event_id.to_string();
+        self.blocking(move |client| client.log(&event_id)).await
+    }
+
+    ///
+    /// The sealed minutes from `from` to `to` as a Parquet file, into `path` (see parquet_export): how many bytes that was
+    ///
+    pub fn export(&self, from: i64, to: i64, query: Option<&str>, columns: &[String], path: &Path) -> Result<u64> {
+        let mut parameters = vec![("from", from.to_string()), ("to", to.to_string())];
+        parameters.extend(query.map(|query| ("query", query.to_string())));
+        if !columns.is_empty() {
+            parameters.push(("columns", columns.join(",")));
+        }
+        let response = self.send("GET", &self.url("/export", &parameters), None)?;
+        let mut file = std::fs::File::create(path)?;
+        let bytes = std::io::copy(&mut response.into_reader(), &mut file)?;
+        file.sync_all()?;
+        Ok(bytes)
+    }
+
+    ///
+    /// Follow a search from `from` (seconds since the epoch) onwards: see Tail
+    ///
+    pub fn tail(&self, search: &str, from: i64) -> Tail {
+        Tail{
+            client: self.clone(),
+            search: search.to_string(),
+            from,
+            seen: HashSet::new(),
+        }
+    }
+
+    pub fn add_marker(&self, marker: &NewMarker) -> Result<Marker> {
+        let body = serde_json::to_string(marker)?;
+        Ok(serde_json::from_str(&self.call("POST", &self.url("/api/v1/markers", &[]), Some(&body))?)?)
+    }
+
+    pub async fn add_marker_async(&self, marker: NewMarker) -> Result<Marker> {
+        self.blocking(move |client| client.add_marker(&marker)).await
+    }
+
+    pub fn markers(&self, from: Option<i64>, to: Option<i64>, kind: Option<&str>) -> Result<Vec<Marker>> {
+        let mut parameters = Vec::new();
+        parameters.extend(from.map(|from| ("from", from.to_string())));
+        parameters.extend(to.map(|to| ("to", to.to_string())));
+        parameters.extend(kind.map(|kind| ("kind", kind.to_string())));
+        self.get("/api/v1/markers", &parameters)
+    }
+
+    pub fn remove_marker(&self, id: u64) -> Result<()> {
+        self.call("DELETE", &self.url(&format!("/api/v1/markers/{}", id), &[]), None)?;
+        Ok(())
+    }
+
+    ///
+    /// Admin: bulk-remove minutes from `from` to `to` (seconds since the epoch), archiving them first if `archive`.
+    /// The admin calls take a `tenant` instead of using the tenancy header; None is the default tenant.
+    ///
+    pub fn delete_minutes(&self, from: i64, to: i64, archive: bool, tenant: Option<&str>) -> Result<DeleteReport> {
+        let mut parameters = vec![("from", from.to_string()), ("to", to.to_string()), ("archive", archive.to_string())];
+        parameters.extend(tenant.map(|tenant| ("tenant", tenant.to_string())));
+        Ok(serde_json::from_str(&self.call("DELETE", &self.url("/admin/minutes", &parameters), None)?)?)
+    }
+
+    pub async fn delete_minutes_async(&self, from: i64, to: i64, archive: bool, tenant: Option<&str>) -> Result<DeleteReport> {
+        let tenant = tenant.map(str::to_string);
+        self.blocking(move |client| client.delete_minutes(from, to, archive, tenant.as_deref())).await
+    }
+
+    pub fn write_amplification(&self, tenant: Option<&str>) -> Result<WriteAmplificationReport> {
+        let parameters: Vec<(&str, String)> = tenant.map(|tenant| ("tenant", tenant.to_string())).into_iter().collect();
+        self.get("/admin/write_amplification", &parameters)
+    }
+
+    pub async fn write_amplification_async(&self, tenant: Option<&str>) -> Result<WriteAmplificationReport> {
+        let tenant = tenant.map(str::to_string);
+        self.blocking(move |client| client.write_amplification(tenant.as_deref())).await
+    }
+
+    ///
+    /// Admin: label every minute from `from` to `to` (seconds since the epoch)
+    ///
+    pub fn label_minutes(&self, from: i64, to: i64, labels: &BTreeMap<String, String>, tenant: Option<&str>) -> Result<LabelReport> {
+        let mut parameters = vec![("from", from.to_string()), ("to", to.to_string())];
+        parameters.extend(tenant.map(|tenant| ("tenant", tenant.to_string())));
+        let body = serde_json::to_string(labels)?;
+        Ok(serde_json::from_str(&self.call("PUT", &self.url("/admin/minute_labels", &parameters), Some(&body))?)?)
+    }
+
+    pub fn minute_labels(&self, from: Option<i64>, to: Option<i64>, label: Option<&str>, tenant: Option<&str>) -> Result<Vec<MinuteLabel>> {
+        let mut parameters = Vec::new();
+        parameters.extend(from.map(|from| ("from", from.to_string())));
+        parameters.extend(to.map(|to| ("to", to.to_string())));
+        parameters.extend(label.map(|label| ("label", label.to_string())));
+        parameters.extend(tenant.map(|tenant| ("tenant", tenant.to_string())));
+        self.get("/admin/minute_labels", &parameters)
+    }
+
+    pub fn unlabel_minutes(&self, from: i64, to: i64, key: Option<&str>, tenant: Option<&str>) -> Result<LabelReport> {
+        let mut parameters = vec![("from", from.to_string()), ("to", to.to_string())];
+        parameters.extend(key.map(|key| ("key", key.to_string())));
+        parameters.extend(tenant.map(|tenant| ("tenant", tenant.to_string())));
+        Ok(serde_json::from_str(&self.call("DELETE", &self.url("/admin/minute_labels", &parameters), None)?)?)
+    }
+
+    pub fn handshake(&self) -> Result<Handshake> {
+        self.get("/api/v1/handshake", &[])
+    }
+
+    ///
+    /// Admin: every minute the server has sealed (see replication::Manifest)
+    ///
+    pub fn replication_manifest(&self, tenant: Option<&str>) -> Result<Manifest> {
+        let parameters: Vec<(&str, String)> = tenant.map(|tenant| ("tenant", tenant.to_string())).into_iter().collect();
+        self.get("/admin/replication/manifest", &parameters)
+    }
+
+    ///
+    /// Admin: copy one sealed minute's file into `path`: how many bytes that was
+    ///
+    pub fn download_minute(&self, minute_id: &MinuteId, tenant: Option<&str>, path: &Path) -> Result<u64> {
+        let mut parameters = vec![("day", minute_id.day.to_string()), ("hour", minute_id.hour.to_string()),
+            ("minute", minute_id.minute.to_string()), ("unique_id", minute_id.unique_id.clone())];
+        parameters.extend(tenant.map(|tenant| ("tenant", tenant.to_string())));
+        let response = self.send("GET", &self.url("/admin/replication/minute", &parameters), None)?;
+        let mut file = std::fs::File::create(path)?;
+        let bytes = std::io::copy(&mut response.into_reader(), &mut file)?;
+        file.sync_all()?;
+        Ok(bytes)
+    }
+
+    ///
+    /// Admin, on a standby: how far behind it is, for every tenant
+    ///
+    pub fn replication_status(&self) -> Result<Vec<ReplicationStatus>> {
+        self.get("/admin/replication", &[])
+    }
+
+    ///
+    /// Admin, on a standby: stop copying from the primary and start taking ingest
+    ///
+    pub fn promote(&self) -> Result<Vec<ReplicationStatus>> {
+        Ok(serde_json::from_str(&self.call("POST", &self.url("/admin/promote", &[]), None)?)?)
+    }
+
+    ///
+    /// Admin: what the server knows about each of its federation peers (see federation::Federation)
+    ///
+    pub fn federation_status(&self) -> Result<Vec<PeerStatus>> {
+        self.get("/admin/federation", &[])
+    }
+}
+
+///
+/// A search that keeps going: every `poll` returns whatever's matched since the last one, oldest first.
+/// Minutes only become searchable once they're sealed, so a tail runs a minute or two behind.
+///
+pub struct Tail{
+    client: Client,
+    search: String,
+    /// where the next poll starts, in seconds since the epoch
+    from: i64,
+    /// logs in the second we're starting from that we've already handed out (the next poll will see them again)
+    seen: HashSet<(i64, i64, Option<String>)>,
+}
+
+impl Tail{
+    pub fn poll(&mut self) -> Result<Vec<Log>> {
+        let query = SearchQuery{ from: Some(self.from), order: Some(SortOrder::Ascending), ..Default::default() };
+        let logs = self.client.search(&self.search, &query)?;
+        Ok(self.take_new(logs))
+    }
+
+    pub async fn poll_async(&mut self) -> Result<Vec<Log>> {
+        let query = SearchQuery{ from: Some(self.from), order: Some(SortOrder::Ascending), ..Default::default() };
+        let logs = self.client.search_async(&self.search, query).await?;
+        Ok(self.take_new(logs))
+    }
+
+    ///
+    /// Drop what we've already handed out, and move `from` up to the newest second we've seen
+    ///
+    fn take_new(&mut self, logs: Vec<Log>) -> Vec<Log> {
+        let logs: Vec<Log> = logs.into_iter().filter(|log| !self.seen.contains(&(log.time, log.id, log.shard.clone()))).collect();
+        if let Some(newest) = logs.iter().map(|log| log.time.div_euclid(1000000)).max() {
+            if newest > self.from {
+                self.from = newest;
+                self.seen.clear();
+            }
+        }
+        let from = self.from;
+        self.seen.extend(logs.iter().filter(|log| log.time.div_euclid(1000000) == from).map(|log| (log.time, log.id, log.shard.clone())));
+        logs
+    }
+}
+
+///
+/// HEC bodies (one JSON event after another) of at most `max_bytes` each, unless a single event is bigger than that on its own
+///
+fn hec_batches(events: &[WritableEvent], max_bytes: usize) -> Vec<String> {
+    let mut batches = Vec::new();
+    let mut batch = String::new();
+    for event in events {
+        let event = serde_json::json!({
+            "event": event.event,
+            "host": event.host,
+            "time": format!("{}.{:06}", event.time.div_euclid(1000000), event.time.rem_euclid(1000000)),
+        }).to_string();
+        if !batch.is_empty() && batch.len() + event.len() > max_bytes {
+            batches.push(std::mem::take(&mut batch));
+        }
+        batch.push_str(&event);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+///
+/// Percent-encode everything but letters, digits and -._~, so a search can go in a path segment or a query string
+///
+fn encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte{
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[test]
+fn test_client_requests() {
+    assert_eq!(encode("level=error !(timeout | 5xx)"), "level%3Derror%20%21%28timeout%20%7C%205xx%29");
+    assert_eq!(encode("héllo"), "h%C3%A9llo");
+
+    let client = Client::new("http://logmunch:8000/");
+    let query = SearchQuery{ from: Some(100), limit: Some(10), order: Some(SortOrder::Ascending), whole_word: true, ..Default::default() };
+    assert_eq!(client.url(&format!("/search/{}", encode("user=42")), &query.parameters()),
+        "http://logmunch:8000/search/user%3D42?from=100&limit=10&order=asc&whole_word=true");
+    assert_eq!(client.url("/api/v1/markers", &[]), "http://logmunch:8000/api/v1/markers");
+
+    let events: Vec<WritableEvent> = (0..10).map(|i| WritableEvent::new(&format!("event {}", i), 1710562887000042 + i, "web-1")).collect();
+    let batches = hec_batches(&events, 250);
+    assert!(batches.len() > 1);
+    let parsed: Vec<serde_json::Value> = batches.iter()
+        .flat_map(|batch| serde_json::Deserializer::from_str(batch).into_iter::<serde_json::Value>().map(|event| event.unwrap()))
+        .collect();
+    assert_eq!(parsed.len(), 10);
+    assert_eq!(parsed[3]["time"], "1710562887.000045");
+    assert_eq!(parsed[3]["event"], "event 3");
+}
+
+#[test]
+fn test_backoff() {
+    let backoff = Backoff::default();
+    assert_eq!(backoff.delay(1, None), Duration::from_millis(200));
+    assert_eq!(backoff.delay(3, None), Duration::from_millis(800));
+    assert_eq!(backoff.delay(30, None), Duration::from_secs(10));
+    // the server knows best, within reason
+    assert_eq!(backoff.delay(1, Some(5)), Duration::from_secs(5));
+    assert_eq!(backoff.delay(1, Some(3600)), Duration::from_secs(10));
+}
+
+#[test]
+fn test_tail() {
+    let mut tail = Client::new("http://logmunch:8000").tail("error", 100);
+    let log = |id: i64, seconds: i64| Log{ id, message: "error".to_string(), time: seconds * 1000000, host: "web-1".to_string(), shard: None, event_id: None, minute_id: None };
+
+    assert_eq!(tail.take_new(vec![log(1, 100), log(2, 101), log(3, 101)]).len(), 3);
+    assert_eq!(tail.from, 101);
+    // the next poll starts at 101 again, so it sees 2 and 3 again: only 4 is new
+    assert_eq!(tail.take_new(vec![log(2, 101), log(3, 101), log(4, 101)]).iter().map(|log| log.id).collect::<Vec<i64>>(), vec![4]);
+    assert_eq!(tail.take_new(vec![log(2, 101), log(3, 101), log(4, 101)]).len(), 0);
+    assert_eq!(tail.take_new(vec![log(4, 101), log(5, 102)]).iter().map(|log| log.id).collect::<Vec<i64>>(), vec![5]);
+    assert_eq!(tail.from, 102);
+}
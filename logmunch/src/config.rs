@@ -0,0 +1,952 @@
+use std::fs;
+use anyhow::Result;
+use serde::Deserialize;
+
+///
+/// About what a busy minute's bloom filter costs in RAM. The bloom cache keeps count of what its blooms really take up
+/// (see MinuteDB::set_bloom_budget): this is only for checking the budget isn't hopelessly small.
+///
+pub const ESTIMATED_MINUTE_BLOOM_SIZE_BYTES: u64 = 650000;
+
+///
+/// Every cardinality field is 4KB more in every minute (and, for anything but host, another pass over its logs at seal time)
+///
+pub const MAX_CARDINALITY_FIELDS: usize = 16;
+
+///
+/// Everything we need to know to boot, out of logmunch.toml (or wherever LOGMUNCH_CONFIG points),
+/// with environment variables on top: every setting can be overridden by the env var with the same name in capitals
+/// (MINUTE_DB_RAM_GB, DATA_DIRECTORY, ...). Leave a setting out and you get the default.
+///
+/// ```toml
+/// data_directory = "/var/lib/logmunch"
+/// machine_id = 3
+/// minute_db_ram_gb = 4.0
+/// minute_db_disk_gb = 200.0
+/// max_write_threads = 8
+/// ```
+///
+/// The only environment variables that aren't settings: LOGMUNCH_CONFIG (it's how we find the settings), HOSTNAME
+/// (the machine's name, for syslog, forward acks and the agent), and LOG_LEVEL for `logmunch <command>` (commands log before they've
+/// loaded any config, and most of them never do).
+///
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config{
+    /// where the minute files (and everything else) go
+    pub data_directory: String,
+    /// tells our minutes apart from other nodes' minutes
+    pub machine_id: u32,
+    /// RAM for minutes' bloom filters: this decides how many minutes we keep
+    pub minute_db_ram_gb: f64,
+    /// disk for the minutes themselves
+    pub minute_db_disk_gb: f64,
+    /// how much free disk we insist on before we'll even start
+    pub min_free_disk_mb: u64,
+    /// most threads writing minutes at once (they're started as they're needed, and then stick around, one shard each)
+    pub max_write_threads: u32,
+    /// longest an event waits in the queue before it's written: lower is fresher (and, with HEC acks, acked sooner), higher is fewer, bigger transactions
+    pub flush_interval_ms: u64,
+    /// write as soon as this many events are waiting, instead of waiting out the interval (no limit, if it's not set)
+    pub flush_max_events: Option<usize>,
+    /// store repeated identical messages once per batch: great for chatty healthchecks
+    pub dedup_messages: bool,
+    /// write every batch to a journal (see ingest_journal) before it's queued and acknowledged, and replay whatever
+    /// didn't get committed after a crash
+    pub ingest_journal: bool,
+    /// fsync the journal at most this often, instead of before every acknowledgement: 0 is every batch
+    pub ingest_journal_fsync_ms: u64,
+    /// how big a journal segment gets before the next one's started (segments are deleted once everything in them is committed)
+    pub ingest_journal_segment_mb: u64,
+    /// drop HEC events we've already queued in the last this many seconds (see hec::HecDedup), so forwarders' retries
+    /// don't show up twice: 0 is off
+    pub hec_dedup_seconds: u64,
+    /// ...remembering at most this many events, per tenant
+    pub hec_dedup_max_events: usize,
+    /// turns on HEC indexer acknowledgement (see hec::HecAcks), and fsyncs every commit, so an ack means the data is on disk
+    pub hec_acks: bool,
+    /// events this late still go into the minute they happened in (see ShardedMinute::set_max_lateness):
+    /// every minute waits this long after it ends before it's sealed and searchable
+    pub max_lateness_seconds: u64,
+    /// the most results one search can ask for (limit plus offset), whatever the token's policy says
+    pub max_search_limit: usize,
+    /// a search that's been going this long stops where it is, and hands back what it found so far (marked truncated): 0 lets it take as long as it takes
+    pub search_timeout_ms: u64,
+    /// most searches (histograms, stats, streams and exports too) running at once, so a burst of them can't starve the writer: 0 is no limit
+    pub max_concurrent_searches: usize,
+    /// how long a search past max_concurrent_searches waits for its turn before it's turned away with a 429: 0 turns it away straight off
+    pub search_queue_ms: u64,
+    /// most of those any one tenant can have running at once, so one tenant's dashboards can't take every slot: 0 is no limit of its own.
+    /// Federated searches count against the tenant on every node they reach
+    pub max_concurrent_searches_per_tenant: usize,
+    /// how many minutes a single search looks through at once: wide time ranges go faster, at the cost of hogging more cores
+    pub search_threads: u32,
+    /// how many (query, minute) results histograms and stats remember per tenant, so repeated dashboard queries only
+    /// have to look at new minutes: 0 turns the cache off
+    pub query_cache_entries: usize,
+    /// searches go into degraded mode (see load_shedding) when any tenant's ingest queue is this full, in percent: 0 never does
+    pub degraded_queue_percent: u64,
+    /// ...or when there's less than this much memory available: 0 never does
+    pub degraded_min_available_memory_mb: u64,
+    /// in degraded mode, searches only look at this many minutes back from their `to`
+    pub degraded_max_range_minutes: u64,
+    /// in degraded mode, searches return at most this many results
+    pub degraded_max_limit: usize,
+    /// minutes that nobody has searched in this long get their connections closed
+    pub reaper_idle_minutes: u64,
+    /// minutes older than this many days are deleted, on top of the disk limit (fractions are fine: 0.5 is twelve hours).
+    /// Leave it out to keep minutes until the disk's full
+    pub retention_days: Option<f64>,
+    /// imported minutes count as being as old as their file, not their logs (see retention::RetentionPolicy)
+    pub retention_imported_by_arrival: bool,
+    /// how many events can be waiting for the writer in the bulk lane before ingest gets turned away (a few hundred MB of typical log lines)
+    pub ingest_queue_events: usize,
+    /// ...and in the interactive lane
+    pub ingest_interactive_queue_events: usize,
+    /// the biggest batch that counts as interactive: 0 puts everything in bulk
+    pub ingest_interactive_max_events: usize,
+    /// how many interactive events the writer takes for every bulk one, when both are waiting
+    pub ingest_interactive_weight: usize,
+    /// archive minutes to this directory before retention deletes them (see archive::Archiver)
+    pub archive_directory: Option<String>,
+    /// ...or to this S3 bucket
+    pub archive_s3_bucket: Option<String>,
+    /// ...in this region
+    pub archive_s3_region: String,
+    /// https://s3.<region>.amazonaws.com, if it's not set
+    pub archive_s3_endpoint: Option<String>,
+    /// the bucket's credentials (as AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, like everywhere else)
+    pub aws_access_key_id: Option<String>,
+    pub aws_secret_access_key: Option<String>,
+    /// put a Parquet copy of every minute we archive next to it
+    pub archive_parquet: bool,
+    /// ...with these fields pulled out of the messages into columns of their own (ARCHIVE_PARQUET_COLUMNS=user_id,status)
+    pub archive_parquet_columns: Vec<String>,
+    /// how much disk archived minutes can take up, per tenant, while searches that reach back that far are using them: 0 turns rehydration off
+    pub rehydrate_scratch_gb: f64,
+    /// fields that get a distinct-count sketch in every minute, for /api/v1/cardinality: "host", or any key=value field.
+    /// Anything other than host means reading every log again when its minute is sealed (CARDINALITY_FIELDS=host,user_id)
+    pub cardinality_fields: Vec<String>,
+    /// "lz4", or "zstd": sealing a minute trains a zstd dictionary on its logs and compresses them all again with it.
+    /// Several times smaller on disk (so several times the retention), for some CPU when each minute's sealed and searched
+    pub log_compression: String,
+    /// turns on the /admin endpoints, for whoever has it
+    pub admin_token: Option<String>,
+    /// turns on the OTLP/gRPC receiver (OTLP/HTTP is always on, at /v1/logs)
+    pub otlp_grpc_port: Option<u16>,
+    /// turns on the Fluentd/Fluent Bit forward protocol listener (see fluent.rs), usually 24224
+    pub fluent_forward_port: Option<u16>,
+    /// what a forwarded record's tag becomes: "field" (a tag=... field; the host comes from the record) or "host"
+    pub fluent_forward_tag: String,
+    /// turns on the GELF (Graylog) input, UDP and TCP both on this port (see gelf.rs), usually 12201
+    pub gelf_port: Option<u16>,
+    /// serve HTTPS (and OTLP/gRPC over TLS) with this cert chain, in PEM: Splunk forwarders usually won't send HEC without it
+    pub tls_cert_path: Option<String>,
+    /// ...and this key, in PEM
+    pub tls_key_path: Option<String>,
+    /// check client certs against this CA, in PEM (see tls::Tls)
+    pub tls_client_ca_path: Option<String>,
+    /// turn away clients that don't have a cert signed by tls_client_ca_path
+    pub tls_client_cert_required: bool,
+    /// how often we look for a renewed cert: a new one relaunches the listeners, not the server
+    pub tls_reload_seconds: u64,
+    /// makes this node a warm standby for the primary at this URL: it copies over every minute the primary seals,
+    /// and turns ingest away until it's promoted (POST /admin/promote). See replication::Standby
+    pub standby_of: Option<String>,
+    /// the primary's admin token: the standby needs it to read the primary's manifest and minutes
+    pub standby_admin_token: Option<String>,
+    /// how often the standby asks the primary what's new
+    pub standby_interval_seconds: u64,
+    /// other nodes to search along with this one (see federation): each search goes to the ones that have minutes for the caller's tenant
+    pub federation_peers: Vec<String>,
+    /// how often we ask the peers which tenants they have
+    pub federation_interval_seconds: u64,
+    /// most events a second any one sender can send us (see rate_limit): no limit, if it's not set
+    pub ingest_rate_events_per_second: Option<u64>,
+    /// ...and most bytes a second
+    pub ingest_rate_bytes_per_second: Option<u64>,
+    /// who counts as one sender: "token" (senders without a token are told apart by address) or "host" (by address, whatever the token)
+    pub ingest_rate_key: String,
+    /// how our own logs come out (see logging): "full", "compact", "pretty" (several lines each, for reading at a terminal),
+    /// or "json" (one object a line, for shipping somewhere)
+    pub log_format: String,
+    /// which of our own logs come out: a level ("info"), or a level per module ("info,logmunch::minute_db=debug")
+    pub log_level: String,
+    /// also log every span (ingest batches, minute writes, seals, searches) when it finishes, with how long it took
+    pub log_spans: bool,
+    /// a JSON file of which tokens belong to which tenant: each tenant gets its own data directory (see tenant::Tenancy)
+    pub tenants: Option<String>,
+    /// a JSON file of per-token search defaults and ceilings (see token_policy)
+    pub token_policies: Option<String>,
+    /// a JSON file of keys and what they're for: ingest, search, admin (see auth::ApiKeys)
+    pub api_keys: Option<String>,
+    /// locks down ingest to these tokens (INGEST_TOKENS=a,b,c)...
+    pub ingest_tokens: Vec<String>,
+    /// ...except from these networks
+    pub ingest_trusted_cidrs: Vec<String>,
+    /// a JSON file of rules that clean up hostnames, at ingest and in searches (see host_rules)
+    pub host_rules: Option<String>,
+    /// a Rhai script (or a directory of them, one per tenant) that can change or drop events before they're written (see ingest_script)
+    pub ingest_script: Option<String>,
+    /// most operations a script gets per event
+    pub ingest_script_max_operations: u64,
+    /// ...and most milliseconds
+    pub ingest_script_timeout_ms: u64,
+    /// a JSON file of rules that turn logs into Prometheus counters and histograms as they're written (see log_metrics)
+    pub log_metrics: Option<String>,
+    /// a JSON file of saved searches to run every few minutes, and the webhooks to call when they cross a threshold (see alerts)
+    pub alerts: Option<String>,
+    /// where the audit trail of searches and admin actions goes: udp://host:514 or tcp://host:601...
+    pub audit_syslog: Option<String>,
+    /// ...or a URL to POST it to
+    pub audit_webhook: Option<String>,
+    /// the webhook's bearer token, if it wants one
+    pub audit_webhook_token: Option<String>,
+    /// "json" or "cef"
+    pub audit_format: String,
+    /// a MaxMind database (.mmdb) that adds country (and city) fields for every IP address in search results (see lookups)
+    pub geoip_database: Option<String>,
+    /// a JSON file of host -> fields (team, env, whatever) that adds them to search results too
+    pub host_map: Option<String>,
+    /// how often the lookup tables check whether their files have changed
+    pub lookup_reload_seconds: u64,
+}
+
+impl Default for Config{
+    fn default() -> Config {
+        // mathin' it out: 1 day (1440 minutes) should occupy about 270MB of RAM, and .... 144GB of disk
+        //  this is based on the assumption that each minute occupies 1.5MB of RAM and 100MB of disk
+        //  and that our ShardedMinuteWriter isn't writing more than one Minute object per minute
+        //      (which it starts to do past 3000 lines/s or 180000 lines/m)
+        Config{
+            data_directory: "./data/".to_string(),
+            machine_id: 1,
+            minute_db_ram_gb: 1.8,
+            minute_db_disk_gb: 30.0,
+            min_free_disk_mb: 100,
+            max_write_threads: 8,
+            flush_interval_ms: 1000,
+            flush_max_events: None,
+            dedup_messages: false,
+            ingest_journal: false,
+            ingest_journal_fsync_ms: 0,
+            ingest_journal_segment_mb: 64,
+            hec_dedup_seconds: 0,
+            hec_dedup_max_events: 1000000,
+            hec_acks: false,
+            max_lateness_seconds: 60,
+            max_search_limit: 100000,
+            search_timeout_ms: 30000,
+            max_concurrent_searches: 8,
+            search_queue_ms: 5000,
+            max_concurrent_searches_per_tenant: 0,
+            search_threads: 4,
+            query_cache_entries: 100000,
+            degraded_queue_percent: 50,
+            degraded_min_available_memory_mb: 256,
+            degraded_max_range_minutes: 15,
+            degraded_max_limit: 1000,
+            reaper_idle_minutes: 10,
+            retention_days: None,
+            retention_imported_by_arrival: false,
+            ingest_queue_events: 1000000,
+            ingest_interactive_queue_events: 10000,
+            ingest_interactive_max_events: 100,
+            ingest_interactive_weight: 8,
+            archive_directory: None,
+            archive_s3_bucket: None,
+            archive_s3_region: "us-east-1".to_string(),
+            archive_s3_endpoint: None,
+            aws_access_key_id: None,
+            aws_secret_access_key: None,
+            archive_parquet: false,
+            archive_parquet_columns: Vec::new(),
+            rehydrate_scratch_gb: 2.0,
+            cardinality_fields: vec!["host".to_string()],
+            log_compression: "lz4".to_string(),
+            admin_token: None,
+            otlp_grpc_port: None,
+            fluent_forward_port: None,
+            fluent_forward_tag: "field".to_string(),
+            gelf_port: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_client_ca_path: None,
+            tls_client_cert_required: false,
+            tls_reload_seconds: 60,
+            standby_of: None,
+            standby_admin_token: None,
+            standby_interval_seconds: 10,
+            federation_peers: Vec::new(),
+            federation_interval_seconds: 10,
+            ingest_rate_events_per_second: None,
+            ingest_rate_bytes_per_second: None,
+            ingest_rate_key: "token".to_string(),
+            log_format: "full".to_string(),
+            log_level: "info".to_string(),
+            log_spans: false,
+            tenants: None,
+            token_policies: None,
+            api_keys: None,
+            ingest_tokens: Vec::new(),
+            ingest_trusted_cidrs: vec!["127.0.0.0/8".to_string(), "::1".to_string()],
+            host_rules: None,
+            ingest_script: None,
+            ingest_script_max_operations: crate::ingest_script::DEFAULT_MAX_OPERATIONS,
+            ingest_script_timeout_ms: crate::ingest_script::DEFAULT_TIMEOUT_MS,
+            log_metrics: None,
+            alerts: None,
+            audit_syslog: None,
+            audit_webhook: None,
+            audit_webhook_token: None,
+            audit_format: "json".to_string(),
+            geoip_database: None,
+            host_map: None,
+            lookup_reload_seconds: 60,
+        }
+    }
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str, value: &str, what: &str) -> Result<T> {
+    value.trim().parse::<T>().map_err(|_| anyhow::anyhow!("{} should be {}, not '{}'", name, what, value))
+}
+
+impl Config{
+    ///
+    /// LOGMUNCH_CONFIG is the config file; without it, we use ./logmunch.toml if there is one.
+    ///
+    pub fn load() -> Result<Config> {
+        let mut config = match std::env::var("LOGMUNCH_CONFIG"){
+            Ok(path) => Self::load_file(&path)?,
+            Err(_) if fs::metadata("logmunch.toml").is_ok() => Self::load_file("logmunch.toml")?,
+            Err(_) => Config::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        Ok(config)
+    }
+
+    pub fn load_file(path: &str) -> Result<Config> {
+        let contents = fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Could not read config from {}: {}", path, e))?;
+        Self::parse(&contents).map_err(|e| anyhow::anyhow!("Could not parse config in {}: {}", path, e))
+    }
+
+    pub fn parse(toml: &str) -> Result<Config> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    pub fn apply_env(&mut self, env: impl Fn(&str) -> Option<String>) -> Result<()> {
+        if let Some(value) = env("DATA_DIRECTORY") {
+            self.data_directory = value;
+        }
+        if let Some(value) = env("MACHINE_ID") {
+            self.machine_id = parse_env("MACHINE_ID", &value, "a whole number")?;
+        }
+        if let Some(value) = env("MINUTE_DB_RAM_GB") {
+            self.minute_db_ram_gb = parse_env("MINUTE_DB_RAM_GB", &value, "a number of gigabytes")?;
+        }
+        if let Some(value) = env("MINUTE_DB_DISK_GB") {
+            self.minute_db_disk_gb = parse_env("MINUTE_DB_DISK_GB", &value, "a number of gigabytes")?;
+        }
+        if let Some(value) = env("MIN_FREE_DISK_MB") {
+            self.min_free_disk_mb = parse_env("MIN_FREE_DISK_MB", &value, "a whole number of megabytes")?;
+        }
+        if let Some(value) = env("MAX_WRITE_THREADS") {
+            self.max_write_threads = parse_env("MAX_WRITE_THREADS", &value, "a whole number")?;
+        }
+        if let Some(value) = env("FLUSH_INTERVAL_MS") {
+            self.flush_interval_ms = parse_env("FLUSH_INTERVAL_MS", &value, "a whole number of milliseconds")?;
+        }
+        if let Some(value) = env("FLUSH_MAX_EVENTS") {
+            self.flush_max_events = Some(parse_env("FLUSH_MAX_EVENTS", &value, "a whole number of events")?);
+        }
+        if let Some(value) = env("DEDUP_MESSAGES") {
+            self.dedup_messages = value == "true" || value == "1";
+        }
+        if let Some(value) = env("INGEST_JOURNAL") {
+            self.ingest_journal = value == "true" || value == "1";
+        }
+        if let Some(value) = env("INGEST_JOURNAL_FSYNC_MS") {
+            self.ingest_journal_fsync_ms = parse_env("INGEST_JOURNAL_FSYNC_MS", &value, "a whole number of milliseconds")?;
+        }
+        if let Some(value) = env("INGEST_JOURNAL_SEGMENT_MB") {
+            self.ingest_journal_segment_mb = parse_env("INGEST_JOURNAL_SEGMENT_MB", &value, "a whole number of megabytes")?;
+        }
+        if let Some(value) = env("HEC_DEDUP_SECONDS") {
+            self.hec_dedup_seconds = parse_env("HEC_DEDUP_SECONDS", &value, "a whole number of seconds")?;
+        }
+        if let Some(value) = env("HEC_DEDUP_MAX_EVENTS") {
+            self.hec_dedup_max_events = parse_env("HEC_DEDUP_MAX_EVENTS", &value, "a whole number of events")?;
+        }
+        if let Some(value) = env("HEC_ACKS") {
+            self.hec_acks = value == "true" || value == "1";
+        }
+        if let Some(value) = env("MAX_LATENESS_SECONDS") {
+            self.max_lateness_seconds = parse_env("MAX_LATENESS_SECONDS", &value, "a whole number of seconds")?;
+        }
+        if let Some(value) = env("MAX_SEARCH_LIMIT") {
+            self.max_search_limit = parse_env("MAX_SEARCH_LIMIT", &value, "a whole number of results")?;
+        }
+        if let Some(value) = env("SEARCH_TIMEOUT_MS") {
+            self.search_timeout_ms = parse_env("SEARCH_TIMEOUT_MS", &value, "a whole number of milliseconds")?;
+        }
+        if let Some(value) = env("MAX_CONCURRENT_SEARCHES") {
+            self.max_concurrent_searches = parse_env("MAX_CONCURRENT_SEARCHES", &value, "a whole number of searches")?;
+        }
+        if let Some(value) = env("SEARCH_QUEUE_MS") {
+            self.search_queue_ms = parse_env("SEARCH_QUEUE_MS", &value, "a whole number of milliseconds")?;
+        }
+        if let Some(value) = env("MAX_CONCURRENT_SEARCHES_PER_TENANT") {
+            self.max_concurrent_searches_per_tenant = parse_env("MAX_CONCURRENT_SEARCHES_PER_TENANT", &value, "a whole number of searches")?;
+        }
+        if let Some(value) = env("SEARCH_THREADS") {
+            self.search_threads = parse_env("SEARCH_THREADS", &value, "a whole number")?;
+        }
+        if let Some(value) = env("QUERY_CACHE_ENTRIES") {
+            self.query_cache_entries = parse_env("QUERY_CACHE_ENTRIES", &value, "a whole number of entries")?;
+        }
+        if let Some(value) = env("DEGRADED_QUEUE_PERCENT") {
+            self.degraded_queue_percent = parse_env("DEGRADED_QUEUE_PERCENT", &value, "a whole number of percent")?;
+        }
+        if let Some(value) = env("DEGRADED_MIN_AVAILABLE_MEMORY_MB") {
+            self.degraded_min_available_memory_mb = parse_env("DEGRADED_MIN_AVAILABLE_MEMORY_MB", &value, "a whole number of megabytes")?;
+        }
+        if let Some(value) = env("DEGRADED_MAX_RANGE_MINUTES") {
+            self.degraded_max_range_minutes = parse_env("DEGRADED_MAX_RANGE_MINUTES", &value, "a whole number of minutes")?;
+        }
+        if let Some(value) = env("DEGRADED_MAX_LIMIT") {
+            self.degraded_max_limit = parse_env("DEGRADED_MAX_LIMIT", &value, "a whole number of results")?;
+        }
+        if let Some(value) = env("REAPER_IDLE_MINUTES") {
+            self.reaper_idle_minutes = parse_env("REAPER_IDLE_MINUTES", &value, "a whole number of minutes")?;
+        }
+        if let Some(value) = env("RETENTION_DAYS") {
+            self.retention_days = Some(parse_env("RETENTION_DAYS", &value, "a number of days")?);
+        }
+        if let Some(value) = env("RETENTION_IMPORTED_BY_ARRIVAL") {
+            self.retention_imported_by_arrival = value == "true" || value == "1";
+        }
+        if let Some(value) = env("INGEST_QUEUE_EVENTS") {
+            self.ingest_queue_events = parse_env("INGEST_QUEUE_EVENTS", &value, "a whole number of events")?;
+        }
+        if let Some(value) = env("INGEST_INTERACTIVE_QUEUE_EVENTS") {
+            self.ingest_interactive_queue_events = parse_env("INGEST_INTERACTIVE_QUEUE_EVENTS", &value, "a whole number of events")?;
+        }
+        if let Some(value) = env("INGEST_INTERACTIVE_MAX_EVENTS") {
+            self.ingest_interactive_max_events = parse_env("INGEST_INTERACTIVE_MAX_EVENTS", &value, "a whole number of events")?;
+        }
+        if let Some(value) = env("INGEST_INTERACTIVE_WEIGHT") {
+            self.ingest_interactive_weight = parse_env("INGEST_INTERACTIVE_WEIGHT", &value, "a whole number")?;
+        }
+        if let Some(value) = env("ARCHIVE_DIRECTORY") {
+            self.archive_directory = Some(value);
+        }
+        if let Some(value) = env("ARCHIVE_S3_BUCKET") {
+            self.archive_s3_bucket = Some(value);
+        }
+        if let Some(value) = env("ARCHIVE_S3_REGION") {
+            self.archive_s3_region = value;
+        }
+        if let Some(value) = env("ARCHIVE_S3_ENDPOINT") {
+            self.archive_s3_endpoint = Some(value);
+        }
+        if let Some(value) = env("AWS_ACCESS_KEY_ID") {
+            self.aws_access_key_id = Some(value);
+        }
+        if let Some(value) = env("AWS_SECRET_ACCESS_KEY") {
+            self.aws_secret_access_key = Some(value);
+        }
+        if let Some(value) = env("ARCHIVE_PARQUET") {
+            self.archive_parquet = value == "true" || value == "1";
+        }
+        if let Some(value) = env("ARCHIVE_PARQUET_COLUMNS") {
+            self.archive_parquet_columns = value.split(',').map(|column| column.trim().to_string()).filter(|column| !column.is_empty()).collect();
+        }
+        if let Some(value) = env("REHYDRATE_SCRATCH_GB") {
+            self.rehydrate_scratch_gb = parse_env("REHYDRATE_SCRATCH_GB", &value, "a number of gigabytes")?;
+        }
+        if let Some(value) = env("CARDINALITY_FIELDS") {
+            self.cardinality_fields = value.split(',').map(|field| field.trim().to_string()).filter(|field| !field.is_empty()).collect();
+        }
+        if let Some(value) = env("LOG_COMPRESSION") {
+            self.log_compression = value;
+        }
+        if let Some(value) = env("ADMIN_TOKEN") {
+            self.admin_token = Some(value);
+        }
+        if let Some(value) = env("OTLP_GRPC_PORT") {
+            self.otlp_grpc_port = Some(parse_env("OTLP_GRPC_PORT", &value, "a port number")?);
+        }
+        if let Some(value) = env("FLUENT_FORWARD_PORT") {
+            self.fluent_forward_port = Some(parse_env("FLUENT_FORWARD_PORT", &value, "a port number")?);
+        }
+        if let Some(value) = env("FLUENT_FORWARD_TAG") {
+            self.fluent_forward_tag = value;
+        }
+        if let Some(value) = env("GELF_PORT") {
+            self.gelf_port = Some(parse_env("GELF_PORT", &value, "a port number")?);
+        }
+        if let Some(value) = env("TLS_CERT_PATH") {
+            self.tls_cert_path = Some(value);
+        }
+        if let Some(value) = env("TLS_KEY_PATH") {
+            self.tls_key_path = Some(value);
+        }
+        if let Some(value) = env("TLS_CLIENT_CA_PATH") {
+            self.tls_client_ca_path = Some(value);
+        }
+        if let Some(value) = env("TLS_CLIENT_CERT_REQUIRED") {
+            self.tls_client_cert_required = value == "true" || value == "1";
+        }
+        if let Some(value) = env("TLS_RELOAD_SECONDS") {
+            self.tls_reload_seconds = parse_env("TLS_RELOAD_SECONDS", &value, "a whole number of seconds")?;
+        }
+        if let Some(value) = env("STANDBY_OF") {
+            self.standby_of = Some(value);
+        }
+        if let Some(value) = env("STANDBY_ADMIN_TOKEN") {
+            self.standby_admin_token = Some(value);
+        }
+        if let Some(value) = env("STANDBY_INTERVAL_SECONDS") {
+            self.standby_interval_seconds = parse_env("STANDBY_INTERVAL_SECONDS", &value, "a whole number of seconds")?;
+        }
+        if let Some(value) = env("FEDERATION_PEERS") {
+            self.federation_peers = value.split(',').map(|peer| peer.trim().to_string()).filter(|peer| !peer.is_empty()).collect();
+        }
+        if let Some(value) = env("FEDERATION_INTERVAL_SECONDS") {
+            self.federation_interval_seconds = parse_env("FEDERATION_INTERVAL_SECONDS", &value, "a whole number of seconds")?;
+        }
+        if let Some(value) = env("INGEST_RATE_EVENTS_PER_SECOND") {
+            self.ingest_rate_events_per_second = Some(parse_env("INGEST_RATE_EVENTS_PER_SECOND", &value, "a whole number of events")?);
+        }
+        if let Some(value) = env("INGEST_RATE_BYTES_PER_SECOND") {
+            self.ingest_rate_bytes_per_second = Some(parse_env("INGEST_RATE_BYTES_PER_SECOND", &value, "a whole number of bytes")?);
+        }
+        if let Some(value) = env("INGEST_RATE_KEY") {
+            self.ingest_rate_key = value;
+        }
+        if let Some(value) = env("LOG_FORMAT") {
+            self.log_format = value;
+        }
+        if let Some(value) = env("LOG_LEVEL") {
+            self.log_level = value;
+        }
+        if let Some(value) = env("LOG_SPANS") {
+            self.log_spans = value == "true" || value == "1";
+        }
+        if let Some(value) = env("TENANTS") {
+            self.tenants = Some(value);
+        }
+        if let Some(value) = env("TOKEN_POLICIES") {
+            self.token_policies = Some(value);
+        }
+        if let Some(value) = env("API_KEYS") {
+            self.api_keys = Some(value);
+        }
+        if let Some(value) = env("INGEST_TOKENS") {
+            self.ingest_tokens = value.split(',').map(|token| token.trim().to_string()).filter(|token| !token.is_empty()).collect();
+        }
+        if let Some(value) = env("INGEST_TRUSTED_CIDRS") {
+            self.ingest_trusted_cidrs = value.split(',').map(|cidr| cidr.trim().to_string()).filter(|cidr| !cidr.is_empty()).collect();
+        }
+        if let Some(value) = env("HOST_RULES") {
+            self.host_rules = Some(value);
+        }
+        if let Some(value) = env("INGEST_SCRIPT") {
+            self.ingest_script = Some(value);
+        }
+        if let Some(value) = env("INGEST_SCRIPT_MAX_OPERATIONS") {
+            self.ingest_script_max_operations = parse_env("INGEST_SCRIPT_MAX_OPERATIONS", &value, "a whole number")?;
+        }
+        if let Some(value) = env("INGEST_SCRIPT_TIMEOUT_MS") {
+            self.ingest_script_timeout_ms = parse_env("INGEST_SCRIPT_TIMEOUT_MS", &value, "a whole number of milliseconds")?;
+        }
+        if let Some(value) = env("LOG_METRICS") {
+            self.log_metrics = Some(value);
+        }
+        if let Some(value) = env("ALERTS") {
+            self.alerts = Some(value);
+        }
+        if let Some(value) = env("AUDIT_SYSLOG") {
+            self.audit_syslog = Some(value);
+        }
+        if let Some(value) = env("AUDIT_WEBHOOK") {
+            self.audit_webhook = Some(value);
+        }
+        if let Some(value) = env("AUDIT_WEBHOOK_TOKEN") {
+            self.audit_webhook_token = Some(value);
+        }
+        if let Some(value) = env("AUDIT_FORMAT") {
+            self.audit_format = value;
+        }
+        if let Some(value) = env("GEOIP_DATABASE") {
+            self.geoip_database = Some(value);
+        }
+        if let Some(value) = env("HOST_MAP") {
+            self.host_map = Some(value);
+        }
+        if let Some(value) = env("LOOKUP_RELOAD_SECONDS") {
+            self.lookup_reload_seconds = parse_env("LOOKUP_RELOAD_SECONDS", &value, "a whole number of seconds")?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Catch settings that would have us fall over later, with something to do about it.
+    /// `n_tenants` share the RAM and disk.
+    ///
+    pub fn validate(&self, n_tenants: u64) -> Result<()> {
+        if self.data_directory.trim().is_empty() {
+            return Err(anyhow::anyhow!("data_directory can't be empty"));
+        }
+        if self.minute_db_ram_gb.is_nan() || self.minute_db_ram_gb <= 0.0 {
+            return Err(anyhow::anyhow!("minute_db_ram_gb has to be more than 0 (it's {})", self.minute_db_ram_gb));
+        }
+        if self.minute_db_disk_gb.is_nan() || self.minute_db_disk_gb <= 0.0 {
+            return Err(anyhow::anyhow!("minute_db_disk_gb has to be more than 0 (it's {})", self.minute_db_disk_gb));
+        }
+        if let Some(days) = self.retention_days {
+            if !days.is_finite() || days <= 0.0 {
+                return Err(anyhow::anyhow!("retention_days has to be more than 0 (it's {}): leave it out to keep minutes until the disk's full", days));
+            }
+        }
+        if self.ingest_queue_events == 0 {
+            return Err(anyhow::anyhow!("ingest_queue_events has to be at least 1"));
+        }
+        if self.ingest_interactive_queue_events < self.ingest_interactive_max_events.max(1) {
+            return Err(anyhow::anyhow!("ingest_interactive_queue_events has to be at least ingest_interactive_max_events (and at least 1)"));
+        }
+        if self.ingest_interactive_weight == 0 {
+            return Err(anyhow::anyhow!("ingest_interactive_weight has to be at least 1"));
+        }
+        if self.archive_directory.is_some() && self.archive_s3_bucket.is_some() {
+            return Err(anyhow::anyhow!("archive_directory and archive_s3_bucket are two places to archive to: pick one"));
+        }
+        if self.archive_s3_bucket.is_some() && (self.aws_access_key_id.is_none() || self.aws_secret_access_key.is_none()) {
+            return Err(anyhow::anyhow!("archive_s3_bucket needs aws_access_key_id and aws_secret_access_key (or AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY) too"));
+        }
+        if self.archive_parquet {
+            // find out now, not at the first minute we archive
+            crate::parquet_export::ParquetExport::new(Vec::new(), &self.archive_parquet_columns).map_err(|e| anyhow::anyhow!("archive_parquet_columns: {}", e))?;
+        }
+        if !self.rehydrate_scratch_gb.is_finite() || self.rehydrate_scratch_gb < 0.0 {
+            return Err(anyhow::anyhow!("rehydrate_scratch_gb can't be less than 0 (it's {}): 0 turns rehydration off", self.rehydrate_scratch_gb));
+        }
+        if self.max_write_threads == 0 {
+            return Err(anyhow::anyhow!("max_write_threads has to be at least 1"));
+        }
+        if self.max_search_limit == 0 {
+            return Err(anyhow::anyhow!("max_search_limit has to be at least 1"));
+        }
+        if self.search_threads == 0 {
+            return Err(anyhow::anyhow!("search_threads has to be at least 1"));
+        }
+        if self.degraded_queue_percent > 100 {
+            return Err(anyhow::anyhow!("degraded_queue_percent is a percentage: it can't be more than 100 (it's {})", self.degraded_queue_percent));
+        }
+        if self.degraded_max_range_minutes == 0 {
+            return Err(anyhow::anyhow!("degraded_max_range_minutes has to be at least 1"));
+        }
+        if self.degraded_max_limit == 0 {
+            return Err(anyhow::anyhow!("degraded_max_limit has to be at least 1"));
+        }
+        if self.flush_interval_ms < 10 || self.flush_interval_ms > 60000 {
+            return Err(anyhow::anyhow!("flush_interval_ms has to be between 10 and 60000 (it's {})", self.flush_interval_ms));
+        }
+        if self.cardinality_fields.len() > MAX_CARDINALITY_FIELDS {
+            return Err(anyhow::anyhow!("cardinality_fields can have at most {} fields (it has {}): each one is another sketch in every minute", MAX_CARDINALITY_FIELDS, self.cardinality_fields.len()));
+        }
+        if self.cardinality_fields.iter().any(|field| field.trim().is_empty()) {
+            return Err(anyhow::anyhow!("cardinality_fields can't have an empty field in it"));
+        }
+        if crate::minute::LogCompression::parse(&self.log_compression).is_none() {
+            return Err(anyhow::anyhow!("log_compression has to be \"lz4\" or \"zstd\" (it's '{}')", self.log_compression));
+        }
+        if self.flush_max_events == Some(0) {
+            return Err(anyhow::anyhow!("flush_max_events has to be at least 1 (leave it out for no limit)"));
+        }
+        if self.standby_of.is_some() && self.standby_admin_token.is_none() {
+            return Err(anyhow::anyhow!("standby_of needs standby_admin_token too: the primary only hands its minutes to admins"));
+        }
+        if self.hec_dedup_seconds > 0 && self.hec_dedup_max_events == 0 {
+            return Err(anyhow::anyhow!("hec_dedup_max_events has to be at least 1 (set hec_dedup_seconds to 0 to turn dedup off)"));
+        }
+        if self.ingest_journal_segment_mb == 0 {
+            return Err(anyhow::anyhow!("ingest_journal_segment_mb has to be at least 1"));
+        }
+        if self.standby_interval_seconds == 0 {
+            return Err(anyhow::anyhow!("standby_interval_seconds has to be at least 1"));
+        }
+        if self.max_concurrent_searches > 0 && self.max_concurrent_searches_per_tenant > self.max_concurrent_searches {
+            return Err(anyhow::anyhow!("max_concurrent_searches_per_tenant ({}) is more than max_concurrent_searches ({}): no tenant could ever get that many",
+                self.max_concurrent_searches_per_tenant, self.max_concurrent_searches));
+        }
+        if let Some(peer) = self.federation_peers.iter().find(|peer| !peer.starts_with("http://") && !peer.starts_with("https://")) {
+            return Err(anyhow::anyhow!("federation_peers are URLs, like http://logmunch-2:8000 (one of them is '{}')", peer));
+        }
+        if self.federation_interval_seconds == 0 {
+            return Err(anyhow::anyhow!("federation_interval_seconds has to be at least 1"));
+        }
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            return Err(anyhow::anyhow!("tls_cert_path and tls_key_path go together: set both of them, or neither"));
+        }
+        if self.tls_client_ca_path.is_some() && self.tls_cert_path.is_none() {
+            return Err(anyhow::anyhow!("tls_client_ca_path only means anything with TLS on: set tls_cert_path and tls_key_path too"));
+        }
+        if self.tls_client_cert_required && self.tls_client_ca_path.is_none() {
+            return Err(anyhow::anyhow!("tls_client_cert_required needs tls_client_ca_path: there's nothing to check client certs against"));
+        }
+        if self.tls_reload_seconds == 0 {
+            return Err(anyhow::anyhow!("tls_reload_seconds has to be at least 1"));
+        }
+        if self.ingest_rate_events_per_second == Some(0) || self.ingest_rate_bytes_per_second == Some(0) {
+            return Err(anyhow::anyhow!("ingest rate limits have to be at least 1 (leave them out for no limit)"));
+        }
+        if crate::fluent::TagMode::parse(&self.fluent_forward_tag).is_none() {
+            return Err(anyhow::anyhow!("fluent_forward_tag has to be \"field\" or \"host\" (it's '{}')", self.fluent_forward_tag));
+        }
+        if crate::rate_limit::RateKey::parse(&self.ingest_rate_key).is_none() {
+            return Err(anyhow::anyhow!("ingest_rate_key has to be \"token\" or \"host\" (it's '{}')", self.ingest_rate_key));
+        }
+        if !crate::logging::LOG_FORMATS.contains(&self.log_format.as_str()) {
+            return Err(anyhow::anyhow!("log_format has to be one of {} (it's '{}')", crate::logging::LOG_FORMATS.join(", "), self.log_format));
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log_level) {
+            return Err(anyhow::anyhow!("log_level should be a level (like \"info\"), or levels per module (like \"info,logmunch::minute_db=debug\"): {}", e));
+        }
+        for cidr in &self.ingest_trusted_cidrs {
+            crate::auth::Cidr::parse(cidr).map_err(|e| anyhow::anyhow!("ingest_trusted_cidrs: {}", e))?;
+        }
+        if self.ingest_script_max_operations == 0 || self.ingest_script_timeout_ms == 0 {
+            return Err(anyhow::anyhow!("ingest_script_max_operations and ingest_script_timeout_ms have to be at least 1: no script gets anything done in 0"));
+        }
+        if self.audit_syslog.is_some() && self.audit_webhook.is_some() {
+            return Err(anyhow::anyhow!("audit_syslog and audit_webhook are two places to send the audit trail: pick one"));
+        }
+        if let Some(target) = &self.audit_syslog {
+            crate::audit::SyslogSink::parse(target)?;
+        }
+        crate::audit::AuditFormat::parse(&self.audit_format)?;
+        if self.lookup_reload_seconds == 0 {
+            return Err(anyhow::anyhow!("lookup_reload_seconds has to be at least 1"));
+        }
+        let n_minutes = self.minute_db_ram_bytes(n_tenants) / ESTIMATED_MINUTE_BLOOM_SIZE_BYTES;
+        if n_minutes < 5 {
+            return Err(anyhow::anyhow!(
+                "minute_db_ram_gb of {} only keeps about {} busy minutes' blooms per tenant ({} tenants), and we need at least 5: give us more RAM",
+                self.minute_db_ram_gb, n_minutes, n_tenants));
+        }
+        Ok(())
+    }
+
+    ///
+    /// How much memory each of `n_tenants` gets for bloom filters
+    ///
+    pub fn minute_db_ram_bytes(&self, n_tenants: u64) -> u64 {
+        (self.minute_db_ram_gb * 1000.0 * 1000.0 * 1000.0) as u64 / n_tenants
+    }
+
+    ///
+    /// How much disk each of `n_tenants` gets for minutes (we leave 10% of the budget as headroom)
+    ///
+    pub fn minute_db_disk_bytes(&self, n_tenants: u64) -> u64 {
+        (self.minute_db_disk_gb * 1000.0 * 1000.0 * 1000.0 * 0.9) as u64 / n_tenants
+    }
+
+    pub fn rehydrate_scratch_bytes(&self) -> u64 {
+        (self.rehydrate_scratch_gb * 1000.0 * 1000.0 * 1000.0) as u64
+    }
+
+    pub fn min_free_disk_bytes(&self) -> u64 {
+        self.min_free_disk_mb * 1000 * 1000
+    }
+
+    ///
+    /// How long a search gets, if there's a limit at all
+    ///
+    pub fn search_timeout(&self) -> Option<std::time::Duration> {
+        match self.search_timeout_ms{
+            0 => None,
+            ms => Some(std::time::Duration::from_millis(ms)),
+        }
+    }
+
+    ///
+    /// Is `limit` results, after skipping `offset`, more than this server hands out in one go?
+    ///
+    pub fn check_search_limit(&self, limit: usize, offset: usize) -> Result<()> {
+        if limit.saturating_add(offset) > self.max_search_limit {
+            return Err(anyhow::anyhow!(
+                "limit ({}) plus offset ({}) is more than this server's maximum of {}: page through with a smaller limit, or narrow your from/to",
+                limit, offset, self.max_search_limit));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_config() -> Result<()> {
+    assert_eq!(Config::parse("")?, Config::default());
+
+    let mut config = Config::parse(r#"
+        data_directory = "/var/lib/logmunch"
+        machine_id = 3
+        minute_db_ram_gb = 4
+        admin_token = "sekrit"
+    "#)?;
+    assert_eq!(config.data_directory, "/var/lib/logmunch");
+    assert_eq!(config.machine_id, 3);
+    assert_eq!(config.minute_db_ram_gb, 4.0);
+    assert_eq!(config.max_write_threads, 8);
+    assert_eq!(config.admin_token, Some("sekrit".to_string()));
+
+    // the environment wins
+    config.apply_env(|name| match name{
+        "MACHINE_ID" => Some("7".to_string()),
+        "DEDUP_MESSAGES" => Some("true".to_string()),
+        "MAX_LATENESS_SECONDS" => Some("300".to_string()),
+        "INGEST_JOURNAL" => Some("1".to_string()),
+        "INGEST_JOURNAL_FSYNC_MS" => Some("200".to_string()),
+        "HEC_DEDUP_SECONDS" => Some("600".to_string()),
+        "FLUSH_MAX_EVENTS" => Some("5000".to_string()),
+        "SEARCH_THREADS" => Some("16".to_string()),
+        "SEARCH_TIMEOUT_MS" => Some("0".to_string()),
+        "MAX_CONCURRENT_SEARCHES" => Some("2".to_string()),
+        "MAX_CONCURRENT_SEARCHES_PER_TENANT" => Some("1".to_string()),
+        "QUERY_CACHE_ENTRIES" => Some("0".to_string()),
+        "DEGRADED_QUEUE_PERCENT" => Some("80".to_string()),
+        "CARDINALITY_FIELDS" => Some("host, user_id,".to_string()),
+        "LOG_COMPRESSION" => Some("zstd".to_string()),
+        "RETENTION_DAYS" => Some("0.5".to_string()),
+        "INGEST_QUEUE_EVENTS" => Some("50000".to_string()),
+        "INGEST_INTERACTIVE_MAX_EVENTS" => Some("0".to_string()),
+        "ARCHIVE_S3_BUCKET" => Some("minutes".to_string()),
+        "AWS_ACCESS_KEY_ID" => Some("AKIA".to_string()),
+        "AWS_SECRET_ACCESS_KEY" => Some("sekrit".to_string()),
+        "ARCHIVE_PARQUET" => Some("true".to_string()),
+        "ARCHIVE_PARQUET_COLUMNS" => Some("user_id, status".to_string()),
+        "REHYDRATE_SCRATCH_GB" => Some("0".to_string()),
+        "STANDBY_OF" => Some("http://primary:8000".to_string()),
+        "FEDERATION_PEERS" => Some("http://logmunch-2:8000, http://logmunch-3:8000,".to_string()),
+        "FLUENT_FORWARD_PORT" => Some("24224".to_string()),
+        "FLUENT_FORWARD_TAG" => Some("host".to_string()),
+        "GELF_PORT" => Some("12201".to_string()),
+        "STANDBY_ADMIN_TOKEN" => Some("sekrit".to_string()),
+        "INGEST_RATE_EVENTS_PER_SECOND" => Some("5000".to_string()),
+        "INGEST_RATE_KEY" => Some("host".to_string()),
+        "LOG_FORMAT" => Some("json".to_string()),
+        "LOG_LEVEL" => Some("warn,logmunch::minute_db=debug".to_string()),
+        "TLS_CERT_PATH" => Some("/etc/logmunch/cert.pem".to_string()),
+        "TLS_KEY_PATH" => Some("/etc/logmunch/key.pem".to_string()),
+        "HEC_ACKS" => Some("1".to_string()),
+        "TENANTS" => Some("/etc/logmunch/tenants.json".to_string()),
+        "INGEST_TOKENS" => Some("abc, def".to_string()),
+        "INGEST_TRUSTED_CIDRS" => Some("10.0.0.0/8".to_string()),
+        "INGEST_SCRIPT_TIMEOUT_MS" => Some("50".to_string()),
+        "AUDIT_WEBHOOK" => Some("https://siem.example.com/audit".to_string()),
+        "AUDIT_FORMAT" => Some("cef".to_string()),
+        _ => None,
+    })?;
+    assert_eq!(config.machine_id, 7);
+    assert!(config.dedup_messages);
+    assert_eq!(config.max_lateness_seconds, 300);
+    assert!(config.ingest_journal);
+    assert_eq!((config.ingest_journal_fsync_ms, config.ingest_journal_segment_mb), (200, 64));
+    assert_eq!((config.hec_dedup_seconds, config.hec_dedup_max_events), (600, 1000000));
+    assert_eq!(config.flush_max_events, Some(5000));
+    assert_eq!(config.flush_interval_ms, 1000);
+    assert_eq!(config.search_threads, 16);
+    assert_eq!(config.search_timeout(), None);
+    assert_eq!((config.max_concurrent_searches, config.search_queue_ms, config.max_concurrent_searches_per_tenant), (2, 5000, 1));
+    assert_eq!(Config::default().search_timeout(), Some(std::time::Duration::from_secs(30)));
+    assert_eq!(config.query_cache_entries, 0);
+    assert_eq!(config.degraded_queue_percent, 80);
+    assert_eq!(config.cardinality_fields, vec!["host".to_string(), "user_id".to_string()]);
+    assert_eq!(config.log_compression, "zstd");
+    assert_eq!((config.retention_days, config.retention_imported_by_arrival), (Some(0.5), false));
+    assert_eq!((config.ingest_queue_events, config.ingest_interactive_queue_events, config.ingest_interactive_max_events), (50000, 10000, 0));
+    assert_eq!((config.archive_directory.clone(), config.archive_s3_bucket.clone()), (None, Some("minutes".to_string())));
+    assert_eq!((config.archive_s3_region.as_str(), config.archive_s3_endpoint.clone()), ("us-east-1", None));
+    assert!(config.archive_parquet);
+    assert_eq!(config.archive_parquet_columns, vec!["user_id".to_string(), "status".to_string()]);
+    assert_eq!(config.rehydrate_scratch_bytes(), 0);
+    assert_eq!(Config::default().rehydrate_scratch_bytes(), 2000000000);
+    assert_eq!(config.standby_of, Some("http://primary:8000".to_string()));
+    assert_eq!(config.fluent_forward_port, Some(24224));
+    assert_eq!(config.fluent_forward_tag, "host");
+    assert_eq!(config.gelf_port, Some(12201));
+    assert_eq!(config.standby_interval_seconds, 10);
+    assert_eq!(config.federation_peers, vec!["http://logmunch-2:8000".to_string(), "http://logmunch-3:8000".to_string()]);
+    assert_eq!(config.federation_interval_seconds, 10);
+    assert_eq!(config.ingest_rate_events_per_second, Some(5000));
+    assert_eq!(config.ingest_rate_bytes_per_second, None);
+    assert_eq!(config.ingest_rate_key, "host");
+    assert_eq!(config.log_format, "json");
+    assert_eq!(config.log_level, "warn,logmunch::minute_db=debug");
+    assert!(!config.log_spans);
+    assert_eq!(config.tls_cert_path, Some("/etc/logmunch/cert.pem".to_string()));
+    assert_eq!(config.tls_key_path, Some("/etc/logmunch/key.pem".to_string()));
+    assert_eq!(config.tls_client_ca_path, None);
+    assert_eq!(config.tls_reload_seconds, 60);
+    assert!(config.hec_acks);
+    assert_eq!((config.tenants.as_deref(), config.api_keys.as_deref()), (Some("/etc/logmunch/tenants.json"), None));
+    assert_eq!(config.ingest_tokens, vec!["abc".to_string(), "def".to_string()]);
+    assert_eq!(config.ingest_trusted_cidrs, vec!["10.0.0.0/8".to_string()]);
+    assert_eq!(Config::default().ingest_trusted_cidrs, vec!["127.0.0.0/8".to_string(), "::1".to_string()]);
+    assert_eq!((config.ingest_script_max_operations, config.ingest_script_timeout_ms), (100000, 50));
+    assert_eq!((config.audit_webhook.as_deref(), config.audit_format.as_str()), (Some("https://siem.example.com/audit"), "cef"));
+    assert_eq!(config.lookup_reload_seconds, 60);
+    assert!(config.validate(1).is_ok());
+
+    assert!(Config::parse("machine_idd = 3").is_err());
+    assert!(Config::parse("machine_id = \"three\"").is_err());
+    assert!(config.clone().apply_env(|name| (name == "MACHINE_ID").then(|| "three".to_string())).is_err());
+    assert!(config.clone().apply_env(|name| (name == "RETENTION_DAYS").then(|| "7d".to_string())).is_err());
+    assert!(Config{ retention_days: Some(0.0), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ retention_days: Some(-1.0), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ retention_days: Some(f64::NAN), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ max_write_threads: 0, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ ingest_queue_events: 0, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ ingest_interactive_queue_events: 50, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ ingest_interactive_weight: 0, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ archive_directory: Some("/mnt/archive".to_string()), ..config.clone() }.validate(1).is_err());
+    assert!(Config{ aws_secret_access_key: None, ..config.clone() }.validate(1).is_err());
+    assert!(Config{ archive_parquet_columns: vec!["host".to_string()], ..config.clone() }.validate(1).is_err());
+    assert!(Config{ rehydrate_scratch_gb: -1.0, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ degraded_queue_percent: 101, ..Config::default() }.validate(1).is_err());
+    assert!(Config::default().check_search_limit(100000, 0).is_ok());
+    assert!(Config::default().check_search_limit(99999, 2).is_err());
+    assert!(Config{ max_search_limit: 0, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ search_threads: 0, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ flush_interval_ms: 1, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ flush_max_events: Some(0), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ cardinality_fields: vec!["".to_string()], ..Config::default() }.validate(1).is_err());
+    assert!(Config{ cardinality_fields: (0..17).map(|n| format!("field{}", n)).collect(), ..Config::default() }.validate(1).is_err());
+    assert_eq!(Config::parse("cardinality_fields = [\"user_id\"]")?.cardinality_fields, vec!["user_id".to_string()]);
+    assert!(Config{ standby_of: Some("http://primary:8000".to_string()), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ standby_interval_seconds: 0, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ max_concurrent_searches_per_tenant: 9, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ max_concurrent_searches: 0, max_concurrent_searches_per_tenant: 9, ..Config::default() }.validate(1).is_ok());
+    assert!(Config{ federation_peers: vec!["logmunch-2:8000".to_string()], ..Config::default() }.validate(1).is_err());
+    assert!(Config{ federation_interval_seconds: 0, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ ingest_journal_segment_mb: 0, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ hec_dedup_seconds: 60, hec_dedup_max_events: 0, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ minute_db_ram_gb: 0.001, ..Config::default() }.validate(1).is_err());
+    assert!(Config::default().validate(1000).is_err());
+    assert!(Config{ ingest_rate_bytes_per_second: Some(0), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ ingest_rate_key: "tenant".to_string(), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ fluent_forward_tag: "source".to_string(), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ log_compression: "gzip".to_string(), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ log_format: "xml".to_string(), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ log_level: "info,logmunch=loud".to_string(), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ tls_cert_path: Some("cert.pem".to_string()), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ tls_client_ca_path: Some("ca.pem".to_string()), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ tls_client_cert_required: true, ..config.clone() }.validate(1).is_err());
+    assert!(Config{ tls_reload_seconds: 0, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ ingest_trusted_cidrs: vec!["10.0.0.0/33".to_string()], ..Config::default() }.validate(1).is_err());
+    assert!(Config{ ingest_script_timeout_ms: 0, ..Config::default() }.validate(1).is_err());
+    assert!(Config{ audit_syslog: Some("udp://siem:514".to_string()), ..config.clone() }.validate(1).is_err());
+    assert!(Config{ audit_syslog: Some("siem.example.com".to_string()), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ audit_format: "xml".to_string(), ..Config::default() }.validate(1).is_err());
+    assert!(Config{ lookup_reload_seconds: 0, ..Config::default() }.validate(1).is_err());
+    Ok(())
+}
@@ -0,0 +1,343 @@
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+
+use crate::archive::{Archiver, ArchiveSettings};
+use crate::config::Config;
+use crate::host_rules::HostRules;
+use crate::ingest_script::IngestScripts;
+use crate::ingest::{IngestQueue, Overloaded, Positions, QueueSettings};
+use crate::ingest_journal::{IngestJournal, JournalSettings};
+use crate::log_metrics::{LogMetrics, MetricRules};
+use crate::minute::{Log, Sealer};
+use crate::minute_db::{HistogramBucket, MinuteDB, SearchOptions, SearchStats};
+use crate::rehydrate::Rehydrator;
+use crate::search_token::Search;
+use crate::write_stats::WriteStatsRecorder;
+use crate::minute_labels::MinuteLabels;
+use crate::markers::Markers;
+use crate::WritableEvent;
+
+///
+/// What every engine gets started with. The RAM and disk budgets are this engine's share:
+/// the server runs one engine per tenant, and they split the config's budgets evenly.
+///
+#[derive(Clone)]
+pub struct EngineSettings{
+    pub config: Arc<Config>,
+    /// the bloom cache's budget (see MinuteDB::set_bloom_budget)
+    pub minute_db_ram_bytes: u64,
+    pub minute_db_disk_bytes: u64,
+    pub host_rules: Arc<HostRules>,
+    /// the script (if any) each tenant's writer runs every event through
+    pub ingest_scripts: Arc<IngestScripts>,
+    /// the rules every tenant's writer turns logs into metrics with (see log_metrics): each tenant counts its own
+    pub log_metric_rules: Arc<MetricRules>,
+    /// fsync every commit (see Minute::set_durable): HEC acks turn this on
+    pub durable: bool,
+    /// how many events can be waiting for the writer before ingest gets turned away
+    pub queue: QueueSettings,
+    /// where minutes go before retention deletes them, if anywhere: every tenant archives under a prefix of its own
+    pub archive: Option<ArchiveSettings>,
+    /// how much disk archived minutes get while searches that reach back that far are using them: 0 turns rehydration off
+    pub rehydrate_scratch_bytes: u64,
+}
+
+impl EngineSettings{
+    ///
+    /// `n_engines` engines sharing `config`'s budgets
+    ///
+    pub fn new(config: Arc<Config>, n_engines: u64) -> EngineSettings {
+        EngineSettings{
+            minute_db_ram_bytes: config.minute_db_ram_bytes(n_engines),
+            minute_db_disk_bytes: config.minute_db_disk_bytes(n_engines),
+            host_rules: Arc::new(HostRules::default()),
+            ingest_scripts: Arc::new(IngestScripts::default()),
+            log_metric_rules: Arc::new(MetricRules::default()),
+            durable: false,
+            queue: QueueSettings::from_config(&config),
+            archive: ArchiveSettings::from_config(&config),
+            rehydrate_scratch_bytes: config.rehydrate_scratch_bytes(),
+            config,
+        }
+    }
+}
+
+///
+/// The whole pipeline for one data directory: the ingest queue, the thread that writes it out into minutes,
+/// the MinuteDB that searches them, and the threads that keep the MinuteDB up to date and tidy.
+/// This is all the server is, once you take the HTTP away, so it's what to use to embed logmunch in something else.
+///
+/// The background threads run for as long as the process does. Events are searchable once their minute is sealed:
+/// that's at most a minute or so after they're written.
+///
+pub struct Engine{
+    name: Option<String>,
+    queue: Arc<IngestQueue>,
+    minute_db: Arc<MinuteDB>,
+    host_rules: Arc<HostRules>,
+    minutes_directory: String,
+    write_stats: Arc<WriteStatsRecorder>,
+    sealer: Sealer,
+    minute_labels: Arc<MinuteLabels>,
+    markers: Arc<Markers>,
+    log_metrics: Arc<LogMetrics>,
+}
+
+impl Engine{
+    ///
+    /// One engine with all of `config` to itself, in `config.data_directory`
+    ///
+    pub fn open(config: Config) -> Result<Engine> {
+        config.validate(1)?;
+        Self::start(None, &EngineSettings::new(Arc::new(config), 1))
+    }
+
+    ///
+    /// Bootstrap the data directory (the config's data directory itself for the default tenant (`None`),
+    /// <data directory>/<tenant> for the rest) and start the writer, reader and reaper.
+    /// If we crashed last time, there are probably minutes lying around that never got sealed: they get sealed before this returns.
+    ///
+    pub fn start(tenant: Option<&str>, settings: &EngineSettings) -> Result<Engine> {
+        let root = match tenant{
+            Some(tenant) => format!("{}/{}", settings.config.data_directory.trim_end_matches('/'), tenant),
+            None => settings.config.data_directory.clone(),
+        };
+        let label = tenant.unwrap_or("default").to_string();
+
+        let data_directory = crate::bootstrap::DataDirectory::bootstrap(&root, settings.config.min_free_disk_bytes(), settings.minute_db_disk_bytes)?;
+        let minute_data_directory = data_directory.minutes.clone();
+
+        let mut queue = IngestQueue::new(settings.queue.clone());
+        // INGEST_JOURNAL=true puts every batch on disk before it's queued: whatever the last run didn't commit gets replayed once the writer's going
+        let (journal, replay) = match JournalSettings::from_config(&settings.config){
+            Some(journal_settings) => {
+                let (journal, replay) = IngestJournal::open(&data_directory.journal, journal_settings)?;
+                (Some(Arc::new(journal)), Some(replay))
+            },
+            None => (None, None),
+        };
+        queue.set_journal(journal.clone());
+        let queue = Arc::new(queue);
+
+        // retention_days (optional) deletes minutes by age, on top of the disk limit
+        // (RAM doesn't limit how many minutes we keep any more: blooms that don't fit get let go, and read back in when a search needs them)
+        let retention = crate::retention::RetentionPolicy::from_config(&settings.config, u64::MAX, settings.minute_db_disk_bytes)?;
+
+        let archiver = match &settings.archive{
+            Some(archive) => {
+                let mut archiver = Archiver::from_settings(archive, &data_directory.archive)?;
+                if let Some(tenant) = tenant {
+                    archiver.set_key_prefix(&format!("tenants/{}/", tenant));
+                }
+                Some(Arc::new(archiver))
+            },
+            None => None,
+        };
+        let rehydrator = Rehydrator::from_settings(archiver.clone(), &data_directory.scratch, settings.rehydrate_scratch_bytes)?.map(Arc::new);
+
+        let mut minute_db = MinuteDB::new(minute_data_directory.clone(), retention, archiver, rehydrator);
+        minute_db.set_search_threads(settings.config.search_threads as usize);
+        minute_db.set_query_cache_entries(settings.config.query_cache_entries);
+        // a minute stays unsealed for max_lateness_seconds after it ends, and then (when the read loop can't watch the data directory) it's up to 10 more seconds before the read loop has it
+        minute_db.set_head_seconds(settings.config.max_lateness_seconds as i64 + 30);
+        minute_db.set_catalog_path(Some(format!("{}/catalog.json", data_directory.metadata)));
+        minute_db.set_bloom_budget(settings.minute_db_ram_bytes);
+        let minute_db = Arc::new(minute_db);
+
+        let minute_labels = Arc::new(MinuteLabels::open(&format!("{}/minute_labels.json", data_directory.metadata))?);
+        let markers = Arc::new(Markers::open(&format!("{}/markers.json", data_directory.metadata))?);
+
+        let mut minute_writer = crate::minute::ShardedMinute::from_config(&settings.config, minute_data_directory.clone());
+        minute_writer.set_host_rules(settings.host_rules.clone());
+        if let Some(ingest_script) = settings.ingest_scripts.for_tenant(tenant) {
+            tracing::info!("Tenant {} runs every event through {}", label, ingest_script.name());
+            minute_writer.set_ingest_script(Some(ingest_script));
+        }
+        let log_metrics = Arc::new(LogMetrics::new(settings.log_metric_rules.clone()));
+        if !log_metrics.is_empty() {
+            minute_writer.set_log_metrics(Some(log_metrics.clone()));
+        }
+        minute_writer.set_durable(settings.durable);
+        let write_stats = minute_writer.write_stats();
+        let sealer = minute_writer.sealer();
+        match minute_writer.seal_orphans(){
+            Ok(n) => tracing::info!("Sealed {} orphaned minutes for tenant {}", n, label),
+            Err(e) => tracing::error!("Error sealing orphaned minutes for tenant {}: {}", label, e)
+        }
+
+        let writer_queue = queue.clone();
+        std::thread::Builder::new().name(format!("writer-{}", label)).spawn(move || {
+            // this is the write thread and it's just gonna spin forever
+            minute_writer.write_loop(writer_queue);
+        })?;
+
+        if let (Some(journal), Some(replay)) = (&journal, replay) {
+            let n_events = replay.events();
+            replay.run(&queue, journal)?;
+            if n_events > 0 {
+                tracing::info!("Replayed {} events from tenant {}'s ingest journal", n_events, label);
+            }
+            if let Some(interval) = journal.fsync_interval() {
+                let journal = journal.clone();
+                std::thread::Builder::new().name(format!("journal-{}", label)).spawn(move || loop {
+                    std::thread::sleep(interval);
+                    if let Err(e) = journal.sync() {
+                        tracing::error!("Can't fsync the ingest journal: {}", e);
+                    }
+                })?;
+            }
+        }
+
+        let minute_reader = minute_db.clone();
+        std::thread::Builder::new().name(format!("reader-{}", label)).spawn(move || {
+            minute_reader.read_loop();
+        })?;
+
+        let reaper = crate::reaper::Reaper::new(minute_db.clone(), minute_data_directory.clone(), Duration::from_secs(settings.config.reaper_idle_minutes * 60));
+        std::thread::Builder::new().name(format!("reaper-{}", label)).spawn(move || {
+            reaper.reap_loop();
+        })?;
+
+        Ok(Engine{
+            name: tenant.map(|tenant| tenant.to_string()),
+            queue,
+            minute_db,
+            host_rules: settings.host_rules.clone(),
+            minutes_directory: minute_data_directory,
+            write_stats,
+            sealer,
+            minute_labels,
+            markers,
+            log_metrics,
+        })
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn queue(&self) -> Arc<IngestQueue> {
+        self.queue.clone()
+    }
+
+    pub fn minute_db(&self) -> Arc<MinuteDB> {
+        self.minute_db.clone()
+    }
+
+    ///
+    /// Where the minute files live: imports (see import::Import) write straight in here
+    ///
+    pub fn minutes_directory(&self) -> &str {
+        &self.minutes_directory
+    }
+
+    ///
+    /// Bytes in versus bytes written, as the writer goes (see write_stats)
+    ///
+    pub fn write_stats(&self) -> Arc<WriteStatsRecorder> {
+        self.write_stats.clone()
+    }
+
+    ///
+    /// For sealing minutes early, without waiting out MAX_LATENESS_SECONDS (see ShardedMinute::seal_range)
+    ///
+    pub fn sealer(&self) -> Sealer {
+        self.sealer.clone()
+    }
+
+    ///
+    /// Notes on minutes, like deploys (see minute_labels)
+    ///
+    pub fn minute_labels(&self) -> Arc<MinuteLabels> {
+        self.minute_labels.clone()
+    }
+
+    ///
+    /// Deploys, incidents and the like, to overlay on searches (see markers)
+    ///
+    pub fn markers(&self) -> Arc<Markers> {
+        self.markers.clone()
+    }
+
+    ///
+    /// What the writer's counted so far (see log_metrics)
+    ///
+    pub fn log_metrics(&self) -> Arc<LogMetrics> {
+        self.log_metrics.clone()
+    }
+
+    ///
+    /// Queue one event for the writer
+    ///
+    pub fn ingest(&self, event: WritableEvent) -> Result<Positions, Overloaded> {
+        self.queue.enqueue(vec![event])
+    }
+
+    ///
+    /// Queue a batch of events, all or nothing. What comes back is the batch's positions in the queue:
+    /// hang on to it and ask `is_written` to find out when the batch is safely in a minute.
+    ///
+    pub fn ingest_batch(&self, events: Vec<WritableEvent>) -> Result<Positions, Overloaded> {
+        self.queue.enqueue(events)
+    }
+
+    pub fn is_written(&self, positions: &Positions) -> bool {
+        self.queue.is_written(positions)
+    }
+
+    ///
+    /// The (up to 1000) most recent logs matching `query`, between `range.start` and `range.end` (seconds since the epoch)
+    ///
+    pub fn search(&self, query: &str, range: Range<i64>) -> Result<Vec<Log>> {
+        let options = SearchOptions{
+            from: Some(range.start),
+            to: Some(range.end),
+            ..SearchOptions::default()
+        };
+        let (logs, _stats) = self.search_with_options(query, &options)?;
+        Ok(logs)
+    }
+
+    pub fn search_with_options(&self, query: &str, options: &SearchOptions) -> Result<(Vec<Log>, SearchStats)> {
+        self.minute_db.search_with_stats(self.parse(query)?, options)
+    }
+
+    ///
+    /// Counts of logs matching `query` per `bucket_seconds`
+    ///
+    pub fn histogram(&self, query: &str, options: &SearchOptions, bucket_seconds: i64) -> Result<(Vec<HistogramBucket>, SearchStats)> {
+        self.minute_db.histogram(self.parse(query)?, options, bucket_seconds)
+    }
+
+    fn parse(&self, query: &str) -> Result<Search> {
+        Ok(Search::parse(&self.host_rules.rewrite_search(query))?)
+    }
+}
+
+#[test]
+fn test_engine_ingest() -> Result<()> {
+    let config = Config{
+        data_directory: crate::minute::test_data_directory("engine"),
+        ..Config::default()
+    };
+    let engine = Engine::open(config)?;
+    assert_eq!(engine.name(), None);
+
+    let now = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH)?.as_micros() as i64;
+    engine.ingest(WritableEvent::new("hello from the engine", now, "test"))?;
+    let positions = engine.ingest_batch((0..100).map(|n| WritableEvent::new(&format!("event {}", n), now, "test")).collect())?;
+    // a hundred events is still small enough to count as interactive, so it lines up behind the one before it
+    assert_eq!(positions, Positions{ lane: crate::ingest::Lane::Interactive, range: 1..101 });
+
+    // the writer wakes up every second
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    while !engine.is_written(&positions) && std::time::Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    assert!(engine.is_written(&positions));
+
+    // the engine's threads are still using the data directory, so it stays behind in test_data/
+    Ok(())
+}
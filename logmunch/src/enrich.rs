@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+
+use crate::minute::Log;
+
+///
+/// A Log, plus everything we can figure out about it without anybody having to reparse the message.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnrichedLog{
+    #[serde(flatten)]
+    pub log: Log,
+    pub level: Option<String>,
+    pub fields: BTreeMap<String, String>,
+    pub minute_id: Option<String>,
+}
+
+impl EnrichedLog{
+    pub fn new(log: Log) -> EnrichedLog {
+        let fields = extract_fields(&log.message);
+        let level = detect_level(&log.message, &fields);
+        let minute_id = log.minute_id.as_ref().map(|minute_id| minute_id.to_string());
+        EnrichedLog{
+            log,
+            level,
+            fields,
+            minute_id,
+        }
+    }
+}
+
+///
+/// Pull `key=value` (and `key="quoted value"`) pairs out of a log line.
+/// If the whole line is a JSON object, its top-level scalar values are the fields instead.
+///
+pub fn extract_fields(message: &str) -> BTreeMap<String, String> {
+    let mut fields = BTreeMap::new();
+
+    let trimmed = message.trim();
+    if trimmed.starts_with('{') {
+        if let Ok(serde_json::Value::Object(map)) = serde_json::from_str::<serde_json::Value>(trimmed) {
+            for (key, value) in map {
+                match value {
+                    serde_json::Value::String(value) => { fields.insert(key, value); },
+                    serde_json::Value::Number(value) => { fields.insert(key, value.to_string()); },
+                    serde_json::Value::Bool(value) => { fields.insert(key, value.to_string()); },
+                    _ => {}
+                }
+            }
+            return fields;
+        }
+    }
+
+    let chars: Vec<char> = message.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        // read a key
+        let key_start = i;
+        while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.' || chars[i] == '-') {
+            i += 1;
+        }
+        if i == key_start || i >= chars.len() || chars[i] != '=' {
+            // not a key=value pair: skip to the next word
+            while i < chars.len() && !chars[i].is_whitespace() {
+                i += 1;
+            }
+            while i < chars.len() && chars[i].is_whitespace() {
+                i += 1;
+            }
+            continue;
+        }
+        let key: String = chars[key_start..i].iter().collect();
+        i += 1;
+
+        // read a value
+        let mut value = String::new();
+        if i < chars.len() && chars[i] == '"' {
+            i += 1;
+            let mut escape = false;
+            while i < chars.len() {
+                if escape {
+                    value.push(chars[i]);
+                    escape = false;
+                }
+                else if chars[i] == '\\' {
+                    escape = true;
+                }
+                else if chars[i] == '"' {
+                    i += 1;
+                    break;
+                }
+                else {
+                    value.push(chars[i]);
+                }
+                i += 1;
+            }
+        }
+        else {
+            while i < chars.len() && !chars[i].is_whitespace() {
+                value.push(chars[i]);
+                i += 1;
+            }
+        }
+        if !value.is_empty() {
+            fields.insert(key, value);
+        }
+        while i < chars.len() && chars[i].is_whitespace() {
+            i += 1;
+        }
+    }
+
+    fields
+}
+
+fn normalize_level(level: &str) -> Option<String> {
+    let level = match level.to_lowercase().as_str() {
+        "trace" => "trace",
+        "debug" => "debug",
+        "info" | "notice" => "info",
+        "warn" | "warning" => "warn",
+        "error" | "err" => "error",
+        "fatal" | "crit" | "critical" | "panic" | "emerg" | "alert" => "fatal",
+        _ => return None,
+    };
+    Some(level.to_string())
+}
+
+///
+/// Figure out the log level, from a `level=` style field if there is one, otherwise from the first word that looks like one.
+///
+pub fn detect_level(message: &str, fields: &BTreeMap<String, String>) -> Option<String> {
+    for key in ["level", "lvl", "severity", "loglevel"] {
+        if let Some(level) = fields.get(key).and_then(|level| normalize_level(level)) {
+            return Some(level);
+        }
+    }
+    for word in message.split_whitespace() {
+        let word = word.trim_matches(|c: char| !c.is_alphanumeric());
+        // only shouty words count: "error" shows up in plenty of perfectly happy log lines
+        if word.len() > 2 && word.chars().all(|c| c.is_ascii_uppercase()) {
+            if let Some(level) = normalize_level(word) {
+                return Some(level);
+            }
+        }
+    }
+    None
+}
+
+#[test]
+fn test_extract_fields() {
+    let fields = extract_fields("notice: r=ggsc8rn0 - m=GET u=/api/1/worlds?apiKey=abc&org=x s=200 msg=\"hello there\" broken= =nope");
+    assert_eq!(fields.get("r"), Some(&"ggsc8rn0".to_string()));
+    assert_eq!(fields.get("m"), Some(&"GET".to_string()));
+    assert_eq!(fields.get("u"), Some(&"/api/1/worlds?apiKey=abc&org=x".to_string()));
+    assert_eq!(fields.get("s"), Some(&"200".to_string()));
+    assert_eq!(fields.get("msg"), Some(&"hello there".to_string()));
+    assert_eq!(fields.get("broken"), None);
+    assert_eq!(fields.len(), 5);
+
+    let fields = extract_fields("{\"level\": \"warn\", \"status\": 503, \"nested\": {\"a\": 1}}");
+    assert_eq!(fields.get("level"), Some(&"warn".to_string()));
+    assert_eq!(fields.get("status"), Some(&"503".to_string()));
+    assert_eq!(fields.get("nested"), None);
+
+    assert!(extract_fields("").is_empty());
+    assert!(extract_fields("dN=\u{30c1}\u{30e7}\u{30b3}").contains_key("dN"));
+}
+
+#[test]
+fn test_detect_level() {
+    let message = "2023-11-10T14:55:41 marquee [ERROR] something broke";
+    assert_eq!(detect_level(message, &extract_fields(message)), Some("error".to_string()));
+
+    let message = "ts=1 level=warning msg=\"error budget fine\"";
+    assert_eq!(detect_level(message, &extract_fields(message)), Some("warn".to_string()));
+
+    let message = "GET /errors 200 0.158 ms";
+    assert_eq!(detect_level(message, &extract_fields(message)), None);
+}
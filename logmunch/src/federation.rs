@@ -0,0 +1,312 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use anyhow::Result;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::{Serialize, Deserialize};
+
+use crate::client::{Backoff, Client, SearchQuery};
+use crate::config::Config;
+use crate::handshake::{Compatibility, Handshake};
+use crate::minute::Log;
+use crate::minute_db::{SearchStats, SortOrder};
+use crate::tenant::Tenants;
+use crate::trace::TraceContext;
+
+///
+/// Sent with every search we pass on to a peer. A peer answers a forwarded search from its own minutes, and doesn't pass it on again:
+/// two nodes that list each other as peers don't bounce it back and forth.
+///
+pub const FORWARDED_HEADER: &str = "X-Logmunch-Federated";
+
+///
+/// How much longer than SEARCH_TIMEOUT_MS we wait for a peer: it stops searching at the timeout too, and still has to send what it found
+///
+const PEER_TIMEOUT_SLACK: Duration = Duration::from_secs(5);
+
+///
+/// What we know about one peer (and what `GET /admin/federation` says about it): the routing table is one of these per peer
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerStatus{
+    pub url: String,
+    /// None until we've heard from it
+    pub machine_id: Option<u32>,
+    pub compatibility: Option<Compatibility>,
+    /// which tenants it has minutes for (None is the default tenant): None if it doesn't say (it's older than us), and then it gets every search
+    pub tenants: Option<BTreeSet<Option<String>>>,
+    /// when we last heard from it, in seconds since the epoch
+    pub last_handshake_at: Option<i64>,
+    /// what went wrong the last time we asked, if it didn't work
+    pub last_error: Option<String>,
+}
+
+impl PeerStatus{
+    ///
+    /// Should a search of `tenant` go here? Only if we've heard from it, we can talk to it, and it has (or might have) that tenant's minutes
+    ///
+    pub fn holds(&self, tenant: Option<&str>) -> bool {
+        match self.compatibility{
+            Some(Compatibility::Full | Compatibility::Degraded) => {},
+            Some(Compatibility::Incompatible) | None => return false,
+        }
+        match &self.tenants{
+            Some(tenants) => tenants.contains(&tenant.map(str::to_string)),
+            None => true,
+        }
+    }
+}
+
+///
+/// Several nodes, each with their own minutes, searched as one (FEDERATION_PEERS). Every `federation_interval_seconds` we ask each
+/// peer for its handshake, which says which tenants it has minutes for; a search then goes to the peers that have the caller's
+/// tenant, and no others, so a cluster with lots of tenants spread around it doesn't search every node for every one of them.
+/// The peers get the caller's token (and tenant, in the tenancy header, if there is one), so the tenant and token policy they search
+/// with are the ones we do, and so is their MAX_CONCURRENT_SEARCHES_PER_TENANT: a tenant that's used up its share on a peer
+/// gets a 429 from it, and the search comes back without that peer's results (marked partial) rather than waiting.
+/// A peer we can't reach keeps its place in the table (its minutes will still be there when it's back): searches that
+/// can't reach it come back partial too.
+/// Only /search is federated, for now: histograms, stats, streams and exports only look here.
+///
+pub struct Federation{
+    machine_id: u32,
+    interval: Duration,
+    /// how long a peer gets to answer a search: None is the client's own timeout
+    timeout: Option<Duration>,
+    /// the tenancy header, if there is one (see tenant::Tenancy)
+    tenant_header: Option<String>,
+    peers: Vec<Peer>,
+}
+
+struct Peer{
+    client: Client,
+    status: Mutex<PeerStatus>,
+}
+
+impl Federation{
+    ///
+    /// None, unless the config has some federation_peers
+    ///
+    pub fn new(config: &Config, tenant_header: Option<&str>) -> Option<Federation> {
+        if config.federation_peers.is_empty() {
+            return None;
+        }
+        let peers = config.federation_peers.iter().map(|url| Peer{
+            // no retrying a handshake: the next refresh is the retry
+            client: Client::new(url).with_backoff(Backoff{ max_attempts: 1, ..Backoff::default() }),
+            status: Mutex::new(PeerStatus{
+                url: url.clone(),
+                ..PeerStatus::default()
+            }),
+        }).collect();
+        Some(Federation{
+            machine_id: config.machine_id,
+            interval: Duration::from_secs(config.federation_interval_seconds),
+            timeout: config.search_timeout().map(|timeout| timeout + PEER_TIMEOUT_SLACK),
+            tenant_header: tenant_header.map(str::to_string),
+            peers,
+        })
+    }
+
+    pub fn status(&self) -> Vec<PeerStatus> {
+        self.peers.iter().map(|peer| peer.status.lock().unwrap().clone()).collect()
+    }
+
+    ///
+    /// The peers a search of `tenant` should go to
+    ///
+    pub fn peers_for(&self, tenant: Option<&str>) -> Vec<PeerStatus> {
+        self.status().into_iter().filter(|peer| peer.holds(tenant)).collect()
+    }
+
+    ///
+    /// Keep the routing table up to date, forever
+    ///
+    pub fn refresh_loop(&self) {
+        tracing::info!("Federated with {} peer(s): asking them which tenants they have every {}s", self.peers.len(), self.interval.as_secs());
+        loop{
+            self.refresh();
+            std::thread::sleep(self.interval);
+        }
+    }
+
+    fn refresh(&self) {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+        for peer in &self.peers {
+            let handshake = peer.client.handshake();
+            let mut status = peer.status.lock().unwrap();
+            match handshake{
+                Ok(handshake) => {
+                    status.machine_id = Some(handshake.machine_id);
+                    status.last_handshake_at = Some(now);
+                    match self.check_peer(&handshake){
+                        Ok(compatibility) => {
+                            status.compatibility = Some(compatibility);
+                            status.tenants = handshake.tenants;
+                            status.last_error = None;
+                        },
+                        Err(e) => {
+                            tracing::error!("Not searching federation peer {}: {}", status.url, e);
+                            status.compatibility = Some(Compatibility::Incompatible);
+                            status.last_error = Some(e.to_string());
+                        },
+                    }
+                },
+                Err(e) => {
+                    tracing::warn!("Couldn't refresh federation peer {}: {}", status.url, e);
+                    status.last_error = Some(e.to_string());
+                },
+            }
+        }
+    }
+
+    ///
+    /// Two nodes with the same machine_id would hand out the same event ids, and a peer more than a version away from us
+    /// might not understand the search: we don't search either of those
+    ///
+    fn check_peer(&self, peer: &Handshake) -> Result<Compatibility> {
+        if peer.machine_id == self.machine_id {
+            return Err(anyhow::anyhow!("it has the same machine_id as us ({}): give one of us a different one", self.machine_id));
+        }
+        let compatibility = Handshake::new(self.machine_id).compatibility(peer);
+        if compatibility == Compatibility::Incompatible {
+            return Err(anyhow::anyhow!("it's at api v{} / minute format v{}: too far from us to search", peer.api_version, peer.minute_format_version));
+        }
+        Ok(compatibility)
+    }
+
+    ///
+    /// Run the search on each of `peers` (see peers_for) at once, as the caller: each peer's results, and its stats.
+    /// A peer that doesn't answer has no results, and one stats with what went wrong in it.
+    ///
+    pub async fn search(&self, peers: &[PeerStatus], tenant: Option<&str>, token: Option<&str>, trace: &TraceContext, search: &str, query: &SearchQuery) -> Vec<(Vec<Log>, Vec<SearchStats>)> {
+        let searches = peers.iter().map(|peer| async move {
+            let client = self.client_for(&peer.url, tenant, token, trace);
+            match client.search_with_stats_async(search, query.clone()).await{
+                Ok(results) => results,
+                Err(e) => {
+                    tracing::warn!("Federated search of {} failed: {}", peer.url, e);
+                    (Vec::new(), vec![SearchStats{
+                        node: peer.machine_id.unwrap_or_default(),
+                        error: Some(e.to_string()),
+                        ..SearchStats::default()
+                    }])
+                },
+            }
+        });
+        futures::future::join_all(searches).await
+    }
+
+    fn client_for(&self, url: &str, tenant: Option<&str>, token: Option<&str>, trace: &TraceContext) -> Client {
+        // the caller's waiting: a busy peer gets left out, not waited for
+        let mut client = Client::new(url)
+            .with_backoff(Backoff{ max_attempts: 1, ..Backoff::default() })
+            .with_header(FORWARDED_HEADER, "true")
+            .with_header("traceparent", &trace.traceparent());
+        if let Some(timeout) = self.timeout {
+            client = client.with_timeout(timeout);
+        }
+        if let Some(token) = token {
+            client = client.with_token(token);
+        }
+        if let (Some(header), Some(tenant)) = (&self.tenant_header, tenant) {
+            client = client.with_tenant(header, tenant);
+        }
+        client
+    }
+}
+
+///
+/// Which tenants have minutes here (None is the default tenant), for our handshake. Minutes still being written count too,
+/// so a tenant that's only just started sending logs here gets searched here as soon as the peers next ask.
+///
+pub fn local_tenants(tenants: &Tenants) -> BTreeSet<Option<String>> {
+    tenants.all()
+        .filter(|tenant| fs::read_dir(&tenant.minutes_directory).map(|mut entries| entries.next().is_some()).unwrap_or(false))
+        .map(|tenant| tenant.name.clone())
+        .collect()
+}
+
+///
+/// Our results and the peers' (each sorted in `order`, and each their own first offset + limit) as one page of the lot.
+/// A minute on two nodes (a standby that's also a peer, say) has the same event ids on both, so its logs only count once.
+///
+pub fn merge(results: Vec<Vec<Log>>, order: SortOrder, offset: usize, limit: usize) -> Vec<Log> {
+    let mut seen = HashSet::new();
+    crate::minute_db::merge_shards(results, order).into_iter()
+        .filter(|log| log.event_id.as_ref().map(|event_id| seen.insert(event_id.clone())).unwrap_or(true))
+        .skip(offset)
+        .take(limit)
+        .collect()
+}
+
+///
+/// Whether this search was passed on to us by a peer (FORWARDED_HEADER): if it was, we answer it from our own minutes
+///
+pub struct Forwarded(pub bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Forwarded {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Forwarded(request.headers().get_one(FORWARDED_HEADER).is_some()))
+    }
+}
+
+#[test]
+fn test_routing() {
+    let config = Config{
+        machine_id: 1,
+        federation_peers: vec!["http://logmunch-2:8000".to_string(), "http://logmunch-3:8000".to_string(), "http://logmunch-4:8000".to_string()],
+        ..Config::default()
+    };
+    assert!(Federation::new(&Config::default(), None).is_none());
+    let federation = Federation::new(&config, Some("X-Logmunch-Tenant")).unwrap();
+    // nobody's been heard from yet
+    assert!(federation.peers_for(None).is_empty());
+
+    let tenants = |names: &[Option<&str>]| Some(names.iter().map(|name| name.map(str::to_string)).collect::<BTreeSet<Option<String>>>());
+    let routes = [
+        (2, Compatibility::Full, tenants(&[None, Some("checkout")])),
+        (3, Compatibility::Degraded, tenants(&[Some("platform")])),
+        // too old to say which tenants it has
+        (4, Compatibility::Full, None),
+    ];
+    for (peer, (machine_id, compatibility, tenants)) in federation.peers.iter().zip(routes) {
+        let mut status = peer.status.lock().unwrap();
+        status.machine_id = Some(machine_id);
+        status.compatibility = Some(compatibility);
+        status.tenants = tenants;
+    }
+    let routed = |tenant: Option<&str>| federation.peers_for(tenant).iter().filter_map(|peer| peer.machine_id).collect::<Vec<u32>>();
+    assert_eq!(routed(None), vec![2, 4]);
+    assert_eq!(routed(Some("checkout")), vec![2, 4]);
+    assert_eq!(routed(Some("platform")), vec![3, 4]);
+    assert_eq!(routed(Some("search")), vec![4]);
+
+    federation.peers[1].status.lock().unwrap().compatibility = Some(Compatibility::Incompatible);
+    assert_eq!(routed(Some("platform")), vec![4]);
+
+    assert!(federation.check_peer(&Handshake::new(1)).is_err());
+    assert_eq!(federation.check_peer(&Handshake::new(2)).unwrap(), Compatibility::Full);
+    let mut ancient = Handshake::new(2);
+    ancient.api_version += 2;
+    assert!(federation.check_peer(&ancient).is_err());
+}
+
+#[test]
+fn test_merge() {
+    let log = |id: i64, seconds: i64, event_id: &str| Log{ id, message: "error".to_string(), time: seconds * 1000000, host: "web-1".to_string(),
+        shard: None, event_id: Some(event_id.to_string()), minute_id: None };
+    let ours = vec![log(1, 105, "a"), log(2, 103, "b"), log(3, 101, "c")];
+    let theirs = vec![log(1, 104, "d"), log(2, 103, "b"), log(3, 102, "e")];
+
+    let ids = |logs: Vec<Log>| logs.into_iter().map(|log| log.event_id.unwrap()).collect::<Vec<String>>();
+    assert_eq!(ids(merge(vec![ours.clone(), theirs.clone()], SortOrder::Descending, 0, 10)), vec!["a", "d", "b", "e", "c"]);
+    assert_eq!(ids(merge(vec![ours.clone(), theirs.clone()], SortOrder::Descending, 1, 2)), vec!["d", "b"]);
+
+    let ascending = |mut logs: Vec<Log>| { logs.reverse(); logs };
+    assert_eq!(ids(merge(vec![ascending(ours), ascending(theirs), Vec::new()], SortOrder::Ascending, 0, 3)), vec!["c", "e", "b"]);
+}
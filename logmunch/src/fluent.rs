@@ -0,0 +1,757 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use anyhow::Result;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::WritableEvent;
+use self::msgpack::Value;
+
+///
+/// A forward message (or a decompressed PackedForward) bigger than this is somebody sending us garbage: hang up on them
+///
+const MAX_MESSAGE_BYTES: usize = 64 * 1024 * 1024;
+
+///
+/// Fluent Bit holds its connections open between flushes: one that's said nothing for this long has gone away
+///
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+///
+/// How many seconds a batch waits for room in a full ingest queue before we hang up (and, without an ack, it gets sent again)
+///
+const MAX_QUEUE_WAIT_SECONDS: u64 = 30;
+
+///
+/// The line itself is the first of these in the record; everything else in the record goes on the front of it as key=value
+///
+const LINE_FIELDS: [&str; 3] = ["log", "message", "msg"];
+
+///
+/// MessagePack, written out by hand: it's the only thing that speaks it, and it's a small format.
+/// Everything the forward protocol sends is a Value; the little we send back gets encoded from one.
+///
+pub mod msgpack{
+    use anyhow::Result;
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Value{
+        Nil,
+        Bool(bool),
+        Int(i64),
+        UInt(u64),
+        Float(f64),
+        Str(String),
+        Bin(Vec<u8>),
+        Array(Vec<Value>),
+        Map(Vec<(Value, Value)>),
+        Ext(i8, Vec<u8>),
+    }
+
+    impl Value{
+        ///
+        /// Strings (and binaries, which some clients send strings as)
+        ///
+        pub fn as_str(&self) -> Option<&str> {
+            match self{
+                Value::Str(value) => Some(value),
+                Value::Bin(value) => std::str::from_utf8(value).ok(),
+                _ => None,
+            }
+        }
+
+        pub fn as_bytes(&self) -> Option<&[u8]> {
+            match self{
+                Value::Str(value) => Some(value.as_bytes()),
+                Value::Bin(value) => Some(value),
+                _ => None,
+            }
+        }
+
+        ///
+        /// A map's value for a string key
+        ///
+        pub fn get(&self, key: &str) -> Option<&Value> {
+            match self{
+                Value::Map(entries) => entries.iter().find(|(entry_key, _)| entry_key.as_str() == Some(key)).map(|(_, value)| value),
+                _ => None,
+            }
+        }
+    }
+
+    enum DecodeError{
+        /// we need more bytes before this is a whole value
+        Incomplete,
+        Invalid(String),
+    }
+
+    // nobody's sending us a record nested deeper than this on purpose
+    const MAX_DEPTH: usize = 64;
+
+    struct Reader<'a>{
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl<'a> Reader<'a>{
+        fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+            let end = self.position.checked_add(n).ok_or(DecodeError::Incomplete)?;
+            let taken = self.bytes.get(self.position..end).ok_or(DecodeError::Incomplete)?;
+            self.position = end;
+            Ok(taken)
+        }
+
+        fn uint(&mut self, n: usize) -> Result<u64, DecodeError> {
+            Ok(self.take(n)?.iter().fold(0, |number, byte| (number << 8) | *byte as u64))
+        }
+
+        fn int(&mut self, n: usize) -> Result<i64, DecodeError> {
+            let unsigned = self.uint(n)?;
+            let shift = 64 - 8 * n as u32;
+            Ok(((unsigned << shift) as i64) >> shift)
+        }
+
+        fn string(&mut self, n: usize) -> Result<Value, DecodeError> {
+            Ok(Value::Str(String::from_utf8_lossy(self.take(n)?).into_owned()))
+        }
+
+        fn array(&mut self, n: usize, depth: usize) -> Result<Value, DecodeError> {
+            // every element is at least a byte, so a length that's more than we've got is just incomplete (don't allocate for it)
+            let mut values = Vec::with_capacity(n.min(self.bytes.len() - self.position));
+            for _ in 0..n {
+                values.push(self.value(depth + 1)?);
+            }
+            Ok(Value::Array(values))
+        }
+
+        fn map(&mut self, n: usize, depth: usize) -> Result<Value, DecodeError> {
+            let mut entries = Vec::with_capacity(n.min(self.bytes.len() - self.position));
+            for _ in 0..n {
+                let key = self.value(depth + 1)?;
+                entries.push((key, self.value(depth + 1)?));
+            }
+            Ok(Value::Map(entries))
+        }
+
+        fn ext(&mut self, n: usize) -> Result<Value, DecodeError> {
+            let kind = self.int(1)? as i8;
+            Ok(Value::Ext(kind, self.take(n)?.to_vec()))
+        }
+
+        fn value(&mut self, depth: usize) -> Result<Value, DecodeError> {
+            if depth > MAX_DEPTH {
+                return Err(DecodeError::Invalid(format!("nested more than {} deep", MAX_DEPTH)));
+            }
+            let marker = self.take(1)?[0];
+            match marker{
+                0x00..=0x7f => Ok(Value::UInt(marker as u64)),
+                0x80..=0x8f => self.map((marker & 0x0f) as usize, depth),
+                0x90..=0x9f => self.array((marker & 0x0f) as usize, depth),
+                0xa0..=0xbf => self.string((marker & 0x1f) as usize),
+                0xc0 => Ok(Value::Nil),
+                0xc2 => Ok(Value::Bool(false)),
+                0xc3 => Ok(Value::Bool(true)),
+                0xc4..=0xc6 => {
+                    let n = self.uint(1 << (marker - 0xc4))? as usize;
+                    Ok(Value::Bin(self.take(n)?.to_vec()))
+                },
+                0xc7..=0xc9 => {
+                    let n = self.uint(1 << (marker - 0xc7))? as usize;
+                    self.ext(n)
+                },
+                0xca => Ok(Value::Float(f32::from_bits(self.uint(4)? as u32) as f64)),
+                0xcb => Ok(Value::Float(f64::from_bits(self.uint(8)?))),
+                0xcc..=0xcf => Ok(Value::UInt(self.uint(1 << (marker - 0xcc))?)),
+                0xd0..=0xd3 => Ok(Value::Int(self.int(1 << (marker - 0xd0))?)),
+                0xd4..=0xd8 => self.ext(1 << (marker - 0xd4)),
+                0xd9..=0xdb => {
+                    let n = self.uint(1 << (marker - 0xd9))? as usize;
+                    self.string(n)
+                },
+                0xdc | 0xdd => {
+                    let n = self.uint(2 << (marker - 0xdc))? as usize;
+                    self.array(n, depth)
+                },
+                0xde | 0xdf => {
+                    let n = self.uint(2 << (marker - 0xde))? as usize;
+                    self.map(n, depth)
+                },
+                0xe0..=0xff => Ok(Value::Int(marker as i8 as i64)),
+                0xc1 => Err(DecodeError::Invalid("0xc1 is never used".to_string())),
+            }
+        }
+    }
+
+    ///
+    /// The first whole value in `bytes`, and how many bytes it took up: None if `bytes` stops partway through it
+    ///
+    pub fn decode(bytes: &[u8]) -> Result<Option<(Value, usize)>> {
+        let mut reader = Reader{ bytes, position: 0 };
+        match reader.value(0){
+            Ok(value) => Ok(Some((value, reader.position))),
+            Err(DecodeError::Incomplete) => Ok(None),
+            Err(DecodeError::Invalid(reason)) => Err(anyhow::anyhow!("That isn't MessagePack: {}", reason)),
+        }
+    }
+
+    ///
+    /// Every value in `bytes`, which has to end where the last one does
+    ///
+    pub fn decode_all(bytes: &[u8]) -> Result<Vec<Value>> {
+        let mut values = Vec::new();
+        let mut position = 0;
+        while position < bytes.len() {
+            match decode(&bytes[position..])?{
+                Some((value, used)) => {
+                    values.push(value);
+                    position += used;
+                },
+                None => return Err(anyhow::anyhow!("MessagePack that stops partway through a value")),
+            }
+        }
+        Ok(values)
+    }
+
+    fn length(out: &mut Vec<u8>, n: usize, fix: Option<(u8, usize)>, markers: [u8; 3]) {
+        match fix{
+            Some((marker, max)) if n <= max => out.push(marker | n as u8),
+            _ if n <= u8::MAX as usize && markers[0] != 0 => out.extend([markers[0], n as u8]),
+            _ if n <= u16::MAX as usize => {
+                out.push(markers[1]);
+                out.extend((n as u16).to_be_bytes());
+            },
+            _ => {
+                out.push(markers[2]);
+                out.extend((n as u32).to_be_bytes());
+            },
+        }
+    }
+
+    pub fn encode(value: &Value, out: &mut Vec<u8>) {
+        match value{
+            Value::Nil => out.push(0xc0),
+            Value::Bool(value) => out.push(if *value { 0xc3 } else { 0xc2 }),
+            Value::UInt(value) => {
+                out.push(0xcf);
+                out.extend(value.to_be_bytes());
+            },
+            Value::Int(value) => {
+                out.push(0xd3);
+                out.extend(value.to_be_bytes());
+            },
+            Value::Float(value) => {
+                out.push(0xcb);
+                out.extend(value.to_bits().to_be_bytes());
+            },
+            Value::Str(value) => {
+                length(out, value.len(), Some((0xa0, 31)), [0xd9, 0xda, 0xdb]);
+                out.extend(value.as_bytes());
+            },
+            Value::Bin(value) => {
+                length(out, value.len(), None, [0xc4, 0xc5, 0xc6]);
+                out.extend(value);
+            },
+            Value::Array(values) => {
+                length(out, values.len(), Some((0x90, 15)), [0, 0xdc, 0xdd]);
+                for value in values {
+                    encode(value, out);
+                }
+            },
+            Value::Map(entries) => {
+                length(out, entries.len(), Some((0x80, 15)), [0, 0xde, 0xdf]);
+                for (key, value) in entries {
+                    encode(key, out);
+                    encode(value, out);
+                }
+            },
+            Value::Ext(kind, data) => {
+                length(out, data.len(), None, [0xc7, 0xc8, 0xc9]);
+                out.push(*kind as u8);
+                out.extend(data);
+            },
+        }
+    }
+}
+
+///
+/// What a record's tag turns into (FLUENT_FORWARD_TAG)
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagMode{
+    /// a `tag=...` field, with the host coming from the record's host/hostname (or the tag, if it hasn't got one)
+    Field,
+    /// the host, whatever the record says
+    Host,
+}
+
+impl TagMode{
+    pub fn parse(mode: &str) -> Option<TagMode> {
+        match mode{
+            "field" => Some(TagMode::Field),
+            "host" => Some(TagMode::Host),
+            _ => None,
+        }
+    }
+}
+
+///
+/// One forward protocol message, whichever mode it came in:
+/// Message `[tag, time, record, option?]`, Forward `[tag, [[time, record], ...], option?]`,
+/// PackedForward `[tag, <the [time, record]s, packed back to back>, option?]`, or CompressedPackedForward (the same, gzipped).
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch{
+    pub tag: String,
+    /// unix microseconds, and the record
+    pub entries: Vec<(i64, Value)>,
+    /// if the sender wants an ack, this goes back to it in one
+    pub chunk: Option<String>,
+}
+
+impl Batch{
+    pub fn parse(message: Value) -> Result<Batch> {
+        let Value::Array(parts) = message else {
+            return Err(anyhow::anyhow!("A forward message should be an array, not {:?}", message));
+        };
+        if parts.len() < 2 {
+            return Err(anyhow::anyhow!("A forward message needs a tag and some entries"));
+        }
+        let tag = parts[0].as_str().ok_or_else(|| anyhow::anyhow!("The tag should be a string, not {:?}", parts[0]))?.to_string();
+        let (entries, option) = match &parts[1]{
+            Value::Array(entries) => {
+                let entries = entries.iter().map(entry).collect::<Result<Vec<(i64, Value)>>>()?;
+                (entries, parts.get(2))
+            },
+            Value::Str(_) | Value::Bin(_) => {
+                let packed = parts[1].as_bytes().unwrap_or_default();
+                let option = parts.get(2);
+                let packed = match option.and_then(|option| option.get("compressed")).and_then(|compressed| compressed.as_str()){
+                    Some("gzip") => gunzip(packed)?,
+                    Some("text") | None => packed.to_vec(),
+                    Some(other) => return Err(anyhow::anyhow!("Entries compressed with {}? We only know gzip", other)),
+                };
+                let entries = msgpack::decode_all(&packed)?.iter().map(entry).collect::<Result<Vec<(i64, Value)>>>()?;
+                (entries, option)
+            },
+            _ => {
+                // Message mode: just the one
+                let record = parts.get(2).cloned().ok_or_else(|| anyhow::anyhow!("A message needs a record after its time"))?;
+                (vec![(time(&parts[1])?, record)], parts.get(3))
+            },
+        };
+        Ok(Batch{
+            tag,
+            entries,
+            chunk: option.and_then(|option| option.get("chunk")).and_then(|chunk| chunk.as_str()).map(|chunk| chunk.to_string()),
+        })
+    }
+
+    ///
+    /// Every entry as an event: the line is the record's log (or message, or msg), with the rest of the record
+    /// (nested maps flattened into dotted keys) on the front of it as key=value, the same as Loki's labels (see loki::flatten)
+    ///
+    pub fn to_writable_events(&self, tag_mode: TagMode) -> Vec<WritableEvent> {
+        self.entries.iter().map(|(time, record)| {
+            let mut fields = BTreeMap::new();
+            flatten_record("", record, &mut fields);
+            let line = LINE_FIELDS.iter().find_map(|field| fields.remove(*field)).unwrap_or_default();
+            match tag_mode{
+                TagMode::Field => {
+                    fields.insert("tag".to_string(), self.tag.clone());
+                    if !["host", "hostname", "instance"].iter().any(|label| fields.contains_key(*label)) {
+                        fields.insert("host".to_string(), self.tag.clone());
+                    }
+                },
+                TagMode::Host => {
+                    fields.insert("host".to_string(), self.tag.clone());
+                },
+            }
+            let (host, prefix) = crate::loki::flatten(&fields);
+            WritableEvent{
+                event: if prefix.is_empty() { line } else if line.is_empty() { prefix } else { format!("{} {}", prefix, line) },
+                time: *time,
+                host,
+            }
+        }).collect()
+    }
+}
+
+///
+/// `[time, record]`
+///
+fn entry(entry: &Value) -> Result<(i64, Value)> {
+    match entry{
+        Value::Array(parts) if parts.len() >= 2 => Ok((time(&parts[0])?, parts[1].clone())),
+        _ => Err(anyhow::anyhow!("An entry should be [time, record], not {:?}", entry)),
+    }
+}
+
+///
+/// Seconds since the epoch, or an EventTime (ext type 0: seconds and nanoseconds, 32 bits each), in microseconds
+///
+fn time(time: &Value) -> Result<i64> {
+    match time{
+        Value::UInt(seconds) => Ok(*seconds as i64 * 1_000_000),
+        Value::Int(seconds) => Ok(seconds * 1_000_000),
+        Value::Float(seconds) => Ok((seconds * 1_000_000.0) as i64),
+        Value::Ext(0, data) if data.len() == 8 => {
+            let seconds = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as i64;
+            let nanoseconds = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as i64;
+            Ok(seconds * 1_000_000 + nanoseconds / 1000)
+        },
+        _ => Err(anyhow::anyhow!("An entry's time should be seconds or an EventTime, not {:?}", time)),
+    }
+}
+
+fn gunzip(compressed: &[u8]) -> Result<Vec<u8>> {
+    // Fluent Bit gzips each chunk on its own and sends them back to back: that's several gzip members, not one
+    let mut decompressed = Vec::new();
+    flate2::read::MultiGzDecoder::new(compressed).take(MAX_MESSAGE_BYTES as u64 + 1).read_to_end(&mut decompressed)?;
+    if decompressed.len() > MAX_MESSAGE_BYTES {
+        return Err(anyhow::anyhow!("More than {} bytes of entries once they're decompressed", MAX_MESSAGE_BYTES));
+    }
+    Ok(decompressed)
+}
+
+fn flatten_record(prefix: &str, value: &Value, fields: &mut BTreeMap<String, String>) {
+    match value{
+        Value::Map(entries) => {
+            for (key, value) in entries {
+                let key = match key.as_str(){
+                    Some(key) => key.to_string(),
+                    None => format!("{:?}", key),
+                };
+                let key = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+                flatten_record(&key, value, fields);
+            }
+        },
+        _ => {
+            fields.insert(prefix.to_string(), text(value));
+        },
+    }
+}
+
+fn text(value: &Value) -> String {
+    match value{
+        Value::Nil => String::new(),
+        Value::Bool(value) => value.to_string(),
+        Value::Int(value) => value.to_string(),
+        Value::UInt(value) => value.to_string(),
+        Value::Float(value) => value.to_string(),
+        Value::Str(value) => value.clone(),
+        Value::Bin(value) => String::from_utf8(value.clone()).unwrap_or_else(|_| hex::encode(value)),
+        Value::Array(values) => format!("[{}]", values.iter().map(text).collect::<Vec<String>>().join(", ")),
+        Value::Map(_) => {
+            let mut fields = BTreeMap::new();
+            flatten_record("", value, &mut fields);
+            format!("{{{}}}", fields.iter().map(|(key, value)| format!("{}: {}", key, value)).collect::<Vec<String>>().join(", "))
+        },
+        Value::Ext(_, data) => hex::encode(data),
+    }
+}
+
+fn sha512_hex(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn str(value: &str) -> Value {
+    Value::Str(value.to_string())
+}
+
+///
+/// The forward protocol's shared key handshake: we send HELO with a nonce, the client answers with a PING that proves it knows
+/// the shared key, and we PONG back to prove we do too. Every ingest token is a shared key, so whichever one the client used
+/// picks its tenant.
+///
+struct Handshake{
+    nonce: Vec<u8>,
+}
+
+impl Handshake{
+    fn new(peer: Option<IpAddr>) -> Handshake {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+        let mut hasher = Sha256::new();
+        hasher.update(now.to_be_bytes());
+        hasher.update(format!("{:?} {:?}", peer, std::thread::current().id()).as_bytes());
+        Handshake{ nonce: hasher.finalize()[..16].to_vec() }
+    }
+
+    fn helo(&self) -> Value {
+        Value::Array(vec![
+            str("HELO"),
+            Value::Map(vec![
+                (str("nonce"), Value::Bin(self.nonce.clone())),
+                // no usernames and passwords, just the shared key
+                (str("auth"), Value::Bin(Vec::new())),
+                (str("keepalive"), Value::Bool(true)),
+            ]),
+        ])
+    }
+
+    ///
+    /// `["PING", client_hostname, shared_key_salt, sha512_hex(salt + client_hostname + nonce + shared_key), username, password]`:
+    /// which of `keys` the client used (if any), and the PONG to send back
+    ///
+    fn ping<'a>(&self, ping: &Value, keys: impl IntoIterator<Item = &'a String>, server_hostname: &str) -> (Option<String>, Value) {
+        let parts = match ping{
+            Value::Array(parts) if parts.len() >= 4 && parts[0].as_str() == Some("PING") => parts,
+            _ => return (None, pong(false, "expected a PING", server_hostname, "")),
+        };
+        let client_hostname = parts[1].as_str().unwrap_or_default();
+        let salt = parts[2].as_bytes().unwrap_or_default();
+        let digest = parts[3].as_str().unwrap_or_default();
+        let key = keys.into_iter().find(|key| sha512_hex(&[salt, client_hostname.as_bytes(), &self.nonce, key.as_bytes()]) == digest);
+        match key{
+            Some(key) => {
+                let digest = sha512_hex(&[salt, server_hostname.as_bytes(), &self.nonce, key.as_bytes()]);
+                (Some(key.clone()), pong(true, "", server_hostname, &digest))
+            },
+            None => (None, pong(false, "shared key mismatch", server_hostname, "")),
+        }
+    }
+}
+
+fn pong(ok: bool, reason: &str, server_hostname: &str, digest: &str) -> Value {
+    Value::Array(vec![str("PONG"), Value::Bool(ok), str(reason), str(server_hostname), str(digest)])
+}
+
+///
+/// Reads whole msgpack values off a connection
+///
+struct Connection{
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Connection{
+    ///
+    /// The next value, and how many bytes it was, or None when the client hangs up
+    ///
+    fn next(&mut self) -> Result<Option<(Value, usize)>> {
+        loop {
+            if let Some((value, used)) = msgpack::decode(&self.buffer)? {
+                self.buffer.drain(..used);
+                return Ok(Some((value, used)));
+            }
+            if self.buffer.len() > MAX_MESSAGE_BYTES {
+                return Err(anyhow::anyhow!("A message more than {} bytes long", MAX_MESSAGE_BYTES));
+            }
+            let mut chunk = [0; 64 * 1024];
+            let read = self.stream.read(&mut chunk)?;
+            if read == 0 {
+                if !self.buffer.is_empty() {
+                    return Err(anyhow::anyhow!("Hung up partway through a message"));
+                }
+                return Ok(None);
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    fn send(&mut self, value: &Value) -> Result<()> {
+        let mut out = Vec::new();
+        msgpack::encode(value, &mut out);
+        self.stream.write_all(&out)?;
+        Ok(())
+    }
+}
+
+///
+/// Fluentd's and Fluent Bit's `forward` output, over plain TCP (put TLS in front of it if you need it).
+/// With INGEST_TOKENS set, clients have to do the shared key handshake, with an ingest token as the shared key
+/// (Fluent Bit: `Shared_Key`), wherever they're connecting from; without, anybody can send, to the default tenant.
+/// Asking for acks (`Require_ack_response`) is a good idea: a batch we can't take gets no ack, and gets sent again.
+///
+pub struct ForwardReceiver{
+    pub tenants: Arc<crate::tenant::Tenants>,
+    pub ingest_policy: Arc<crate::auth::IngestPolicy>,
+    pub rate_limiter: Arc<crate::rate_limit::IngestRateLimiter>,
+    pub standby: Option<Arc<crate::replication::Standby>>,
+    pub tag_mode: TagMode,
+}
+
+impl ForwardReceiver{
+    ///
+    /// Serve forward on `port` until the process exits, a thread per connection
+    ///
+    pub fn serve(self: Arc<Self>, port: u16) -> Result<()> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        tracing::info!("Listening for Fluent forward on {}", listener.local_addr()?);
+        self.accept(listener)
+    }
+
+    fn accept(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = match stream{
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::warn!("Error accepting a Fluent forward connection: {}", err);
+                    continue;
+                }
+            };
+            let receiver = self.clone();
+            std::thread::Builder::new().name("fluent-forward".to_string()).spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(err) = receiver.handle(stream) {
+                    tracing::warn!("Fluent forward connection from {:?} closed: {}", peer, err);
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    fn handle(&self, stream: TcpStream) -> Result<()> {
+        if self.standby.as_ref().is_some_and(|standby| !standby.accepts_ingest()) {
+            return Err(anyhow::anyhow!("this node is a standby: send logs to the primary"));
+        }
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        let ip = stream.peer_addr().ok().map(|addr| addr.ip());
+        let mut connection = Connection{ stream, buffer: Vec::new() };
+
+        let token = match self.ingest_policy.tokens.is_empty(){
+            true => None,
+            false => {
+                let handshake = Handshake::new(ip);
+                connection.send(&handshake.helo())?;
+                let Some((ping, _)) = connection.next()? else {
+                    return Ok(());
+                };
+                let server_hostname = std::env::var("HOSTNAME").unwrap_or("logmunch".to_string());
+                let (token, pong) = handshake.ping(&ping, &self.ingest_policy.tokens, &server_hostname);
+                connection.send(&pong)?;
+                Some(token.ok_or_else(|| anyhow::anyhow!("the client's shared key isn't an ingest token"))?)
+            }
+        };
+        let tenant = self.tenants.resolve(token.as_deref(), None)?;
+        let rate_key = self.rate_limiter.key_for(token.as_deref(), ip);
+
+        while let Some((message, bytes)) = connection.next()? {
+            let batch = Batch::parse(message)?;
+            let events = batch.to_writable_events(self.tag_mode);
+            // no way to say "slow down" in this protocol, except by not reading: the client's buffers fill up instead of ours
+            while let Err(limited) = self.rate_limiter.check(&rate_key, events.len(), bytes) {
+                std::thread::sleep(Duration::from_secs(limited.retry_after_seconds.max(1)));
+            }
+            let mut waited = 0;
+            while tenant.queue.enqueue(events.clone()).is_err() {
+                if waited >= MAX_QUEUE_WAIT_SECONDS {
+                    return Err(anyhow::anyhow!("the ingest queue has been full for {}s", waited));
+                }
+                std::thread::sleep(Duration::from_secs(1));
+                waited += 1;
+            }
+            if let Some(chunk) = batch.chunk {
+                connection.send(&Value::Map(vec![(str("ack"), Value::Str(chunk))]))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    msgpack::encode(value, &mut out);
+    out
+}
+
+#[test]
+fn test_msgpack() -> Result<()> {
+    let value = Value::Array(vec![
+        str("app.web"),
+        Value::Map(vec![(str("status"), Value::UInt(500)), (str("ok"), Value::Bool(false)), (str("ratio"), Value::Float(0.5))]),
+        Value::Int(-3),
+        Value::Nil,
+        Value::Bin(vec![1, 2, 3]),
+        Value::Str("x".repeat(300)),
+        Value::Ext(0, vec![0; 8]),
+        Value::Array((0..20).map(Value::UInt).collect()),
+    ]);
+    let bytes = encode(&value);
+    assert_eq!(msgpack::decode(&bytes)?, Some((value, bytes.len())));
+    // half a message is just not there yet
+    assert_eq!(msgpack::decode(&bytes[..bytes.len() / 2])?, None);
+    assert!(msgpack::decode(&[0xc1]).is_err());
+
+    // the compact forms other encoders use
+    assert_eq!(msgpack::decode(&[0x93, 0x01, 0xff, 0xa2, b'h', b'i'])?, Some((Value::Array(vec![Value::UInt(1), Value::Int(-1), str("hi")]), 6)));
+    assert_eq!(msgpack::decode(&[0xd1, 0xff, 0x38])?, Some((Value::Int(-200), 3)));
+    assert_eq!(msgpack::decode(&[0xca, 0x3f, 0x80, 0x00, 0x00])?, Some((Value::Float(1.0), 5)));
+    Ok(())
+}
+
+#[test]
+fn test_forward_modes() -> Result<()> {
+    let record = |line: &str| Value::Map(vec![(str("log"), str(line)), (str("kubernetes"), Value::Map(vec![(str("pod_name"), str("web-7d9f"))])), (str("stream"), str("stderr"))]);
+    let event_time = Value::Ext(0, [1710561600u32.to_be_bytes(), 123456789u32.to_be_bytes()].concat());
+
+    // Message
+    let batch = Batch::parse(Value::Array(vec![str("kube.web"), Value::UInt(1710561600), record("boom")]))?;
+    assert_eq!(batch.entries, vec![(1710561600000000, record("boom"))]);
+    assert_eq!(batch.chunk, None);
+
+    // Forward, asking for an ack
+    let batch = Batch::parse(Value::Array(vec![
+        str("kube.web"),
+        Value::Array(vec![Value::Array(vec![event_time.clone(), record("one")]), Value::Array(vec![Value::UInt(1710561601), record("two")])]),
+        Value::Map(vec![(str("chunk"), str("p8n9gmxTQVC8/nh2wlKKeQ=="))]),
+    ]))?;
+    assert_eq!(batch.entries.iter().map(|(time, _)| *time).collect::<Vec<i64>>(), vec![1710561600123456, 1710561601000000]);
+    assert_eq!(batch.chunk, Some("p8n9gmxTQVC8/nh2wlKKeQ==".to_string()));
+
+    // PackedForward, and CompressedPackedForward (two gzip members, the way Fluent Bit sends them)
+    let packed = [encode(&Value::Array(vec![event_time.clone(), record("one")])), encode(&Value::Array(vec![event_time.clone(), record("two")]))];
+    let batch = Batch::parse(Value::Array(vec![str("kube.web"), Value::Bin(packed.concat())]))?;
+    assert_eq!(batch.entries.len(), 2);
+    let gzip = |bytes: &[u8]| -> Result<Vec<u8>> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes)?;
+        Ok(encoder.finish()?)
+    };
+    let compressed = [gzip(&packed[0])?, gzip(&packed[1])?].concat();
+    let compressed_batch = Batch::parse(Value::Array(vec![str("kube.web"), Value::Bin(compressed), Value::Map(vec![(str("compressed"), str("gzip"))])]))?;
+    assert_eq!(compressed_batch, batch);
+
+    assert!(Batch::parse(Value::Array(vec![str("kube.web")])).is_err());
+    assert!(Batch::parse(Value::Array(vec![str("kube.web"), Value::Bin(packed[0][..5].to_vec())])).is_err());
+
+    // the tag's a field (and the host, since the record hasn't got one), or the host
+    let events = batch.to_writable_events(TagMode::Field);
+    assert_eq!(events[0].host, "kube.web");
+    assert_eq!(events[0].event, "kubernetes.pod_name=web-7d9f stream=stderr tag=kube.web one");
+    assert_eq!(events[0].time, 1710561600123456);
+    let batch = Batch::parse(Value::Array(vec![str("kube.web"), Value::UInt(1710561600), Value::Map(vec![(str("message"), str("hi there")), (str("hostname"), str("node-3"))])]))?;
+    assert_eq!(batch.to_writable_events(TagMode::Field)[0].host, "node-3");
+    assert_eq!(batch.to_writable_events(TagMode::Field)[0].event, "tag=kube.web hi there");
+    assert_eq!(batch.to_writable_events(TagMode::Host)[0].host, "kube.web");
+    assert_eq!(batch.to_writable_events(TagMode::Host)[0].event, "hostname=node-3 hi there");
+    Ok(())
+}
+
+#[test]
+fn test_handshake() -> Result<()> {
+    let handshake = Handshake::new(None);
+    let Value::Array(parts) = handshake.helo() else { panic!("HELO should be an array") };
+    assert_eq!(parts[1].get("nonce").and_then(|nonce| nonce.as_bytes()), Some(handshake.nonce.as_slice()));
+
+    let keys = vec!["wrong".to_string(), "sekrit".to_string()];
+    let digest = sha512_hex(&[b"salt", b"fluent-bit-1", &handshake.nonce, b"sekrit"]);
+    let ping = Value::Array(vec![str("PING"), str("fluent-bit-1"), str("salt"), str(&digest), str(""), str("")]);
+    let (key, pong) = handshake.ping(&ping, &keys, "logmunch-1");
+    assert_eq!(key, Some("sekrit".to_string()));
+    let expected = sha512_hex(&[b"salt", b"logmunch-1", &handshake.nonce, b"sekrit"]);
+    assert_eq!(pong, Value::Array(vec![str("PONG"), Value::Bool(true), str(""), str("logmunch-1"), str(&expected)]));
+
+    let ping = Value::Array(vec![str("PING"), str("fluent-bit-1"), str("salt"), str("nope"), str(""), str("")]);
+    let (key, pong) = handshake.ping(&ping, &keys, "logmunch-1");
+    assert_eq!(key, None);
+    let Value::Array(parts) = pong else { panic!("PONG should be an array") };
+    assert_eq!(parts[1], Value::Bool(false));
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use fxhash::FxHashSet as HashSet;
+use growable_bloom_filter::GrowableBloom;
+
+use crate::minute::Minute;
+use crate::search_token::{Modifiers, Search};
+
+///
+/// The bodies of the cargo-fuzz targets in fuzz/ (`cargo fuzz run search`), kept in here so that they get built
+/// (and get a quick run in test_fuzz_harnesses) along with everything else.
+///
+/// Anything a person can type in a search box ends up in `search`: it can fail to parse, but it must never panic,
+/// and whatever it matches, the bloom filters must let through (a bloom that says no to a minute that has a match loses logs).
+///
+pub fn search(data: &[u8]) {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    // the first line is the search, the rest is a log for it to look at
+    let (search, log) = text.split_once('\n').unwrap_or((text, text));
+    for (case_sensitive, whole_word) in [(false, false), (true, false), (false, true), (true, true)] {
+        let Ok(search) = Search::parse_with_modifiers(search, Modifiers{ case_sensitive, whole_word }) else {
+            continue;
+        };
+        let matched = search.test_log("fuzz-host", log);
+        let mut trigrams = HashSet::default();
+        Minute::explode(&mut trigrams, &format!("fuzz-host {}", log));
+        let mut bloom = GrowableBloom::new(0.01, trigrams.len().max(1));
+        for trigram in &trigrams {
+            bloom.insert(trigram);
+        }
+        if matched {
+            assert!(search.bloom_plan().test(&bloom), "the bloom filter turned down a minute where {:?} matches {:?}", search.search_string, log);
+        }
+        search.expensive_operator();
+        search.cache_key();
+        let _ = serde_json::to_string(&search);
+    }
+}
+
+///
+/// Every log that gets written goes through explode
+///
+pub fn explode(data: &[u8]) {
+    let text = String::from_utf8_lossy(data);
+    let mut trigrams = HashSet::default();
+    Minute::explode(&mut trigrams, &text);
+    for trigram in &trigrams {
+        assert_eq!(trigram.chars().count(), 3, "{:?} isn't a trigram", trigram);
+        assert!(!trigram.chars().any(char::is_whitespace), "{:?} has whitespace in it", trigram);
+    }
+}
+
+#[test]
+fn test_fuzz_harnesses() {
+    // not a fuzzer, but a thousand searches made out of the characters the parser cares about, to catch the obvious
+    let alphabet: Vec<char> = "ab cd()|&!*\"\\:cs:word:has:missing:İıΣς é\n".chars().collect();
+    let mut state: u64 = 0x2545F4914F6CDD1D;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for _ in 0..1000 {
+        let length = (next() % 24) as usize;
+        let text: String = (0..length).map(|_| alphabet[(next() % alphabet.len() as u64) as usize]).collect();
+        search(text.as_bytes());
+        explode(text.as_bytes());
+    }
+    for text in ["(((((a", "a))))", "\"", "\\", "cs:", "word:\"", "***", "!!!!", "a | | b", "has:", "İstanbul\nistanbul İSTANBUL"] {
+        search(text.as_bytes());
+    }
+    search(&[0xff, 0xfe, b'(']);
+    explode(&[0xff, 0xfe, b'a', b'b', b'c']);
+}
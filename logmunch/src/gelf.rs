@@ -0,0 +1,356 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read};
+use std::net::{IpAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::Result;
+
+use crate::WritableEvent;
+
+///
+/// Chunked GELF starts with these two bytes, then an 8 byte message id, then the chunk's number and how many there are
+///
+const CHUNK_MAGIC: [u8; 2] = [0x1e, 0x0f];
+const CHUNK_HEADER_BYTES: usize = 12;
+
+///
+/// The spec's limit: a message in more chunks than this gets thrown away
+///
+const MAX_CHUNKS: u8 = 128;
+
+///
+/// ...and so does one that hasn't got all its chunks in this long
+///
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(5);
+
+///
+/// Half-assembled messages we'll keep track of at once: past that, somebody's spraying chunks that never finish
+///
+const MAX_PENDING_MESSAGES: usize = 1000;
+
+///
+/// A message (once it's decompressed, or between the nulls on TCP) bigger than this isn't a log line
+///
+const MAX_MESSAGE_BYTES: usize = 8 * 1024 * 1024;
+
+///
+/// Docker holds its GELF TCP connections open between containers' worth of logs: one that's said nothing for this long has gone away
+///
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+///
+/// How many seconds a TCP message waits for room in a full ingest queue before we hang up
+///
+const MAX_QUEUE_WAIT_SECONDS: u64 = 30;
+
+///
+/// GELF's fields that aren't "additional" ones
+///
+const STANDARD_FIELDS: [&str; 6] = ["version", "host", "short_message", "full_message", "timestamp", "level"];
+
+///
+/// One GELF message (uncompressed, gzipped, or zlibbed, whichever it came as) as an event:
+/// the line is full_message (or short_message, if there's no full one), and every additional field, underscore and all
+/// taken off, goes on the front of it as key=value, the same as Loki's labels (see loki::flatten). The syslog level
+/// number becomes `level=error` and so on; `timestamp` is seconds since the epoch, with a fraction.
+///
+pub fn parse(payload: &[u8]) -> Result<WritableEvent> {
+    let payload = decompress(payload)?;
+    let message = match serde_json::from_slice::<serde_json::Value>(&payload)?{
+        serde_json::Value::Object(message) => message,
+        other => return Err(anyhow::anyhow!("A GELF message should be a JSON object, not {}", other)),
+    };
+
+    let text = |value: &serde_json::Value| match value{
+        serde_json::Value::String(value) => value.clone(),
+        other => other.to_string(),
+    };
+    let line = message.get("full_message").or(message.get("short_message")).map(text)
+        .ok_or_else(|| anyhow::anyhow!("A GELF message needs a short_message"))?;
+    let time = match message.get("timestamp").and_then(|timestamp| timestamp.as_f64()){
+        Some(seconds) => (seconds * 1_000_000.0) as i64,
+        None => SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_micros() as i64,
+    };
+
+    let mut fields: BTreeMap<String, String> = message.iter()
+        .filter(|(key, _)| !STANDARD_FIELDS.contains(&key.as_str()) && key.as_str() != "_id")
+        .map(|(key, value)| (key.strip_prefix('_').unwrap_or(key).to_string(), text(value)))
+        .collect();
+    if let Some(level) = message.get("level").and_then(|level| level.as_u64()).and_then(level_name) {
+        fields.insert("level".to_string(), level.to_string());
+    }
+    // the host label picks the host (see loki::flatten): GELF's own host wins over an additional field called host
+    for label in ["host", "hostname", "instance"] {
+        fields.remove(label);
+    }
+    fields.insert("host".to_string(), message.get("host").map(text).unwrap_or("unknown".to_string()));
+    let (host, prefix) = crate::loki::flatten(&fields);
+
+    Ok(WritableEvent{
+        event: if prefix.is_empty() { line } else { format!("{} {}", prefix, line) },
+        time,
+        host,
+    })
+}
+
+///
+/// Syslog severities, in the names enrich::detect_level knows
+///
+pub(crate) fn level_name(level: u64) -> Option<&'static str> {
+    match level{
+        0..=2 => Some("fatal"),
+        3 => Some("error"),
+        4 => Some("warn"),
+        5 | 6 => Some("info"),
+        7 => Some("debug"),
+        _ => None,
+    }
+}
+
+fn decompress(payload: &[u8]) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    match payload{
+        [0x1f, 0x8b, ..] => flate2::read::GzDecoder::new(payload).take(MAX_MESSAGE_BYTES as u64 + 1).read_to_end(&mut decompressed)?,
+        // zlib: 0x78 and a second byte that makes the pair a multiple of 31
+        [0x78, second, ..] if (0x7800 | *second as u16).is_multiple_of(31) => flate2::read::ZlibDecoder::new(payload).take(MAX_MESSAGE_BYTES as u64 + 1).read_to_end(&mut decompressed)?,
+        _ => return Ok(payload.to_vec()),
+    };
+    if decompressed.len() > MAX_MESSAGE_BYTES {
+        return Err(anyhow::anyhow!("A GELF message more than {} bytes long once it's decompressed", MAX_MESSAGE_BYTES));
+    }
+    Ok(decompressed)
+}
+
+struct PartialMessage{
+    first_seen: Instant,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+///
+/// Puts chunked UDP messages back together. Chunks can turn up in any order (or twice); a message that
+/// isn't whole within CHUNK_TIMEOUT is dropped.
+///
+#[derive(Default)]
+pub struct Chunks{
+    pending: HashMap<[u8; 8], PartialMessage>,
+}
+
+impl Chunks{
+    ///
+    /// The whole payload, if this datagram was all of a message or finished one off
+    ///
+    pub fn add(&mut self, datagram: &[u8], now: Instant) -> Result<Option<Vec<u8>>> {
+        if !datagram.starts_with(&CHUNK_MAGIC) {
+            return Ok(Some(datagram.to_vec()));
+        }
+        if datagram.len() < CHUNK_HEADER_BYTES {
+            return Err(anyhow::anyhow!("A GELF chunk shorter than its header"));
+        }
+        let id: [u8; 8] = datagram[2..10].try_into()?;
+        let (sequence, count) = (datagram[10], datagram[11]);
+        if count == 0 || count > MAX_CHUNKS || sequence >= count {
+            return Err(anyhow::anyhow!("GELF chunk {} of {} isn't a chunk we can use", sequence, count));
+        }
+
+        self.pending.retain(|_, partial| now.duration_since(partial.first_seen) < CHUNK_TIMEOUT);
+        if !self.pending.contains_key(&id) && self.pending.len() >= MAX_PENDING_MESSAGES {
+            return Err(anyhow::anyhow!("{} chunked GELF messages are already half finished", MAX_PENDING_MESSAGES));
+        }
+        let partial = self.pending.entry(id).or_insert_with(|| PartialMessage{
+            first_seen: now,
+            chunks: vec![None; count as usize],
+        });
+        if partial.chunks.len() != count as usize {
+            self.pending.remove(&id);
+            return Err(anyhow::anyhow!("GELF chunks for the same message disagree about how many there are"));
+        }
+        partial.chunks[sequence as usize] = Some(datagram[CHUNK_HEADER_BYTES..].to_vec());
+        if partial.chunks.iter().any(|chunk| chunk.is_none()) {
+            return Ok(None);
+        }
+        let partial = self.pending.remove(&id).unwrap();
+        Ok(Some(partial.chunks.into_iter().flatten().flatten().collect()))
+    }
+}
+
+///
+/// GELF over UDP (chunked or not) and TCP (null-terminated, uncompressed), on the same port, the way Graylog does it:
+/// Docker's `gelf` log driver can point straight at it. There's no way to send a token, so with INGEST_TOKENS set
+/// only INGEST_TRUSTED_CIDRS can use it; everything goes to the default tenant.
+///
+pub struct GelfReceiver{
+    pub tenants: Arc<crate::tenant::Tenants>,
+    pub ingest_policy: Arc<crate::auth::IngestPolicy>,
+    pub rate_limiter: Arc<crate::rate_limit::IngestRateLimiter>,
+    pub standby: Option<Arc<crate::replication::Standby>>,
+}
+
+impl GelfReceiver{
+    ///
+    /// Anything that stops this sender's logs going in right now. Over TCP we'll wait that out;
+    /// over UDP there's nobody to tell, so the message gets dropped.
+    ///
+    fn admit(&self, ip: Option<IpAddr>) -> Result<()> {
+        if !self.ingest_policy.allows(ip, None) {
+            return Err(anyhow::anyhow!("{:?} isn't in INGEST_TRUSTED_CIDRS, and GELF can't send a token", ip));
+        }
+        if self.standby.as_ref().is_some_and(|standby| !standby.accepts_ingest()) {
+            return Err(anyhow::anyhow!("this node is a standby: send logs to the primary"));
+        }
+        Ok(())
+    }
+
+    pub fn serve_udp(&self, port: u16) -> Result<()> {
+        let socket = UdpSocket::bind(("0.0.0.0", port))?;
+        tracing::info!("Listening for GELF over UDP on {}", socket.local_addr()?);
+        let tenant = self.tenants.resolve(None, None)?;
+        let mut chunks = Chunks::default();
+        // the biggest a datagram can be
+        let mut buffer = vec![0; 65536];
+        let mut dropped: u64 = 0;
+        loop {
+            let (length, from) = socket.recv_from(&mut buffer)?;
+            let ip = Some(from.ip());
+            let event = self.admit(ip)
+                .and_then(|_| chunks.add(&buffer[..length], Instant::now()))
+                .and_then(|payload| payload.map(|payload| parse(&payload)).transpose());
+            let delivered = match event{
+                Ok(None) => continue,
+                Ok(Some(event)) => {
+                    let bytes = event.event.len();
+                    self.rate_limiter.check(&self.rate_limiter.key_for(None, ip), 1, bytes).map_err(|limited| anyhow::anyhow!(limited.to_string()))
+                        .and_then(|_| tenant.queue.enqueue(vec![event]).map(|_| ()).map_err(|err| anyhow::anyhow!(err.to_string())))
+                },
+                Err(err) => Err(err),
+            };
+            if let Err(err) = delivered {
+                dropped += 1;
+                // one warning per thousand, or a flood of garbage is a flood of warnings
+                if dropped % 1000 == 1 {
+                    tracing::warn!("Dropped a GELF message from {} ({} so far): {}", from, dropped, err);
+                }
+            }
+        }
+    }
+
+    ///
+    /// A thread per connection, like fluent::ForwardReceiver
+    ///
+    pub fn serve_tcp(self: Arc<Self>, port: u16) -> Result<()> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        tracing::info!("Listening for GELF over TCP on {}", listener.local_addr()?);
+        for stream in listener.incoming() {
+            let stream = match stream{
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::warn!("Error accepting a GELF connection: {}", err);
+                    continue;
+                }
+            };
+            let receiver = self.clone();
+            std::thread::Builder::new().name("gelf-tcp".to_string()).spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(err) = receiver.handle(stream) {
+                    tracing::warn!("GELF connection from {:?} closed: {}", peer, err);
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    fn handle(&self, stream: TcpStream) -> Result<()> {
+        let ip = stream.peer_addr().ok().map(|addr| addr.ip());
+        self.admit(ip)?;
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        let tenant = self.tenants.resolve(None, None)?;
+        let rate_key = self.rate_limiter.key_for(None, ip);
+
+        let mut reader = BufReader::new(stream).take(MAX_MESSAGE_BYTES as u64 + 1);
+        let mut message = Vec::new();
+        loop {
+            message.clear();
+            reader.set_limit(MAX_MESSAGE_BYTES as u64 + 1);
+            if reader.read_until(0, &mut message)? == 0 {
+                return Ok(());
+            }
+            if message.len() > MAX_MESSAGE_BYTES {
+                return Err(anyhow::anyhow!("A GELF message more than {} bytes long", MAX_MESSAGE_BYTES));
+            }
+            if message.last() == Some(&0) {
+                message.pop();
+            }
+            // some senders put a newline after the null, too
+            if message.iter().all(|byte| byte.is_ascii_whitespace()) {
+                continue;
+            }
+            let event = parse(&message)?;
+
+            // the sender can wait: it'll back up behind us instead of losing logs
+            while let Err(limited) = self.rate_limiter.check(&rate_key, 1, message.len()) {
+                std::thread::sleep(Duration::from_secs(limited.retry_after_seconds.max(1)));
+            }
+            let mut waited = 0;
+            while tenant.queue.enqueue(vec![event.clone()]).is_err() {
+                if waited >= MAX_QUEUE_WAIT_SECONDS {
+                    return Err(anyhow::anyhow!("the ingest queue has been full for {}s", waited));
+                }
+                std::thread::sleep(Duration::from_secs(1));
+                waited += 1;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse() -> Result<()> {
+    use std::io::Write;
+
+    let message = r#"{"version": "1.1", "host": "docker-host-1", "short_message": "boom", "full_message": "boom\n  at main.rs:12",
+        "timestamp": 1710561600.123, "level": 3, "_container_name": "web", "_tag": "web app", "_id": "ignored", "_host": "not this one"}"#;
+    let event = parse(message.as_bytes())?;
+    assert_eq!(event.host, "docker-host-1");
+    assert_eq!(event.time, 1710561600123000);
+    assert_eq!(event.event, "container_name=web level=error tag=\"web app\" boom\n  at main.rs:12");
+
+    // gzip and zlib come out the same
+    let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gzip.write_all(message.as_bytes())?;
+    assert_eq!(parse(&gzip.finish()?)?.event, event.event);
+    let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    zlib.write_all(message.as_bytes())?;
+    assert_eq!(parse(&zlib.finish()?)?.event, event.event);
+
+    let event = parse(br#"{"version": "1.1", "short_message": "hi", "_status": 200}"#)?;
+    assert_eq!(event.host, "unknown");
+    assert_eq!(event.event, "status=200 hi");
+    assert!(parse(br#"{"version": "1.1", "host": "x"}"#).is_err());
+    assert!(parse(b"[1, 2]").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_chunks() -> Result<()> {
+    let chunk = |id: u8, sequence: u8, count: u8, data: &[u8]| [&CHUNK_MAGIC[..], &[id; 8], &[sequence, count], data].concat();
+    let now = Instant::now();
+    let mut chunks = Chunks::default();
+
+    // not chunked at all
+    assert_eq!(chunks.add(b"{}", now)?, Some(b"{}".to_vec()));
+
+    // out of order, with another message in between and a repeat
+    assert_eq!(chunks.add(&chunk(1, 2, 3, b"c"), now)?, None);
+    assert_eq!(chunks.add(&chunk(2, 0, 2, b"x"), now)?, None);
+    assert_eq!(chunks.add(&chunk(1, 0, 3, b"a"), now)?, None);
+    assert_eq!(chunks.add(&chunk(1, 0, 3, b"a"), now)?, None);
+    assert_eq!(chunks.add(&chunk(1, 1, 3, b"b"), now)?, Some(b"abc".to_vec()));
+    assert_eq!(chunks.add(&chunk(2, 1, 2, b"y"), now)?, Some(b"xy".to_vec()));
+
+    // too slow
+    assert_eq!(chunks.add(&chunk(3, 0, 2, b"x"), now)?, None);
+    assert_eq!(chunks.add(&chunk(3, 1, 2, b"y"), now + CHUNK_TIMEOUT)?, None);
+
+    assert!(chunks.add(&chunk(4, 3, 3, b"x"), now).is_err());
+    assert!(chunks.add(&chunk(4, 0, 200, b"x"), now).is_err());
+    assert!(chunks.add(&CHUNK_MAGIC, now).is_err());
+    Ok(())
+}
@@ -0,0 +1,276 @@
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+use crate::markers::Marker;
+use crate::minute::Log;
+use crate::minute_db::HistogramBucket;
+
+///
+/// A search annotation flags at most this many logs (the most recent ones): past that the flags are just a smear along the top of the panel
+///
+pub const MAX_ANNOTATIONS: usize = 100;
+
+///
+/// However narrow the interval, a time series never gets more points than this: the buckets get wider instead
+///
+const MAX_DATAPOINTS: i64 = 10_000;
+
+///
+/// `{"from": "2024-03-16T04:00:00.000Z", "to": "2024-03-16T05:00:00.000Z"}`
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct Range{
+    pub from: String,
+    pub to: String,
+}
+
+impl Range{
+    ///
+    /// from and to, in seconds since the epoch
+    ///
+    pub fn seconds(&self) -> Result<(i64, i64)> {
+        let parse = |time: &str| chrono::DateTime::parse_from_rfc3339(time)
+            .map(|time| time.timestamp())
+            .map_err(|_| anyhow::anyhow!("'{}' isn't an RFC 3339 timestamp", time));
+        let (from, to) = (parse(&self.from)?, parse(&self.to)?);
+        if from > to {
+            return Err(anyhow::anyhow!("The range starts ({}) after it ends ({})", self.from, self.to));
+        }
+        Ok((from, to))
+    }
+}
+
+///
+/// `POST /search`: `{"target": "what the user has typed so far"}`
+///
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SearchRequest{
+    #[serde(default)]
+    pub target: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum TargetType{
+    /// counts of matching logs over time, bucketed by the panel's interval
+    #[default]
+    #[serde(rename = "timeserie", alias = "timeseries")]
+    TimeSerie,
+    /// the matching logs themselves
+    #[serde(rename = "table")]
+    Table,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Target{
+    /// a logmunch search
+    #[serde(default)]
+    pub target: String,
+    #[serde(default, rename = "type")]
+    pub kind: TargetType,
+    /// queries the user has switched off still get sent, with this set
+    #[serde(default)]
+    pub hide: bool,
+}
+
+///
+/// Grafana's JSON datasources (simple-json, and infinity pointed at the same URLs) speak a tiny protocol:
+/// `GET /` to test the connection, `POST /search` for the drop-down of things you can query,
+/// `POST /query` for the panel data and `POST /annotations` for the little flags along the top.
+/// Every "target" a panel asks for is just a logmunch search.
+///
+/// `POST /query`: `{"range": {...}, "intervalMs": 30000, "maxDataPoints": 550, "targets": [{"target": "error", "refId": "A", "type": "timeserie"}]}`
+///
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRequest{
+    pub range: Range,
+    #[serde(default)]
+    pub interval_ms: Option<i64>,
+    #[serde(default)]
+    pub max_data_points: Option<usize>,
+    #[serde(default)]
+    pub targets: Vec<Target>,
+}
+
+impl QueryRequest{
+    ///
+    /// Histogram buckets as wide as Grafana's interval (it already picked one that fits the panel), but never narrower than a second:
+    /// if it didn't send one, enough buckets to fill maxDataPoints, or a minute
+    ///
+    pub fn bucket_seconds(&self, from: i64, to: i64) -> i64 {
+        let bucket_seconds = match (self.interval_ms, self.max_data_points){
+            (Some(interval_ms), _) => (interval_ms / 1000).max(1),
+            (None, Some(points)) if points > 0 => ((to - from) / points as i64).max(1),
+            _ => 60,
+        };
+        bucket_seconds.max((to - from) / MAX_DATAPOINTS)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Column{
+    pub text: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum QueryResult{
+    /// `{"target": "error", "datapoints": [[count, unix milliseconds], ...]}`
+    TimeSerie{
+        target: String,
+        datapoints: Vec<(u64, i64)>,
+    },
+    /// `{"type": "table", "columns": [{"text": "Time", "type": "time"}, ...], "rows": [[unix milliseconds, host, message], ...]}`
+    Table{
+        #[serde(rename = "type")]
+        kind: String,
+        columns: Vec<Column>,
+        rows: Vec<(i64, String, String)>,
+    },
+}
+
+impl QueryResult{
+    ///
+    /// Histograms skip the buckets nothing matched in; Grafana would draw a line straight across those, so they get filled in with zeroes
+    ///
+    pub fn time_serie(target: &str, buckets: Vec<HistogramBucket>, bucket_seconds: i64, from: i64, to: i64) -> QueryResult {
+        let counts: std::collections::BTreeMap<i64, u64> = buckets.into_iter().map(|bucket| (bucket.time, bucket.count)).collect();
+        let mut datapoints = Vec::new();
+        let mut time = from - from.rem_euclid(bucket_seconds);
+        while time <= to {
+            datapoints.push((counts.get(&time).copied().unwrap_or(0), time * 1000));
+            time += bucket_seconds;
+        }
+        QueryResult::TimeSerie{
+            target: target.to_string(),
+            datapoints,
+        }
+    }
+
+    pub fn table(logs: Vec<Log>) -> QueryResult {
+        QueryResult::Table{
+            kind: "table".to_string(),
+            columns: vec![
+                Column{ text: "Time".to_string(), kind: "time".to_string() },
+                Column{ text: "Host".to_string(), kind: "string".to_string() },
+                Column{ text: "Message".to_string(), kind: "string".to_string() },
+            ],
+            rows: logs.into_iter().map(|log| (log.time / 1000, log.host, log.message)).collect(),
+        }
+    }
+}
+
+///
+/// `POST /annotations`: `{"range": {...}, "annotation": {"name": "deploys", "query": "", ...}}`.
+/// The annotation gets handed back with every event, so it's kept as whatever Grafana sent.
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct AnnotationRequest{
+    pub range: Range,
+    pub annotation: serde_json::Value,
+}
+
+impl AnnotationRequest{
+    ///
+    /// The annotation's search: blank means "the markers" (deploys, incidents and so on) instead of logs
+    ///
+    pub fn query(&self) -> &str {
+        self.annotation.get("query").and_then(|query| query.as_str()).unwrap_or("").trim()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AnnotationEvent{
+    pub annotation: serde_json::Value,
+    /// unix milliseconds
+    pub time: i64,
+    pub title: String,
+    pub text: String,
+    pub tags: Vec<String>,
+}
+
+impl AnnotationEvent{
+    pub fn from_marker(annotation: &serde_json::Value, marker: Marker) -> AnnotationEvent {
+        let mut tags = vec![marker.kind.clone()];
+        tags.extend(marker.labels.iter().map(|(key, value)| format!("{}={}", key, value)));
+        AnnotationEvent{
+            annotation: annotation.clone(),
+            time: marker.time * 1000,
+            title: marker.kind,
+            text: marker.message.unwrap_or_default(),
+            tags,
+        }
+    }
+
+    pub fn from_log(annotation: &serde_json::Value, log: Log) -> AnnotationEvent {
+        AnnotationEvent{
+            annotation: annotation.clone(),
+            time: log.time / 1000,
+            title: log.host.clone(),
+            text: log.message,
+            tags: vec![log.host],
+        }
+    }
+}
+
+///
+/// What `POST /search` suggests: a `host:<name>` search for every host that's been sending logs, narrowed down by whatever's been typed
+///
+pub fn suggestions(hosts: impl IntoIterator<Item = String>, typed: &str) -> Vec<String> {
+    let typed = typed.trim().to_lowercase();
+    hosts.into_iter()
+        .map(|host| format!("host:{}", host))
+        .filter(|search| search.to_lowercase().contains(&typed))
+        .collect()
+}
+
+#[test]
+fn test_parse_query_request() -> Result<()> {
+    let request: QueryRequest = serde_json::from_str(r#"{
+        "range": {"from": "2024-03-16T04:00:00.000Z", "to": "2024-03-16T05:00:00.000Z", "raw": {"from": "now-1h", "to": "now"}},
+        "intervalMs": 30000,
+        "maxDataPoints": 120,
+        "targets": [{"target": "error", "refId": "A", "type": "timeserie"}, {"target": "timeout", "refId": "B", "type": "table", "hide": true}, {"refId": "C"}]
+    }"#)?;
+    let (from, to) = request.range.seconds()?;
+    assert_eq!((from, to), (1710561600, 1710565200));
+    assert_eq!(request.bucket_seconds(from, to), 30);
+    // a year at 30 seconds a point is too many points
+    assert_eq!(request.bucket_seconds(0, 86400 * 365), 3153);
+    assert_eq!(request.targets[0].kind, TargetType::TimeSerie);
+    assert_eq!(request.targets[1].kind, TargetType::Table);
+    assert!(request.targets[1].hide);
+    assert_eq!(request.targets[2].target, "");
+
+    let request: QueryRequest = serde_json::from_str(r#"{"range": {"from": "2024-03-16T04:00:00Z", "to": "2024-03-16T05:00:00Z"}, "maxDataPoints": 60}"#)?;
+    assert_eq!(request.bucket_seconds(from, to), 60);
+    let backwards = Range{ from: "2024-03-16T05:00:00Z".to_string(), to: "2024-03-16T04:00:00Z".to_string() };
+    assert!(backwards.seconds().is_err());
+    assert!(Range{ from: "now-1h".to_string(), to: "now".to_string() }.seconds().is_err());
+    Ok(())
+}
+
+#[test]
+fn test_query_results() -> Result<()> {
+    let buckets = vec![HistogramBucket{ time: 120, count: 3, labels: Vec::new(), markers: Vec::new() }];
+    let result = serde_json::to_value(QueryResult::time_serie("error", buckets, 60, 90, 240))?;
+    assert_eq!(result, serde_json::json!({"target": "error", "datapoints": [[0, 60000], [3, 120000], [0, 180000], [0, 240000]]}));
+
+    let log = Log{ id: 1, message: "oh no".to_string(), time: 1710561600123456, host: "web-1".to_string(), shard: None, event_id: None, minute_id: None };
+    let result = serde_json::to_value(QueryResult::table(vec![log.clone()]))?;
+    assert_eq!(result["type"], "table");
+    assert_eq!(result["columns"][0], serde_json::json!({"text": "Time", "type": "time"}));
+    assert_eq!(result["rows"], serde_json::json!([[1710561600123i64, "web-1", "oh no"]]));
+
+    let request: AnnotationRequest = serde_json::from_str(r#"{"range": {"from": "2024-03-16T04:00:00Z", "to": "2024-03-16T05:00:00Z"}, "annotation": {"name": "errors", "query": " error "}}"#)?;
+    assert_eq!(request.query(), "error");
+    let event = AnnotationEvent::from_log(&request.annotation, log);
+    assert_eq!(event.time, 1710561600123);
+    assert_eq!(event.annotation["name"], "errors");
+
+    assert_eq!(suggestions(vec!["web-1".to_string(), "db-1".to_string()], "WEB"), vec!["host:web-1"]);
+    assert_eq!(suggestions(vec!["web-1".to_string(), "db-1".to_string()], "").len(), 2);
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeSet;
+use serde::{Serialize, Deserialize};
+use rocket::fairing::AdHoc;
+
+///
+/// Bump this when the HTTP API changes in a way an older peer wouldn't understand.
+///
+pub const API_VERSION: u32 = 1;
+
+///
+/// Bump this when the on-disk layout of a Minute changes (it's stamped into each minute's `user_version`).
+///  1: log, search_fragments, bloom
+///  2: occurrence (repeated messages stored once, see DEDUP_MESSAGES)
+///  3: search_fragments.min_log_id and max_log_id (which logs in the batch each fragment is in)
+///  4: dictionary (logs in a sealed minute can be zstd with the minute's own dictionary, see LOG_COMPRESSION)
+///  5: host_count (events per host, counted when the minute's sealed)
+///  6: summary (what the minute came to when it was sealed, see Minute::summary)
+///
+pub const MINUTE_FORMAT_VERSION: u32 = 6;
+
+///
+/// What we tell peers (federation, replicas) about ourselves before we start trading searches or minutes.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Handshake{
+    pub api_version: u32,
+    pub minute_format_version: u32,
+    pub build_version: String,
+    pub machine_id: u32,
+    /// which tenants we have minutes for (None is the default tenant), so federated searches only come here for those (see federation).
+    /// Left out by nodes older than that, which get sent every search.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenants: Option<BTreeSet<Option<String>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compatibility{
+    /// same versions: everything works
+    Full,
+    /// one version apart (mid-rolling-upgrade): talk to them, but only use what both sides understand
+    /// (a standby leaves minutes written in a newer format than its own on the primary, see replication::Standby)
+    Degraded,
+    /// too far apart: don't talk to them at all
+    Incompatible,
+}
+
+///
+/// Our reply to a peer's handshake: who we are, and what we think of them.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HandshakeResponse{
+    pub handshake: Handshake,
+    pub compatibility: Compatibility,
+}
+
+impl Handshake{
+    pub fn new(machine_id: u32) -> Handshake {
+        Handshake{
+            api_version: API_VERSION,
+            minute_format_version: MINUTE_FORMAT_VERSION,
+            build_version: env!("CARGO_PKG_VERSION").to_string(),
+            machine_id,
+            tenants: None,
+        }
+    }
+
+    pub fn with_tenants(mut self, tenants: BTreeSet<Option<String>>) -> Handshake {
+        self.tenants = Some(tenants);
+        self
+    }
+
+    ///
+    /// Clusters get upgraded node-by-node, so we have to tolerate peers that are one version behind (or ahead).
+    ///
+    pub fn compatibility(&self, peer: &Handshake) -> Compatibility {
+        let api_distance = self.api_version.abs_diff(peer.api_version);
+        let format_distance = self.minute_format_version.abs_diff(peer.minute_format_version);
+
+        if api_distance > 1 || format_distance > 1 {
+            Compatibility::Incompatible
+        }
+        else if api_distance == 1 || format_distance == 1 {
+            Compatibility::Degraded
+        }
+        else {
+            Compatibility::Full
+        }
+    }
+}
+
+///
+/// Stamp every response with the API version, so peers (and curious humans) can see it without a handshake.
+///
+pub fn version_header() -> AdHoc {
+    AdHoc::on_response("API Version Header", |_request, response| Box::pin(async move {
+        response.set_raw_header("X-Logmunch-Api-Version", API_VERSION.to_string());
+    }))
+}
+
+#[test]
+fn test_compatibility() {
+    let me = Handshake::new(1);
+
+    assert_eq!(me.compatibility(&Handshake::new(2)), Compatibility::Full);
+
+    let mut older = Handshake::new(2);
+    older.api_version = API_VERSION - 1;
+    assert_eq!(me.compatibility(&older), Compatibility::Degraded);
+
+    let mut newer_format = Handshake::new(2);
+    newer_format.minute_format_version = MINUTE_FORMAT_VERSION + 1;
+    assert_eq!(me.compatibility(&newer_format), Compatibility::Degraded);
+
+    let mut ancient = Handshake::new(2);
+    ancient.api_version = API_VERSION + 2;
+    assert_eq!(me.compatibility(&ancient), Compatibility::Incompatible);
+
+    // older nodes don't say which tenants they have
+    let older: Handshake = serde_json::from_str(r#"{"api_version": 1, "minute_format_version": 6, "build_version": "0.1.0", "machine_id": 2}"#).unwrap();
+    assert_eq!(older.tenants, None);
+    let tenants = Handshake::new(2).with_tenants([None, Some("checkout".to_string())].into_iter().collect());
+    assert_eq!(serde_json::from_str::<Handshake>(&serde_json::to_string(&tenants).unwrap()).unwrap(), tenants);
+}
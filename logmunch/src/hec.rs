@@ -0,0 +1,474 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use fxhash::FxHashMap as HashMap;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use serde::{Serialize, Deserialize};
+
+use crate::ingest::IngestQueue;
+
+///
+/// What Splunk's HTTP Event Collector says back. Some forwarders actually read this, so the codes and text
+/// are the documented ones: https://docs.splunk.com/Documentation/Splunk/latest/Data/TroubleshootHTTPEventCollector
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HecResponse{
+    pub text: String,
+    pub code: u32,
+    #[serde(rename = "invalid-event-number", skip_serializing_if = "Option::is_none")]
+    pub invalid_event_number: Option<usize>,
+    #[serde(rename = "ackId", skip_serializing_if = "Option::is_none")]
+    pub ack_id: Option<u64>,
+    #[serde(skip)]
+    pub status: u16,
+    /// how long a rate-limited sender should wait (see rate_limited)
+    #[serde(skip)]
+    pub retry_after_seconds: Option<u64>,
+}
+
+impl HecResponse{
+    fn new(status: u16, code: u32, text: &str, invalid_event_number: Option<usize>) -> HecResponse {
+        HecResponse{
+            text: text.to_string(),
+            code,
+            invalid_event_number,
+            ack_id: None,
+            status,
+            retry_after_seconds: None,
+        }
+    }
+
+    pub fn success() -> HecResponse {
+        Self::new(200, 0, "Success", None)
+    }
+
+    pub fn success_with_ack(ack_id: u64) -> HecResponse {
+        HecResponse{
+            ack_id: Some(ack_id),
+            ..Self::success()
+        }
+    }
+
+    pub fn token_required() -> HecResponse {
+        Self::new(401, 2, "Token is required", None)
+    }
+
+    pub fn invalid_token() -> HecResponse {
+        Self::new(403, 4, "Invalid token", None)
+    }
+
+    pub fn no_data() -> HecResponse {
+        Self::new(400, 5, "No data", None)
+    }
+
+    pub fn invalid_data_format(event_number: usize) -> HecResponse {
+        Self::new(400, 6, "Invalid data format", Some(event_number))
+    }
+
+    pub fn internal_error() -> HecResponse {
+        Self::new(500, 8, "Internal server error", None)
+    }
+
+    pub fn server_busy() -> HecResponse {
+        Self::new(503, 9, "Server is busy", None)
+    }
+
+    ///
+    /// Splunk hasn't got a code for this, so it's "busy" (which forwarders retry), but with a 429 and a Retry-After that says how long
+    ///
+    pub fn rate_limited(limited: &crate::rate_limit::RateLimited) -> HecResponse {
+        HecResponse{
+            retry_after_seconds: Some(limited.retry_after_seconds),
+            ..Self::new(429, 9, "Server is busy", None)
+        }
+    }
+
+    pub fn data_channel_missing() -> HecResponse {
+        Self::new(400, 10, "Data channel is missing", None)
+    }
+
+    pub fn invalid_data_channel() -> HecResponse {
+        Self::new(400, 11, "Invalid data channel", None)
+    }
+
+    pub fn event_required(event_number: usize) -> HecResponse {
+        Self::new(400, 12, "Event field is required", Some(event_number))
+    }
+
+    pub fn event_blank(event_number: usize) -> HecResponse {
+        Self::new(400, 13, "Event field cannot be blank", Some(event_number))
+    }
+
+    pub fn ack_disabled() -> HecResponse {
+        Self::new(400, 14, "ACK is disabled", None)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for HecResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = Status::from_code(self.status).unwrap_or(Status::InternalServerError);
+        let retry_after_seconds = self.retry_after_seconds;
+        let mut response = Json(self).respond_to(request)?;
+        response.set_status(status);
+        if status == Status::ServiceUnavailable {
+            // forwarders back off and resend the batch
+            response.set_header(crate::ingest::retry_after());
+        }
+        if let Some(seconds) = retry_after_seconds {
+            response.set_header(rocket::http::Header::new("Retry-After", seconds.to_string()));
+        }
+        Ok(response)
+    }
+}
+
+///
+/// Guards fail before the endpoint ever runs, so the HEC-flavoured bodies for those come from catchers
+/// (registered under /services/collector only).
+///
+#[catch(401)]
+pub fn unauthorized(request: &Request) -> HecResponse {
+    match request.headers().get_one("Authorization").and_then(crate::auth::ApiToken::parse){
+        Some(_) => HecResponse::invalid_token(),
+        None => HecResponse::token_required(),
+    }
+}
+
+///
+/// A tenant header naming a tenant we haven't got (see tenant::CallerTenant)
+///
+#[catch(403)]
+pub fn forbidden() -> HecResponse {
+    HecResponse::invalid_token()
+}
+
+#[catch(500)]
+pub fn internal_error() -> HecResponse {
+    HecResponse::internal_error()
+}
+
+///
+/// A standby that hasn't been promoted (see auth::IngestAllowed): forwarders retry, and by then maybe it has been
+///
+#[catch(503)]
+pub fn unavailable() -> HecResponse {
+    HecResponse::server_busy()
+}
+
+///
+/// Forwarders name their channel (a GUID they make up) in the X-Splunk-Request-Channel header, or `?channel=`
+///
+pub struct HecChannel(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for HecChannel {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let channel = request.headers().get_one("X-Splunk-Request-Channel")
+            .or_else(|| request.query_value::<&str>("channel").and_then(|channel| channel.ok()))
+            .map(|channel| channel.to_string());
+        request::Outcome::Success(HecChannel(channel))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AckRequest{
+    pub acks: Vec<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AckResponse{
+    pub acks: BTreeMap<u64, bool>,
+}
+
+///
+/// Forwarders that don't hear back about an ack for a while resend the batch, so we don't have to remember acks forever
+///
+const MAX_PENDING_ACKS_PER_CHANNEL: usize = 10000;
+const MAX_CHANNELS: usize = 10000;
+
+struct AckChannel{
+    next_ack_id: u64,
+    pending: BTreeMap<u64, crate::ingest::Positions>,
+    last_used: Instant,
+}
+
+///
+/// HEC's "indexer acknowledgement": every batch sent on a channel gets an ackId back, and the forwarder asks
+/// /services/collector/ack about those ids until they come back true, which means the batch has been committed (and fsynced).
+/// A true is only ever given out once: after that we forget the ack, the same way Splunk does.
+///
+pub struct HecAcks{
+    queue: Arc<IngestQueue>,
+    channels: Mutex<HashMap<String, AckChannel>>,
+}
+
+impl HecAcks{
+    pub fn new(queue: Arc<IngestQueue>) -> HecAcks {
+        HecAcks{
+            queue,
+            channels: Mutex::new(HashMap::default()),
+        }
+    }
+
+    ///
+    /// hec_acks turns acks on (and turns on fsync-every-commit in the writer, or the acks wouldn't mean much)
+    ///
+    pub fn from_config(config: &crate::config::Config, queue: Arc<IngestQueue>) -> Option<HecAcks> {
+        config.hec_acks.then(|| Self::new(queue))
+    }
+
+    ///
+    /// Channels are GUIDs, but all we really need is something sane to use as a key
+    ///
+    pub fn valid_channel(channel: &str) -> bool {
+        !channel.is_empty() && channel.len() <= 128 && channel.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    }
+
+    ///
+    /// Queue a batch, and hand back the ackId the forwarder can ask about it with
+    ///
+    pub fn enqueue(&self, channel: &str, events: Vec<crate::WritableEvent>) -> Result<u64, crate::ingest::Overloaded> {
+        let positions = self.queue.enqueue(events)?;
+        let mut channels = self.channels.lock().unwrap();
+        if !channels.contains_key(channel) && channels.len() >= MAX_CHANNELS {
+            let stalest = channels.iter().min_by_key(|(_, ack_channel)| ack_channel.last_used).map(|(name, _)| name.clone());
+            if let Some(stalest) = stalest {
+                channels.remove(&stalest);
+            }
+        }
+        let ack_channel = channels.entry(channel.to_string()).or_insert_with(|| AckChannel{
+            next_ack_id: 0,
+            pending: BTreeMap::new(),
+            last_used: Instant::now(),
+        });
+        let ack_id = ack_channel.next_ack_id;
+        ack_channel.next_ack_id += 1;
+        ack_channel.last_used = Instant::now();
+        ack_channel.pending.insert(ack_id, positions);
+        if ack_channel.pending.len() > MAX_PENDING_ACKS_PER_CHANNEL {
+            ack_channel.pending.pop_first();
+        }
+        Ok(ack_id)
+    }
+
+    ///
+    /// Which of `ack_ids` have been committed? Ids we've never heard of (or already said true to) are false.
+    ///
+    pub fn query(&self, channel: &str, ack_ids: &[u64]) -> AckResponse {
+        let mut channels = self.channels.lock().unwrap();
+        let mut acks = BTreeMap::new();
+        for ack_id in ack_ids {
+            let committed = match channels.get_mut(channel){
+                Some(ack_channel) => {
+                    ack_channel.last_used = Instant::now();
+                    match ack_channel.pending.get(ack_id){
+                        Some(positions) if self.queue.is_written(positions) => {
+                            ack_channel.pending.remove(ack_id);
+                            true
+                        },
+                        _ => false,
+                    }
+                },
+                None => false,
+            };
+            acks.insert(*ack_id, committed);
+        }
+        AckResponse{ acks }
+    }
+}
+
+///
+/// Forwarders that time out waiting for us resend the whole batch, and if the first try did get in, every line in it is there twice.
+/// With HEC_DEDUP_SECONDS set, we remember every event we've queued for that long (or until we've remembered HEC_DEDUP_MAX_EVENTS of them),
+/// keyed by its channel, time, host and text, and quietly drop the ones we see again.
+///
+/// Lines that are the same on purpose (two identical healthchecks in the same second) are told apart by which of them it is in the batch:
+/// the second "GET /health" in a batch is only a duplicate of the second "GET /health" in an earlier one.
+///
+pub struct HecDedup{
+    window: std::time::Duration,
+    max_events: usize,
+    seen: Mutex<SeenEvents>,
+    duplicates: std::sync::atomic::AtomicU64,
+}
+
+#[derive(Default)]
+struct SeenEvents{
+    keys: fxhash::FxHashSet<u64>,
+    /// oldest first, for letting them go
+    order: std::collections::VecDeque<(Instant, u64)>,
+}
+
+///
+/// The events in a batch we haven't seen before, and the keys they're remembered by (to `release`, if they don't get queued)
+///
+#[derive(Debug, Default)]
+pub struct FreshEvents{
+    pub events: Vec<crate::WritableEvent>,
+    pub keys: Vec<u64>,
+    pub duplicates: usize,
+}
+
+impl HecDedup{
+    pub fn new(window: std::time::Duration, max_events: usize) -> HecDedup {
+        HecDedup{
+            window,
+            max_events,
+            seen: Mutex::new(SeenEvents::default()),
+            duplicates: std::sync::atomic::AtomicU64::new(0),
+        }
+    }
+
+    pub fn from_config(config: &crate::config::Config) -> Option<HecDedup> {
+        (config.hec_dedup_seconds > 0).then(|| Self::new(std::time::Duration::from_secs(config.hec_dedup_seconds), config.hec_dedup_max_events))
+    }
+
+    ///
+    /// Every event's key: which channel it came in on, what it says, and which copy of that it is in this batch
+    ///
+    fn keys(channel: Option<&str>, events: &[crate::WritableEvent]) -> Vec<u64> {
+        use std::hash::{Hash, Hasher};
+        let mut copies: HashMap<(&str, &str, i64), u32> = HashMap::default();
+        events.iter().map(|event| {
+            let copy = copies.entry((event.host.as_str(), event.event.as_str(), event.time)).or_default();
+            *copy += 1;
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            (channel.unwrap_or(""), event.time, &event.host, &event.event, *copy).hash(&mut hasher);
+            hasher.finish()
+        }).collect()
+    }
+
+    ///
+    /// Take out the events we've already queued (or are queueing right now), and hold on to the rest: a copy that turns up
+    /// while this one's still on its way to the queue is a duplicate too. If the batch doesn't get queued after all
+    /// (the queue's full, say), `release` it, so that it's let in when it comes back.
+    ///
+    pub fn fresh(&self, channel: Option<&str>, events: Vec<crate::WritableEvent>) -> FreshEvents {
+        let keys = Self::keys(channel, &events);
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.expire(now, self.window, self.max_events);
+        let mut fresh = FreshEvents::default();
+        for (event, key) in events.into_iter().zip(keys) {
+            if seen.keys.insert(key) {
+                seen.order.push_back((now, key));
+                fresh.events.push(event);
+                fresh.keys.push(key);
+            }
+            else {
+                fresh.duplicates += 1;
+            }
+        }
+        seen.expire(now, self.window, self.max_events);
+        drop(seen);
+        if fresh.duplicates > 0 {
+            self.duplicates.fetch_add(fresh.duplicates as u64, std::sync::atomic::Ordering::Relaxed);
+            tracing::debug!("Dropped {} events we'd already queued{}", fresh.duplicates, channel.map(|channel| format!(" on channel {}", channel)).unwrap_or_default());
+        }
+        fresh
+    }
+
+    ///
+    /// These events didn't get queued after all: the next time we see them, they're new
+    ///
+    pub fn release(&self, keys: &[u64]) {
+        if keys.is_empty() {
+            return;
+        }
+        let released: fxhash::FxHashSet<u64> = keys.iter().copied().collect();
+        let mut seen = self.seen.lock().unwrap();
+        seen.keys.retain(|key| !released.contains(key));
+        seen.order.retain(|(_, key)| !released.contains(key));
+    }
+
+    ///
+    /// How many events we've dropped as duplicates, ever
+    ///
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+impl SeenEvents{
+    fn expire(&mut self, now: Instant, window: std::time::Duration, max_events: usize) {
+        while let Some((time, key)) = self.order.front().copied() {
+            if now.duration_since(time) < window && self.order.len() <= max_events {
+                break;
+            }
+            self.order.pop_front();
+            self.keys.remove(&key);
+        }
+    }
+}
+
+#[test]
+fn test_hec_response_body() {
+    assert_eq!(serde_json::to_string(&HecResponse::success()).unwrap(), r#"{"text":"Success","code":0}"#);
+    assert_eq!(serde_json::to_string(&HecResponse::success_with_ack(7)).unwrap(), r#"{"text":"Success","code":0,"ackId":7}"#);
+    assert_eq!(serde_json::to_string(&HecResponse::invalid_data_format(3)).unwrap(), r#"{"text":"Invalid data format","code":6,"invalid-event-number":3}"#);
+}
+
+#[test]
+fn test_acks() {
+    let queue = Arc::new(IngestQueue::new(crate::ingest::QueueSettings{ bulk_capacity: 10, ..crate::ingest::QueueSettings::default() }));
+    let acks = HecAcks::new(queue.clone());
+    let event = || crate::WritableEvent{
+        event: "hello".to_string(),
+        time: 1,
+        host: "h".to_string(),
+    };
+
+    assert_eq!(acks.enqueue("a", vec![event()]), Ok(0));
+    assert_eq!(acks.enqueue("b", vec![event(), event()]), Ok(0));
+    assert_eq!(acks.enqueue("a", vec![event()]), Ok(1));
+
+    assert_eq!(serde_json::to_string(&acks.query("a", &[0, 1])).unwrap(), r#"{"acks":{"0":false,"1":false}}"#);
+    queue.written(crate::ingest::Lane::Interactive, 3, true);
+    assert_eq!(acks.query("a", &[0, 1, 2]).acks, BTreeMap::from([(0, true), (1, false), (2, false)]));
+    assert_eq!(acks.query("b", &[0]).acks, BTreeMap::from([(0, true)]));
+    // true only comes back once
+    assert_eq!(acks.query("a", &[0]).acks, BTreeMap::from([(0, false)]));
+    assert_eq!(acks.query("c", &[0]).acks, BTreeMap::from([(0, false)]));
+
+    assert!(HecAcks::valid_channel("FE0ECFAD-13D5-401B-847D-77833BD77131"));
+    assert!(!HecAcks::valid_channel(""));
+    assert!(!HecAcks::valid_channel("../etc"));
+}
+
+#[test]
+fn test_dedup() {
+    let dedup = HecDedup::new(std::time::Duration::from_secs(60), 5);
+    let event = |text: &str, time: i64| crate::WritableEvent::new(text, time, "web-1");
+    let batch = vec![event("GET /health", 1), event("GET /health", 1), event("oh no", 2)];
+
+    let first = dedup.fresh(Some("a"), batch.clone());
+    assert_eq!((first.events.len(), first.duplicates), (3, 0));
+    // a copy that shows up while the first one's still being queued doesn't get in too
+    let fresh = dedup.fresh(Some("a"), batch.clone());
+    assert_eq!((fresh.events.len(), fresh.duplicates), (0, 3));
+    // turned away (the queue was full, say): once it's released, the retry gets in
+    dedup.release(&first.keys);
+    let fresh = dedup.fresh(Some("a"), batch.clone());
+    assert_eq!(fresh.events.len(), 3);
+
+    // the retry of a batch that did get in
+    let fresh = dedup.fresh(Some("a"), batch.clone());
+    assert_eq!((fresh.events.len(), fresh.duplicates), (0, 3));
+    // a third healthcheck that second is new, and so is anything at another time
+    let fresh = dedup.fresh(Some("a"), vec![event("GET /health", 1), event("GET /health", 1), event("GET /health", 1), event("oh no", 3)]);
+    assert_eq!(fresh.events, vec![event("GET /health", 1), event("oh no", 3)]);
+    assert_eq!(dedup.duplicates(), 8);
+
+    // ...or on another channel: and past max_events, the oldest are forgotten
+    assert_eq!(dedup.fresh(Some("b"), batch.clone()).duplicates, 0);
+    assert_eq!(dedup.fresh(Some("a"), batch.clone()).events.len(), 3);
+
+    // ...and past the window, all of them are
+    let dedup = HecDedup::new(std::time::Duration::ZERO, 5);
+    dedup.fresh(Some("a"), batch.clone());
+    assert_eq!(dedup.fresh(Some("a"), batch).duplicates, 0);
+}
@@ -0,0 +1,148 @@
+use std::fs;
+use std::net::IpAddr;
+use anyhow::Result;
+use regex::Regex;
+use serde::Deserialize;
+
+///
+/// One alias rule: hosts that match `pattern` become `host` (which can use the pattern's capture groups, like `$1`)
+///
+#[derive(Debug, Clone, Deserialize)]
+struct AliasConfig{
+    pattern: String,
+    host: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct HostRulesConfig{
+    #[serde(default)]
+    lowercase: bool,
+    #[serde(default)]
+    strip_domain: bool,
+    #[serde(default)]
+    strip_suffixes: Vec<String>,
+    #[serde(default)]
+    aliases: Vec<AliasConfig>,
+}
+
+///
+/// The same service shows up under dozens of hostnames (web-1.prod.example.com, checkout-7f9c8d6b5-x2x9q, ...),
+/// so we clean hostnames up before we store them, and clean up the hosts people search for the same way.
+///
+/// HOST_RULES points at a JSON file that looks like:
+/// {
+///     "lowercase": true,
+///     "strip_domain": true,
+///     "strip_suffixes": [".svc.cluster.local"],
+///     "aliases": [
+///         {"pattern": "^([a-z-]+)-[0-9a-f]{8,10}-[0-9a-z]{5}$", "host": "$1"},
+///         {"pattern": "^[0-9a-f]{12}$", "host": "docker"}
+///     ]
+/// }
+/// Rules apply in that order: lowercase, strip suffixes, strip the domain (IP addresses keep theirs), then the first alias that matches.
+///
+#[derive(Debug, Clone, Default)]
+pub struct HostRules{
+    lowercase: bool,
+    strip_domain: bool,
+    strip_suffixes: Vec<String>,
+    aliases: Vec<(Regex, String)>,
+}
+
+impl HostRules{
+    pub fn from_config(config: &crate::config::Config) -> Result<HostRules> {
+        match &config.host_rules{
+            Some(path) => Self::load(path),
+            None => Ok(HostRules::default()),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<HostRules> {
+        let contents = fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Could not read host rules from {}: {}", path, e))?;
+        Self::parse(&contents).map_err(|e| anyhow::anyhow!("Could not parse host rules in {}: {}", path, e))
+    }
+
+    pub fn parse(json: &str) -> Result<HostRules> {
+        let config: HostRulesConfig = serde_json::from_str(json)?;
+        let aliases = config.aliases.into_iter()
+            .map(|alias| Ok((Regex::new(&alias.pattern).map_err(|e| anyhow::anyhow!("Bad pattern {}: {}", alias.pattern, e))?, alias.host)))
+            .collect::<Result<Vec<(Regex, String)>>>()?;
+        Ok(HostRules{
+            lowercase: config.lowercase,
+            strip_domain: config.strip_domain,
+            strip_suffixes: config.strip_suffixes,
+            aliases,
+        })
+    }
+
+    pub fn normalize(&self, host: &str) -> String {
+        let mut host = host.trim().to_string();
+        if self.lowercase {
+            host = host.to_lowercase();
+        }
+        for suffix in &self.strip_suffixes {
+            if let Some(stripped) = host.strip_suffix(suffix.as_str()) {
+                if !stripped.is_empty() {
+                    host = stripped.to_string();
+                }
+            }
+        }
+        if self.strip_domain && host.parse::<IpAddr>().is_err() {
+            if let Some((name, _domain)) = host.split_once('.') {
+                if !name.is_empty() {
+                    host = name.to_string();
+                }
+            }
+        }
+        for (pattern, alias) in &self.aliases {
+            if pattern.is_match(&host) {
+                return pattern.replace(&host, alias.as_str()).to_string();
+            }
+        }
+        host
+    }
+
+    ///
+    /// Searches can ask for a host with `host:<name>`: we store hosts normalized, so the name in the search has to be too.
+    /// (after this it's just a plain search token: the host is part of what every search gets tested against)
+    ///
+    pub fn rewrite_search(&self, search: &str) -> String {
+        search.split(' ')
+            .map(|word| match word.strip_prefix("host:"){
+                Some(host) if !host.is_empty() => self.normalize(host),
+                _ => word.to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+#[test]
+fn test_normalize() -> Result<()> {
+    let rules = HostRules::parse(r#"{
+        "lowercase": true,
+        "strip_domain": true,
+        "strip_suffixes": [".svc.cluster.local"],
+        "aliases": [
+            {"pattern": "^([a-z-]+)-[0-9a-f]{8,10}-[0-9a-z]{5}$", "host": "$1"},
+            {"pattern": "^[0-9a-f]{12}$", "host": "docker"}
+        ]
+    }"#)?;
+    assert_eq!(rules.normalize("Web-1.prod.example.com"), "web-1");
+    assert_eq!(rules.normalize("checkout-7f9c8d6b5-x2x9q"), "checkout");
+    assert_eq!(rules.normalize("checkout-7f9c8d6b5-x2x9q.default.svc.cluster.local"), "checkout");
+    assert_eq!(rules.normalize("1349ca097c74"), "docker");
+    assert_eq!(rules.normalize("10.42.0.7"), "10.42.0.7");
+
+    assert_eq!(HostRules::default().normalize("Web-1.prod.example.com"), "Web-1.prod.example.com");
+    assert!(HostRules::parse(r#"{"aliases": [{"pattern": "(", "host": "x"}]}"#).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_rewrite_search() -> Result<()> {
+    let rules = HostRules::parse(r#"{"strip_domain": true}"#)?;
+    assert_eq!(rules.rewrite_search("host:web-1.prod.example.com error !timeout"), "web-1 error !timeout");
+    assert_eq!(rules.rewrite_search("host: error"), "host: error");
+    Ok(())
+}
@@ -0,0 +1,326 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader, Read};
+use std::sync::Arc;
+use std::time::SystemTime;
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::minute::Minute;
+use crate::minute_id::MinuteId;
+use crate::text_ingest::TextUpload;
+use crate::WritableEvent;
+
+///
+/// How many events we hold on to before writing them out to their minutes: imports go minute by minute
+/// (one open connection at a time), not with a connection open for every minute of a week of logs at once
+///
+const MAX_BUFFERED_EVENTS: usize = 200000;
+
+///
+/// Where the timestamp comes from, in JSONL files that don't say (the first of these that's there)
+///
+const JSONL_TIME_FIELDS: [&str; 5] = ["time", "timestamp", "@timestamp", "ts", "date"];
+const JSONL_HOST_FIELDS: [&str; 2] = ["host", "hostname"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat{
+    /// one event per line, timestamps found by `timestamp_regex` (see TextUpload)
+    Text,
+    /// one JSON object per line: the whole object is the event, and the time and host come out of its fields
+    Jsonl,
+}
+
+impl ImportFormat{
+    pub fn parse(format: &str) -> Result<ImportFormat> {
+        match format{
+            "text" => Ok(ImportFormat::Text),
+            "jsonl" | "ndjson" => Ok(ImportFormat::Jsonl),
+            other => Err(anyhow::anyhow!("format must be 'text' or 'jsonl', not '{}'", other)),
+        }
+    }
+}
+
+///
+/// How to read a file. `format` None means look at the first line and guess.
+///
+#[derive(Debug, Clone, Default)]
+pub struct ImportSettings{
+    pub format: Option<ImportFormat>,
+    pub host: Option<String>,
+    pub timestamp_regex: Option<String>,
+    /// a chrono format, for timestamps parse_timestamp doesn't already know
+    pub timestamp_format: Option<String>,
+    /// JSONL only: which field has the time in it
+    pub timestamp_field: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportReport{
+    pub files: usize,
+    pub events: usize,
+    pub timestamped: usize,
+    /// lines we couldn't make sense of (JSONL lines that aren't JSON objects)
+    pub skipped: usize,
+    pub minutes: usize,
+    /// seconds since the epoch
+    pub earliest: Option<i64>,
+    pub latest: Option<i64>,
+}
+
+///
+/// Backfill: old logs go into the minutes they happened in, not the minute we happen to read them.
+/// Every minute an import writes is its own shard, with a unique id that starts with minute_id::IMPORTED_PREFIX,
+/// so it never collides with the live writer (or another import), and retention can tell it apart.
+/// Nothing's searchable until `finish` seals it all.
+///
+pub struct Import{
+    minutes_directory: String,
+    unique_id: String,
+    host_rules: Arc<crate::host_rules::HostRules>,
+    dedup: bool,
+    cardinality_fields: Vec<String>,
+    compression: crate::minute::LogCompression,
+    buffered: BTreeMap<MinuteId, Vec<WritableEvent>>,
+    n_buffered: usize,
+    written: BTreeSet<MinuteId>,
+    report: ImportReport,
+}
+
+impl Import{
+    pub fn new(minutes_directory: &str, machine_id: u32) -> Import {
+        let started = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        Import{
+            minutes_directory: minutes_directory.to_string(),
+            unique_id: format!("{}{}-{}", crate::minute_id::IMPORTED_PREFIX, machine_id, started),
+            host_rules: Arc::new(crate::host_rules::HostRules::default()),
+            dedup: false,
+            cardinality_fields: vec!["host".to_string()],
+            compression: crate::minute::LogCompression::Lz4,
+            buffered: BTreeMap::new(),
+            n_buffered: 0,
+            written: BTreeSet::new(),
+            report: ImportReport::default(),
+        }
+    }
+
+    pub fn from_config(config: &crate::config::Config, minutes_directory: &str) -> Import {
+        let mut import = Self::new(minutes_directory, config.machine_id);
+        import.dedup = config.dedup_messages;
+        import.cardinality_fields = config.cardinality_fields.clone();
+        import.compression = crate::minute::LogCompression::parse(&config.log_compression).unwrap_or(crate::minute::LogCompression::Lz4);
+        import
+    }
+
+    ///
+    /// Clean up every event's hostname before it's written (see HostRules), same as the live writer does
+    ///
+    pub fn set_host_rules(&mut self, host_rules: Arc<crate::host_rules::HostRules>) {
+        self.host_rules = host_rules;
+    }
+
+    pub fn add(&mut self, mut event: WritableEvent) -> Result<()> {
+        event.host = self.host_rules.normalize(&event.host);
+        let seconds = event.time.div_euclid(1000000);
+        self.report.events += 1;
+        self.report.earliest = Some(self.report.earliest.map_or(seconds, |earliest| earliest.min(seconds)));
+        self.report.latest = Some(self.report.latest.map_or(seconds, |latest| latest.max(seconds)));
+
+        let mut minute_id = MinuteId::from_timestamp(seconds);
+        minute_id.unique_id = self.unique_id.clone();
+        self.buffered.entry(minute_id).or_default().push(event);
+        self.n_buffered += 1;
+        if self.n_buffered >= MAX_BUFFERED_EVENTS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        for (minute_id, events) in std::mem::take(&mut self.buffered) {
+            let mut minute = Minute::new(minute_id.day, minute_id.hour, minute_id.minute, &minute_id.unique_id, &self.minutes_directory, true)?;
+            minute.set_dedup(self.dedup);
+            minute.write_second(events)?;
+            self.written.insert(minute_id);
+        }
+        self.n_buffered = 0;
+        Ok(())
+    }
+
+    ///
+    /// Read one file's worth of events. Gzipped is fine: we can tell by looking.
+    ///
+    pub fn read(&mut self, reader: impl Read, settings: &ImportSettings) -> Result<()> {
+        let reader = ungzip(reader)?;
+
+        let mut upload = TextUpload::new(settings.host.as_deref(), None, settings.timestamp_regex.as_deref())?;
+        upload.set_timestamp_format(settings.timestamp_format.as_deref());
+        let mut format = settings.format;
+        let mut time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_micros() as i64;
+
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let format = *format.get_or_insert(if line.trim_start().starts_with('{') { ImportFormat::Jsonl } else { ImportFormat::Text });
+            let parsed = match format{
+                ImportFormat::Text => upload.parse_line(&line, &mut time),
+                ImportFormat::Jsonl => parse_jsonl(&line, settings, &mut time),
+            };
+            match parsed{
+                Some((event, timestamped)) => {
+                    if timestamped {
+                        self.report.timestamped += 1;
+                    }
+                    self.add(event)?;
+                },
+                None => self.report.skipped += 1,
+            }
+        }
+        self.report.files += 1;
+        Ok(())
+    }
+
+    ///
+    /// The most events `read` could get out of this (every line that isn't blank), without importing any of it:
+    /// for charging an import to its sender's rate limit before it's written
+    ///
+    pub fn count_events(reader: impl Read) -> Result<usize> {
+        let mut n_events = 0;
+        for line in ungzip(reader)?.lines() {
+            if !line?.trim().is_empty() {
+                n_events += 1;
+            }
+        }
+        Ok(n_events)
+    }
+
+    ///
+    /// A file, or every file under a directory (in name order)
+    ///
+    pub fn read_path(&mut self, path: &str, settings: &ImportSettings) -> Result<()> {
+        let mut paths: Vec<std::path::PathBuf> = walkdir::WalkDir::new(path).into_iter()
+            .collect::<std::result::Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .collect();
+        paths.sort();
+        for path in paths {
+            let file = std::fs::File::open(&path).map_err(|e| anyhow::anyhow!("Can't read {}: {}", path.display(), e))?;
+            self.read(file, settings).map_err(|e| anyhow::anyhow!("Error importing {}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Write out whatever's left and seal every minute we wrote to
+    ///
+    pub fn finish(mut self) -> Result<ImportReport> {
+        self.flush()?;
+        for minute_id in &self.written {
+            let mut minute = Minute::new(minute_id.day, minute_id.hour, minute_id.minute, &minute_id.unique_id, &self.minutes_directory, true)?;
+            minute.set_cardinality_fields(&self.cardinality_fields);
+            minute.set_compression(self.compression);
+            minute.seal()?;
+        }
+        self.report.minutes = self.written.len();
+        Ok(self.report)
+    }
+}
+
+///
+/// Gzipped is fine: we can tell by looking
+///
+fn ungzip<'a>(reader: impl Read + 'a) -> Result<Box<dyn BufRead + 'a>> {
+    let mut reader = BufReader::new(reader);
+    let gzipped = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+    Ok(match gzipped{
+        true => Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(reader))),
+        false => Box::new(reader),
+    })
+}
+
+///
+/// One JSONL line: None if it isn't a JSON object. Lines without a time we can read get the time of the line before.
+///
+fn parse_jsonl(line: &str, settings: &ImportSettings, time: &mut i64) -> Option<(WritableEvent, bool)> {
+    let object = match serde_json::from_str::<serde_json::Value>(line){
+        Ok(serde_json::Value::Object(object)) => object,
+        _ => return None,
+    };
+    let field_names: Vec<&str> = match &settings.timestamp_field{
+        Some(field) => vec![field.as_str()],
+        None => JSONL_TIME_FIELDS.to_vec(),
+    };
+    let found = field_names.iter().filter_map(|name| object.get(*name)).find_map(|value| {
+        let value = match value{
+            serde_json::Value::String(value) => value.clone(),
+            serde_json::Value::Number(value) => value.to_string(),
+            _ => return None,
+        };
+        match &settings.timestamp_format{
+            Some(format) => crate::text_ingest::parse_timestamp_with_format(&value, format),
+            None => crate::text_ingest::parse_timestamp(&value),
+        }
+    });
+    if let Some(found) = found {
+        *time = found;
+    }
+    let host = JSONL_HOST_FIELDS.iter().filter_map(|name| object.get(*name)).find_map(|value| value.as_str())
+        .or(settings.host.as_deref())
+        .unwrap_or("unknown");
+    Some((WritableEvent{
+        event: line.trim().to_string(),
+        time: *time,
+        host: host.to_string(),
+    }, found.is_some()))
+}
+
+#[test]
+fn test_import() -> Result<()> {
+    use std::io::Write;
+
+    let minutes_directory = format!("{}/minutes", crate::minute::test_data_directory("import"));
+    let mut import = Import::new(&minutes_directory, 1);
+
+    // plain text, over two minutes
+    let text = "2023-11-10 14:55:41 ERROR boom\n  at thing.rs:12\n2023-11-10 14:56:02 INFO fine\n";
+    import.read(text.as_bytes(), &ImportSettings{
+        host: Some("web-1".to_string()),
+        timestamp_regex: Some(r"^(\d{4}-\d\d-\d\d \d\d:\d\d:\d\d)".to_string()),
+        ..ImportSettings::default()
+    })?;
+
+    // gzipped JSONL, into the first of those minutes
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(b"{\"ts\": \"2023-11-10T14:55:50Z\", \"host\": \"web-2\", \"msg\": \"hello\"}\nnot json\n")?;
+    let gzipped = encoder.finish()?;
+    assert_eq!(Import::count_events(gzipped.as_slice())?, 2);
+    assert_eq!(Import::count_events(text.as_bytes())?, 3);
+    import.read(gzipped.as_slice(), &ImportSettings::default())?;
+
+    let report = import.finish()?;
+    assert_eq!(report.files, 2);
+    assert_eq!(report.events, 4);
+    assert_eq!(report.timestamped, 3);
+    assert_eq!(report.skipped, 1);
+    assert_eq!(report.minutes, 2);
+    assert_eq!(report.earliest, Some(1699628141));
+    assert_eq!(report.latest, Some(1699628162));
+
+    let files = crate::catalog::FileInfo::scan(&minutes_directory)?;
+    assert_eq!(files.len(), 2);
+    assert!(files.iter().all(|file| file.to_minute_id().is_imported()));
+
+    let minute_db = crate::minute_db::MinuteDB::new(minutes_directory.clone(), crate::retention::RetentionPolicy::new(10, u64::MAX, None), None, None);
+    minute_db.load(files.iter().map(|file| file.to_minute_id()).collect())?;
+    let options = crate::minute_db::SearchOptions{ from: Some(1699628100), to: Some(1699628159), ..Default::default() };
+    let logs = minute_db.search(crate::search_token::Search::new(""), &options)?;
+    assert_eq!(logs.len(), 3);
+    assert!(logs.iter().any(|log| log.host == "web-2" && log.time == 1699628150000000));
+    assert!(logs.iter().any(|log| log.message == "  at thing.rs:12" && log.time == 1699628141000000));
+
+    std::fs::remove_dir_all(minutes_directory.trim_end_matches("/minutes"))?;
+    Ok(())
+}
@@ -0,0 +1,488 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use anyhow::Result;
+use crossbeam::channel::{bounded, Sender, Receiver, Select, TrySendError, TryRecvError, RecvTimeoutError};
+use rocket::http::Header;
+use serde::{Serialize, Deserialize};
+
+use crate::ingest_journal::IngestJournal;
+use crate::WritableEvent;
+
+///
+/// How long we tell clients to wait before they retry, when the writer can't keep up
+///
+pub const RETRY_AFTER_SECONDS: u32 = 5;
+
+///
+/// Which line an event waits in. Small batches (a marker's worth of events, a canary, somebody poking at the API by hand)
+/// go in the interactive lane, so they aren't stuck behind a forwarder's multi-megabyte batch; everything else is bulk.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Lane{
+    Interactive,
+    Bulk,
+}
+
+impl Lane{
+    pub const ALL: [Lane; 2] = [Lane::Interactive, Lane::Bulk];
+
+    pub(crate) fn index(self) -> usize {
+        match self{
+            Lane::Interactive => 0,
+            Lane::Bulk => 1,
+        }
+    }
+}
+
+///
+/// How big the lanes are, which batches count as interactive, and how much of the writer's attention they get
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueSettings{
+    pub bulk_capacity: usize,
+    pub interactive_capacity: usize,
+    pub interactive_max_events: usize,
+    pub interactive_weight: usize,
+}
+
+impl Default for QueueSettings{
+    fn default() -> QueueSettings {
+        QueueSettings{
+            bulk_capacity: 1000000,
+            interactive_capacity: 10000,
+            interactive_max_events: 100,
+            interactive_weight: 8,
+        }
+    }
+}
+
+impl QueueSettings{
+    ///
+    /// The queue is bounded: if the disk is slow, we'd rather tell clients to back off (they all know how to retry)
+    /// than buffer until we run out of memory. The ingest_queue_ settings say how far (Config::validate checks them)
+    ///
+    pub fn from_config(config: &crate::config::Config) -> QueueSettings {
+        QueueSettings{
+            bulk_capacity: config.ingest_queue_events,
+            interactive_capacity: config.ingest_interactive_queue_events,
+            interactive_max_events: config.ingest_interactive_max_events,
+            interactive_weight: config.ingest_interactive_weight,
+        }
+    }
+
+    ///
+    /// Which lane a batch of `n_events` goes in
+    ///
+    pub fn lane_for(&self, n_events: usize) -> Lane {
+        match n_events <= self.interactive_max_events{
+            true => Lane::Interactive,
+            false => Lane::Bulk,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overloaded;
+
+impl std::fmt::Display for Overloaded{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Ingest queue is full, try again later")
+    }
+}
+
+impl std::error::Error for Overloaded{}
+
+///
+/// How many failed writes we remember, for acks that haven't been asked about yet
+///
+const MAX_FAILED_WRITES: usize = 1000;
+
+#[derive(Debug, Default)]
+struct Written{
+    through: u64,
+    failed: VecDeque<Range<u64>>,
+}
+
+///
+/// Where a batch went: its lane, and its positions in that lane
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Positions{
+    pub lane: Lane,
+    pub range: Range<u64>,
+}
+
+///
+/// One lane's channel, plus a running count of events in and out of it: every event that goes in gets a position,
+/// and the writer reports how far through those positions it has got (and which of them it couldn't write).
+/// That's how a HEC ack knows whether its batch is safely on disk. Each lane keeps its own count, because the writer
+/// takes events out of the lanes in a different order than they came in.
+///
+struct LaneQueue{
+    sender: Sender<WritableEvent>,
+    receiver: Receiver<WritableEvent>,
+    enqueued: Mutex<u64>,
+    written: Mutex<Written>,
+}
+
+impl LaneQueue{
+    fn new(capacity: usize) -> LaneQueue {
+        let (sender, receiver) = bounded(capacity);
+        LaneQueue{
+            sender,
+            receiver,
+            enqueued: Mutex::new(0),
+            written: Mutex::new(Written::default()),
+        }
+    }
+}
+
+///
+/// Events wait in here until the writer gets to them: one bounded channel per lane.
+/// When both lanes have events waiting, the writer takes `interactive_weight` interactive events for every bulk one,
+/// so a flood of little batches can slow bulk ingest down, but never stop it.
+///
+pub struct IngestQueue{
+    lanes: [LaneQueue; 2],
+    settings: QueueSettings,
+    interactive_streak: Mutex<usize>,
+    journal: Option<Arc<IngestJournal>>,
+}
+
+impl IngestQueue{
+    pub fn new(settings: QueueSettings) -> IngestQueue {
+        IngestQueue{
+            lanes: [LaneQueue::new(settings.interactive_capacity), LaneQueue::new(settings.bulk_capacity)],
+            settings,
+            interactive_streak: Mutex::new(0),
+            journal: None,
+        }
+    }
+
+    ///
+    /// Journal every batch before it's queued, and tell the journal as the writer commits (see ingest_journal)
+    ///
+    pub fn set_journal(&mut self, journal: Option<Arc<IngestJournal>>) {
+        self.journal = journal;
+    }
+
+    fn lane(&self, lane: Lane) -> &LaneQueue {
+        &self.lanes[lane.index()]
+    }
+
+    ///
+    /// The most events that can ever go in at once
+    ///
+    pub fn capacity(&self) -> usize {
+        self.settings.bulk_capacity.max(self.settings.interactive_capacity)
+    }
+
+    ///
+    /// How many events are waiting to be written right now
+    ///
+    pub fn len(&self) -> usize {
+        self.lanes.iter().map(|lane| lane.sender.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    ///
+    /// Could a batch of `n_events` ever get in? One that's bigger than its lane would be turned away (503, try again later)
+    /// forever: better to tell the sender it's too big, so they don't keep trying.
+    ///
+    pub fn fits(&self, n_events: usize) -> bool {
+        n_events <= self.lane_capacity(self.settings.lane_for(n_events))
+    }
+
+    ///
+    /// The most events that can ever go in `lane` at once
+    ///
+    pub fn lane_capacity(&self, lane: Lane) -> usize {
+        self.lane(lane).sender.capacity().unwrap_or(usize::MAX)
+    }
+
+    ///
+    /// Would `n_events` fit in `lane` right now?
+    ///
+    pub fn has_room_in(&self, lane: Lane, n_events: usize) -> bool {
+        has_room(&self.lane(lane).sender, n_events)
+    }
+
+    ///
+    /// (events waiting, capacity) for each lane
+    ///
+    pub fn usage(&self) -> Vec<(usize, usize)> {
+        self.lanes.iter().map(|lane| (lane.sender.len(), lane.sender.capacity().unwrap_or(usize::MAX))).collect()
+    }
+
+    ///
+    /// Queue a batch (all or nothing, see `enqueue`) in whichever lane its size puts it in, and say which positions it got
+    ///
+    pub fn enqueue(&self, events: Vec<WritableEvent>) -> Result<Positions, Overloaded> {
+        self.enqueue_to(self.settings.lane_for(events.len()), events)
+    }
+
+    ///
+    /// Queue a batch in a particular lane. Batches go into a lane one at a time, so the positions are also the order
+    /// the writer will see them in (within that lane).
+    ///
+    /// With a journal, the batch is in the journal before it's in the queue; a batch the journal can't take is turned away,
+    /// the same as one the queue hasn't got room for.
+    ///
+    pub fn enqueue_to(&self, lane: Lane, events: Vec<WritableEvent>) -> Result<Positions, Overloaded> {
+        let lane_queue = self.lane(lane);
+        let mut enqueued = lane_queue.enqueued.lock().unwrap();
+        let n_events = events.len() as u64;
+        if let Some(journal) = &self.journal {
+            // nobody else puts anything in this lane while we've got `enqueued`, so if there's room now, there's room after the journal
+            if !has_room(&lane_queue.sender, events.len()) {
+                return Err(Overloaded);
+            }
+            if let Err(e) = journal.append(lane, *enqueued, &events) {
+                tracing::error!("Can't write to the ingest journal: {}", e);
+                return Err(Overloaded);
+            }
+        }
+        enqueue(&lane_queue.sender, events)?;
+        let start = *enqueued;
+        *enqueued += n_events;
+        Ok(Positions{ lane, range: start..*enqueued })
+    }
+
+    ///
+    /// The next event for the writer, and the lane it came out of, without waiting
+    ///
+    pub fn try_recv(&self) -> Result<(Lane, WritableEvent), TryRecvError> {
+        let mut streak = self.interactive_streak.lock().unwrap();
+        // interactive goes first, until it's had its share: then bulk gets one (if it wants it)
+        let order = match *streak < self.settings.interactive_weight{
+            true => [Lane::Interactive, Lane::Bulk],
+            false => [Lane::Bulk, Lane::Interactive],
+        };
+        let mut disconnected = 0;
+        for lane in order {
+            match self.lane(lane).receiver.try_recv(){
+                Ok(event) => {
+                    *streak = match lane{
+                        Lane::Interactive => *streak + 1,
+                        Lane::Bulk => 0,
+                    };
+                    return Ok((lane, event));
+                },
+                Err(TryRecvError::Disconnected) => disconnected += 1,
+                Err(TryRecvError::Empty) => {},
+            }
+        }
+        match disconnected == order.len(){
+            true => Err(TryRecvError::Disconnected),
+            false => Err(TryRecvError::Empty),
+        }
+    }
+
+    ///
+    /// The next event for the writer, waiting until `deadline` for one to show up in either lane
+    ///
+    pub fn recv_deadline(&self, deadline: Instant) -> Result<(Lane, WritableEvent), RecvTimeoutError> {
+        loop {
+            match self.try_recv(){
+                Ok(next) => return Ok(next),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {},
+            }
+            let mut select = Select::new();
+            for lane in &self.lanes {
+                select.recv(&lane.receiver);
+            }
+            if select.ready_deadline(deadline).is_err() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+        }
+    }
+
+    ///
+    /// The writer took the next `n_events` off `lane`, and either committed them or didn't
+    ///
+    pub fn written(&self, lane: Lane, n_events: usize, ok: bool) {
+        let mut written = self.lane(lane).written.lock().unwrap();
+        let start = written.through;
+        written.through += n_events as u64;
+        if !ok {
+            let end = written.through;
+            written.failed.push_back(start..end);
+            if written.failed.len() > MAX_FAILED_WRITES {
+                written.failed.pop_front();
+            }
+        }
+        let through = written.through;
+        drop(written);
+        if let Some(journal) = &self.journal {
+            if let Err(e) = journal.committed(lane, through) {
+                tracing::error!("Can't update the ingest journal: {}", e);
+            }
+        }
+    }
+
+    ///
+    /// Has every event in `positions` been committed?
+    ///
+    pub fn is_written(&self, positions: &Positions) -> bool {
+        let written = self.lane(positions.lane).written.lock().unwrap();
+        let range = &positions.range;
+        range.end <= written.through
+            && !written.failed.iter().any(|failed| failed.start < range.end && range.start < failed.end)
+    }
+}
+
+///
+/// Queue a batch of events for the writer, all or nothing: a client we turn away is going to resend the whole batch,
+/// so letting half of it in would just mean storing that half twice.
+///
+pub fn enqueue(sender: &Sender<WritableEvent>, events: Vec<WritableEvent>) -> Result<(), Overloaded> {
+    if !has_room(sender, events.len()) {
+        return Err(Overloaded);
+    }
+    for event in events {
+        // somebody else can still beat us to the last few slots
+        match sender.try_send(event){
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => return Err(Overloaded),
+        }
+    }
+    Ok(())
+}
+
+fn has_room(sender: &Sender<WritableEvent>, n_events: usize) -> bool {
+    sender.capacity().is_none_or(|capacity| capacity.saturating_sub(sender.len()) >= n_events)
+}
+
+///
+/// Why an ingest endpoint (other than HEC, which has its own error bodies) turned a batch away
+///
+#[derive(Debug, Responder)]
+pub enum IngestError{
+    #[response(status = 400)]
+    BadRequest(String),
+    #[response(status = 415)]
+    UnsupportedMediaType(String),
+    #[response(status = 503)]
+    Overloaded(String, Header<'static>),
+    /// this sender is over its ingest rate limit (see rate_limit)
+    #[response(status = 429)]
+    RateLimited(String, Header<'static>),
+}
+
+impl From<Overloaded> for IngestError{
+    fn from(overloaded: Overloaded) -> IngestError {
+        IngestError::Overloaded(overloaded.to_string(), retry_after())
+    }
+}
+
+impl From<crate::rate_limit::RateLimited> for IngestError{
+    fn from(limited: crate::rate_limit::RateLimited) -> IngestError {
+        let retry_after = Header::new("Retry-After", limited.retry_after_seconds.to_string());
+        IngestError::RateLimited(limited.to_string(), retry_after)
+    }
+}
+
+pub fn retry_after() -> Header<'static> {
+    Header::new("Retry-After", RETRY_AFTER_SECONDS.to_string())
+}
+
+#[test]
+fn test_enqueue() {
+    let (sender, receiver) = bounded::<WritableEvent>(3);
+    let event = || WritableEvent{
+        event: "hello".to_string(),
+        time: 1,
+        host: "h".to_string(),
+    };
+
+    assert_eq!(enqueue(&sender, vec![event(), event()]), Ok(()));
+    // there's room for one more, but not two: none of them go in
+    assert_eq!(enqueue(&sender, vec![event(), event()]), Err(Overloaded));
+    assert_eq!(receiver.len(), 2);
+
+    receiver.recv().unwrap();
+    assert_eq!(enqueue(&sender, vec![event(), event()]), Ok(()));
+    assert_eq!(receiver.len(), 3);
+}
+
+#[test]
+fn test_queue_positions() {
+    let queue = IngestQueue::new(QueueSettings{ bulk_capacity: 10, interactive_max_events: 0, ..QueueSettings::default() });
+    let event = || WritableEvent{
+        event: "hello".to_string(),
+        time: 1,
+        host: "h".to_string(),
+    };
+
+    let first = queue.enqueue(vec![event(), event()]).unwrap();
+    let second = queue.enqueue(vec![event()]).unwrap();
+    let third = queue.enqueue(vec![event(), event()]).unwrap();
+    assert_eq!((first.range.clone(), second.range.clone(), third.range.clone()), (0..2, 2..3, 3..5));
+    assert!(!queue.is_written(&first));
+
+    queue.written(Lane::Bulk, 2, true);
+    assert!(queue.is_written(&first));
+    assert!(!queue.is_written(&second));
+
+    // the writer's batches don't line up with ours: a failure anywhere in our range means no ack
+    queue.written(Lane::Bulk, 2, false);
+    queue.written(Lane::Bulk, 1, true);
+    assert!(!queue.is_written(&second));
+    assert!(!queue.is_written(&third));
+    assert!(queue.is_written(&first));
+
+    // each lane counts for itself
+    let interactive = queue.enqueue_to(Lane::Interactive, vec![event()]).unwrap();
+    assert_eq!(interactive, Positions{ lane: Lane::Interactive, range: 0..1 });
+    assert!(!queue.is_written(&interactive));
+    queue.written(Lane::Interactive, 1, true);
+    assert!(queue.is_written(&interactive));
+}
+
+#[test]
+fn test_lanes() {
+    assert_eq!(QueueSettings::from_config(&crate::config::Config::default()), QueueSettings::default());
+
+    let queue = IngestQueue::new(QueueSettings{ bulk_capacity: 100, interactive_capacity: 100, interactive_max_events: 2, interactive_weight: 3 });
+    let event = |text: &str| WritableEvent{
+        event: text.to_string(),
+        time: 1,
+        host: "h".to_string(),
+    };
+
+    // a big batch lands in bulk, then a pile of little ones show up behind it
+    let bulk = queue.enqueue((0..5).map(|_| event("bulk")).collect()).unwrap();
+    assert_eq!(bulk.lane, Lane::Bulk);
+    for _ in 0..4 {
+        assert_eq!(queue.enqueue(vec![event("interactive"), event("interactive")]).unwrap().lane, Lane::Interactive);
+    }
+    assert_eq!(queue.len(), 13);
+    assert_eq!(queue.usage(), vec![(8, 100), (5, 100)]);
+
+    // three interactive for every bulk, and once one lane runs dry the other gets everything
+    let mut lanes = Vec::new();
+    while let Ok((lane, _)) = queue.try_recv() {
+        lanes.push(match lane{ Lane::Interactive => 'i', Lane::Bulk => 'b' });
+    }
+    assert_eq!(lanes.into_iter().collect::<String>(), "iiibiiibiibbb");
+
+    // a batch bigger than the lane it would go in never gets in, however long it waits
+    assert!(queue.fits(100));
+    assert!(!queue.fits(101));
+    let small = IngestQueue::new(QueueSettings{ bulk_capacity: 5, interactive_capacity: 10, interactive_max_events: 2, interactive_weight: 3 });
+    assert_eq!(small.capacity(), 10);
+    assert!(small.fits(2));
+    assert!(!small.fits(6));
+
+    let deadline = Instant::now() + std::time::Duration::from_millis(10);
+    assert_eq!(queue.recv_deadline(deadline).map(|(lane, _)| lane), Err(RecvTimeoutError::Timeout));
+    queue.enqueue(vec![event("late")]).unwrap();
+    assert_eq!(queue.recv_deadline(Instant::now() + std::time::Duration::from_secs(1)).map(|(lane, event)| (lane, event.event)), Ok((Lane::Interactive, "late".to_string())));
+}
@@ -0,0 +1,420 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+use crate::ingest::{IngestQueue, Lane, Overloaded};
+use crate::WritableEvent;
+
+///
+/// How often a replayed batch that didn't fit in the queue tries again, while the writer makes room
+///
+const REPLAY_RETRY: Duration = Duration::from_millis(100);
+
+///
+/// How long replay waits for the writer to make room before it gives up (and startup fails, rather than hanging)
+///
+const REPLAY_STALL: Duration = Duration::from_secs(300);
+
+///
+/// How the journal gets to disk: every batch fsynced before it's acknowledged (`fsync_interval` None),
+/// or fsynced every so often in the background (a crash of the whole machine can lose that much; a crash of just logmunch can't)
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalSettings{
+    pub fsync_interval: Option<Duration>,
+    /// a segment this big is closed, and the next batch starts a new one
+    pub segment_bytes: u64,
+}
+
+impl JournalSettings{
+    pub fn from_config(config: &crate::config::Config) -> Option<JournalSettings> {
+        config.ingest_journal.then(|| JournalSettings{
+            fsync_interval: (config.ingest_journal_fsync_ms > 0).then(|| Duration::from_millis(config.ingest_journal_fsync_ms)),
+            segment_bytes: config.ingest_journal_segment_mb * 1024 * 1024,
+        })
+    }
+}
+
+///
+/// One line of a segment: a batch as it went into the queue (its lane, and its first position in that lane), or how far
+/// the writer has committed a lane. Events are `[time, host, event]`.
+///
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Line{
+    Batch{
+        lane: Lane,
+        start: u64,
+        events: Vec<(i64, String, String)>,
+    },
+    Committed{
+        lane: Lane,
+        through: u64,
+    },
+}
+
+///
+/// A segment we've written batches to, and the end of the last batch it has in each lane:
+/// once the writer's committed past all of those, it can go
+///
+#[derive(Debug)]
+struct Segment{
+    path: String,
+    file: File,
+    bytes: u64,
+    ends: [u64; 2],
+}
+
+impl Segment{
+    fn committed(&self, through: &[u64; 2]) -> bool {
+        self.ends.iter().zip(through).all(|(end, through)| end <= through)
+    }
+}
+
+#[derive(Debug, Default)]
+struct JournalState{
+    current: Option<Segment>,
+    closed: Vec<Segment>,
+    next_sequence: u64,
+    through: [u64; 2],
+    /// written since the last fsync
+    dirty: bool,
+}
+
+///
+/// Events only live in the ingest queue's memory between the HTTP request and the writer's commit, so a crash used to lose them.
+/// With INGEST_JOURNAL on, every batch is appended to a journal file before it's queued (and before whoever sent it hears back),
+/// and the writer's progress is noted in there as it commits. Anything that never got committed is replayed into the queue on startup.
+///
+/// The journal is a directory of append-only segments, `<epoch>-<sequence>.journal`, one JSON line per batch. Queue positions
+/// start again from 0 every boot, so each boot gets a new epoch. A segment is deleted once everything in it is committed.
+/// Replay is at-least-once: a crash between a commit and its note in the journal means that much gets written twice.
+///
+#[derive(Debug)]
+pub struct IngestJournal{
+    directory: String,
+    epoch: u64,
+    settings: JournalSettings,
+    state: Mutex<JournalState>,
+}
+
+///
+/// What an earlier boot left behind: the batches that never got committed, and the segments they came out of
+///
+#[derive(Debug, Default)]
+pub struct Replay{
+    pub batches: Vec<(Lane, Vec<WritableEvent>)>,
+    pub segments: Vec<String>,
+}
+
+impl Replay{
+    pub fn events(&self) -> usize {
+        self.batches.iter().map(|(_, events)| events.len()).sum()
+    }
+
+    ///
+    /// Queue every batch again (which journals it again, in the new epoch), then let the old segments go.
+    /// The writer has to be running already: a replay bigger than the queue waits for it to make room, a lane's worth at a time.
+    ///
+    pub fn run(self, queue: &IngestQueue, journal: &IngestJournal) -> Result<()> {
+        self.run_within(queue, journal, REPLAY_STALL)
+    }
+
+    fn run_within(self, queue: &IngestQueue, journal: &IngestJournal, stall: Duration) -> Result<()> {
+        for (lane, mut events) in self.batches {
+            // the batch can be bigger than its lane (the lanes got smaller since it was journaled): it goes back in as pieces that fit
+            let piece = queue.lane_capacity(lane).max(1);
+            while !events.is_empty() {
+                let rest = events.split_off(piece.min(events.len()));
+                let chunk = std::mem::replace(&mut events, rest);
+                let deadline = Instant::now() + stall;
+                while !queue.has_room_in(lane, chunk.len()) {
+                    if Instant::now() > deadline {
+                        return Err(anyhow::anyhow!("Gave up replaying the ingest journal: the writer hasn't made room for {} events in {}s", chunk.len(), stall.as_secs()));
+                    }
+                    std::thread::sleep(REPLAY_RETRY);
+                }
+                // nothing else is queueing yet, so the room's still there
+                if let Err(Overloaded) = queue.enqueue_to(lane, chunk) {
+                    return Err(anyhow::anyhow!("Couldn't replay the ingest journal: the queue turned a batch away"));
+                }
+            }
+        }
+        journal.sync()?;
+        for segment in self.segments {
+            fs::remove_file(&segment)?;
+        }
+        Ok(())
+    }
+}
+
+impl IngestJournal{
+    ///
+    /// Start a new epoch in `directory`, and read back whatever the last one (or, if we crashed while replaying, the last few) didn't commit
+    ///
+    pub fn open(directory: &str, settings: JournalSettings) -> Result<(IngestJournal, Replay)> {
+        fs::create_dir_all(directory).map_err(|e| anyhow::anyhow!("Can't create {}: {}", directory, e))?;
+        let mut segments: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            let Some(epoch) = name.strip_suffix(".journal").and_then(|stem| stem.split_once('-')).and_then(|(epoch, _)| epoch.parse::<u64>().ok()) else { continue };
+            segments.entry(epoch).or_default().push(path.to_string_lossy().into_owned());
+        }
+
+        let mut replay = Replay::default();
+        for paths in segments.values_mut() {
+            paths.sort();
+            replay.batches.extend(read_epoch(paths)?);
+            replay.segments.extend(paths.iter().cloned());
+        }
+
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_micros() as u64;
+        let epoch = now.max(segments.keys().last().map(|epoch| epoch + 1).unwrap_or(0));
+        let journal = IngestJournal{
+            directory: directory.trim_end_matches('/').to_string(),
+            epoch,
+            settings,
+            state: Mutex::new(JournalState::default()),
+        };
+        Ok((journal, replay))
+    }
+
+    pub fn fsync_interval(&self) -> Option<Duration> {
+        self.settings.fsync_interval
+    }
+
+    ///
+    /// Note down a batch that's about to go into `lane` at position `start`. Once this returns, it's in the journal
+    /// (and on disk, unless we're fsyncing on an interval).
+    ///
+    pub fn append(&self, lane: Lane, start: u64, events: &[WritableEvent]) -> Result<()> {
+        let line = Line::Batch{
+            lane,
+            start,
+            events: events.iter().map(|event| (event.time, event.host.clone(), event.event.clone())).collect(),
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.current.is_none() {
+            let path = format!("{}/{:020}-{:08}.journal", self.directory, self.epoch, state.next_sequence);
+            state.next_sequence += 1;
+            let file = File::options().create_new(true).append(true).open(&path).map_err(|e| anyhow::anyhow!("Can't create {}: {}", path, e))?;
+            // the new file's name has to survive a crash too
+            File::open(&self.directory)?.sync_all()?;
+            state.current = Some(Segment{ path, file, bytes: 0, ends: [0; 2] });
+        }
+        let segment = state.current.as_mut().unwrap();
+        let line = format!("{}\n", serde_json::to_string(&line)?);
+        segment.file.write_all(line.as_bytes())?;
+        segment.bytes += line.len() as u64;
+        segment.ends[lane.index()] = start + events.len() as u64;
+        match self.settings.fsync_interval{
+            None => segment.file.sync_data()?,
+            Some(_) => state.dirty = true,
+        }
+
+        if state.current.as_ref().is_some_and(|segment| segment.bytes >= self.settings.segment_bytes) {
+            let segment = state.current.take().unwrap();
+            segment.file.sync_data()?;
+            state.closed.push(segment);
+        }
+        Ok(())
+    }
+
+    ///
+    /// The writer has committed (or given up on) everything in `lane` before `through`:
+    /// segments that were all that go, and the rest get a note of how far it's got
+    ///
+    pub fn committed(&self, lane: Lane, through: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.through[lane.index()] = through;
+        let through = state.through;
+
+        let (done, closed): (Vec<Segment>, Vec<Segment>) = std::mem::take(&mut state.closed).into_iter().partition(|segment| segment.committed(&through));
+        state.closed = closed;
+        for segment in done {
+            fs::remove_file(&segment.path)?;
+        }
+
+        if state.current.as_ref().is_some_and(|segment| segment.committed(&through)) {
+            let segment = state.current.take().unwrap();
+            fs::remove_file(&segment.path)?;
+            return Ok(());
+        }
+        // the note goes in the newest segment there is: replay reads every segment before it believes any of them
+        let state = &mut *state;
+        if let Some(segment) = state.current.as_mut().or(state.closed.last_mut()) {
+            let line = format!("{}\n", serde_json::to_string(&Line::Committed{ lane, through: through[lane.index()] })?);
+            segment.file.write_all(line.as_bytes())?;
+            segment.bytes += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    ///
+    /// fsync whatever's been written since the last one: with an fsync interval, something calls this every interval
+    ///
+    pub fn sync(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.dirty {
+            if let Some(segment) = state.current.as_ref() {
+                segment.file.sync_data()?;
+            }
+            state.dirty = false;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Segments on disk right now, and how many bytes are in them
+    ///
+    pub fn usage(&self) -> (usize, u64) {
+        let state = self.state.lock().unwrap();
+        let segments: Vec<&Segment> = state.closed.iter().chain(state.current.as_ref()).collect();
+        (segments.len(), segments.iter().map(|segment| segment.bytes).sum())
+    }
+}
+
+///
+/// One epoch's segments (in order), down to what its writer never committed. A line we can't read is the end of that segment:
+/// it's the one we were halfway through writing when we went down.
+///
+fn read_epoch(paths: &[String]) -> Result<Vec<(Lane, Vec<WritableEvent>)>> {
+    let mut lines = Vec::new();
+    for path in paths {
+        for line in BufReader::new(File::open(path)?).lines() {
+            match serde_json::from_str::<Line>(&line?){
+                Ok(line) => lines.push(line),
+                Err(e) => {
+                    tracing::warn!("{} ends in a line we can't read ({}): replaying up to there", path, e);
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut through = [0; 2];
+    for line in &lines {
+        if let Line::Committed{ lane, through: committed } = line {
+            let through = &mut through[lane.index()];
+            *through = (*through).max(*committed);
+        }
+    }
+    let mut batches = Vec::new();
+    for line in lines {
+        if let Line::Batch{ lane, start, events } = line {
+            // the writer's batches don't line up with ours: it can have committed the front of this one
+            let skip = through[lane.index()].saturating_sub(start) as usize;
+            let events: Vec<WritableEvent> = events.into_iter().skip(skip).map(|(time, host, event)| WritableEvent{ event, time, host }).collect();
+            if !events.is_empty() {
+                batches.push((lane, events));
+            }
+        }
+    }
+    Ok(batches)
+}
+
+#[test]
+fn test_ingest_journal() -> Result<()> {
+    let directory = crate::minute::test_data_directory("ingest_journal");
+    let settings = JournalSettings{ fsync_interval: None, segment_bytes: 200 };
+    let event = |text: &str| WritableEvent::new(text, 1710562887000000, "web-1");
+
+    let (journal, replay) = IngestJournal::open(&directory, settings.clone())?;
+    assert_eq!(replay.events(), 0);
+    journal.append(Lane::Bulk, 0, &[event("one"), event("two"), event("three")])?;
+    journal.append(Lane::Interactive, 0, &[event("four")])?;
+    // the first segment's full now
+    assert_eq!(journal.usage().0, 1);
+    journal.append(Lane::Bulk, 3, &[event("five")])?;
+    assert_eq!(journal.usage().0, 2);
+
+    // everything in the first segment's committed: it goes
+    journal.committed(Lane::Bulk, 3)?;
+    assert_eq!(journal.usage().0, 2);
+    journal.committed(Lane::Interactive, 1)?;
+    assert_eq!(journal.usage().0, 1);
+    journal.append(Lane::Bulk, 4, &[event("six"), event("seven")])?;
+    journal.committed(Lane::Bulk, 5)?;
+
+    // and then we crash, halfway through writing a line
+    let path = fs::read_dir(&directory)?.next().unwrap()?.path();
+    File::options().append(true).open(&path)?.write_all(b"{\"batch\":{\"lane\":\"bu")?;
+    drop(journal);
+
+    let (journal, replay) = IngestJournal::open(&directory, settings.clone())?;
+    assert_eq!(replay.batches, vec![(Lane::Bulk, vec![event("seven")])]);
+    assert_eq!(replay.segments.len(), 1);
+
+    // replaying journals it again, in a new epoch, and lets the old segment go
+    let journal = std::sync::Arc::new(journal);
+    let mut queue = IngestQueue::new(crate::ingest::QueueSettings::default());
+    queue.set_journal(Some(journal.clone()));
+    replay.run(&queue, &journal)?;
+    assert_eq!(queue.len(), 1);
+    assert_eq!(fs::read_dir(&directory)?.count(), 1);
+    let (_, replay) = IngestJournal::open(&directory, settings.clone())?;
+    assert_eq!(replay.events(), 1);
+
+    // once the writer's committed it, there's nothing left
+    queue.written(Lane::Bulk, 1, true);
+    assert_eq!(fs::read_dir(&directory)?.count(), 0);
+    let (_, replay) = IngestJournal::open(&directory, settings)?;
+    assert_eq!(replay.events(), 0);
+    Ok(())
+}
+
+#[test]
+fn test_replay_bigger_than_the_queue() -> Result<()> {
+    let directory = crate::minute::test_data_directory("ingest_journal_replay");
+    let settings = JournalSettings{ fsync_interval: None, segment_bytes: 1000000 };
+    let event = |text: &str| WritableEvent::new(text, 1710562887000000, "web-1");
+    let texts = ["one", "two", "three", "four", "five"];
+    let queue_settings = crate::ingest::QueueSettings{
+        bulk_capacity: 2,
+        interactive_capacity: 2,
+        interactive_max_events: 0,
+        interactive_weight: 8,
+    };
+
+    // with nobody writing, it gives up instead of waiting forever
+    let (journal, _) = IngestJournal::open(&directory, settings.clone())?;
+    let replay = Replay{ batches: vec![(Lane::Bulk, texts.iter().map(|text| event(text)).collect())], segments: Vec::new() };
+    let queue = IngestQueue::new(queue_settings.clone());
+    assert!(replay.run_within(&queue, &journal, Duration::from_millis(250)).is_err());
+    assert_eq!(queue.len(), 2);
+    drop(journal);
+    let _ = fs::remove_dir_all(&directory);
+
+    // with a writer, it goes in two at a time, in order
+    let (journal, _) = IngestJournal::open(&directory, settings)?;
+    let journal = std::sync::Arc::new(journal);
+    let mut queue = IngestQueue::new(queue_settings);
+    queue.set_journal(Some(journal.clone()));
+    let queue = std::sync::Arc::new(queue);
+    let writer = {
+        let queue = queue.clone();
+        std::thread::spawn(move || {
+            let mut written = Vec::new();
+            while written.len() < 5 {
+                match queue.try_recv(){
+                    Ok((lane, event)) => {
+                        written.push(event.event);
+                        queue.written(lane, 1, true);
+                    },
+                    Err(_) => std::thread::sleep(Duration::from_millis(10)),
+                }
+            }
+            written
+        })
+    };
+    let replay = Replay{ batches: vec![(Lane::Bulk, texts.iter().map(|text| event(text)).collect())], segments: Vec::new() };
+    replay.run_within(&queue, &journal, Duration::from_secs(10))?;
+    assert_eq!(writer.join().unwrap(), texts);
+    assert_eq!(fs::read_dir(&directory)?.count(), 0);
+    Ok(())
+}
@@ -0,0 +1,302 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use rhai::{CallFnOptions, Dynamic, Engine, Map, Scope, AST, INT};
+use serde::Serialize;
+
+use crate::WritableEvent;
+
+///
+/// How much work one event gets, by default: a script that's still going after this is stuck in a loop, and the event goes in as it was
+///
+pub const DEFAULT_MAX_OPERATIONS: u64 = 100000;
+pub const DEFAULT_TIMEOUT_MS: u64 = 10;
+
+///
+/// How often (in operations) a running script checks the clock
+///
+const OPERATIONS_PER_CLOCK_CHECK: u64 = 1000;
+
+thread_local! {
+    // when the event this thread is transforming runs out of time: Engine::on_progress only gets an operation count
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+///
+/// What a script has done since we started
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ScriptStats{
+    pub events: u64,
+    pub dropped: u64,
+    /// events the script choked on (or ran out of time on): those go in untouched
+    pub errors: u64,
+}
+
+///
+/// For the transforms HostRules (and friends) can't do: a Rhai script with a `transform` function,
+/// that gets every event on its way into a minute and can change it or drop it.
+///
+/// ```rhai
+/// fn transform(event) {
+///     // event.message, event.host, and event.time (microseconds since the epoch)
+///     if event.message.contains("GET /healthz") {
+///         return ();                                  // () drops the event
+///     }
+///     let fields = fields(event.message);             // key=value pairs, same as search results get
+///     if "service" in fields {
+///         event.host = fields.service;
+///     }
+///     event
+/// }
+/// ```
+///
+/// Scripts are sandboxed: no files, no network, no `eval`, and every event gets a budget of
+/// INGEST_SCRIPT_MAX_OPERATIONS operations (default 100000) and INGEST_SCRIPT_TIMEOUT_MS milliseconds (default 10).
+/// A script that errors, or runs over, leaves the event the way it was: we'd rather store it untransformed than lose it.
+///
+pub struct IngestScript{
+    name: String,
+    engine: Engine,
+    ast: AST,
+    timeout: Duration,
+    events: AtomicU64,
+    dropped: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl IngestScript{
+    pub fn parse(name: &str, script: &str, max_operations: u64, timeout: Duration) -> Result<IngestScript> {
+        let mut engine = Engine::new();
+        engine.disable_symbol("eval");
+        engine.set_max_operations(max_operations);
+        engine.set_max_call_levels(32);
+        engine.set_max_expr_depths(64, 32);
+        engine.set_max_string_size(1 << 20);
+        engine.set_max_array_size(10000);
+        engine.set_max_map_size(10000);
+        engine.on_progress(|operations| {
+            if operations % OPERATIONS_PER_CLOCK_CHECK != 0 {
+                return None;
+            }
+            DEADLINE.with(|deadline| match deadline.get(){
+                Some(deadline) if Instant::now() > deadline => Some(Dynamic::from("out of time")),
+                _ => None,
+            })
+        });
+        let print_name = name.to_string();
+        engine.on_print(move |text| tracing::info!("Ingest script {}: {}", print_name, text));
+        let debug_name = name.to_string();
+        engine.on_debug(move |text, _, _| tracing::debug!("Ingest script {}: {}", debug_name, text));
+        engine.register_fn("fields", |message: &str| -> Map {
+            crate::enrich::extract_fields(message).into_iter().map(|(key, value)| (key.into(), value.into())).collect()
+        });
+
+        let ast = engine.compile(script).map_err(|e| anyhow::anyhow!("Ingest script {} doesn't compile: {}", name, e))?;
+        if !ast.iter_functions().any(|function| function.name == "transform" && function.params.len() == 1) {
+            return Err(anyhow::anyhow!("Ingest script {} needs a `fn transform(event)`", name));
+        }
+        Ok(IngestScript{
+            name: name.to_string(),
+            engine,
+            ast,
+            timeout,
+            events: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        })
+    }
+
+    pub fn load(path: &str, max_operations: u64, timeout: Duration) -> Result<IngestScript> {
+        let contents = fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Could not read ingest script from {}: {}", path, e))?;
+        Self::parse(path, &contents, max_operations, timeout)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    ///
+    /// Run one event through the script: None if the script dropped it
+    ///
+    pub fn transform(&self, event: WritableEvent) -> Result<Option<WritableEvent>> {
+        let mut map = Map::new();
+        map.insert("message".into(), event.event.into());
+        map.insert("host".into(), event.host.into());
+        map.insert("time".into(), (event.time as INT).into());
+
+        DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + self.timeout)));
+        // the top level of the script ran once, when we compiled it: only transform runs per event
+        let options = CallFnOptions::new().eval_ast(false);
+        let result = self.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &self.ast, "transform", (map,));
+        DEADLINE.with(|deadline| deadline.set(None));
+
+        let result = result.map_err(|e| anyhow::anyhow!("{}", e))?;
+        if result.is_unit() {
+            return Ok(None);
+        }
+        let map = result.try_cast::<Map>().ok_or_else(|| anyhow::anyhow!("transform has to return the event, or () to drop it"))?;
+        let field = |name: &str| map.get(name).ok_or_else(|| anyhow::anyhow!("the event doesn't have a {} any more", name));
+        Ok(Some(WritableEvent{
+            event: field("message")?.to_string(),
+            host: field("host")?.to_string(),
+            time: field("time")?.as_int().map_err(|_| anyhow::anyhow!("the event's time has to be a number of microseconds"))?,
+        }))
+    }
+
+    ///
+    /// Run a whole batch through the script. Events the script fails on go in as they were, and we say so (once per batch).
+    ///
+    pub fn transform_batch(&self, events: Vec<WritableEvent>) -> Vec<WritableEvent> {
+        let n_events = events.len();
+        let mut transformed = Vec::with_capacity(n_events);
+        let mut errors = 0;
+        let mut last_error = None;
+        for event in events {
+            match self.transform(event.clone()){
+                Ok(Some(event)) => transformed.push(event),
+                Ok(None) => {},
+                Err(e) => {
+                    errors += 1;
+                    last_error = Some(e);
+                    transformed.push(event);
+                }
+            }
+        }
+        if let Some(e) = last_error {
+            tracing::warn!("Ingest script {} failed on {} of {} events (they went in as they were): {}", self.name, errors, n_events, e);
+        }
+        self.events.fetch_add(n_events as u64, Ordering::Relaxed);
+        self.dropped.fetch_add((n_events - transformed.len()) as u64, Ordering::Relaxed);
+        self.errors.fetch_add(errors, Ordering::Relaxed);
+        transformed
+    }
+
+    pub fn stats(&self) -> ScriptStats {
+        ScriptStats{
+            events: self.events.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+///
+/// Which script each tenant's writer runs, if any.
+///
+/// INGEST_SCRIPT is either one .rhai file that every tenant uses, or a directory of them: `default.rhai` for the default tenant,
+/// and `<tenant>.rhai` for each named tenant. Tenants without a script of their own don't get one.
+///
+#[derive(Default)]
+pub struct IngestScripts{
+    default: Option<Arc<IngestScript>>,
+    /// None is "the same script for everybody"
+    tenants: Option<HashMap<String, Arc<IngestScript>>>,
+}
+
+impl IngestScripts{
+    pub fn from_config(config: &crate::config::Config) -> Result<IngestScripts> {
+        match &config.ingest_script{
+            Some(path) => Self::load(path, config.ingest_script_max_operations, Duration::from_millis(config.ingest_script_timeout_ms)),
+            None => Ok(IngestScripts::default()),
+        }
+    }
+
+    pub fn load(path: &str, max_operations: u64, timeout: Duration) -> Result<IngestScripts> {
+        if !fs::metadata(path).map_err(|e| anyhow::anyhow!("Could not read ingest script from {}: {}", path, e))?.is_dir() {
+            return Ok(IngestScripts{
+                default: Some(Arc::new(IngestScript::load(path, max_operations, timeout)?)),
+                tenants: None,
+            });
+        }
+        let mut default = None;
+        let mut tenants = HashMap::new();
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+            let name = match (path.file_stem().and_then(|stem| stem.to_str()), path.extension().and_then(|extension| extension.to_str())){
+                (Some(name), Some("rhai")) => name.to_string(),
+                _ => continue,
+            };
+            let script = Arc::new(IngestScript::load(&path.to_string_lossy(), max_operations, timeout)?);
+            if name == "default" {
+                default = Some(script);
+            }
+            else {
+                tenants.insert(name, script);
+            }
+        }
+        Ok(IngestScripts{
+            default,
+            tenants: Some(tenants),
+        })
+    }
+
+    pub fn for_tenant(&self, tenant: Option<&str>) -> Option<Arc<IngestScript>> {
+        match (tenant, &self.tenants){
+            (Some(tenant), Some(tenants)) => tenants.get(tenant).cloned(),
+            _ => self.default.clone(),
+        }
+    }
+}
+
+#[test]
+fn test_ingest_script() -> Result<()> {
+    let script = IngestScript::parse("test", r#"
+        fn transform(event) {
+            if event.message.contains("healthz") {
+                return ();
+            }
+            if event.message == "loop" {
+                loop {}
+            }
+            if event.message == "nonsense" {
+                return 5;
+            }
+            let fields = fields(event.message);
+            if "service" in fields {
+                event.host = fields.service;
+            }
+            event.message = event.message.to_upper();
+            event
+        }
+    "#, DEFAULT_MAX_OPERATIONS, Duration::from_millis(DEFAULT_TIMEOUT_MS))?;
+
+    let event = |message: &str| WritableEvent::new(message, 1234, "web-1");
+    assert_eq!(script.transform(event("GET /healthz"))?, None);
+    assert_eq!(script.transform(event("hi service=checkout"))?, Some(WritableEvent::new("HI SERVICE=CHECKOUT", 1234, "checkout")));
+    assert!(script.transform(event("loop")).is_err());
+    assert!(script.transform(event("nonsense")).is_err());
+
+    // errors go in as they were
+    let transformed = script.transform_batch(vec![event("a"), event("GET /healthz"), event("loop")]);
+    assert_eq!(transformed, vec![event("A"), event("loop")]);
+    assert_eq!(script.stats(), ScriptStats{ events: 3, dropped: 1, errors: 1 });
+
+    // no time for infinite loops, even with all the operations in the world
+    let slow = IngestScript::parse("slow", "fn transform(event) { loop {} }", 0, Duration::from_millis(5))?;
+    let started = Instant::now();
+    assert!(slow.transform(event("a")).is_err());
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    assert!(IngestScript::parse("eval", r#"fn transform(event) { eval("event") }"#, 1000, Duration::from_millis(10)).is_err());
+    assert!(IngestScript::parse("missing", "fn transmogrify(event) { event }", 1000, Duration::from_millis(10)).is_err());
+
+    // one script per tenant
+    let directory = crate::minute::test_data_directory("ingest_script");
+    fs::create_dir_all(&directory)?;
+    fs::write(format!("{}/default.rhai", directory), "fn transform(event) { event }")?;
+    fs::write(format!("{}/checkout.rhai", directory), "fn transform(event) { () }")?;
+    let scripts = IngestScripts::load(&directory, 1000, Duration::from_millis(10))?;
+    assert!(scripts.for_tenant(None).is_some());
+    assert_eq!(scripts.for_tenant(Some("checkout")).unwrap().transform(event("a"))?, None);
+    assert!(scripts.for_tenant(Some("search")).is_none());
+    let everybody = IngestScripts::load(&format!("{}/checkout.rhai", directory), 1000, Duration::from_millis(10))?;
+    assert!(everybody.for_tenant(Some("search")).is_some());
+
+    fs::remove_dir_all(&directory)?;
+    Ok(())
+}
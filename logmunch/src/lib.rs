@@ -0,0 +1,110 @@
+//!
+//! logmunch: a log store built out of one SQLite database per minute, with a bloom filter in front of each.
+//!
+//! The server (src/main.rs) is a thin Rocket app over this library. To run the engine inside something else
+//! without any HTTP, start an `Engine`:
+//!
+//! ```no_run
+//! use logmunch::{Engine, WritableEvent};
+//!
+//! let engine = Engine::open(logmunch::config::Config::load()?)?;
+//! engine.ingest(WritableEvent::new("level=error user=42 checkout failed", 1710562887000000, "web-1"))?;
+//! let logs = engine.search("checkout failed", 1710562800..1710562900)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! `logmunch query|histogram|minutes|verify` work on a data directory directly, with no server running, and `logmunch import`
+//! backfills old log files into it (see cli and import).
+//!
+#[macro_use] extern crate rocket;
+
+pub mod minute;
+pub mod segment;
+pub mod minute_id;
+pub mod minute_db;
+pub mod search_token;
+pub mod auth;
+pub mod token_policy;
+pub mod handshake;
+pub mod retention;
+pub mod reaper;
+pub mod archive;
+pub mod rehydrate;
+pub mod enrich;
+pub mod trace;
+pub mod search_response;
+pub mod bootstrap;
+pub mod otlp;
+pub mod fluent;
+pub mod gelf;
+pub mod loki;
+pub mod grafana;
+pub mod spl;
+pub mod hec;
+pub mod ingest;
+pub mod ingest_journal;
+pub mod host_rules;
+pub mod ingest_script;
+pub mod lookups;
+pub mod text_ingest;
+pub mod tenant;
+pub mod config;
+pub mod arrow_export;
+pub mod parquet_export;
+pub mod search_export;
+pub mod search_limit;
+pub mod engine;
+pub mod offline;
+pub mod import;
+pub mod write_stats;
+pub mod audit;
+pub mod alerts;
+pub mod log_metrics;
+pub mod check;
+pub mod minute_labels;
+pub mod markers;
+pub mod query_cache;
+pub mod load_shedding;
+pub mod rate_limit;
+pub mod tls;
+pub mod replication;
+pub mod federation;
+pub mod cardinality;
+pub mod client;
+pub mod cli;
+pub mod fuzz;
+pub mod soak;
+pub mod agent;
+
+pub mod catalog;
+pub mod watch;
+pub mod chaos;
+pub mod profile;
+pub mod logging;
+
+pub use engine::Engine;
+pub use minute::Log;
+
+///
+/// One log line on its way in: `time` is microseconds since the epoch
+///
+#[derive(Clone, PartialEq, Debug)]
+pub struct WritableEvent{
+    pub event: String,
+    pub time: i64,
+    pub host: String
+}
+
+impl WritableEvent{
+    pub fn new(event: &str, time: i64, host: &str) -> WritableEvent {
+        WritableEvent{
+            event: event.to_string(),
+            time,
+            host: host.to_string(),
+        }
+    }
+
+    pub fn get_size_in_bytes(&self) -> usize {
+        self.event.len() + self.host.len() + 8
+    }
+}
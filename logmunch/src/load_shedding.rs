@@ -0,0 +1,151 @@
+use std::fs;
+use anyhow::Result;
+
+use crate::config::Config;
+use crate::minute_db::SearchOptions;
+use crate::search_token::Search;
+use crate::tenant::Tenants;
+
+///
+/// Keeps ingest alive when the node is struggling: if the writers are falling behind (an ingest queue is filling up)
+/// or we're running out of memory, searches go into degraded mode instead of piling on.
+/// Degraded searches cover a shorter range, return fewer results, and can't use the operators that scan everything
+/// (see Search::expensive_operator); the response says so in `X-Logmunch-Degraded`.
+///
+pub struct LoadShedder{
+    queue_percent: u64,
+    min_available_memory_bytes: u64,
+    max_range_seconds: i64,
+    max_limit: usize,
+}
+
+///
+/// Why we're degraded, and what that means for searches
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Degraded{
+    pub reasons: Vec<String>,
+    pub max_range_seconds: i64,
+    pub max_limit: usize,
+}
+
+impl LoadShedder{
+    pub fn new(config: &Config) -> LoadShedder {
+        LoadShedder{
+            queue_percent: config.degraded_queue_percent,
+            min_available_memory_bytes: config.degraded_min_available_memory_mb * 1000 * 1000,
+            max_range_seconds: config.degraded_max_range_minutes as i64 * 60,
+            max_limit: config.degraded_max_limit,
+        }
+    }
+
+    ///
+    /// Are we degraded right now? Looks at every lane of every tenant's queue (they all share the node) and at /proc/meminfo
+    ///
+    pub fn status(&self, tenants: &Tenants) -> Option<Degraded> {
+        let queues: Vec<(usize, usize)> = tenants.all().flat_map(|tenant| tenant.queue.usage()).collect();
+        self.check(&queues, available_memory_bytes())
+    }
+
+    ///
+    /// `queues` is (events waiting, capacity) for each ingest queue; `available_memory_bytes` is None if we can't tell
+    ///
+    pub fn check(&self, queues: &[(usize, usize)], available_memory_bytes: Option<u64>) -> Option<Degraded> {
+        let mut reasons = Vec::new();
+        if self.queue_percent > 0 {
+            let fullest = queues.iter()
+                .filter(|(_, capacity)| *capacity > 0 && *capacity < usize::MAX)
+                .map(|(waiting, capacity)| (*waiting as u128 * 100 / *capacity as u128) as u64)
+                .max();
+            if let Some(fullest) = fullest.filter(|fullest| *fullest >= self.queue_percent) {
+                reasons.push(format!("ingest queue {}% full", fullest));
+            }
+        }
+        if let Some(available) = available_memory_bytes.filter(|available| *available < self.min_available_memory_bytes) {
+            reasons.push(format!("{}MB of memory available", available / 1000 / 1000));
+        }
+        match reasons.is_empty(){
+            true => None,
+            false => Some(Degraded{
+                reasons,
+                max_range_seconds: self.max_range_seconds,
+                max_limit: self.max_limit,
+            }),
+        }
+    }
+}
+
+impl Degraded{
+    ///
+    /// What goes in `X-Logmunch-Degraded`
+    ///
+    pub fn header(&self) -> String {
+        self.reasons.join("; ")
+    }
+
+    ///
+    /// Cut a search down to what we can afford right now: at most max_range_seconds back from `to`, at most max_limit results.
+    /// Searches with expensive operators in them get turned away instead.
+    ///
+    pub fn restrict(&self, search: &Search, options: &mut SearchOptions, now: i64) -> Result<()> {
+        if let Some(operator) = search.expensive_operator() {
+            return Err(anyhow::anyhow!("{} are off while this node is degraded ({}): try again in a bit", operator, self.header()));
+        }
+        let earliest = options.to.unwrap_or(now) - self.max_range_seconds;
+        options.from = Some(options.from.map_or(earliest, |from| from.max(earliest)));
+        options.limit = options.limit.min(self.max_limit);
+        Ok(())
+    }
+}
+
+///
+/// MemAvailable out of /proc/meminfo, in bytes: None anywhere that doesn't have it
+///
+pub fn available_memory_bytes() -> Option<u64> {
+    parse_meminfo(&fs::read_to_string("/proc/meminfo").ok()?)
+}
+
+fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[test]
+fn test_load_shedder() {
+    let shedder = LoadShedder::new(&Config{
+        degraded_queue_percent: 50,
+        degraded_min_available_memory_mb: 100,
+        degraded_max_range_minutes: 10,
+        degraded_max_limit: 50,
+        ..Config::default()
+    });
+    let plenty = Some(1000 * 1000 * 1000);
+    assert_eq!(shedder.check(&[(10, 100), (49, 100)], plenty), None);
+    // an unbounded queue can't fill up
+    assert_eq!(shedder.check(&[(1000, usize::MAX)], None), None);
+
+    let degraded = shedder.check(&[(10, 100), (75, 100)], Some(50 * 1000 * 1000)).unwrap();
+    assert_eq!(degraded.header(), "ingest queue 75% full; 50MB of memory available");
+
+    let mut options = SearchOptions{ from: Some(0), to: Some(10000), limit: 1000, ..SearchOptions::default() };
+    degraded.restrict(&Search::new("error"), &mut options, 20000).unwrap();
+    assert_eq!((options.from, options.to, options.limit), (Some(9400), Some(10000), 50));
+    // no `to` means now
+    let mut options = SearchOptions{ limit: 10, ..SearchOptions::default() };
+    degraded.restrict(&Search::new("error"), &mut options, 20000).unwrap();
+    assert_eq!((options.from, options.limit), (Some(19400), 10));
+
+    assert!(degraded.restrict(&Search::new("error*"), &mut SearchOptions::default(), 20000).is_err());
+    assert!(degraded.restrict(&Search::new("!error"), &mut SearchOptions::default(), 20000).is_err());
+
+    let off = LoadShedder::new(&Config{ degraded_queue_percent: 0, degraded_min_available_memory_mb: 0, ..Config::default() });
+    assert_eq!(off.check(&[(100, 100)], Some(0)), None);
+}
+
+#[test]
+fn test_parse_meminfo() {
+    let meminfo = "MemTotal:       16297352 kB\nMemFree:          935524 kB\nMemAvailable:    8123456 kB\n";
+    assert_eq!(parse_meminfo(meminfo), Some(8123456 * 1024));
+    assert_eq!(parse_meminfo("MemTotal: 100 kB\n"), None);
+}
@@ -0,0 +1,364 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::Result;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::WritableEvent;
+
+///
+/// A rule that's matched this many different sets of label values doesn't get any more: a label on something like a user id
+/// would otherwise grow the metrics (and whatever's scraping them) without end
+///
+const MAX_SERIES_PER_RULE: usize = 1000;
+
+///
+/// Prometheus's own default buckets
+///
+const DEFAULT_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricType{
+    #[default]
+    Counter,
+    Histogram,
+}
+
+///
+/// One metric, and the logs that count towards it: the message has to match `regex` (anywhere in it) and every field in
+/// `fields` has to match its regex (all of it), e.g. `{"status": "5\\d\\d"}`. Fields are whatever enrich::extract_fields finds.
+///
+/// `labels` are fields (or named groups in `regex`, or `host`) whose values become labels: `http.status` is labelled `http_status`.
+/// A counter goes up by one per log, or by the number in `value` (a field or named group); a histogram observes `value`,
+/// into `buckets` (Prometheus's defaults, if there aren't any). Logs without a number in `value` don't count.
+///
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MetricRule{
+    pub name: String,
+    #[serde(default)]
+    pub help: Option<String>,
+    #[serde(default, rename = "type")]
+    pub kind: MetricType,
+    #[serde(default)]
+    pub regex: Option<String>,
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    #[serde(default)]
+    pub labels: Vec<String>,
+    #[serde(default)]
+    pub value: Option<String>,
+    #[serde(default)]
+    pub buckets: Option<Vec<f64>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct MetricRulesConfig{
+    #[serde(default)]
+    pub rules: Vec<MetricRule>,
+}
+
+struct CompiledRule{
+    rule: MetricRule,
+    regex: Option<Regex>,
+    fields: Vec<(String, Regex)>,
+    label_names: Vec<String>,
+    buckets: Vec<f64>,
+}
+
+impl CompiledRule{
+    fn new(rule: MetricRule) -> Result<CompiledRule> {
+        if !is_metric_name(&rule.name) {
+            return Err(anyhow::anyhow!("'{}' isn't a Prometheus metric name", rule.name));
+        }
+        let regex = rule.regex.as_deref()
+            .map(|regex| Regex::new(regex).map_err(|e| anyhow::anyhow!("Bad regex for {}: {}", rule.name, e)))
+            .transpose()?;
+        let fields = rule.fields.iter()
+            .map(|(field, pattern)| Ok((field.clone(), Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| anyhow::anyhow!("Bad pattern for {} in {}: {}", field, rule.name, e))?)))
+            .collect::<Result<Vec<(String, Regex)>>>()?;
+        if rule.kind == MetricType::Histogram && rule.value.is_none() {
+            return Err(anyhow::anyhow!("{} is a histogram, so it needs a value to observe", rule.name));
+        }
+        let mut buckets = rule.buckets.clone().unwrap_or(DEFAULT_BUCKETS.to_vec());
+        if buckets.iter().any(|bucket| !bucket.is_finite()) {
+            return Err(anyhow::anyhow!("{} has a bucket that isn't a number", rule.name));
+        }
+        buckets.sort_by(|a, b| a.total_cmp(b));
+        buckets.dedup();
+        let label_names: Vec<String> = rule.labels.iter().map(|label| label_name(label)).collect();
+        if label_names.iter().any(|label| label == "le" || label.starts_with("__")) {
+            return Err(anyhow::anyhow!("{} has a label Prometheus keeps for itself", rule.name));
+        }
+        Ok(CompiledRule{
+            rule,
+            regex,
+            fields,
+            label_names,
+            buckets,
+        })
+    }
+
+    fn needs_fields(&self) -> bool {
+        !self.fields.is_empty() || !self.rule.labels.is_empty() || self.rule.value.is_some()
+    }
+
+    ///
+    /// If `event` counts, its label values and what it counts for
+    ///
+    fn observe(&self, event: &WritableEvent, fields: &BTreeMap<String, String>) -> Option<(Vec<String>, f64)> {
+        let captures = match &self.regex{
+            Some(regex) => Some(regex.captures(&event.event)?),
+            None => None,
+        };
+        for (field, pattern) in &self.fields {
+            if !pattern.is_match(fields.get(field)?) {
+                return None;
+            }
+        }
+        let lookup = |name: &str| -> Option<String> {
+            if let Some(found) = captures.as_ref().and_then(|captures| captures.name(name)) {
+                return Some(found.as_str().to_string());
+            }
+            match name{
+                "host" => Some(event.host.clone()),
+                _ => fields.get(name).cloned(),
+            }
+        };
+        let value = match &self.rule.value{
+            Some(value) => lookup(value)?.parse::<f64>().ok().filter(|value| value.is_finite())?,
+            None => 1.0,
+        };
+        let labels = self.rule.labels.iter().map(|label| lookup(label).unwrap_or_default()).collect();
+        Some((labels, value))
+    }
+}
+
+fn is_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|first| first.is_ascii_alphabetic() || first == '_' || first == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+fn label_name(field: &str) -> String {
+    let name: String = field.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    match name.starts_with(|c: char| c.is_ascii_digit()){
+        true => format!("_{}", name),
+        false => name,
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+///
+/// The rules in LOG_METRICS (a JSON file: `{"rules": [{"name": "http_5xx_total", "help": "5xx responses", "fields": {"status": "5\\d\\d"},
+/// "labels": ["host"]}]}`, see MetricRule), checked once at boot and shared by every tenant
+///
+#[derive(Default)]
+pub struct MetricRules{
+    rules: Vec<CompiledRule>,
+}
+
+impl MetricRules{
+    pub fn from_config(config: &crate::config::Config) -> Result<MetricRules> {
+        match &config.log_metrics{
+            Some(path) => Self::load(path),
+            None => Ok(MetricRules::default()),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<MetricRules> {
+        let contents = fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Could not read log metrics from {}: {}", path, e))?;
+        Self::parse(&contents).map_err(|e| anyhow::anyhow!("Could not parse log metrics in {}: {}", path, e))
+    }
+
+    pub fn parse(json: &str) -> Result<MetricRules> {
+        let config: MetricRulesConfig = serde_json::from_str(json)?;
+        let rules = config.rules.into_iter().map(CompiledRule::new).collect::<Result<Vec<CompiledRule>>>()?;
+        for (i, rule) in rules.iter().enumerate() {
+            if rules[..i].iter().any(|other| other.rule.name == rule.rule.name) {
+                return Err(anyhow::anyhow!("there's more than one rule for {}", rule.rule.name));
+            }
+        }
+        Ok(MetricRules{ rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+enum Series{
+    Counter(f64),
+    Histogram{
+        /// how many observations were at most each bucket (not cumulative: render adds them up)
+        buckets: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+///
+/// Logs turned into metrics as they go through a tenant's writer (see ShardedMinute::set_log_metrics), so "5xx per minute" is a
+/// graph in Prometheus instead of a search. They start at zero every time logmunch does, like any other counter.
+///
+pub struct LogMetrics{
+    rules: Arc<MetricRules>,
+    /// one map of label values to series per rule
+    series: Mutex<Vec<HashMap<Vec<String>, Series>>>,
+    dropped: AtomicU64,
+}
+
+impl LogMetrics{
+    pub fn new(rules: Arc<MetricRules>) -> LogMetrics {
+        let series = rules.rules.iter().map(|_| HashMap::new()).collect();
+        LogMetrics{
+            rules,
+            series: Mutex::new(series),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    ///
+    /// Count a batch of events, on their way to being written
+    ///
+    pub fn observe(&self, events: &[WritableEvent]) {
+        if self.rules.is_empty() {
+            return;
+        }
+        let needs_fields = self.rules.rules.iter().any(|rule| rule.needs_fields());
+        let mut series = self.series.lock().unwrap();
+        for event in events {
+            let fields = match needs_fields{
+                true => crate::enrich::extract_fields(&event.event),
+                false => BTreeMap::new(),
+            };
+            for (rule, series) in self.rules.rules.iter().zip(series.iter_mut()) {
+                let Some((labels, value)) = rule.observe(event, &fields) else {
+                    continue;
+                };
+                if !series.contains_key(&labels) && series.len() >= MAX_SERIES_PER_RULE {
+                    if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                        tracing::warn!("{} has {} series already: logs with new label values don't count towards it", rule.rule.name, MAX_SERIES_PER_RULE);
+                    }
+                    continue;
+                }
+                let entry = series.entry(labels).or_insert_with(|| match rule.rule.kind{
+                    MetricType::Counter => Series::Counter(0.0),
+                    MetricType::Histogram => Series::Histogram{ buckets: vec![0; rule.buckets.len()], sum: 0.0, count: 0 },
+                });
+                match entry{
+                    Series::Counter(total) => *total += value,
+                    Series::Histogram{ buckets, sum, count } => {
+                        if let Some(bucket) = rule.buckets.iter().position(|bucket| value <= *bucket) {
+                            buckets[bucket] += 1;
+                        }
+                        *sum += value;
+                        *count += 1;
+                    },
+                }
+            }
+        }
+    }
+
+    ///
+    /// Every series, in Prometheus's text format (plus logmunch_log_metrics_dropped_total, for logs that didn't count)
+    ///
+    pub fn render(&self) -> String {
+        let series = self.series.lock().unwrap();
+        let mut out = String::new();
+        for (rule, series) in self.rules.rules.iter().zip(series.iter()) {
+            let name = &rule.rule.name;
+            if let Some(help) = &rule.rule.help {
+                out.push_str(&format!("# HELP {} {}\n", name, help.replace('\\', "\\\\").replace('\n', "\\n")));
+            }
+            let kind = match rule.rule.kind{
+                MetricType::Counter => "counter",
+                MetricType::Histogram => "histogram",
+            };
+            out.push_str(&format!("# TYPE {} {}\n", name, kind));
+            let mut sorted: Vec<(&Vec<String>, &Series)> = series.iter().collect();
+            sorted.sort_by(|a, b| a.0.cmp(b.0));
+            for (values, series) in sorted {
+                let labels: Vec<String> = rule.label_names.iter().zip(values.iter())
+                    .map(|(label, value)| format!("{}=\"{}\"", label, escape_label_value(value)))
+                    .collect();
+                let braced = |extra: Option<String>| {
+                    let all: Vec<String> = labels.iter().cloned().chain(extra).collect();
+                    match all.is_empty(){
+                        true => String::new(),
+                        false => format!("{{{}}}", all.join(",")),
+                    }
+                };
+                match series{
+                    Series::Counter(total) => out.push_str(&format!("{}{} {}\n", name, braced(None), total)),
+                    Series::Histogram{ buckets, sum, count } => {
+                        let mut cumulative = 0;
+                        for (bucket, n) in rule.buckets.iter().zip(buckets.iter()) {
+                            cumulative += n;
+                            out.push_str(&format!("{}_bucket{} {}\n", name, braced(Some(format!("le=\"{}\"", bucket))), cumulative));
+                        }
+                        out.push_str(&format!("{}_bucket{} {}\n", name, braced(Some("le=\"+Inf\"".to_string())), count));
+                        out.push_str(&format!("{}_sum{} {}\n", name, braced(None), sum));
+                        out.push_str(&format!("{}_count{} {}\n", name, braced(None), count));
+                    },
+                }
+            }
+        }
+        out.push_str("# HELP logmunch_log_metrics_dropped_total Logs that would have started a series past a rule's limit\n");
+        out.push_str("# TYPE logmunch_log_metrics_dropped_total counter\n");
+        out.push_str(&format!("logmunch_log_metrics_dropped_total {}\n", self.dropped.load(Ordering::Relaxed)));
+        out
+    }
+}
+
+#[test]
+fn test_log_metrics() -> Result<()> {
+    let rules = MetricRules::parse(r#"{"rules": [
+        {"name": "http_5xx_total", "help": "5xx responses", "fields": {"status": "5\\d\\d"}, "labels": ["host", "status"]},
+        {"name": "checkout_failures_total", "regex": "checkout failed: (?P<reason>\\w+)", "labels": ["reason"]},
+        {"name": "bytes_sent_total", "value": "bytes"},
+        {"name": "request_seconds", "type": "histogram", "value": "duration", "buckets": [1, 0.1], "labels": ["http.method"]}
+    ]}"#)?;
+    let metrics = LogMetrics::new(Arc::new(rules));
+    let event = |message: &str, host: &str| WritableEvent::new(message, 0, host);
+    metrics.observe(&[
+        event("status=500 bytes=100 duration=0.05 http.method=GET", "web-1"),
+        event("status=503 bytes=50 duration=0.5 http.method=GET", "web-1"),
+        event("status=200 bytes=10 duration=5 http.method=POST", "web-2"),
+        event("status=5000 bytes=lots", "web-2"),
+        event("checkout failed: declined", "web-2"),
+        event(r#"checkout failed: "quoted""#, "web-2"),
+    ]);
+    let rendered = metrics.render();
+    assert!(rendered.contains("# HELP http_5xx_total 5xx responses\n# TYPE http_5xx_total counter\n"));
+    assert!(rendered.contains("http_5xx_total{host=\"web-1\",status=\"500\"} 1\nhttp_5xx_total{host=\"web-1\",status=\"503\"} 1\n"));
+    assert!(!rendered.contains("status=\"5000\""));
+    assert!(rendered.contains("checkout_failures_total{reason=\"declined\"} 1\n"));
+    assert!(rendered.contains("bytes_sent_total 160\n"));
+    assert!(rendered.contains("request_seconds_bucket{http_method=\"GET\",le=\"0.1\"} 1\nrequest_seconds_bucket{http_method=\"GET\",le=\"1\"} 2\nrequest_seconds_bucket{http_method=\"GET\",le=\"+Inf\"} 2\nrequest_seconds_sum{http_method=\"GET\"} 0.55\nrequest_seconds_count{http_method=\"GET\"} 2\n"));
+    assert!(rendered.contains("request_seconds_bucket{http_method=\"POST\",le=\"1\"} 0\nrequest_seconds_bucket{http_method=\"POST\",le=\"+Inf\"} 1\n"));
+    assert!(rendered.ends_with("logmunch_log_metrics_dropped_total 0\n"));
+
+    // a label on something that's different every time stops growing
+    let rules = MetricRules::parse(r#"{"rules": [{"name": "users_total", "labels": ["user"]}]}"#)?;
+    let metrics = LogMetrics::new(Arc::new(rules));
+    metrics.observe(&(0..MAX_SERIES_PER_RULE + 10).map(|n| event(&format!("user={}", n), "web-1")).collect::<Vec<_>>());
+    assert!(metrics.render().ends_with("logmunch_log_metrics_dropped_total 10\n"));
+    assert_eq!(escape_label_value("say \"hi\"\n"), "say \\\"hi\\\"\\n");
+
+    assert!(MetricRules::parse(r#"{"rules": [{"name": "5xx"}]}"#).is_err());
+    assert!(MetricRules::parse(r#"{"rules": [{"name": "latency", "type": "histogram"}]}"#).is_err());
+    assert!(MetricRules::parse(r#"{"rules": [{"name": "bad", "regex": "("}]}"#).is_err());
+    assert!(MetricRules::parse(r#"{"rules": [{"name": "twice"}, {"name": "twice"}]}"#).is_err());
+    assert!(MetricRules::parse(r#"{"rules": [{"name": "le_label", "labels": ["le"]}]}"#).is_err());
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use anyhow::Result;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::Config;
+
+///
+/// What log_format can be: "full" is tracing's usual one line per event, with the spans it happened in
+///
+pub const LOG_FORMATS: &[&str] = &["full", "compact", "pretty", "json"];
+
+///
+/// Our own logs (not the ones we store!) go through `tracing`: this decides what comes out, and how it looks, for the server.
+/// Everything `log`s too (Rocket does), so that ends up here as well.
+/// Without this (in tests, or inside somebody else's program) nothing comes out unless they set up a subscriber of their own.
+///
+pub fn init(config: &Config) -> Result<()> {
+    let filter = EnvFilter::try_new(&config.log_level)?;
+    let span_events = match config.log_spans{
+        true => FmtSpan::CLOSE,
+        false => FmtSpan::NONE,
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(span_events)
+        .with_thread_names(true);
+    let started = match config.log_format.as_str(){
+        "json" => subscriber.json().with_current_span(true).with_span_list(false).try_init(),
+        "compact" => subscriber.compact().try_init(),
+        "pretty" => subscriber.pretty().try_init(),
+        _ => subscriber.try_init(),
+    };
+    started.map_err(|e| anyhow::anyhow!("Couldn't start logging: {}", e))
+}
+
+///
+/// For `logmunch <command>`: what the command prints is the output, so our logs go to stderr, and only warnings
+/// (or whatever LOG_LEVEL says)
+///
+pub fn init_cli() {
+    let filter = std::env::var("LOG_LEVEL").ok()
+        .and_then(|level| EnvFilter::try_new(level).ok())
+        .unwrap_or_else(|| EnvFilter::new("warn"));
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .compact()
+        .try_init();
+}
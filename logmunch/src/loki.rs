@@ -0,0 +1,509 @@
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::str::Chars;
+use std::time::SystemTime;
+use anyhow::Result;
+use prost::Message;
+use serde::{Serialize, Deserialize};
+
+use crate::WritableEvent;
+use crate::minute::Log;
+use crate::minute_db::StatsBy;
+use crate::search_token::Search;
+
+///
+/// Loki's push.proto (logproto), as much of it as Promtail and Grafana Agent actually send.
+///
+pub mod proto{
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PushRequest{
+        #[prost(message, repeated, tag = "1")]
+        pub streams: Vec<Stream>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Stream{
+        /// `{app="checkout", env="prod"}`
+        #[prost(string, tag = "1")]
+        pub labels: String,
+        #[prost(message, repeated, tag = "2")]
+        pub entries: Vec<Entry>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Entry{
+        #[prost(message, optional, tag = "1")]
+        pub timestamp: Option<Timestamp>,
+        #[prost(string, tag = "2")]
+        pub line: String,
+    }
+
+    /// google.protobuf.Timestamp
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Timestamp{
+        #[prost(int64, tag = "1")]
+        pub seconds: i64,
+        #[prost(int32, tag = "2")]
+        pub nanos: i32,
+    }
+}
+
+///
+/// The JSON flavour: `{"streams": [{"stream": {"app": "checkout"}, "values": [["<unix nanoseconds>", "line"], ...]}]}`
+/// (values can have a third element, structured metadata, which we ignore)
+///
+#[derive(Debug, Deserialize)]
+struct JsonPushRequest{
+    streams: Vec<JsonStream>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonStream{
+    stream: BTreeMap<String, String>,
+    values: Vec<Vec<serde_json::Value>>,
+}
+
+///
+/// Parse a Prometheus-style label set: `{app="checkout", msg="say \"hi\""}`
+///
+pub fn parse_labels(labels: &str) -> Result<BTreeMap<String, String>> {
+    let labels = labels.trim();
+    let inner = labels.strip_prefix('{').and_then(|labels| labels.strip_suffix('}'))
+        .ok_or_else(|| anyhow::anyhow!("Labels should look like {{key=\"value\"}}, not {}", labels))?;
+
+    let mut parsed = BTreeMap::new();
+    let mut chars = inner.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace() || *c == ',') {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+        let mut key = String::new();
+        while let Some(c) = chars.peek() {
+            if *c == '=' || c.is_whitespace() {
+                break;
+            }
+            key.push(*c);
+            chars.next();
+        }
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.next() != Some('=') || chars.next() != Some('"') {
+            return Err(anyhow::anyhow!("Expected {}=\"...\" in {}", key, labels));
+        }
+        let mut value = String::new();
+        let mut closed = false;
+        while let Some(c) = chars.next() {
+            match c{
+                '\\' => {
+                    match chars.next(){
+                        Some('n') => value.push('\n'),
+                        Some(escaped) => value.push(escaped),
+                        None => break,
+                    }
+                },
+                '"' => {
+                    closed = true;
+                    break;
+                },
+                c => value.push(c),
+            }
+        }
+        if !closed {
+            return Err(anyhow::anyhow!("Unterminated value for {} in {}", key, labels));
+        }
+        parsed.insert(key, value);
+    }
+    Ok(parsed)
+}
+
+const HOST_LABELS: [&str; 3] = ["host", "hostname", "instance"];
+
+///
+/// The first of host/hostname/instance becomes the host; every other label gets stuck on the front of the line
+/// as key=value, so it's searchable (and `?fields=all` pulls it back out again).
+///
+pub(crate) fn flatten(labels: &BTreeMap<String, String>) -> (String, String) {
+    let host_label = HOST_LABELS.iter().find(|label| labels.contains_key(**label));
+    let host = host_label.and_then(|label| labels.get(*label)).cloned().unwrap_or("unknown".to_string());
+    let prefix = labels.iter()
+        .filter(|(key, _)| Some(&key.as_str()) != host_label)
+        .map(|(key, value)| {
+            if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"') {
+                format!("{}={:?}", key, value)
+            }
+            else {
+                format!("{}={}", key, value)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ");
+    (host, prefix)
+}
+
+fn event(host: &str, prefix: &str, line: &str, time: i64) -> WritableEvent {
+    WritableEvent{
+        event: if prefix.is_empty() { line.to_string() } else { format!("{} {}", prefix, line) },
+        time,
+        host: host.to_string(),
+    }
+}
+
+pub fn from_protobuf(request: &proto::PushRequest) -> Result<Vec<WritableEvent>> {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_micros() as i64;
+    let mut events = Vec::new();
+    for stream in &request.streams {
+        let (host, prefix) = flatten(&parse_labels(&stream.labels)?);
+        for entry in &stream.entries {
+            let time = match &entry.timestamp{
+                Some(timestamp) => timestamp.seconds * 1000000 + (timestamp.nanos / 1000) as i64,
+                None => now,
+            };
+            events.push(event(&host, &prefix, &entry.line, time));
+        }
+    }
+    Ok(events)
+}
+
+fn from_json(request: &JsonPushRequest) -> Result<Vec<WritableEvent>> {
+    let mut events = Vec::new();
+    for stream in &request.streams {
+        let (host, prefix) = flatten(&stream.stream);
+        for value in &stream.values {
+            let (time, line) = match (value.first(), value.get(1)){
+                (Some(serde_json::Value::String(time)), Some(serde_json::Value::String(line))) => (time, line),
+                _ => return Err(anyhow::anyhow!("Each value should be [\"<unix nanoseconds>\", \"<line>\"], not {:?}", value)),
+            };
+            let nanoseconds = time.parse::<i64>().map_err(|_| anyhow::anyhow!("'{}' isn't a timestamp in nanoseconds", time))?;
+            events.push(event(&host, &prefix, line, nanoseconds / 1000));
+        }
+    }
+    Ok(events)
+}
+
+///
+/// Promtail sends snappy-compressed protobuf; curl-wielding humans send JSON (maybe gzipped).
+///
+pub fn decode(body: &[u8], content_type: Option<&str>, content_encoding: Option<&str>) -> Result<Vec<WritableEvent>> {
+    let body = match content_encoding{
+        Some("gzip") => {
+            let mut decompressed = Vec::new();
+            std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(body), &mut decompressed)?;
+            decompressed
+        },
+        None | Some("identity") => body.to_vec(),
+        Some(other) => return Err(anyhow::anyhow!("Unsupported Content-Encoding: {}", other)),
+    };
+    match content_type.map(|content_type| content_type.split(';').next().unwrap_or("").trim()){
+        Some("application/json") => from_json(&serde_json::from_slice(&body)?),
+        Some("application/x-protobuf") | None => {
+            let decompressed = snap::raw::Decoder::new().decompress_vec(&body)?;
+            from_protobuf(&proto::PushRequest::decode(decompressed.as_slice())?)
+        },
+        Some(other) => Err(anyhow::anyhow!("Unsupported Content-Type: {}", other)),
+    }
+}
+
+///
+/// The bit of LogQL we can answer: a stream selector full of `label="value"` / `label!="value"` matchers,
+/// followed by any number of `|= "text"` / `!= "text"` line filters. No regexes, parsers, or metric queries.
+///
+/// Labels other than host got flattened onto the front of the line at push time, so they turn into
+/// `key=value` search tokens. The host isn't in the trigram index, so host matchers filter the results instead.
+/// (Like the rest of logmunch's search, everything is case-insensitive, which Loki's `|=` is not.)
+///
+#[derive(Debug)]
+pub struct LogQuery{
+    pub search: Search,
+    hosts: Vec<(String, bool)>,
+}
+
+impl LogQuery{
+    pub fn parse(query: &str) -> Result<LogQuery> {
+        let mut chars = query.trim().chars().peekable();
+        let mut terms = Vec::new();
+        let mut hosts = Vec::new();
+
+        if chars.next() != Some('{') {
+            return Err(anyhow::anyhow!("Queries should start with a stream selector like {{host=\"web-1\"}}, not {}", query));
+        }
+        loop {
+            while chars.peek().is_some_and(|c| c.is_whitespace() || *c == ',') {
+                chars.next();
+            }
+            if chars.peek() == Some(&'}') {
+                chars.next();
+                break;
+            }
+            let mut key = String::new();
+            while let Some(c) = chars.peek() {
+                if *c == '=' || *c == '!' || c.is_whitespace() {
+                    break;
+                }
+                key.push(*c);
+                chars.next();
+            }
+            if key.is_empty() {
+                return Err(anyhow::anyhow!("Expected a label matcher in {}", query));
+            }
+            skip_whitespace(&mut chars);
+            let negated = match (chars.next(), chars.peek()){
+                (Some('='), Some('~')) | (Some('!'), Some('~')) => return Err(anyhow::anyhow!("Regex matchers (=~, !~) aren't supported: {}", key)),
+                (Some('='), _) => false,
+                (Some('!'), Some('=')) => {
+                    chars.next();
+                    true
+                },
+                _ => return Err(anyhow::anyhow!("Expected = or != after {} in {}", key, query)),
+            };
+            skip_whitespace(&mut chars);
+            let value = read_string(&mut chars)?;
+
+            if HOST_LABELS.contains(&key.as_str()) {
+                hosts.push((value, negated));
+            }
+            else {
+                let (_, pair) = flatten(&BTreeMap::from([(key, value)]));
+                terms.push(term(&pair, negated));
+            }
+        }
+
+        loop {
+            skip_whitespace(&mut chars);
+            let negated = match (chars.next(), chars.next()){
+                (None, _) => break,
+                (Some('|'), Some('=')) => false,
+                (Some('!'), Some('=')) => true,
+                (Some('|'), Some('~')) | (Some('!'), Some('~')) => return Err(anyhow::anyhow!("Regex line filters (|~, !~) aren't supported")),
+                _ => return Err(anyhow::anyhow!("Only |= and != line filters are supported after the stream selector: {}", query)),
+            };
+            skip_whitespace(&mut chars);
+            terms.push(term(&read_string(&mut chars)?, negated));
+        }
+
+        Ok(LogQuery{
+            search: Search::parse(&terms.join(" "))?,
+            hosts,
+        })
+    }
+
+    ///
+    /// Hosts were normalized on the way in, so the ones we're matching against have to be too
+    ///
+    pub fn normalize_hosts(&mut self, host_rules: &crate::host_rules::HostRules) {
+        for (host, _negated) in self.hosts.iter_mut() {
+            *host = host_rules.normalize(host);
+        }
+    }
+
+    pub fn matches_host(&self, host: &str) -> bool {
+        self.hosts.iter().all(|(value, negated)| (host == value) != *negated)
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+///
+/// A LogQL string: "double quoted, with \"escapes\"" or `backticked, raw`
+///
+fn read_string(chars: &mut Peekable<Chars>) -> Result<String> {
+    let quote = match chars.next(){
+        Some(quote) if quote == '"' || quote == '`' => quote,
+        _ => return Err(anyhow::anyhow!("Expected a quoted string")),
+    };
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        match c{
+            '\\' if quote == '"' => {
+                match chars.next(){
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some(escaped) => value.push(escaped),
+                    None => break,
+                }
+            },
+            c if c == quote => return Ok(value),
+            c => value.push(c),
+        }
+    }
+    Err(anyhow::anyhow!("Unterminated string: {}", value))
+}
+
+///
+/// Escape a piece of text so the search tokenizer treats it as one token (spl needs this for fields, too).
+/// (Backslashes, not quotes: the tokenizer doesn't honour escapes inside quotes, and label values can contain quotes.)
+///
+pub(crate) fn term(text: &str, negated: bool) -> String {
+    let escaped: String = text.chars().flat_map(|c| {
+        if c.is_alphanumeric() { vec![c] } else { vec!['\\', c] }
+    }).collect();
+    format!("{}{}", if negated { "!" } else { "" }, escaped)
+}
+
+///
+/// What /loki/api/v1/query_range answers with: one stream per host, newest first unless asked otherwise.
+///
+#[derive(Debug, Serialize)]
+pub struct QueryResponse{
+    pub status: &'static str,
+    pub data: QueryData,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryData{
+    pub result_type: &'static str,
+    pub result: Vec<QueryStream>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryStream{
+    pub stream: BTreeMap<String, String>,
+    /// `["<unix nanoseconds>", "line"]`
+    pub values: Vec<[String; 2]>,
+}
+
+impl QueryResponse{
+    pub fn streams(logs: Vec<Log>) -> QueryResponse {
+        let mut by_host: BTreeMap<String, Vec<[String; 2]>> = BTreeMap::new();
+        for log in logs {
+            by_host.entry(log.host).or_default().push([(log.time * 1000).to_string(), log.message]);
+        }
+        QueryResponse{
+            status: "success",
+            data: QueryData{
+                result_type: "streams",
+                result: by_host.into_iter().map(|(host, values)| QueryStream{
+                    stream: BTreeMap::from([("host".to_string(), host)]),
+                    values,
+                }).collect(),
+            },
+        }
+    }
+}
+
+///
+/// /loki/api/v1/labels and /loki/api/v1/label/<name>/values
+///
+#[derive(Debug, Serialize)]
+pub struct LabelsResponse{
+    pub status: &'static str,
+    pub data: Vec<String>,
+}
+
+impl LabelsResponse{
+    pub fn new(data: Vec<String>) -> LabelsResponse {
+        LabelsResponse{
+            status: "success",
+            data,
+        }
+    }
+}
+
+///
+/// Label values come out of the same place `/search/<query>/stats` gets them: hosts, or key=value fields.
+///
+pub fn stats_by_label(label: &str) -> StatsBy {
+    if HOST_LABELS.contains(&label) {
+        return StatsBy::Host;
+    }
+    StatsBy::Field(label.to_string())
+}
+
+///
+/// Loki takes timestamps as unix nanoseconds, or as (fractional) unix seconds; we want seconds.
+///
+pub fn parse_timestamp_seconds(timestamp: &str) -> Result<i64> {
+    if let Ok(nanoseconds) = timestamp.parse::<i64>() {
+        // nanoseconds since the epoch are far bigger than this; seconds won't be for a few thousand years
+        if nanoseconds > 100_000_000_000 {
+            return Ok(nanoseconds / 1_000_000_000);
+        }
+        return Ok(nanoseconds);
+    }
+    timestamp.parse::<f64>().map(|seconds| seconds as i64).map_err(|_| anyhow::anyhow!("'{}' isn't a unix timestamp", timestamp))
+}
+
+#[test]
+fn test_parse_labels() -> Result<()> {
+    let labels = parse_labels("{app=\"checkout\", env=\"prod\",msg=\"say \\\"hi\\\"\"}")?;
+    assert_eq!(labels.get("app"), Some(&"checkout".to_string()));
+    assert_eq!(labels.get("env"), Some(&"prod".to_string()));
+    assert_eq!(labels.get("msg"), Some(&"say \"hi\"".to_string()));
+    assert!(parse_labels("{}")?.is_empty());
+    assert!(parse_labels("app=checkout").is_err());
+    assert!(parse_labels("{app=\"checkout}").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_decode_protobuf() -> Result<()> {
+    let request = proto::PushRequest{
+        streams: vec![proto::Stream{
+            labels: "{host=\"web-1\", job=\"nginx\", team=\"big spenders\"}".to_string(),
+            entries: vec![proto::Entry{
+                timestamp: Some(proto::Timestamp{ seconds: 1710562887, nanos: 366663000 }),
+                line: "GET /cart 200".to_string(),
+            }],
+        }],
+    };
+    let body = snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())?;
+    let events = decode(&body, Some("application/x-protobuf"), None)?;
+    assert_eq!(events, vec![WritableEvent{
+        event: "job=nginx team=\"big spenders\" GET /cart 200".to_string(),
+        time: 1710562887366663,
+        host: "web-1".to_string(),
+    }]);
+    assert_eq!(crate::enrich::extract_fields(&events[0].event).get("team"), Some(&"big spenders".to_string()));
+    Ok(())
+}
+
+#[test]
+fn test_decode_json() -> Result<()> {
+    let body = r#"{"streams": [{"stream": {"app": "checkout"}, "values": [["1710562887366663000", "hello"], ["1710562888000000000", "there", {"trace_id": "abc"}]]}]}"#;
+    let events = decode(body.as_bytes(), Some("application/json; charset=utf-8"), None)?;
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].event, "app=checkout hello");
+    assert_eq!(events[0].host, "unknown");
+    assert_eq!(events[1].time, 1710562888000000);
+
+    assert!(decode(b"{\"streams\": [{\"stream\": {}, \"values\": [[1, 2]]}]}", Some("application/json"), None).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_parse_log_query() -> Result<()> {
+    let query = LogQuery::parse("{host=\"web-1\", job=\"nginx\", team=\"big spenders\", env!=\"dev\"} |= \"GET\" != `healthz`")?;
+    assert!(query.search.test("web-1 job=nginx team=\"big spenders\" GET /cart 200"));
+    assert!(!query.search.test("web-1 job=nginx team=\"big spenders\" GET /healthz 200"));
+    assert!(!query.search.test("web-1 job=nginx team=\"big spenders\" env=dev GET /cart 200"));
+    assert!(!query.search.test("web-1 job=nginx GET /cart 200"));
+    assert!(query.matches_host("web-1"));
+    assert!(!query.matches_host("web-2"));
+
+    assert!(LogQuery::parse("{host!=\"web-1\"}")?.matches_host("web-2"));
+    assert!(LogQuery::parse("{}")?.search.test("anything at all"));
+
+    assert!(LogQuery::parse("{app=~\"check.*\"}").is_err());
+    assert!(LogQuery::parse("{app=\"checkout\"} |~ \"err.*\"").is_err());
+    assert!(LogQuery::parse("{app=\"checkout\"} | json").is_err());
+    assert!(LogQuery::parse("count_over_time({app=\"checkout\"}[5m])").is_err());
+    assert!(LogQuery::parse("{app=\"checkout}").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_parse_timestamp_seconds() -> Result<()> {
+    assert_eq!(parse_timestamp_seconds("1710562887366663000")?, 1710562887);
+    assert_eq!(parse_timestamp_seconds("1710562887")?, 1710562887);
+    assert_eq!(parse_timestamp_seconds("1710562887.5")?, 1710562887);
+    assert!(parse_timestamp_seconds("yesterday").is_err());
+    Ok(())
+}
@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::enrich::EnrichedLog;
+
+///
+/// Something we can load out of a file and use to add fields to search results
+///
+pub trait LookupTable: Sized {
+    fn load(path: &str) -> Result<Self>;
+    fn entries(&self) -> usize;
+    fn enrich(&self, log: &mut EnrichedLog);
+}
+
+///
+/// GeoIP: every field that holds an IP address gets `<field>_country` (and `<field>_city`, if the database knows)
+/// out of a MaxMind database (GeoLite2-City.mmdb or GeoLite2-Country.mmdb)
+///
+pub struct GeoIp{
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+impl LookupTable for GeoIp{
+    fn load(path: &str) -> Result<GeoIp> {
+        // read the whole thing in rather than mmap it: the file is going to get replaced out from under us
+        let reader = maxminddb::Reader::open_readfile(path)?;
+        Ok(GeoIp{ reader })
+    }
+
+    fn entries(&self) -> usize {
+        self.reader.metadata.node_count as usize
+    }
+
+    fn enrich(&self, log: &mut EnrichedLog) {
+        let mut found = Vec::new();
+        for (key, value) in &log.fields {
+            let ip = match value.parse::<IpAddr>(){
+                Ok(ip) => ip,
+                Err(_) => continue,
+            };
+            let city = match self.reader.lookup::<maxminddb::geoip2::City>(ip){
+                Ok(city) => city,
+                Err(_) => continue,
+            };
+            if let Some(country) = city.country.and_then(|country| country.iso_code) {
+                found.push((format!("{}_country", key), country.to_string()));
+            }
+            if let Some(name) = city.city.and_then(|city| city.names).and_then(|names| names.get("en").map(|name| name.to_string())) {
+                found.push((format!("{}_city", key), name));
+            }
+        }
+        for (key, value) in found {
+            log.fields.entry(key).or_insert(value);
+        }
+    }
+}
+
+///
+/// Host maps: a JSON file of host -> fields, like {"web-1": {"team": "checkout", "env": "prod"}}.
+/// Fields the log already has win.
+///
+pub struct HostMap{
+    hosts: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl HostMap{
+    pub fn parse(json: &str) -> Result<HostMap> {
+        Ok(HostMap{ hosts: serde_json::from_str(json)? })
+    }
+}
+
+impl LookupTable for HostMap{
+    fn load(path: &str) -> Result<HostMap> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    fn entries(&self) -> usize {
+        self.hosts.len()
+    }
+
+    fn enrich(&self, log: &mut EnrichedLog) {
+        if let Some(fields) = self.hosts.get(&log.log.host) {
+            for (key, value) in fields {
+                log.fields.entry(key.clone()).or_insert(value.clone());
+            }
+        }
+    }
+}
+
+struct Loaded<T>{
+    table: Arc<T>,
+    version: u64,
+    loaded_at: u64,
+    modified: Option<SystemTime>,
+    last_error: Option<String>,
+}
+
+///
+/// What the admin API says about a lookup table
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct LookupStatus{
+    pub name: String,
+    pub path: String,
+    pub version: u64,
+    pub loaded_at: u64,
+    pub entries: usize,
+    pub last_error: Option<String>,
+}
+
+///
+/// A lookup table that reloads itself when its file changes. Searches grab the current table and hang on to it
+/// for as long as they need: a reload swaps in a new one, and the old one goes away when the last search lets go.
+///
+pub struct Watched<T: LookupTable>{
+    name: String,
+    path: String,
+    loaded: RwLock<Loaded<T>>,
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
+
+impl<T: LookupTable> Watched<T>{
+    ///
+    /// The first load has to work: if it doesn't, we'd rather not start than quietly not enrich anything
+    ///
+    pub fn load(name: &str, path: &str) -> Result<Watched<T>> {
+        let modified = modified(path);
+        let table = T::load(path).map_err(|e| anyhow::anyhow!("Could not load {} from {}: {}", name, path, e))?;
+        Ok(Watched{
+            name: name.to_string(),
+            path: path.to_string(),
+            loaded: RwLock::new(Loaded{
+                table: Arc::new(table),
+                version: 1,
+                loaded_at: now(),
+                modified,
+                last_error: None,
+            }),
+        })
+    }
+
+    pub fn current(&self) -> Arc<T> {
+        self.loaded.read().unwrap().table.clone()
+    }
+
+    ///
+    /// Reload if the file has changed since we last looked. A file that's broken (or half-copied) leaves the old table in place.
+    ///
+    pub fn reload_if_changed(&self) -> bool {
+        let modified = modified(&self.path);
+        if modified.is_none() || modified == self.loaded.read().unwrap().modified {
+            return false;
+        }
+        // load outside the lock: searches keep using the old table in the meantime
+        let result = T::load(&self.path);
+        let mut loaded = self.loaded.write().unwrap();
+        loaded.modified = modified;
+        match result{
+            Ok(table) => {
+                loaded.table = Arc::new(table);
+                loaded.version += 1;
+                loaded.loaded_at = now();
+                loaded.last_error = None;
+                tracing::info!("Reloaded {} from {} (version {})", self.name, self.path, loaded.version);
+                true
+            },
+            Err(e) => {
+                tracing::error!("Error reloading {} from {}: {}", self.name, self.path, e);
+                loaded.last_error = Some(e.to_string());
+                false
+            }
+        }
+    }
+
+    pub fn status(&self) -> LookupStatus {
+        let loaded = self.loaded.read().unwrap();
+        LookupStatus{
+            name: self.name.clone(),
+            path: self.path.clone(),
+            version: loaded.version,
+            loaded_at: loaded.loaded_at,
+            entries: loaded.table.entries(),
+            last_error: loaded.last_error.clone(),
+        }
+    }
+}
+
+///
+/// Every lookup table we've been configured with.
+/// geoip_database (optional) is a MaxMind .mmdb file, host_map (optional) is a JSON host map (see HostMap).
+/// Both get checked for changes every lookup_reload_seconds (default 60), so dropping in this month's GeoIP database is enough.
+///
+pub struct Lookups{
+    geoip: Option<Watched<GeoIp>>,
+    host_map: Option<Watched<HostMap>>,
+    reload_seconds: u64,
+}
+
+impl Lookups{
+    pub fn from_config(config: &crate::config::Config) -> Result<Lookups> {
+        let geoip = match &config.geoip_database{
+            Some(path) => Some(Watched::load("geoip", path)?),
+            None => None,
+        };
+        let host_map = match &config.host_map{
+            Some(path) => Some(Watched::load("host_map", path)?),
+            None => None,
+        };
+        Ok(Lookups{
+            geoip,
+            host_map,
+            reload_seconds: config.lookup_reload_seconds,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.geoip.is_none() && self.host_map.is_none()
+    }
+
+    pub fn enrich(&self, mut log: EnrichedLog) -> EnrichedLog {
+        if let Some(host_map) = &self.host_map {
+            host_map.current().enrich(&mut log);
+        }
+        if let Some(geoip) = &self.geoip {
+            geoip.current().enrich(&mut log);
+        }
+        log
+    }
+
+    pub fn status(&self) -> Vec<LookupStatus> {
+        let mut status = Vec::new();
+        if let Some(geoip) = &self.geoip {
+            status.push(geoip.status());
+        }
+        if let Some(host_map) = &self.host_map {
+            status.push(host_map.status());
+        }
+        status
+    }
+
+    pub fn reload_if_changed(&self) {
+        if let Some(geoip) = &self.geoip {
+            geoip.reload_if_changed();
+        }
+        if let Some(host_map) = &self.host_map {
+            host_map.reload_if_changed();
+        }
+    }
+
+    pub fn reload_loop(&self) {
+        loop {
+            std::thread::sleep(std::time::Duration::from_secs(self.reload_seconds));
+            self.reload_if_changed();
+        }
+    }
+}
+
+#[test]
+fn test_host_map_reload() -> Result<()> {
+    let directory = crate::minute::test_data_directory("host_map_reload");
+    fs::create_dir_all(&directory)?;
+    let path = format!("{}/hosts.json", directory);
+    fs::write(&path, r#"{"web-1": {"team": "checkout"}}"#)?;
+
+    let host_map = Watched::<HostMap>::load("host_map", &path)?;
+    let log = || EnrichedLog::new(crate::minute::Log{
+        id: 1,
+        time: 1,
+        host: "web-1".to_string(),
+        message: "team=payments status=200".to_string(),
+        event_id: None,
+        minute_id: None,
+        shard: None,
+    });
+    let mut enriched = log();
+    host_map.current().enrich(&mut enriched);
+    // the log's own field wins
+    assert_eq!(enriched.fields.get("team"), Some(&"payments".to_string()));
+    assert!(!host_map.reload_if_changed());
+
+    // a search that's already holding the old table keeps it
+    let old = host_map.current();
+    fs::write(&path, r#"{"web-1": {"env": "prod"}, "web-2": {"env": "prod"}}"#)?;
+    fs::File::options().write(true).open(&path)?.set_modified(SystemTime::now() + std::time::Duration::from_secs(5))?;
+    assert!(host_map.reload_if_changed());
+    assert_eq!(old.entries(), 1);
+    assert_eq!(host_map.status().version, 2);
+    assert_eq!(host_map.status().entries, 2);
+    let mut enriched = log();
+    host_map.current().enrich(&mut enriched);
+    assert_eq!(enriched.fields.get("env"), Some(&"prod".to_string()));
+
+    // a broken file doesn't take the old table down with it
+    fs::write(&path, r#"{"web-1": "#)?;
+    fs::File::options().write(true).open(&path)?.set_modified(SystemTime::now() + std::time::Duration::from_secs(10))?;
+    assert!(!host_map.reload_if_changed());
+    assert_eq!(host_map.status().version, 2);
+    assert!(host_map.status().last_error.is_some());
+    assert_eq!(host_map.current().entries(), 2);
+
+    fs::remove_dir_all(&directory)?;
+    Ok(())
+}
@@ -1,21 +1,18 @@
 #[macro_use] extern crate rocket;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use rocket::data::Data;
 use rocket::data::ToByteUnit;
 use rocket::State;
 use rocket::serde::json::Json;
+use rocket::response::status::BadRequest;
+use std::time::SystemTime;
 use serde::Deserialize;
-use crossbeam::channel::unbounded;
-use crossbeam::channel::{Sender, Receiver};
 use rocket::tokio;
-use anyhow::Result;
 
-mod minute;
-mod minute_id;
-mod minute_db;
-mod search_token;
-
-mod file_list;
+use logmunch::{alerts, arrow_export, audit, auth, cardinality, check, cli, client, config, enrich, federation, fluent, gelf, grafana, handshake, hec, host_rules, import, ingest_script, ingest, load_shedding, log_metrics, logging, loki, lookups, markers, minute, minute_db, minute_labels, otlp, parquet_export, profile, rate_limit, replication, search_export, search_limit, search_response, search_token, spl, tenant, text_ingest, tls, token_policy, trace, write_stats};
+use logmunch::engine::{Engine, EngineSettings};
+use logmunch::WritableEvent;
 
 /*
 POST /services/collector/event/1.0 {}
@@ -66,26 +63,13 @@ struct InputEvent{
 }
 
 impl InputEvent{
-    pub fn to_writable_event(&self) -> WritableEvent{
-        let time_microseconds = (self.time.parse::<f64>().unwrap() * 1000000.0) as i64;
-        WritableEvent{
+    pub fn to_writable_event(&self) -> Option<WritableEvent>{
+        let time_microseconds = (self.time.parse::<f64>().ok()? * 1000000.0) as i64;
+        Some(WritableEvent{
             event: self.event.clone(),
             time: time_microseconds,
             host: self.host.clone()
-        }
-    }
-}
-
-#[derive(Clone, PartialEq, Debug)]
-struct WritableEvent{
-    event: String,
-    time: i64,
-    host: String
-}
-
-impl WritableEvent{
-    pub fn get_size_in_bytes(&self) -> usize {
-        self.event.len() + self.host.len() + 8
+        })
     }
 }
 
@@ -96,25 +80,47 @@ fn ingest_options_endpoint(version: f32) -> &'static str {
     "OK"
 }
 
-async fn do_something(services: &State<Services>, row: &str){
-    // do something with row
-    let event = serde_json::from_str::<InputEvent>(row).unwrap();
-
-    services.sender.send(event.to_writable_event()).unwrap();
+///
+/// One HEC event, or the HEC error that explains what's wrong with it (`event_number` counts from 0)
+///
+fn parse_hec_event(row: &str, event_number: usize) -> Result<WritableEvent, hec::HecResponse> {
+    let value = serde_json::from_str::<serde_json::Value>(row).map_err(|_| hec::HecResponse::invalid_data_format(event_number))?;
+    match value.get("event"){
+        None | Some(serde_json::Value::Null) => return Err(hec::HecResponse::event_required(event_number)),
+        Some(serde_json::Value::String(event)) if event.is_empty() => return Err(hec::HecResponse::event_blank(event_number)),
+        _ => {}
+    }
+    serde_json::from_value::<InputEvent>(value).ok()
+        .and_then(|event| event.to_writable_event())
+        .ok_or(hec::HecResponse::invalid_data_format(event_number))
 }
 
 #[post("/services/collector/event/<version>", data="<data>")]
-async fn ingest_endpoint(services: &State<Services>, data: Data<'_>, version: f32) -> &'static str {
+async fn ingest_endpoint(_allowed: auth::IngestAllowed, limit: rate_limit::IngestLimit, tenant: tenant::CallerTenant, channel: hec::HecChannel, data: Data<'_>, version: f32) -> hec::HecResponse {
+    // dedup keys on the channel, acks or not
+    let request_channel = channel.0.clone();
+    // with acks on, every batch has to say which channel it's on, or there's nowhere to keep its ackId
+    let channel = match (&tenant.0.hec_acks, channel.0){
+        (None, _) => None,
+        (Some(_), None) => return hec::HecResponse::data_channel_missing(),
+        (Some(_), Some(channel)) if !hec::HecAcks::valid_channel(&channel) => return hec::HecResponse::invalid_data_channel(),
+        (Some(_), Some(channel)) => Some(channel),
+    };
 
     let stream = data.open(10.megabytes());
-    let str = stream.into_string().await;
+    let str = match stream.into_string().await{
+        Ok(str) => str,
+        Err(_) => return hec::HecResponse::invalid_data_format(0),
+    };
     let _version = version;
+    let n_bytes = str.len();
 
     let mut charbuffer: Vec<char> = Vec::new();
     let mut in_quotes = false;
     let mut cancel = false;
+    let mut events = Vec::new();
 
-    for character in str.unwrap().into_inner().chars() {
+    for character in str.into_inner().chars() {
         charbuffer.push(character);
 
         if character == '"' && !cancel{
@@ -123,7 +129,11 @@ async fn ingest_endpoint(services: &State<Services>, data: Data<'_>, version: f3
         }
         else if character == '}' && !cancel && !in_quotes{
             let row: String = charbuffer.into_iter().collect();
-            do_something(&services, &row).await;
+            // if any event is bad, none of them go in: that way a forwarder can safely retry the whole batch
+            match parse_hec_event(&row, events.len()){
+                Ok(event) => events.push(event),
+                Err(response) => return response,
+            }
             charbuffer = Vec::new();
         }
         else if character == '\\'{
@@ -134,85 +144,1672 @@ async fn ingest_endpoint(services: &State<Services>, data: Data<'_>, version: f3
         }
     }
 
+    if events.is_empty() {
+        return hec::HecResponse::no_data();
+    }
+    if !tenant.0.queue.fits(events.len()) {
+        // this would never fit, no matter how many times the forwarder tried: the first event that doesn't is the bad one
+        return hec::HecResponse::invalid_data_format(tenant.0.queue.capacity());
+    }
+    if let Err(limited) = limit.check(events.len(), n_bytes) {
+        return hec::HecResponse::rate_limited(&limited);
+    }
+    // a retry of a batch we already have is a success, whether or not there's anything left of it to queue
+    let fresh = match &tenant.0.hec_dedup{
+        Some(dedup) => dedup.fresh(request_channel.as_deref(), events),
+        None => hec::FreshEvents{ events, ..hec::FreshEvents::default() },
+    };
+    let queued = match (&tenant.0.hec_acks, channel){
+        (Some(acks), Some(channel)) => acks.enqueue(&channel, fresh.events).map(hec::HecResponse::success_with_ack).map_err(|_| ()),
+        (_, _) if fresh.events.is_empty() => Ok(hec::HecResponse::success()),
+        _ => tenant.0.queue.enqueue(fresh.events).map(|_| hec::HecResponse::success()).map_err(|_| ()),
+    };
+    match queued{
+        Ok(response) => response,
+        Err(()) => {
+            // it didn't get in, so when the forwarder tries again, it isn't a duplicate
+            if let Some(dedup) = &tenant.0.hec_dedup {
+                dedup.release(&fresh.keys);
+            }
+            hec::HecResponse::server_busy()
+        },
+    }
+}
+
+///
+/// Forwarders with useACK=true ask here which of their batches have been committed: {"acks": [0, 1, 2]}
+///
+#[post("/services/collector/ack", data="<body>")]
+async fn hec_ack_endpoint(_allowed: auth::IngestAllowed, tenant: tenant::CallerTenant, channel: hec::HecChannel, body: String) -> Result<Json<hec::AckResponse>, hec::HecResponse> {
+    let acks = match &tenant.0.hec_acks{
+        Some(acks) => acks,
+        None => return Err(hec::HecResponse::ack_disabled()),
+    };
+    let channel = match channel.0{
+        Some(channel) if hec::HecAcks::valid_channel(&channel) => channel,
+        Some(_) => return Err(hec::HecResponse::invalid_data_channel()),
+        None => return Err(hec::HecResponse::data_channel_missing()),
+    };
+    let request = match serde_json::from_str::<hec::AckRequest>(&body){
+        Ok(request) => request,
+        Err(_) => return Err(hec::HecResponse::invalid_data_format(0)),
+    };
+    Ok(Json(acks.query(&channel, &request.acks)))
+}
+
+///
+/// The ingest formats that aren't Splunk's care about Content-Type and Content-Encoding
+///
+struct ContentHeaders{
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for ContentHeaders {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::request::Request<'_>) -> rocket::request::Outcome<Self, Self::Error> {
+        rocket::request::Outcome::Success(ContentHeaders{
+            content_type: request.headers().get_one("Content-Type").map(|value| value.to_string()),
+            content_encoding: request.headers().get_one("Content-Encoding").map(|value| value.to_lowercase()),
+        })
+    }
+}
+
+///
+/// OTLP/HTTP logs (protobuf, optionally gzipped), for pointing an OpenTelemetry Collector straight at us
+///
+#[post("/v1/logs", data="<data>")]
+async fn otlp_logs_endpoint(_allowed: auth::IngestAllowed, limit: rate_limit::IngestLimit, tenant: tenant::CallerTenant, headers: ContentHeaders, data: Data<'_>) -> Result<(rocket::http::ContentType, Vec<u8>), ingest::IngestError> {
+    if headers.content_type.as_deref() != Some("application/x-protobuf") {
+        return Err(ingest::IngestError::UnsupportedMediaType("OTLP/HTTP has to be application/x-protobuf".to_string()));
+    }
+    let body = match data.open(10.megabytes()).into_bytes().await{
+        Ok(body) => body.into_inner(),
+        Err(err) => return Err(ingest::IngestError::BadRequest(err.to_string())),
+    };
+    let request = match otlp::decode_http(&body, headers.content_encoding.as_deref()){
+        Ok(request) => request,
+        Err(err) => return Err(ingest::IngestError::BadRequest(err.to_string())),
+    };
+    let events = otlp::to_writable_events(&request);
+    if !tenant.0.queue.fits(events.len()) {
+        return Err(ingest::IngestError::BadRequest(format!("{} log records is more than the ingest queue holds ({}): send smaller batches", events.len(), tenant.0.queue.capacity())));
+    }
+    limit.check(events.len(), body.len())?;
+    tenant.0.queue.enqueue(events)?;
+    Ok((rocket::http::ContentType::new("application", "x-protobuf"), otlp::encode_response()))
+}
+
+///
+/// The Loki push API, so Promtail and Grafana Agent can ship to us unchanged
+///
+#[post("/loki/api/v1/push", data="<data>")]
+async fn loki_push_endpoint(_allowed: auth::IngestAllowed, limit: rate_limit::IngestLimit, tenant: tenant::CallerTenant, headers: ContentHeaders, data: Data<'_>) -> Result<rocket::http::Status, ingest::IngestError> {
+    let body = match data.open(10.megabytes()).into_bytes().await{
+        Ok(body) => body.into_inner(),
+        Err(err) => return Err(ingest::IngestError::BadRequest(err.to_string())),
+    };
+    let events = match loki::decode(&body, headers.content_type.as_deref(), headers.content_encoding.as_deref()){
+        Ok(events) => events,
+        Err(err) => return Err(ingest::IngestError::BadRequest(err.to_string())),
+    };
+    if !tenant.0.queue.fits(events.len()) {
+        return Err(ingest::IngestError::BadRequest(format!("{} log lines is more than the ingest queue holds ({}): send smaller batches", events.len(), tenant.0.queue.capacity())));
+    }
+    limit.check(events.len(), body.len())?;
+    tenant.0.queue.enqueue(events)?;
+    Ok(rocket::http::Status::NoContent)
+}
+
+///
+/// A plain text log file, one event per line (see text_ingest::TextUpload), e.g.
+/// curl --data-binary @app.log 'localhost:8000/api/v1/ingest/text?host=web-1&source=app.log&timestamp_regex=^(\S+)'
+///
+#[post("/api/v1/ingest/text?<host>&<source>&<timestamp_regex>", data="<data>")]
+async fn text_ingest_endpoint(_allowed: auth::IngestAllowed, limit: rate_limit::IngestLimit, tenant: tenant::CallerTenant, host: Option<&str>, source: Option<&str>, timestamp_regex: Option<&str>, data: Data<'_>) -> Result<Json<text_ingest::TextIngestReport>, ingest::IngestError> {
+    let upload = match text_ingest::TextUpload::new(host, source, timestamp_regex){
+        Ok(upload) => upload,
+        Err(err) => return Err(ingest::IngestError::BadRequest(err.to_string())),
+    };
+    let body = match data.open(64.mebibytes()).into_string().await{
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => return Err(ingest::IngestError::BadRequest("Uploads are limited to 64MiB: split the file up".to_string())),
+        Err(err) => return Err(ingest::IngestError::BadRequest(err.to_string())),
+    };
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_micros() as i64;
+    let (events, report) = upload.parse(&body, now);
+    if !tenant.0.queue.fits(events.len()) {
+        // this would never fit, no matter how long they waited
+        return Err(ingest::IngestError::BadRequest(format!("{} lines is more than the ingest queue holds ({}): split the file up", events.len(), tenant.0.queue.capacity())));
+    }
+    limit.check(events.len(), body.len())?;
+    tenant.0.queue.enqueue(events)?;
+    Ok(Json(report))
+}
+
+///
+/// Old logs, into the minutes they happened in (see import::Import): plain text or JSONL, gzipped or not, e.g.
+/// curl --data-binary @app.log.gz 'localhost:8000/api/v1/import?host=web-1&timestamp_regex=^(\S+)'
+/// Unlike /api/v1/ingest/text this skips the ingest queue, and returns once it's all sealed and searchable.
+///
+#[post("/api/v1/import?<host>&<format>&<timestamp_regex>&<timestamp_format>&<timestamp_field>", data="<data>")]
+// every guard and query parameter is an argument, that's just how rocket works
+#[allow(clippy::too_many_arguments)]
+async fn import_endpoint(services: &State<Services>, _allowed: auth::IngestAllowed, limit: rate_limit::IngestLimit, tenant: tenant::CallerTenant, host: Option<&str>, format: Option<&str>, timestamp_regex: Option<&str>, timestamp_format: Option<&str>, timestamp_field: Option<&str>, data: Data<'_>) -> Result<Json<import::ImportReport>, ingest::IngestError> {
+    let settings = import::ImportSettings{
+        format: format.map(import::ImportFormat::parse).transpose().map_err(|err| ingest::IngestError::BadRequest(err.to_string()))?,
+        host: host.map(|host| host.to_string()),
+        timestamp_regex: timestamp_regex.map(|regex| regex.to_string()),
+        timestamp_format: timestamp_format.map(|format| format.to_string()),
+        timestamp_field: timestamp_field.map(|field| field.to_string()),
+    };
+    let body = match data.open(256.mebibytes()).into_bytes().await{
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => return Err(ingest::IngestError::BadRequest("Imports are limited to 256MiB: split the file up, or use `logmunch import`".to_string())),
+        Err(err) => return Err(ingest::IngestError::BadRequest(err.to_string())),
+    };
+    // imports skip the queue, but not the sender's rate limit: it's charged for every line before any of them are written
+    let (body, n_events) = tokio::task::spawn_blocking(move || {
+        let n_events = import::Import::count_events(body.as_slice());
+        (body, n_events)
+    }).await.map_err(|err| ingest::IngestError::BadRequest(err.to_string()))?;
+    let n_events = n_events.map_err(|err| ingest::IngestError::BadRequest(err.to_string()))?;
+    limit.check(n_events, body.len())?;
+    let mut import = import::Import::from_config(&services.config, &tenant.0.minutes_directory);
+    import.set_host_rules(services.host_rules.clone());
+    let report = tokio::task::spawn_blocking(move || {
+        import.read(body.as_slice(), &settings)?;
+        import.finish()
+    }).await.map_err(|err| ingest::IngestError::BadRequest(err.to_string()))?;
+    match report{
+        Ok(report) => Ok(Json(report)),
+        Err(err) => Err(ingest::IngestError::BadRequest(err.to_string())),
+    }
+}
+
+///
+/// Enough of Loki's query API for Grafana's Loki datasource to use us: see loki::LogQuery for what LogQL we understand.
+/// start/end are unix nanoseconds (or seconds), direction is "backward" (the default) or "forward".
+///
+#[get("/loki/api/v1/query_range?<query>&<start>&<end>&<limit>&<direction>")]
+// every guard and query parameter is an argument, that's just how rocket works
+#[allow(clippy::too_many_arguments)]
+async fn loki_query_range_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, _slot: search_limit::SearchSlot, query: &str, start: Option<&str>, end: Option<&str>, limit: Option<usize>, direction: Option<&str>) -> Result<Json<loki::QueryResponse>, BadRequest<String>> {
+    let mut query = match loki::LogQuery::parse(query){
+        Ok(query) => query,
+        Err(err) => return Err(BadRequest(err.to_string())),
+    };
+    query.normalize_hosts(&services.host_rules);
+    let order = match direction{
+        None | Some("backward") => minute_db::SortOrder::Descending,
+        Some("forward") => minute_db::SortOrder::Ascending,
+        Some(other) => return Err(BadRequest(format!("direction must be 'backward' or 'forward', not '{}'", other))),
+    };
+    let (from, to) = match (start.map(loki::parse_timestamp_seconds).transpose(), end.map(loki::parse_timestamp_seconds).transpose()){
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(err), _) | (_, Err(err)) => return Err(BadRequest(err.to_string())),
+    };
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let mut options = match services.token_policies.get(&token).admit(from, to, limit, now){
+        Ok(options) => options,
+        Err(err) => return Err(BadRequest(err.to_string())),
+    };
+    options.order = order;
+    if let Err(err) = services.config.check_search_limit(options.limit, 0) {
+        return Err(BadRequest(err.to_string()));
+    }
+
+    // host matchers are applied after the search, so a host-restricted query can come back with fewer than `limit` lines
+    let results = match tenant.0.minute_db.search_async(query.search.clone(), options).await{
+        Ok((results, _stats)) => results,
+        Err(err) => {
+            tracing::error!("Error searching: {:?}", err);
+            Vec::new()
+        }
+    };
+    let results = results.into_iter().filter(|log| query.matches_host(&log.host)).collect();
+
+    Ok(Json(loki::QueryResponse::streams(results)))
+}
+
+///
+/// Grafana asks for these to fill in its query builder (and to test the datasource).
+/// Everything that isn't the host is a key=value field in the line, which we can't list without reading every log, so: just host.
+///
+#[get("/loki/api/v1/labels")]
+fn loki_labels_endpoint(_allowed: auth::SearchAllowed) -> Json<loki::LabelsResponse> {
+    Json(loki::LabelsResponse::new(vec!["host".to_string()]))
+}
+
+#[get("/loki/api/v1/label/<name>/values?<start>&<end>")]
+#[allow(clippy::too_many_arguments)]
+async fn loki_label_values_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, _slot: search_limit::SearchSlot, name: &str, start: Option<&str>, end: Option<&str>) -> Result<Json<loki::LabelsResponse>, BadRequest<String>> {
+    let (from, to) = match (start.map(loki::parse_timestamp_seconds).transpose(), end.map(loki::parse_timestamp_seconds).transpose()){
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(err), _) | (_, Err(err)) => return Err(BadRequest(err.to_string())),
+    };
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let options = match services.token_policies.get(&token).admit(from, to, None, now){
+        Ok(options) => options,
+        Err(err) => return Err(BadRequest(err.to_string())),
+    };
+
+    let by = loki::stats_by_label(name);
+    let values = match tenant.0.minute_db.stats_async(search_token::Search::new(""), options, by, 1000).await{
+        Ok((result, _stats)) => result.top.into_iter().map(|value_count| value_count.value).collect(),
+        Err(err) => {
+            tracing::error!("Error listing label values: {:?}", err);
+            Vec::new()
+        }
+    };
+
+    Ok(Json(loki::LabelsResponse::new(values)))
+}
+
+///
+/// Grafana's JSON datasource (simple-json, or infinity) can use us directly: point it at /grafana, which is what it asks when you test the connection.
+/// Every target is a search; see grafana::QueryRequest.
+///
+#[get("/grafana")]
+fn grafana_endpoint(_allowed: auth::SearchAllowed) -> &'static str {
     "OK"
 }
 
-#[get("/search/<search>")]
-async fn search_endpoint(services: &State<Services>, search: &str) -> Json<Vec<crate::minute::Log>> {
-    let search = search_token::Search::new(&search);
+///
+/// The drop-down of targets: the hosts that have sent logs in the last hour
+///
+#[post("/grafana/search", data="<request>")]
+async fn grafana_search_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, _slot: search_limit::SearchSlot, request: Json<grafana::SearchRequest>) -> Result<Json<Vec<String>>, BadRequest<String>> {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let options = services.token_policies.get(&token).admit(Some(now - 3600), None, None, now).map_err(|err| BadRequest(err.to_string()))?;
+    let hosts = match tenant.0.minute_db.hosts_async(options).await{
+        Ok(hosts) => hosts.hosts.into_iter().map(|host| host.host).collect(),
+        Err(err) => {
+            tracing::error!("Error listing hosts: {:?}", err);
+            Vec::new()
+        }
+    };
+    Ok(Json(grafana::suggestions(hosts, &request.target)))
+}
 
-    let results = match services.minute_db.search_async(search).await{
-        Ok(results) => results,
+///
+/// "timeserie" targets get a histogram of the search, "table" targets get the matching logs (most recent first, up to maxDataPoints)
+///
+#[post("/grafana/query", data="<request>")]
+async fn grafana_query_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, _slot: search_limit::SearchSlot, request: Json<grafana::QueryRequest>) -> Result<Json<Vec<grafana::QueryResult>>, search_response::SearchError> {
+    let (from, to) = request.range.seconds().map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+    let bucket_seconds = request.bucket_seconds(from, to);
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+
+    let mut results = Vec::new();
+    for target in request.targets.iter().filter(|target| !target.hide) {
+        let search = search_token::Search::parse(&services.host_rules.rewrite_search(&target.target)).map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+        let limit = match target.kind{
+            grafana::TargetType::TimeSerie => None,
+            grafana::TargetType::Table => request.max_data_points,
+        };
+        let mut options = services.token_policies.get(&token).admit(Some(from), Some(to), limit, now).map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+        shed_load(services, &search, &mut options)?;
+
+        match target.kind{
+            grafana::TargetType::TimeSerie => {
+                let buckets = match tenant.0.minute_db.histogram_async(search, options, bucket_seconds).await{
+                    Ok((buckets, _stats)) => buckets,
+                    Err(err) => {
+                        tracing::error!("Error building histogram: {:?}", err);
+                        Vec::new()
+                    }
+                };
+                results.push(grafana::QueryResult::time_serie(&target.target, buckets, bucket_seconds, from, to));
+            },
+            grafana::TargetType::Table => {
+                services.config.check_search_limit(options.limit, 0).map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+                options.timeout = services.config.search_timeout();
+                let logs = match tenant.0.minute_db.search_async(search, options).await{
+                    Ok((logs, _stats)) => logs,
+                    Err(err) => {
+                        tracing::error!("Error searching: {:?}", err);
+                        Vec::new()
+                    }
+                };
+                results.push(grafana::QueryResult::table(logs));
+            },
+        }
+    }
+    Ok(Json(results))
+}
+
+///
+/// An annotation with a blank query shows the markers (deploys, incidents); otherwise it's a search, and the most recent
+/// grafana::MAX_ANNOTATIONS matching logs each get a flag
+///
+#[post("/grafana/annotations", data="<request>")]
+async fn grafana_annotations_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, _slot: search_limit::SearchSlot, request: Json<grafana::AnnotationRequest>) -> Result<Json<Vec<grafana::AnnotationEvent>>, search_response::SearchError> {
+    let (from, to) = request.range.seconds().map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+    if request.query().is_empty() {
+        let markers = tenant.0.markers.for_search(Some(from), Some(to));
+        return Ok(Json(markers.into_iter().map(|marker| grafana::AnnotationEvent::from_marker(&request.annotation, marker)).collect()));
+    }
+
+    let search = search_token::Search::parse(&services.host_rules.rewrite_search(request.query())).map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let mut options = services.token_policies.get(&token).admit(Some(from), Some(to), Some(grafana::MAX_ANNOTATIONS), now).map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+    shed_load(services, &search, &mut options)?;
+    options.timeout = services.config.search_timeout();
+    let logs = match tenant.0.minute_db.search_async(search, options).await{
+        Ok((logs, _stats)) => logs,
         Err(err) => {
-            println!("Error searching: {:?}", err);
+            tracing::error!("Error searching: {:?}", err);
             Vec::new()
         }
     };
+    Ok(Json(logs.into_iter().map(|log| grafana::AnnotationEvent::from_log(&request.annotation, log)).collect()))
+}
+
+///
+/// from/to are seconds since the epoch
+///
+#[derive(FromForm, Debug, Default)]
+struct SearchParams<'r>{
+    from: Option<i64>,
+    to: Option<i64>,
+    limit: Option<usize>,
+    /// skip this many results: the next page is offset + limit
+    offset: Option<usize>,
+    /// at most this many results from any one minute
+    max_per_minute: Option<usize>,
+    fields: Option<&'r str>,
+    /// "desc" (most recent first, the default) or "asc"
+    order: Option<&'r str>,
+    /// "json" (the default) or "arrow" (an Arrow IPC stream, for pandas/polars)
+    format: Option<&'r str>,
+    /// every term is `cs:` (case sensitive)
+    case_sensitive: Option<bool>,
+    /// every term is `word:` (whole words only)
+    whole_word: Option<bool>,
+}
+
+impl SearchParams<'_>{
+    ///
+    /// The search, parsed, or what's wrong with it
+    ///
+    fn search(&self, services: &Services, search: &str) -> Result<search_token::Search, BadRequest<String>> {
+        let modifiers = search_token::Modifiers{
+            case_sensitive: self.case_sensitive.unwrap_or(false),
+            whole_word: self.whole_word.unwrap_or(false),
+        };
+        search_token::Search::parse_with_modifiers(&services.host_rules.rewrite_search(search), modifiers).map_err(|err| BadRequest(err.to_string()))
+    }
+
+    ///
+    /// What the caller asked for, with their token's defaults filled in, as long as neither the token's policy nor MAX_SEARCH_LIMIT objects
+    ///
+    fn options(&self, services: &Services, token: &auth::ApiToken) -> Result<minute_db::SearchOptions, BadRequest<String>> {
+        let options = self.admitted_options(services, token)?;
+        services.config.check_search_limit(options.limit, options.offset).map_err(|err| BadRequest(err.to_string()))?;
+        Ok(options)
+    }
+
+    ///
+    /// options, before MAX_SEARCH_LIMIT has had its say
+    ///
+    fn admitted_options(&self, services: &Services, token: &auth::ApiToken) -> Result<minute_db::SearchOptions, BadRequest<String>> {
+        let order = match self.order.map(minute_db::SortOrder::parse).transpose(){
+            Ok(order) => order.unwrap_or_default(),
+            Err(err) => return Err(BadRequest(err.to_string())),
+        };
+        if self.max_per_minute == Some(0) {
+            return Err(BadRequest("max_per_minute has to be at least 1".to_string()));
+        }
+
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+        let mut options = match services.token_policies.get(token).admit(self.from, self.to, self.limit, now){
+            Ok(options) => options,
+            Err(err) => return Err(BadRequest(err.to_string())),
+        };
+        options.order = order;
+        options.offset = self.offset.unwrap_or(0);
+        options.max_per_minute = self.max_per_minute;
+        Ok(options)
+    }
+}
+
+///
+/// If the node's degraded (see load_shedding), cut the search down to fit, or turn it away if it's too expensive:
+/// what comes back goes in X-Logmunch-Degraded
+///
+fn shed_load(services: &Services, search: &search_token::Search, options: &mut minute_db::SearchOptions) -> Result<Option<String>, search_response::SearchError> {
+    let degraded = match services.load_shedder.status(&services.tenants){
+        Some(degraded) => degraded,
+        None => return Ok(None),
+    };
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    degraded.restrict(search, options, now).map_err(|err| search_response::SearchError::Overloaded(err.to_string(), ingest::retry_after()))?;
+    Ok(Some(degraded.header()))
+}
+
+#[get("/search/<search>?<params..>")]
+#[allow(clippy::too_many_arguments)]
+async fn search_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, _slot: search_limit::SearchSlot, trace: trace::TraceContext, forwarded: federation::Forwarded, search: &str, params: SearchParams<'_>) -> Result<search_response::SearchResponse, search_response::SearchError> {
+    let enrich = match params.fields{
+        None | Some("raw") => false,
+        Some("all") => true,
+        Some(other) => return Err(search_response::SearchError::BadRequest(format!("fields must be 'all' or 'raw', not '{}'", other))),
+    };
+    let arrow = match params.format{
+        None | Some("json") => false,
+        Some("arrow") => true,
+        Some(other) => return Err(search_response::SearchError::BadRequest(format!("format must be 'json' or 'arrow', not '{}'", other))),
+    };
+
+    let mut options = params.options(services, &token)?;
+    options.timeout = services.config.search_timeout();
+
+    let query = search;
+    let search = params.search(services, query)?;
+    let degraded = shed_load(services, &search, &mut options)?;
+
+    let markers = tenant.0.markers.for_search(options.from, options.to);
+
+    // federated (see federation): the peers that have this tenant's minutes each send their first offset + limit, and so do we,
+    // and the page comes out of all of them together
+    let federation = services.federation.as_ref().filter(|_| !forwarded.0);
+    let peers = federation.map(|federation| federation.peers_for(tenant.0.name.as_deref())).unwrap_or_default();
+    let (order, offset, limit) = (options.order, options.offset, options.limit);
+    if !peers.is_empty() {
+        options.limit = offset + limit;
+        options.offset = 0;
+    }
+    let peer_query = client::SearchQuery{
+        from: options.from,
+        to: options.to,
+        limit: Some(options.limit),
+        offset: Some(0),
+        max_per_minute: options.max_per_minute,
+        order: Some(order),
+        case_sensitive: params.case_sensitive.unwrap_or(false),
+        whole_word: params.whole_word.unwrap_or(false),
+    };
+    let peer_searches = async {
+        match federation{
+            Some(federation) if !peers.is_empty() => federation.search(&peers, tenant.0.name.as_deref(), token.0.as_deref(), &trace, query, &peer_query).await,
+            _ => Vec::new(),
+        }
+    };
+
+    let (local, remote) = tokio::join!(tenant.0.minute_db.search_async(search, options), peer_searches);
+    let (results, mut stats) = match local{
+        Ok(results) => results,
+        Err(err) => {
+            tracing::error!("Error searching: {:?}", err);
+            (Vec::new(), minute_db::SearchStats::default())
+        }
+    };
+    stats.node = services.config.machine_id;
+    let mut stats = vec![stats];
+    let results = match peers.is_empty(){
+        true => results,
+        false => {
+            let mut pages = vec![results];
+            for (logs, peer_stats) in remote {
+                pages.push(logs);
+                stats.extend(peer_stats);
+            }
+            federation::merge(pages, order, offset, limit)
+        },
+    };
+
+    let results = match (enrich, arrow){
+        (true, false) => search_response::SearchResults::All(results.into_iter().map(|log| services.lookups.enrich(enrich::EnrichedLog::new(log))).collect()),
+        (false, false) => search_response::SearchResults::Raw(results),
+        (true, true) => {
+            let results: Vec<enrich::EnrichedLog> = results.into_iter().map(|log| services.lookups.enrich(enrich::EnrichedLog::new(log))).collect();
+            search_response::SearchResults::Arrow(arrow_export::encode_enriched(&results).map_err(|err| BadRequest(err.to_string()))?)
+        },
+        (false, true) => search_response::SearchResults::Arrow(arrow_export::encode_logs(&results).map_err(|err| BadRequest(err.to_string()))?),
+    };
+
+    Ok(search_response::SearchResponse{
+        results,
+        trace,
+        stats,
+        markers,
+        degraded,
+    })
+}
+
+///
+/// The same search, as newline-delimited JSON (one log per line) that starts arriving as soon as the first minute's been searched,
+/// instead of one big JSON document at the end: `curl -N .../stream | jq` gets going right away.
+///
+#[get("/search/<search>/stream?<params..>")]
+async fn search_stream_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, slot: search_limit::SearchSlot, search: &str, params: SearchParams<'_>) -> Result<(rocket::http::ContentType, rocket::response::stream::TextStream![String]), search_response::SearchError> {
+    let enrich = match params.fields{
+        None | Some("raw") => false,
+        Some("all") => true,
+        Some(other) => return Err(search_response::SearchError::BadRequest(format!("fields must be 'all' or 'raw', not '{}'", other))),
+    };
+    match params.format{
+        None | Some("ndjson") => {},
+        Some(other) => return Err(search_response::SearchError::BadRequest(format!("streams are always ndjson, not '{}'", other))),
+    }
+
+    let options = params.options(services, &token)?;
+
+    let search = params.search(services, search)?;
+    // a stream keeps its search going for as long as somebody's reading: not something we can cut down to fit
+    if let Some(degraded) = services.load_shedder.status(&services.tenants) {
+        return Err(search_response::SearchError::Overloaded(format!("streaming searches are off while this node is degraded ({}): try again in a bit", degraded.header()), ingest::retry_after()));
+    }
+    let mut minutes = tenant.0.minute_db.search_stream(search, options);
+    let lookups = services.lookups.clone();
+
+    let stream = rocket::response::stream::TextStream! {
+        // the search is still going until the stream's done, so its turn is too
+        let _slot = slot;
+        // one chunk per minute: a line per log
+        while let Some(logs) = minutes.recv().await {
+            let mut chunk = String::new();
+            for log in logs {
+                let line = match enrich{
+                    true => serde_json::to_string(&lookups.enrich(enrich::EnrichedLog::new(log))),
+                    false => serde_json::to_string(&log),
+                };
+                if let Ok(line) = line {
+                    chunk.push_str(&line);
+                    chunk.push('\n');
+                }
+            }
+            yield chunk;
+        }
+    };
+    Ok((rocket::http::ContentType::new("application", "x-ndjson"), stream))
+}
+
+///
+/// Every log the search finds, as CSV (for a spreadsheet) or JSONL (for jq), streamed a minute at a time: the search only
+/// gets as far ahead of the download as search_stream lets it. There's no limit unless you ask for one (and then it's checked
+/// like any other search's) or your token's policy has one: nothing's held on to. `columns` gives CSV a column for each of those fields.
+///
+#[get("/search/<search>/export?<columns>&<params..>")]
+#[allow(clippy::too_many_arguments)]
+async fn search_export_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, slot: search_limit::SearchSlot, search: &str, columns: Option<&str>, params: SearchParams<'_>) -> Result<(rocket::http::ContentType, rocket::response::stream::TextStream![String]), search_response::SearchError> {
+    let enrich = match params.fields{
+        None | Some("raw") => false,
+        Some("all") => true,
+        Some(other) => return Err(search_response::SearchError::BadRequest(format!("fields must be 'all' or 'raw', not '{}'", other))),
+    };
+    let format = search_export::ExportFormat::parse(params.format).map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+    let columns: Vec<String> = columns.unwrap_or_default().split(',').map(|column| column.trim().to_string()).filter(|column| !column.is_empty()).collect();
+    let lines = search_export::ExportLines::new(format, enrich, &columns).map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+
+    let mut options = match params.limit{
+        Some(_) => params.options(services, &token)?,
+        None => params.admitted_options(services, &token)?,
+    };
+    options.limit = params.limit.or(services.token_policies.get(&token).max_limit).unwrap_or(usize::MAX);
+    let search = params.search(services, search)?;
+    if let Some(degraded) = services.load_shedder.status(&services.tenants) {
+        return Err(search_response::SearchError::Overloaded(format!("exports are off while this node is degraded ({}): try again in a bit", degraded.header()), ingest::retry_after()));
+    }
+    let mut minutes = tenant.0.minute_db.search_stream(search, options);
+    let lookups = services.lookups.clone();
+
+    let stream = rocket::response::stream::TextStream! {
+        let _slot = slot;
+        if let Some(header) = lines.header() {
+            yield header;
+        }
+        while let Some(logs) = minutes.recv().await {
+            let mut chunk = String::new();
+            for log in logs {
+                if let Ok(line) = lines.line(log, |enriched| lookups.enrich(enriched)) {
+                    chunk.push_str(&line);
+                }
+            }
+            yield chunk;
+        }
+    };
+    let (top, sub) = format.content_type();
+    Ok((rocket::http::ContentType::new(top, sub), stream))
+}
+
+///
+/// What Splunk's search/jobs/export takes (as a query string or a form): anything else a Splunk client sends is ignored.
+/// earliest= and latest= in the search beat earliest_time and latest_time.
+///
+#[derive(FromForm, Debug, Default)]
+struct SplunkExportParams<'r>{
+    search: &'r str,
+    earliest_time: Option<&'r str>,
+    latest_time: Option<&'r str>,
+    /// only "json": no xml, csv or raw
+    output_mode: Option<&'r str>,
+}
+
+///
+/// For scripts written against Splunk: see spl::SplQuery for the SPL we understand. Results are streamed like
+/// /search/<search>/export, most recent first, as Splunk's JSON export lines.
+///
+#[get("/services/search/jobs/export?<params..>")]
+async fn splunk_export_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, slot: search_limit::SearchSlot, params: SplunkExportParams<'_>) -> Result<(rocket::http::ContentType, rocket::response::stream::TextStream![String]), search_response::SearchError> {
+    splunk_export(services, tenant, token, slot, params)
+}
+
+#[post("/services/search/jobs/export", data="<params>")]
+async fn splunk_export_form_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, slot: search_limit::SearchSlot, params: rocket::form::Form<SplunkExportParams<'_>>) -> Result<(rocket::http::ContentType, rocket::response::stream::TextStream![String]), search_response::SearchError> {
+    splunk_export(services, tenant, token, slot, params.into_inner())
+}
+
+///
+/// Splunk's SDKs put the owner and app in the path
+///
+#[post("/servicesNS/<_owner>/<_app>/search/jobs/export", data="<params>")]
+#[allow(clippy::too_many_arguments)]
+async fn splunk_export_ns_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, slot: search_limit::SearchSlot, _owner: &str, _app: &str, params: rocket::form::Form<SplunkExportParams<'_>>) -> Result<(rocket::http::ContentType, rocket::response::stream::TextStream![String]), search_response::SearchError> {
+    splunk_export(services, tenant, token, slot, params.into_inner())
+}
+
+fn splunk_export(services: &Services, tenant: tenant::CallerTenant, token: auth::ApiToken, slot: search_limit::SearchSlot, params: SplunkExportParams<'_>) -> Result<(rocket::http::ContentType, rocket::response::stream::TextStream![String]), search_response::SearchError> {
+    if params.output_mode.is_some_and(|output_mode| output_mode != "json") {
+        return Err(search_response::SearchError::BadRequest(format!("output_mode must be 'json', not '{}'", params.output_mode.unwrap_or_default())));
+    }
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let query = spl::SplQuery::parse(params.search, now).map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+    let (earliest, latest) = match (params.earliest_time.map(|time| spl::parse_time(time, now)).transpose(), params.latest_time.map(|time| spl::parse_time(time, now)).transpose()){
+        (Ok(earliest), Ok(latest)) => (query.earliest.or(earliest), query.latest.or(latest)),
+        (Err(err), _) | (_, Err(err)) => return Err(search_response::SearchError::BadRequest(err.to_string())),
+    };
+
+    // like /search/<search>/export: no limit unless `| head` asks for one (which gets checked), or the token's policy has one
+    let mut options = services.token_policies.get(&token).admit(earliest, latest, query.head, now).map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+    if let Some(head) = query.head {
+        services.config.check_search_limit(head, 0).map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+    }
+    options.limit = query.head.or(services.token_policies.get(&token).max_limit).unwrap_or(usize::MAX);
+    let search = search_token::Search::parse(&services.host_rules.rewrite_search(&query.search)).map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+    if let Some(degraded) = services.load_shedder.status(&services.tenants) {
+        return Err(search_response::SearchError::Overloaded(format!("exports are off while this node is degraded ({}): try again in a bit", degraded.header()), ingest::retry_after()));
+    }
+    let mut minutes = tenant.0.minute_db.search_stream(search, options);
+
+    let stream = rocket::response::stream::TextStream! {
+        let _slot = slot;
+        // the last row says so, so each one waits for the next before it goes out
+        let mut offset = 0;
+        let mut waiting: Option<minute::Log> = None;
+        while let Some(logs) = minutes.recv().await {
+            let mut chunk = String::new();
+            for log in logs {
+                if let Some(previous) = waiting.replace(log) {
+                    chunk.push_str(&spl::export_line(&previous, offset, false));
+                    offset += 1;
+                }
+            }
+            yield chunk;
+        }
+        if let Some(last) = waiting {
+            yield spl::export_line(&last, offset, true);
+        }
+    };
+    Ok((rocket::http::ContentType::JSON, stream))
+}
+
+///
+/// The sealed minutes from `from` to `to` (seconds since the epoch) as one Parquet file, for DuckDB, Spark and friends
+/// (see parquet_export): `query` keeps only the logs that match, and `columns` (comma separated) gives those fields columns
+/// of their own. It goes out as it's written, so `curl -o logs.parquet` and then `SELECT * FROM 'logs.parquet'`.
+///
+#[get("/export?<from>&<to>&<query>&<columns>")]
+#[allow(clippy::too_many_arguments)]
+async fn export_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, slot: search_limit::SearchSlot, from: i64, to: i64, query: Option<&str>, columns: Option<&str>) -> Result<(rocket::http::ContentType, rocket::response::stream::ByteStream![Vec<u8>]), search_response::SearchError> {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    services.token_policies.get(&token).admit(Some(from), Some(to), None, now).map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+    let search = search_token::Search::parse(&services.host_rules.rewrite_search(query.unwrap_or_default()))
+        .map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+    let columns: Vec<String> = columns.unwrap_or_default().split(',').map(|column| column.trim().to_string()).filter(|column| !column.is_empty()).collect();
+    // reads every log in the range: not something we can cut down to fit
+    if let Some(degraded) = services.load_shedder.status(&services.tenants) {
+        return Err(search_response::SearchError::Overloaded(format!("exports are off while this node is degraded ({}): try again in a bit", degraded.header()), ingest::retry_after()));
+    }
+    let mut chunks = tenant.0.minute_db.export_stream(search, from, to, &columns).map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
 
-    Json(results)
+    let stream = rocket::response::stream::ByteStream! {
+        let _slot = slot;
+        while let Some(chunk) = chunks.recv().await {
+            yield chunk;
+        }
+    };
+    let (top, sub) = parquet_export::CONTENT_TYPE;
+    Ok((rocket::http::ContentType::new(top, sub), stream))
+}
+
+///
+/// Why is this search slow, or why doesn't it find anything? How it parsed, which trigrams the bloom filters get asked about,
+/// and which minutes in the range get past them (see MinuteDB::explain): nothing's actually searched
+///
+#[get("/search/<search>/explain?<params..>")]
+fn explain_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, search: &str, params: SearchParams<'_>) -> Result<Json<minute_db::Explanation>, BadRequest<String>> {
+    let options = params.options(services, &token)?;
+    let search = params.search(services, search)?;
+    Ok(Json(tenant.0.minute_db.explain(&search, &options)))
+}
+
+///
+/// Counts of matching logs per `bucket` ("1m" by default) instead of the logs themselves
+///
+#[get("/search/<search>/histogram?<bucket>&<labels>&<params..>")]
+// every guard and query parameter is an argument, that's just how rocket works
+#[allow(clippy::too_many_arguments)]
+async fn histogram_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, _slot: search_limit::SearchSlot, trace: trace::TraceContext, search: &str, bucket: Option<&str>, labels: Option<bool>, params: SearchParams<'_>) -> Result<search_response::SearchResponse, search_response::SearchError> {
+    let bucket_seconds = match minute_db::parse_bucket(bucket.unwrap_or("1m")){
+        Ok(bucket_seconds) => bucket_seconds,
+        Err(err) => return Err(search_response::SearchError::BadRequest(err.to_string())),
+    };
+
+    if params.format.is_some_and(|format| format != "json") {
+        return Err(search_response::SearchError::BadRequest("only searches come in other formats: histograms and stats are always json".to_string()));
+    }
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let mut options = match services.token_policies.get(&token).admit(params.from, params.to, params.limit, now){
+        Ok(options) => options,
+        Err(err) => return Err(search_response::SearchError::BadRequest(err.to_string())),
+    };
+
+    let search = params.search(services, search)?;
+    let degraded = shed_load(services, &search, &mut options)?;
+
+    let (from, to) = (options.from, options.to);
+    let (mut buckets, mut stats) = match tenant.0.minute_db.histogram_async(search, options, bucket_seconds).await{
+        Ok(results) => results,
+        Err(err) => {
+            tracing::error!("Error building histogram: {:?}", err);
+            (Vec::new(), minute_db::SearchStats::default())
+        }
+    };
+    stats.node = services.config.machine_id;
+    // labels=true puts labeled minutes (deploys and the like) on the histogram
+    if labels.unwrap_or(false) {
+        buckets = tenant.0.minute_labels.overlay(buckets, bucket_seconds, from, to);
+    }
+    // markers (deploys, incidents) always go on, in the bars they happened in
+    buckets = tenant.0.markers.overlay(buckets, bucket_seconds, from, to);
+
+    Ok(search_response::SearchResponse{
+        results: search_response::SearchResults::Histogram(buckets),
+        trace,
+        stats: vec![stats],
+        markers: Vec::new(),
+        degraded,
+    })
+}
+
+///
+/// Counts of matching logs grouped `by` host (the default), level, or any key=value field, keeping the `top` (default 10) values
+///
+#[get("/search/<search>/stats?<by>&<top>&<params..>")]
+// every guard and query parameter is an argument, that's just how rocket works
+#[allow(clippy::too_many_arguments)]
+async fn stats_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, _slot: search_limit::SearchSlot, trace: trace::TraceContext, search: &str, by: Option<&str>, top: Option<usize>, params: SearchParams<'_>) -> Result<search_response::SearchResponse, search_response::SearchError> {
+    let by = minute_db::StatsBy::parse(by.unwrap_or("host"));
+
+    if params.format.is_some_and(|format| format != "json") {
+        return Err(search_response::SearchError::BadRequest("only searches come in other formats: histograms and stats are always json".to_string()));
+    }
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let mut options = match services.token_policies.get(&token).admit(params.from, params.to, params.limit, now){
+        Ok(options) => options,
+        Err(err) => return Err(search_response::SearchError::BadRequest(err.to_string())),
+    };
+
+    let search = params.search(services, search)?;
+    let degraded = shed_load(services, &search, &mut options)?;
+
+    let markers = tenant.0.markers.for_search(options.from, options.to);
+    let (result, mut stats) = match tenant.0.minute_db.stats_async(search, options, by.clone(), top.unwrap_or(10)).await{
+        Ok(results) => results,
+        Err(err) => {
+            tracing::error!("Error computing stats: {:?}", err);
+            (minute_db::StatsResult::empty(&by), minute_db::SearchStats::default())
+        }
+    };
+    stats.node = services.config.machine_id;
+
+    Ok(search_response::SearchResponse{
+        results: search_response::SearchResults::Stats(result),
+        trace,
+        stats: vec![stats],
+        markers,
+        degraded,
+    })
+}
+
+///
+/// For monitoring systems that can poll a URL but can't script: how many logs matched `q` in the last `window` (5m by default),
+/// and whether that's OK, WARN (`warn` or more) or CRIT (`crit` or more). `format=text` is just the Nagios-style line.
+/// The last minute or so isn't sealed yet, so it isn't counted: pick a window that's a few minutes long.
+///
+#[get("/api/v1/check?<q>&<window>&<warn>&<crit>&<format>")]
+// every guard and query parameter is an argument, that's just how rocket works
+#[allow(clippy::too_many_arguments)]
+async fn check_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, _slot: search_limit::SearchSlot, q: &str, window: Option<&str>, warn: Option<u64>, crit: Option<u64>, format: Option<&str>) -> Result<check::CheckResponse, BadRequest<String>> {
+    let window = window.unwrap_or("5m");
+    let window_seconds = minute_db::parse_bucket(window).map_err(|err| BadRequest(err.to_string()))?;
+    let thresholds = check::Thresholds::new(warn, crit).map_err(|err| BadRequest(err.to_string()))?;
+    let text = match format{
+        None | Some("json") => false,
+        Some("text") => true,
+        Some(other) => return Err(BadRequest(format!("format must be 'json' or 'text', not '{}'", other))),
+    };
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let options = match services.token_policies.get(&token).admit(Some(now - window_seconds), Some(now), None, now){
+        Ok(options) => options,
+        Err(err) => return Err(BadRequest(err.to_string())),
+    };
+
+    let search = search_token::Search::parse(&services.host_rules.rewrite_search(q)).map_err(|err| BadRequest(err.to_string()))?;
+    let count = match tenant.0.minute_db.stats_async(search, options, minute_db::StatsBy::Host, 0).await{
+        Ok((result, _stats)) => result.total,
+        Err(err) => return Err(BadRequest(format!("Error counting logs: {}", err))),
+    };
+
+    let result = check::CheckResult::new(q, window, window_seconds, thresholds, count);
+    Ok(match text{
+        true => check::CheckResponse::Text(result.message),
+        false => check::CheckResponse::Json(Json(result)),
+    })
+}
+
+///
+/// About how many different values `field` (host, or any of cardinality_fields) had from `from` to `to`,
+/// e.g. /api/v1/cardinality?field=user_id&from=1710460800: merged out of every minute's sketch, so no logs get read
+///
+#[get("/api/v1/cardinality?<field>&<from>&<to>")]
+#[allow(clippy::too_many_arguments)]
+async fn cardinality_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, _slot: search_limit::SearchSlot, field: &str, from: Option<i64>, to: Option<i64>) -> Result<Json<cardinality::Cardinality>, BadRequest<String>> {
+    if !services.config.cardinality_fields.iter().any(|sketched| sketched == field) {
+        return Err(BadRequest(format!("'{}' isn't sketched: cardinality_fields is [{}]", field, services.config.cardinality_fields.join(", "))));
+    }
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let options = services.token_policies.get(&token).admit(from, to, None, now).map_err(|err| BadRequest(err.to_string()))?;
+    match tenant.0.minute_db.cardinality_async(field.to_string(), options).await{
+        Ok(cardinality) => Ok(Json(cardinality)),
+        Err(err) => Err(BadRequest(format!("Error counting {}: {}", field, err))),
+    }
+}
+
+///
+/// Every host that sent logs from `from` to `to`, busiest first, with how many events each: for filling in a host drop-down.
+/// Counted when each minute was sealed, so no logs get read (except in the minutes that aren't sealed yet).
+///
+#[get("/hosts?<from>&<to>")]
+async fn hosts_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, _slot: search_limit::SearchSlot, from: Option<i64>, to: Option<i64>) -> Result<Json<minute_db::Hosts>, BadRequest<String>> {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let options = services.token_policies.get(&token).admit(from, to, None, now).map_err(|err| BadRequest(err.to_string()))?;
+    match tenant.0.minute_db.hosts_async(options).await{
+        Ok(hosts) => Ok(Json(hosts)),
+        Err(err) => Err(BadRequest(format!("Error counting hosts: {}", err))),
+    }
+}
+
+///
+/// One log, by the event_id a search gave it, e.g. /log/19798-4-21-1-0:1710562887000012: a permalink that points at the same log
+/// whichever shard or node it came from, for as long as its minute's still around
+///
+#[get("/log/<event_id>")]
+async fn log_endpoint(services: &State<Services>, _allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, token: auth::ApiToken, event_id: &str) -> Result<Json<minute::Log>, search_response::SearchError> {
+    let event_id = logmunch::minute_id::EventId::from_string(event_id).map_err(|err| search_response::SearchError::BadRequest(err.to_string()))?;
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    services.token_policies.get(&token).admit_minute(event_id.minute_id.to_timestamp(), now)
+        .map_err(|err| search_response::SearchError::Forbidden(format!("Can't show log {}: {}", event_id, err)))?;
+    match tenant.0.minute_db.get_async(event_id.clone()).await{
+        Ok(Some(log)) => Ok(Json(log)),
+        Ok(None) => Err(search_response::SearchError::NotFound(format!("There's no log {}: it might have been redacted, or aged out", event_id))),
+        Err(err) => Err(search_response::SearchError::BadRequest(format!("Error getting log {}: {}", event_id, err))),
+    }
+}
+
+///
+/// Record a deploy (or an incident, or anything else worth seeing next to the logs), e.g.
+/// curl -d '{"kind": "deploy", "message": "v123", "labels": {"service": "checkout"}}' localhost:8000/api/v1/markers
+/// Leave out "time" (seconds since the epoch) and it happened just now. Anybody who's allowed to ingest can add markers.
+///
+#[post("/api/v1/markers", data="<marker>")]
+fn add_marker_endpoint(_allowed: auth::IngestAllowed, tenant: tenant::CallerTenant, marker: Json<markers::NewMarker>) -> Result<Json<markers::Marker>, BadRequest<String>> {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    match tenant.0.markers.add(marker.into_inner(), now){
+        Ok(marker) => Ok(Json(marker)),
+        Err(err) => Err(BadRequest(err.to_string())),
+    }
+}
+
+///
+/// Markers from `from` to `to` (seconds since the epoch, either end can be left open), oldest first, optionally only one `kind`
+///
+#[get("/api/v1/markers?<from>&<to>&<kind>")]
+fn markers_endpoint(_allowed: auth::SearchAllowed, tenant: tenant::CallerTenant, from: Option<i64>, to: Option<i64>, kind: Option<&str>) -> Json<Vec<markers::Marker>> {
+    Json(tenant.0.markers.list(from, to, kind))
+}
+
+#[delete("/api/v1/markers/<id>")]
+fn remove_marker_endpoint(_allowed: auth::IngestAllowed, tenant: tenant::CallerTenant, id: u64) -> Result<(), rocket::http::Status> {
+    match tenant.0.markers.remove(id){
+        Ok(true) => Ok(()),
+        Ok(false) => Err(rocket::http::Status::NotFound),
+        Err(err) => {
+            tracing::error!("Error removing marker {}: {}", id, err);
+            Err(rocket::http::Status::InternalServerError)
+        },
+    }
+}
+
+///
+/// A small search page (src/ui.html, baked into the binary), so there's something to hand teammates besides curl
+///
+#[get("/")]
+fn ui_endpoint() -> rocket::response::content::RawHtml<&'static str> {
+    rocket::response::content::RawHtml(include_str!("ui.html"))
+}
+
+#[get("/api/v1/handshake")]
+fn handshake_endpoint(services: &State<Services>) -> Json<handshake::Handshake> {
+    Json(handshake::Handshake::new(services.config.machine_id).with_tenants(federation::local_tenants(&services.tenants)))
+}
+
+#[post("/api/v1/handshake", data="<peer>")]
+fn peer_handshake_endpoint(services: &State<Services>, peer: Json<handshake::Handshake>) -> Json<handshake::HandshakeResponse> {
+    let handshake = handshake::Handshake::new(services.config.machine_id).with_tenants(federation::local_tenants(&services.tenants));
+    let compatibility = handshake.compatibility(&peer);
+    if compatibility != handshake::Compatibility::Full {
+        tracing::warn!("Peer {} is at api v{} / minute format v{}: {:?}", peer.machine_id, peer.api_version, peer.minute_format_version, compatibility);
+    }
+    Json(handshake::HandshakeResponse{
+        handshake,
+        compatibility,
+    })
+}
+
+///
+/// Bulk-remove minutes, e.g. after a test flood. from/to are seconds since the epoch; `archive=true` archives them first.
+/// `tenant` picks the tenant (the default tenant, if there isn't one).
+///
+#[delete("/admin/minutes?<from>&<to>&<archive>&<tenant>")]
+async fn delete_minutes_endpoint(services: &State<Services>, _admin: auth::AdminToken, from: i64, to: i64, archive: Option<bool>, tenant: Option<&str>) -> Result<Json<minute_db::DeleteReport>, BadRequest<String>> {
+    if to < from {
+        return Err(BadRequest("to must not be before from".to_string()));
+    }
+    let tenant = match services.tenants.get(tenant){
+        Some(tenant) => tenant,
+        None => return Err(BadRequest(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+    };
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    match tenant.minute_db.delete_range_async(from, to, archive.unwrap_or(false), now).await{
+        Ok(report) => Ok(Json(report)),
+        Err(err) => Err(BadRequest(err.to_string())),
+    }
+}
+
+///
+/// Take every log matching `query` out of the minutes from `from` to `to` (seconds since the epoch), for when a secret
+/// got logged: the minutes are rewritten without them, blooms and all. Minutes that aren't sealed yet are skipped (and counted):
+/// seal them with /admin/seal first. `tenant` picks the tenant (the default tenant, if there isn't one).
+///
+#[delete("/logs?<query>&<from>&<to>&<tenant>")]
+async fn redact_endpoint(services: &State<Services>, _admin: auth::AdminToken, query: &str, from: i64, to: i64, tenant: Option<&str>) -> Result<Json<minute_db::RedactReport>, BadRequest<String>> {
+    if query.trim().is_empty() {
+        return Err(BadRequest("A redaction needs a query: to delete whole minutes, use DELETE /admin/minutes".to_string()));
+    }
+    if to < from {
+        return Err(BadRequest("to must not be before from".to_string()));
+    }
+    let search = search_token::Search::parse(&services.host_rules.rewrite_search(query)).map_err(|err| BadRequest(err.to_string()))?;
+    let found = match services.tenants.get(tenant){
+        Some(found) => found,
+        None => return Err(BadRequest(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+    };
+    match found.minute_db.redact_async(search, from, to).await{
+        Ok(report) => Ok(Json(report)),
+        Err(err) => Err(BadRequest(err.to_string())),
+    }
+}
+
+///
+/// The minute files from `from` to `to` (seconds since the epoch, both optional), newest first: how big they are,
+/// whether they're sealed yet, and whether their blooms are in memory. Up to `limit` of them (1000, if there's no limit).
+/// `tenant` picks the tenant (the default tenant, if there isn't one).
+///
+#[get("/admin/minutes?<from>&<to>&<limit>&<tenant>")]
+fn list_minutes_endpoint(services: &State<Services>, _admin: auth::AdminToken, from: Option<i64>, to: Option<i64>, limit: Option<usize>, tenant: Option<&str>) -> Result<Json<minute_db::MinuteListing>, BadRequest<String>> {
+    let found = match services.tenants.get(tenant){
+        Some(found) => found,
+        None => return Err(BadRequest(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+    };
+    match found.minute_db.list_minutes(from, to, limit.unwrap_or(1000)){
+        Ok(listing) => Ok(Json(listing)),
+        Err(err) => Err(BadRequest(err.to_string())),
+    }
+}
+
+///
+/// What each sealed minute from `from` to `to` (seconds since the epoch, both optional) came to, newest first: how many
+/// events, how many bytes, which hosts, and how big its fragments and bloom are, plus the totals, for capacity planning.
+/// Up to `limit` of them (1000, if there's no limit). `tenant` picks the tenant (the default tenant, if there isn't one).
+///
+#[get("/admin/minute_summaries?<from>&<to>&<limit>&<tenant>")]
+async fn minute_summaries_endpoint(services: &State<Services>, _admin: auth::AdminToken, from: Option<i64>, to: Option<i64>, limit: Option<usize>, tenant: Option<&str>) -> Result<Json<minute_db::SummaryListing>, BadRequest<String>> {
+    let found = match services.tenants.get(tenant){
+        Some(found) => found,
+        None => return Err(BadRequest(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+    };
+    match found.minute_db.minute_summaries_async(from, to, limit.unwrap_or(1000)).await{
+        Ok(listing) => Ok(Json(listing)),
+        Err(err) => Err(BadRequest(err.to_string())),
+    }
+}
+
+///
+/// The caller's tenant's log metrics (see LOG_METRICS), for Prometheus to scrape
+///
+#[get("/metrics")]
+fn metrics_endpoint(_allowed: auth::SearchAllowed, tenant: tenant::CallerTenant) -> (rocket::http::ContentType, String) {
+    (rocket::http::ContentType::new("text", "plain").with_params([("version", "0.0.4"), ("charset", "utf-8")]), tenant.0.log_metrics.render())
+}
+
+///
+/// Every alert (see ALERTS), whether it's firing, and what it found the last time it was checked
+///
+#[get("/admin/alerts")]
+fn alerts_endpoint(services: &State<Services>, _admin: auth::AdminToken) -> Json<Vec<alerts::AlertStatus>> {
+    Json(services.alerts.status())
+}
+
+///
+/// Seal the minutes from `from` to `to` (seconds since the epoch: just `from` seals one minute) now, instead of waiting
+/// out MAX_LATENESS_SECONDS, so they're searchable. Anything else that shows up late for them goes in the current minute.
+/// `tenant` picks the tenant (the default tenant, if there isn't one).
+///
+#[post("/admin/seal?<from>&<to>&<tenant>")]
+async fn seal_minutes_endpoint(services: &State<Services>, _admin: auth::AdminToken, from: i64, to: Option<i64>, tenant: Option<&str>) -> Result<Json<minute::SealReport>, BadRequest<String>> {
+    let to = to.unwrap_or(from);
+    if to < from {
+        return Err(BadRequest("to must not be before from".to_string()));
+    }
+    let found = match services.tenants.get(tenant){
+        Some(found) => found,
+        None => return Err(BadRequest(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+    };
+    match found.sealer.seal_async(from, to).await{
+        Ok(report) => Ok(Json(report)),
+        Err(err) => Err(BadRequest(err.to_string())),
+    }
+}
+
+///
+/// Enforce retention (RETENTION_DAYS and the disk budget) right now, rather than on the read loop's next pass.
+/// `tenant` picks the tenant (the default tenant, if there isn't one).
+///
+#[post("/admin/retention?<tenant>")]
+async fn retention_sweep_endpoint(services: &State<Services>, _admin: auth::AdminToken, tenant: Option<&str>) -> Result<Json<minute_db::RetentionReport>, BadRequest<String>> {
+    let found = match services.tenants.get(tenant){
+        Some(found) => found,
+        None => return Err(BadRequest(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+    };
+    match found.minute_db.sweep_retention_async().await{
+        Ok(report) => Ok(Json(report)),
+        Err(err) => Err(BadRequest(err.to_string())),
+    }
+}
+
+///
+/// Disk used against the tenant's share of MINUTE_DB_DISK_GB, how much is free, how far back the minutes go,
+/// and the blooms' memory (like /admin/bloom_memory). `tenant` picks the tenant (the default tenant, if there isn't one).
+///
+#[get("/admin/budget?<tenant>")]
+fn budget_endpoint(services: &State<Services>, _admin: auth::AdminToken, tenant: Option<&str>) -> Result<Json<minute_db::Budget>, BadRequest<String>> {
+    let found = match services.tenants.get(tenant){
+        Some(found) => found,
+        None => return Err(BadRequest(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+    };
+    match found.minute_db.budget(){
+        Ok(budget) => Ok(Json(budget)),
+        Err(err) => Err(BadRequest(err.to_string())),
+    }
+}
+
+///
+/// Bytes written to disk versus bytes sent to us, since we started and for each recent minute, and what we'd change about it.
+/// `tenant` picks the tenant (the default tenant, if there isn't one).
+///
+#[get("/admin/write_amplification?<tenant>")]
+fn write_amplification_endpoint(services: &State<Services>, _admin: auth::AdminToken, tenant: Option<&str>) -> Result<Json<write_stats::WriteAmplificationReport>, BadRequest<String>> {
+    match services.tenants.get(tenant){
+        Some(found) => Ok(Json(found.write_stats.report(&services.config))),
+        None => Err(BadRequest(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+    }
+}
+
+///
+/// How much memory the sealed minutes' bloom filters are taking up, against MINUTE_DB_RAM_GB's share for the tenant,
+/// and how many were let go to stay under it. `tenant` picks the tenant (the default tenant, if there isn't one).
+///
+#[get("/admin/bloom_memory?<tenant>")]
+fn bloom_memory_endpoint(services: &State<Services>, _admin: auth::AdminToken, tenant: Option<&str>) -> Result<Json<minute_db::BloomMemory>, BadRequest<String>> {
+    match services.tenants.get(tenant){
+        Some(found) => Ok(Json(found.minute_db.bloom_memory())),
+        None => Err(BadRequest(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+    }
+}
+
+///
+/// Label every minute from `from` to `to` (seconds since the epoch: just `from` labels one minute) with the labels in the body,
+/// like {"deploy": "v123"}. `tenant` picks the tenant (the default tenant, if there isn't one).
+///
+#[put("/admin/minute_labels?<from>&<to>&<tenant>", data="<labels>")]
+fn label_minutes_endpoint(services: &State<Services>, _admin: auth::AdminToken, from: i64, to: Option<i64>, tenant: Option<&str>, labels: Json<std::collections::BTreeMap<String, String>>) -> Result<Json<minute_labels::LabelReport>, BadRequest<String>> {
+    let tenant = match services.tenants.get(tenant){
+        Some(tenant) => tenant,
+        None => return Err(BadRequest(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+    };
+    match tenant.minute_labels.label(from, to.unwrap_or(from), &labels){
+        Ok(report) => Ok(Json(report)),
+        Err(err) => Err(BadRequest(err.to_string())),
+    }
+}
+
+///
+/// Labeled minutes between `from` and `to` (both optional), oldest first. `label` ("deploy", or "deploy=v123") only lists the minutes that have it.
+///
+#[get("/admin/minute_labels?<from>&<to>&<label>&<tenant>")]
+fn minute_labels_endpoint(services: &State<Services>, _admin: auth::AdminToken, from: Option<i64>, to: Option<i64>, label: Option<&str>, tenant: Option<&str>) -> Result<Json<Vec<minute_labels::MinuteLabel>>, BadRequest<String>> {
+    let tenant = match services.tenants.get(tenant){
+        Some(tenant) => tenant,
+        None => return Err(BadRequest(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+    };
+    let filter = match label.map(minute_labels::LabelFilter::parse).transpose(){
+        Ok(filter) => filter,
+        Err(err) => return Err(BadRequest(err.to_string())),
+    };
+    Ok(Json(tenant.minute_labels.list(from, to, filter.as_ref())))
+}
+
+///
+/// Take the `key` label (or, without one, every label) off every minute from `from` to `to`
+///
+#[delete("/admin/minute_labels?<from>&<to>&<key>&<tenant>")]
+fn unlabel_minutes_endpoint(services: &State<Services>, _admin: auth::AdminToken, from: i64, to: Option<i64>, key: Option<&str>, tenant: Option<&str>) -> Result<Json<minute_labels::LabelReport>, BadRequest<String>> {
+    let tenant = match services.tenants.get(tenant){
+        Some(tenant) => tenant,
+        None => return Err(BadRequest(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+    };
+    match tenant.minute_labels.unlabel(from, to.unwrap_or(from), key){
+        Ok(report) => Ok(Json(report)),
+        Err(err) => Err(BadRequest(err.to_string())),
+    }
+}
+
+///
+/// Which lookup tables (GeoIP, host maps) are loaded, and which version of each
+///
+#[get("/admin/lookups")]
+fn lookups_endpoint(services: &State<Services>, _admin: auth::AdminToken) -> Json<Vec<lookups::LookupStatus>> {
+    Json(services.lookups.status())
+}
+
+///
+/// Every minute this node has sealed, for a standby to copy (see replication::Standby).
+/// `tenant` picks the tenant (the default tenant, if there isn't one).
+///
+#[get("/admin/replication/manifest?<tenant>")]
+fn replication_manifest_endpoint(services: &State<Services>, _admin: auth::AdminToken, tenant: Option<&str>) -> Result<Json<replication::Manifest>, BadRequest<String>> {
+    match services.tenants.get(tenant){
+        Some(found) => Ok(Json(replication::Manifest::new(services.config.machine_id, &found.minute_db))),
+        None => Err(BadRequest(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+    }
+}
+
+///
+/// One sealed minute's file, as it is on disk. Minutes that aren't sealed yet aren't on offer: they're still changing.
+///
+#[get("/admin/replication/minute?<day>&<hour>&<minute>&<unique_id>&<tenant>")]
+async fn replication_minute_endpoint(services: &State<Services>, _admin: auth::AdminToken, day: u32, hour: u32, minute: u32, unique_id: &str, tenant: Option<&str>) -> Result<rocket::fs::NamedFile, rocket::response::status::NotFound<String>> {
+    let minute_id = logmunch::minute_id::MinuteId::new(day, hour, minute, unique_id);
+    let path = match services.tenants.get(tenant){
+        Some(found) => found.minute_db.sealed_path(&minute_id),
+        None => return Err(rocket::response::status::NotFound(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+    };
+    let Some(path) = path else {
+        return Err(rocket::response::status::NotFound(format!("There's no sealed minute {}", minute_id)));
+    };
+    rocket::fs::NamedFile::open(path).await.map_err(|e| rocket::response::status::NotFound(e.to_string()))
+}
+
+///
+/// On a standby: how far behind the primary each tenant is
+///
+#[get("/admin/replication")]
+fn replication_status_endpoint(services: &State<Services>, _admin: auth::AdminToken) -> Result<Json<Vec<replication::ReplicationStatus>>, BadRequest<String>> {
+    match &services.standby{
+        Some(standby) => Ok(Json(standby.status())),
+        None => Err(BadRequest("This node isn't a standby (see standby_of)".to_string())),
+    }
+}
+
+///
+/// On a standby: stop copying from the primary, and start taking ingest. For when the primary's gone.
+///
+#[post("/admin/promote")]
+fn promote_endpoint(services: &State<Services>, _admin: auth::AdminToken) -> Result<Json<Vec<replication::ReplicationStatus>>, BadRequest<String>> {
+    match &services.standby{
+        Some(standby) => Ok(Json(standby.promote())),
+        None => Err(BadRequest("This node isn't a standby (see standby_of), so there's nothing to promote".to_string())),
+    }
+}
+
+///
+/// With federation_peers: what we know about each peer, and which tenants' searches go to it
+///
+#[get("/admin/federation")]
+fn federation_status_endpoint(services: &State<Services>, _admin: auth::AdminToken) -> Result<Json<Vec<federation::PeerStatus>>, BadRequest<String>> {
+    match &services.federation{
+        Some(federation) => Ok(Json(federation.status())),
+        None => Err(BadRequest("This node isn't federated (see federation_peers)".to_string())),
+    }
+}
+
+///
+/// Sample what the whole server is doing for `seconds` (default 10, at most profile::MAX_PROFILE_SECONDS), `frequency` times a second (default 99),
+/// while you run the slow search somewhere else. `format` is flamegraph (an SVG, the default), folded (stacks, for inferno or speedscope),
+/// or summary (JSON: how much went on bloom tests, SQLite, and the matcher). Only one profile at a time.
+///
+#[get("/admin/profile?<seconds>&<frequency>&<format>")]
+async fn profile_endpoint(_admin: auth::AdminToken, seconds: Option<u64>, frequency: Option<i32>, format: Option<&str>) -> Result<(rocket::http::ContentType, Vec<u8>), BadRequest<String>> {
+    let format = profile::ProfileFormat::parse(format).map_err(|err| BadRequest(err.to_string()))?;
+    let seconds = seconds.unwrap_or(10);
+    let frequency = frequency.unwrap_or(profile::DEFAULT_PROFILE_FREQUENCY);
+    let profile = tokio::task::spawn_blocking(move || profile::capture(seconds, frequency)).await
+        .map_err(|err| BadRequest(err.to_string()))?
+        .map_err(|err| BadRequest(err.to_string()))?;
+    match format{
+        profile::ProfileFormat::Flamegraph => match profile.flamegraph(){
+            Ok(svg) => Ok((rocket::http::ContentType::SVG, svg)),
+            Err(err) => Err(BadRequest(err.to_string())),
+        },
+        profile::ProfileFormat::Folded => Ok((rocket::http::ContentType::Plain, profile.folded().into_bytes())),
+        profile::ProfileFormat::Summary => match serde_json::to_vec(&profile.summary()){
+            Ok(json) => Ok((rocket::http::ContentType::JSON, json)),
+            Err(err) => Err(BadRequest(err.to_string())),
+        },
+    }
+}
+
+///
+/// Failure injection (only with `--features chaos`): what's armed
+///
+#[cfg(feature = "chaos")]
+#[get("/admin/chaos")]
+fn chaos_endpoint(_admin: auth::AdminToken) -> Json<Vec<logmunch::chaos::Fault>> {
+    Json(logmunch::chaos::armed())
+}
+
+///
+/// Arm a fault (see chaos::FaultKind). With a `tenant` and no `scope` of its own, it only goes off for that tenant's minutes.
+///
+#[cfg(feature = "chaos")]
+#[post("/admin/chaos?<tenant>", data = "<fault>")]
+fn arm_chaos_endpoint(services: &State<Services>, _admin: auth::AdminToken, tenant: Option<&str>, fault: Json<logmunch::chaos::Fault>) -> Result<Json<Vec<logmunch::chaos::Fault>>, BadRequest<String>> {
+    let mut fault = fault.into_inner();
+    if tenant.is_some() && fault.scope.is_none() {
+        match services.tenants.get(tenant){
+            Some(found) => fault.scope = Some(found.minutes_directory.clone()),
+            None => return Err(BadRequest(format!("There's no tenant called '{}'", tenant.unwrap_or_default()))),
+        }
+    }
+    logmunch::chaos::arm(fault);
+    Ok(Json(logmunch::chaos::armed()))
+}
+
+///
+/// Disarm every fault (or, with a `scope`, just the ones armed with that scope)
+///
+#[cfg(feature = "chaos")]
+#[delete("/admin/chaos?<scope>")]
+fn clear_chaos_endpoint(_admin: auth::AdminToken, scope: Option<&str>) -> Json<usize> {
+    Json(logmunch::chaos::clear(scope))
 }
 
 #[derive(Clone)]
-pub struct Services{
-    sender: Arc<Sender<WritableEvent>>,
-    receiver: Arc<Receiver<WritableEvent>>,
-    minute_db: Arc<minute_db::MinuteDB>,
+struct Services{
+    tenants: Arc<tenant::Tenants>,
+    config: Arc<config::Config>,
+    token_policies: Arc<token_policy::TokenPolicies>,
+    host_rules: Arc<host_rules::HostRules>,
+    lookups: Arc<lookups::Lookups>,
+    load_shedder: Arc<load_shedding::LoadShedder>,
+    standby: Option<Arc<replication::Standby>>,
+    federation: Option<Arc<federation::Federation>>,
+    alerts: Arc<alerts::Alerts>,
+}
+
+///
+/// Start a tenant's engine (see engine::Engine) and, if HEC acks are on, somewhere to keep its acks
+///
+async fn start_tenant(name: Option<String>, settings: &EngineSettings) -> Arc<tenant::Tenant> {
+    let engine_settings = settings.clone();
+    let engine_name = name.clone();
+    let engine = match tokio::task::spawn_blocking(move || Engine::start(engine_name.as_deref(), &engine_settings)).await.unwrap(){
+        Ok(engine) => engine,
+        Err(e) => {
+            tracing::error!("Can't start tenant {}: {}", name.unwrap_or("default".to_string()), e);
+            std::process::exit(1);
+        }
+    };
+
+    // hec_acks turns on HEC indexer acknowledgement (and fsyncs every commit, so an ack means the data is on disk)
+    let hec_acks = hec::HecAcks::from_config(&settings.config, engine.queue()).map(Arc::new);
+    // HEC_DEDUP_SECONDS (optional): each tenant remembers what it's queued for that long, and drops forwarders' retries of it
+    let hec_dedup = hec::HecDedup::from_config(&settings.config).map(Arc::new);
+
+    Arc::new(tenant::Tenant{
+        name,
+        queue: engine.queue(),
+        hec_acks,
+        hec_dedup,
+        minute_db: engine.minute_db(),
+        minutes_directory: engine.minutes_directory().to_string(),
+        write_stats: engine.write_stats(),
+        sealer: engine.sealer(),
+        minute_labels: engine.minute_labels(),
+        markers: engine.markers(),
+        log_metrics: engine.log_metrics(),
+    })
 }
 
-const ESTIMATED_MINUTE_BLOOM_SIZE_BYTES: u64 = 650000;
+///
+/// `logmunch` starts the server: `logmunch <command>` runs one of cli::COMMANDS against a data directory and exits
+///
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if cli::is_command(args.first()) {
+        logging::init_cli();
+        std::process::exit(cli::run(&args));
+    }
+    if let Err(e) = rocket::execute(serve()) {
+        tracing::error!("Server stopped: {}", e);
+        std::process::exit(1);
+    }
+}
 
-#[launch]
-async fn rocket() -> _ {
+///
+/// Boot, then keep Rocket up. Rocket only reads its TLS cert when it launches, so a renewed cert (see tls::Tls)
+/// means launching it again: the tenants, queues and threads are the same ones, only the listener starts over.
+///
+async fn serve() -> Result<(), rocket::Error> {
+    let server = boot().await;
+    loop {
+        let changes = server.tls.as_ref().map(|tls| tls.subscribe());
+        let app = server.rocket().ignite().await?;
+        let relaunching = Arc::new(AtomicBool::new(false));
+        if let Some(mut changes) = changes {
+            let (shutdown, relaunching) = (app.shutdown(), relaunching.clone());
+            tokio::spawn(async move {
+                if changes.changed().await.is_ok() {
+                    relaunching.store(true, Ordering::SeqCst);
+                    shutdown.notify();
+                }
+            });
+        }
+        app.launch().await?;
+        if !relaunching.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+    }
+}
 
-    let (sender, receiver) = unbounded::<WritableEvent>();
+///
+/// Everything that outlives a launch of Rocket
+///
+struct Server{
+    services: Services,
+    config: Arc<config::Config>,
+    ingest_policy: Arc<auth::IngestPolicy>,
+    api_keys: Arc<auth::ApiKeys>,
+    rate_limiter: Arc<rate_limit::IngestRateLimiter>,
+    search_limiter: Arc<search_limit::SearchLimiter>,
+    tenants: Arc<tenant::Tenants>,
+    standby: Option<Arc<replication::Standby>>,
+    audit_log: Option<Arc<audit::AuditLog>>,
+    tls: Option<Arc<tls::Tls>>,
+}
 
-    // TODO: these things should be configurable env vars
-    // mathin' it out: 1 day (1440 minutes) should occupy about 270MB of RAM, and .... 144GB of disk
-    //  this is based on the assumption that each minute occupies 1.5MB of RAM and 100MB of disk
-    //  and that our ShardedMinuteWriter isn't writing more than one Minute object per minute
-    //      (which it starts to do past 3000 lines/s or 180000 lines/m)
-    let minute_db_gigabytes_string = std::env::var("MINUTE_DB_RAM_GB").unwrap_or("1.8".to_string());
-    let minute_db_disk_gigabytes_string = std::env::var("MINUTE_DB_DISK_GB").unwrap_or("30".to_string());
-    let minute_db_bytes = (minute_db_gigabytes_string.parse::<f64>().unwrap() * 1000.0 * 1000.0 * 1000.0) as u64;
-    let minute_db_disk_bytes = (minute_db_disk_gigabytes_string.parse::<f64>().unwrap() * 1000.0 * 1000.0 * 1000.0 * 0.9) as u64;
+async fn boot() -> Server {
 
-    let machine_id = std::env::var("MACHINE_ID").unwrap_or("1".to_string()).parse::<u32>().unwrap();
+    // logmunch.toml (or LOGMUNCH_CONFIG), with env vars on top: budgets, machine id, data directory, threads... (see config::Config)
+    // tenants (optional) is a JSON file of which tokens belong to which tenant: each tenant gets its own data directory
+    let (config, tenancy) = match config::Config::load().and_then(|config| {
+        let tenancy = tenant::Tenancy::from_config(&config)?;
+        config.validate(1 + tenancy.tenants().count() as u64)?;
+        Ok((config, tenancy))
+    }){
+        Ok((config, tenancy)) => (Arc::new(config), tenancy),
+        Err(e) => {
+            eprintln!("Can't start: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let n_tenants = 1 + tenancy.tenants().count() as u64;
+    if let Err(e) = logging::init(&config) {
+        eprintln!("Can't start: {}", e);
+        std::process::exit(1);
+    }
+
+    // tenants split the RAM and disk evenly
+    let mut settings = EngineSettings::new(config.clone(), n_tenants);
+    tracing::info!("Booting {} tenant(s) with {} bytes of bloom filters in memory each: searches that reach back further than that read blooms from disk", n_tenants, settings.minute_db_ram_bytes);
+
+    // token_policies is a JSON file of per-token search defaults and ceilings
+    let token_policies = token_policy::TokenPolicies::from_config(&config).unwrap();
+
+    // api_keys (optional) is a JSON file of keys and what they're for (ingest, search, admin): see auth::ApiKeys
+    let api_keys = Arc::new(auth::ApiKeys::from_config(&config).unwrap());
+
+    // ingest_tokens (optional) locks down ingest, except from ingest_trusted_cidrs (localhost, unless you say otherwise)
+    let ingest_policy = Arc::new(auth::IngestPolicy::from_config(&config).unwrap().with_api_keys(&api_keys));
 
-    // DATA_DIRECTORY is where we store the minute files
-    let data_directory = std::env::var("DATA_DIRECTORY").unwrap_or("./data/".to_string());
-    let minute_data_directory = format!("{}/minutes", data_directory);
-    // TODO: make sure the directory exists
-    let minute_db_n_minutes = minute_db_bytes / ESTIMATED_MINUTE_BLOOM_SIZE_BYTES;
+    // INGEST_RATE_EVENTS_PER_SECOND and INGEST_RATE_BYTES_PER_SECOND (optional) hold each sender (INGEST_RATE_KEY) to a rate
+    let rate_limiter = Arc::new(rate_limit::IngestRateLimiter::from_config(&config));
+    let search_limiter = Arc::new(search_limit::SearchLimiter::from_config(&config));
 
-    let max_write_threads = std::env::var("MAX_WRITE_THREADS").unwrap_or("8".to_string()).parse::<u32>().unwrap();
+    // host_rules (optional) is a JSON file of rules that clean up hostnames, at ingest and in searches
+    let host_rules = Arc::new(host_rules::HostRules::from_config(&config).unwrap());
 
-    if minute_db_n_minutes < 5 {
-        panic!("Not enough memory or disk space to run this program!");
+    // ingest_script (optional) is a Rhai script (or a directory of them, one per tenant) that can change or drop events before they're written
+    let ingest_scripts = Arc::new(ingest_script::IngestScripts::from_config(&config).unwrap());
+
+    // audit_syslog or audit_webhook (optional): where the audit trail of searches and admin actions goes, as json or cef (audit_format)
+    let audit_log = audit::AuditLog::from_config(&config).unwrap().map(Arc::new);
+
+    // alerts (optional) is a JSON file of saved searches to run every few minutes, and the webhooks to call when they cross a threshold
+    let alerts = Arc::new(alerts::Alerts::from_config(&config, host_rules.clone()).unwrap());
+
+    // geoip_database and host_map (optional) are lookup tables that add fields to search results: they reload themselves when they change
+    let lookups = Arc::new(lookups::Lookups::from_config(&config).unwrap());
+
+    settings.host_rules = host_rules.clone();
+    settings.ingest_scripts = ingest_scripts;
+    // log_metrics (optional) is a JSON file of rules that turn logs into Prometheus counters and histograms as they're written (GET /metrics)
+    settings.log_metric_rules = Arc::new(log_metrics::MetricRules::from_config(&config).unwrap());
+    settings.durable = config.hec_acks;
+    let default_tenant = start_tenant(None, &settings).await;
+    let mut named_tenants = Vec::new();
+    for name in tenancy.tenants() {
+        named_tenants.push(start_tenant(Some(name.clone()), &settings).await);
+    }
+    let tenants = Arc::new(tenant::Tenants::new(tenancy, default_tenant, named_tenants));
+    if let Err(e) = alerts.check_tenants(&tenants) {
+        tracing::error!("Can't start: {}", e);
+        std::process::exit(1);
     }
-    println!("Booting with {} minutes in memory: increase minute cache length by increasing RAM", minute_db_n_minutes);
+
+    // TLS_CERT_PATH and TLS_KEY_PATH (optional) turn on HTTPS, and TLS for OTLP/gRPC: the files are watched for renewals
+    let tls = match tls::Tls::from_config(&config){
+        Ok(tls) => tls.map(Arc::new),
+        Err(e) => {
+            tracing::error!("Can't start: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // STANDBY_OF (optional) makes this a warm standby of another node: it copies that node's sealed minutes, and takes no ingest until it's promoted
+    let standby = replication::Standby::new(&config, &tenants).map(Arc::new);
+
+    // FEDERATION_PEERS (optional) are other nodes to search along with this one: each search goes to the ones with the caller's tenant
+    let federation = federation::Federation::new(&config, tenants.header()).map(Arc::new);
 
     let services = Services{
-        sender: Arc::new(sender),
-        receiver: Arc::new(receiver),
-        minute_db: Arc::new(minute_db::MinuteDB::new(minute_data_directory.to_string(), minute_db_n_minutes, minute_db_disk_bytes)),
+        tenants: tenants.clone(),
+        config: config.clone(),
+        token_policies: Arc::new(token_policies),
+        host_rules,
+        lookups: lookups.clone(),
+        load_shedder: Arc::new(load_shedding::LoadShedder::new(&config)),
+        standby: standby.clone(),
+        federation: federation.clone(),
+        alerts: alerts.clone(),
     };
 
-    let mut app = rocket::build();
-    app = app.manage(services.clone());
-    app = app.mount("/", routes![ingest_options_endpoint, ingest_endpoint, search_endpoint]);
+    if let Some(standby) = &standby {
+        let standby = standby.clone();
+        std::thread::Builder::new().name("standby".to_string()).spawn(move || {
+            standby.replicate_loop();
+        }).unwrap();
+    }
+    if let Some(federation) = federation {
+        std::thread::Builder::new().name("federation".to_string()).spawn(move || {
+            federation.refresh_loop();
+        }).unwrap();
+    }
+    if let Some(audit_log) = &audit_log {
+        let audit_log = audit_log.clone();
+        std::thread::Builder::new().name("audit".to_string()).spawn(move || {
+            audit_log.export_loop();
+        }).unwrap();
+    }
+    if !alerts.is_empty() {
+        let (alerts, tenants, standby) = (alerts.clone(), tenants.clone(), standby.clone());
+        std::thread::Builder::new().name("alerts".to_string()).spawn(move || {
+            alerts.run_loop(&tenants, standby.as_deref());
+        }).unwrap();
+    }
+    if let Some(tls) = &tls {
+        tracing::info!("Serving HTTPS with the cert in {}", config.tls_cert_path.as_deref().unwrap_or_default());
+        let tls = tls.clone();
+        std::thread::Builder::new().name("tls".to_string()).spawn(move || {
+            tls.reload_loop();
+        }).unwrap();
+    }
 
-    tokio::task::spawn_blocking(move || {
-        // this is the write thread and it's just gonna spin forever
-        let mut minute_writer = minute::ShardedMinute::new(machine_id, minute_data_directory.to_string(), max_write_threads);
+    if !lookups.is_empty() {
+        tokio::task::spawn_blocking(move || {
+            lookups.reload_loop();
+        });
+    }
 
-        minute_writer.write_loop(services.receiver.clone());
-    });
+    if let Some(port) = config.otlp_grpc_port {
+        let (tenants, ingest_policy, rate_limiter, standby, tls) = (tenants.clone(), ingest_policy.clone(), rate_limiter.clone(), standby.clone(), tls.clone());
+        tokio::spawn(async move {
+            if let Err(e) = otlp::serve_grpc(port, tenants, ingest_policy, rate_limiter, standby, tls).await {
+                tracing::error!("OTLP/gRPC receiver stopped: {}", e);
+            }
+        });
+    }
 
-    tokio::task::spawn_blocking(move || {
-        let minute_reader = services.minute_db.clone();
+    if let Some(port) = config.fluent_forward_port {
+        let receiver = Arc::new(fluent::ForwardReceiver{
+            tenants: tenants.clone(),
+            ingest_policy: ingest_policy.clone(),
+            rate_limiter: rate_limiter.clone(),
+            standby: standby.clone(),
+            // checked by Config::validate
+            tag_mode: fluent::TagMode::parse(&config.fluent_forward_tag).unwrap_or(fluent::TagMode::Field),
+        });
+        std::thread::Builder::new().name("fluent-forward".to_string()).spawn(move || {
+            if let Err(e) = receiver.serve(port) {
+                tracing::error!("Fluent forward receiver stopped: {}", e);
+            }
+        }).unwrap();
+    }
+
+    if let Some(port) = config.gelf_port {
+        let receiver = Arc::new(gelf::GelfReceiver{
+            tenants: tenants.clone(),
+            ingest_policy: ingest_policy.clone(),
+            rate_limiter: rate_limiter.clone(),
+            standby: standby.clone(),
+        });
+        let udp_receiver = receiver.clone();
+        std::thread::Builder::new().name("gelf-udp".to_string()).spawn(move || {
+            if let Err(e) = udp_receiver.serve_udp(port) {
+                tracing::error!("GELF UDP receiver stopped: {}", e);
+            }
+        }).unwrap();
+        std::thread::Builder::new().name("gelf-tcp".to_string()).spawn(move || {
+            if let Err(e) = receiver.serve_tcp(port) {
+                tracing::error!("GELF TCP receiver stopped: {}", e);
+            }
+        }).unwrap();
+    }
+
+    Server{ services, config, ingest_policy, api_keys, rate_limiter, search_limiter, tenants, standby, audit_log, tls }
+}
+
+impl Server{
+    fn rocket(&self) -> rocket::Rocket<rocket::Build> {
+        let figment = match &self.tls{
+            Some(tls) => tls.rocket_figment(rocket::Config::figment()),
+            None => rocket::Config::figment(),
+        };
+        let mut app = rocket::custom(figment);
+        app = app.manage(self.services.clone());
+        // the request guards (auth::AdminToken, auth::IngestAllowed, auth::SearchAllowed, tenant::CallerTenant, search_limit::SearchSlot) look these up themselves
+        app = app.manage(self.config.clone());
+        app = app.manage(self.ingest_policy.clone());
+        app = app.manage(self.api_keys.clone());
+        app = app.manage(self.rate_limiter.clone());
+        app = app.manage(self.search_limiter.clone());
+        app = app.manage(self.tenants.clone());
+        if let Some(standby) = &self.standby {
+            app = app.manage(standby.clone());
+        }
+        app = app.attach(handshake::version_header());
+        if let Some(audit_log) = &self.audit_log {
+            app = app.attach(audit::fairing(audit_log.clone()));
+        }
+        app = app.register("/", catchers![search_limit::too_many_searches]);
+        app = app.register("/services/collector", catchers![hec::unauthorized, hec::forbidden, hec::internal_error, hec::unavailable]);
+        app = app.mount("/", routes![ui_endpoint, ingest_options_endpoint, ingest_endpoint, hec_ack_endpoint, otlp_logs_endpoint, loki_push_endpoint, text_ingest_endpoint, import_endpoint, loki_query_range_endpoint, loki_labels_endpoint, loki_label_values_endpoint, grafana_endpoint, grafana_search_endpoint, grafana_query_endpoint, grafana_annotations_endpoint, search_endpoint, search_stream_endpoint, search_export_endpoint, splunk_export_endpoint, splunk_export_form_endpoint, splunk_export_ns_endpoint, export_endpoint, explain_endpoint, histogram_endpoint, stats_endpoint, check_endpoint, cardinality_endpoint, hosts_endpoint, log_endpoint, metrics_endpoint, add_marker_endpoint, markers_endpoint, remove_marker_endpoint, handshake_endpoint, peer_handshake_endpoint, delete_minutes_endpoint, redact_endpoint, list_minutes_endpoint, minute_summaries_endpoint, alerts_endpoint, seal_minutes_endpoint, retention_sweep_endpoint, budget_endpoint, write_amplification_endpoint, bloom_memory_endpoint, label_minutes_endpoint, minute_labels_endpoint, unlabel_minutes_endpoint, lookups_endpoint, replication_manifest_endpoint, replication_minute_endpoint, replication_status_endpoint, promote_endpoint, federation_status_endpoint, profile_endpoint]);
+        #[cfg(feature = "chaos")]
+        {
+            tracing::warn!("Chaos is compiled in: faults can be armed at /admin/chaos");
+            app = app.mount("/", routes![chaos_endpoint, arm_chaos_endpoint, clear_chaos_endpoint]);
+        }
+        app
+    }
+}
 
-        minute_reader.read_loop();
-    });
+#[test]
+fn test_parse_hec_event() {
+    let event = parse_hec_event(r#"{"event": "hello", "time": "1710562887.5", "host": "h"}"#, 0).unwrap();
+    assert_eq!(event, WritableEvent{ event: "hello".to_string(), time: 1710562887500000, host: "h".to_string() });
 
-    app
+    assert_eq!(parse_hec_event(r#"{"time": "1", "host": "h"}"#, 2).unwrap_err(), hec::HecResponse::event_required(2));
+    assert_eq!(parse_hec_event(r#"{"event": "", "time": "1", "host": "h"}"#, 3).unwrap_err(), hec::HecResponse::event_blank(3));
+    assert_eq!(parse_hec_event(r#"{"event": "hi", "time": "yesterday", "host": "h"}"#, 4).unwrap_err(), hec::HecResponse::invalid_data_format(4));
+    assert_eq!(parse_hec_event(r#"{"event": "hi", "#, 5).unwrap_err(), hec::HecResponse::invalid_data_format(5));
 }
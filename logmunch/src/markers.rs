@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::RwLock;
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+use crate::minute_db::HistogramBucket;
+
+const MAX_KIND_LENGTH: usize = 64;
+const MAX_MESSAGE_LENGTH: usize = 1024;
+const MAX_LABELS: usize = 32;
+const MAX_KEY_LENGTH: usize = 64;
+const MAX_VALUE_LENGTH: usize = 256;
+
+///
+/// The most markers a search response carries (in a header, so it can't be too big): the most recent ones win
+///
+pub const MAX_MARKERS_PER_RESPONSE: usize = 100;
+
+///
+/// Something that happened at one moment, that might explain what the logs did next: a deploy, an incident, a config change.
+/// `time` is in seconds since the epoch.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Marker{
+    pub id: u64,
+    pub time: i64,
+    /// "deploy", "incident", whatever you like
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+///
+/// What `POST /api/v1/markers` takes: a marker without an id, and without a time if it's happening right now
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NewMarker{
+    pub time: Option<i64>,
+    pub kind: String,
+    pub message: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl NewMarker{
+    fn validate(&self) -> Result<()> {
+        if self.kind.is_empty() || self.kind.len() > MAX_KIND_LENGTH {
+            return Err(anyhow::anyhow!("kind has to be 1-{} characters", MAX_KIND_LENGTH));
+        }
+        if self.message.as_ref().is_some_and(|message| message.len() > MAX_MESSAGE_LENGTH) {
+            return Err(anyhow::anyhow!("the message is too long: messages are at most {} characters", MAX_MESSAGE_LENGTH));
+        }
+        if self.labels.len() > MAX_LABELS {
+            return Err(anyhow::anyhow!("that's too many labels: a marker gets at most {}", MAX_LABELS));
+        }
+        for (key, value) in &self.labels {
+            if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+                return Err(anyhow::anyhow!("'{}' can't be a label: keys are 1-{} characters", key, MAX_KEY_LENGTH));
+            }
+            if value.len() > MAX_VALUE_LENGTH {
+                return Err(anyhow::anyhow!("the value of {} is too long: values are at most {} characters", key, MAX_VALUE_LENGTH));
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// Deploys and incidents and the like, to put on top of histograms and search results, so that
+/// "the errors started at 14:02" can be followed by "and v123 went out at 14:01".
+/// Like minute labels, they're kept in metadata (markers.json), not in the minutes.
+///
+pub struct Markers{
+    /// None keeps the markers in memory only
+    path: Option<String>,
+    /// by (time, id), so ranges come out oldest first
+    markers: RwLock<BTreeMap<(i64, u64), Marker>>,
+}
+
+impl Markers{
+    pub fn new() -> Markers {
+        Markers{
+            path: None,
+            markers: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn open(path: &str) -> Result<Markers> {
+        let markers = match fs::read_to_string(path){
+            Ok(contents) => {
+                let markers: Vec<Marker> = serde_json::from_str(&contents).map_err(|e| anyhow::anyhow!("Could not parse markers in {}: {}", path, e))?;
+                markers.into_iter().map(|marker| ((marker.time, marker.id), marker)).collect()
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(anyhow::anyhow!("Could not read markers from {}: {}", path, e)),
+        };
+        Ok(Markers{
+            path: Some(path.to_string()),
+            markers: RwLock::new(markers),
+        })
+    }
+
+    fn save(&self, markers: &BTreeMap<(i64, u64), Marker>) -> Result<()> {
+        if let Some(path) = &self.path {
+            let markers: Vec<&Marker> = markers.values().collect();
+            // write it next door and move it over, so a crash halfway through doesn't cost us every marker
+            let temporary = format!("{}.tmp", path);
+            fs::write(&temporary, serde_json::to_string(&markers)?)?;
+            fs::rename(&temporary, path)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Record a marker (at `now`, if it doesn't say when) and hand it back with its id
+    ///
+    pub fn add(&self, marker: NewMarker, now: i64) -> Result<Marker> {
+        marker.validate()?;
+        let mut markers = self.markers.write().unwrap();
+        let id = markers.values().map(|marker| marker.id).max().map(|id| id + 1).unwrap_or(1);
+        let marker = Marker{
+            id,
+            time: marker.time.unwrap_or(now),
+            kind: marker.kind,
+            message: marker.message,
+            labels: marker.labels,
+        };
+        markers.insert((marker.time, marker.id), marker.clone());
+        self.save(&markers)?;
+        Ok(marker)
+    }
+
+    ///
+    /// Forget a marker; false if there wasn't one with that id
+    ///
+    pub fn remove(&self, id: u64) -> Result<bool> {
+        let mut markers = self.markers.write().unwrap();
+        let key = markers.iter().find(|(_, marker)| marker.id == id).map(|(key, _)| *key);
+        match key{
+            Some(key) => {
+                markers.remove(&key);
+                self.save(&markers)?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    ///
+    /// Markers between `from` and `to` (inclusive, either end can be left open), oldest first, optionally only one `kind`
+    ///
+    pub fn list(&self, from: Option<i64>, to: Option<i64>, kind: Option<&str>) -> Vec<Marker> {
+        let from = from.unwrap_or(i64::MIN);
+        let to = to.unwrap_or(i64::MAX);
+        if to < from {
+            return Vec::new();
+        }
+        self.markers.read().unwrap().range((from, 0)..=(to, u64::MAX))
+            .map(|(_, marker)| marker)
+            .filter(|marker| kind.is_none_or(|kind| marker.kind == kind))
+            .cloned()
+            .collect()
+    }
+
+    ///
+    /// What goes along with a page of search results: the last MAX_MARKERS_PER_RESPONSE markers in the range
+    ///
+    pub fn for_search(&self, from: Option<i64>, to: Option<i64>) -> Vec<Marker> {
+        let mut markers = self.list(from, to, None);
+        let extra = markers.len().saturating_sub(MAX_MARKERS_PER_RESPONSE);
+        markers.drain(..extra);
+        markers
+    }
+
+    ///
+    /// Put every marker between `from` and `to` on the histogram, in the bucket it falls in
+    /// (giving it an empty bucket if nothing matched there, same as MinuteLabels::overlay)
+    ///
+    pub fn overlay(&self, buckets: Vec<HistogramBucket>, bucket_seconds: i64, from: Option<i64>, to: Option<i64>) -> Vec<HistogramBucket> {
+        let mut buckets: BTreeMap<i64, HistogramBucket> = buckets.into_iter().map(|bucket| (bucket.time, bucket)).collect();
+        for marker in self.list(from, to, None) {
+            let time = marker.time - marker.time.rem_euclid(bucket_seconds);
+            buckets.entry(time).or_insert_with(|| HistogramBucket{ time, count: 0, labels: Vec::new(), markers: Vec::new() }).markers.push(marker);
+        }
+        buckets.into_values().collect()
+    }
+}
+
+impl Default for Markers{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_markers() -> Result<()> {
+    let directory = crate::minute::test_data_directory("markers");
+    fs::create_dir_all(&directory)?;
+    let path = format!("{}/markers.json", directory);
+
+    let new_marker = |time: Option<i64>, kind: &str| NewMarker{ time, kind: kind.to_string(), message: None, labels: BTreeMap::new() };
+    let markers = Markers::open(&path)?;
+    let deploy = markers.add(NewMarker{
+        message: Some("v123 went out".to_string()),
+        labels: [("version".to_string(), "v123".to_string())].into_iter().collect(),
+        ..new_marker(Some(125), "deploy")
+    }, 1000)?;
+    assert_eq!(deploy.id, 1);
+    assert_eq!(deploy.time, 125);
+    // no time means right now
+    assert_eq!(markers.add(new_marker(None, "incident"), 1000)?.time, 1000);
+    assert_eq!(markers.add(new_marker(Some(60), "deploy"), 1000)?.id, 3);
+    assert!(markers.add(new_marker(Some(60), ""), 1000).is_err());
+    assert!(markers.add(NewMarker{ message: Some("x".repeat(MAX_MESSAGE_LENGTH + 1)), ..new_marker(None, "deploy") }, 1000).is_err());
+
+    // it's all still there the next time we boot
+    let markers = Markers::open(&path)?;
+    assert_eq!(markers.list(None, None, None).iter().map(|marker| marker.id).collect::<Vec<u64>>(), vec![3, 1, 2]);
+    assert_eq!(markers.list(Some(100), Some(999), None), vec![deploy.clone()]);
+    assert_eq!(markers.list(None, None, Some("deploy")).len(), 2);
+    assert!(markers.list(Some(10), Some(0), None).is_empty());
+
+    let buckets = vec![HistogramBucket{ time: 0, count: 10, labels: Vec::new(), markers: Vec::new() }];
+    let overlaid = markers.overlay(buckets, 120, None, Some(999));
+    assert_eq!(overlaid.iter().map(|bucket| (bucket.time, bucket.count, bucket.markers.len())).collect::<Vec<(i64, u64, usize)>>(),
+        vec![(0, 10, 1), (120, 0, 1)]);
+
+    assert!(markers.remove(1)?);
+    assert!(!markers.remove(1)?);
+    assert_eq!(Markers::open(&path)?.list(None, None, None).len(), 2);
+
+    fs::remove_dir_all(&directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_markers_for_search() -> Result<()> {
+    let markers = Markers::new();
+    for time in 0..(MAX_MARKERS_PER_RESPONSE as i64 + 10) {
+        markers.add(NewMarker{ time: Some(time), kind: "deploy".to_string(), message: None, labels: BTreeMap::new() }, 0)?;
+    }
+    let found = markers.for_search(None, None);
+    assert_eq!(found.len(), MAX_MARKERS_PER_RESPONSE);
+    assert_eq!(found[0].time, 10);
+    Ok(())
+}
@@ -1,146 +1,3089 @@
 use std::sync::{Arc, RwLock, Mutex};
-use std::time::SystemTime;
-use std::collections::{HashSet, BTreeMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, Duration, Instant};
+use std::collections::{HashSet, BTreeMap, BinaryHeap};
+use std::ops::Bound;
 use growable_bloom_filter::GrowableBloom;
+use rayon::prelude::*;
 use anyhow::Result;
+use serde::{Serialize, Deserialize};
 use rocket::tokio;
 
-use crate::minute_id::MinuteId;
+use crate::minute_id::{EventId, MinuteId};
+use crate::minute::Log;
+#[cfg(test)]
 use crate::minute::Minute;
+use crate::segment::{Segment, SegmentFormat};
 
 
+///
+/// Which end of the time range a search starts from. Results are always sorted by the log's own (host) time.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortOrder{
+    /// most recent first
+    #[default]
+    Descending,
+    /// oldest first
+    Ascending,
+}
+
+impl SortOrder{
+    pub fn parse(order: &str) -> Result<SortOrder> {
+        match order{
+            "desc" => Ok(SortOrder::Descending),
+            "asc" => Ok(SortOrder::Ascending),
+            other => Err(anyhow::anyhow!("order must be 'asc' or 'desc', not '{}'", other)),
+        }
+    }
+}
+
+///
+/// Everything about a search that isn't the search string itself.
+/// from/to are in seconds since the epoch, and are applied at minute granularity
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchOptions{
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub limit: usize,
+    /// skip this many results first (the next page starts at offset + limit)
+    pub offset: usize,
+    /// at most this many results from any one minute, so one noisy minute can't crowd out the rest
+    pub max_per_minute: Option<usize>,
+    pub order: SortOrder,
+    /// stop after opening this many minutes (the ones the bloom filter lets through), and say so in the stats
+    pub max_minutes_scanned: Option<usize>,
+    /// give up after this long, and say so in the stats (see Deadline): searches only, histograms and stats take as long as they take
+    pub timeout: Option<Duration>,
+}
+
+impl Default for SearchOptions{
+    fn default() -> Self {
+        SearchOptions{
+            from: None,
+            to: None,
+            limit: 1000,
+            offset: 0,
+            max_per_minute: None,
+            order: SortOrder::Descending,
+            max_minutes_scanned: None,
+            timeout: None,
+        }
+    }
+}
+
+///
+/// When a search has to stop, wherever it's got to: minutes check between batches (see Minute::for_each_match), and
+/// the search between minutes, so it can take a batch longer than it's supposed to, but not a whole minute longer.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline{
+    pub fn after(timeout: Option<Duration>) -> Deadline {
+        Deadline(timeout.map(|timeout| Instant::now() + timeout))
+    }
+
+    pub fn never() -> Deadline {
+        Deadline(None)
+    }
+
+    pub fn passed(&self) -> bool {
+        self.0.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+///
+/// A minute we know about, which may or may not currently have an open connection: it doesn't get one until somebody searches it,
+/// and the reaper closes minutes that nobody has searched in a while (we reopen them the next time somebody does).
+///
+pub struct MinuteHandle{
+    minute: Option<Box<dyn Segment>>,
+    last_used: SystemTime,
+}
+
+impl MinuteHandle{
+    fn new() -> MinuteHandle {
+        MinuteHandle{
+            minute: None,
+            last_used: SystemTime::now(),
+        }
+    }
+}
+
+///
+/// A sealed minute's bloom, which may or may not be in memory right now: they only get to use bloom_budget_bytes between them
+/// (see MinuteDB::set_bloom_budget), and the ones that haven't been asked anything in longest get let go first.
+/// One that's been let go gets read back in (from its sidecar, or the minute) the next time a search's time range takes in its minute.
+///
+struct CachedBloom{
+    bloom: RwLock<Option<Arc<GrowableBloom>>>,
+    /// how big it is serialized, which is about how much memory it takes up
+    bytes: u64,
+    /// the bloom clock (see MinuteDB::prefilter) the last time a search asked it anything
+    last_used: AtomicU64,
+    /// every bloom that's in memory counts itself in here, and takes itself back out when it goes
+    cached_bytes: Arc<AtomicU64>,
+}
+
+impl CachedBloom{
+    fn new(bloom: GrowableBloom, bytes: u64, now: u64, cached_bytes: Arc<AtomicU64>) -> CachedBloom {
+        cached_bytes.fetch_add(bytes, Ordering::Relaxed);
+        CachedBloom{
+            bloom: RwLock::new(Some(Arc::new(bloom))),
+            bytes,
+            last_used: AtomicU64::new(now),
+            cached_bytes,
+        }
+    }
+
+    fn get(&self) -> Option<Arc<GrowableBloom>> {
+        self.bloom.read().unwrap().clone()
+    }
+
+    ///
+    /// Put a bloom that was let go back (unless somebody beat us to it)
+    ///
+    fn restore(&self, bloom: Arc<GrowableBloom>) {
+        let mut slot = self.bloom.write().unwrap();
+        if slot.is_none() {
+            *slot = Some(bloom);
+            self.cached_bytes.fetch_add(self.bytes, Ordering::Relaxed);
+        }
+    }
+
+    fn evict(&self) -> bool {
+        let evicted = self.bloom.write().unwrap().take().is_some();
+        if evicted {
+            self.cached_bytes.fetch_sub(self.bytes, Ordering::Relaxed);
+        }
+        evicted
+    }
+}
+
+impl Drop for CachedBloom{
+    fn drop(&mut self) {
+        self.evict();
+    }
+}
+
+///
+/// How the bloom cache is doing against its budget (see MinuteDB::set_bloom_budget)
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BloomMemory{
+    pub budget_bytes: u64,
+    /// what the blooms in memory add up to, serialized
+    pub cached_bytes: u64,
+    pub cached: usize,
+    /// sealed minutes whose blooms have been let go: searching them means reading the bloom back in first
+    pub evicted: usize,
+}
+
+///
+/// Where a search spent its time on one node (federated searches collect one of these per node).
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchStats{
+    pub node: u32,
+    /// minutes in the requested time range
+    pub minutes_considered: usize,
+    /// minutes that got past the bloom filter and actually had to be opened
+    pub minutes_scanned: usize,
+    /// minutes that had to be pulled back out of the archive to be searched
+    pub minutes_rehydrated: usize,
+    /// minutes whose results came out of the query cache, so they didn't have to be opened at all
+    #[serde(default)]
+    pub minutes_cached: usize,
+    /// minutes that are still being written: no bloom filter yet, so every one of them got scanned
+    #[serde(default)]
+    pub minutes_unsealed: usize,
+    pub rows_matched: usize,
+    pub bloom_us: u64,
+    pub scan_us: u64,
+    pub total_us: u64,
+    /// set when the search gave up before it got through the whole time range
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hint: Option<SearchHint>,
+    /// the search ran out of time (see SearchOptions::timeout) before it found everything it was after: the results are what it had by then
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// set when the search asked for logs from before the oldest one this node still has
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<RetentionWarning>,
+    /// set when this node couldn't be searched at all (a federated peer that was down, or too busy): none of its results are in there
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+///
+/// The search hit max_minutes_scanned, so the results are partial: they only cover covered_from..covered_to
+/// (seconds since the epoch, whole minutes), and there's more out there if you narrow the search down.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchHint{
+    pub max_minutes_scanned: usize,
+    pub covered_from: i64,
+    pub covered_to: i64,
+    pub suggestion: String,
+}
+
+impl SearchHint{
+    fn new(max_minutes_scanned: usize, first_minute: i64, last_minute: i64) -> SearchHint {
+        SearchHint{
+            max_minutes_scanned,
+            covered_from: first_minute.min(last_minute),
+            covered_to: first_minute.max(last_minute) + 59,
+            suggestion: format!("only searched {} minutes that might have matches: narrow your from/to, or make your search more specific", max_minutes_scanned),
+        }
+    }
+}
+
+///
+/// The search started before the oldest minute this node still has, so an empty result for the early part doesn't mean nothing
+/// happened: retention got there first. Says what was actually covered, and whether the archive has any of the rest
+/// (seconds since the epoch, whole minutes).
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionWarning{
+    pub requested_from: i64,
+    /// the oldest minute this node still has on disk: None if it doesn't have any
+    pub retained_from: Option<i64>,
+    /// the oldest archived minute in the part of the range that's no longer on disk, if the archive has any
+    pub archived_from: Option<i64>,
+    /// whether those archived minutes got searched (they do, if this node can rehydrate them)
+    pub archive_searched: bool,
+    pub warning: String,
+}
+
+impl RetentionWarning{
+    fn new(requested_from: i64, retained_from: Option<i64>, archived_from: Option<i64>, archive_searched: bool) -> RetentionWarning {
+        let retained = match retained_from{
+            Some(retained_from) => format!("this node only has logs from {} on", retained_from),
+            None => "this node doesn't have any logs on disk".to_string(),
+        };
+        let archived = match (archived_from, archive_searched){
+            (Some(archived_from), true) => format!("anything older came out of the archive (which has logs from {} on)", archived_from),
+            (Some(archived_from), false) => format!("the archive has older logs (from {} on), but this node can't search it", archived_from),
+            (None, _) => "and there's nothing older in the archive".to_string(),
+        };
+        RetentionWarning{
+            requested_from,
+            retained_from,
+            archived_from,
+            archive_searched,
+            warning: format!("asked for logs from {}, but {}: {}", requested_from, retained, archived),
+        }
+    }
+}
+
+///
+/// One bar of a histogram: `count` logs matched from `time` (seconds since the epoch) until the next bucket.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistogramBucket{
+    pub time: i64,
+    pub count: u64,
+    /// labeled minutes in this bucket, if somebody asked (see MinuteLabels::overlay)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<crate::minute_labels::MinuteLabel>,
+    /// deploys, incidents and the like that happened during this bucket (see Markers::overlay)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub markers: Vec<crate::markers::Marker>,
+}
+
+///
+/// "30s", "1m", "5m", "1h", "1d" (or just a number of seconds) -> seconds
+///
+pub fn parse_bucket(bucket: &str) -> Result<i64> {
+    let bucket = bucket.trim();
+    let (number, multiplier) = match bucket.char_indices().last(){
+        Some((i, 's')) => (&bucket[..i], 1),
+        Some((i, 'm')) => (&bucket[..i], 60),
+        Some((i, 'h')) => (&bucket[..i], 3600),
+        Some((i, 'd')) => (&bucket[..i], 86400),
+        _ => (bucket, 1),
+    };
+    let seconds = number.parse::<i64>().map_err(|_| anyhow::anyhow!("Can't make sense of bucket size '{}'", bucket))? * multiplier;
+    if seconds <= 0 {
+        return Err(anyhow::anyhow!("Bucket size must be at least a second"));
+    }
+    Ok(seconds)
+}
+
+///
+/// What `/stats?by=` groups matching logs by: `host`, `level`, or the name of any key=value field
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatsBy{
+    Host,
+    Level,
+    Field(String),
+}
+
+impl StatsBy{
+    pub fn parse(by: &str) -> StatsBy {
+        match by{
+            "host" => StatsBy::Host,
+            "level" => StatsBy::Level,
+            field => StatsBy::Field(field.to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self{
+            StatsBy::Host => "host",
+            StatsBy::Level => "level",
+            StatsBy::Field(field) => field,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueCount{
+    pub value: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostCount{
+    pub host: String,
+    pub events: u64,
+}
+
+///
+/// Every host that sent anything in the time range, busiest first: see MinuteDB::hosts
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hosts{
+    pub hosts: Vec<HostCount>,
+    /// minutes (shards, really) that got counted
+    pub minutes: usize,
+    /// minutes in range we couldn't count (archived, or not ready to be read): whoever sent those logs isn't in here
+    pub minutes_skipped: usize,
+}
+
+///
+/// `total` logs matched, with `distinct` different values between them; `top` is the most common few.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsResult{
+    pub by: String,
+    pub total: u64,
+    pub distinct: usize,
+    pub top: Vec<ValueCount>,
+}
+
+impl StatsResult{
+    pub fn empty(by: &StatsBy) -> StatsResult {
+        StatsResult{
+            by: by.name().to_string(),
+            total: 0,
+            distinct: 0,
+            top: Vec::new(),
+        }
+    }
+}
+
+///
+/// What an admin range delete actually did.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeleteReport{
+    pub deleted: usize,
+    pub archived: usize,
+    /// minutes in range that are too recent to delete: the writer might still be using them
+    pub skipped: usize,
+    pub bytes: u64,
+}
+
+///
+/// What taking logs out of minutes (see MinuteDB::redact) did
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RedactReport{
+    /// sealed minutes in range whose blooms said they might have something to take out
+    pub checked: usize,
+    /// ...and the ones that did
+    pub minutes: usize,
+    pub logs: u64,
+    /// minutes in range that aren't sealed (or the read loop hasn't got to yet): seal them (see ShardedMinute::seal_range) and try again
+    pub skipped: usize,
+}
+
+///
+/// What a retention sweep (see MinuteDB::sweep_retention) actually did
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionReport{
+    pub deleted: usize,
+    pub archived: usize,
+    pub bytes: u64,
+    /// what's left, and how big it is
+    pub kept: usize,
+    pub kept_bytes: u64,
+}
+
+///
+/// One minute file, for the admin API
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MinuteInfo{
+    pub minute: String,
+    /// when its logs happened, in seconds since the epoch
+    pub time: i64,
+    pub size_bytes: u64,
+    /// when the file was last written to, in seconds since the epoch
+    pub modified_at: i64,
+    /// done being written to: the writer seals a minute max_lateness_seconds after it ends
+    pub sealed: bool,
+    /// whether its bloom is in memory, or was let go to stay under the bloom budget (sealed minutes only)
+    pub bloom_in_memory: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MinuteListing{
+    /// newest first, and only up to the limit that was asked for
+    pub minutes: Vec<MinuteInfo>,
+    /// every minute in the time range, listed or not
+    pub total: usize,
+    pub total_bytes: u64,
+}
+
+///
+/// Sealed minutes' summaries (see Minute::summary), newest first, and what they all add up to
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SummaryListing{
+    /// only up to the limit that was asked for
+    pub minutes: Vec<crate::minute::MinuteSummary>,
+    /// the totals are for every sealed minute in the time range that has a summary, listed or not
+    pub events: u64,
+    pub bytes: u64,
+    pub bloom_bytes: u64,
+    /// minutes in the time range that aren't sealed yet, or were sealed before they got summaries
+    pub without_summary: usize,
+}
+
+///
+/// How much of its disk and memory a tenant is using
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Budget{
+    /// the tenant's share of MINUTE_DB_DISK_GB (less the headroom): past this, retention deletes the oldest minutes
+    pub disk_budget_bytes: u64,
+    pub disk_used_bytes: u64,
+    /// what's left on the filesystem the minutes are on, whoever's using the rest
+    pub disk_free_bytes: u64,
+    pub minutes: usize,
+    /// when the oldest minute's logs happened, in seconds since the epoch
+    pub oldest: Option<i64>,
+    /// RETENTION_DAYS, in seconds, if there is one
+    pub max_age_seconds: Option<i64>,
+    pub bloom_memory: BloomMemory,
+}
+
+fn sort_logs(logs: &mut [Log], order: SortOrder) {
+    logs.sort_by_key(|log| merge_key(log, order));
+}
+
+///
+/// Logs sort by host time (ties broken by id), smallest key first.
+///
+fn merge_key(log: &Log, order: SortOrder) -> (i64, i64) {
+    match order{
+        SortOrder::Ascending => (log.time, log.id),
+        SortOrder::Descending => (-log.time, -log.id),
+    }
+}
+
+///
+/// Interleave logs from several shards (or nodes, see federation), each one already sorted in `order`, into one list, also in `order`.
+///
+pub fn merge_shards(shards: Vec<Vec<Log>>, order: SortOrder) -> Vec<Log> {
+    let total = shards.iter().map(|shard| shard.len()).sum();
+    let mut shards: Vec<std::vec::IntoIter<Log>> = shards.into_iter().map(|shard| shard.into_iter()).collect();
+    let mut heads: Vec<Option<Log>> = shards.iter_mut().map(|shard| shard.next()).collect();
+
+    // BinaryHeap is a max-heap, so Reverse it to pop the smallest key first
+    let mut heap = BinaryHeap::new();
+    for (i, head) in heads.iter().enumerate() {
+        if let Some(log) = head {
+            heap.push(std::cmp::Reverse((merge_key(log, order), i)));
+        }
+    }
+
+    let mut merged = Vec::with_capacity(total);
+    while let Some(std::cmp::Reverse((_, i))) = heap.pop() {
+        if let Some(log) = heads[i].take() {
+            merged.push(log);
+        }
+        heads[i] = shards[i].next();
+        if let Some(log) = &heads[i] {
+            heap.push(std::cmp::Reverse((merge_key(log, order), i)));
+        }
+    }
+    merged
+}
+
+///
+/// What the read loop saw last time it looked at the data directory, so the next look only has to deal with what changed
+///
+#[derive(Debug, Default)]
+struct ScanState{
+    /// every minute file, sealed or not
+    seen: HashSet<MinuteId>,
+    /// when that scan started, in seconds since the epoch
+    started_at: i64,
+}
+
+///
+/// How many minutes of results a streaming search gets ahead of whoever's reading it
+///
+const SEARCH_STREAM_MINUTES: usize = 16;
+
+///
+/// How many chunks of an export (see ChannelWriter) get written ahead of whoever's downloading it
+///
+const EXPORT_STREAM_CHUNKS: usize = 4;
+
+///
+/// How many minutes' blooms get checked at once, before any of them are opened
+///
+const BLOOM_BATCH_MINUTES: usize = 4096;
+
+///
+/// How many threads read the blooms of new minutes (see MinuteDB::load_blooms): at startup, that's every minute there is
+///
+const BLOOM_LOADERS: usize = 4;
+
+///
+/// An explanation lists at most this many of the minutes that got past the bloom filters (it counts all of them)
+///
+pub const MAX_EXPLAINED_MINUTES: usize = 1000;
+
+///
+/// What `GET /search/<search>/explain` says about a search (see MinuteDB::explain)
+///
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Explanation{
+    pub search: String,
+    pub tree: crate::search_token::SearchTree,
+    /// what every minute's bloom filter gets asked about (each one only once)
+    pub trigrams: Vec<String>,
+    /// false means the bloom filters can't rule anything out: every minute in range gets opened
+    pub narrows: bool,
+    /// minutes in the time range
+    pub minutes_considered: usize,
+    /// minutes that got past the bloom filters, and would be opened and scanned
+    pub candidates: usize,
+    /// minutes in range that retention has archived: they'd have to be rehydrated before anybody knows what's in them
+    pub archived: usize,
+    /// minutes in range that aren't sealed yet: they don't have a bloom filter, so they're all candidates
+    #[serde(default)]
+    pub unsealed: usize,
+    /// the first MAX_EXPLAINED_MINUTES candidates, in the order the search would get to them
+    pub candidate_minutes: Vec<String>,
+}
+
+///
+/// What came of looking at one minute: `result` is None if the bloom filter said there was nothing in there
+///
+struct MinuteScan<T>{
+    result: Option<T>,
+    rehydrated: bool,
+    unsealed: bool,
+    /// the result came out of the query cache
+    cached: bool,
+    bloom_us: u64,
+    scan_us: u64,
+}
+
 #[derive(Clone)]
 pub struct MinuteDB{
-    db: Arc<RwLock<BTreeMap<MinuteId, Arc<Mutex<Minute>>>>>,
-    bloom_cache: Arc<RwLock<BTreeMap<MinuteId, Arc<GrowableBloom>>>>,
+    db: Arc<RwLock<BTreeMap<MinuteId, Arc<Mutex<MinuteHandle>>>>>,
+    bloom_cache: Arc<RwLock<BTreeMap<MinuteId, CachedBloom>>>,
+    scan_state: Arc<Mutex<ScanState>>,
     data_directory: String,
-    max_minutes: u64,
-    max_disk_bytes: u64,
+    retention: crate::retention::RetentionPolicy,
+    archiver: Option<Arc<crate::archive::Archiver>>,
+    rehydrator: Option<Arc<crate::rehydrate::Rehydrator>>,
+    search_threads: usize,
+    query_cache: Arc<crate::query_cache::QueryCache>,
+    head_seconds: i64,
+    catalog_path: Option<String>,
+    segment_format: Arc<dyn SegmentFormat>,
+    bloom_budget_bytes: u64,
+    /// what the blooms in memory add up to (see CachedBloom)
+    cached_bloom_bytes: Arc<AtomicU64>,
+    /// goes up by one every time a search checks some blooms: the least recently used ones are the first to go
+    bloom_clock: Arc<AtomicU64>,
+    /// held while retention deletes things, so the read loop and somebody asking for a sweep don't both try to
+    retention_lock: Arc<Mutex<()>>,
 }
 
 impl MinuteDB{
-    pub fn new(data_directory: String, max_minutes: u64, max_disk_bytes: u64) -> MinuteDB{
+    pub fn new(data_directory: String, retention: crate::retention::RetentionPolicy, archiver: Option<Arc<crate::archive::Archiver>>, rehydrator: Option<Arc<crate::rehydrate::Rehydrator>>) -> MinuteDB{
 
         MinuteDB{
             db: Arc::new(RwLock::new(BTreeMap::new())),
             bloom_cache: Arc::new(RwLock::new(BTreeMap::new())),
+            scan_state: Arc::new(Mutex::new(ScanState::default())),
             data_directory,
-            max_minutes,
-            max_disk_bytes
+            retention,
+            archiver,
+            rehydrator,
+            search_threads: 1,
+            query_cache: Arc::new(crate::query_cache::QueryCache::new(0)),
+            head_seconds: 0,
+            catalog_path: None,
+            segment_format: crate::segment::default_format(),
+            bloom_budget_bytes: u64::MAX,
+            cached_bloom_bytes: Arc::new(AtomicU64::new(0)),
+            bloom_clock: Arc::new(AtomicU64::new(0)),
+            retention_lock: Arc::new(Mutex::new(())),
         }
     }
 
-    fn search_within_minute(minute: &Arc<Mutex<Minute>>, search: &crate::search_token::Search) -> Result<Vec<crate::minute::Log>>{
-        let minute = minute.lock().map_err(|_| anyhow::anyhow!("Error locking minute"))?;
-        minute.search(&search)
+    ///
+    /// How many (query, minute) results histograms and stats get to keep around (see QueryCache). 0 (the default) keeps none.
+    ///
+    pub fn set_query_cache_entries(&mut self, entries: usize) {
+        self.query_cache = Arc::new(crate::query_cache::QueryCache::new(entries));
     }
 
+    ///
+    /// How many minutes one search (or histogram, or stats) looks at at the same time. 1 is one after the other.
+    ///
+    pub fn set_search_threads(&mut self, search_threads: usize) {
+        self.search_threads = search_threads.max(1);
+    }
 
-    pub fn search(&self, search: crate::search_token::Search) -> Result<Vec<crate::minute::Log>>{
-        let db = self.db.read().unwrap();
+    ///
+    /// Minutes that ended less than `seconds` ago get searched even if they aren't sealed yet (see Minute::open_unsealed),
+    /// so that "what just happened" doesn't have to wait for the writer to seal the minute and the read loop to notice.
+    /// 0 (the default) only searches sealed minutes.
+    ///
+    pub fn set_head_seconds(&mut self, seconds: i64) {
+        self.head_seconds = seconds.max(0);
+    }
+
+    ///
+    /// Where the read loop saves its catalog of the data directory (see catalog::Catalog). None (the default) doesn't save it.
+    ///
+    pub fn set_catalog_path(&mut self, path: Option<String>) {
+        self.catalog_path = path;
+    }
+
+    ///
+    /// What the minutes in the data directory were written as (see segment::SegmentFormat): SQLite, unless somebody says otherwise
+    ///
+    pub fn set_segment_format(&mut self, format: Arc<dyn SegmentFormat>) {
+        self.segment_format = format;
+    }
+
+    ///
+    /// How much memory the sealed minutes' blooms get, all together (counted as what they are serialized, in their sidecars).
+    /// Past that, the blooms that haven't been used in longest are let go, and read back in when a search needs them.
+    /// u64::MAX (the default) keeps every one.
+    ///
+    pub fn set_bloom_budget(&mut self, bytes: u64) {
+        self.bloom_budget_bytes = bytes;
+    }
+
+    pub fn bloom_memory(&self) -> BloomMemory {
         let bloom_cache = self.bloom_cache.read().unwrap();
+        let cached = bloom_cache.values().filter(|cached| cached.bloom.read().unwrap().is_some()).count();
+        BloomMemory{
+            budget_bytes: self.bloom_budget_bytes,
+            cached_bytes: self.cached_bloom_bytes.load(Ordering::Relaxed),
+            cached,
+            evicted: bloom_cache.len() - cached,
+        }
+    }
 
-        let results_min = 30;
-        let results_max = 1000;
+    ///
+    /// Let go of the least recently used blooms until the rest fit in the budget (oldest minutes first, when it's a tie): how many went
+    ///
+    fn evict_blooms(&self, bloom_cache: &BTreeMap<MinuteId, CachedBloom>) -> usize {
+        if self.cached_bloom_bytes.load(Ordering::Relaxed) <= self.bloom_budget_bytes {
+            return 0;
+        }
+        let mut candidates: Vec<(u64, &MinuteId, &CachedBloom)> = bloom_cache.iter()
+            .filter(|(_, cached)| cached.bloom.read().unwrap().is_some())
+            .map(|(minute_id, cached)| (cached.last_used.load(Ordering::Relaxed), minute_id, cached))
+            .collect();
+        candidates.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+        let mut evicted = 0;
+        for (_, _, cached) in candidates {
+            if self.cached_bloom_bytes.load(Ordering::Relaxed) <= self.bloom_budget_bytes {
+                break;
+            }
+            if cached.evict() {
+                evicted += 1;
+            }
+        }
+        evicted
+    }
 
-        let mut results = Vec::new();
-        for (minute_id, bloom) in bloom_cache.iter(){
-            if search.bloom_test(bloom){
-                let minute = db.get(&minute_id);
-                if let Some(minute) = minute{
-                    results.extend(Self::search_within_minute(minute, &search)?);
-                    if results.len() > results_min {
-                        break;
+    ///
+    /// A bloom that got let go, back from wherever it's kept
+    ///
+    fn reload_bloom(&self, minute_id: &MinuteId) -> Result<GrowableBloom> {
+        match crate::segment::read_bloom_sidecar(&self.path_for(minute_id)){
+            Ok((bloom, _)) => Ok(bloom),
+            Err(_) => self.segment_format.open(minute_id, &self.data_directory)?.bloom(),
+        }
+    }
+
+    fn path_for(&self, minute_id: &MinuteId) -> String {
+        format!("{}/{}/{}/{}-{}.db", self.data_directory, minute_id.day, minute_id.hour, minute_id.minute, minute_id.unique_id)
+    }
+
+    ///
+    /// Every sealed minute we have, oldest first (what a standby copies, see replication::Manifest)
+    ///
+    pub fn sealed_minutes(&self) -> Vec<MinuteId> {
+        self.bloom_cache.read().unwrap().keys().cloned().collect()
+    }
+
+    ///
+    /// Where a sealed minute's file is: None if we don't have it, or if the writer might still be writing to it
+    ///
+    pub fn sealed_path(&self, minute_id: &MinuteId) -> Option<String> {
+        self.bloom_cache.read().unwrap().contains_key(minute_id).then(|| self.path_for(minute_id))
+    }
+
+    fn with_minute<T, F: FnOnce(&dyn Segment) -> Result<T>>(&self, minute_id: &MinuteId, handle: &Arc<Mutex<MinuteHandle>>, f: F) -> Result<Option<T>>{
+        let mut handle = handle.lock().map_err(|_| anyhow::anyhow!("Error locking minute"))?;
+        handle.last_used = SystemTime::now();
+        if handle.minute.is_none() {
+            handle.minute = Some(self.segment_format.open(minute_id, &self.data_directory)?);
+        }
+        match &handle.minute{
+            Some(minute) => Ok(Some(f(minute.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// Close the connection to every minute that hasn't been searched in `max_idle`.
+    /// (they stay in the db, and get reopened if somebody searches them again)
+    ///
+    pub fn close_idle(&self, max_idle: Duration) -> usize {
+        let db = self.db.read().unwrap();
+        let mut closed = 0;
+        for handle in db.values(){
+            // if somebody's searching it right now, it's not idle
+            if let Ok(mut handle) = handle.try_lock(){
+                let idle = handle.last_used.elapsed().unwrap_or_default();
+                if handle.minute.is_some() && idle > max_idle {
+                    handle.minute = None;
+                    closed += 1;
+                }
+            }
+        }
+        closed
+    }
+
+
+    pub fn search(&self, search: crate::search_token::Search, options: &SearchOptions) -> Result<Vec<Log>>{
+        Ok(self.search_with_stats(search, options)?.0)
+    }
+
+    ///
+    /// Look at one minute: bloom filter first, then (if it might have matches) `visit`.
+    /// Archived minutes have to be pulled back out of the archive before we can even check their bloom filter.
+    ///
+    ///
+    /// Look at one minute that's already passed the bloom filter (see prefilter): archived minutes only find out once they're here
+    ///
+    fn scan_minute<T, V>(&self, minute_id: &MinuteId, search: &crate::search_token::Search, cache_key: Option<&str>, db: &BTreeMap<MinuteId, Arc<Mutex<MinuteHandle>>>, bloom_cache: &BTreeMap<MinuteId, CachedBloom>, visit: &V) -> Result<MinuteScan<T>>
+    where
+        T: Clone + Send + Sync + 'static,
+        V: Fn(&dyn Segment) -> Result<T>,
+    {
+        let mut scan = MinuteScan{ result: None, rehydrated: false, unsealed: false, cached: false, bloom_us: 0, scan_us: 0 };
+        match (bloom_cache.contains_key(minute_id), &self.rehydrator){
+            (true, _) => {
+                if let Some(result) = cache_key.and_then(|cache_key| self.query_cache.get::<T>(cache_key, minute_id)) {
+                    scan.result = Some(result);
+                    scan.cached = true;
+                }
+                else if let Some(handle) = db.get(minute_id) {
+                    let scan_started = Instant::now();
+                    scan.result = self.with_minute(minute_id, handle, visit)?;
+                    scan.scan_us += scan_started.elapsed().as_micros() as u64;
+                    if let (Some(cache_key), Some(result)) = (cache_key, &scan.result) {
+                        self.query_cache.insert(cache_key, minute_id, result.clone());
                     }
                 }
+            },
+            (false, Some(rehydrator)) => {
+                // downloading counts as scanning: it's the slow part
+                let scan_started = Instant::now();
+                let minute = rehydrator.open(minute_id)?;
+                scan.rehydrated = true;
+                scan.scan_us += scan_started.elapsed().as_micros() as u64;
+
+                let bloom_started = Instant::now();
+                let bloom_match = search.bloom_test(&minute.get_bloom_filter()?);
+                scan.bloom_us += bloom_started.elapsed().as_micros() as u64;
+                if bloom_match{
+                    let scan_started = Instant::now();
+                    scan.result = Some(visit(&minute)?);
+                    scan.scan_us += scan_started.elapsed().as_micros() as u64;
+                }
+            },
+            (false, None) => {},
+        }
+        Ok(scan)
+    }
+
+    ///
+    /// Look at a minute that isn't sealed yet: no bloom filter and no cache, just a look at every batch.
+    /// The writer's still busy with it, so if it isn't ready to be read (it's brand new, or it's mid-seal) we skip it this time.
+    ///
+    fn scan_unsealed<T, V>(&self, minute_id: &MinuteId, visit: &V) -> MinuteScan<T>
+    where
+        V: Fn(&dyn Segment) -> Result<T>,
+    {
+        let mut scan = MinuteScan{ result: None, rehydrated: false, unsealed: true, cached: false, bloom_us: 0, scan_us: 0 };
+        let scan_started = Instant::now();
+        let minute = self.segment_format.open_unsealed(minute_id, &self.data_directory);
+        match minute.and_then(|minute| visit(minute.as_ref())){
+            Ok(result) => scan.result = Some(result),
+            Err(e) => tracing::warn!("Skipping unsealed minute {}: {}", minute_id, e),
+        }
+        scan.scan_us += scan_started.elapsed().as_micros() as u64;
+        scan
+    }
+
+    ///
+    /// Which of these minutes could have something in them, according to their blooms, checked all at once (and in parallel):
+    /// this is the part that has to get through every minute in the range, so it had better not touch SQLite.
+    /// Archived minutes don't have their blooms in the cache, so they pass, and find out for real once they're rehydrated.
+    /// Blooms that were let go to stay under the budget get read back in (usually from their sidecars): whoever calls this
+    /// gets to evict_blooms afterwards.
+    ///
+    fn prefilter(&self, plan: &crate::search_token::BloomPlan, minute_ids: &[MinuteId], bloom_cache: &BTreeMap<MinuteId, CachedBloom>) -> Vec<bool> {
+        let now = self.bloom_clock.fetch_add(1, Ordering::Relaxed) + 1;
+        minute_ids.par_chunks(256).flat_map_iter(|minute_ids| {
+            minute_ids.iter().map(|minute_id| match bloom_cache.get(minute_id){
+                Some(cached) => {
+                    cached.last_used.store(now, Ordering::Relaxed);
+                    let bloom = match cached.get(){
+                        Some(bloom) => bloom,
+                        None => match self.reload_bloom(minute_id){
+                            Ok(bloom) => {
+                                let bloom = Arc::new(bloom);
+                                cached.restore(bloom.clone());
+                                bloom
+                            },
+                            Err(e) => {
+                                // no way to rule it out
+                                tracing::error!("Error reading the bloom filter for {} back in: {:?}", minute_id, e);
+                                return true;
+                            }
+                        },
+                    };
+                    plan.test(&bloom)
+                },
+                None => true,
+            }).collect::<Vec<bool>>()
+        }).collect()
+    }
+
+    ///
+    /// The minutes in range that ended less than head_seconds ago, but that aren't in the db yet: still being written,
+    /// or sealed so recently that the read loop hasn't got to them. Only the directories for those last few hours get listed.
+    ///
+    fn unsealed_in_range(&self, options: &SearchOptions, bloom_cache: &BTreeMap<MinuteId, CachedBloom>, now: i64) -> HashSet<MinuteId> {
+        let mut unsealed = HashSet::new();
+        if self.head_seconds == 0 {
+            return unsealed;
+        }
+        let from = MinuteId::from_timestamp(options.from.unwrap_or(0)).to_timestamp().max(now.saturating_sub(self.head_seconds));
+        let to = options.to.unwrap_or(now).min(now);
+        // the minute that's still going at `from` might have started in the hour before
+        let mut hour = (from - 60) - (from - 60).rem_euclid(3600);
+        while hour <= to {
+            let hour_id = MinuteId::from_timestamp(hour);
+            let entries = std::fs::read_dir(format!("{}/{}/{}", self.data_directory, hour_id.day, hour_id.hour)).into_iter().flatten().flatten();
+            for entry in entries {
+                let file_name = entry.file_name();
+                let Some((minute, unique_id)) = file_name.to_str().and_then(|name| name.strip_suffix(".db")).and_then(|name| name.split_once('-')) else {
+                    continue;
+                };
+                let Ok(minute) = minute.parse::<u32>() else {
+                    continue;
+                };
+                let minute_id = MinuteId::new(hour_id.day, hour_id.hour, minute, unique_id);
+                let timestamp = minute_id.to_timestamp();
+                if timestamp + 60 > from && timestamp <= to && !bloom_cache.contains_key(&minute_id) {
+                    unsealed.insert(minute_id);
+                }
             }
+            hour += 3600;
         }
-        // only show the first 1000 results
-        results.truncate(results_max);
+        unsealed
+    }
 
-        Ok(results)
+    ///
+    /// Every minute a search with these options would consider, in the order it would get to them,
+    /// and which of those aren't sealed yet
+    ///
+    fn minutes_in_range(&self, options: &SearchOptions, bloom_cache: &BTreeMap<MinuteId, CachedBloom>) -> (Vec<MinuteId>, HashSet<MinuteId>) {
+        // the minute containing `to` is still in range, so the range ends at the start of the _next_ minute
+        let start = Bound::Included(MinuteId::from_timestamp(options.from.unwrap_or(0)));
+        let end = match options.to{
+            Some(to) => Bound::Excluded(MinuteId::from_timestamp(to + 60)),
+            None => Bound::Unbounded,
+        };
+
+        // minutes that retention has already archived only get searched if somebody explicitly asked for a time range:
+        //  otherwise every search would go digging through the whole archive
+        let mut minute_ids: Vec<MinuteId> = bloom_cache.range((start.clone(), end.clone())).map(|(minute_id, _)| minute_id.clone()).collect();
+        if let (Some(rehydrator), Some(_)) = (&self.rehydrator, options.from) {
+            minute_ids.extend(rehydrator.archived_in_range(start, end).into_iter().filter(|minute_id| !bloom_cache.contains_key(minute_id)));
+            minute_ids.sort();
+        }
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|now| now.as_secs() as i64).unwrap_or(0);
+        let unsealed = self.unsealed_in_range(options, bloom_cache, now);
+        if !unsealed.is_empty() {
+            minute_ids.extend(unsealed.iter().cloned());
+            minute_ids.sort();
+            minute_ids.dedup();
+        }
+        // "the most recent 100" means starting at the most recent minute, and stopping once we have 100
+        if options.order == SortOrder::Descending {
+            minute_ids.reverse();
+        }
+        (minute_ids, unsealed)
     }
 
-    pub async fn search_async(&self, search: crate::search_token::Search) -> Result<Vec<crate::minute::Log>>{
-        let self_clone = self.clone();
-        let results = tokio::task::spawn_blocking(move || {
-            self_clone.search(search)
-        }).await??;
+    ///
+    /// If the search starts before the oldest minute we've got (sealed or not), a warning that says so.
+    /// Searches without a `from` asked for whatever there is, so they never get one.
+    ///
+    fn retention_warning(&self, options: &SearchOptions, bloom_cache: &BTreeMap<MinuteId, CachedBloom>, unsealed: &HashSet<MinuteId>) -> Option<RetentionWarning> {
+        let requested_from = options.from?;
+        let oldest_sealed = bloom_cache.keys().next().map(|minute_id| minute_id.to_timestamp());
+        let oldest_unsealed = unsealed.iter().map(|minute_id| minute_id.to_timestamp()).min();
+        let retained_from = oldest_sealed.into_iter().chain(oldest_unsealed).min();
+        // the minute `from` falls in counts as covered
+        if retained_from.is_some_and(|retained_from| retained_from <= requested_from - requested_from.rem_euclid(60)) {
+            return None;
+        }
 
-        Ok(results)
+        let mut end = match retained_from{
+            Some(retained_from) => Bound::Excluded(MinuteId::from_timestamp(retained_from)),
+            None => Bound::Unbounded,
+        };
+        if let Some(to) = options.to.filter(|to| retained_from.is_none_or(|retained_from| to + 60 < retained_from)) {
+            end = Bound::Excluded(MinuteId::from_timestamp(to + 60));
+        }
+        let archived_from = self.archiver.as_ref()
+            .and_then(|archiver| archiver.archived_in_range(Bound::Included(MinuteId::from_timestamp(requested_from)), end).first().map(|minute_id| minute_id.to_timestamp()));
+        Some(RetentionWarning::new(requested_from, retained_from, archived_from, archived_from.is_some() && self.rehydrator.is_some()))
     }
 
-    pub fn update(&self, new_list: HashSet<MinuteId>) -> Result<()> {
-        let mut db = self.db.write().unwrap();
-        let mut bloom_cache = self.bloom_cache.write().unwrap();
+    ///
+    /// What a search would do, without doing it: how it parsed, what it asks the bloom filters, and which minutes get past them.
+    /// Nothing gets opened, so it's cheap even over a wide range.
+    ///
+    pub fn explain(&self, search: &crate::search_token::Search, options: &SearchOptions) -> Explanation {
+        let bloom_cache = self.bloom_cache.read().unwrap();
+        let (minute_ids, unsealed) = self.minutes_in_range(options, &bloom_cache);
+        let plan = search.bloom_plan();
+        let passed = self.prefilter(&plan, &minute_ids, &bloom_cache);
 
-        let existing_keys = db.keys().cloned().collect::<HashSet<MinuteId>>();
-        println!("Minute Keys: {} existing, {} files", existing_keys.len(), new_list.len());
-        let mut removed = 0;
-        let mut added = 0;
-        for key in existing_keys{
-            if !new_list.contains(&key) {
-                db.remove(&key);
-                bloom_cache.remove(&key);
-                removed += 1;
-            }
-        }
-        for key in new_list{
-            if db.contains_key(&key) == false {
-                let minute = Minute::new(key.day, key.hour, key.minute, &key.unique_id, &self.data_directory, false)?;
-                match minute.is_sealed(){
-                    Ok(true) => {},
-                    Ok(false) => {
-                        // this minute isn't sealed yet, so we shouldn't read it
-                        continue;
-                    },
-                    Err(e) => {
-                        println!("Error checking if minute is sealed: {:?}", e);
-                    }
+        let mut explanation = Explanation{
+            search: search.search_string.clone(),
+            tree: search.tree.clone(),
+            trigrams: plan.trigrams().to_vec(),
+            narrows: plan.narrows(),
+            minutes_considered: minute_ids.len(),
+            candidates: 0,
+            archived: 0,
+            unsealed: unsealed.len(),
+            candidate_minutes: Vec::new(),
+        };
+        explanation.trigrams.sort();
+        for (minute_id, passed) in minute_ids.iter().zip(passed) {
+            if !bloom_cache.contains_key(minute_id) && !unsealed.contains(minute_id) {
+                explanation.archived += 1;
+            }
+            if passed {
+                explanation.candidates += 1;
+                if explanation.candidate_minutes.len() < MAX_EXPLAINED_MINUTES {
+                    explanation.candidate_minutes.push(minute_id.to_string());
                 }
-                let bloom = minute.get_bloom_filter()?;
-                bloom_cache.insert(key.clone(), Arc::new(bloom));
-                db.insert(key, Arc::new(Mutex::new(minute)));
-                added += 1;
             }
         }
+        self.evict_blooms(&bloom_cache);
+        explanation
+    }
+
+    ///
+    /// Every minute in the time range that passes the bloom filter gets handed to `visit`, and whatever it found goes to `merge`
+    /// in `options.order`, until `merge` returns false. The shards of a minute were written at the same time, so once `merge` has had enough,
+    /// it still gets the rest of the shards of the minute it's in: otherwise the shard that happens to sort last would never get a look.
+    ///
+    /// Up to `search_threads` minutes get visited at once, so `visit` can't count on `merge` having seen the minutes before it:
+    /// the ones after the minute `merge` stopped at may well have been searched already, and what they found just gets dropped.
+    /// No more minutes get visited once `deadline` has passed.
+    ///
+    #[allow(clippy::too_many_arguments)]
+    fn scan_minutes<T, V, M>(&self, search: &crate::search_token::Search, options: &SearchOptions, cache_key: Option<&str>, deadline: Deadline, stats: &mut SearchStats, visit: V, mut merge: M) -> Result<()>
+    where
+        T: Clone + Send + Sync + 'static,
+        V: Fn(&dyn Segment) -> Result<T> + Sync,
+        M: FnMut(i64, T) -> Result<bool>,
+    {
+        let span = tracing::info_span!("search", search = %search.search_string, from = options.from, to = options.to);
+        let _span = span.enter();
+        let db = self.db.read().unwrap();
+        let bloom_cache = self.bloom_cache.read().unwrap();
+        let (minute_ids, unsealed) = self.minutes_in_range(options, &bloom_cache);
+        stats.retention = self.retention_warning(options, &bloom_cache, &unsealed);
+        // minutes that aren't sealed yet are still changing, so nothing they find goes in the query cache
+        let scan = |minute_id: &MinuteId| match unsealed.contains(minute_id){
+            true => Ok(self.scan_unsealed(minute_id, &visit)),
+            false => self.scan_minute(minute_id, search, cache_key, &db, &bloom_cache, &visit),
+        };
+
+        let plan = search.bloom_plan();
+        // which minutes passed the bloom filter: worked out BLOOM_BATCH_MINUTES at a time, as the search gets to them
+        let mut passed: Vec<bool> = Vec::new();
+        let mut next = 0;
+        let mut stopping_at: Option<i64> = None;
+        let mut first_minute: Option<i64> = None;
+        let mut last_minute: Option<i64> = None;
+        'scanning: loop{
+            if deadline.passed() {
+                break;
+            }
+            // no point searching more minutes at once than max_minutes_scanned is going to let us use
+            let window_size = match options.max_minutes_scanned{
+                Some(max_minutes_scanned) => self.search_threads.min(max_minutes_scanned.saturating_sub(stats.minutes_scanned).max(1)),
+                None => self.search_threads,
+            };
+            // minutes the bloom filter turned down come along for the ride (they still count as considered), but don't take up a thread
+            let mut window: Vec<(&MinuteId, bool)> = Vec::new();
+            let mut window_passed = 0;
+            while window_passed < window_size && next < minute_ids.len() {
+                let minute_id = &minute_ids[next];
+                if stopping_at.is_some_and(|stopping_at| stopping_at != minute_id.to_timestamp()) {
+                    break;
+                }
+                if next == passed.len() {
+                    let bloom_started = Instant::now();
+                    let batch = &minute_ids[next..minute_ids.len().min(next + BLOOM_BATCH_MINUTES)];
+                    passed.extend(self.prefilter(&plan, batch, &bloom_cache));
+                    stats.bloom_us += bloom_started.elapsed().as_micros() as u64;
+                }
+                window.push((minute_id, passed[next]));
+                if passed[next] {
+                    window_passed += 1;
+                }
+                next += 1;
+            }
+            if window.is_empty() {
+                break;
+            }
 
-        println!("MinuteDB update: {} removed, {} added", removed, added);
+            let mut scans: Vec<Result<MinuteScan<T>>> = if window_passed <= 1 {
+                window.iter().filter(|(_, passed)| *passed).map(|(minute_id, _)| scan(minute_id)).collect()
+            }
+            else {
+                std::thread::scope(|scope| {
+                    let threads: Vec<_> = window.iter().filter(|(_, passed)| *passed).map(|(minute_id, _)| {
+                        let scan = &scan;
+                        let span = &span;
+                        scope.spawn(move || span.in_scope(|| scan(minute_id)))
+                    }).collect();
+                    threads.into_iter().map(|thread| thread.join().unwrap_or_else(|_| Err(anyhow::anyhow!("search thread panicked")))).collect()
+                })
+            };
+            scans.reverse();
+
+            // everything after this works exactly as though we'd searched the minutes one at a time
+            for (minute_id, passed) in window {
+                let scan = match passed{
+                    true => scans.pop().unwrap_or_else(|| Err(anyhow::anyhow!("search thread went missing"))),
+                    false => Ok(MinuteScan{ result: None, rehydrated: false, unsealed: false, cached: false, bloom_us: 0, scan_us: 0 }),
+                };
+                if stopping_at.is_some_and(|stopping_at| stopping_at != minute_id.to_timestamp()) {
+                    break 'scanning;
+                }
+                if let (Some(max_minutes_scanned), Some(first_minute), Some(last_minute)) = (options.max_minutes_scanned, first_minute, last_minute) {
+                    if stats.minutes_scanned >= max_minutes_scanned && stopping_at.is_none() {
+                        // partial results are fine, as long as everybody knows they're partial
+                        stats.hint = Some(SearchHint::new(max_minutes_scanned, first_minute, last_minute));
+                        break 'scanning;
+                    }
+                }
+                first_minute = first_minute.or(Some(minute_id.to_timestamp()));
+                last_minute = Some(minute_id.to_timestamp());
+                stats.minutes_considered += 1;
 
+                let scan = scan?;
+                stats.bloom_us += scan.bloom_us;
+                stats.scan_us += scan.scan_us;
+                if scan.rehydrated {
+                    stats.minutes_rehydrated += 1;
+                }
+                if scan.unsealed {
+                    stats.minutes_unsealed += 1;
+                }
+                let keep_going = match scan.result{
+                    Some(result) => {
+                        match scan.cached{
+                            true => stats.minutes_cached += 1,
+                            false => stats.minutes_scanned += 1,
+                        }
+                        merge(minute_id.to_timestamp(), result)?
+                    },
+                    None => true,
+                };
+                if !keep_going && stopping_at.is_none() {
+                    stopping_at = Some(minute_id.to_timestamp());
+                }
+            }
+        }
+        // blooms this search read back in count against the budget too (and if it errored out, the next read loop catches them)
+        self.evict_blooms(&bloom_cache);
         Ok(())
     }
 
-    pub fn read_loop(&self){
-        // 10 seconds (in microseconds)
-        let interval_us = 10 * 1000000;
-
-        loop {
-            // start a timer
-            let now = SystemTime::now();
+    ///
+    /// The search itself: `on_minute` gets the shards of each minute that found anything, one minute at a time, in `options.order`,
+    /// until there are `options.offset + options.limit` results between them (or `on_minute` returns false). Returns how many results that was.
+    /// With `options.max_per_minute`, each minute's shards come already merged into one, and cut down to that many.
+    ///
+    fn search_minutes<F: FnMut(Vec<Vec<Log>>) -> bool>(&self, search: &crate::search_token::Search, options: &SearchOptions, stats: &mut SearchStats, mut on_minute: F) -> Result<usize>{
+        // the results we skip for the offset still have to be found
+        let results_max = options.offset.saturating_add(options.limit);
+        let per_minute = options.max_per_minute.unwrap_or(usize::MAX);
+        let mut finish_minute = |minute_shards: Vec<Vec<Log>>| match options.max_per_minute{
+            Some(max_per_minute) => {
+                let mut logs = merge_shards(minute_shards, options.order);
+                logs.truncate(max_per_minute);
+                on_minute(vec![logs])
+            },
+            None => on_minute(minute_shards),
+        };
 
-            // read from disk and insert into db
-            let files = crate::file_list::FileInfo::scan_and_clean(&self.data_directory, self.max_minutes, self.max_disk_bytes).unwrap();
-            let set_of_minutes: HashSet<MinuteId> = files.iter().map(|f| f.to_minute_id()).collect();
-            match self.update(set_of_minutes){
-                Ok(_) => {},
-                Err(e) => {
-                    println!("Error updating minute db: {:?}", e);
+        // the shards of the minute we're in, which go to on_minute once we get to the next one
+        let mut minute_shards: Vec<Vec<Log>> = Vec::new();
+        let mut listening = true;
+        // the minute we're in (its start, how many results we had when we got to it, and how many it's found itself), and how many we have now.
+        //  the minutes being searched while merge is busy need to know how much of the budget is left
+        let found = Mutex::new((None, 0, 0, 0));
+        let deadline = Deadline::after(options.timeout);
+        // how much a minute finds depends on how much everybody else has found, so searches can't be cached
+        self.scan_minutes(search, options, None, deadline, stats, |minute| {
+            let minute_start = minute.id().to_timestamp();
+            // this might be more than the minute ends up with (the minutes searched alongside it might use some of it up), never less
+            let budget = match *found.lock().unwrap(){
+                (Some(start), found_before_minute, _, _) if start == minute_start => results_max.saturating_sub(found_before_minute),
+                (_, _, _, n_results) => results_max.saturating_sub(n_results),
+            }.min(per_minute);
+            let mut shard = minute.search(search, budget, options.order, deadline)?;
+            sort_logs(&mut shard, options.order);
+            shard.truncate(budget);
+            Ok(shard)
+        }, |minute_start, mut shard| {
+            // every shard of a minute gets whatever budget was left when we got to the minute: they get merged by time afterwards
+            let mut found = found.lock().unwrap();
+            let (current_minute, found_before_minute, found_in_minute, n_results) = &mut *found;
+            if *current_minute != Some(minute_start) {
+                if !minute_shards.is_empty() {
+                    listening = finish_minute(std::mem::take(&mut minute_shards));
                 }
+                *current_minute = Some(minute_start);
+                *found_before_minute = *n_results;
+                *found_in_minute = 0;
+            }
+            shard.truncate(results_max.saturating_sub(*found_before_minute).min(per_minute));
+            *found_in_minute += shard.len();
+            *n_results = *found_before_minute + (*found_in_minute).min(per_minute);
+            if !shard.is_empty() {
+                minute_shards.push(shard);
             }
+            Ok(listening && *n_results < results_max)
+        })?;
+        if listening && !minute_shards.is_empty() {
+            finish_minute(minute_shards);
+        }
+        let (_, _, _, n_results) = found.into_inner().unwrap();
+        if listening && n_results < results_max && deadline.passed() {
+            stats.truncated = true;
+        }
+        Ok(n_results)
+    }
 
-            // how long did that take?
-            let elapsed = now.elapsed().unwrap();
-            let elapsed_us = elapsed.as_micros() as i128;
-            let sleep_us = interval_us - elapsed_us;
+    pub fn search_with_stats(&self, search: crate::search_token::Search, options: &SearchOptions) -> Result<(Vec<Log>, SearchStats)>{
+        let started = Instant::now();
+        let mut stats = SearchStats::default();
 
-            // if we took too long, just skip the sleep
-            if sleep_us < 0 {
-                println!("Warning: read thread took too long: {} us", elapsed_us);
-                continue;
+        let mut shards = Vec::new();
+        stats.rows_matched = self.search_minutes(&search, options, &mut stats, |minute_shards| {
+            shards.extend(minute_shards);
+            true
+        })?;
+
+        // host clocks don't line up perfectly with the minute a log landed in (and shards of the same minute interleave),
+        //  so merge everything we found by host time before we cut it down
+        let results = merge_shards(shards, options.order);
+        // only show `limit` results, starting from `offset`
+        let results = results.into_iter().skip(options.offset).take(options.limit).collect();
+
+        stats.total_us = started.elapsed().as_micros() as u64;
+        Ok((results, stats))
+    }
+
+    ///
+    /// Search, but hand the results over a minute at a time, as soon as each minute has been searched, instead of all at once at the end.
+    /// Each minute's logs are in order, but a log with a skewed host clock can end up out of order with the minute next door:
+    /// search_with_stats has all the results to sort, and we don't.
+    /// The search stops when it's found `options.limit` logs (after skipping `options.offset`), or when the receiver goes away.
+    ///
+    pub fn search_stream(&self, search: crate::search_token::Search, options: SearchOptions) -> tokio::sync::mpsc::Receiver<Vec<Log>>{
+        let (sender, receiver) = tokio::sync::mpsc::channel(SEARCH_STREAM_MINUTES);
+        let self_clone = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut stats = SearchStats::default();
+            let mut skipped = 0;
+            let mut sent = 0;
+            let searched = self_clone.search_minutes(&search, &options, &mut stats, |minute_shards| {
+                let mut logs = merge_shards(minute_shards, options.order);
+                let skipping = options.offset.saturating_sub(skipped).min(logs.len());
+                logs.drain(..skipping);
+                skipped += skipping;
+                // the shards of the last minute get the same budget each, so together they can go over
+                logs.truncate(options.limit.saturating_sub(sent));
+                sent += logs.len();
+                logs.is_empty() || sender.blocking_send(logs).is_ok()
+            });
+            if let Err(err) = searched {
+                tracing::error!("Error streaming search: {:?}", err);
+            }
+        });
+        receiver
+    }
+
+    ///
+    /// How many logs matched, per `bucket_seconds` of host time. Unlike search, there's no limit: every minute in range gets counted.
+    /// Buckets with nothing in them are left out.
+    ///
+    pub fn histogram(&self, search: crate::search_token::Search, options: &SearchOptions, bucket_seconds: i64) -> Result<(Vec<HistogramBucket>, SearchStats)>{
+        let started = Instant::now();
+        let mut stats = SearchStats::default();
+
+        let bucket_us = bucket_seconds * 1000000;
+        let mut counts: BTreeMap<i64, u64> = BTreeMap::new();
+        let cache_key = format!("histogram {} {}", bucket_us, search.cache_key());
+        self.scan_minutes(&search, options, Some(&cache_key), Deadline::never(), &mut stats, |minute| minute.histogram(&search, bucket_us), |_, minute_counts| {
+            for (bucket, count) in minute_counts {
+                *counts.entry(bucket).or_insert(0) += count;
+            }
+            Ok(true)
+        })?;
+
+        let buckets: Vec<HistogramBucket> = counts.into_iter().map(|(bucket, count)| HistogramBucket{
+            time: bucket / 1000000,
+            count,
+            labels: Vec::new(),
+            markers: Vec::new(),
+        }).collect();
+
+        stats.rows_matched = buckets.iter().map(|bucket| bucket.count as usize).sum();
+        stats.total_us = started.elapsed().as_micros() as u64;
+        Ok((buckets, stats))
+    }
+
+    ///
+    /// Count matching logs grouped by `by`, across every minute in range (no limit, like histogram),
+    /// and keep the `top` most common values.
+    ///
+    pub fn stats(&self, search: crate::search_token::Search, options: &SearchOptions, by: &StatsBy, top: usize) -> Result<(StatsResult, SearchStats)>{
+        let started = Instant::now();
+        let mut stats = SearchStats::default();
+
+        let mut total = 0;
+        let mut counts: std::collections::HashMap<String, u64> = std::collections::HashMap::new();
+        let cache_key = format!("stats {} {}", by.name(), search.cache_key());
+        self.scan_minutes(&search, options, Some(&cache_key), Deadline::never(), &mut stats, |minute| minute.stats(&search, by), |_, (minute_total, minute_counts)| {
+            total += minute_total;
+            for (value, count) in minute_counts {
+                *counts.entry(value).or_insert(0) += count;
             }
-            else{
-                std::thread::sleep(std::time::Duration::from_micros(sleep_us as u64));
+            Ok(true)
+        })?;
+
+        let distinct = counts.len();
+        let mut values: Vec<ValueCount> = counts.into_iter().map(|(value, count)| ValueCount{ value, count }).collect();
+        values.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+        values.truncate(top);
+
+        stats.rows_matched = total as usize;
+        stats.total_us = started.elapsed().as_micros() as u64;
+        Ok((StatsResult{
+            by: by.name().to_string(),
+            total,
+            distinct,
+            top: values,
+        }, stats))
+    }
+
+    ///
+    /// About how many different values `field` had over the time range, out of the sketches every minute got when it was sealed
+    /// (see Minute::generate_sketches): no logs get read, so it's cheap however wide the range is.
+    /// Archived and unsealed minutes don't have sketches we can get at, so they're only counted as missing.
+    ///
+    pub fn cardinality(&self, field: &str, options: &SearchOptions) -> Result<crate::cardinality::Cardinality> {
+        let db = self.db.read().unwrap();
+        let (minute_ids, _) = {
+            let bloom_cache = self.bloom_cache.read().unwrap();
+            self.minutes_in_range(options, &bloom_cache)
+        };
+
+        let mut merged = crate::cardinality::HyperLogLog::new();
+        let mut minutes = 0;
+        let mut minutes_without_sketch = 0;
+        for minute_id in &minute_ids {
+            let sketch = match db.get(minute_id){
+                Some(handle) => self.with_minute(minute_id, handle, |minute| minute.sketch(field))?.flatten(),
+                None => None,
+            };
+            match sketch{
+                Some(sketch) => {
+                    merged.merge(&sketch);
+                    minutes += 1;
+                },
+                None => minutes_without_sketch += 1,
             }
         }
+
+        Ok(crate::cardinality::Cardinality{
+            field: field.to_string(),
+            estimate: merged.estimate(),
+            standard_error: crate::cardinality::HyperLogLog::standard_error(),
+            minutes,
+            minutes_without_sketch,
+        })
     }
+
+    ///
+    /// Every host that sent logs from `options.from` to `options.to`, and how many, out of the counts every minute
+    /// wrote down when it was sealed (see Minute::host_counts): cheap enough for filling in a drop-down. Minutes that
+    /// aren't sealed yet get counted the slow way; archived ones don't get counted at all.
+    ///
+    pub fn hosts(&self, options: &SearchOptions) -> Result<Hosts> {
+        let db = self.db.read().unwrap();
+        let (minute_ids, unsealed) = {
+            let bloom_cache = self.bloom_cache.read().unwrap();
+            self.minutes_in_range(options, &bloom_cache)
+        };
+
+        let mut merged: BTreeMap<String, u64> = BTreeMap::new();
+        let mut minutes = 0;
+        let mut minutes_skipped = 0;
+        for minute_id in &minute_ids {
+            let counts = match (unsealed.contains(minute_id), db.get(minute_id)){
+                (true, _) => self.scan_unsealed(minute_id, &|minute: &dyn Segment| minute.host_counts()).result,
+                (false, Some(handle)) => self.with_minute(minute_id, handle, |minute| minute.host_counts())?,
+                (false, None) => None,
+            };
+            match counts{
+                Some(counts) => {
+                    for (host, events) in counts {
+                        *merged.entry(host).or_default() += events;
+                    }
+                    minutes += 1;
+                },
+                None => minutes_skipped += 1,
+            }
+        }
+
+        let mut hosts: Vec<HostCount> = merged.into_iter().map(|(host, events)| HostCount{ host, events }).collect();
+        hosts.sort_by(|a, b| b.events.cmp(&a.events).then_with(|| a.host.cmp(&b.host)));
+        Ok(Hosts{
+            hosts,
+            minutes,
+            minutes_skipped,
+        })
+    }
+
+    pub async fn hosts_async(&self, options: SearchOptions) -> Result<Hosts>{
+        let self_clone = self.clone();
+        tokio::task::spawn_blocking(move || {
+            self_clone.hosts(&options)
+        }).await?
+    }
+
+    pub async fn cardinality_async(&self, field: String, options: SearchOptions) -> Result<crate::cardinality::Cardinality>{
+        let self_clone = self.clone();
+        tokio::task::spawn_blocking(move || {
+            self_clone.cardinality(&field, &options)
+        }).await?
+    }
+
+    pub async fn stats_async(&self, search: crate::search_token::Search, options: SearchOptions, by: StatsBy, top: usize) -> Result<(StatsResult, SearchStats)>{
+        let self_clone = self.clone();
+        tokio::task::spawn_blocking(move || {
+            self_clone.stats(search, &options, &by, top)
+        }).await?
+    }
+
+    pub async fn histogram_async(&self, search: crate::search_token::Search, options: SearchOptions, bucket_seconds: i64) -> Result<(Vec<HistogramBucket>, SearchStats)>{
+        let self_clone = self.clone();
+        tokio::task::spawn_blocking(move || {
+            self_clone.histogram(search, &options, bucket_seconds)
+        }).await?
+    }
+
+    pub async fn search_async(&self, search: crate::search_token::Search, options: SearchOptions) -> Result<(Vec<Log>, SearchStats)>{
+        let self_clone = self.clone();
+        let results = tokio::task::spawn_blocking(move || {
+            self_clone.search_with_stats(search, &options)
+        }).await??;
+
+        Ok(results)
+    }
+
+    pub fn update(&self, new_list: HashSet<MinuteId>) -> Result<()> {
+        tracing::debug!("Minute Keys: {} existing, {} files", self.db.read().unwrap().len(), new_list.len());
+        let (removed, added) = self.load(new_list)?;
+        tracing::info!("MinuteDB update: {} removed, {} added", removed, added);
+        Ok(())
+    }
+
+    ///
+    /// Make the db hold exactly the sealed minutes in `new_list`, quietly: (removed, added)
+    ///
+    pub fn load(&self, new_list: HashSet<MinuteId>) -> Result<(usize, usize)> {
+        let (removed, added) = {
+            let db = self.db.read().unwrap();
+            let removed: Vec<MinuteId> = db.keys().filter(|key| !new_list.contains(key)).cloned().collect();
+            let added: Vec<MinuteId> = new_list.into_iter().filter(|key| !db.contains_key(key)).collect();
+            (removed, added)
+        };
+        self.apply_changes(removed, added)
+    }
+
+    ///
+    /// Take `removed` out of the db and put whichever of `added` are sealed in: (removed, added)
+    ///
+    fn apply_changes(&self, removed: Vec<MinuteId>, added: Vec<MinuteId>) -> Result<(usize, usize)> {
+        if removed.is_empty() && added.is_empty() {
+            return Ok((0, 0));
+        }
+
+        // read the new minutes' blooms before we take the write lock: searches can keep going meanwhile
+        let loaded = self.load_blooms(&added)?;
+
+        let mut db = self.db.write().unwrap();
+        let mut bloom_cache = self.bloom_cache.write().unwrap();
+        let mut n_removed = 0;
+        for key in removed{
+            self.query_cache.forget(&key);
+            bloom_cache.remove(&key);
+            if db.remove(&key).is_some() {
+                n_removed += 1;
+            }
+        }
+        let mut n_added = 0;
+        let now = self.bloom_clock.load(Ordering::Relaxed);
+        for (key, bloom, bytes) in loaded{
+            if db.contains_key(&key) {
+                continue;
+            }
+            self.query_cache.forget(&key);
+            bloom_cache.insert(key.clone(), CachedBloom::new(bloom, bytes, now, self.cached_bloom_bytes.clone()));
+            db.insert(key, Arc::new(Mutex::new(MinuteHandle::new())));
+            n_added += 1;
+        }
+        let evicted = self.evict_blooms(&bloom_cache);
+        if evicted > 0 {
+            tracing::debug!("Let go of {} blooms to stay under the bloom budget of {} bytes", evicted, self.bloom_budget_bytes);
+        }
+
+        Ok((n_removed, n_added))
+    }
+
+    ///
+    /// The blooms of whichever of these minutes are sealed, read by up to BLOOM_LOADERS threads at once
+    /// (at startup, that's every minute in the data directory)
+    ///
+    fn load_blooms(&self, minute_ids: &[MinuteId]) -> Result<Vec<(MinuteId, GrowableBloom, u64)>> {
+        if minute_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let chunk_size = minute_ids.len().div_ceil(BLOOM_LOADERS);
+        let chunks: Vec<Result<Vec<(MinuteId, GrowableBloom, u64)>>> = std::thread::scope(|scope| {
+            let loaders: Vec<_> = minute_ids.chunks(chunk_size).map(|minute_ids| scope.spawn(move || {
+                let mut loaded = Vec::new();
+                for minute_id in minute_ids {
+                    if let Some((bloom, bytes)) = self.load_bloom(minute_id)? {
+                        loaded.push((minute_id.clone(), bloom, bytes));
+                    }
+                }
+                Ok(loaded)
+            })).collect();
+            loaders.into_iter().map(|loader| loader.join().unwrap_or_else(|_| Err(anyhow::anyhow!("bloom loader panicked")))).collect()
+        });
+        let mut loaded = Vec::new();
+        for chunk in chunks {
+            loaded.extend(chunk?);
+        }
+        Ok(loaded)
+    }
+
+    ///
+    /// A minute's bloom, if it's sealed. That's usually just reading the file the writer left next to it when it sealed it
+    /// (see segment::bloom_sidecar_path). A minute that doesn't have one (it was sealed before there were such things,
+    /// or replication copied it over without one) gets opened just long enough to get its bloom, and to leave the file for next time.
+    ///
+    fn load_bloom(&self, minute_id: &MinuteId) -> Result<Option<(GrowableBloom, u64)>> {
+        let path = self.path_for(minute_id);
+        // somebody deleted it between the scan and now: opening it would just make a new, empty minute
+        if std::fs::metadata(&path).is_err() {
+            return Ok(None);
+        }
+        // not there (the minute isn't sealed, or it's an old one), or not all there (we crashed writing it): the minute has the real thing
+        if let Ok(loaded) = crate::segment::read_bloom_sidecar(&path) {
+            return Ok(Some(loaded));
+        }
+
+        // look before we open it for real: the writer still has unsealed minutes open, and opening one for reading
+        //  means fiddling with its journal, which has to wait for the writer to let go
+        let sealed = self.segment_format.open_unsealed(minute_id, &self.data_directory)
+            .and_then(|minute| minute.is_sealed());
+        match sealed{
+            Ok(true) => {},
+            Ok(false) => {
+                // this minute isn't sealed yet, so we shouldn't read it
+                return Ok(None);
+            },
+            Err(e) => {
+                tracing::error!("Error checking if minute is sealed: {:?}", e);
+            }
+        }
+        let bloom = self.segment_format.open(minute_id, &self.data_directory)?.bloom()?;
+        let bytes = match crate::segment::write_bloom_sidecar(&path, &bloom){
+            Ok(bytes) => bytes,
+            Err(e) => {
+                tracing::error!("Error saving the bloom filter for {}: {:?}", minute_id, e);
+                postcard::to_allocvec(&bloom)?.len() as u64
+            }
+        };
+        Ok(Some((bloom, bytes)))
+    }
+
+    ///
+    /// Catch the db up with a scan of the data directory that started at `started_at`, only touching what changed since the last one:
+    /// files that are gone come out, and files that are new (or have been written to since, like a minute that just got sealed) go in.
+    /// With tens of thousands of minutes, almost every pass is "nothing changed" and costs next to nothing.
+    ///
+    pub fn apply_scan(&self, files: &[crate::catalog::FileInfo], started_at: i64) -> Result<(usize, usize)> {
+        let mut scan_state = self.scan_state.lock().unwrap();
+        let current: HashSet<MinuteId> = files.iter().map(|file| file.to_minute_id()).collect();
+        let removed: Vec<MinuteId> = scan_state.seen.iter().filter(|key| !current.contains(key)).cloned().collect();
+        let added: Vec<MinuteId> = {
+            let db = self.db.read().unwrap();
+            files.iter()
+                // modification times are in whole seconds: a write in the same second as the last scan started could go either way
+                .filter(|file| !scan_state.seen.contains(&file.to_minute_id()) || file.modified_at >= scan_state.started_at - 1)
+                .map(|file| file.to_minute_id())
+                .filter(|key| !db.contains_key(key))
+                .collect()
+        };
+        let changes = self.apply_changes(removed, added)?;
+        // only once that's worked: if it didn't, the next pass tries all of it again
+        scan_state.seen = current;
+        scan_state.started_at = started_at;
+        Ok(changes)
+    }
+
+    ///
+    /// Catch the db up with minutes the watcher says changed (see watch::MinuteWatcher), without looking at any others:
+    /// the ones that are gone come out, and the ones that are there go in, if they're sealed. (removed, added)
+    ///
+    pub fn apply_watched(&self, changed: Vec<MinuteId>) -> Result<(usize, usize)> {
+        let (removed, present): (Vec<MinuteId>, Vec<MinuteId>) = changed.into_iter()
+            .partition(|minute_id| std::fs::metadata(self.path_for(minute_id)).is_err());
+        let added = {
+            let db = self.db.read().unwrap();
+            present.into_iter().filter(|minute_id| !db.contains_key(minute_id)).collect()
+        };
+        self.apply_changes(removed, added)
+    }
+
+    ///
+    /// Remove every minute between `from` and `to` (seconds since the epoch, minute granularity, like search).
+    /// The minutes come out of the db first and the files go second, all while holding the db locks,
+    /// so the read loop never sees a minute that's half gone.
+    /// Minutes from the last couple of minutes are left alone: the writer might still have them open.
+    ///
+    pub fn delete_range(&self, from: i64, to: i64, archive: bool, now: i64) -> Result<DeleteReport> {
+        let archiver = match (archive, &self.archiver){
+            (false, _) => None,
+            (true, Some(archiver)) => Some(archiver.clone()),
+            (true, None) => return Err(anyhow::anyhow!("Can't archive: no archive is configured")),
+        };
+
+        let mut db = self.db.write().unwrap();
+        let mut bloom_cache = self.bloom_cache.write().unwrap();
+
+        let mut report = DeleteReport::default();
+        for file in crate::catalog::FileInfo::scan(&self.data_directory)? {
+            let minute_id = file.to_minute_id();
+            let timestamp = minute_id.to_timestamp();
+            if timestamp + 60 <= from || timestamp > to {
+                continue;
+            }
+            if timestamp > now - 120 {
+                report.skipped += 1;
+                continue;
+            }
+
+            db.remove(&minute_id);
+            bloom_cache.remove(&minute_id);
+            self.query_cache.forget(&minute_id);
+
+            let path = self.path_for(&minute_id);
+            if let Some(archiver) = &archiver {
+                archiver.archive(&file, &path)?;
+                report.archived += 1;
+            }
+            std::fs::remove_file(&path)?;
+            for suffix in ["-wal", "-shm", "-bloom"] {
+                let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+            }
+            report.deleted += 1;
+            report.bytes += file.size_bytes;
+        }
+
+        tracing::info!("Deleted {} minutes ({} archived, {} skipped, {} bytes)", report.deleted, report.archived, report.skipped, report.bytes);
+        Ok(report)
+    }
+
+    pub async fn delete_range_async(&self, from: i64, to: i64, archive: bool, now: i64) -> Result<DeleteReport> {
+        let self_clone = self.clone();
+        tokio::task::spawn_blocking(move || {
+            self_clone.delete_range(from, to, archive, now)
+        }).await?
+    }
+
+    ///
+    /// Take every log matching `search` out of the sealed minutes between `from` and `to` (seconds since the epoch, minute
+    /// granularity, like search), for when a secret ended up in the logs: see Minute::redact.
+    /// Only the minutes here: whatever's already been archived (or copied to a standby) keeps its copy.
+    ///
+    pub fn redact(&self, search: crate::search_token::Search, from: i64, to: i64) -> Result<RedactReport> {
+        let (sealed, skipped) = self.sealed_in_range(from, to)?;
+        let mut report = RedactReport{
+            skipped,
+            ..Default::default()
+        };
+
+        for minute_id in sealed {
+            // deleted while we weren't looking
+            let Some(bloom) = self.bloom_for(&minute_id)? else {
+                continue;
+            };
+            if !search.bloom_test(&bloom) {
+                continue;
+            }
+            report.checked += 1;
+            let Some(handle) = self.db.read().unwrap().get(&minute_id).cloned() else {
+                continue;
+            };
+
+            let redacted = {
+                // searches wait for us, and the connection they had goes: the redaction needs the minute to itself
+                let mut handle = handle.lock().map_err(|_| anyhow::anyhow!("Error locking minute"))?;
+                handle.minute = None;
+                self.segment_format.open(&minute_id, &self.data_directory)?.redact(&search)?
+            };
+            if redacted == 0 {
+                continue;
+            }
+            report.minutes += 1;
+            report.logs += redacted;
+
+            // the old bloom still says yes to everything it should, but it says yes to what's gone, too
+            let (bloom, bytes) = crate::segment::read_bloom_sidecar(&self.path_for(&minute_id))?;
+            let mut bloom_cache = self.bloom_cache.write().unwrap();
+            if bloom_cache.contains_key(&minute_id) {
+                bloom_cache.insert(minute_id.clone(), CachedBloom::new(bloom, bytes, self.bloom_clock.load(Ordering::Relaxed), self.cached_bloom_bytes.clone()));
+            }
+            drop(bloom_cache);
+            self.query_cache.forget(&minute_id);
+        }
+
+        tracing::info!("Redacted {} logs from {} minutes ({} checked, {} not sealed yet)", report.logs, report.minutes, report.checked, report.skipped);
+        Ok(report)
+    }
+
+    pub async fn redact_async(&self, search: crate::search_token::Search, from: i64, to: i64) -> Result<RedactReport> {
+        let self_clone = self.clone();
+        tokio::task::spawn_blocking(move || {
+            self_clone.redact(search, from, to)
+        }).await?
+    }
+
+    ///
+    /// The sealed minutes from `from` to `to` (seconds since the epoch, minute granularity), oldest first,
+    /// and how many minutes in that range are still waiting to be sealed
+    ///
+    fn sealed_in_range(&self, from: i64, to: i64) -> Result<(Vec<MinuteId>, usize)> {
+        let in_range = |minute_id: &MinuteId| {
+            let timestamp = minute_id.to_timestamp();
+            timestamp + 60 > from && timestamp <= to
+        };
+        let sealed: Vec<MinuteId> = self.bloom_cache.read().unwrap().keys().filter(|minute_id| in_range(minute_id)).cloned().collect();
+        let unsealed = crate::catalog::FileInfo::scan(&self.data_directory)?.iter()
+            .map(|file| file.to_minute_id())
+            .filter(|minute_id| in_range(minute_id) && sealed.binary_search(minute_id).is_err())
+            .count();
+        Ok((sealed, unsealed))
+    }
+
+    ///
+    /// A sealed minute's bloom, off disk if it's been evicted: None if the minute's gone
+    ///
+    fn bloom_for(&self, minute_id: &MinuteId) -> Result<Option<Arc<GrowableBloom>>> {
+        let cached = self.bloom_cache.read().unwrap().get(minute_id).map(|cached| cached.get());
+        match cached{
+            Some(Some(bloom)) => Ok(Some(bloom)),
+            Some(None) => Ok(Some(Arc::new(self.reload_bloom(minute_id)?))),
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// Every log matching `search` in the sealed minutes from `from` to `to` (seconds since the epoch), into `export`
+    /// a minute at a time, oldest first. Like redact, minutes that aren't sealed yet are skipped (and counted).
+    ///
+    pub fn export<W: std::io::Write + Send>(&self, search: &crate::search_token::Search, from: i64, to: i64, export: &mut crate::parquet_export::ParquetExport<W>) -> Result<()> {
+        let (sealed, skipped) = self.sealed_in_range(from, to)?;
+        for _ in 0..skipped {
+            export.skip_minute();
+        }
+        for minute_id in sealed {
+            let Some(bloom) = self.bloom_for(&minute_id)? else {
+                continue;
+            };
+            if !search.bloom_test(&bloom) {
+                continue;
+            }
+            let Some(handle) = self.db.read().unwrap().get(&minute_id).cloned() else {
+                continue;
+            };
+            let logs = self.with_minute(&minute_id, &handle, |minute| minute.search(search, usize::MAX, SortOrder::Ascending, Deadline::never()))?;
+            if let Some(logs) = logs.filter(|logs| !logs.is_empty()) {
+                export.write_minute(logs)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// export, as a Parquet file that goes out a chunk at a time while it's being written (see ChannelWriter).
+    /// If something goes wrong halfway through, the file just stops: without its footer, nothing will read it.
+    ///
+    pub fn export_stream(&self, search: crate::search_token::Search, from: i64, to: i64, columns: &[String]) -> Result<tokio::sync::mpsc::Receiver<Vec<u8>>> {
+        let (sender, receiver) = tokio::sync::mpsc::channel(EXPORT_STREAM_CHUNKS);
+        let mut export = crate::parquet_export::ParquetExport::new(crate::parquet_export::ChannelWriter::new(sender), columns)?;
+        let self_clone = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let exported = self_clone.export(&search, from, to, &mut export).and_then(|_| {
+                let (mut writer, report) = export.finish()?;
+                std::io::Write::flush(&mut writer)?;
+                Ok(report)
+            });
+            match exported{
+                Ok(report) => tracing::info!("Exported {} logs from {} minutes ({} not sealed yet)", report.logs, report.minutes, report.skipped),
+                Err(err) => tracing::error!("Error exporting: {:?}", err),
+            }
+        });
+        Ok(receiver)
+    }
+
+    ///
+    /// Enforce retention right now, instead of waiting for the read loop to get around to it,
+    /// and take whatever it deleted out of the db on the way out
+    ///
+    pub fn sweep_retention(&self) -> Result<RetentionReport> {
+        let mut files = crate::catalog::FileInfo::scan(&self.data_directory)?;
+        let deleted = {
+            let _sweeping = self.retention_lock.lock().unwrap();
+            crate::catalog::FileInfo::enforce_retention(&self.data_directory, &mut files, &self.retention, self.archiver.as_deref())?
+        };
+        // new minutes can wait for the read loop, like always
+        self.apply_changes(deleted.iter().map(|file| file.to_minute_id()).collect(), Vec::new())?;
+
+        let report = RetentionReport{
+            deleted: deleted.len(),
+            archived: if self.archiver.is_some() { deleted.len() } else { 0 },
+            bytes: deleted.iter().map(|file| file.size_bytes).sum(),
+            kept: files.len(),
+            kept_bytes: files.iter().map(|file| file.size_bytes).sum(),
+        };
+        tracing::info!("Retention sweep deleted {} minutes ({} bytes), kept {} ({} bytes)", report.deleted, report.bytes, report.kept, report.kept_bytes);
+        Ok(report)
+    }
+
+    pub async fn sweep_retention_async(&self) -> Result<RetentionReport> {
+        let self_clone = self.clone();
+        tokio::task::spawn_blocking(move || {
+            self_clone.sweep_retention()
+        }).await?
+    }
+
+    ///
+    /// Every minute file between `from` and `to` (seconds since the epoch, minute granularity, like search), sealed or not,
+    /// newest first: the first `limit` of them, and how many there are altogether
+    ///
+    pub fn list_minutes(&self, from: Option<i64>, to: Option<i64>, limit: usize) -> Result<MinuteListing> {
+        let mut files: Vec<crate::catalog::FileInfo> = crate::catalog::FileInfo::scan(&self.data_directory)?.into_iter()
+            .filter(|file| from.is_none_or(|from| file.sort_key + 60 > from) && to.is_none_or(|to| file.sort_key <= to))
+            .collect();
+        files.sort_by(|a, b| (b.sort_key, &b.unique_id).cmp(&(a.sort_key, &a.unique_id)));
+
+        let bloom_cache = self.bloom_cache.read().unwrap();
+        let mut listing = MinuteListing{
+            total: files.len(),
+            total_bytes: files.iter().map(|file| file.size_bytes).sum(),
+            ..Default::default()
+        };
+        for file in files.into_iter().take(limit) {
+            let minute_id = file.to_minute_id();
+            let cached = bloom_cache.get(&minute_id);
+            // sealed minutes the read loop hasn't got to yet already have their bloom written out next to them
+            let sealed = cached.is_some() || std::fs::metadata(crate::segment::bloom_sidecar_path(&self.path_for(&minute_id))).is_ok();
+            listing.minutes.push(MinuteInfo{
+                minute: minute_id.to_string(),
+                time: file.sort_key,
+                size_bytes: file.size_bytes,
+                modified_at: file.modified_at,
+                sealed,
+                bloom_in_memory: cached.is_some_and(|cached| cached.get().is_some()),
+            });
+        }
+        Ok(listing)
+    }
+
+    ///
+    /// The summary every sealed minute from `from` to `to` (seconds since the epoch, both optional) wrote down when it
+    /// was sealed, newest first: one row out of each minute, rather than a look at all its logs
+    ///
+    pub fn minute_summaries(&self, from: Option<i64>, to: Option<i64>, limit: usize) -> Result<SummaryListing> {
+        let (sealed, unsealed) = self.sealed_in_range(from.unwrap_or(0), to.unwrap_or(i64::MAX))?;
+        let db = self.db.read().unwrap();
+        let mut listing = SummaryListing{
+            without_summary: unsealed,
+            ..Default::default()
+        };
+        for minute_id in sealed.iter().rev() {
+            let summary = match db.get(minute_id){
+                Some(handle) => self.with_minute(minute_id, handle, |minute| minute.summary())?.flatten(),
+                None => None,
+            };
+            let Some(summary) = summary else {
+                listing.without_summary += 1;
+                continue;
+            };
+            listing.events += summary.events;
+            listing.bytes += summary.bytes;
+            listing.bloom_bytes += summary.bloom_bytes;
+            if listing.minutes.len() < limit {
+                listing.minutes.push(summary);
+            }
+        }
+        Ok(listing)
+    }
+
+    pub async fn minute_summaries_async(&self, from: Option<i64>, to: Option<i64>, limit: usize) -> Result<SummaryListing>{
+        let self_clone = self.clone();
+        tokio::task::spawn_blocking(move || {
+            self_clone.minute_summaries(from, to, limit)
+        }).await?
+    }
+
+    ///
+    /// One log, by its event id (see minute_id::EventId): None if its minute isn't here (any more), or it isn't in its minute.
+    /// Sealed minutes first, then the archive, then minutes the writer's still writing.
+    ///
+    pub fn get(&self, event_id: &EventId) -> Result<Option<Log>> {
+        let minute_id = &event_id.minute_id;
+        if let Some(handle) = self.db.read().unwrap().get(minute_id) {
+            return Ok(self.with_minute(minute_id, handle, |minute| minute.get(event_id.id))?.flatten());
+        }
+        if let Some(rehydrator) = &self.rehydrator {
+            if !rehydrator.archived_in_range(Bound::Included(minute_id.clone()), Bound::Included(minute_id.clone())).is_empty() {
+                return rehydrator.open(minute_id)?.get(event_id.id);
+            }
+        }
+        // open_unsealed won't make a minute that isn't there, so an error here mostly means there's no such minute
+        match self.segment_format.open_unsealed(minute_id, &self.data_directory){
+            Ok(minute) => minute.get(event_id.id),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub async fn get_async(&self, event_id: EventId) -> Result<Option<Log>>{
+        let self_clone = self.clone();
+        tokio::task::spawn_blocking(move || {
+            self_clone.get(&event_id)
+        }).await?
+    }
+
+    ///
+    /// How the minutes on disk and the blooms in memory measure up against what they're allowed
+    ///
+    pub fn budget(&self) -> Result<Budget> {
+        let files = crate::catalog::FileInfo::scan(&self.data_directory)?;
+        Ok(Budget{
+            disk_budget_bytes: self.retention.max_bytes,
+            disk_used_bytes: files.iter().map(|file| file.size_bytes).sum(),
+            disk_free_bytes: fs2::available_space(&self.data_directory).map_err(|e| anyhow::anyhow!("Can't tell how much space is free on {}: {}", self.data_directory, e))?,
+            minutes: files.len(),
+            oldest: files.iter().map(|file| file.sort_key).min(),
+            max_age_seconds: self.retention.max_age_seconds,
+            bloom_memory: self.bloom_memory(),
+        })
+    }
+
+    ///
+    /// Keep the db in step with the data directory, forever. The watcher (see watch::MinuteWatcher) tells us about minutes as they're
+    /// sealed, copied in, or deleted, so they're searchable (or not) within milliseconds; every so often we go over the whole directory anyway,
+    /// to enforce retention and to catch whatever the watcher missed. Without a watcher, that scan is all there is, so it's more often.
+    /// Either way, the scan is a catalog::Catalog refresh: only the hour directories that changed since the last one get read.
+    ///
+    pub fn read_loop(&self){
+        // before the first scan: anything that changes after the scan has looked must show up in the watcher
+        let mut watcher = crate::watch::MinuteWatcher::start(&self.data_directory);
+        let interval = match watcher{
+            Some(_) => Duration::from_secs(60),
+            None => Duration::from_secs(10),
+        };
+        // a new catalog, so the first pass reads everything: after that, it's only the hours that changed
+        let mut catalog = crate::catalog::Catalog::new(&self.data_directory, self.catalog_path.clone());
+
+        loop {
+            crate::chaos::delay(crate::chaos::FaultKind::ReadLoopStall, &self.data_directory);
+
+            // start a timer
+            let now = SystemTime::now();
+
+            // read from disk (only the hours that changed) and insert whatever changed into db
+            let started_at = now.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+            match catalog.refresh(){
+                Ok(mut files) => {
+                    let sweeping = self.retention_lock.lock().unwrap();
+                    if let Err(e) = crate::catalog::FileInfo::enforce_retention(&self.data_directory, &mut files, &self.retention, self.archiver.as_deref()) {
+                        tracing::error!("Error enforcing retention: {:?}", e);
+                    }
+                    drop(sweeping);
+                    match self.apply_scan(&files, started_at){
+                        Ok((0, 0)) => {},
+                        Ok((removed, added)) => {
+                            tracing::info!("MinuteDB update: {} files, {} removed, {} added", files.len(), removed, added);
+                        },
+                        Err(e) => {
+                            tracing::error!("Error updating minute db: {:?}", e);
+                        }
+                    }
+                },
+                Err(e) => {
+                    tracing::error!("Error scanning {}: {:?}", self.data_directory, e);
+                }
+            }
+
+            // how long did that take?
+            let elapsed = now.elapsed().unwrap();
+
+            // if we took too long, just skip the wait
+            if elapsed > interval {
+                tracing::warn!("read thread took too long: {} us", elapsed.as_micros());
+                continue;
+            }
+            let Some(watcher) = watcher.as_mut() else {
+                std::thread::sleep(interval - elapsed);
+                continue;
+            };
+            // until it's time for the next scan, keep up with whatever the watcher sees
+            loop {
+                let remaining = interval.saturating_sub(now.elapsed().unwrap_or(interval));
+                if remaining.is_zero() {
+                    break;
+                }
+                crate::chaos::delay(crate::chaos::FaultKind::ReadLoopStall, &self.data_directory);
+                match watcher.wait(remaining).and_then(|changed| self.apply_watched(changed)){
+                    Ok((0, 0)) => {},
+                    Ok((removed, added)) => {
+                        tracing::info!("MinuteDB update: {} removed, {} added", removed, added);
+                    },
+                    Err(e) => {
+                        // we've lost track of something: the scan will find it
+                        tracing::warn!("Error watching for new minutes: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_apply_scan() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("apply_scan");
+    let mut test_data_source = crate::minute::TestData::new();
+    let mut sealed = Minute::new(1, 2, 3, "sealed", &data_directory, true)?;
+    sealed.write_second(vec![crate::minute::generate_test_data(&mut test_data_source)])?;
+    sealed.seal()?;
+    let mut unsealed = Minute::new(1, 2, 4, "unsealed", &data_directory, true)?;
+    unsealed.write_second(vec![crate::minute::generate_test_data(&mut test_data_source)])?;
+    // the writers are done with them
+    drop(sealed);
+    drop(unsealed);
+
+    let minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, u64::MAX, None), None, None);
+    let mut files = crate::catalog::FileInfo::scan(&data_directory)?;
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+    assert_eq!(minute_db.apply_scan(&files, now)?, (0, 1));
+
+    // the unsealed one's been written to since the last scan started, so it gets another look (and it's still unsealed)
+    assert_eq!(minute_db.apply_scan(&files, now + 100)?, (0, 0));
+    // and after that, it's left alone until it changes
+    Minute::new(1, 2, 4, "unsealed", &data_directory, true)?.seal()?;
+    assert_eq!(minute_db.apply_scan(&files, now + 200)?, (0, 0));
+    assert_eq!(minute_db.db.read().unwrap().len(), 1);
+
+    // sealing it wrote to it
+    for file in files.iter_mut() {
+        file.modified_at = now + 250;
+    }
+    assert_eq!(minute_db.apply_scan(&files, now + 300)?, (0, 1));
+
+    // a file that's gone comes out
+    files.retain(|file| file.unique_id != "sealed");
+    assert_eq!(minute_db.apply_scan(&files, now + 400)?, (1, 0));
+    assert_eq!(minute_db.db.read().unwrap().keys().cloned().collect::<Vec<MinuteId>>(), vec![MinuteId::new(1, 2, 4, "unsealed")]);
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_apply_watched() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("apply_watched");
+    let mut test_data_source = crate::minute::TestData::new();
+    let mut sealed = Minute::new(1, 2, 3, "sealed", &data_directory, true)?;
+    sealed.write_second(vec![crate::minute::generate_test_data(&mut test_data_source)])?;
+    sealed.seal()?;
+    drop(sealed);
+    drop(Minute::new(1, 2, 4, "unsealed", &data_directory, true)?);
+
+    let minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, u64::MAX, None), None, None);
+    let sealed = MinuteId::new(1, 2, 3, "sealed");
+    let unsealed = MinuteId::new(1, 2, 4, "unsealed");
+    assert_eq!(minute_db.apply_watched(vec![sealed.clone(), unsealed.clone()])?, (0, 1));
+    // again: it's already in
+    assert_eq!(minute_db.apply_watched(vec![sealed.clone()])?, (0, 0));
+
+    std::fs::remove_file(minute_db.path_for(&sealed))?;
+    assert_eq!(minute_db.apply_watched(vec![sealed])?, (1, 0));
+    assert!(minute_db.sealed_minutes().is_empty());
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_search_unsealed() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("search_unsealed");
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+    let minute_id = MinuteId::from_timestamp(now);
+    let mut writer = Minute::new(minute_id.day, minute_id.hour, minute_id.minute, "1-0", &data_directory, true)?;
+    writer.write_second(vec![
+        crate::WritableEvent::new("fresh needle", now * 1000000, "localhost"),
+        crate::WritableEvent::new("fresh hay", now * 1000000, "localhost"),
+    ])?;
+
+    let mut minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, u64::MAX, None), None, None);
+    let options = SearchOptions{ from: Some(now - 3600), ..SearchOptions::default() };
+    // nothing's sealed, so without the head there's nothing to see
+    assert!(minute_db.search(crate::search_token::Search::new("needle"), &options)?.is_empty());
+
+    minute_db.set_head_seconds(120);
+    let (logs, stats) = minute_db.search_with_stats(crate::search_token::Search::new("needle"), &options)?;
+    assert_eq!(logs.iter().map(|log| log.message.as_str()).collect::<Vec<&str>>(), vec!["fresh needle"]);
+    assert_eq!((stats.minutes_considered, stats.minutes_unsealed), (1, 1));
+    let (buckets, _) = minute_db.histogram(crate::search_token::Search::new("fresh"), &options, 60)?;
+    assert_eq!(buckets.iter().map(|bucket| bucket.count).sum::<u64>(), 2);
+    let explanation = minute_db.explain(&crate::search_token::Search::new("needle"), &options);
+    assert_eq!((explanation.candidates, explanation.unsealed, explanation.archived), (1, 1, 0));
+    // it still has to be in range
+    assert!(minute_db.search(crate::search_token::Search::new("needle"), &SearchOptions{ to: Some(now - 600), ..options.clone() })?.is_empty());
+
+    // once it's sealed and the read loop has it, it's searched like any other minute (and only once)
+    writer.write_second(vec![crate::WritableEvent::new("another needle", now * 1000000, "localhost")])?;
+    writer.seal()?;
+    drop(writer);
+    minute_db.apply_scan(&crate::catalog::FileInfo::scan(&data_directory)?, now)?;
+    let (logs, stats) = minute_db.search_with_stats(crate::search_token::Search::new("needle"), &options)?;
+    assert_eq!(logs.len(), 2);
+    assert_eq!((stats.minutes_considered, stats.minutes_unsealed), (1, 0));
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_cardinality() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("cardinality");
+    let fields = vec!["host".to_string(), "user".to_string()];
+    // two minutes, two shards in the second one, and users 50..100 turn up in all of them
+    for (minute, shard, users) in [(3, "1-0", 0..100), (4, "1-0", 50..150), (4, "1-1", 50..200)] {
+        let mut writer = Minute::new(1, 2, minute, shard, &data_directory, true)?;
+        writer.set_cardinality_fields(&fields);
+        writer.write_second(users.map(|user| crate::WritableEvent::new(&format!("level=error user={} checkout failed", user), 1, &format!("web-{}", user % 3))).collect())?;
+        writer.seal()?;
+    }
+    // sealed before anybody asked for users
+    let mut old = Minute::new(1, 2, 5, "1-0", &data_directory, true)?;
+    old.write_second(vec![crate::WritableEvent::new("user=999", 1, "web-9")])?;
+    old.seal()?;
+    drop(old);
+
+    let minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, u64::MAX, None), None, None);
+    minute_db.apply_scan(&crate::catalog::FileInfo::scan(&data_directory)?, 0)?;
+
+    let users = minute_db.cardinality("user", &SearchOptions::default())?;
+    assert_eq!((users.minutes, users.minutes_without_sketch), (3, 1));
+    assert!((users.estimate as f64 - 200.0).abs() < 10.0, "{}", users.estimate);
+    let hosts = minute_db.cardinality("host", &SearchOptions::default())?;
+    assert_eq!((hosts.estimate, hosts.minutes), (4, 4));
+    let first_minute = minute_db.cardinality("user", &SearchOptions{ from: Some(MinuteId::new(1, 2, 3, "").to_timestamp()), to: Some(MinuteId::new(1, 2, 3, "").to_timestamp()), ..SearchOptions::default() })?;
+    assert!((first_minute.estimate as f64 - 100.0).abs() < 5.0, "{}", first_minute.estimate);
+    assert_eq!(minute_db.cardinality("nobody", &SearchOptions::default())?.minutes_without_sketch, 4);
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_hosts() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("hosts");
+    for (minute, shard) in [(3, "1-0"), (4, "1-0"), (4, "1-1")] {
+        let mut writer = Minute::new(1, 2, minute, shard, &data_directory, true)?;
+        writer.set_dedup(true);
+        // web-0 says the same thing every time: one log, ten events
+        writer.write_second((0..30).map(|n| crate::WritableEvent::new(&format!("request {}", n / 3 * (n % 3)), 1, &format!("web-{}", n % 3))).collect())?;
+        writer.seal()?;
+    }
+    // sealed before minutes counted their hosts
+    let mut old = Minute::new(1, 2, 5, "1-0", &data_directory, true)?;
+    old.write_second(vec![crate::WritableEvent::new("hello", 1, "db-1")])?;
+    old.seal()?;
+    drop(old);
+    let old = rusqlite::Connection::open(format!("{}/1/2/5-1-0.db", data_directory))?;
+    old.execute("DELETE FROM host_count", [])?;
+    old.pragma_update(None, "user_version", 4)?;
+    drop(old);
+
+    let minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, u64::MAX, None), None, None);
+    minute_db.apply_scan(&crate::catalog::FileInfo::scan(&data_directory)?, 0)?;
+
+    let hosts = minute_db.hosts(&SearchOptions::default())?;
+    assert_eq!((hosts.minutes, hosts.minutes_skipped), (4, 0));
+    assert_eq!(hosts.hosts.iter().map(|count| (count.host.as_str(), count.events)).collect::<Vec<_>>(),
+        vec![("web-0", 30), ("web-1", 30), ("web-2", 30), ("db-1", 1)]);
+    let first_minute = MinuteId::new(1, 2, 3, "").to_timestamp();
+    let hosts = minute_db.hosts(&SearchOptions{ from: Some(first_minute), to: Some(first_minute), ..SearchOptions::default() })?;
+    assert_eq!(hosts.hosts.iter().map(|count| count.events).sum::<u64>(), 30);
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_minute_summaries() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("minute_summaries");
+    for minute in 3..6 {
+        let mut writer = Minute::new(1, 2, minute, "1-0", &data_directory, true)?;
+        writer.write_second((0..100).map(|n| crate::WritableEvent::new(&format!("request {}", n), n, &format!("web-{}", n % 2))).collect())?;
+        writer.seal()?;
+    }
+    // sealed before minutes had summaries
+    let old = rusqlite::Connection::open(format!("{}/1/2/3-1-0.db", data_directory))?;
+    old.execute("DELETE FROM summary", [])?;
+    drop(old);
+    Minute::new(1, 2, 6, "1-0", &data_directory, true)?.write_second(vec![crate::WritableEvent::new("not sealed", 1, "web-0")])?;
+
+    let minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, u64::MAX, None), None, None);
+    minute_db.apply_scan(&crate::catalog::FileInfo::scan(&data_directory)?, 0)?;
+
+    let summaries = minute_db.minute_summaries(None, None, 1)?;
+    assert_eq!((summaries.minutes.len(), summaries.events, summaries.without_summary), (1, 200, 2));
+    let newest = &summaries.minutes[0];
+    assert_eq!(newest.minute, MinuteId::new(1, 2, 5, "1-0").to_string());
+    assert_eq!(newest.hosts, vec!["web-0", "web-1"]);
+    assert_eq!((newest.min_host_time, newest.max_host_time), (Some(0), Some(99)));
+    assert!(newest.fragments > 0 && newest.bloom_bytes > 0);
+    assert_eq!(newest.bytes, std::fs::metadata(format!("{}/1/2/5-1-0.db", data_directory))?.len());
+    assert_eq!(summaries.bytes, newest.bytes * 2);
+    let first_minute = MinuteId::new(1, 2, 4, "").to_timestamp();
+    assert_eq!(minute_db.minute_summaries(Some(first_minute), Some(first_minute), 10)?.minutes.len(), 1);
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_get_by_event_id() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("get_by_event_id");
+    for shard in ["1-0", "1-1"] {
+        let mut writer = Minute::new(1, 2, 3, shard, &data_directory, true)?;
+        writer.set_dedup(true);
+        // the same message three times is one log with three occurrences
+        writer.write_second(vec![
+            crate::WritableEvent::new(&format!("hello from {}", shard), 10, "web-1"),
+            crate::WritableEvent::new("again", 11, "web-1"),
+            crate::WritableEvent::new("again", 12, "web-1"),
+            crate::WritableEvent::new("again", 13, "web-1"),
+        ])?;
+        writer.seal()?;
+    }
+    Minute::new(1, 2, 4, "1-0", &data_directory, true)?.write_second(vec![crate::WritableEvent::new("not sealed", 14, "web-2")])?;
+
+    let minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, u64::MAX, None), None, None);
+    minute_db.apply_scan(&crate::catalog::FileInfo::scan(&data_directory)?, 0)?;
+
+    let mut results = minute_db.search(crate::search_token::Search::new("hello"), &SearchOptions::default())?;
+    results.extend(minute_db.search(crate::search_token::Search::new("again"), &SearchOptions::default())?);
+    assert_eq!(results.len(), 8);
+    let event_ids: HashSet<String> = results.iter().filter_map(|log| log.event_id.clone()).collect();
+    assert_eq!(event_ids.len(), 8);
+    for log in &results {
+        let found = minute_db.get(&EventId::from_string(log.event_id.as_deref().unwrap())?)?.unwrap();
+        assert_eq!((found.id, &found.message, found.time, &found.host, &found.shard), (log.id, &log.message, log.time, &log.host, &log.shard));
+    }
+
+    let unsealed = minute_db.get(&EventId::new(MinuteId::new(1, 2, 4, "1-0"), 0))?;
+    assert_eq!(unsealed, None);
+    let unsealed_id = rusqlite::Connection::open(format!("{}/1/2/4-1-0.db", data_directory))?.query_row("SELECT id FROM log", [], |row| row.get(0))?;
+    assert_eq!(minute_db.get(&EventId::new(MinuteId::new(1, 2, 4, "1-0"), unsealed_id))?.map(|log| log.message), Some("not sealed".to_string()));
+    assert_eq!(minute_db.get(&EventId::new(MinuteId::new(1, 2, 5, "1-0"), unsealed_id))?, None);
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_close_idle_minutes() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("close_idle");
+    {
+        let mut minute = Minute::new(1, 2, 3, "idle", &data_directory, true)?;
+        let mut test_data_source = crate::minute::TestData::new();
+        let mut test_data = Vec::new();
+        for _ in 0..1000 {
+            test_data.push(crate::minute::generate_test_data(&mut test_data_source));
+        }
+        minute.write_second(test_data)?;
+        minute.seal()?;
+    }
+
+    let minute_db = MinuteDB::new(data_directory, crate::retention::RetentionPolicy::new(10, 1000000000, None), None, None);
+    let mut minutes = HashSet::new();
+    minutes.insert(MinuteId::new(1, 2, 3, "idle"));
+    minute_db.update(minutes)?;
+
+    // nothing's open until somebody searches it
+    assert_eq!(minute_db.close_idle(Duration::ZERO), 0);
+    let results = minute_db.search(crate::search_token::Search::new("presence"), &SearchOptions::default())?;
+    assert!(!results.is_empty());
+    assert_eq!(minute_db.close_idle(Duration::from_secs(3600)), 0);
+    assert_eq!(minute_db.close_idle(Duration::ZERO), 1);
+    assert_eq!(minute_db.close_idle(Duration::ZERO), 0);
+
+    // closed minutes get reopened when somebody searches them
+    let results = minute_db.search(crate::search_token::Search::new("presence"), &SearchOptions::default())?;
+    assert!(!results.is_empty());
+    assert_eq!(minute_db.close_idle(Duration::ZERO), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_search_rehydrates_archived_minutes() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("search_rehydrated");
+    {
+        let mut minute = Minute::new(1, 2, 3, "archived", &data_directory, true)?;
+        let mut test_data_source = crate::minute::TestData::new();
+        let mut test_data = Vec::new();
+        for _ in 0..1000 {
+            test_data.push(crate::minute::generate_test_data(&mut test_data_source));
+        }
+        minute.write_second(test_data)?;
+        minute.seal()?;
+    }
+    let store = crate::archive::DirectoryStore::new(&format!("{}-bucket", data_directory));
+    let archiver = Arc::new(crate::archive::Archiver::new(Box::new(store), &format!("{}-archive", data_directory))?);
+    for file in crate::catalog::FileInfo::scan(&data_directory)? {
+        let path = format!("{}{}", data_directory, file.path);
+        archiver.archive(&file, &path)?;
+        std::fs::remove_file(path)?;
+    }
+    let rehydrator = Arc::new(crate::rehydrate::Rehydrator::new(archiver.clone(), &format!("{}-scratch", data_directory), 1000000000)?);
+    let minute_db = MinuteDB::new(data_directory, crate::retention::RetentionPolicy::new(10, 1000000000, None), Some(archiver), Some(rehydrator));
+
+    // no time range, no digging through the archive
+    let (results, stats) = minute_db.search_with_stats(crate::search_token::Search::new("presence"), &SearchOptions::default())?;
+    assert!(results.is_empty());
+    assert_eq!(stats.minutes_rehydrated, 0);
+
+    let minute_start = MinuteId::new(1, 2, 3, "").to_timestamp();
+    let options = SearchOptions{
+        from: Some(minute_start),
+        to: Some(minute_start),
+        ..Default::default()
+    };
+    let (results, stats) = minute_db.search_with_stats(crate::search_token::Search::new("presence"), &options)?;
+    assert!(!results.is_empty());
+    assert_eq!(stats.minutes_rehydrated, 1);
+    assert_eq!(stats.minutes_scanned, 1);
+
+    // a range that doesn't cover the archived minute leaves it alone
+    let options = SearchOptions{
+        from: Some(minute_start + 60),
+        ..Default::default()
+    };
+    let (_, stats) = minute_db.search_with_stats(crate::search_token::Search::new("presence"), &options)?;
+    assert_eq!(stats.minutes_rehydrated, 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_retention_warning() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("retention_warning");
+    for minute in [3, 5] {
+        let mut minute = Minute::new(1, 2, minute, "1-0", &data_directory, true)?;
+        minute.write_second(vec![crate::WritableEvent::new("still here", 0, "localhost")])?;
+        minute.seal()?;
+    }
+    // minute 3 is only in the archive now
+    let store = crate::archive::DirectoryStore::new(&format!("{}-bucket", data_directory));
+    let archiver = Arc::new(crate::archive::Archiver::new(Box::new(store), &format!("{}-archive", data_directory))?);
+    for file in crate::catalog::FileInfo::scan(&data_directory)? {
+        if file.to_minute_id().minute == 3 {
+            let path = format!("{}{}", data_directory, file.path);
+            archiver.archive(&file, &path)?;
+            std::fs::remove_file(path)?;
+        }
+    }
+    let mut minutes = HashSet::new();
+    minutes.insert(MinuteId::new(1, 2, 5, "1-0"));
+    let minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, 1000000000, None), Some(archiver.clone()), None);
+    minute_db.update(minutes.clone())?;
+
+    let minute = |minute: u32| MinuteId::new(1, 2, minute, "").to_timestamp();
+    let search = |minute_db: &MinuteDB, from: Option<i64>, to: Option<i64>| -> Result<Option<RetentionWarning>> {
+        let options = SearchOptions{ from, to, ..SearchOptions::default() };
+        Ok(minute_db.search_with_stats(crate::search_token::Search::new("still"), &options)?.1.retention)
+    };
+
+    // starting anywhere in the oldest minute we've got is fine, and so is not saying where to start
+    assert_eq!(search(&minute_db, Some(minute(5) + 30), None)?, None);
+    assert_eq!(search(&minute_db, None, None)?, None);
+
+    let warning = search(&minute_db, Some(minute(0)), None)?.unwrap();
+    assert_eq!((warning.requested_from, warning.retained_from, warning.archived_from, warning.archive_searched), (minute(0), Some(minute(5)), Some(minute(3)), false));
+    assert!(warning.warning.contains("can't search it"), "{}", warning.warning);
+
+    // nothing in the archive for the part of the range that's gone
+    let warning = search(&minute_db, Some(minute(4)), None)?.unwrap();
+    assert_eq!((warning.retained_from, warning.archived_from), (Some(minute(5)), None));
+    let warning = search(&minute_db, Some(minute(0)), Some(minute(2)))?.unwrap();
+    assert_eq!(warning.archived_from, None);
+
+    // a node that can rehydrate searched the archive too
+    let rehydrator = Arc::new(crate::rehydrate::Rehydrator::new(archiver.clone(), &format!("{}-scratch", data_directory), 1000000000)?);
+    let minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, 1000000000, None), Some(archiver), Some(rehydrator));
+    minute_db.update(minutes)?;
+    let warning = search(&minute_db, Some(minute(0)), None)?.unwrap();
+    assert!(warning.archive_searched);
+
+    // an empty node says so
+    let empty = MinuteDB::new(crate::minute::test_data_directory("retention_warning_empty"), crate::retention::RetentionPolicy::new(10, 1000000000, None), None, None);
+    let warning = search(&empty, Some(minute(0)), None)?.unwrap();
+    assert_eq!((warning.retained_from, warning.archived_from), (None, None));
+
+    Ok(())
+}
+
+#[test]
+fn test_delete_range() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("delete_range");
+    for minute in 0..3 {
+        let mut minute = Minute::new(1, 2, minute, "flood", &data_directory, true)?;
+        let mut test_data_source = crate::minute::TestData::new();
+        let mut test_data = Vec::new();
+        for _ in 0..1000 {
+            test_data.push(crate::minute::generate_test_data(&mut test_data_source));
+        }
+        minute.write_second(test_data)?;
+        minute.seal()?;
+    }
+
+    let minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, 1000000000, None), None, None);
+    let all_minutes: HashSet<MinuteId> = (0..3).map(|minute| MinuteId::new(1, 2, minute, "flood")).collect();
+    minute_db.update(all_minutes.clone())?;
+
+    // archiving without an archive is an error, and doesn't touch anything
+    let first = MinuteId::new(1, 2, 0, "").to_timestamp();
+    assert!(minute_db.delete_range(first, first + 60, true, first + 3600).is_err());
+
+    // the minute containing `to` is in range; the minute after it isn't
+    let report = minute_db.delete_range(first, first + 60, false, first + 3600)?;
+    assert_eq!(report.deleted, 2);
+    assert_eq!(report.skipped, 0);
+    assert!(std::fs::metadata(format!("{}/1/2/0-flood.db", data_directory)).is_err());
+    assert!(std::fs::metadata(format!("{}/1/2/2-flood.db", data_directory)).is_ok());
+
+    // a scan from before the delete doesn't bring the deleted minutes back
+    minute_db.update(all_minutes)?;
+    assert!(std::fs::metadata(format!("{}/1/2/0-flood.db", data_directory)).is_err());
+    let (_, stats) = minute_db.search_with_stats(crate::search_token::Search::new("presence"), &SearchOptions::default())?;
+    assert_eq!(stats.minutes_considered, 1);
+
+    // too recent to delete
+    let report = minute_db.delete_range(first, first + 3600, false, first + 180)?;
+    assert_eq!(report.deleted, 0);
+    assert_eq!(report.skipped, 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_redact_range() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("redact_range");
+    let event = |message: &str| crate::WritableEvent{ event: message.to_string(), time: 1, host: "localhost".to_string() };
+    for minute in 0..3 {
+        let mut minute_file = Minute::new(1, 2, minute, "redact", &data_directory, true)?;
+        minute_file.write_second(vec![event("password=hunter2 oops"), event("all good here")])?;
+        // the last one's still being written
+        if minute < 2 {
+            minute_file.seal()?;
+        }
+    }
+    let minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, 1000000000, None), None, None);
+    minute_db.update((0..2).map(|minute| MinuteId::new(1, 2, minute, "redact")).collect())?;
+    let search = |query: &str| -> Result<usize> {
+        Ok(minute_db.search(crate::search_token::Search::new(query), &SearchOptions::default())?.len())
+    };
+    assert_eq!(search("hunter2")?, 2);
+
+    let first = MinuteId::new(1, 2, 0, "").to_timestamp();
+    let report = minute_db.redact(crate::search_token::Search::new("hunter2"), first, first + 180)?;
+    assert_eq!(report, RedactReport{ checked: 2, minutes: 2, logs: 2, skipped: 1 });
+    assert_eq!(search("hunter2")?, 0);
+    assert_eq!(search("good")?, 2);
+    // the blooms in memory are the new ones: nothing gets past them
+    let (_, stats) = minute_db.search_with_stats(crate::search_token::Search::new("hunter2"), &SearchOptions::default())?;
+    assert_eq!(stats.minutes_scanned, 0);
+    assert_eq!(minute_db.redact(crate::search_token::Search::new("hunter2"), first, first + 180)?.checked, 0);
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_export() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("export");
+    let event = |message: &str, time: i64| crate::WritableEvent{ event: message.to_string(), time, host: "localhost".to_string() };
+    for minute in 0..3 {
+        let mut minute_file = Minute::new(1, 2, minute, "export", &data_directory, true)?;
+        minute_file.write_second(vec![event("status=500 path=/checkout", 2), event("status=200 path=/", 1)])?;
+        // the last one's still being written
+        if minute < 2 {
+            minute_file.seal()?;
+        }
+    }
+    let minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, 1000000000, None), None, None);
+    minute_db.update((0..2).map(|minute| MinuteId::new(1, 2, minute, "export")).collect())?;
+    let first = MinuteId::new(1, 2, 0, "").to_timestamp();
+    let path = format!("{}/export.parquet", data_directory);
+    let export = |query: &str, from: i64, to: i64| -> Result<crate::parquet_export::ExportReport> {
+        let mut export = crate::parquet_export::ParquetExport::new(std::fs::File::create(&path)?, &["status".to_string()])?;
+        minute_db.export(&crate::search_token::Search::new(query), from, to, &mut export)?;
+        Ok(export.finish()?.1)
+    };
+
+    assert_eq!(export("", first, first + 180)?, crate::parquet_export::ExportReport{ minutes: 2, logs: 4, skipped: 1 });
+    let reader = parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path)?)?;
+    assert_eq!(reader.metadata().num_row_groups(), 2);
+    let batches = reader.build()?.collect::<std::result::Result<Vec<arrow_array::RecordBatch>, _>>()?;
+    let statuses = batches[0].column_by_name("status").unwrap().as_any().downcast_ref::<arrow_array::StringArray>().unwrap();
+    assert_eq!((statuses.value(0), statuses.value(1)), ("200", "500"));
+
+    // just what matches, just in range
+    assert_eq!(export("status=500", first, first + 30)?, crate::parquet_export::ExportReport{ minutes: 1, logs: 1, skipped: 0 });
+    assert_eq!(export("nothing-like-this", first, first + 180)?.logs, 0);
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_minute_admin() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("minute_admin");
+    for minute in 0..4 {
+        let mut minute_file = Minute::new(1, 2, minute, "admin", &data_directory, true)?;
+        let mut test_data_source = crate::minute::TestData::new();
+        minute_file.write_second((0..100).map(|_| crate::minute::generate_test_data(&mut test_data_source)).collect())?;
+        // the last one's still being written
+        if minute < 3 {
+            minute_file.seal()?;
+        }
+    }
+    let minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(2, 1000000000, None), None, None);
+    minute_db.update((0..2).map(|minute| MinuteId::new(1, 2, minute, "admin")).collect())?;
+
+    // newest first, sealed or not, and whether the read loop's got to them
+    let listing = minute_db.list_minutes(None, None, 3)?;
+    assert_eq!(listing.total, 4);
+    assert_eq!(listing.minutes.len(), 3);
+    assert_eq!(listing.minutes[0].minute, MinuteId::new(1, 2, 3, "admin").to_string());
+    assert!(!listing.minutes[0].sealed);
+    assert!(listing.minutes[1].sealed && !listing.minutes[1].bloom_in_memory);
+    assert!(listing.minutes[2].sealed && listing.minutes[2].bloom_in_memory);
+    assert!(listing.minutes.iter().all(|minute| minute.size_bytes > 0));
+    let first = MinuteId::new(1, 2, 0, "").to_timestamp();
+    assert_eq!(minute_db.list_minutes(Some(first), Some(first + 60), 1000)?.total, 2);
+
+    let budget = minute_db.budget()?;
+    assert_eq!(budget.minutes, 4);
+    assert_eq!(budget.disk_used_bytes, listing.total_bytes);
+    assert_eq!(budget.oldest, Some(first));
+    assert_eq!(budget.bloom_memory.cached, 2);
+
+    // only two minutes are allowed: the oldest ones go, and out of the db with them
+    let report = minute_db.sweep_retention()?;
+    assert_eq!(report.deleted, 2);
+    assert_eq!(report.kept, 2);
+    assert!(std::fs::metadata(format!("{}/1/2/0-admin.db", data_directory)).is_err());
+    assert_eq!(minute_db.bloom_memory().cached, 0);
+    assert_eq!(minute_db.sweep_retention()?.deleted, 0);
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_search_order() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("search_order");
+    // the second minute's logs claim to be older than some of the first minute's: host clocks are like that
+    for (minute, times) in [(0, vec![10, 30, 20]), (1, vec![40, 25, 50])] {
+        let mut writer = Minute::new(1, 2, minute, "ordered", &data_directory, true)?;
+        writer.write_second(times.into_iter().map(|time| crate::WritableEvent{
+            event: format!("ordering test {}", time),
+            time,
+            host: "localhost".to_string(),
+        }).collect())?;
+        writer.seal()?;
+    }
+    let minute_db = MinuteDB::new(data_directory, crate::retention::RetentionPolicy::new(10, 1000000000, None), None, None);
+    minute_db.update((0..2).map(|minute| MinuteId::new(1, 2, minute, "ordered")).collect())?;
+
+    let times = |options: SearchOptions| -> Result<Vec<i64>> {
+        Ok(minute_db.search(crate::search_token::Search::new("ordering"), &options)?.iter().map(|log| log.time).collect())
+    };
+    assert_eq!(times(SearchOptions::default())?, vec![50, 40, 30, 25, 20, 10]);
+    assert_eq!(times(SearchOptions{ order: SortOrder::Ascending, ..Default::default() })?, vec![10, 20, 25, 30, 40, 50]);
+    // most recent 3 comes out of the most recent minute
+    assert_eq!(times(SearchOptions{ limit: 3, ..Default::default() })?, vec![50, 40, 25]);
+    assert_eq!(times(SearchOptions{ limit: 2, order: SortOrder::Ascending, ..Default::default() })?, vec![10, 20]);
+
+    assert_eq!(SortOrder::parse("asc")?, SortOrder::Ascending);
+    assert!(SortOrder::parse("sideways").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_histogram() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("histogram");
+    for minute in 0..2 {
+        let mut writer = Minute::new(1, 2, minute, "histogram", &data_directory, true)?;
+        let minute_start = MinuteId::new(1, 2, minute, "").to_timestamp() * 1000000;
+        let mut events = Vec::new();
+        for second in 0..60 {
+            events.push(crate::WritableEvent{
+                event: format!("tick {}", second),
+                time: minute_start + second * 1000000,
+                host: "localhost".to_string(),
+            });
+            if second % 2 == 0 {
+                events.push(crate::WritableEvent{
+                    event: format!("tock {}", second),
+                    time: minute_start + second * 1000000,
+                    host: "localhost".to_string(),
+                });
+            }
+        }
+        writer.write_second(events)?;
+        writer.seal()?;
+    }
+    let minute_db = MinuteDB::new(data_directory, crate::retention::RetentionPolicy::new(10, 1000000000, None), None, None);
+    minute_db.update((0..2).map(|minute| MinuteId::new(1, 2, minute, "histogram")).collect())?;
+
+    let first = MinuteId::new(1, 2, 0, "").to_timestamp();
+    let (buckets, stats) = minute_db.histogram(crate::search_token::Search::new("tick"), &SearchOptions::default(), 60)?;
+    assert_eq!(buckets, vec![HistogramBucket{ time: first, count: 60, labels: Vec::new(), markers: Vec::new() }, HistogramBucket{ time: first + 60, count: 60, labels: Vec::new(), markers: Vec::new() }]);
+    assert_eq!(stats.rows_matched, 120);
+
+    // the limit doesn't apply to histograms
+    let options = SearchOptions{ limit: 1, ..Default::default() };
+    let (buckets, _) = minute_db.histogram(crate::search_token::Search::new("tock"), &options, 30)?;
+    assert_eq!(buckets.iter().map(|bucket| bucket.count).collect::<Vec<u64>>(), vec![15, 15, 15, 15]);
+
+    assert_eq!(parse_bucket("1m")?, 60);
+    assert_eq!(parse_bucket("90")?, 90);
+    assert_eq!(parse_bucket("2h")?, 7200);
+    assert!(parse_bucket("0s").is_err());
+    assert!(parse_bucket("fortnight").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_stats() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("stats");
+    {
+        let mut writer = Minute::new(1, 2, 3, "stats", &data_directory, true)?;
+        let mut events = Vec::new();
+        for i in 0..100 {
+            events.push(crate::WritableEvent{
+                event: format!("GET /thing status={} {}", if i % 10 == 0 { 500 } else { 200 }, if i % 10 == 0 { "ERROR" } else { "" }),
+                time: i,
+                host: format!("web-{}", i % 3),
+            });
+        }
+        writer.write_second(events)?;
+        writer.seal()?;
+    }
+    let minute_db = MinuteDB::new(data_directory, crate::retention::RetentionPolicy::new(10, 1000000000, None), None, None);
+    minute_db.update([MinuteId::new(1, 2, 3, "stats")].into_iter().collect())?;
+
+    let search = || crate::search_token::Search::new("thing");
+    let (result, _) = minute_db.stats(search(), &SearchOptions::default(), &StatsBy::Host, 2)?;
+    assert_eq!(result.total, 100);
+    assert_eq!(result.distinct, 3);
+    assert_eq!(result.top, vec![ValueCount{ value: "web-0".to_string(), count: 34 }, ValueCount{ value: "web-1".to_string(), count: 33 }]);
+
+    let (result, _) = minute_db.stats(search(), &SearchOptions::default(), &StatsBy::parse("status"), 10)?;
+    assert_eq!(result.top, vec![ValueCount{ value: "200".to_string(), count: 90 }, ValueCount{ value: "500".to_string(), count: 10 }]);
+
+    // logs without a level count towards the total, but not towards any value
+    let (result, _) = minute_db.stats(search(), &SearchOptions::default(), &StatsBy::Level, 10)?;
+    assert_eq!(result.total, 100);
+    assert_eq!(result.top, vec![ValueCount{ value: "error".to_string(), count: 10 }]);
+
+    Ok(())
+}
+
+#[test]
+fn test_search_merges_shards() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("merge_shards");
+    // two writer threads in the same minute, taking turns
+    for (shard, times) in [("1-0", vec![10, 30, 50]), ("1-1", vec![20, 40, 60])] {
+        let mut writer = Minute::new(1, 2, 3, shard, &data_directory, true)?;
+        writer.write_second(times.into_iter().map(|time| crate::WritableEvent{
+            event: format!("interleaved {}", time),
+            time,
+            host: "localhost".to_string(),
+        }).collect())?;
+        writer.seal()?;
+    }
+    let minute_db = MinuteDB::new(data_directory, crate::retention::RetentionPolicy::new(10, 1000000000, None), None, None);
+    minute_db.update(["1-0", "1-1"].into_iter().map(|shard| MinuteId::new(1, 2, 3, shard)).collect())?;
+
+    let results = minute_db.search(crate::search_token::Search::new("interleaved"), &SearchOptions::default())?;
+    assert_eq!(results.iter().map(|log| log.time).collect::<Vec<i64>>(), vec![60, 50, 40, 30, 20, 10]);
+    assert_eq!(results.iter().map(|log| log.shard.as_deref().unwrap()).collect::<Vec<&str>>(), vec!["1-1", "1-0", "1-1", "1-0", "1-1", "1-0"]);
+
+    // having enough results after the first shard doesn't mean skipping the second
+    let options = SearchOptions{ limit: 2, order: SortOrder::Ascending, ..Default::default() };
+    let results = minute_db.search(crate::search_token::Search::new("interleaved"), &options)?;
+    assert_eq!(results.iter().map(|log| log.time).collect::<Vec<i64>>(), vec![10, 20]);
+
+    assert!(serde_json::to_string(&results[1])?.contains("\"shard\":\"1-1\""));
+
+    Ok(())
+}
+
+#[test]
+fn test_max_minutes_scanned() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("max_minutes_scanned");
+    for minute in 0..5 {
+        let mut writer = Minute::new(1, 2, minute, "capped", &data_directory, true)?;
+        writer.write_second(vec![crate::WritableEvent{
+            event: format!("capped {}", ["alpha", "bravo", "charlie", "delta", "echo"][minute as usize]),
+            time: minute as i64,
+            host: "localhost".to_string(),
+        }])?;
+        writer.seal()?;
+    }
+    let minute_db = MinuteDB::new(data_directory, crate::retention::RetentionPolicy::new(10, 1000000000, None), None, None);
+    minute_db.update((0..5).map(|minute| MinuteId::new(1, 2, minute, "capped")).collect())?;
+
+    let (results, stats) = minute_db.search_with_stats(crate::search_token::Search::new("capped"), &SearchOptions::default())?;
+    assert_eq!(results.len(), 5);
+    assert_eq!(stats.hint, None);
+
+    // newest first, so the two minutes we get to are the last two
+    let options = SearchOptions{ max_minutes_scanned: Some(2), ..Default::default() };
+    let (results, stats) = minute_db.search_with_stats(crate::search_token::Search::new("capped"), &options)?;
+    assert_eq!(results.iter().map(|log| log.time).collect::<Vec<i64>>(), vec![4, 3]);
+    let hint = stats.hint.unwrap();
+    assert_eq!(hint.covered_from, MinuteId::new(1, 2, 3, "").to_timestamp());
+    assert_eq!(hint.covered_to, MinuteId::new(1, 2, 4, "").to_timestamp() + 59);
+
+    // minutes the bloom filter throws out don't count towards the cap
+    let (results, stats) = minute_db.search_with_stats(crate::search_token::Search::new("capped alpha"), &options)?;
+    assert_eq!(results.len(), 1);
+    assert_eq!(stats.hint, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_search_timeout() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("search_timeout");
+    for minute in 0..5 {
+        let mut writer = Minute::new(1, 2, minute, "timeout", &data_directory, true)?;
+        writer.write_second(vec![crate::WritableEvent::new("slow search", minute as i64, "localhost")])?;
+        writer.seal()?;
+    }
+    let minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, 1000000000, None), None, None);
+    minute_db.update((0..5).map(|minute| MinuteId::new(1, 2, minute, "timeout")).collect())?;
+    let search = || crate::search_token::Search::new("slow");
+
+    let (results, stats) = minute_db.search_with_stats(search(), &SearchOptions{ timeout: Some(Duration::from_secs(60)), ..Default::default() })?;
+    assert_eq!((results.len(), stats.truncated), (5, false));
+
+    // out of time before it started: nothing, and it says so
+    let (results, stats) = minute_db.search_with_stats(search(), &SearchOptions{ timeout: Some(Duration::ZERO), ..Default::default() })?;
+    assert_eq!((results.len(), stats.truncated), (0, true));
+    assert!(serde_json::to_string(&stats)?.contains(r#""truncated":true"#));
+    assert!(!serde_json::to_string(&SearchStats::default())?.contains("truncated"));
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_parallel_search() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("parallel_search");
+    let mut minute_ids = HashSet::new();
+    // seven minutes, two shards each, with a word that only shows up in every third minute
+    for minute in 0..7 {
+        for shard in ["1-0", "1-1"] {
+            let mut writer = Minute::new(1, 2, minute, shard, &data_directory, true)?;
+            writer.write_second((0..20).map(|i| crate::WritableEvent{
+                event: format!("parallel {} {}", i, if minute % 3 == 0 { "rare" } else { "common" }),
+                time: minute as i64 * 1000 + i * 2 + if shard == "1-0" { 0 } else { 1 },
+                host: format!("web-{}", i % 4),
+            }).collect())?;
+            writer.seal()?;
+            minute_ids.insert(MinuteId::new(1, 2, minute, shard));
+        }
+    }
+    let serial = MinuteDB::new(data_directory, crate::retention::RetentionPolicy::new(100, 1000000000, None), None, None);
+    serial.update(minute_ids)?;
+    let mut parallel = serial.clone();
+    parallel.set_search_threads(3);
+
+    // however many minutes we search at once, we find the same things
+    for search in ["parallel", "rare"] {
+        for limit in [1, 15, 25, 100, 1000] {
+            for order in [SortOrder::Descending, SortOrder::Ascending] {
+                for max_minutes_scanned in [None, Some(3)] {
+                    let options = SearchOptions{ limit, order, max_minutes_scanned, ..Default::default() };
+                    let (expected, expected_stats) = serial.search_with_stats(crate::search_token::Search::new(search), &options)?;
+                    let (results, stats) = parallel.search_with_stats(crate::search_token::Search::new(search), &options)?;
+                    assert_eq!(results.iter().map(|log| log.id).collect::<Vec<i64>>(), expected.iter().map(|log| log.id).collect::<Vec<i64>>());
+                    assert_eq!(stats.hint, expected_stats.hint);
+                    assert_eq!(stats.minutes_scanned, expected_stats.minutes_scanned);
+                }
+            }
+        }
+    }
+    let options = SearchOptions{ limit: 1000, ..Default::default() };
+    assert_eq!(parallel.search(crate::search_token::Search::new("parallel"), &options)?.len(), 280);
+    assert_eq!(parallel.search(crate::search_token::Search::new("rare"), &options)?.len(), 120);
+    // the blooms turn down the minutes without "rare" in them before anything's opened, but they still count as considered
+    let (_, stats) = parallel.search_with_stats(crate::search_token::Search::new("rare"), &options)?;
+    assert_eq!((stats.minutes_considered, stats.minutes_scanned), (14, 6));
+
+    // explain knows that without opening anything
+    let explanation = parallel.explain(&crate::search_token::Search::new("rare"), &options);
+    assert_eq!((explanation.minutes_considered, explanation.candidates, explanation.archived), (14, 6, 0));
+    assert_eq!(explanation.candidate_minutes.len(), 6);
+    assert_eq!(explanation.candidate_minutes[0], MinuteId::new(1, 2, 6, "1-1").to_string());
+    assert!(explanation.narrows);
+    assert_eq!(explanation.trigrams, vec!["are".to_string(), "rar".to_string()]);
+
+    let (histogram, stats) = parallel.histogram(crate::search_token::Search::new("parallel"), &SearchOptions::default(), 60)?;
+    assert_eq!(histogram, serial.histogram(crate::search_token::Search::new("parallel"), &SearchOptions::default(), 60)?.0);
+    assert_eq!(stats.minutes_scanned, 14);
+    let (result, _) = parallel.stats(crate::search_token::Search::new("common"), &SearchOptions::default(), &StatsBy::Host, 10)?;
+    assert_eq!(result.total, 160);
+    assert_eq!(result, serial.stats(crate::search_token::Search::new("common"), &SearchOptions::default(), &StatsBy::Host, 10)?.0);
+
+    Ok(())
+}
+
+#[test]
+fn test_search_stream() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("search_stream");
+    let mut minute_ids = HashSet::new();
+    for minute in 0..4 {
+        for shard in ["1-0", "1-1"] {
+            let mut writer = Minute::new(1, 2, minute, shard, &data_directory, true)?;
+            writer.write_second((0..5).map(|i| crate::WritableEvent{
+                event: format!("streamed {}", i),
+                time: minute as i64 * 1000 + i * 2 + if shard == "1-0" { 0 } else { 1 },
+                host: "localhost".to_string(),
+            }).collect())?;
+            writer.seal()?;
+            minute_ids.insert(MinuteId::new(1, 2, minute, shard));
+        }
+    }
+    let minute_db = MinuteDB::new(data_directory, crate::retention::RetentionPolicy::new(100, 1000000000, None), None, None);
+    minute_db.update(minute_ids)?;
+    let search = crate::search_token::Search::new("streamed");
+
+    // a minute at a time, with both shards of the minute merged together
+    let runtime = tokio::runtime::Runtime::new()?;
+    let minutes: Vec<Vec<i64>> = runtime.block_on(async {
+        let mut receiver = minute_db.search_stream(search.clone(), SearchOptions{ limit: 25, ..Default::default() });
+        let mut minutes = Vec::new();
+        while let Some(logs) = receiver.recv().await {
+            minutes.push(logs.iter().map(|log| log.time).collect());
+        }
+        minutes
+    });
+    assert_eq!(minutes, vec![
+        (0..10).rev().map(|time| 3000 + time).collect::<Vec<i64>>(),
+        (0..10).rev().map(|time| 2000 + time).collect::<Vec<i64>>(),
+        // the limit cuts the last minute short, the same way it would a regular search
+        vec![1009, 1008, 1007, 1006, 1005],
+    ]);
+
+    // whoever's listening can hang up
+    let mut heard = 0;
+    let mut stats = SearchStats::default();
+    minute_db.search_minutes(&search, &SearchOptions::default(), &mut stats, |_| {
+        heard += 1;
+        false
+    })?;
+    assert_eq!(heard, 1);
+    // we only find out they've gone once the next minute's started, and that minute gets finished, like always
+    assert_eq!(stats.minutes_scanned, 4);
+
+    Ok(())
+}
+
+#[test]
+fn test_search_paging() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("search_paging");
+    let mut minute_ids = HashSet::new();
+    // a noisy minute in between two quiet ones
+    for (minute, n_logs) in [(0, 3), (1, 50), (2, 3)] {
+        for shard in ["1-0", "1-1"] {
+            let mut writer = Minute::new(1, 2, minute, shard, &data_directory, true)?;
+            writer.write_second((0..n_logs).map(|i| crate::WritableEvent{
+                event: format!("paged {}", i),
+                time: minute as i64 * 1000 + i * 2 + if shard == "1-0" { 0 } else { 1 },
+                host: "localhost".to_string(),
+            }).collect())?;
+            writer.seal()?;
+            minute_ids.insert(MinuteId::new(1, 2, minute, shard));
+        }
+    }
+    let mut minute_db = MinuteDB::new(data_directory, crate::retention::RetentionPolicy::new(100, 1000000000, None), None, None);
+    minute_db.update(minute_ids)?;
+    minute_db.set_search_threads(2);
+
+    let times = |options: SearchOptions| -> Result<Vec<i64>> {
+        Ok(minute_db.search(crate::search_token::Search::new("paged"), &options)?.iter().map(|log| log.time).collect())
+    };
+    let everything = times(SearchOptions::default())?;
+    assert_eq!(everything.len(), 112);
+
+    // pages line up with each other, and with everything
+    let first_page = times(SearchOptions{ limit: 10, ..Default::default() })?;
+    let second_page = times(SearchOptions{ limit: 10, offset: 10, ..Default::default() })?;
+    assert_eq!(first_page, everything[..10]);
+    assert_eq!(second_page, everything[10..20]);
+    let ascending = times(SearchOptions{ limit: 5, offset: 3, order: SortOrder::Ascending, ..Default::default() })?;
+    assert_eq!(ascending, vec![3, 4, 5, 1000, 1001]);
+    assert!(times(SearchOptions{ offset: 200, ..Default::default() })?.is_empty());
+
+    // the noisy minute only gets 4 (the most recent 4, across both its shards), and the quiet ones get through
+    let capped = times(SearchOptions{ max_per_minute: Some(4), ..Default::default() })?;
+    assert_eq!(capped, vec![2005, 2004, 2003, 2002, 1099, 1098, 1097, 1096, 5, 4, 3, 2]);
+    let capped = times(SearchOptions{ max_per_minute: Some(4), limit: 6, offset: 1, ..Default::default() })?;
+    assert_eq!(capped, vec![2004, 2003, 2002, 1099, 1098, 1097]);
+
+    Ok(())
+}
+
+#[test]
+fn test_query_cache() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("query_cache");
+    let mut minute_ids = HashSet::new();
+    for minute in 0..5 {
+        let mut writer = Minute::new(1, 2, minute, "1-0", &data_directory, true)?;
+        writer.write_second((0..10).map(|i| crate::WritableEvent{
+            event: format!("cached {} level={}", i, if i % 2 == 0 { "info" } else { "error" }),
+            time: minute as i64 * 1000 + i,
+            host: "localhost".to_string(),
+        }).collect())?;
+        writer.seal()?;
+        minute_ids.insert(MinuteId::new(1, 2, minute, "1-0"));
+    }
+    let mut minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(100, 1000000000, None), None, None);
+    minute_db.set_query_cache_entries(1000);
+    minute_db.update(minute_ids.clone())?;
+
+    let search = || crate::search_token::Search::new("cached");
+    let (first, stats) = minute_db.histogram(search(), &SearchOptions::default(), 60)?;
+    assert_eq!((stats.minutes_scanned, stats.minutes_cached), (5, 0));
+    // the second time around, nothing gets opened
+    let (second, stats) = minute_db.histogram(search(), &SearchOptions::default(), 60)?;
+    assert_eq!((stats.minutes_scanned, stats.minutes_cached), (0, 5));
+    assert_eq!(first, second);
+    // a different bucket size (or a different search) is a different query
+    let (_, stats) = minute_db.histogram(search(), &SearchOptions::default(), 120)?;
+    assert_eq!(stats.minutes_cached, 0);
+
+    let (first, _) = minute_db.stats(search(), &SearchOptions::default(), &StatsBy::Field("level".to_string()), 10)?;
+    let (second, stats) = minute_db.stats(search(), &SearchOptions::default(), &StatsBy::Field("level".to_string()), 10)?;
+    assert_eq!(stats.minutes_cached, 5);
+    assert_eq!(first, second);
+    assert_eq!(second.total, 50);
+
+    // searches don't get cached
+    let (_, stats) = minute_db.search_with_stats(search(), &SearchOptions::default())?;
+    assert_eq!(stats.minutes_cached, 0);
+
+    // a minute that's gone takes what we remember about it with it
+    let removed = MinuteId::new(1, 2, 0, "1-0");
+    minute_ids.remove(&removed);
+    minute_db.load(minute_ids)?;
+    let (buckets, stats) = minute_db.histogram(search(), &SearchOptions::default(), 60)?;
+    assert_eq!((stats.minutes_scanned, stats.minutes_cached), (0, 4));
+    assert_eq!(buckets.iter().map(|bucket| bucket.count).sum::<u64>(), 40);
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_bloom_sidecars() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("bloom_sidecars");
+    let event = || crate::WritableEvent::new("a sidecar full of bloom", 0, "localhost");
+    let mut minute_ids = Vec::new();
+    for n in 0..6 {
+        let mut minute = Minute::new(1, 2, n, "bloom", &data_directory, true)?;
+        minute.write_second(vec![event()])?;
+        minute.seal()?;
+        minute_ids.push(MinuteId::new(1, 2, n, "bloom"));
+    }
+    let sidecar = |minute_id: &MinuteId| format!("{}/1/2/{}-bloom.db-bloom", data_directory, minute_id.minute);
+    assert!(minute_ids.iter().all(|minute_id| std::fs::metadata(sidecar(minute_id)).is_ok()));
+
+    // one from before there were sidecars, and one whose sidecar didn't get all the way written: they're read out of the minute instead
+    std::fs::remove_file(sidecar(&minute_ids[0]))?;
+    std::fs::write(sidecar(&minute_ids[1]), "half a bloom")?;
+    // and one that isn't sealed yet
+    let mut unsealed = Minute::new(1, 2, 59, "bloom", &data_directory, true)?;
+    unsealed.write_second(vec![event()])?;
+
+    let minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, 1000000000, None), None, None);
+    let files = crate::catalog::FileInfo::scan(&data_directory)?;
+    assert_eq!(files.len(), 7);
+    assert_eq!(minute_db.apply_scan(&files, 0)?, (0, 6));
+    assert_eq!(minute_db.sealed_minutes(), minute_ids);
+    // and now they've got sidecars too
+    assert!(crate::segment::read_bloom_sidecar(&minute_db.path_for(&minute_ids[0])).is_ok());
+    assert!(crate::segment::read_bloom_sidecar(&minute_db.path_for(&minute_ids[1])).is_ok());
+    assert!(std::fs::metadata(sidecar(&MinuteId::new(1, 2, 59, "bloom"))).is_err());
+
+    // every one of them is searchable, and none of them got opened to get them there
+    assert_eq!(minute_db.close_idle(Duration::ZERO), 0);
+    let results = minute_db.search(crate::search_token::Search::new("sidecar"), &SearchOptions::default())?;
+    assert_eq!(results.len(), 6);
+
+    drop(unsealed);
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
+
+#[test]
+fn test_bloom_budget() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("bloom_budget");
+    let mut minute_ids = Vec::new();
+    let mut bytes = Vec::new();
+    for n in 0..6 {
+        let mut minute = Minute::new(1, 2, n, "budget", &data_directory, true)?;
+        minute.write_second(vec![crate::WritableEvent::new(&format!("over budget number{}", n), 0, "localhost")])?;
+        minute.seal()?;
+        minute_ids.push(MinuteId::new(1, 2, n, "budget"));
+        bytes.push(std::fs::metadata(format!("{}/1/2/{}-budget.db-bloom", data_directory, n))?.len());
+    }
+
+    // room for three of them
+    let mut minute_db = MinuteDB::new(data_directory.clone(), crate::retention::RetentionPolicy::new(10, 1000000000, None), None, None);
+    minute_db.set_bloom_budget(bytes[..3].iter().sum());
+    assert_eq!(minute_db.apply_scan(&crate::catalog::FileInfo::scan(&data_directory)?, 0)?, (0, 6));
+    let memory = minute_db.bloom_memory();
+    assert_eq!((memory.cached, memory.evicted), (3, 3));
+    assert!(memory.cached_bytes <= memory.budget_bytes);
+    // they're all still sealed minutes, even the ones whose blooms got let go
+    assert_eq!(minute_db.sealed_minutes(), minute_ids);
+
+    // the blooms that got let go come back for the search that needs them, and they still rule out what they should
+    let search = crate::search_token::Search::new("number0");
+    let explanation = minute_db.explain(&search, &SearchOptions::default());
+    assert_eq!(explanation.candidates, 1);
+    assert_eq!(minute_db.search(search, &SearchOptions::default())?.len(), 1);
+    assert_eq!(minute_db.search(crate::search_token::Search::new("budget"), &SearchOptions::default())?.len(), 6);
+    // and we're back under budget afterwards
+    let memory = minute_db.bloom_memory();
+    assert!(memory.cached_bytes <= memory.budget_bytes);
+    assert_eq!(memory.cached + memory.evicted, 6);
+
+    // the most recently used blooms are the ones that stay
+    minute_db.search(crate::search_token::Search::new("number5"), &SearchOptions::default())?;
+    assert!(minute_db.bloom_cache.read().unwrap().get(&minute_ids[5]).unwrap().get().is_some());
+
+    // dropping a minute gives its bloom's bytes back
+    let cached_bytes = minute_db.bloom_memory().cached_bytes;
+    minute_db.bloom_cache.write().unwrap().remove(&minute_ids[5]);
+    assert_eq!(minute_db.bloom_memory().cached_bytes, cached_bytes - bytes[5]);
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
 }
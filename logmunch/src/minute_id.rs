@@ -1,5 +1,11 @@
 use anyhow::Result;
 
+///
+/// Minutes written by an import (rather than by the live writer) have unique ids that start with this:
+/// retention can treat them differently (see RetentionPolicy::imported_by_arrival)
+///
+pub const IMPORTED_PREFIX: &str = "imported-";
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct MinuteId{
     pub day: u32,
@@ -10,31 +16,37 @@ pub struct MinuteId{
 
 impl PartialOrd for MinuteId {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MinuteId {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         if self.day < other.day {
-            return Some(std::cmp::Ordering::Less);
+            return std::cmp::Ordering::Less;
         }
         if self.day > other.day {
-            return Some(std::cmp::Ordering::Greater);
+            return std::cmp::Ordering::Greater;
         }
         if self.hour < other.hour {
-            return Some(std::cmp::Ordering::Less);
+            return std::cmp::Ordering::Less;
         }
         if self.hour > other.hour {
-            return Some(std::cmp::Ordering::Greater);
+            return std::cmp::Ordering::Greater;
         }
         if self.minute < other.minute {
-            return Some(std::cmp::Ordering::Less);
+            return std::cmp::Ordering::Less;
         }
         if self.minute > other.minute {
-            return Some(std::cmp::Ordering::Greater);
+            return std::cmp::Ordering::Greater;
         }
-        Some(self.unique_id.cmp(&other.unique_id))
+        self.unique_id.cmp(&other.unique_id)
     }
 }
 
-impl Ord for MinuteId {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+impl std::fmt::Display for MinuteId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}-{}-{}", self.day, self.hour, self.minute, self.unique_id)
     }
 }
 
@@ -48,12 +60,39 @@ impl MinuteId{
         }
     }
 
-    pub fn to_string(&self) -> String {
-        format!("{}-{}-{}-{}", self.day, self.hour, self.minute, self.unique_id)
+    ///
+    /// The first MinuteId that could exist for the minute containing `timestamp` (in seconds since the epoch).
+    /// (the empty unique_id sorts before every real one, so this is useful as the start of a BTreeMap range)
+    ///
+    pub fn from_timestamp(timestamp: i64) -> MinuteId {
+        let timestamp = timestamp.max(0) as u64;
+        MinuteId{
+            day: (timestamp / 86400) as u32,
+            hour: ((timestamp % 86400) / 3600) as u32,
+            minute: ((timestamp % 3600) / 60) as u32,
+            unique_id: "".to_string(),
+        }
+    }
+
+    ///
+    /// The start of this minute, in seconds since the epoch.
+    ///
+    pub fn to_timestamp(&self) -> i64 {
+        self.day as i64 * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60
+    }
+
+    pub fn is_imported(&self) -> bool {
+        self.unique_id.starts_with(IMPORTED_PREFIX)
     }
 
+    ///
+    /// The other way around from Display: the unique id gets everything after the minute, dashes and all
+    ///
     pub fn from_string(s: &str) -> Result<MinuteId> {
-        let split = s.split("-").collect::<Vec<&str>>();
+        let split = s.splitn(4, '-').collect::<Vec<&str>>();
+        if split.len() < 4 || split[3].is_empty() {
+            return Err(anyhow::anyhow!("'{}' isn't a minute id: they look like day-hour-minute-unique_id", s));
+        }
         let day = split[0].parse::<u32>()?;
         let hour = split[1].parse::<u32>()?;
         let minute = split[2].parse::<u32>()?;
@@ -66,3 +105,65 @@ impl MinuteId{
         })
     }
 }
+
+///
+/// A log's id is only unique inside its minute: every shard numbers its own logs by when it wrote them, so two shards
+/// (or two nodes) can hand out the same one. The minute and the id together are unique everywhere, and stay put when the
+/// minute's sealed, archived or replicated, so they're what a permalink holds on to (GET /log/<event_id>).
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EventId{
+    pub minute_id: MinuteId,
+    pub id: i64,
+}
+
+impl std::fmt::Display for EventId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.minute_id, self.id)
+    }
+}
+
+impl EventId{
+    pub fn new(minute_id: MinuteId, id: i64) -> EventId {
+        EventId{
+            minute_id,
+            id,
+        }
+    }
+
+    pub fn from_string(s: &str) -> Result<EventId> {
+        let Some((minute_id, id)) = s.rsplit_once(':') else {
+            return Err(anyhow::anyhow!("'{}' isn't an event id: they look like day-hour-minute-unique_id:id", s));
+        };
+        Ok(EventId{
+            minute_id: MinuteId::from_string(minute_id)?,
+            id: id.parse::<i64>()?,
+        })
+    }
+}
+
+#[test]
+fn test_timestamp_round_trip() {
+    let minute_id = MinuteId::from_timestamp(1710562887);
+    assert_eq!(minute_id.day, 19798);
+    assert_eq!(minute_id.hour, 4);
+    assert_eq!(minute_id.minute, 21);
+    assert_eq!(minute_id.to_timestamp(), 1710562860);
+
+    assert!(MinuteId::from_timestamp(1710562887) < MinuteId::new(19798, 4, 21, "1-0"));
+    assert!(MinuteId::from_timestamp(1710562947) > MinuteId::new(19798, 4, 21, "1-0"));
+}
+
+#[test]
+fn test_event_id_round_trip() -> Result<()> {
+    let minute_id = MinuteId::new(19798, 4, 21, "imported-1-1710562887");
+    assert_eq!(MinuteId::from_string(&minute_id.to_string())?, minute_id);
+    assert!(MinuteId::from_string("19798-4-21").is_err());
+
+    let event_id = EventId::new(MinuteId::new(19798, 4, 21, "1-0"), 1710562887000012);
+    assert_eq!(event_id.to_string(), "19798-4-21-1-0:1710562887000012");
+    assert_eq!(EventId::from_string(&event_id.to_string())?, event_id);
+    assert!(EventId::from_string("19798-4-21-1-0").is_err());
+    assert!(EventId::from_string("19798-4-21-1-0:latest").is_err());
+    Ok(())
+}
@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::RwLock;
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+use crate::minute_db::HistogramBucket;
+
+///
+/// The most minutes one request can label: a week's worth
+///
+const MAX_MINUTES_PER_REQUEST: i64 = 7 * 24 * 60;
+
+const MAX_KEY_LENGTH: usize = 64;
+const MAX_VALUE_LENGTH: usize = 256;
+
+///
+/// The labels on one minute. `time` is the start of the minute, in seconds since the epoch.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MinuteLabel{
+    pub time: i64,
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LabelReport{
+    /// how many minutes were labeled (or unlabeled)
+    pub minutes: usize,
+}
+
+///
+/// "deploy" (minutes that have a deploy label) or "deploy=v123" (minutes where it's v123)
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelFilter{
+    key: String,
+    value: Option<String>,
+}
+
+impl LabelFilter{
+    pub fn parse(filter: &str) -> Result<LabelFilter> {
+        let (key, value) = match filter.split_once('='){
+            Some((key, value)) => (key, Some(value.to_string())),
+            None => (filter, None),
+        };
+        if key.is_empty() {
+            return Err(anyhow::anyhow!("label filters look like 'key' or 'key=value', not '{}'", filter));
+        }
+        Ok(LabelFilter{ key: key.to_string(), value })
+    }
+
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        match (labels.get(&self.key), &self.value){
+            (Some(found), Some(value)) => found == value,
+            (found, None) => found.is_some(),
+            (None, Some(_)) => false,
+        }
+    }
+}
+
+fn minute_start(time: i64) -> i64 {
+    time - time.rem_euclid(60)
+}
+
+///
+/// Notes on minutes ("deploy=v123 happened here", "imported-from=legacy"), for whoever's looking at a histogram later and wondering what happened.
+/// They're kept in metadata/minute_labels.json, not in the minutes themselves: sealed minutes don't change,
+/// and you can label a minute before it's been written (or after retention has deleted it).
+///
+pub struct MinuteLabels{
+    /// None keeps the labels in memory only
+    path: Option<String>,
+    minutes: RwLock<BTreeMap<i64, BTreeMap<String, String>>>,
+}
+
+impl MinuteLabels{
+    pub fn new() -> MinuteLabels {
+        MinuteLabels{
+            path: None,
+            minutes: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn open(path: &str) -> Result<MinuteLabels> {
+        let minutes = match fs::read_to_string(path){
+            Ok(contents) => {
+                let labels: Vec<MinuteLabel> = serde_json::from_str(&contents).map_err(|e| anyhow::anyhow!("Could not parse minute labels in {}: {}", path, e))?;
+                labels.into_iter().map(|label| (label.time, label.labels)).collect()
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(anyhow::anyhow!("Could not read minute labels from {}: {}", path, e)),
+        };
+        Ok(MinuteLabels{
+            path: Some(path.to_string()),
+            minutes: RwLock::new(minutes),
+        })
+    }
+
+    fn save(&self, minutes: &BTreeMap<i64, BTreeMap<String, String>>) -> Result<()> {
+        if let Some(path) = &self.path {
+            let labels: Vec<MinuteLabel> = minutes.iter().map(|(time, labels)| MinuteLabel{ time: *time, labels: labels.clone() }).collect();
+            // write it next door and move it over, so a crash halfway through doesn't cost us every label
+            let temporary = format!("{}.tmp", path);
+            fs::write(&temporary, serde_json::to_string(&labels)?)?;
+            fs::rename(&temporary, path)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Every minute from `from` to `to` (seconds since the epoch, inclusive) gets `labels`, on top of whatever labels it already had
+    ///
+    pub fn label(&self, from: i64, to: i64, labels: &BTreeMap<String, String>) -> Result<LabelReport> {
+        let (from, to) = (minute_start(from), minute_start(to));
+        if to < from {
+            return Err(anyhow::anyhow!("to must not be before from"));
+        }
+        if (to - from) / 60 >= MAX_MINUTES_PER_REQUEST {
+            return Err(anyhow::anyhow!("that's more than {} minutes: label a shorter range", MAX_MINUTES_PER_REQUEST));
+        }
+        if labels.is_empty() {
+            return Err(anyhow::anyhow!("no labels to add"));
+        }
+        for (key, value) in labels {
+            if key.is_empty() || key.len() > MAX_KEY_LENGTH || key.contains('=') {
+                return Err(anyhow::anyhow!("'{}' can't be a label: keys are 1-{} characters, without an =", key, MAX_KEY_LENGTH));
+            }
+            if value.len() > MAX_VALUE_LENGTH {
+                return Err(anyhow::anyhow!("the value of {} is too long: values are at most {} characters", key, MAX_VALUE_LENGTH));
+            }
+        }
+
+        let mut minutes = self.minutes.write().unwrap();
+        let mut labeled = 0;
+        for time in (from..=to).step_by(60) {
+            minutes.entry(time).or_default().extend(labels.clone());
+            labeled += 1;
+        }
+        self.save(&minutes)?;
+        Ok(LabelReport{ minutes: labeled })
+    }
+
+    ///
+    /// Take `key` off every minute from `from` to `to` (or every label, with no key)
+    ///
+    pub fn unlabel(&self, from: i64, to: i64, key: Option<&str>) -> Result<LabelReport> {
+        let (from, to) = (minute_start(from), minute_start(to));
+        if to < from {
+            return Err(anyhow::anyhow!("to must not be before from"));
+        }
+        let mut minutes = self.minutes.write().unwrap();
+        let mut unlabeled = 0;
+        for labels in minutes.range_mut(from..=to).map(|(_, labels)| labels) {
+            let removed = match key{
+                Some(key) => labels.remove(key).is_some(),
+                None => {
+                    let had_labels = !labels.is_empty();
+                    labels.clear();
+                    had_labels
+                },
+            };
+            if removed {
+                unlabeled += 1;
+            }
+        }
+        minutes.retain(|_, labels| !labels.is_empty());
+        self.save(&minutes)?;
+        Ok(LabelReport{ minutes: unlabeled })
+    }
+
+    ///
+    /// Labeled minutes between `from` and `to` (either end can be left open), oldest first, and only the ones that match `filter`
+    ///
+    pub fn list(&self, from: Option<i64>, to: Option<i64>, filter: Option<&LabelFilter>) -> Vec<MinuteLabel> {
+        let from = from.map(minute_start).unwrap_or(i64::MIN);
+        let to = to.map(minute_start).unwrap_or(i64::MAX);
+        if to < from {
+            return Vec::new();
+        }
+        self.minutes.read().unwrap().range(from..=to)
+            .filter(|(_, labels)| filter.is_none_or(|filter| filter.matches(labels)))
+            .map(|(time, labels)| MinuteLabel{ time: *time, labels: labels.clone() })
+            .collect()
+    }
+
+    ///
+    /// Put every labeled minute between `from` and `to` on the histogram, in the bucket it falls in.
+    /// Labeled minutes in buckets where nothing matched get an empty bucket of their own: otherwise the deploy you were looking for
+    /// would disappear from the exact spot where the errors stopped.
+    ///
+    pub fn overlay(&self, buckets: Vec<HistogramBucket>, bucket_seconds: i64, from: Option<i64>, to: Option<i64>) -> Vec<HistogramBucket> {
+        let mut buckets: BTreeMap<i64, HistogramBucket> = buckets.into_iter().map(|bucket| (bucket.time, bucket)).collect();
+        for label in self.list(from, to, None) {
+            let time = label.time - label.time.rem_euclid(bucket_seconds);
+            buckets.entry(time).or_insert_with(|| HistogramBucket{ time, count: 0, labels: Vec::new(), markers: Vec::new() }).labels.push(label);
+        }
+        buckets.into_values().collect()
+    }
+}
+
+impl Default for MinuteLabels{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_minute_labels() -> Result<()> {
+    let directory = crate::minute::test_data_directory("minute_labels");
+    fs::create_dir_all(&directory)?;
+    let path = format!("{}/minute_labels.json", directory);
+
+    let minute_labels = MinuteLabels::open(&path)?;
+    let labels = |pairs: &[(&str, &str)]| pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect::<BTreeMap<String, String>>();
+    assert_eq!(minute_labels.label(125, 125, &labels(&[("deploy", "v123")]))?.minutes, 1);
+    assert_eq!(minute_labels.label(0, 179, &labels(&[("imported-from", "legacy")]))?.minutes, 3);
+    assert!(minute_labels.label(0, 60 * MAX_MINUTES_PER_REQUEST, &labels(&[("too", "long")])).is_err());
+    assert!(minute_labels.label(0, 0, &labels(&[("a=b", "c")])).is_err());
+    assert!(minute_labels.label(60, 0, &labels(&[("a", "b")])).is_err());
+
+    // it's all still there the next time we boot
+    let minute_labels = MinuteLabels::open(&path)?;
+    assert_eq!(minute_labels.list(None, None, None).len(), 3);
+    assert_eq!(minute_labels.list(Some(60), Some(179), None), vec![
+        MinuteLabel{ time: 60, labels: labels(&[("imported-from", "legacy")]) },
+        MinuteLabel{ time: 120, labels: labels(&[("deploy", "v123"), ("imported-from", "legacy")]) },
+    ]);
+    let deploys = minute_labels.list(None, None, Some(&LabelFilter::parse("deploy")?));
+    assert_eq!(deploys.iter().map(|label| label.time).collect::<Vec<i64>>(), vec![120]);
+    assert!(minute_labels.list(None, None, Some(&LabelFilter::parse("deploy=v124")?)).is_empty());
+    assert!(LabelFilter::parse("=v1").is_err());
+
+    // one bucket got a label and some logs, one got a label and no logs
+    let buckets = vec![HistogramBucket{ time: 0, count: 10, labels: Vec::new(), markers: Vec::new() }, HistogramBucket{ time: 240, count: 5, labels: Vec::new(), markers: Vec::new() }];
+    let overlaid = minute_labels.overlay(buckets, 120, None, None);
+    assert_eq!(overlaid.iter().map(|bucket| (bucket.time, bucket.count, bucket.labels.len())).collect::<Vec<(i64, u64, usize)>>(),
+        vec![(0, 10, 2), (120, 0, 1), (240, 5, 0)]);
+
+    assert_eq!(minute_labels.unlabel(0, 60, Some("imported-from"))?.minutes, 2);
+    assert_eq!(minute_labels.unlabel(120, 120, None)?.minutes, 1);
+    assert!(MinuteLabels::open(&path)?.list(None, None, None).is_empty());
+
+    fs::remove_dir_all(&directory)?;
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use std::collections::HashSet;
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::catalog::FileInfo;
+use crate::minute::Minute;
+use crate::minute_db::MinuteDB;
+use crate::minute_id::MinuteId;
+
+///
+/// A data directory that nobody's running: a copy off a dead node, or a live one you'd rather not touch through the API.
+/// Nothing here deletes, seals or archives anything, and no background threads get started.
+///
+pub struct OfflineDirectory{
+    minutes_directory: String,
+}
+
+///
+/// One minute file, as `logmunch minutes` sees it
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct MinuteInfo{
+    pub minute_id: String,
+    /// the start of the minute, in seconds since the epoch
+    pub time: i64,
+    pub size_bytes: u64,
+    pub sealed: bool,
+    pub events: Option<u64>,
+    pub format_version: Option<u32>,
+}
+
+///
+/// What `logmunch verify` found wrong with a minute (nothing, hopefully)
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyReport{
+    pub minute_id: String,
+    pub problems: Vec<String>,
+}
+
+impl OfflineDirectory{
+    ///
+    /// `path` can be a data directory (with a minutes/ in it) or a minutes directory on its own
+    ///
+    pub fn open(path: &str) -> Result<OfflineDirectory> {
+        let path = path.trim_end_matches('/');
+        if std::fs::metadata(path).is_err() {
+            return Err(anyhow::anyhow!("There's no data directory at {}", path));
+        }
+        let minutes = format!("{}/minutes", path);
+        let minutes_directory = match std::fs::metadata(&minutes){
+            Ok(metadata) if metadata.is_dir() => minutes,
+            _ => path.to_string(),
+        };
+        Ok(OfflineDirectory{ minutes_directory })
+    }
+
+    fn files(&self) -> Result<Vec<FileInfo>> {
+        let mut files = FileInfo::scan(&self.minutes_directory)?;
+        files.sort_by_key(|file| file.to_minute_id());
+        Ok(files)
+    }
+
+    fn open_minute(&self, minute_id: &MinuteId) -> Result<Minute> {
+        Minute::new(minute_id.day, minute_id.hour, minute_id.minute, &minute_id.unique_id, &self.minutes_directory, false)
+    }
+
+    ///
+    /// Every minute file, oldest first
+    ///
+    pub fn minutes(&self) -> Result<Vec<MinuteInfo>> {
+        let mut minutes = Vec::new();
+        for file in self.files()? {
+            let minute_id = file.to_minute_id();
+            let minute = self.open_minute(&minute_id).ok();
+            minutes.push(MinuteInfo{
+                minute_id: minute_id.to_string(),
+                time: minute_id.to_timestamp(),
+                size_bytes: file.size_bytes,
+                sealed: minute.as_ref().and_then(|minute| minute.is_sealed().ok()).unwrap_or(false),
+                events: minute.as_ref().and_then(|minute| minute.count().ok()),
+                format_version: minute.as_ref().and_then(|minute| minute.format_version().ok()),
+            });
+        }
+        Ok(minutes)
+    }
+
+    ///
+    /// A MinuteDB over every sealed minute, for searches and histograms.
+    /// Unsealed minutes can't be searched: the second number is how many got left out.
+    ///
+    pub fn minute_db(&self) -> Result<(MinuteDB, usize)> {
+        let minute_ids: HashSet<MinuteId> = self.files()?.iter().map(|file| file.to_minute_id()).collect();
+        let n_minutes = minute_ids.len();
+        let minute_db = MinuteDB::new(self.minutes_directory.clone(), crate::retention::RetentionPolicy::new(u64::MAX, u64::MAX, None), None, None);
+        let (_removed, added) = minute_db.load(minute_ids)?;
+        Ok((minute_db, n_minutes - added))
+    }
+
+    ///
+    /// Check every minute (see Minute::verify)
+    ///
+    pub fn verify(&self) -> Result<Vec<VerifyReport>> {
+        let mut reports = Vec::new();
+        for file in self.files()? {
+            let minute_id = file.to_minute_id();
+            let problems = match self.open_minute(&minute_id).and_then(|minute| minute.verify()){
+                Ok(problems) => problems,
+                Err(e) => vec![format!("can't open it: {}", e)],
+            };
+            reports.push(VerifyReport{
+                minute_id: minute_id.to_string(),
+                problems,
+            });
+        }
+        Ok(reports)
+    }
+}
+
+#[test]
+fn test_offline_directory() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("offline");
+    let minutes_directory = format!("{}/minutes", data_directory);
+    {
+        let mut sealed = Minute::new(1, 2, 3, "1-0", &minutes_directory, true)?;
+        sealed.write_second(vec![crate::WritableEvent::new("hello offline world", 93780000000, "web-1")])?;
+        sealed.seal()?;
+        let mut unsealed = Minute::new(1, 2, 4, "1-0", &minutes_directory, true)?;
+        unsealed.write_second(vec![crate::WritableEvent::new("hello again", 93840000000, "web-1")])?;
+    }
+
+    let offline = OfflineDirectory::open(&data_directory)?;
+    let minutes = offline.minutes()?;
+    assert_eq!(minutes.len(), 2);
+    assert_eq!(minutes[0].minute_id, "1-2-3-1-0");
+    assert!(minutes[0].sealed);
+    assert_eq!(minutes[0].events, Some(1));
+    assert!(!minutes[1].sealed);
+
+    let (minute_db, unsealed) = offline.minute_db()?;
+    assert_eq!(unsealed, 1);
+    let logs = minute_db.search(crate::search_token::Search::new("offline"), &crate::minute_db::SearchOptions::default())?;
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].message, "hello offline world");
+
+    let reports = offline.verify()?;
+    assert!(reports[0].problems.is_empty());
+    assert_eq!(reports[1].problems, vec!["not sealed".to_string()]);
+
+    // pointing it straight at the minutes works too
+    assert_eq!(OfflineDirectory::open(&minutes_directory)?.minutes()?.len(), 2);
+    assert!(OfflineDirectory::open(&format!("{}/nope", data_directory)).is_err());
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
@@ -0,0 +1,378 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::io::Read;
+use anyhow::Result;
+use prost::Message;
+use tonic::codegen::{http, Body, BoxFuture, CompressionEncoding, Context, Poll, Service, StdError};
+
+use crate::WritableEvent;
+
+///
+/// Just enough of opentelemetry-proto (logs/v1, collector/logs/v1, common/v1, resource/v1) to take logs off of an OTel Collector.
+/// Field numbers are straight out of the .proto files: don't renumber them.
+///
+pub mod proto{
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ExportLogsServiceRequest{
+        #[prost(message, repeated, tag = "1")]
+        pub resource_logs: Vec<ResourceLogs>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ExportLogsServiceResponse{
+        #[prost(message, optional, tag = "1")]
+        pub partial_success: Option<ExportLogsPartialSuccess>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ExportLogsPartialSuccess{
+        #[prost(int64, tag = "1")]
+        pub rejected_log_records: i64,
+        #[prost(string, tag = "2")]
+        pub error_message: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ResourceLogs{
+        #[prost(message, optional, tag = "1")]
+        pub resource: Option<Resource>,
+        #[prost(message, repeated, tag = "2")]
+        pub scope_logs: Vec<ScopeLogs>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Resource{
+        #[prost(message, repeated, tag = "1")]
+        pub attributes: Vec<KeyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ScopeLogs{
+        #[prost(message, repeated, tag = "2")]
+        pub log_records: Vec<LogRecord>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LogRecord{
+        #[prost(fixed64, tag = "1")]
+        pub time_unix_nano: u64,
+        #[prost(fixed64, tag = "11")]
+        pub observed_time_unix_nano: u64,
+        #[prost(int32, tag = "2")]
+        pub severity_number: i32,
+        #[prost(string, tag = "3")]
+        pub severity_text: String,
+        #[prost(message, optional, tag = "5")]
+        pub body: Option<AnyValue>,
+        #[prost(message, repeated, tag = "6")]
+        pub attributes: Vec<KeyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct KeyValue{
+        #[prost(string, tag = "1")]
+        pub key: String,
+        #[prost(message, optional, tag = "2")]
+        pub value: Option<AnyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AnyValue{
+        #[prost(oneof = "any_value::Value", tags = "1, 2, 3, 4, 5, 6, 7")]
+        pub value: Option<any_value::Value>,
+    }
+
+    pub mod any_value{
+        // named the way prost-build would name them, so these line up with every other OTLP crate
+        #[allow(clippy::enum_variant_names)]
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Value{
+            #[prost(string, tag = "1")]
+            StringValue(String),
+            #[prost(bool, tag = "2")]
+            BoolValue(bool),
+            #[prost(int64, tag = "3")]
+            IntValue(i64),
+            #[prost(double, tag = "4")]
+            DoubleValue(f64),
+            #[prost(message, tag = "5")]
+            ArrayValue(super::ArrayValue),
+            #[prost(message, tag = "6")]
+            KvlistValue(super::KeyValueList),
+            #[prost(bytes, tag = "7")]
+            BytesValue(Vec<u8>),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ArrayValue{
+        #[prost(message, repeated, tag = "1")]
+        pub values: Vec<AnyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct KeyValueList{
+        #[prost(message, repeated, tag = "1")]
+        pub values: Vec<KeyValue>,
+    }
+}
+
+///
+/// Strings come through as-is; everything else gets turned into something JSON-ish, which our search can still find words in.
+///
+pub fn any_value_to_string(value: &proto::AnyValue) -> String {
+    use proto::any_value::Value;
+    match &value.value{
+        Some(Value::StringValue(value)) => value.clone(),
+        Some(Value::BoolValue(value)) => value.to_string(),
+        Some(Value::IntValue(value)) => value.to_string(),
+        Some(Value::DoubleValue(value)) => value.to_string(),
+        Some(Value::BytesValue(value)) => hex::encode(value),
+        Some(Value::ArrayValue(array)) => format!("[{}]", array.values.iter().map(any_value_to_string).collect::<Vec<String>>().join(", ")),
+        Some(Value::KvlistValue(list)) => format!("{{{}}}", list.values.iter()
+            .map(|kv| format!("\"{}\": {}", kv.key, kv.value.as_ref().map(any_value_to_string).unwrap_or_default()))
+            .collect::<Vec<String>>().join(", ")),
+        None => String::new(),
+    }
+}
+
+fn resource_host(resource: Option<&proto::Resource>) -> String {
+    let attributes = match resource{
+        Some(resource) => &resource.attributes,
+        None => return "unknown".to_string(),
+    };
+    for key in ["host.name", "service.name"] {
+        if let Some(value) = attributes.iter().find(|kv| kv.key == key).and_then(|kv| kv.value.as_ref()) {
+            return any_value_to_string(value);
+        }
+    }
+    "unknown".to_string()
+}
+
+///
+/// host <- resource attribute host.name (or service.name), event <- body, time <- observed timestamp
+/// (or the event timestamp if there's no observed one, or now if there's neither)
+///
+pub fn to_writable_events(request: &proto::ExportLogsServiceRequest) -> Vec<WritableEvent> {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_micros() as i64;
+    let mut events = Vec::new();
+    for resource_logs in &request.resource_logs {
+        let host = resource_host(resource_logs.resource.as_ref());
+        for scope_logs in &resource_logs.scope_logs {
+            for record in &scope_logs.log_records {
+                let time = match (record.observed_time_unix_nano, record.time_unix_nano){
+                    (0, 0) => now,
+                    (0, time) => (time / 1000) as i64,
+                    (observed, _) => (observed / 1000) as i64,
+                };
+                events.push(WritableEvent{
+                    event: record.body.as_ref().map(any_value_to_string).unwrap_or_default(),
+                    time,
+                    host: host.clone(),
+                });
+            }
+        }
+    }
+    events
+}
+
+///
+/// OTLP/HTTP bodies are usually gzipped (that's the collector's default)
+///
+pub fn decode_http(body: &[u8], content_encoding: Option<&str>) -> Result<proto::ExportLogsServiceRequest> {
+    let request = match content_encoding{
+        Some("gzip") => {
+            let mut decompressed = Vec::new();
+            flate2::read::GzDecoder::new(body).read_to_end(&mut decompressed)?;
+            proto::ExportLogsServiceRequest::decode(decompressed.as_slice())?
+        },
+        None | Some("identity") => proto::ExportLogsServiceRequest::decode(body)?,
+        Some(other) => return Err(anyhow::anyhow!("Unsupported Content-Encoding: {}", other)),
+    };
+    Ok(request)
+}
+
+pub fn encode_response() -> Vec<u8> {
+    proto::ExportLogsServiceResponse::default().encode_to_vec()
+}
+
+struct Receiver{
+    tenants: Arc<crate::tenant::Tenants>,
+    ingest_policy: Arc<crate::auth::IngestPolicy>,
+    rate_limiter: Arc<crate::rate_limit::IngestRateLimiter>,
+    standby: Option<Arc<crate::replication::Standby>>,
+}
+
+impl Receiver{
+    // tonic::Status is just big, and it's what tonic wants back
+    #[allow(clippy::result_large_err)]
+    fn export(&self, request: tonic::Request<proto::ExportLogsServiceRequest>) -> Result<tonic::Response<proto::ExportLogsServiceResponse>, tonic::Status> {
+        let token = request.metadata().get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(crate::auth::ApiToken::parse);
+        let ip = request.remote_addr().map(|addr| addr.ip());
+        if !self.ingest_policy.allows(ip, token.as_deref()) {
+            return Err(tonic::Status::unauthenticated("missing or unknown ingest token"));
+        }
+        if self.standby.as_ref().is_some_and(|standby| !standby.accepts_ingest()) {
+            return Err(tonic::Status::unavailable("this node is a standby: send logs to the primary"));
+        }
+        let header = self.tenants.header().and_then(|header| request.metadata().get(header.to_lowercase().as_str())).and_then(|value| value.to_str().ok());
+        let tenant = self.tenants.resolve(token.as_deref(), header)
+            .map_err(|e| tonic::Status::permission_denied(e.to_string()))?;
+        let events = to_writable_events(request.get_ref());
+        if !tenant.queue.fits(events.len()) {
+            // anything they'd retry would never get in: INVALID_ARGUMENT they drop
+            return Err(tonic::Status::invalid_argument(format!("{} log records is more than the ingest queue holds ({}): send smaller batches", events.len(), tenant.queue.capacity())));
+        }
+        // OTLP exporters only retry RESOURCE_EXHAUSTED if it comes with RetryInfo, so it's UNAVAILABLE for this too: they'd drop the batch otherwise
+        self.rate_limiter.check(&self.rate_limiter.key_for(token.as_deref(), ip), events.len(), request.get_ref().encoded_len())
+            .map_err(|limited| tonic::Status::unavailable(limited.to_string()))?;
+        // UNAVAILABLE is the one OTLP exporters retry with backoff
+        tenant.queue.enqueue(events)
+            .map_err(|_| tonic::Status::unavailable("ingest queue is full, try again later"))?;
+        Ok(tonic::Response::new(proto::ExportLogsServiceResponse::default()))
+    }
+}
+
+///
+/// opentelemetry.proto.collector.logs.v1.LogsService, written out by hand (it's one method, it's not worth a build.rs)
+///
+#[derive(Clone)]
+pub struct LogsServiceServer{
+    receiver: Arc<Receiver>,
+}
+
+const EXPORT_PATH: &str = "/opentelemetry.proto.collector.logs.v1.LogsService/Export";
+
+impl tonic::server::NamedService for LogsServiceServer{
+    const NAME: &'static str = "opentelemetry.proto.collector.logs.v1.LogsService";
+}
+
+struct ExportService(Arc<Receiver>);
+
+impl tonic::server::UnaryService<proto::ExportLogsServiceRequest> for ExportService{
+    type Response = proto::ExportLogsServiceResponse;
+    type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+
+    fn call(&mut self, request: tonic::Request<proto::ExportLogsServiceRequest>) -> Self::Future {
+        let response = self.0.export(request);
+        Box::pin(async move { response })
+    }
+}
+
+impl<B> Service<http::Request<B>> for LogsServiceServer
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        if request.uri().path() != EXPORT_PATH {
+            return Box::pin(async move {
+                Ok(tonic::Status::unimplemented(format!("{} isn't something we do", request.uri().path())).into_http())
+            });
+        }
+        let receiver = self.receiver.clone();
+        Box::pin(async move {
+            let mut grpc = tonic::server::Grpc::new(tonic::codec::ProstCodec::default())
+                .accept_compressed(CompressionEncoding::Gzip);
+            Ok(grpc.unary(ExportService(receiver), request).await)
+        })
+    }
+}
+
+///
+/// Serve OTLP/gRPC on `port` until the process exits.
+///
+pub async fn serve_grpc(port: u16, tenants: Arc<crate::tenant::Tenants>, ingest_policy: Arc<crate::auth::IngestPolicy>, rate_limiter: Arc<crate::rate_limit::IngestRateLimiter>, standby: Option<Arc<crate::replication::Standby>>, tls: Option<Arc<crate::tls::Tls>>) -> Result<()> {
+    let service = LogsServiceServer{
+        receiver: Arc::new(Receiver{ tenants, ingest_policy, rate_limiter, standby }),
+    };
+    let address = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+    let Some(tls) = tls else {
+        tracing::info!("Listening for OTLP/gRPC on {}", address);
+        tonic::transport::Server::builder()
+            .add_service(service)
+            .serve(address)
+            .await?;
+        return Ok(());
+    };
+    // tonic can't swap certs while it's up either: a renewed one means serving again (see tls::Tls)
+    loop {
+        let mut changes = tls.subscribe();
+        tracing::info!("Listening for OTLP/gRPC over TLS on {} (cert version {})", address, tls.version());
+        tonic::transport::Server::builder()
+            .tls_config(tls.grpc_config())?
+            .add_service(service.clone())
+            .serve_with_shutdown(address, async move {
+                if changes.changed().await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            })
+            .await?;
+    }
+}
+
+#[cfg(test)]
+fn test_request() -> proto::ExportLogsServiceRequest {
+    use proto::*;
+    let string = |value: &str| Some(AnyValue{ value: Some(any_value::Value::StringValue(value.to_string())) });
+    ExportLogsServiceRequest{
+        resource_logs: vec![ResourceLogs{
+            resource: Some(Resource{
+                attributes: vec![
+                    KeyValue{ key: "service.name".to_string(), value: string("checkout") },
+                    KeyValue{ key: "host.name".to_string(), value: string("web-1") },
+                ],
+            }),
+            scope_logs: vec![ScopeLogs{
+                log_records: vec![
+                    LogRecord{
+                        time_unix_nano: 1_000_000_000,
+                        observed_time_unix_nano: 2_000_000_000,
+                        body: string("GET /cart 200"),
+                        ..Default::default()
+                    },
+                    LogRecord{
+                        time_unix_nano: 3_000_000_000,
+                        body: Some(AnyValue{ value: Some(any_value::Value::KvlistValue(KeyValueList{
+                            values: vec![KeyValue{ key: "status".to_string(), value: Some(AnyValue{ value: Some(any_value::Value::IntValue(500)) }) }],
+                        })) }),
+                        ..Default::default()
+                    },
+                ],
+            }],
+        }],
+    }
+}
+
+#[test]
+fn test_to_writable_events() {
+    let events = to_writable_events(&test_request());
+    assert_eq!(events, vec![
+        WritableEvent{ event: "GET /cart 200".to_string(), time: 2_000_000, host: "web-1".to_string() },
+        WritableEvent{ event: "{\"status\": 500}".to_string(), time: 3_000_000, host: "web-1".to_string() },
+    ]);
+}
+
+#[test]
+fn test_decode_http() -> Result<()> {
+    let request = test_request();
+    let body = request.encode_to_vec();
+    assert_eq!(decode_http(&body, None)?, request);
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    std::io::Write::write_all(&mut encoder, &body)?;
+    assert_eq!(decode_http(&encoder.finish()?, Some("gzip"))?, request);
+
+    assert!(decode_http(&body, Some("br")).is_err());
+    Ok(())
+}
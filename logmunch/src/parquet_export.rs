@@ -0,0 +1,189 @@
+use std::io::Write;
+use std::sync::Arc;
+use anyhow::Result;
+use rocket::tokio;
+use arrow_array::{ArrayRef, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use serde::Serialize;
+
+use crate::enrich::EnrichedLog;
+use crate::minute::Log;
+
+pub const CONTENT_TYPE: (&str, &str) = ("application", "vnd.apache.parquet");
+
+const ZSTD_LEVEL: i32 = 3;
+
+///
+/// How much ChannelWriter holds on to before it sends it
+///
+const CHUNK_BYTES: usize = 1024 * 1024;
+
+///
+/// What went into an export
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ExportReport{
+    pub minutes: usize,
+    pub logs: u64,
+    /// minutes in range that aren't sealed yet (seal them with /admin/seal first)
+    pub skipped: usize,
+}
+
+///
+/// Sealed minutes as one Parquet file, for DuckDB, Spark, polars and friends: the same columns as `?format=arrow&fields=all`
+/// (id, time, host, message, shard, level, fields as a JSON object, minute_id), plus a column of their own for each of `columns`,
+/// pulled out of the message the same way `fields` is. Every minute is a row group of its own, oldest log first, so a reader
+/// that only wants some of the time range can skip the rest.
+///
+pub struct ParquetExport<W: Write + Send>{
+    writer: ArrowWriter<W>,
+    schema: Arc<Schema>,
+    columns: Vec<String>,
+    report: ExportReport,
+}
+
+impl<W: Write + Send> ParquetExport<W>{
+    pub fn new(out: W, columns: &[String]) -> Result<ParquetExport<W>> {
+        let mut fields = crate::arrow_export::enriched_fields();
+        for column in columns {
+            if column.trim().is_empty() || fields.iter().any(|field| field.name() == column) {
+                return Err(anyhow::anyhow!("Can't export '{}' as a column of its own: it's empty, or already a column", column));
+            }
+            fields.push(Field::new(column, DataType::Utf8, true));
+        }
+        let schema = Arc::new(Schema::new(fields));
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::try_new(ZSTD_LEVEL)?))
+            .build();
+        Ok(ParquetExport{
+            writer: ArrowWriter::try_new(out, schema.clone(), Some(properties))?,
+            schema,
+            columns: columns.to_vec(),
+            report: ExportReport::default(),
+        })
+    }
+
+    ///
+    /// One minute's logs, as its own row group
+    ///
+    pub fn write_minute(&mut self, mut logs: Vec<Log>) -> Result<()> {
+        // rows in a batch aren't in host time order
+        logs.sort_by_key(|log| (log.time, log.id));
+        let enriched: Vec<EnrichedLog> = logs.into_iter().map(EnrichedLog::new).collect();
+        for chunk in enriched.chunks(crate::arrow_export::BATCH_ROWS) {
+            let mut columns = crate::arrow_export::enriched_columns(chunk);
+            for column in &self.columns {
+                columns.push(Arc::new(chunk.iter().map(|enriched| enriched.fields.get(column).map(|value| value.as_str())).collect::<StringArray>()) as ArrayRef);
+            }
+            self.writer.write(&RecordBatch::try_new(self.schema.clone(), columns)?)?;
+        }
+        self.writer.flush()?;
+        self.report.minutes += 1;
+        self.report.logs += enriched.len() as u64;
+        Ok(())
+    }
+
+    pub fn skip_minute(&mut self) {
+        self.report.skipped += 1;
+    }
+
+    ///
+    /// Write the footer: until then, nothing can read it
+    ///
+    pub fn finish(self) -> Result<(W, ExportReport)> {
+        Ok((self.writer.into_inner()?, self.report))
+    }
+}
+
+///
+/// Hands whatever's written to it over a channel, a chunk at a time, so an export can start going out before it's done.
+/// It blocks when the channel's full: keep it off the async runtime.
+///
+pub struct ChannelWriter{
+    sender: tokio::sync::mpsc::Sender<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl ChannelWriter{
+    pub fn new(sender: tokio::sync::mpsc::Sender<Vec<u8>>) -> ChannelWriter {
+        ChannelWriter{
+            sender,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl Write for ChannelWriter{
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= CHUNK_BYTES {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.sender.blocking_send(std::mem::take(&mut self.buffer))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "nobody's reading the export anymore"))
+    }
+}
+
+///
+/// A whole minute as a Parquet file of its own (see ARCHIVE_PARQUET)
+///
+pub fn encode_minute(logs: Vec<Log>, columns: &[String]) -> Result<Vec<u8>> {
+    let mut export = ParquetExport::new(Vec::new(), columns)?;
+    export.write_minute(logs)?;
+    Ok(export.finish()?.0)
+}
+
+#[test]
+fn test_parquet_export() -> Result<()> {
+    use arrow_array::{Array, Int64Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let minute = |start: i64| (0..10000).rev().map(|n| Log{
+        id: start + n,
+        message: format!("level=info n={} user_id={}", n, n % 7),
+        time: 1700000000000000 + start + n,
+        host: "web-1".to_string(),
+        shard: Some("1-0".to_string()),
+        event_id: None,
+        minute_id: None,
+    }).collect::<Vec<_>>();
+
+    let mut export = ParquetExport::new(Vec::new(), &["user_id".to_string(), "missing".to_string()])?;
+    export.write_minute(minute(0))?;
+    export.write_minute(minute(60000000))?;
+    export.skip_minute();
+    let (bytes, report) = export.finish()?;
+    assert_eq!(report, ExportReport{ minutes: 2, logs: 20000, skipped: 1 });
+
+    let directory = crate::minute::test_data_directory("parquet_export");
+    std::fs::create_dir_all(&directory)?;
+    let path = format!("{}/export.parquet", directory);
+    std::fs::write(&path, bytes)?;
+    let reader = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path)?)?;
+    assert_eq!(reader.metadata().num_row_groups(), 2);
+    let batches = reader.build()?.collect::<std::result::Result<Vec<RecordBatch>, _>>()?;
+    assert_eq!(batches.iter().map(|batch| batch.num_rows()).sum::<usize>(), 20000);
+    // oldest first, whatever order they came in
+    let ids = batches[0].column_by_name("id").unwrap().as_any().downcast_ref::<Int64Array>().unwrap();
+    assert_eq!((ids.value(0), ids.value(1)), (0, 1));
+    let user_ids = batches[0].column_by_name("user_id").unwrap().as_any().downcast_ref::<StringArray>().unwrap();
+    assert_eq!(user_ids.value(3), "3");
+    assert!(batches[0].column_by_name("missing").unwrap().is_null(0));
+    let fields = batches[0].column_by_name("fields").unwrap().as_any().downcast_ref::<StringArray>().unwrap();
+    assert_eq!(fields.value(1), r#"{"level":"info","n":"1","user_id":"1"}"#);
+
+    assert!(ParquetExport::new(Vec::new(), &["host".to_string()]).is_err());
+    assert!(encode_minute(minute(0), &[])?.starts_with(b"PAR1"));
+    std::fs::remove_dir_all(&directory)?;
+    Ok(())
+}
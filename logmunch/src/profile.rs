@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use anyhow::Result;
+use serde::Serialize;
+
+///
+/// Long enough to catch a slow search in the act, short enough that nobody forgets one is running
+///
+pub const MAX_PROFILE_SECONDS: u64 = 60;
+
+///
+/// Samples a second: a little off 100, so we don't march in step with anything that runs every 10ms
+///
+pub const DEFAULT_PROFILE_FREQUENCY: i32 = 99;
+
+///
+/// How many of the hottest functions the summary lists
+///
+const SUMMARY_TOP_FUNCTIONS: usize = 20;
+
+///
+/// What a sample was busy with, going by the innermost frame in its stack that we recognize:
+/// the first one of these whose marker shows up in a frame's name, so a regex running inside the matcher counts as the matcher
+///
+const AREAS: &[(&str, &[&str])] = &[
+    ("bloom", &["search_token::BloomPlan::test", "search_token::SearchTree::bloom_test", "growable_bloom_filter::"]),
+    ("matcher", &["search_token::Search::test", "search_token::SearchTree::test", "search_token::WildcardToken::test"]),
+    ("sqlite", &["sqlite3", "rusqlite::"]),
+];
+
+///
+/// Frames that belong to taking the sample, rather than to whatever got sampled
+///
+const PROFILER_FRAMES: &[&str] = &["__restore_rt", "pprof::profiler::", "pprof::backtrace::"];
+
+///
+/// The profiler takes over SIGPROF for the whole process, so there's only ever one of it
+///
+static PROFILING: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileFormat{
+    /// an SVG you can open in a browser and click around in
+    Flamegraph,
+    /// one line per stack ("thread;outermost;...;innermost count"), for inferno, speedscope, or diffing two profiles
+    Folded,
+    /// JSON: how many samples landed in bloom tests, SQLite, the matcher, or anything else, and the hottest functions
+    Summary,
+}
+
+impl ProfileFormat{
+    pub fn parse(format: Option<&str>) -> Result<ProfileFormat> {
+        match format{
+            None | Some("flamegraph") | Some("svg") => Ok(ProfileFormat::Flamegraph),
+            Some("folded") => Ok(ProfileFormat::Folded),
+            Some("summary") => Ok(ProfileFormat::Summary),
+            Some(other) => Err(anyhow::anyhow!("There's no profile format called '{}': try flamegraph, folded or summary", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ProfileSummary{
+    pub seconds: u64,
+    pub frequency: i32,
+    pub samples: u64,
+    /// bloom, matcher, sqlite and other: see AREAS
+    pub areas: BTreeMap<String, u64>,
+    /// where the samples were when they were taken (self time), hottest first
+    pub top: Vec<(String, u64)>,
+}
+
+///
+/// One stack and how many samples caught it there: the thread's name, then the frames from outermost to innermost
+///
+type Stack = (Vec<String>, u64);
+
+pub struct Profile{
+    pub seconds: u64,
+    pub frequency: i32,
+    stacks: Vec<Stack>,
+}
+
+///
+/// Sample the whole process `frequency` times a second for `seconds`: this blocks the whole time, so keep it off the async runtime
+///
+pub fn capture(seconds: u64, frequency: i32) -> Result<Profile> {
+    if seconds == 0 || seconds > MAX_PROFILE_SECONDS {
+        return Err(anyhow::anyhow!("A profile has to be between 1 and {} seconds", MAX_PROFILE_SECONDS));
+    }
+    if !(1..=1000).contains(&frequency) {
+        return Err(anyhow::anyhow!("frequency has to be between 1 and 1000 samples a second"));
+    }
+    let _profiling = PROFILING.try_lock().map_err(|_| anyhow::anyhow!("Somebody else is already taking a profile: try again when they're done"))?;
+
+    tracing::info!("Profiling for {}s at {} samples a second", seconds, frequency);
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(frequency)
+        // unwinding through these while they hold locks can deadlock
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()?;
+    std::thread::sleep(Duration::from_secs(seconds));
+    let report = guard.report().build()?;
+
+    let stacks = report.data.iter().map(|(frames, count)| {
+        let mut stack = vec![frames.thread_name_or_id()];
+        // pprof has them innermost first
+        stack.extend(frames.frames.iter().rev().flat_map(|frame| frame.iter().rev().map(|symbol| symbol.to_string())));
+        // and every one of them ends in the profiler's own signal handler, which isn't what anybody's asking about
+        if let Some(handler) = stack.iter().position(|frame| PROFILER_FRAMES.iter().any(|profiler| frame.contains(profiler))) {
+            stack.truncate(handler.max(1));
+        }
+        (stack, (*count).max(0) as u64)
+    }).collect();
+    Ok(Profile{ seconds, frequency, stacks })
+}
+
+///
+/// Which of AREAS a stack (outermost frame first) was busy with
+///
+pub fn area(stack: &[String]) -> &'static str {
+    for frame in stack.iter().rev() {
+        for (area, markers) in AREAS {
+            if markers.iter().any(|marker| frame.contains(marker)) {
+                return area;
+            }
+        }
+    }
+    "other"
+}
+
+impl Profile{
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self.stacks.iter().map(|(stack, count)| format!("{} {}", stack.join(";"), count)).collect();
+        lines.sort();
+        lines.join("\n")
+    }
+
+    pub fn flamegraph(&self) -> Result<Vec<u8>> {
+        let mut svg = Vec::new();
+        if self.stacks.is_empty() {
+            return Err(anyhow::anyhow!("Nothing got sampled in {}s: was the server busy?", self.seconds));
+        }
+        let folded = self.folded();
+        let mut options = pprof::flamegraph::Options::default();
+        options.title = format!("logmunch: {}s at {} samples a second", self.seconds, self.frequency);
+        pprof::flamegraph::from_lines(&mut options, folded.lines(), &mut svg)?;
+        Ok(svg)
+    }
+
+    pub fn summary(&self) -> ProfileSummary {
+        let mut summary = ProfileSummary{
+            seconds: self.seconds,
+            frequency: self.frequency,
+            ..Default::default()
+        };
+        let mut functions: BTreeMap<&str, u64> = BTreeMap::new();
+        for (area, _) in AREAS {
+            summary.areas.insert(area.to_string(), 0);
+        }
+        summary.areas.insert("other".to_string(), 0);
+        for (stack, count) in &self.stacks {
+            summary.samples += count;
+            *summary.areas.entry(area(stack).to_string()).or_default() += count;
+            if let Some(innermost) = stack.last() {
+                *functions.entry(innermost).or_default() += count;
+            }
+        }
+        let mut top: Vec<(String, u64)> = functions.into_iter().map(|(function, count)| (function.to_string(), count)).collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(SUMMARY_TOP_FUNCTIONS);
+        summary.top = top;
+        summary
+    }
+}
+
+#[test]
+fn test_profile_summary() -> Result<()> {
+    let stack = |frames: &[&str]| frames.iter().map(|frame| frame.to_string()).collect::<Vec<String>>();
+    let profile = Profile{
+        seconds: 1,
+        frequency: 99,
+        stacks: vec![
+            (stack(&["search-1", "logmunch::minute_db::MinuteDB::prefilter", "logmunch::search_token::BloomPlan::test", "growable_bloom_filter::GrowableBloom::contains"]), 5),
+            (stack(&["search-2", "logmunch::minute::Minute::search_limited", "rusqlite::Statement::step", "sqlite3VdbeExec"]), 7),
+            (stack(&["search-2", "logmunch::minute::Minute::search_limited", "logmunch::search_token::Search::test_log", "logmunch::search_token::WildcardToken::test", "regex::Regex::is_match"]), 3),
+            (stack(&["rocket-worker", "tokio::runtime::park"]), 1),
+        ],
+    };
+    assert_eq!(area(&profile.stacks[2].0), "matcher");
+    let summary = profile.summary();
+    assert_eq!(summary.samples, 16);
+    assert_eq!(summary.areas.get("bloom"), Some(&5));
+    assert_eq!(summary.areas.get("sqlite"), Some(&7));
+    assert_eq!(summary.areas.get("matcher"), Some(&3));
+    assert_eq!(summary.areas.get("other"), Some(&1));
+    assert_eq!(summary.top[0], ("sqlite3VdbeExec".to_string(), 7));
+
+    assert!(profile.folded().lines().any(|line| line == "rocket-worker;tokio::runtime::park 1"));
+    assert!(String::from_utf8(profile.flamegraph()?)?.contains("<svg"));
+    assert_eq!(ProfileFormat::parse(None)?, ProfileFormat::Flamegraph);
+    assert!(ProfileFormat::parse(Some("pdf")).is_err());
+    assert!(capture(MAX_PROFILE_SECONDS + 1, DEFAULT_PROFILE_FREQUENCY).is_err());
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::minute_id::MinuteId;
+
+struct Entry{
+    value: Arc<dyn Any + Send + Sync>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState{
+    minutes: HashMap<MinuteId, HashMap<String, Entry>>,
+    entries: usize,
+    /// goes up by one every time anything's looked at, so we know what was used least recently
+    clock: u64,
+}
+
+///
+/// What a query found in each sealed minute, so that a dashboard asking the same thing every 30 seconds
+/// only has to look at the minutes that are new since last time: sealed minutes never change, so neither does what's in them.
+/// Keyed by (query, minute): the query is whatever the caller says makes two queries the same (see Search::cache_key).
+/// Holds at most `capacity` entries, and forgets the least recently used ones to make room. A capacity of 0 turns it off.
+///
+pub struct QueryCache{
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+impl QueryCache{
+    pub fn new(capacity: usize) -> QueryCache {
+        QueryCache{
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn get<T: Clone + 'static>(&self, query: &str, minute_id: &MinuteId) -> Option<T> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let entry = state.minutes.get_mut(minute_id)?.get_mut(query)?;
+        entry.last_used = clock;
+        entry.value.downcast_ref::<T>().cloned()
+    }
+
+    pub fn insert<T: Send + Sync + 'static>(&self, query: &str, minute_id: &MinuteId, value: T) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let entry = Entry{ value: Arc::new(value), last_used: state.clock };
+        if state.minutes.entry(minute_id.clone()).or_default().insert(query.to_string(), entry).is_none() {
+            state.entries += 1;
+        }
+        if state.entries > self.capacity {
+            Self::evict(&mut state, self.capacity);
+        }
+    }
+
+    ///
+    /// Get down to 7/8ths of `capacity`, least recently used first: evicting in batches means we don't have to sort on every insert
+    ///
+    fn evict(state: &mut CacheState, capacity: usize) {
+        let keep = capacity - capacity / 8;
+        let mut by_age: Vec<(u64, MinuteId, String)> = state.minutes.iter()
+            .flat_map(|(minute_id, queries)| queries.iter().map(|(query, entry)| (entry.last_used, minute_id.clone(), query.clone())))
+            .collect();
+        by_age.sort_unstable_by_key(|(last_used, _, _)| *last_used);
+        let n_evict = by_age.len().saturating_sub(keep);
+        for (_, minute_id, query) in by_age.into_iter().take(n_evict) {
+            if let Some(queries) = state.minutes.get_mut(&minute_id) {
+                queries.remove(&query);
+                if queries.is_empty() {
+                    state.minutes.remove(&minute_id);
+                }
+            }
+        }
+        state.entries -= n_evict;
+    }
+
+    ///
+    /// The minute's gone (or it's been replaced): nothing we remember about it is any good any more
+    ///
+    pub fn forget(&self, minute_id: &MinuteId) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(queries) = state.minutes.remove(minute_id) {
+            state.entries -= queries.len();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[test]
+fn test_query_cache() {
+    let cache = QueryCache::new(8);
+    let minute = |n: u32| MinuteId::new(1, 2, n, "1-0");
+
+    assert_eq!(cache.get::<u64>("error", &minute(1)), None);
+    cache.insert("error", &minute(1), 10u64);
+    cache.insert("warn", &minute(1), 20u64);
+    cache.insert("error", &minute(2), 30u64);
+    assert_eq!(cache.get::<u64>("error", &minute(1)), Some(10));
+    assert_eq!(cache.get::<u64>("warn", &minute(1)), Some(20));
+    // the wrong type is as good as not being there
+    assert_eq!(cache.get::<String>("error", &minute(1)), None);
+    assert_eq!(cache.len(), 3);
+
+    cache.forget(&minute(1));
+    assert_eq!(cache.get::<u64>("error", &minute(1)), None);
+    assert_eq!(cache.len(), 1);
+
+    // full up: the least recently used go first
+    for n in 3..11 {
+        cache.insert("error", &minute(n), n as u64);
+        assert!(cache.get::<u64>("error", &minute(2)).is_some());
+    }
+    assert_eq!(cache.len(), 7);
+    assert_eq!(cache.get::<u64>("error", &minute(2)), Some(30));
+    assert_eq!(cache.get::<u64>("error", &minute(3)), None);
+    assert_eq!(cache.get::<u64>("error", &minute(4)), None);
+    assert_eq!(cache.get::<u64>("error", &minute(10)), Some(10));
+
+    let disabled = QueryCache::new(0);
+    disabled.insert("error", &minute(1), 10u64);
+    assert!(disabled.is_empty());
+}
@@ -0,0 +1,264 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use fxhash::FxHashMap as HashMap;
+use rocket::request::{FromRequest, Outcome, Request};
+
+use crate::config::Config;
+
+///
+/// Past this many senders, the ones that have been quiet long enough to have full buckets get forgotten
+///
+const MAX_TRACKED_SENDERS: usize = 10000;
+
+///
+/// Who a rate limit applies to (see Config::ingest_rate_key)
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateKey{
+    /// each ingest token gets its own limit: senders without a token are told apart by address
+    Token,
+    /// each address gets its own limit, whatever token it's using
+    Host,
+}
+
+impl RateKey{
+    pub fn parse(key: &str) -> Option<RateKey> {
+        match key{
+            "token" => Some(RateKey::Token),
+            "host" => Some(RateKey::Host),
+            _ => None,
+        }
+    }
+}
+
+///
+/// One sender's allowance: it fills up at `per_second`, to at most one second's worth, and every batch takes from it.
+/// A batch only has to find something left in it, not enough for all of it: the bucket goes into debt, and the sender
+/// waits that debt out. That way a batch that's bigger than a second's worth still goes in, eventually.
+///
+#[derive(Debug, Clone)]
+struct Bucket{
+    available: f64,
+    updated: Instant,
+}
+
+impl Bucket{
+    fn new(per_second: u64, now: Instant) -> Bucket {
+        Bucket{ available: per_second as f64, updated: now }
+    }
+
+    fn refill(&mut self, per_second: u64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * per_second as f64).min(per_second as f64);
+        self.updated = now;
+    }
+
+    ///
+    /// How many seconds until there's something in it again
+    ///
+    fn wait_seconds(&self, per_second: u64) -> u64 {
+        match self.available > 0.0{
+            true => 0,
+            false => ((-self.available / per_second as f64).ceil() as u64).max(1),
+        }
+    }
+
+    fn is_full(&self, per_second: u64) -> bool {
+        self.available >= per_second as f64
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Buckets{
+    events: Option<Bucket>,
+    bytes: Option<Bucket>,
+}
+
+///
+/// A batch that's over its sender's limit: nothing in it went in
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimited{
+    pub key: String,
+    pub retry_after_seconds: u64,
+}
+
+impl std::fmt::Display for RateLimited{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is sending more than its ingest rate limit: try again in {}s", self.key, self.retry_after_seconds)
+    }
+}
+
+///
+/// Per-sender limits on how fast logs can come in (INGEST_RATE_EVENTS_PER_SECOND and INGEST_RATE_BYTES_PER_SECOND),
+/// so one service that's lost its mind can't fill the ingest queue for everybody else sharing the node.
+/// Applies to everything that goes through the ingest queue (HEC, OTLP, Loki push, text uploads), and to imports.
+///
+pub struct IngestRateLimiter{
+    events_per_second: Option<u64>,
+    bytes_per_second: Option<u64>,
+    key: RateKey,
+    buckets: Mutex<HashMap<String, Buckets>>,
+}
+
+impl IngestRateLimiter{
+    pub fn new(events_per_second: Option<u64>, bytes_per_second: Option<u64>, key: RateKey) -> IngestRateLimiter {
+        IngestRateLimiter{
+            events_per_second,
+            bytes_per_second,
+            key,
+            buckets: Mutex::new(HashMap::default()),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> IngestRateLimiter {
+        // validate has already turned away anything else
+        let key = RateKey::parse(&config.ingest_rate_key).unwrap_or(RateKey::Token);
+        Self::new(config.ingest_rate_events_per_second, config.ingest_rate_bytes_per_second, key)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.events_per_second.is_some() || self.bytes_per_second.is_some()
+    }
+
+    ///
+    /// Which sender a batch is from: tokens are never written out whole, so they don't end up in errors and logs
+    ///
+    pub fn key_for(&self, token: Option<&str>, ip: Option<IpAddr>) -> String {
+        let host = || match ip{
+            Some(ip) => format!("host {}", ip),
+            None => "host unknown".to_string(),
+        };
+        match (self.key, token){
+            (RateKey::Token, Some(token)) => format!("token {}...", token.chars().take(4).collect::<String>()),
+            _ => host(),
+        }
+    }
+
+    pub fn check(&self, key: &str, events: usize, bytes: usize) -> Result<(), RateLimited> {
+        self.check_at(key, events, bytes, Instant::now())
+    }
+
+    ///
+    /// Take `events` and `bytes` from `key`'s allowance, if it's got any left: if it hasn't, none of it gets taken
+    ///
+    pub fn check_at(&self, key: &str, events: usize, bytes: usize, now: Instant) -> Result<(), RateLimited> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_SENDERS && !buckets.contains_key(key) {
+            self.forget_idle(&mut buckets, now);
+        }
+        let sender = buckets.entry(key.to_string()).or_insert_with(|| Buckets{
+            events: self.events_per_second.map(|per_second| Bucket::new(per_second, now)),
+            bytes: self.bytes_per_second.map(|per_second| Bucket::new(per_second, now)),
+        });
+        let limits = [(&mut sender.events, self.events_per_second, events), (&mut sender.bytes, self.bytes_per_second, bytes)];
+
+        let mut retry_after_seconds = 0;
+        let mut taking = Vec::new();
+        for (bucket, per_second, amount) in limits {
+            let (Some(bucket), Some(per_second)) = (bucket.as_mut(), per_second) else {
+                continue;
+            };
+            bucket.refill(per_second, now);
+            retry_after_seconds = retry_after_seconds.max(bucket.wait_seconds(per_second));
+            taking.push((bucket, amount));
+        }
+        if retry_after_seconds > 0 {
+            return Err(RateLimited{ key: key.to_string(), retry_after_seconds });
+        }
+        for (bucket, amount) in taking {
+            bucket.available -= amount as f64;
+        }
+        Ok(())
+    }
+
+    fn forget_idle(&self, buckets: &mut HashMap<String, Buckets>, now: Instant) {
+        buckets.retain(|_, sender| {
+            let events_full = match (&mut sender.events, self.events_per_second){
+                (Some(bucket), Some(per_second)) => { bucket.refill(per_second, now); bucket.is_full(per_second) },
+                _ => true,
+            };
+            let bytes_full = match (&mut sender.bytes, self.bytes_per_second){
+                (Some(bucket), Some(per_second)) => { bucket.refill(per_second, now); bucket.is_full(per_second) },
+                _ => true,
+            };
+            !(events_full && bytes_full)
+        });
+    }
+}
+
+///
+/// The ingest rate limit for whoever sent this request: the endpoint calls `check` once it knows how many events
+/// (and how many bytes) it's got. With no IngestRateLimiter managed, there's no limit.
+///
+pub struct IngestLimit{
+    limiter: Option<Arc<IngestRateLimiter>>,
+    key: String,
+}
+
+impl IngestLimit{
+    pub fn check(&self, events: usize, bytes: usize) -> Result<(), RateLimited> {
+        match &self.limiter{
+            Some(limiter) => limiter.check(&self.key, events, bytes),
+            None => Ok(()),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IngestLimit {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let limiter = request.rocket().state::<Arc<IngestRateLimiter>>().filter(|limiter| limiter.is_enabled()).cloned();
+        let key = match &limiter{
+            Some(limiter) => {
+                let token = request.headers().get_one("Authorization").and_then(crate::auth::ApiToken::parse);
+                limiter.key_for(token.as_deref(), crate::auth::peer_ip(request))
+            },
+            None => String::new(),
+        };
+        Outcome::Success(IngestLimit{ limiter, key })
+    }
+}
+
+#[test]
+fn test_rate_limit() {
+    let limiter = IngestRateLimiter::new(Some(100), Some(1000), RateKey::Token);
+    let start = Instant::now();
+    let later = |ms: u64| start + std::time::Duration::from_millis(ms);
+    let noisy = limiter.key_for(Some("noisy-token"), None);
+    let quiet = limiter.key_for(Some("quiet-token"), None);
+    assert_eq!(noisy, "token nois...");
+
+    // a second's worth goes straight in
+    assert!(limiter.check_at(&noisy, 100, 100, start).is_ok());
+    assert_eq!(limiter.check_at(&noisy, 1, 1, start), Err(RateLimited{ key: noisy.clone(), retry_after_seconds: 1 }));
+    // somebody else's allowance is their own
+    assert!(limiter.check_at(&quiet, 10, 10, start).is_ok());
+    // half a second later, half a second's worth
+    assert!(limiter.check_at(&noisy, 50, 10, later(500)).is_ok());
+    assert!(limiter.check_at(&noisy, 1, 1, later(500)).is_err());
+
+    // a batch that's more than a second's worth of bytes goes in when there's anything left, and then it's paid off
+    assert!(limiter.check_at(&quiet, 1, 3500, later(1000)).is_ok());
+    assert_eq!(limiter.check_at(&quiet, 1, 1, later(1000)).unwrap_err().retry_after_seconds, 3);
+    assert!(limiter.check_at(&quiet, 1, 1, later(3000)).is_err());
+    assert!(limiter.check_at(&quiet, 1, 1, later(4100)).is_ok());
+
+    // keyed by host, tokens don't matter
+    let by_host = IngestRateLimiter::new(Some(1), None, RateKey::Host);
+    let ip: IpAddr = "10.0.0.7".parse().unwrap();
+    assert_eq!(by_host.key_for(Some("noisy-token"), Some(ip)), "host 10.0.0.7");
+    assert_eq!(limiter.key_for(None, Some(ip)), "host 10.0.0.7");
+
+    // no limits, no buckets
+    let unlimited = IngestRateLimiter::new(None, None, RateKey::Token);
+    assert!(!unlimited.is_enabled());
+    assert!(unlimited.check_at("anybody", 1000000, 1000000, start).is_ok());
+    assert!(unlimited.buckets.lock().unwrap().is_empty());
+}
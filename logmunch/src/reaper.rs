@@ -0,0 +1,111 @@
+use std::sync::Arc;
+use std::time::{SystemTime, Duration};
+use anyhow::Result;
+
+use crate::minute::Minute;
+use crate::minute_db::MinuteDB;
+
+///
+/// Long-running instances slowly accrete resources that nobody is using anymore.
+/// The reaper wanders around every so often and cleans them up:
+///  - connections to minutes that nobody has searched in a while get closed
+///  - WAL files left behind on minutes that are well in the past get checkpointed away
+///
+pub struct Reaper{
+    minute_db: Arc<MinuteDB>,
+    data_directory: String,
+    max_idle: Duration,
+}
+
+impl Reaper{
+    pub fn new(minute_db: Arc<MinuteDB>, data_directory: String, max_idle: Duration) -> Reaper {
+        Reaper{
+            minute_db,
+            data_directory,
+            max_idle,
+        }
+    }
+
+    ///
+    /// Checkpoint the WAL of any minute that's at least a couple of minutes old:
+    /// the writer is definitely done with it, so the WAL is just taking up space.
+    ///
+    pub fn checkpoint_lingering_wals(&self) -> Result<usize> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+        let mut checkpointed = 0;
+        for file in crate::catalog::FileInfo::scan_wal_files(&self.data_directory)? {
+            let minute_id = file.to_minute_id();
+            if minute_id.to_timestamp() > now - 120 {
+                continue;
+            }
+            // if the writer is busy with it, this fails, and that's fine: we'll get it next time
+            let minute = match Minute::new(minute_id.day, minute_id.hour, minute_id.minute, &minute_id.unique_id, &self.data_directory, true){
+                Ok(minute) => minute,
+                Err(_) => continue,
+            };
+            match minute.checkpoint(){
+                Ok(_) => checkpointed += 1,
+                Err(e) => tracing::error!("Error checkpointing minute {}: {}", minute_id, e)
+            }
+        }
+        Ok(checkpointed)
+    }
+
+    pub fn reap(&self) -> Result<()> {
+        let closed = self.minute_db.close_idle(self.max_idle);
+        let checkpointed = self.checkpoint_lingering_wals()?;
+        if closed > 0 || checkpointed > 0 {
+            tracing::info!("Reaper: closed {} idle minutes, checkpointed {} WALs", closed, checkpointed);
+        }
+        Ok(())
+    }
+
+    pub fn reap_loop(&self){
+        // 1 minute (in microseconds)
+        let interval_us = 60 * 1000000;
+
+        loop {
+            std::thread::sleep(std::time::Duration::from_micros(interval_us));
+
+            match self.reap(){
+                Ok(_) => {},
+                Err(e) => {
+                    tracing::error!("Error reaping: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_checkpoint_lingering_wals() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("lingering_wal");
+    let crashed_directory = format!("{}-crashed", data_directory);
+    {
+        let mut minute = Minute::new(1, 2, 3, "wal", &data_directory, true)?;
+        let mut test_data_source = crate::minute::TestData::new();
+        let mut test_data = Vec::new();
+        for _ in 0..100 {
+            test_data.push(crate::minute::generate_test_data(&mut test_data_source));
+        }
+        minute.write_second(test_data)?;
+
+        // take a snapshot of the files while the writer is still mid-minute: that's what a crash leaves behind
+        std::fs::create_dir_all(format!("{}/1/2", crashed_directory))?;
+        std::fs::copy(format!("{}/1/2/3-wal.db", data_directory), format!("{}/1/2/3-wal.db", crashed_directory))?;
+        std::fs::copy(format!("{}/1/2/3-wal.db-wal", data_directory), format!("{}/1/2/3-wal.db-wal", crashed_directory))?;
+    }
+
+    assert_eq!(crate::catalog::FileInfo::scan_wal_files(&crashed_directory)?.len(), 1);
+
+    let minute_db = Arc::new(MinuteDB::new(crashed_directory.clone(), crate::retention::RetentionPolicy::new(10, 1000000000, None), None, None));
+    let reaper = Reaper::new(minute_db, crashed_directory.clone(), Duration::from_secs(600));
+    assert_eq!(reaper.checkpoint_lingering_wals()?, 1);
+    assert_eq!(crate::catalog::FileInfo::scan_wal_files(&crashed_directory)?.len(), 0);
+
+    // and none of the data went missing
+    let minute = Minute::new(1, 2, 3, "wal", &crashed_directory, false)?;
+    assert!(!minute.search(&crate::search_token::Search::new(""))?.is_empty());
+
+    Ok(())
+}
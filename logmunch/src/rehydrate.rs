@@ -0,0 +1,166 @@
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::fs;
+use anyhow::Result;
+
+use crate::archive::Archiver;
+use crate::minute::Minute;
+use crate::minute_id::MinuteId;
+
+struct ScratchMinute{
+    size_bytes: u64,
+    last_used: SystemTime,
+}
+
+///
+/// Historical searches sometimes want minutes that retention already shipped off to the archive.
+/// The Rehydrator pulls those back down into a scratch directory, hands out read-only connections to them,
+/// and throws the least recently used ones away again once the scratch directory gets bigger than its budget.
+///
+pub struct Rehydrator{
+    archiver: Arc<Archiver>,
+    scratch_directory: String,
+    max_scratch_bytes: u64,
+    attached: Mutex<BTreeMap<MinuteId, ScratchMinute>>,
+}
+
+impl Rehydrator{
+    pub fn new(archiver: Arc<Archiver>, scratch_directory: &str, max_scratch_bytes: u64) -> Result<Rehydrator> {
+        // anything left in scratch from last time isn't tracked, so it'd never get evicted: start clean
+        if fs::metadata(scratch_directory).is_ok() {
+            fs::remove_dir_all(scratch_directory)?;
+        }
+        fs::create_dir_all(scratch_directory)?;
+
+        Ok(Rehydrator{
+            archiver,
+            scratch_directory: scratch_directory.to_string(),
+            max_scratch_bytes,
+            attached: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    ///
+    /// Archived minutes can take up to `max_scratch_bytes` of disk while we search them (see Config::rehydrate_scratch_gb).
+    /// 0 turns rehydration off, as does not having an archive in the first place.
+    ///
+    pub fn from_settings(archiver: Option<Arc<Archiver>>, scratch_directory: &str, max_scratch_bytes: u64) -> Result<Option<Rehydrator>> {
+        let archiver = match archiver{
+            Some(archiver) => archiver,
+            None => return Ok(None),
+        };
+        if max_scratch_bytes == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Self::new(archiver, scratch_directory, max_scratch_bytes)?))
+    }
+
+    pub fn archived_in_range(&self, start: Bound<MinuteId>, end: Bound<MinuteId>) -> Vec<MinuteId> {
+        self.archiver.archived_in_range(start, end)
+    }
+
+    fn path_for(&self, minute_id: &MinuteId) -> String {
+        format!("{}/{}/{}/{}-{}.db", self.scratch_directory, minute_id.day, minute_id.hour, minute_id.minute, minute_id.unique_id)
+    }
+
+    ///
+    /// Download the minute if we don't already have it in scratch, and open it.
+    /// The connection stays good even if the file gets evicted out from under it afterwards.
+    ///
+    pub fn open(&self, minute_id: &MinuteId) -> Result<Minute> {
+        let mut attached = self.attached.lock().map_err(|_| anyhow::anyhow!("Error locking scratch minutes"))?;
+
+        if !attached.contains_key(minute_id) {
+            let path = self.path_for(minute_id);
+            self.archiver.restore(minute_id, &path)?;
+            let size_bytes = fs::metadata(&path)?.len();
+            attached.insert(minute_id.clone(), ScratchMinute{
+                size_bytes,
+                last_used: SystemTime::now(),
+            });
+        }
+        if let Some(scratch) = attached.get_mut(minute_id) {
+            scratch.last_used = SystemTime::now();
+        }
+
+        // open it before evicting anything: Minute::new would happily create an empty minute where the file used to be
+        let minute = Minute::new(minute_id.day, minute_id.hour, minute_id.minute, &minute_id.unique_id, &self.scratch_directory, false)?;
+        self.evict(&mut attached, minute_id);
+
+        Ok(minute)
+    }
+
+    ///
+    /// Throw away least-recently-used minutes until we're under budget (but never `keep`, we're about to use it)
+    ///
+    fn evict(&self, attached: &mut BTreeMap<MinuteId, ScratchMinute>, keep: &MinuteId) {
+        let mut total: u64 = attached.values().map(|scratch| scratch.size_bytes).sum();
+        while total > self.max_scratch_bytes {
+            let oldest = attached.iter()
+                .filter(|(minute_id, _)| *minute_id != keep)
+                .min_by_key(|(_, scratch)| scratch.last_used)
+                .map(|(minute_id, _)| minute_id.clone());
+            let oldest = match oldest{
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some(scratch) = attached.remove(&oldest) {
+                total -= scratch.size_bytes;
+            }
+            if let Err(e) = fs::remove_file(self.path_for(&oldest)) {
+                tracing::error!("Error evicting rehydrated minute {}: {}", oldest, e);
+            }
+        }
+    }
+
+    pub fn scratch_bytes(&self) -> u64 {
+        self.attached.lock().unwrap().values().map(|scratch| scratch.size_bytes).sum()
+    }
+}
+
+#[test]
+fn test_rehydrate_and_evict() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("rehydrate");
+    let store = crate::archive::DirectoryStore::new(&format!("{}-bucket", data_directory));
+    let archiver = Arc::new(Archiver::new(Box::new(store), &format!("{}-archive", data_directory))?);
+
+    for minute in 0..3 {
+        {
+            let mut minute = Minute::new(1, 2, minute, "old", &data_directory, true)?;
+            let mut test_data_source = crate::minute::TestData::new();
+            let mut test_data = Vec::new();
+            for _ in 0..100 {
+                test_data.push(crate::minute::generate_test_data(&mut test_data_source));
+            }
+            minute.write_second(test_data)?;
+            minute.seal()?;
+        }
+    }
+    for file in crate::catalog::FileInfo::scan(&data_directory)? {
+        archiver.archive(&file, &format!("{}{}", data_directory, file.path))?;
+    }
+
+    let archived = archiver.archived_in_range(Bound::Included(MinuteId::new(1, 2, 1, "")), Bound::Unbounded);
+    assert_eq!(archived, vec![MinuteId::new(1, 2, 1, "old"), MinuteId::new(1, 2, 2, "old")]);
+
+    // a budget of one byte still lets us open one minute at a time
+    let rehydrator = Rehydrator::new(archiver.clone(), &format!("{}-scratch", data_directory), 1)?;
+    let minute = rehydrator.open(&MinuteId::new(1, 2, 0, "old"))?;
+    assert!(minute.is_sealed()?);
+    let first_size = rehydrator.scratch_bytes();
+    assert!(first_size > 0);
+
+    let other = rehydrator.open(&MinuteId::new(1, 2, 1, "old"))?;
+    assert!(fs::metadata(rehydrator.path_for(&MinuteId::new(1, 2, 0, "old"))).is_err());
+    assert!(fs::metadata(rehydrator.path_for(&MinuteId::new(1, 2, 1, "old"))).is_ok());
+
+    // the evicted minute's connection still works
+    assert!(!minute.search(&crate::search_token::Search::new("presence"))?.is_empty());
+    assert!(!other.search(&crate::search_token::Search::new("presence"))?.is_empty());
+
+    assert!(rehydrator.open(&MinuteId::new(1, 3, 0, "never")).is_err());
+
+    Ok(())
+}
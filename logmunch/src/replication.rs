@@ -0,0 +1,435 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+use crate::catalog::FileInfo;
+use crate::client::{Backoff, Client};
+use crate::config::Config;
+use crate::handshake::{Compatibility, Handshake};
+use crate::minute::Minute;
+use crate::minute_db::MinuteDB;
+use crate::minute_id::MinuteId;
+use crate::tenant::Tenants;
+
+///
+/// Most minutes one tenant copies in one pull. They go newest first, so a standby that's a long way behind
+/// has the recent past before the distant past, and never goes long without noticing that it's been promoted.
+///
+const MAX_MINUTES_PER_PULL: usize = 100;
+
+///
+/// Everything a primary has that a standby might want: every minute it's sealed (unsealed ones are still changing), oldest first
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest{
+    pub machine_id: u32,
+    pub minutes: Vec<ManifestMinute>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestMinute{
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub unique_id: String,
+    pub size_bytes: u64,
+}
+
+impl ManifestMinute{
+    pub fn to_minute_id(&self) -> MinuteId {
+        MinuteId::new(self.day, self.hour, self.minute, &self.unique_id)
+    }
+}
+
+impl Manifest{
+    pub fn new(machine_id: u32, minute_db: &MinuteDB) -> Manifest {
+        let minutes = minute_db.sealed_minutes().into_iter()
+            .filter_map(|minute_id| {
+                // retention might have got to it since
+                let size_bytes = fs::metadata(minute_db.sealed_path(&minute_id)?).ok()?.len();
+                Some(ManifestMinute{
+                    day: minute_id.day,
+                    hour: minute_id.hour,
+                    minute: minute_id.minute,
+                    unique_id: minute_id.unique_id,
+                    size_bytes,
+                })
+            })
+            .collect();
+        Manifest{
+            machine_id,
+            minutes,
+        }
+    }
+
+    ///
+    /// The minutes in here that aren't in `have`, newest first
+    ///
+    pub fn missing(&self, have: &HashSet<MinuteId>) -> Vec<&ManifestMinute> {
+        self.minutes.iter().rev().filter(|minute| !have.contains(&minute.to_minute_id())).collect()
+    }
+
+    ///
+    /// How much of the primary's most recent history we'd lose if we were promoted with only `have`, in seconds:
+    /// from the newest minute we have to the newest minute it has (all of it, if we don't have any)
+    ///
+    pub fn lag_seconds(&self, have: &HashSet<MinuteId>) -> i64 {
+        let (Some(oldest), Some(newest)) = (self.minutes.first(), self.minutes.last()) else {
+            return 0;
+        };
+        let newest_had = self.minutes.iter().rev().find(|minute| have.contains(&minute.to_minute_id()));
+        match newest_had{
+            Some(had) => newest.to_minute_id().to_timestamp() - had.to_minute_id().to_timestamp(),
+            None => newest.to_minute_id().to_timestamp() + 60 - oldest.to_minute_id().to_timestamp(),
+        }
+    }
+}
+
+///
+/// What `GET /admin/replication` says about one tenant on a standby
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicationStatus{
+    pub tenant: Option<String>,
+    pub primary: String,
+    pub promoted: bool,
+    /// when we last heard from the primary, in seconds since the epoch
+    pub last_pull_at: Option<i64>,
+    /// what went wrong with the last pull, if it didn't work
+    pub last_error: Option<String>,
+    /// minutes copied since we started
+    pub minutes_replicated: u64,
+    /// minutes the primary has sealed that we don't have yet
+    pub minutes_behind: usize,
+    /// see Manifest::lag_seconds: 0 is caught up (with the primary as of last_pull_at)
+    pub lag_seconds: i64,
+    /// minutes the primary wrote in a newer format than ours (it's been upgraded and we haven't):
+    /// they stay on the primary until we're upgraded too
+    pub minutes_too_new: usize,
+}
+
+///
+/// One tenant's copy of the primary's same-named tenant
+///
+struct Replica{
+    tenant: Option<String>,
+    minutes_directory: String,
+    /// minutes we've copied (and the primary still has): if our own retention deletes one, we don't copy it again
+    replicated: Mutex<HashSet<MinuteId>>,
+    /// minutes we've downloaded and found to be in a newer format than ours: we don't download them again
+    too_new: Mutex<HashSet<MinuteId>>,
+    status: Mutex<ReplicationStatus>,
+}
+
+///
+/// A warm standby: every `standby_interval_seconds`, every tenant asks the same tenant on the primary for its manifest,
+/// and copies over the sealed minutes it doesn't have yet. They land in the data directory like any other minute,
+/// so the read loop picks them up and they're searchable here too.
+/// Until it's promoted (POST /admin/promote, when the primary's gone), a standby turns ingest away, so nothing gets written
+/// to one node that the other doesn't know about. Promotion stops the copying for good: to go back, start it again as a standby.
+/// Only minutes get copied: markers and minute labels stay where they were made.
+///
+pub struct Standby{
+    primary: Client,
+    primary_url: String,
+    machine_id: u32,
+    interval: Duration,
+    promoted: AtomicBool,
+    /// whether we've said so yet, when the primary's a version apart from us
+    warned_degraded: AtomicBool,
+    replicas: Vec<Replica>,
+}
+
+impl Standby{
+    ///
+    /// None, unless the config says we're a standby (standby_of)
+    ///
+    pub fn new(config: &Config, tenants: &Tenants) -> Option<Standby> {
+        Self::for_tenants(config, tenants.all().map(|tenant| (tenant.name.clone(), tenant.minutes_directory.clone())).collect())
+    }
+
+    ///
+    /// `tenants` is each tenant's name and minutes directory
+    ///
+    fn for_tenants(config: &Config, tenants: Vec<(Option<String>, String)>) -> Option<Standby> {
+        let primary_url = config.standby_of.clone()?;
+        // no retrying inside a pull: the next pull is the retry
+        let mut primary = Client::new(&primary_url).with_backoff(Backoff{ max_attempts: 1, ..Backoff::default() });
+        if let Some(token) = &config.standby_admin_token {
+            primary = primary.with_token(token);
+        }
+        let replicas = tenants.into_iter().map(|(tenant, minutes_directory)| Replica{
+            tenant: tenant.clone(),
+            minutes_directory,
+            replicated: Mutex::new(HashSet::new()),
+            too_new: Mutex::new(HashSet::new()),
+            status: Mutex::new(ReplicationStatus{
+                tenant,
+                primary: primary_url.clone(),
+                ..ReplicationStatus::default()
+            }),
+        }).collect();
+        Some(Standby{
+            primary,
+            primary_url,
+            machine_id: config.machine_id,
+            interval: Duration::from_secs(config.standby_interval_seconds),
+            promoted: AtomicBool::new(false),
+            warned_degraded: AtomicBool::new(false),
+            replicas,
+        })
+    }
+
+    ///
+    /// Should we be taking ingest? Not until we're promoted
+    ///
+    pub fn accepts_ingest(&self) -> bool {
+        self.promoted.load(Ordering::SeqCst)
+    }
+
+    ///
+    /// Become the primary: no more copying, and ingest is open. Doing it twice is fine.
+    ///
+    pub fn promote(&self) -> Vec<ReplicationStatus> {
+        if !self.promoted.swap(true, Ordering::SeqCst) {
+            tracing::warn!("Promoted: no longer a standby of {}, taking ingest", self.primary_url);
+        }
+        self.status()
+    }
+
+    pub fn status(&self) -> Vec<ReplicationStatus> {
+        let promoted = self.accepts_ingest();
+        self.replicas.iter().map(|replica| ReplicationStatus{
+            promoted,
+            ..replica.status.lock().unwrap().clone()
+        }).collect()
+    }
+
+    ///
+    /// Pull from the primary until we're promoted
+    ///
+    pub fn replicate_loop(&self) {
+        tracing::info!("Standby of {}: pulling sealed minutes every {}s", self.primary_url, self.interval.as_secs());
+        while !self.accepts_ingest() {
+            self.pull();
+            std::thread::sleep(self.interval);
+        }
+    }
+
+    fn pull(&self) {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+        let checked = self.primary.handshake().and_then(|primary| {
+            if self.check_primary(&primary)? == Compatibility::Degraded && !self.warned_degraded.swap(true, Ordering::SeqCst) {
+                tracing::warn!("The primary is at api v{} / minute format v{}, a version away from us: minutes in a format we can't read stay on the primary",
+                    primary.api_version, primary.minute_format_version);
+            }
+            Ok(())
+        });
+        for replica in &self.replicas {
+            let result = checked.as_ref()
+                .map_err(|e| anyhow::anyhow!("{}", e))
+                .and_then(|_| replica.pull(&self.primary, &self.promoted));
+            let mut status = replica.status.lock().unwrap();
+            match result{
+                Ok(()) => {
+                    status.last_pull_at = Some(now);
+                    status.last_error = None;
+                },
+                Err(e) => {
+                    tracing::error!("Error replicating {} from {}: {}", replica.tenant.as_deref().unwrap_or("the default tenant"), self.primary_url, e);
+                    status.last_error = Some(e.to_string());
+                },
+            }
+        }
+    }
+
+    ///
+    /// Copying minutes from a node that shares our machine_id would mean writing ours over theirs once we're promoted,
+    /// and a primary more than a version away from us is too far to trust its minutes at all.
+    /// One version away (mid-rolling-upgrade), we copy what we can read: see Replica::copy
+    ///
+    fn check_primary(&self, primary: &Handshake) -> Result<Compatibility> {
+        if primary.machine_id == self.machine_id {
+            return Err(anyhow::anyhow!("the primary has the same machine_id as us ({}): give the standby a different one", self.machine_id));
+        }
+        if Handshake::new(self.machine_id).compatibility(primary) == Compatibility::Incompatible {
+            return Err(anyhow::anyhow!("the primary is at api v{} / minute format v{}: too far from us to copy its minutes", primary.api_version, primary.minute_format_version));
+        }
+        Ok(Handshake::new(self.machine_id).compatibility(primary))
+    }
+}
+
+impl Replica{
+    fn pull(&self, primary: &Client, promoted: &AtomicBool) -> Result<()> {
+        let manifest = primary.replication_manifest(self.tenant.as_deref())?;
+        let on_primary: HashSet<MinuteId> = manifest.minutes.iter().map(|minute| minute.to_minute_id()).collect();
+        let mut replicated = self.replicated.lock().unwrap();
+        replicated.retain(|minute_id| on_primary.contains(minute_id));
+        let mut too_new = self.too_new.lock().unwrap();
+        too_new.retain(|minute_id| on_primary.contains(minute_id));
+
+        let mut have: HashSet<MinuteId> = FileInfo::scan(&self.minutes_directory)?.iter().map(|file| file.to_minute_id()).collect();
+        have.extend(replicated.iter().cloned());
+        let mut skip = have.clone();
+        skip.extend(too_new.iter().cloned());
+        let missing = manifest.missing(&skip);
+
+        let mut copied = 0;
+        let mut newly_replicated = 0;
+        let mut result = Ok(());
+        for minute in missing.iter().take(MAX_MINUTES_PER_PULL) {
+            if promoted.load(Ordering::SeqCst) {
+                break;
+            }
+            let minute_id = minute.to_minute_id();
+            match self.copy(primary, minute, &minute_id){
+                Ok(true) => {
+                    replicated.insert(minute_id.clone());
+                    have.insert(minute_id);
+                    newly_replicated += 1;
+                },
+                Ok(false) => {
+                    too_new.insert(minute_id);
+                },
+                Err(e) => {
+                    result = Err(anyhow::anyhow!("copying minute {}: {}", minute_id, e));
+                    break;
+                },
+            }
+            copied += 1;
+        }
+
+        let mut status = self.status.lock().unwrap();
+        status.minutes_replicated += newly_replicated;
+        status.minutes_behind = missing.len() - copied;
+        status.minutes_too_new = too_new.len();
+        status.lag_seconds = manifest.lag_seconds(&have);
+        result
+    }
+
+    ///
+    /// Download a minute next to where it goes, as a swap file (the catalog skips those, so the read loop never sees half a minute),
+    /// make sure it's all there and sealed, then move it into place.
+    /// False if it's in a newer format than we can read (the primary's been upgraded, and we haven't): it doesn't go anywhere.
+    ///
+    fn copy(&self, primary: &Client, minute: &ManifestMinute, minute_id: &MinuteId) -> Result<bool> {
+        let directory = format!("{}/{}/{}", self.minutes_directory, minute_id.day, minute_id.hour);
+        fs::create_dir_all(&directory)?;
+        let path = format!("{}/{}-{}.db", directory, minute_id.minute, minute_id.unique_id);
+        let swap = format!("{}.swp", path);
+        let checked = primary.download_minute(minute_id, self.tenant.as_deref(), Path::new(&swap))
+            .and_then(|bytes| check_copy(&swap, minute_id, minute.size_bytes, bytes));
+        match checked{
+            Ok(true) => {
+                fs::rename(&swap, &path)?;
+                Ok(true)
+            },
+            Ok(false) => {
+                tracing::info!("Minute {} on the primary is in a newer format than ours: leaving it there", minute_id);
+                let _ = fs::remove_file(&swap);
+                Ok(false)
+            },
+            Err(e) => {
+                let _ = fs::remove_file(&swap);
+                Err(e)
+            }
+        }
+    }
+}
+
+///
+/// Is the minute we downloaded to `swap` all there, and sealed? And can we read it (true), or was it written in a newer format (false)?
+///
+fn check_copy(swap: &str, minute_id: &MinuteId, expected_bytes: u64, bytes: u64) -> Result<bool> {
+    if bytes != expected_bytes {
+        return Err(anyhow::anyhow!("expected {} bytes, got {}", expected_bytes, bytes));
+    }
+    let minute = Minute::open_read_only(swap, minute_id.clone())?;
+    if !minute.is_sealed()? {
+        return Err(anyhow::anyhow!("it isn't sealed"));
+    }
+    Ok(minute.format_version()? <= crate::handshake::MINUTE_FORMAT_VERSION)
+}
+
+#[test]
+fn test_manifest() {
+    let minute = |minute: u32, unique_id: &str| ManifestMinute{ day: 19800, hour: 10, minute, unique_id: unique_id.to_string(), size_bytes: 4096 };
+    let manifest = Manifest{
+        machine_id: 1,
+        minutes: vec![minute(0, "1-0"), minute(1, "1-0"), minute(1, "1-1"), minute(5, "1-0")],
+    };
+    let mut have = HashSet::new();
+    assert_eq!(manifest.missing(&have).len(), 4);
+    assert_eq!(manifest.missing(&have)[0], &minute(5, "1-0"));
+    assert_eq!(manifest.lag_seconds(&have), 360);
+
+    // newest first: once we have the newest, we're not behind on the recent past, even with older gaps
+    have.insert(MinuteId::new(19800, 10, 5, "1-0"));
+    assert_eq!(manifest.lag_seconds(&have), 0);
+    assert_eq!(manifest.missing(&have).len(), 3);
+
+    let have: HashSet<MinuteId> = [MinuteId::new(19800, 10, 1, "1-1"), MinuteId::new(19800, 10, 0, "1-0")].into_iter().collect();
+    assert_eq!(manifest.lag_seconds(&have), 240);
+    assert_eq!(manifest.missing(&have), vec![&minute(5, "1-0"), &minute(1, "1-0")]);
+
+    assert_eq!(Manifest{ machine_id: 1, minutes: vec![] }.lag_seconds(&HashSet::new()), 0);
+}
+
+#[test]
+fn test_standby() -> Result<()> {
+    let primary = Handshake::new(1);
+    let config = Config{
+        machine_id: 2,
+        standby_of: Some("http://127.0.0.1:9".to_string()),
+        standby_admin_token: Some("sekrit".to_string()),
+        ..Config::default()
+    };
+    let tenants = vec![(None, crate::minute::test_data_directory("standby"))];
+    assert!(Standby::for_tenants(&Config::default(), tenants.clone()).is_none());
+
+    let standby = Standby::for_tenants(&config, tenants).unwrap();
+    assert_eq!(standby.check_primary(&primary)?, Compatibility::Full);
+    // mid-upgrade: we still copy, but only what we can read (see check_copy)
+    assert_eq!(standby.check_primary(&Handshake{ minute_format_version: primary.minute_format_version + 1, ..primary.clone() })?, Compatibility::Degraded);
+    assert!(standby.check_primary(&Handshake::new(2)).is_err());
+    assert!(standby.check_primary(&Handshake{ minute_format_version: primary.minute_format_version + 2, ..primary.clone() }).is_err());
+
+    // nobody's listening there: the pull fails, and says so
+    assert!(!standby.accepts_ingest());
+    standby.pull();
+    let status = standby.status();
+    assert_eq!(status.len(), 1);
+    assert!(status[0].last_error.is_some());
+    assert_eq!(status[0].last_pull_at, None);
+
+    assert!(standby.promote().iter().all(|status| status.promoted));
+    assert!(standby.accepts_ingest());
+    // promoted: the loop doesn't pull at all
+    standby.replicate_loop();
+    Ok(())
+}
+
+#[test]
+fn test_check_copy() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("check_copy");
+    let minute_id = MinuteId::new(19800, 10, 5, "1-0");
+    let mut minute = Minute::new(minute_id.day, minute_id.hour, minute_id.minute, &minute_id.unique_id, &data_directory, true)?;
+    minute.write_second(vec![crate::WritableEvent::new("hello", 1, "web-1")])?;
+    minute.seal()?;
+    drop(minute);
+    let path = format!("{}/19800/10/5-1-0.db", data_directory);
+    let size = fs::metadata(&path)?.len();
+
+    assert!(check_copy(&path, &minute_id, size, size)?);
+    assert!(check_copy(&path, &minute_id, size, size - 1).is_err());
+
+    // a primary that's been upgraded before us writes minutes we can't read yet
+    rusqlite::Connection::open(&path)?.pragma_update(None, "user_version", crate::handshake::MINUTE_FORMAT_VERSION + 1)?;
+    assert!(!check_copy(&path, &minute_id, size, size)?);
+    Ok(())
+}
@@ -0,0 +1,242 @@
+use anyhow::Result;
+use crate::catalog::FileInfo;
+
+///
+/// How much data we're willing to keep around. Every limit applies at once:
+/// a minute is removed if it's too old, OR if there are too many minutes, OR if they take up too much disk.
+///
+/// "Old" means when the minute's logs happened (its day/hour/minute), never when its file was written:
+/// a minute backfilled today with last month's logs is a month old, and goes first.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionPolicy{
+    /// u64::MAX for the server (RAM only limits how many blooms stay in memory, see MinuteDB::set_bloom_budget)
+    pub max_minutes: u64,
+    /// set by how much disk space we can use
+    pub max_bytes: u64,
+    /// set by how long anybody actually cares about logs (None = forever, or at least until we run out of space)
+    pub max_age_seconds: Option<i64>,
+    /// imported minutes (see minute_id::IMPORTED_PREFIX) count as being as old as their file, not their logs:
+    /// for when you've imported old logs to look at them, and don't want retention throwing them straight back out
+    pub imported_by_arrival: bool,
+}
+
+impl RetentionPolicy{
+    pub fn new(max_minutes: u64, max_bytes: u64, max_age_seconds: Option<i64>) -> RetentionPolicy {
+        RetentionPolicy{
+            max_minutes,
+            max_bytes,
+            max_age_seconds,
+            imported_by_arrival: false,
+        }
+    }
+
+    ///
+    /// The config's retention_days (and retention_imported_by_arrival) on top of these limits
+    ///
+    pub fn from_config(config: &crate::config::Config, max_minutes: u64, max_bytes: u64) -> Result<RetentionPolicy> {
+        let max_age_seconds = match config.retention_days{
+            Some(days) if !days.is_finite() || days <= 0.0 => return Err(anyhow::anyhow!("retention_days has to be more than 0 (it's {})", days)),
+            Some(days) => Some((days * 86400.0) as i64),
+            None => None,
+        };
+        let mut policy = Self::new(max_minutes, max_bytes, max_age_seconds);
+        policy.imported_by_arrival = config.retention_imported_by_arrival;
+        Ok(policy)
+    }
+
+    ///
+    /// How old retention thinks `file` is, in seconds since the epoch
+    ///
+    pub fn age_key(&self, file: &FileInfo) -> i64 {
+        if self.imported_by_arrival && file.to_minute_id().is_imported() {
+            file.modified_at
+        }
+        else {
+            file.sort_key
+        }
+    }
+
+    ///
+    /// Everything that falls outside of the policy is removed from `files` and returned (oldest last),
+    /// so that the caller can delete it. `files` can be in any order: what's left is sorted most recent first.
+    ///
+    pub fn apply(&self, files: &mut Vec<FileInfo>, now: i64) -> Vec<FileInfo> {
+        let mut expired = Vec::new();
+
+        // ties (shards of the same minute) go by unique id, so we always pick the same shard to go first
+        files.sort_by(|a, b| (self.age_key(b), &b.unique_id).cmp(&(self.age_key(a), &a.unique_id)));
+
+        // anything older than max_age goes, no matter how little data we have
+        if let Some(max_age_seconds) = self.max_age_seconds {
+            let oldest_allowed = now - max_age_seconds;
+            let (keep, old): (Vec<FileInfo>, Vec<FileInfo>) = files.drain(..).partition(|file| self.age_key(file) >= oldest_allowed);
+            *files = keep;
+            expired.extend(old);
+        }
+
+        // if there are more files than max_minutes, the oldest files go
+        if files.len() > self.max_minutes as usize {
+            expired.extend(files.split_off(self.max_minutes as usize));
+        }
+
+        // if the total size of the files is greater than max_bytes, the oldest files go
+        let mut total_bytes: u64 = files.iter().map(|file| file.size_bytes).sum();
+        while total_bytes > self.max_bytes {
+            match files.pop(){
+                Some(file) => {
+                    total_bytes -= file.size_bytes;
+                    expired.push(file);
+                },
+                None => break,
+            }
+        }
+
+        expired
+    }
+}
+
+///
+/// A minute that was written while it was happening
+///
+#[cfg(test)]
+fn test_file(day: i32, hour: i32, minute: i32, size_bytes: u64) -> FileInfo {
+    let sort_key = FileInfo::event_time(day, hour, minute);
+    FileInfo{
+        path: format!("/{}/{}/{}-test.db", day, hour, minute),
+        size_bytes,
+        modified_at: sort_key + 60,
+        day,
+        hour,
+        minute,
+        sort_key,
+        unique_id: "test".to_string(),
+    }
+}
+
+///
+/// A minute with old logs in it, written at `modified_at`
+///
+#[cfg(test)]
+fn backfilled_file(day: i32, hour: i32, minute: i32, unique_id: &str, modified_at: i64) -> FileInfo {
+    FileInfo{
+        path: format!("/{}/{}/{}-{}.db", day, hour, minute, unique_id),
+        unique_id: unique_id.to_string(),
+        modified_at,
+        ..test_file(day, hour, minute, 100)
+    }
+}
+
+#[test]
+fn test_retention_by_count_and_bytes() {
+    let policy = RetentionPolicy::new(3, 250, None);
+    let mut files = vec![test_file(1, 0, 4, 100), test_file(1, 0, 3, 100), test_file(1, 0, 2, 100), test_file(1, 0, 1, 100)];
+
+    let expired = policy.apply(&mut files, 86400 * 2);
+
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0].minute, 4);
+    assert_eq!(files[1].minute, 3);
+    assert_eq!(expired.len(), 2);
+    assert_eq!(expired[0].minute, 1);
+    assert_eq!(expired[1].minute, 2);
+}
+
+#[test]
+fn test_retention_by_age() {
+    // plenty of room, very little data: only the age limit should do anything
+    let policy = RetentionPolicy::new(1000, 1000000, Some(3600));
+    let now = 86400 * 10 + 3600 * 12;
+    let mut files = vec![
+        test_file(10, 11, 30, 100),
+        test_file(10, 11, 0, 100),
+        test_file(10, 10, 59, 100),
+        test_file(3, 0, 0, 100),
+    ];
+
+    let expired = policy.apply(&mut files, now);
+
+    assert_eq!(files.len(), 2);
+    assert_eq!(expired.len(), 2);
+    assert!(expired.iter().all(|file| file.hour == 10 || file.day == 3));
+}
+
+#[test]
+fn test_retention_empty() {
+    let policy = RetentionPolicy::new(0, 0, Some(0));
+    let mut files = Vec::new();
+    assert!(policy.apply(&mut files, 0).is_empty());
+}
+
+#[test]
+fn test_retention_backfill_goes_by_event_time() {
+    let now = 86400 * 10 + 3600 * 12;
+
+    // last week's logs, written just now: they're a week old, and RETENTION_DAYS=1 means they go
+    let policy = RetentionPolicy::new(1000, 1000000, Some(86400));
+    let mut files = vec![backfilled_file(3, 0, 0, "1-0", now), test_file(10, 11, 59, 100)];
+    let expired = policy.apply(&mut files, now);
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].day, 3);
+    assert_eq!(files.len(), 1);
+
+    // and when there are too many minutes, the backfill goes first, however recently it was written
+    //  (and however it came out of the directory scan)
+    let policy = RetentionPolicy::new(2, 1000000, None);
+    let mut files = vec![
+        backfilled_file(3, 0, 1, "1-0", now),
+        test_file(10, 11, 58, 100),
+        backfilled_file(3, 0, 0, "1-0", now - 5),
+        test_file(10, 11, 59, 100),
+    ];
+    let expired = policy.apply(&mut files, now);
+    assert_eq!(files.iter().map(|file| file.minute).collect::<Vec<i32>>(), vec![59, 58]);
+    // oldest last
+    assert_eq!(expired.iter().map(|file| file.minute).collect::<Vec<i32>>(), vec![1, 0]);
+}
+
+#[test]
+fn test_retention_of_imported_minutes() {
+    let now = 86400 * 10 + 3600 * 12;
+    let imported = || vec![
+        backfilled_file(3, 0, 0, "imported-1-0", now - 60),
+        backfilled_file(3, 0, 0, "1-0", now - 60),
+        backfilled_file(2, 0, 0, "imported-1-0", now - 86400 * 2),
+    ];
+
+    // by default, imported minutes are as old as their logs, same as everything else
+    let policy = RetentionPolicy::new(1000, 1000000, Some(86400));
+    let mut files = imported();
+    assert_eq!(policy.apply(&mut files, now).len(), 3);
+
+    // with the override, they're as old as the import: this one was imported a minute ago, so it stays
+    let mut policy = RetentionPolicy::new(1000, 1000000, Some(86400));
+    policy.imported_by_arrival = true;
+    let mut files = imported();
+    let expired = policy.apply(&mut files, now);
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].unique_id, "imported-1-0");
+    assert_eq!(files[0].day, 3);
+    assert_eq!(expired.len(), 2);
+
+    // ... and it sorts as a recent minute when it comes to the count limit, too
+    let mut policy = RetentionPolicy::new(2, 1000000, None);
+    policy.imported_by_arrival = true;
+    let mut files = imported();
+    files.push(test_file(10, 11, 0, 100));
+    let expired = policy.apply(&mut files, now);
+    assert_eq!(files.iter().map(|file| file.unique_id.as_str()).collect::<Vec<&str>>(), vec!["imported-1-0", "test"]);
+    // the day 2 minute was imported two days ago, so it's newer than the day 3 minute that wasn't imported at all
+    assert_eq!(expired.iter().map(|file| (file.day, file.unique_id.as_str())).collect::<Vec<(i32, &str)>>(), vec![(2, "imported-1-0"), (3, "1-0")]);
+}
+
+#[test]
+fn test_retention_from_config() -> Result<()> {
+    let config = crate::config::Config{ retention_days: Some(0.5), retention_imported_by_arrival: true, ..crate::config::Config::default() };
+    let policy = RetentionPolicy::from_config(&config, 10, 1000)?;
+    assert_eq!(policy.max_age_seconds, Some(43200));
+    assert!(policy.imported_by_arrival);
+    assert_eq!(RetentionPolicy::from_config(&crate::config::Config::default(), 10, 1000)?.max_age_seconds, None);
+    assert!(RetentionPolicy::from_config(&crate::config::Config{ retention_days: Some(f64::NAN), ..config }, 10, 1000).is_err());
+    Ok(())
+}
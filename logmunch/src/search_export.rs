@@ -0,0 +1,158 @@
+use anyhow::Result;
+
+use crate::enrich::EnrichedLog;
+use crate::minute::Log;
+
+///
+/// What `/search/<search>/export` sends: a spreadsheet's worth of CSV, or a line of JSON per log for jq
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat{
+    Csv,
+    Jsonl,
+}
+
+impl ExportFormat{
+    pub fn parse(format: Option<&str>) -> Result<ExportFormat> {
+        match format{
+            None | Some("jsonl") | Some("ndjson") => Ok(ExportFormat::Jsonl),
+            Some("csv") => Ok(ExportFormat::Csv),
+            Some(other) => Err(anyhow::anyhow!("exports are csv or jsonl, not '{}'", other)),
+        }
+    }
+
+    pub fn content_type(&self) -> (&'static str, &'static str) {
+        match self{
+            ExportFormat::Csv => ("text", "csv"),
+            ExportFormat::Jsonl => ("application", "x-ndjson"),
+        }
+    }
+}
+
+///
+/// Turns logs into lines of an export. CSV gets id, time (RFC 3339, UTC), host, shard and message, then level, minute_id
+/// and every field (as a JSON object) with `fields=all`, then a column for each of `columns`. JSONL is the same as
+/// `/search/<search>/stream`.
+///
+pub struct ExportLines{
+    format: ExportFormat,
+    enrich: bool,
+    columns: Vec<String>,
+}
+
+impl ExportLines{
+    pub fn new(format: ExportFormat, enrich: bool, columns: &[String]) -> Result<ExportLines> {
+        if format == ExportFormat::Jsonl && !columns.is_empty() {
+            return Err(anyhow::anyhow!("columns is for csv: jsonl gets every field with fields=all"));
+        }
+        Ok(ExportLines{
+            format,
+            enrich,
+            columns: columns.to_vec(),
+        })
+    }
+
+    ///
+    /// What goes before the first log, if anything
+    ///
+    pub fn header(&self) -> Option<String> {
+        if self.format != ExportFormat::Csv {
+            return None;
+        }
+        let mut header: Vec<&str> = vec!["id", "time", "host", "shard", "message"];
+        if self.enrich {
+            header.extend(["level", "minute_id", "fields"]);
+        }
+        header.extend(self.columns.iter().map(|column| column.as_str()));
+        Some(format!("{}\n", header.into_iter().map(csv_field).collect::<Vec<String>>().join(",")))
+    }
+
+    ///
+    /// One log's line, newline and all: `lookups` gets the enriched log first, with `fields=all`
+    ///
+    pub fn line(&self, log: Log, lookups: impl Fn(EnrichedLog) -> EnrichedLog) -> Result<String> {
+        let enriched = match self.enrich || !self.columns.is_empty(){
+            true => EnrichedLog::new(log),
+            false => return self.raw_line(&log),
+        };
+        let enriched = match self.enrich{
+            true => lookups(enriched),
+            false => enriched,
+        };
+        match self.format{
+            ExportFormat::Jsonl => Ok(format!("{}\n", serde_json::to_string(&enriched)?)),
+            ExportFormat::Csv => {
+                let mut row = Self::csv_row(&enriched.log);
+                if self.enrich {
+                    row.push(enriched.level.clone().unwrap_or_default());
+                    row.push(enriched.minute_id.clone().unwrap_or_default());
+                    row.push(serde_json::to_string(&enriched.fields)?);
+                }
+                row.extend(self.columns.iter().map(|column| enriched.fields.get(column).cloned().unwrap_or_default()));
+                Ok(format!("{}\n", row.iter().map(|value| csv_field(value)).collect::<Vec<String>>().join(",")))
+            },
+        }
+    }
+
+    fn raw_line(&self, log: &Log) -> Result<String> {
+        match self.format{
+            ExportFormat::Jsonl => Ok(format!("{}\n", serde_json::to_string(log)?)),
+            ExportFormat::Csv => Ok(format!("{}\n", Self::csv_row(log).iter().map(|value| csv_field(value)).collect::<Vec<String>>().join(","))),
+        }
+    }
+
+    fn csv_row(log: &Log) -> Vec<String> {
+        let time = chrono::DateTime::from_timestamp_micros(log.time)
+            .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Micros, true))
+            .unwrap_or(log.time.to_string());
+        vec![log.id.to_string(), time, log.host.clone(), log.shard.clone().unwrap_or_default(), log.message.clone()]
+    }
+}
+
+///
+/// Quoted if it has to be (RFC 4180). A cell that starts like a formula gets a ' in front, so a log line that
+/// says `=HYPERLINK(...)` stays a log line when somebody opens the export in a spreadsheet.
+///
+pub fn csv_field(value: &str) -> String {
+    let value = match value.starts_with(['=', '+', '-', '@', '\t', '\r']){
+        true => format!("'{}", value),
+        false => value.to_string(),
+    };
+    match value.contains([',', '"', '\n', '\r']){
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value,
+    }
+}
+
+#[test]
+fn test_search_export() -> Result<()> {
+    let log = || Log{
+        id: 7,
+        message: r#"user_id=42 said "hi, there""#.to_string(),
+        time: 1700000000123456,
+        host: "web-1".to_string(),
+        shard: Some("1-0".to_string()),
+        event_id: None,
+        minute_id: None,
+    };
+    let unchanged = |enriched: EnrichedLog| enriched;
+
+    let csv = ExportLines::new(ExportFormat::Csv, false, &["user_id".to_string()])?;
+    assert_eq!(csv.header().as_deref(), Some("id,time,host,shard,message,user_id\n"));
+    assert_eq!(csv.line(log(), unchanged)?, "7,2023-11-14T22:13:20.123456Z,web-1,1-0,\"user_id=42 said \"\"hi, there\"\"\",42\n");
+
+    let all = ExportLines::new(ExportFormat::Csv, true, &[])?;
+    assert_eq!(all.header().as_deref(), Some("id,time,host,shard,message,level,minute_id,fields\n"));
+    assert!(all.line(log(), |mut enriched| { enriched.level = Some("info".to_string()); enriched })?.contains(",info,,"));
+
+    let jsonl = ExportLines::new(ExportFormat::Jsonl, false, &[])?;
+    assert_eq!(jsonl.header(), None);
+    assert_eq!(serde_json::from_str::<Log>(jsonl.line(log(), unchanged)?.trim_end())?, log());
+    assert!(ExportLines::new(ExportFormat::Jsonl, false, &["user_id".to_string()]).is_err());
+
+    assert_eq!(csv_field("=HYPERLINK(\"http://evil\")"), "\"'=HYPERLINK(\"\"http://evil\"\")\"");
+    assert_eq!(csv_field("plain"), "plain");
+    assert_eq!(ExportFormat::parse(Some("csv"))?, ExportFormat::Csv);
+    assert!(ExportFormat::parse(Some("xlsx")).is_err());
+    Ok(())
+}
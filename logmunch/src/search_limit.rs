@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::tokio;
+use rocket::tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::Config;
+use crate::tenant::CallerTenant;
+
+///
+/// How long we tell a search that didn't get a turn to wait before it tries again
+///
+pub const RETRY_AFTER_SECONDS: u32 = 1;
+
+///
+/// How many searches run at once (MAX_CONCURRENT_SEARCHES). Every search is a blocking task that opens and locks minutes
+/// (and uses up to SEARCH_THREADS cores of its own), so without a limit a burst of dashboard refreshes can take every core
+/// the writer needs. Past the limit, a search waits its turn for up to SEARCH_QUEUE_MS, and then it's turned away with a 429.
+/// MAX_CONCURRENT_SEARCHES_PER_TENANT is a second, smaller limit for each tenant: a search needs a turn from both.
+/// A federated search (see federation) takes one turn here and one on every peer it goes to, as the same tenant.
+///
+pub struct SearchLimiter{
+    /// None means there's no limit
+    semaphore: Option<Arc<Semaphore>>,
+    max_concurrent: usize,
+    /// 0 means there's no per-tenant limit
+    max_per_tenant: usize,
+    /// made the first time each tenant (None is the default tenant) searches
+    tenant_semaphores: Mutex<HashMap<Option<String>, Arc<Semaphore>>>,
+    queue_timeout: Duration,
+}
+
+impl SearchLimiter{
+    pub fn new(max_concurrent: usize, max_per_tenant: usize, queue_timeout: Duration) -> SearchLimiter {
+        SearchLimiter{
+            semaphore: (max_concurrent > 0).then(|| Arc::new(Semaphore::new(max_concurrent))),
+            max_concurrent,
+            max_per_tenant,
+            tenant_semaphores: Mutex::new(HashMap::new()),
+            queue_timeout,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> SearchLimiter {
+        Self::new(config.max_concurrent_searches, config.max_concurrent_searches_per_tenant, Duration::from_millis(config.search_queue_ms))
+    }
+
+    ///
+    /// A turn for `tenant`, as soon as there is one (or None if the wait's longer than queue_timeout): the search gets to run
+    /// for as long as it holds on to it. The tenant's turn comes first, so a tenant that's used up its own share waits
+    /// without holding on to one of everybody else's.
+    ///
+    pub async fn acquire(&self, tenant: Option<&str>) -> Option<SearchSlot> {
+        let deadline = tokio::time::Instant::now() + self.queue_timeout;
+        let tenant_permit = match self.tenant_semaphore(tenant){
+            Some(semaphore) => Some(wait_for(semaphore, deadline).await?),
+            None => None,
+        };
+        let permit = match &self.semaphore{
+            Some(semaphore) => Some(wait_for(semaphore.clone(), deadline).await?),
+            None => None,
+        };
+        Some(SearchSlot{ _permit: permit, _tenant_permit: tenant_permit })
+    }
+
+    fn tenant_semaphore(&self, tenant: Option<&str>) -> Option<Arc<Semaphore>> {
+        if self.max_per_tenant == 0 {
+            return None;
+        }
+        let mut tenant_semaphores = self.tenant_semaphores.lock().unwrap();
+        let semaphore = tenant_semaphores.entry(tenant.map(str::to_string)).or_insert_with(|| Arc::new(Semaphore::new(self.max_per_tenant)));
+        Some(semaphore.clone())
+    }
+
+    ///
+    /// How many searches are running right now
+    ///
+    pub fn running(&self) -> usize {
+        match &self.semaphore{
+            Some(semaphore) => self.max_concurrent - semaphore.available_permits(),
+            None => 0,
+        }
+    }
+
+    ///
+    /// How many searches `tenant` has running right now (0 if there's no per-tenant limit to count them against)
+    ///
+    pub fn running_for(&self, tenant: Option<&str>) -> usize {
+        match self.tenant_semaphores.lock().unwrap().get(&tenant.map(str::to_string)){
+            Some(semaphore) => self.max_per_tenant - semaphore.available_permits(),
+            None => 0,
+        }
+    }
+}
+
+///
+/// A permit straight away if there's one going, or whichever comes first: a permit, or the deadline
+///
+async fn wait_for(semaphore: Arc<Semaphore>, deadline: tokio::time::Instant) -> Option<OwnedSemaphorePermit> {
+    if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+        return Some(permit);
+    }
+    tokio::time::timeout_at(deadline, semaphore.acquire_owned()).await.ok()?.ok()
+}
+
+///
+/// A search's turn (see SearchLimiter): hold on to it until the search is done, which for a stream means moving it into the stream.
+/// With no SearchLimiter managed, everybody gets one straight away.
+///
+pub struct SearchSlot{
+    _permit: Option<OwnedSemaphorePermit>,
+    _tenant_permit: Option<OwnedSemaphorePermit>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SearchSlot {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(limiter) = request.rocket().state::<Arc<SearchLimiter>>() else {
+            return Outcome::Success(SearchSlot{ _permit: None, _tenant_permit: None });
+        };
+        // a caller without a tenant is about to be turned away by CallerTenant anyway
+        let tenant = request.guard::<CallerTenant>().await.succeeded().and_then(|tenant| tenant.0.name.clone());
+        match limiter.acquire(tenant.as_deref()).await{
+            Some(slot) => Outcome::Success(slot),
+            None => {
+                tracing::warn!("Turned a search away: all {} search slots (or {} of {}'s) were busy for {}ms",
+                    limiter.max_concurrent, limiter.max_per_tenant, tenant.as_deref().unwrap_or("the default tenant"), limiter.queue_timeout.as_millis());
+                Outcome::Error((Status::TooManyRequests, ()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Responder)]
+#[response(status = 429)]
+pub struct TooManySearches(String, Header<'static>);
+
+///
+/// What a search that didn't get a turn gets back: the guard fails before the endpoint runs, so it's a catcher
+///
+#[catch(429)]
+pub fn too_many_searches() -> TooManySearches {
+    TooManySearches(
+        "Too many searches are running right now: try again in a moment".to_string(),
+        Header::new("Retry-After", RETRY_AFTER_SECONDS.to_string()),
+    )
+}
+
+#[test]
+fn test_search_limiter() -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let limiter = SearchLimiter::new(2, 0, Duration::from_millis(50));
+        let first = limiter.acquire(None).await.unwrap();
+        let _second = limiter.acquire(None).await.unwrap();
+        assert_eq!(limiter.running(), 2);
+        // it waited its turn, and its turn didn't come
+        assert!(limiter.acquire(None).await.is_none());
+
+        // ...unless somebody finishes while it's waiting
+        let finishing = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(first);
+        });
+        assert!(limiter.acquire(None).await.is_some());
+        finishing.await.unwrap();
+        assert_eq!(limiter.running(), 1);
+
+        let unlimited = SearchLimiter::new(0, 0, Duration::ZERO);
+        let mut slots = Vec::new();
+        for _ in 0..100 {
+            slots.push(unlimited.acquire(None).await.unwrap());
+        }
+        assert_eq!(unlimited.running(), 0);
+
+        // one tenant using up its own share doesn't hold anybody else up
+        let limiter = SearchLimiter::new(2, 1, Duration::from_millis(50));
+        let checkout = limiter.acquire(Some("checkout")).await.unwrap();
+        assert!(limiter.acquire(Some("checkout")).await.is_none());
+        assert_eq!((limiter.running(), limiter.running_for(Some("checkout"))), (1, 1));
+        let _default = limiter.acquire(None).await.unwrap();
+        assert_eq!(limiter.running(), 2);
+        // ...but everybody still needs one of the overall slots, and they're all gone
+        assert!(limiter.acquire(Some("platform")).await.is_none());
+        assert_eq!(limiter.running_for(Some("platform")), 0);
+        drop(checkout);
+        assert!(limiter.acquire(Some("platform")).await.is_some());
+    });
+    Ok(())
+}
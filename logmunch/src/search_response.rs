@@ -0,0 +1,133 @@
+use rocket::http::{ContentType, Header};
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket::response::status::BadRequest;
+use rocket::serde::json::Json;
+use serde::Serialize;
+
+use crate::minute_db::SearchStats;
+use crate::trace::TraceContext;
+
+///
+/// `?fields=raw` (the default) returns logs as they were stored (and which shard they were stored in),
+/// `?fields=all` adds everything we can derive from them (level, key=value fields, minute id).
+/// `?format=arrow` sends either of those as an Arrow IPC stream instead of JSON (see arrow_export).
+///
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum SearchResults{
+    Raw(Vec<crate::minute::Log>),
+    All(Vec<crate::enrich::EnrichedLog>),
+    Histogram(Vec<crate::minute_db::HistogramBucket>),
+    Stats(crate::minute_db::StatsResult),
+    #[serde(skip)]
+    Arrow(Vec<u8>),
+}
+
+///
+/// Search results, with everything we know about how we got them tucked into the headers
+/// (so that the body stays a plain list of logs):
+///  - `traceparent`: the trace this search was part of
+///  - `Server-Timing`: where the time went, in a format browsers' dev tools understand
+///  - `X-Logmunch-Trace`: the per-node breakdown, as JSON
+///  - `X-Logmunch-Hint`: if a node stopped early (see SearchHint), what it did cover and how to get the rest, as JSON
+///  - `X-Logmunch-Truncated`: `true`, if a node ran out of time (SEARCH_TIMEOUT_MS) and only sent what it had found by then
+///  - `X-Logmunch-Retention`: if the search started before the oldest log a node still has (see RetentionWarning), as JSON
+///  - `X-Logmunch-Markers`: deploys and incidents and the like from the time range searched (see markers), as JSON
+///  - `X-Logmunch-Degraded`: if the node was shedding load (see load_shedding), why: the search was cut down to fit
+///  - `X-Logmunch-Partial`: `true`, if a federated peer couldn't be searched (its stats in X-Logmunch-Trace say why)
+///
+pub struct SearchResponse{
+    pub results: SearchResults,
+    pub trace: TraceContext,
+    pub stats: Vec<SearchStats>,
+    pub markers: Vec<crate::markers::Marker>,
+    pub degraded: Option<String>,
+}
+
+impl SearchResponse{
+    pub fn server_timing(&self) -> String {
+        let mut timings = Vec::new();
+        for stats in &self.stats {
+            timings.push(format!("bloom-{};dur={:.3}", stats.node, stats.bloom_us as f64 / 1000.0));
+            timings.push(format!("scan-{};dur={:.3}", stats.node, stats.scan_us as f64 / 1000.0));
+            timings.push(format!("total-{};dur={:.3}", stats.node, stats.total_us as f64 / 1000.0));
+        }
+        timings.join(", ")
+    }
+}
+
+impl<'r> Responder<'r, 'static> for SearchResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let server_timing = self.server_timing();
+        let trace = serde_json::to_string(&self.stats).unwrap_or_default();
+        let mut response = match self.results{
+            SearchResults::Arrow(bytes) => {
+                let (top, sub) = crate::arrow_export::CONTENT_TYPE;
+                (ContentType::new(top, sub), bytes).respond_to(request)?
+            },
+            results => Json(results).respond_to(request)?,
+        };
+        response.set_raw_header("traceparent", self.trace.traceparent());
+        response.set_raw_header("Server-Timing", server_timing);
+        response.set_raw_header("X-Logmunch-Trace", trace);
+        if let Some(hint) = self.stats.iter().find_map(|stats| stats.hint.as_ref()) {
+            response.set_raw_header("X-Logmunch-Hint", serde_json::to_string(hint).unwrap_or_default());
+        }
+        if self.stats.iter().any(|stats| stats.truncated) {
+            response.set_raw_header("X-Logmunch-Truncated", "true");
+        }
+        if let Some(retention) = self.stats.iter().find_map(|stats| stats.retention.as_ref()) {
+            response.set_raw_header("X-Logmunch-Retention", serde_json::to_string(retention).unwrap_or_default());
+        }
+        if !self.markers.is_empty() {
+            response.set_raw_header("X-Logmunch-Markers", serde_json::to_string(&self.markers).unwrap_or_default());
+        }
+        if self.stats.iter().any(|stats| stats.error.is_some()) {
+            response.set_raw_header("X-Logmunch-Partial", "true");
+        }
+        if let Some(degraded) = self.degraded {
+            response.set_raw_header("X-Logmunch-Degraded", degraded);
+        }
+        Ok(response)
+    }
+}
+
+///
+/// Why a search didn't happen: something wrong with it (400), or the node is degraded and it's too expensive right now (503)
+///
+#[derive(Debug, Responder)]
+pub enum SearchError{
+    #[response(status = 400)]
+    BadRequest(String),
+    #[response(status = 403)]
+    Forbidden(String),
+    #[response(status = 404)]
+    NotFound(String),
+    #[response(status = 503)]
+    Overloaded(String, Header<'static>),
+}
+
+impl From<BadRequest<String>> for SearchError{
+    fn from(bad_request: BadRequest<String>) -> SearchError {
+        SearchError::BadRequest(bad_request.0)
+    }
+}
+
+#[test]
+fn test_server_timing() {
+    let response = SearchResponse{
+        results: SearchResults::Raw(Vec::new()),
+        trace: TraceContext::new(),
+        stats: vec![SearchStats{
+            node: 3,
+            bloom_us: 1500,
+            scan_us: 20000,
+            total_us: 22000,
+            ..SearchStats::default()
+        }],
+        markers: Vec::new(),
+        degraded: None,
+    };
+    assert_eq!(response.server_timing(), "bloom-3;dur=1.500, scan-3;dur=20.000, total-3;dur=22.000");
+}
@@ -1,3 +1,6 @@
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
 use growable_bloom_filter::GrowableBloom;
 use serde::{Serialize, Deserialize};
 //use std::collections::HashSet;
@@ -9,201 +12,540 @@ pub struct SearchToken{
     pub trigrams: HashSet<String>,
 }
 
+///
+/// How a term matches, when it's not the usual case-insensitive substring:
+/// `cs:Error` only matches "Error", `word:error` doesn't match "errors", and `cs:word:Error` is both.
+/// (`?case_sensitive=true` and `?whole_word=true` do the same for every term in the search)
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Modifiers{
+    pub case_sensitive: bool,
+    /// nothing but whitespace or punctuation (or the start or end of the log) on either side
+    pub whole_word: bool,
+}
+
+const CASE_SENSITIVE_PREFIX: &str = "cs:";
+const WHOLE_WORD_PREFIX: &str = "word:";
+
+impl Modifiers{
+    ///
+    /// Take the `cs:` and `word:` prefixes off the front of a term (in any order, in any case), and say what's left
+    ///
+    fn strip<'a>(&self, term: &'a str) -> (Modifiers, &'a str) {
+        let mut modifiers = *self;
+        let mut term = term;
+        loop{
+            let strip_prefix = |term: &'a str, prefix: &str| term.get(..prefix.len())
+                .filter(|start| start.eq_ignore_ascii_case(prefix))
+                .map(|_| &term[prefix.len()..])
+                .filter(|rest| !rest.is_empty());
+            if let Some(rest) = strip_prefix(term, CASE_SENSITIVE_PREFIX) {
+                modifiers.case_sensitive = true;
+                term = rest;
+            }
+            else if let Some(rest) = strip_prefix(term, WHOLE_WORD_PREFIX) {
+                modifiers.whole_word = true;
+                term = rest;
+            }
+            else {
+                return (modifiers, term);
+            }
+        }
+    }
+
+    ///
+    /// Is this nothing but modifiers so far? (`cs:"Two Words"` opens its quotes after the prefix)
+    ///
+    fn is_prefix(term: &str) -> bool {
+        let lowercase = term.to_lowercase();
+        let mut rest = lowercase.as_str();
+        while !rest.is_empty() {
+            match rest.strip_prefix(CASE_SENSITIVE_PREFIX).or_else(|| rest.strip_prefix(WHOLE_WORD_PREFIX)){
+                Some(stripped) => rest = stripped,
+                None => return false,
+            }
+        }
+        !term.is_empty()
+    }
+}
+
+fn is_word_character(character: char) -> bool {
+    character.is_alphanumeric() || character == '_'
+}
+
+///
+/// Is `needle` somewhere in `haystack` with no word characters right up against either side of it?
+///
+fn contains_word(haystack: &str, needle: &str) -> bool {
+    haystack.match_indices(needle).any(|(start, _)| {
+        let before = haystack[..start].chars().next_back();
+        let after = haystack[start + needle.len()..].chars().next();
+        !before.is_some_and(is_word_character) && !after.is_some_and(is_word_character)
+    })
+}
+
+///
+/// Limits on what a search can be, so that nothing anybody types can run us out of stack (the parser and the tree both recurse)
+/// or build a regex big enough to fail: real searches are nowhere near any of these
+///
+pub const MAX_SEARCH_CHARACTERS: usize = 4096;
+pub const MAX_SEARCH_TERMS: usize = 256;
+pub const MAX_SEARCH_NESTING: usize = 32;
+pub const MAX_SEARCH_WILDCARDS: usize = 32;
+
+///
+/// What the tokenizer leaves where there was a `*` that wasn't quoted or escaped: a character nobody types
+///
+const WILDCARD: char = '\u{E000}';
+
+///
+/// `user_*` (a word that starts with user_), `*timeout` (a word that ends in timeout), `conn*refused` (both, with anything but whitespace in between).
+/// The ends that don't have a `*` on them have to be at the edge of a word, same as `word:`.
+/// `pieces` are the literal bits between the wildcards, and their trigrams are the only ones we can look for.
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WildcardToken{
+    pub pieces: Vec<String>,
+    pub trigrams: HashSet<String>,
+    pub case_sensitive: bool,
+    #[serde(skip)]
+    regex: OnceLock<regex::Regex>,
+}
+
+impl WildcardToken{
+    fn new(pattern: &str, case_sensitive: bool) -> WildcardToken {
+        let pattern = match case_sensitive{
+            true => pattern.to_string(),
+            false => pattern.to_lowercase(),
+        };
+        let pieces: Vec<String> = pattern.split(WILDCARD).map(|piece| piece.to_string()).collect();
+        let mut trigrams = HashSet::default();
+        for piece in &pieces {
+            crate::minute::Minute::explode(&mut trigrams, &piece.to_lowercase());
+        }
+        WildcardToken{ pieces, trigrams, case_sensitive, regex: OnceLock::new() }
+    }
+
+    fn regex(&self) -> &regex::Regex {
+        self.regex.get_or_init(|| {
+            // \S* is the wildcard: it doesn't cross whitespace, because words don't
+            let body = self.pieces.iter().map(|piece| regex::escape(piece)).collect::<Vec<String>>().join(r"\S*");
+            let start = if self.pieces.first().is_some_and(|piece| !piece.is_empty()) { r"(?:^|[^\w])" } else { "" };
+            let end = if self.pieces.last().is_some_and(|piece| !piece.is_empty()) { r"(?:$|[^\w])" } else { "" };
+            regex::Regex::new(&format!("{}{}{}", start, body, end)).expect("escaped pieces always make a valid regex")
+        })
+    }
+
+    fn test(&self, event: &str) -> bool {
+        match self.case_sensitive{
+            true => self.regex().is_match(event),
+            false => self.regex().is_match(&event.to_lowercase()),
+        }
+    }
+}
+
+impl PartialEq for WildcardToken{
+    fn eq(&self, other: &Self) -> bool {
+        self.pieces == other.pieces && self.case_sensitive == other.case_sensitive
+    }
+}
+
+impl Eq for WildcardToken{}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SearchTree{
     None,
     Token(SearchToken),
+    /// a token with `cs:` or `word:` (or both) in front of it: its trigrams are still lowercase, because that's how they're stored
+    Modified(SearchToken, Modifiers),
+    Wildcard(WildcardToken),
+    /// `has:trace_id`: the log has a trace_id field (see enrich::extract_fields) with something in it.
+    /// `missing:trace_id` is Not(HasField(trace_id)).
+    HasField(SearchToken),
     Not(Box<SearchTree>),
     And(Box<SearchTree>, Box<SearchTree>),
     Or(Box<SearchTree>, Box<SearchTree>),
 }
+///
+/// Why a search didn't parse, and where: `position` counts characters from the start of the search, starting at 0
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ParseError{
+    pub position: usize,
+    pub message: String,
+}
+
+impl ParseError{
+    fn new(position: usize, message: &str) -> ParseError {
+        ParseError{ position, message: message.to_string() }
+    }
+}
+
+impl std::fmt::Display for ParseError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (at character {})", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for ParseError{}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LexemeKind{
+    Open,
+    Close,
+    Not,
+    Or,
+    And,
+    /// a word, or everything between a pair of quotes
+    Term(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Lexeme{
+    kind: LexemeKind,
+    /// where it starts, in characters
+    position: usize,
+}
+
+///
+/// Turns lexemes into a tree, lowest precedence first:
+///  - `a | b`: either one
+///  - `a b` (or `a & b`): both
+///  - `!a`: not
+///  - `(a | b) c`: parentheses, to say otherwise
+///
+struct Parser<'a>{
+    lexemes: &'a [Lexeme],
+    next: usize,
+    modifiers: Modifiers,
+    /// where the search ends, for complaining about what's missing from the end of it
+    end: usize,
+    /// how many parentheses deep we are
+    depth: usize,
+}
+
+impl Parser<'_>{
+    fn peek(&self) -> Option<&Lexeme> {
+        self.lexemes.get(self.next)
+    }
+
+    ///
+    /// `a | b` and `a & b` need something on both sides
+    ///
+    fn operand_follows(&self, operator: &Lexeme) -> Result<(), ParseError> {
+        match self.peek().map(|lexeme| &lexeme.kind){
+            Some(LexemeKind::Term(_)) | Some(LexemeKind::Open) | Some(LexemeKind::Not) => Ok(()),
+            _ => Err(ParseError::new(operator.position, match operator.kind{
+                LexemeKind::Or => "| needs something on both sides",
+                _ => "& needs something on both sides",
+            })),
+        }
+    }
+
+    ///
+    /// `a | b` is Or(a, b), and `a | b | c` is Or(a, Or(b, c)) (and likewise for And)
+    ///
+    fn chain(mut trees: Vec<SearchTree>, join: fn(Box<SearchTree>, Box<SearchTree>) -> SearchTree) -> SearchTree {
+        let mut tree = trees.pop().unwrap_or(SearchTree::None);
+        while let Some(left) = trees.pop() {
+            tree = join(Box::new(left), Box::new(tree));
+        }
+        tree
+    }
+
+    fn or(&mut self) -> Result<SearchTree, ParseError> {
+        let mut trees = vec![self.and()?];
+        while let Some(lexeme) = self.peek().filter(|lexeme| lexeme.kind == LexemeKind::Or).cloned() {
+            self.next += 1;
+            self.operand_follows(&lexeme)?;
+            trees.push(self.and()?);
+        }
+        Ok(Self::chain(trees, SearchTree::Or))
+    }
+
+    fn and(&mut self) -> Result<SearchTree, ParseError> {
+        let mut trees = vec![self.not()?];
+        while let Some(lexeme) = self.peek().cloned() {
+            match lexeme.kind{
+                LexemeKind::And => {
+                    self.next += 1;
+                    self.operand_follows(&lexeme)?;
+                },
+                LexemeKind::Term(_) | LexemeKind::Open | LexemeKind::Not => {},
+                _ => break,
+            }
+            trees.push(self.not()?);
+        }
+        Ok(Self::chain(trees, SearchTree::And))
+    }
+
+    fn not(&mut self) -> Result<SearchTree, ParseError> {
+        // !!a is just a
+        let mut negated = false;
+        while let Some(lexeme) = self.peek().filter(|lexeme| lexeme.kind == LexemeKind::Not).cloned() {
+            negated = !negated;
+            self.next += 1;
+            if !matches!(self.peek().map(|next| &next.kind), Some(LexemeKind::Term(_)) | Some(LexemeKind::Open) | Some(LexemeKind::Not)) {
+                return Err(ParseError::new(lexeme.position, "! needs something after it"));
+            }
+        }
+        let tree = self.term()?;
+        Ok(match negated{
+            true => SearchTree::Not(Box::new(tree)),
+            false => tree,
+        })
+    }
+
+    fn term(&mut self) -> Result<SearchTree, ParseError> {
+        let lexeme = match self.peek(){
+            Some(lexeme) => lexeme.clone(),
+            None => return Err(ParseError::new(self.end, "the search ends where there should be something to search for")),
+        };
+        self.next += 1;
+        match lexeme.kind{
+            LexemeKind::Term(term) => Ok(SearchTree::leaf(&term, self.modifiers)),
+            LexemeKind::Open => {
+                if self.peek().is_some_and(|next| next.kind == LexemeKind::Close) {
+                    return Err(ParseError::new(lexeme.position, "there's nothing in these parentheses"));
+                }
+                if self.depth == MAX_SEARCH_NESTING {
+                    return Err(ParseError::new(lexeme.position, &format!("parentheses can only go {} deep", MAX_SEARCH_NESTING)));
+                }
+                self.depth += 1;
+                let tree = self.or()?;
+                match self.peek(){
+                    Some(Lexeme{ kind: LexemeKind::Close, .. }) => {
+                        self.next += 1;
+                        self.depth -= 1;
+                        Ok(tree)
+                    },
+                    _ => Err(ParseError::new(lexeme.position, "this ( is never closed")),
+                }
+            },
+            LexemeKind::Close => Err(ParseError::new(lexeme.position, "there's no ( for this )")),
+            LexemeKind::Or => Err(ParseError::new(lexeme.position, "| needs something on both sides")),
+            LexemeKind::And => Err(ParseError::new(lexeme.position, "& needs something on both sides")),
+            LexemeKind::Not => Err(ParseError::new(lexeme.position, "! needs something after it")),
+        }
+    }
+}
+
+///
+/// For SearchTree::id_range: the first and last log id a set of trigrams all turn up in, if they do
+///
+pub type IdRangeLookup<'a> = dyn Fn(&HashSet<String>) -> Option<(i64, i64)> + 'a;
+
 impl SearchTree {
+    ///
+    /// `modifiers` apply to every term (on top of whatever the term asks for itself)
+    ///
+    pub fn parse(search_string: &str, modifiers: Modifiers) -> Result<Self, ParseError> {
+        let length = search_string.chars().count();
+        if length > MAX_SEARCH_CHARACTERS {
+            return Err(ParseError::new(MAX_SEARCH_CHARACTERS, &format!("searches can be at most {} characters long", MAX_SEARCH_CHARACTERS)));
+        }
+        let lexemes = Self::lex(search_string)?;
+        Self::check_limits(&lexemes)?;
+        Self::build_tree(&lexemes, length, modifiers)
+    }
 
-    pub fn new(search_string: &str) -> Self {
-        let fragments = Self::tokenize(search_string);
-        Self::build_tree(&fragments)
+    fn check_limits(lexemes: &[Lexeme]) -> Result<(), ParseError> {
+        let mut terms = 0;
+        let mut wildcards = 0;
+        for lexeme in lexemes {
+            if let LexemeKind::Term(term) = &lexeme.kind {
+                terms += 1;
+                if terms > MAX_SEARCH_TERMS {
+                    return Err(ParseError::new(lexeme.position, &format!("that's too many terms: a search can have at most {}", MAX_SEARCH_TERMS)));
+                }
+                wildcards += term.chars().filter(|character| *character == WILDCARD).count();
+                if wildcards > MAX_SEARCH_WILDCARDS {
+                    return Err(ParseError::new(lexeme.position, &format!("that's too many *s: a search can have at most {}", MAX_SEARCH_WILDCARDS)));
+                }
+            }
+        }
+        Ok(())
     }
 
-    fn tokenize(search_string: &str) -> Vec<String> {
-        let mut tokens: Vec<String> = Vec::new();
+    fn lex(search_string: &str) -> Result<Vec<Lexeme>, ParseError> {
+        let mut lexemes: Vec<Lexeme> = Vec::new();
         let mut current_token: Vec<char> = Vec::new();
+        // where current_token started
+        let mut start = 0;
+        let finish = |lexemes: &mut Vec<Lexeme>, current_token: &mut Vec<char>, start: usize| {
+            if !current_token.is_empty() {
+                let term: String = current_token.drain(..).collect();
+                // a & on its own is an explicit and; anywhere else it's just part of a word
+                let kind = match term.as_str(){
+                    "&" => LexemeKind::And,
+                    _ => LexemeKind::Term(term),
+                };
+                lexemes.push(Lexeme{ kind, position: start });
+            }
+        };
 
         let mut escape = false;
-        let mut in_quotes = false;
-        for char in search_string.to_lowercase().chars() {
+        // where the quotes we're in were opened
+        let mut quoted_at: Option<usize> = None;
+        // terms get lowercased once we know whether they're case sensitive (see leaf)
+        for (position, char) in search_string.chars().enumerate() {
+            if current_token.is_empty() && quoted_at.is_none() {
+                start = position;
+            }
             if escape {
                 current_token.push(char);
                 escape = false;
             }
-            else if in_quotes && char == '"' {
-                // close quotes
-                tokens.push(current_token.iter().collect());
-                current_token = Vec::new();
-                in_quotes = false;
+            else if quoted_at.is_some() && char == '"' {
+                // close quotes: even "" is something to search for (it matches everything)
+                lexemes.push(Lexeme{ kind: LexemeKind::Term(current_token.drain(..).collect()), position: start });
+                quoted_at = None;
             }
-            else if current_token.len() == 0 && char == '"' {
+            else if (current_token.is_empty() || Modifiers::is_prefix(&current_token.iter().collect::<String>())) && char == '"' {
                 // open quotes
-                in_quotes = true;
+                quoted_at = Some(position);
             }
-            else if in_quotes{
+            else if quoted_at.is_some() {
                 // inside quotes
                 current_token.push(char);
             }
-            else if current_token.len() == 0 && !escape && char == '(' {
-                // open paren
-                tokens.push("(".to_string());
+            else if current_token.is_empty() && char == '(' {
+                lexemes.push(Lexeme{ kind: LexemeKind::Open, position });
             }
-            else if !escape && char == ')'{
-                // close paren
-                tokens.push(")".to_string());
+            else if char == ')' && current_token.iter().filter(|c| **c == '(').count() <= current_token.iter().filter(|c| **c == ')').count() {
+                // (a ')' that closes a '(' in the middle of a word, like foo(bar), is part of the word)
+                finish(&mut lexemes, &mut current_token, start);
+                lexemes.push(Lexeme{ kind: LexemeKind::Close, position });
             }
-            else if current_token.len() == 0 && !escape && char == '!' {
-                // not
-                tokens.push("!".to_string());
+            else if current_token.is_empty() && char == '!' {
+                lexemes.push(Lexeme{ kind: LexemeKind::Not, position });
             }
-            else if current_token.len() == 0 && !escape && char == '|' {
-                // or
-                tokens.push("|".to_string());
+            else if current_token.is_empty() && char == '|' {
+                lexemes.push(Lexeme{ kind: LexemeKind::Or, position });
             }
-            else if char == ' ' {
-                if current_token.len() > 0 {
-                    tokens.push(current_token.iter().collect());
-                    current_token = Vec::new();
-                }
-                else{
-                    // we don't do anything with whitespace outside of quotes
-                }
+            else if char.is_whitespace() {
+                finish(&mut lexemes, &mut current_token, start);
             }
             else if char == '\\' {
                 escape = true;
             }
+            else if char == '*' {
+                // quoted or escaped, a * is just a *
+                current_token.push(WILDCARD);
+            }
             else{
                 current_token.push(char);
             }
         }
 
-        if current_token.len() > 0 {
-            tokens.push(current_token.iter().collect());
+        if let Some(quoted_at) = quoted_at {
+            return Err(ParseError::new(quoted_at, "this quote is never closed"));
         }
+        finish(&mut lexemes, &mut current_token, start);
+        Ok(lexemes)
+    }
 
-        tokens
+    fn build_tree(lexemes: &[Lexeme], end: usize, modifiers: Modifiers) -> Result<SearchTree, ParseError> {
+        if lexemes.is_empty() {
+            return Ok(SearchTree::None);
+        }
+        let mut parser = Parser{ lexemes, next: 0, modifiers, end, depth: 0 };
+        let tree = parser.or()?;
+        match parser.peek(){
+            // the only thing that can stop the parser early is a ) that doesn't close anything
+            Some(lexeme) => Err(ParseError::new(lexeme.position, "there's no ( for this )")),
+            None => Ok(tree),
+        }
     }
 
-    fn quick_trigrams(token: &str) -> HashSet<String> {
-        let mut trigrams: HashSet<String> = HashSet::default();
-        crate::minute::Minute::explode(&mut trigrams, &token.to_string());
-        trigrams
+    ///
+    /// The terms and operators in a search, as strings
+    ///
+    #[cfg(test)]
+    fn tokenize(search_string: &str) -> Vec<String> {
+        Self::lex(search_string).unwrap().into_iter().map(|lexeme| match lexeme.kind{
+            LexemeKind::Open => "(".to_string(),
+            LexemeKind::Close => ")".to_string(),
+            LexemeKind::Not => "!".to_string(),
+            LexemeKind::Or => "|".to_string(),
+            LexemeKind::And => "&".to_string(),
+            LexemeKind::Term(term) => term,
+        }).collect()
     }
 
-    fn build_tree(tokens: &Vec<String>) -> SearchTree {
-        Self::build_tree_int(tokens, false)
+    ///
+    /// ... and back again (a quoted "(" comes back as an open paren, but none of the tests do that)
+    ///
+    #[cfg(test)]
+    fn build_tree_from_strings(tokens: &[String]) -> SearchTree {
+        let lexemes: Vec<Lexeme> = tokens.iter().enumerate().map(|(position, token)| Lexeme{
+            kind: match token.as_str(){
+                "(" => LexemeKind::Open,
+                ")" => LexemeKind::Close,
+                "!" => LexemeKind::Not,
+                "|" => LexemeKind::Or,
+                "&" => LexemeKind::And,
+                term => LexemeKind::Term(term.to_string()),
+            },
+            position,
+        }).collect();
+        Self::build_tree(&lexemes, tokens.len(), Modifiers::default()).unwrap()
     }
 
-    fn build_tree_int(tokens: &Vec<String>, pending_negation: bool) -> SearchTree {
-        let mut stack: Vec<SearchTree> = Vec::new();
-        let mut i = 0;
-        let mut pending_negation = pending_negation;
+    fn quick_trigrams(token: &str) -> HashSet<String> {
+        let mut trigrams: HashSet<String> = HashSet::default();
+        crate::minute::Minute::explode(&mut trigrams, token);
+        trigrams
+    }
 
-        while i < tokens.len() {
-            let token = &tokens[i];
-            if token == "(" {
-                let mut paren_count = 1;
-                let mut j = i + 1;
-                while j < tokens.len() {
-                    if tokens[j] == "(" {
-                        paren_count += 1;
-                    }
-                    else if tokens[j] == ")" {
-                        paren_count -= 1;
-                        if paren_count == 0 {
-                            break;
-                        }
-                    }
-                    j += 1;
-                }
-                let sub_tokens = tokens[i+1..j].to_vec();
-                if pending_negation{
-                    stack.push(SearchTree::Not(Box::new(Self::build_tree(&sub_tokens))));
-                    pending_negation = false;
-                }
-                else{
-                    stack.push(Self::build_tree(&sub_tokens));
-                }
-                i = j;
-            }
-            else if token == "!" {
-                pending_negation = !pending_negation;
-            }
-            else if token == "|" && stack.len() > 0 {
-                pending_negation = false;
-                let left = stack.pop().unwrap();
-                let right = Self::build_tree(&tokens[i+1..].to_vec());
-                stack.push(SearchTree::Or(Box::new(left), Box::new(right)));
-                break;
-            }
-            else if token == "|" && stack.len() == 0 {
-                pending_negation = false;
-                // that's weird, just ignore it
-                continue;
-            }
-            else if token == "&" && stack.len() > 0 {
-                pending_negation = false;
-                let left = stack.pop().unwrap();
-                let right = Self::build_tree(&tokens[i+1..].to_vec());
-                stack.push(SearchTree::And(Box::new(left), Box::new(right)));
-                break;
-            }
-            else if stack.len() == 1{
-                let left = stack.pop().unwrap();
-                let right = Self::build_tree_int(&tokens[i..].to_vec(), pending_negation);
-                stack.push(SearchTree::And(Box::new(left), Box::new(right)));
-                break;
-            }
-            else {
-                if pending_negation{
-                    stack.push(SearchTree::Not(Box::new(SearchTree::Token(
-                        SearchToken {
-                            token: token.to_string(),
-                            trigrams: SearchTree::quick_trigrams(token),
-                        }
-                    ))));
-                    pending_negation = false;
-                }
-                else{
-                    stack.push(SearchTree::Token(
-                        SearchToken {
-                            token: token.to_string(),
-                            trigrams: Self::quick_trigrams(token),
-                        }
-                    ));
-                }
-            }
-            i += 1;
+    ///
+    /// A single search term: usually a plain token, but `has:<field>` and `missing:<field>` check for fields instead.
+    /// The field name still has to be somewhere in the log, so its trigrams narrow things down just like a token's do.
+    ///
+    fn leaf(token: &str, modifiers: Modifiers) -> SearchTree {
+        let field = |name: &str| SearchToken {
+            token: name.to_string(),
+            trigrams: Self::quick_trigrams(name),
+        };
+        let (modifiers, term) = modifiers.strip(token);
+        // nothing but wildcards is just asterisks
+        if term.contains(WILDCARD) && term.chars().any(|character| character != WILDCARD) {
+            return SearchTree::Wildcard(WildcardToken::new(term, modifiers.case_sensitive));
         }
-
-        if stack.len() > 2 {
-            panic!("The fuck?: {:?}", tokens);
+        let term = term.replace(WILDCARD, "*");
+        let term = term.as_str();
+        let lowercase = term.to_lowercase();
+        if let Some(name) = lowercase.strip_prefix("has:").filter(|name| !name.is_empty()) {
+            return SearchTree::HasField(field(name));
         }
-        else if stack.len() == 0 {
-            SearchTree::None
+        if let Some(name) = lowercase.strip_prefix("missing:").filter(|name| !name.is_empty()) {
+            return SearchTree::Not(Box::new(SearchTree::HasField(field(name))));
         }
-        else if stack.len() == 1 {
-            stack.pop().unwrap()
+        if modifiers == Modifiers::default() {
+            return SearchTree::Token(field(&lowercase));
         }
-        else {
-            if pending_negation {
-                SearchTree::Not(Box::new(SearchTree::And(Box::new(stack.pop().unwrap()), Box::new(stack.pop().unwrap()))))
-            }
-            else {
-                SearchTree::And(Box::new(stack.pop().unwrap()), Box::new(stack.pop().unwrap()))
-            }
+        SearchTree::Modified(SearchToken{
+            token: if modifiers.case_sensitive { term.to_string() } else { lowercase.clone() },
+            trigrams: Self::quick_trigrams(&lowercase),
+        }, modifiers)
+    }
+
+    ///
+    /// Is `predicate` true of this node, or anything under it?
+    ///
+    pub fn any(&self, predicate: &dyn Fn(&SearchTree) -> bool) -> bool {
+        predicate(self) || match self {
+            SearchTree::Not(tree) => tree.any(predicate),
+            SearchTree::And(left, right) | SearchTree::Or(left, right) => left.any(predicate) || right.any(predicate),
+            _ => false,
         }
     }
 
     pub fn list_trigrams(&self) -> HashSet<String> {
         match self {
             SearchTree::None => HashSet::default(),
-            SearchTree::Token(token) => token.trigrams.clone(),
+            SearchTree::Token(token) | SearchTree::Modified(token, _) | SearchTree::HasField(token) => token.trigrams.clone(),
+            SearchTree::Wildcard(wildcard) => wildcard.trigrams.clone(),
             SearchTree::Not(_tree) => HashSet::default(), // don't include trigrams from not
             SearchTree::And(left, right) => {
                 let mut trigrams = left.list_trigrams();
@@ -219,6 +561,13 @@ impl SearchTree {
     }
 
     pub fn test(&self, event: &str) -> bool {
+        self.test_fields(event, event, &OnceCell::new())
+    }
+
+    ///
+    /// `message` is where `has:`/`missing:` look for fields: we only pull them out of it if the search actually needs them
+    ///
+    fn test_fields(&self, event: &str, message: &str, fields: &OnceCell<BTreeMap<String, String>>) -> bool {
         match self {
             SearchTree::None => true,
             SearchTree::Token(token) => {
@@ -226,20 +575,36 @@ impl SearchTree {
                 // check if the token is in the event
                 event.to_lowercase().contains(&token.token)
             },
+            SearchTree::Modified(token, modifiers) => {
+                let event = match modifiers.case_sensitive{
+                    true => std::borrow::Cow::Borrowed(event),
+                    false => std::borrow::Cow::Owned(event.to_lowercase()),
+                };
+                match modifiers.whole_word{
+                    true => contains_word(&event, &token.token),
+                    false => event.contains(&token.token),
+                }
+            },
+            SearchTree::Wildcard(wildcard) => wildcard.test(event),
+            SearchTree::HasField(field) => {
+                fields.get_or_init(|| crate::enrich::extract_fields(message))
+                    .iter()
+                    .any(|(key, value)| !value.is_empty() && key.to_lowercase() == field.token)
+            },
             SearchTree::Not(tree) => {
-                !tree.test(event)
+                !tree.test_fields(event, message, fields)
             },
             SearchTree::And(left, right) => {
-                left.test(event) && right.test(event)
+                left.test_fields(event, message, fields) && right.test_fields(event, message, fields)
             },
             SearchTree::Or(left, right) => {
                 if left.as_ref() == &SearchTree::None {
-                    return right.test(event);
+                    return right.test_fields(event, message, fields);
                 }
                 if right.as_ref() == &SearchTree::None {
-                    return left.test(event);
+                    return left.test_fields(event, message, fields);
                 }
-                left.test(event) || right.test(event)
+                left.test_fields(event, message, fields) || right.test_fields(event, message, fields)
             }
         }
     }
@@ -247,13 +612,13 @@ impl SearchTree {
     pub fn bloom_test(&self, filter: &GrowableBloom) -> bool {
         match self {
             SearchTree::None => true,
-            SearchTree::Token(token) => {
-                for trigram in token.trigrams.iter() {
+            SearchTree::Token(SearchToken{ trigrams, .. }) | SearchTree::Modified(SearchToken{ trigrams, .. }, _) | SearchTree::HasField(SearchToken{ trigrams, .. }) | SearchTree::Wildcard(WildcardToken{ trigrams, .. }) => {
+                for trigram in trigrams.iter() {
                     if !filter.contains(trigram) {
                         return false;
                     }
                 }
-                return true;
+                true
             }
             SearchTree::Not(_tree) => true,
             SearchTree::And(left, right) => {
@@ -276,11 +641,37 @@ impl SearchTree {
     /// and returns a boolean. The lambda function should return true if the data set
     /// contains all of the trigrams in the hashset.
     ///
+    ///
+    /// Which logs (by id) could match, given `lookup`, which says which ids a set of trigrams all turn up in
+    /// (None if they don't all turn up in one log). None means nothing can match; (i64::MIN, i64::MAX) means anything could.
+    /// Like lambda_test, except it narrows things down as well as ruling them out.
+    ///
+    pub fn id_range(&self, lookup: &IdRangeLookup) -> Option<(i64, i64)> {
+        match self {
+            SearchTree::None | SearchTree::Not(_) => Some((i64::MIN, i64::MAX)),
+            SearchTree::Token(SearchToken{ trigrams, .. }) | SearchTree::Modified(SearchToken{ trigrams, .. }, _) | SearchTree::HasField(SearchToken{ trigrams, .. }) | SearchTree::Wildcard(WildcardToken{ trigrams, .. }) => {
+                lookup(trigrams)
+            },
+            SearchTree::And(left, right) => {
+                let (left_min, left_max) = left.id_range(lookup)?;
+                let (right_min, right_max) = right.id_range(lookup)?;
+                Some((left_min.max(right_min), left_max.min(right_max))).filter(|(min, max)| min <= max)
+            },
+            SearchTree::Or(left, right) if left.as_ref() == &SearchTree::None => right.id_range(lookup),
+            SearchTree::Or(left, right) if right.as_ref() == &SearchTree::None => left.id_range(lookup),
+            SearchTree::Or(left, right) => match (left.id_range(lookup), right.id_range(lookup)){
+                (Some((left_min, left_max)), Some((right_min, right_max))) => Some((left_min.min(right_min), left_max.max(right_max))),
+                (Some(range), None) | (None, Some(range)) => Some(range),
+                (None, None) => None,
+            },
+        }
+    }
+
     pub fn lambda_test(&self, lambda: &dyn Fn(&HashSet<String>) -> bool) -> bool {
         match self {
             SearchTree::None => true,
-            SearchTree::Token(token) => {
-                lambda(&token.trigrams)
+            SearchTree::Token(SearchToken{ trigrams, .. }) | SearchTree::Modified(SearchToken{ trigrams, .. }, _) | SearchTree::HasField(SearchToken{ trigrams, .. }) | SearchTree::Wildcard(WildcardToken{ trigrams, .. }) => {
+                lambda(trigrams)
             },
             SearchTree::Not(_tree) => {
                 // we should just ignore the tree here
@@ -307,54 +698,201 @@ impl SearchTree {
     }
 }
 
+///
+/// One step of a BloomPlan: `AllOf` is a token, as indices into the plan's trigrams
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BloomStep{
+    Pass,
+    AllOf(Vec<usize>),
+    And(Box<BloomStep>, Box<BloomStep>),
+    Or(Box<BloomStep>, Box<BloomStep>),
+}
+
+///
+/// A search's bloom test, worked out once so it can be run against thousands of minutes' blooms:
+/// every distinct trigram in the search is in one flat list, and gets checked at most once per bloom,
+/// no matter how many tokens it turns up in. Says the same thing SearchTree::bloom_test would.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomPlan{
+    trigrams: Vec<String>,
+    step: BloomStep,
+}
+
+impl BloomPlan{
+    pub fn new(tree: &SearchTree) -> BloomPlan {
+        let mut trigrams = Vec::new();
+        let step = Self::plan(tree, &mut trigrams);
+        BloomPlan{ trigrams, step }
+    }
+
+    fn plan(tree: &SearchTree, trigrams: &mut Vec<String>) -> BloomStep {
+        match tree{
+            SearchTree::None | SearchTree::Not(_) => BloomStep::Pass,
+            SearchTree::Token(SearchToken{ trigrams: token_trigrams, .. }) | SearchTree::Modified(SearchToken{ trigrams: token_trigrams, .. }, _)
+            | SearchTree::HasField(SearchToken{ trigrams: token_trigrams, .. }) | SearchTree::Wildcard(WildcardToken{ trigrams: token_trigrams, .. }) => {
+                let mut indices: Vec<usize> = token_trigrams.iter().map(|trigram| {
+                    match trigrams.iter().position(|known| known == trigram){
+                        Some(index) => index,
+                        None => {
+                            trigrams.push(trigram.clone());
+                            trigrams.len() - 1
+                        },
+                    }
+                }).collect();
+                indices.sort_unstable();
+                BloomStep::AllOf(indices)
+            },
+            SearchTree::And(left, right) => BloomStep::And(Box::new(Self::plan(left, trigrams)), Box::new(Self::plan(right, trigrams))),
+            // an Or with nothing on one side is just the other side (see SearchTree::bloom_test)
+            SearchTree::Or(left, right) if left.as_ref() == &SearchTree::None => Self::plan(right, trigrams),
+            SearchTree::Or(left, right) if right.as_ref() == &SearchTree::None => Self::plan(left, trigrams),
+            SearchTree::Or(left, right) => BloomStep::Or(Box::new(Self::plan(left, trigrams)), Box::new(Self::plan(right, trigrams))),
+        }
+    }
+
+    pub fn trigrams(&self) -> &[String] {
+        &self.trigrams
+    }
+
+    ///
+    /// Does the bloom filter rule anything out? If it doesn't, every minute in the range gets opened and scanned
+    ///
+    pub fn narrows(&self) -> bool {
+        Self::step_narrows(&self.step)
+    }
+
+    fn step_narrows(step: &BloomStep) -> bool {
+        match step{
+            BloomStep::Pass => false,
+            BloomStep::AllOf(indices) => !indices.is_empty(),
+            BloomStep::And(left, right) => Self::step_narrows(left) || Self::step_narrows(right),
+            BloomStep::Or(left, right) => Self::step_narrows(left) && Self::step_narrows(right),
+        }
+    }
+
+    ///
+    /// Could anything in the minute with this bloom filter match?
+    ///
+    pub fn test(&self, filter: &GrowableBloom) -> bool {
+        // 0 is "haven't checked", 1 is "not in there", 2 is "in there"
+        let mut checked = vec![0u8; self.trigrams.len()];
+        self.test_step(&self.step, filter, &mut checked)
+    }
+
+    fn test_step(&self, step: &BloomStep, filter: &GrowableBloom, checked: &mut [u8]) -> bool {
+        match step{
+            BloomStep::Pass => true,
+            BloomStep::AllOf(indices) => indices.iter().all(|&index| {
+                if checked[index] == 0 {
+                    checked[index] = if filter.contains(&self.trigrams[index]) { 2 } else { 1 };
+                }
+                checked[index] == 2
+            }),
+            BloomStep::And(left, right) => self.test_step(left, filter, checked) && self.test_step(right, filter, checked),
+            BloomStep::Or(left, right) => self.test_step(left, filter, checked) || self.test_step(right, filter, checked),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Search{
     pub search_string: String,
-    pub tree: SearchTree
+    pub tree: SearchTree,
+    /// ?case_sensitive and ?whole_word: they're in the tree already, but the search string doesn't say so
+    #[serde(default)]
+    pub modifiers: Modifiers,
 }
 
 impl Search{
+    ///
+    /// For searches we wrote ourselves: one that doesn't parse is a bug, so it panics.
+    /// Anything a person typed goes through `parse`.
+    ///
     pub fn new(search_string: &str) -> Self {
-        Search {
-            search_string: search_string.to_string(),
-            tree: SearchTree::new(search_string)
+        match Self::parse(search_string){
+            Ok(search) => search,
+            Err(err) => panic!("{:?} doesn't parse: {}", search_string, err),
         }
     }
 
+    pub fn parse(search_string: &str) -> Result<Self, ParseError> {
+        Self::parse_with_modifiers(search_string, Modifiers::default())
+    }
+
+    pub fn parse_with_modifiers(search_string: &str, modifiers: Modifiers) -> Result<Self, ParseError> {
+        Ok(Search {
+            search_string: search_string.to_string(),
+            tree: SearchTree::parse(search_string, modifiers)?,
+            modifiers,
+        })
+    }
+
+    ///
+    /// Two searches with the same key find the same things (see QueryCache)
+    ///
+    pub fn cache_key(&self) -> String {
+        format!("{}{} {}", if self.modifiers.case_sensitive { "cs:" } else { "" }, if self.modifiers.whole_word { "word:" } else { "" }, self.search_string)
+    }
+
     pub fn test(&self, event: &str) -> bool {
         self.tree.test(event)
     }
 
+    ///
+    /// Test a stored log: tokens match against "<host> <message>", fields come out of the message alone
+    ///
+    pub fn test_log(&self, host: &str, message: &str) -> bool {
+        self.tree.test_fields(&format!("{} {}", host, message), message, &OnceCell::new())
+    }
+
     pub fn lambda_test(&self, lambda: &dyn Fn(&HashSet<String>) -> bool) -> bool {
         self.tree.lambda_test(lambda)
     }
 
+    pub fn id_range(&self, lookup: &IdRangeLookup) -> Option<(i64, i64)> {
+        self.tree.id_range(lookup)
+    }
+
     pub fn bloom_test(&self, filter: &GrowableBloom) -> bool {
         self.tree.bloom_test(filter)
     }
 
-    pub fn tokens(&self) -> HashSet<String> {
-        self.tree.list_trigrams()
+    pub fn bloom_plan(&self) -> BloomPlan {
+        BloomPlan::new(&self.tree)
     }
 
-    pub fn search_string(&self) -> String {
-        self.search_string.clone()
+    ///
+    /// The first thing in this search that costs a lot more than a plain token (see load_shedding), if there is one
+    ///
+    pub fn expensive_operator(&self) -> Option<&'static str> {
+        if self.tree.any(&|tree| matches!(tree, SearchTree::Wildcard(_))) {
+            return Some("wildcards");
+        }
+        if self.tree.any(&|tree| matches!(tree, SearchTree::HasField(_))) {
+            return Some("has: and missing:");
+        }
+        if !self.bloom_plan().narrows() {
+            return Some("searches that the index can't narrow down (no word of 3 or more letters that has to be there)");
+        }
+        None
     }
 
-    pub fn tree(&self) -> SearchTree {
-        self.tree.clone()
+    pub fn tokens(&self) -> HashSet<String> {
+        self.tree.list_trigrams()
     }
 
 }
 
 #[test]
 fn test_tokenize_and_parse() {
-    let fragments = SearchTree::tokenize(&"hello world".to_string());
+    let fragments = SearchTree::tokenize("hello world");
 
     assert!(fragments.contains(&"hello".to_string()));
     assert!(fragments.contains(&"world".to_string()));
 
-    let tree = SearchTree::build_tree(&fragments);
+    let tree = SearchTree::build_tree_from_strings(&fragments);
 
     assert_eq!(tree,
         SearchTree::And(
@@ -369,12 +907,12 @@ fn test_tokenize_and_parse() {
         )
     );
 
-    let fragments = SearchTree::tokenize(&"hello \"world of tanks\"".to_string());
+    let fragments = SearchTree::tokenize("hello \"world of tanks\"");
 
     assert!(fragments.contains(&"hello".to_string()));
     assert!(fragments.contains(&"world of tanks".to_string()));
 
-    let tree = SearchTree::build_tree(&fragments);
+    let tree = SearchTree::build_tree_from_strings(&fragments);
 
     assert_eq!(tree,
         SearchTree::And(
@@ -389,7 +927,7 @@ fn test_tokenize_and_parse() {
         )
     );
 
-    let fragments = SearchTree::tokenize(&"(hello \"world of tanks\") | (goodbye \"sweet prince\")".to_string());
+    let fragments = SearchTree::tokenize("(hello \"world of tanks\") | (goodbye \"sweet prince\")");
 
     assert_eq!(fragments, vec![
         "(".to_string(),
@@ -402,7 +940,7 @@ fn test_tokenize_and_parse() {
         "sweet prince".to_string(),
         ")".to_string()]);
 
-    let tree = SearchTree::build_tree(&fragments);
+    let tree = SearchTree::build_tree_from_strings(&fragments);
 
     assert_eq!(tree,
         SearchTree::Or(
@@ -429,29 +967,29 @@ fn test_tokenize_and_parse() {
         )
     );
 
-    assert!(tree.test(&"hello world of tanks"));
-    assert!(!tree.test(&"hello sweet goodbye"));
-    assert!(tree.test(&"goodbye sweet prince"));
-    assert!(tree.test(&"sweet prince goodbye"));
-    assert!(tree.test(&"sweet prince---09999 HELLOHLgoodbye=98282"));
-    assert!(tree.test(&"sting stang stung h=hello t=world of tanks"));
+    assert!(tree.test("hello world of tanks"));
+    assert!(!tree.test("hello sweet goodbye"));
+    assert!(tree.test("goodbye sweet prince"));
+    assert!(tree.test("sweet prince goodbye"));
+    assert!(tree.test("sweet prince---09999 HELLOHLgoodbye=98282"));
+    assert!(tree.test("sting stang stung h=hello t=world of tanks"));
 }
 
 #[test]
 fn test_negation() {
-    let fragments = SearchTree::tokenize(&"!hello".to_string());
-    let tree = SearchTree::build_tree(&fragments);
+    let fragments = SearchTree::tokenize("!hello");
+    let tree = SearchTree::build_tree_from_strings(&fragments);
 
-    assert!(!tree.test(&"hello world"));
-    assert!(tree.test(&"goodbye world"));
+    assert!(!tree.test("hello world"));
+    assert!(tree.test("goodbye world"));
 
-    let fragments = SearchTree::tokenize(&"!hello | goodbye".to_string());
-    let tree = SearchTree::build_tree(&fragments);
-    assert!(!tree.test(&"hello world"));
-    assert!(tree.test(&"goodbye world"));
+    let fragments = SearchTree::tokenize("!hello | goodbye");
+    let tree = SearchTree::build_tree_from_strings(&fragments);
+    assert!(!tree.test("hello world"));
+    assert!(tree.test("goodbye world"));
 
-    let fragments = SearchTree::tokenize(&"!hello & !goodbye".to_string());
-    let tree = SearchTree::build_tree(&fragments);
+    let fragments = SearchTree::tokenize("!hello & !goodbye");
+    let tree = SearchTree::build_tree_from_strings(&fragments);
 
     assert_eq!(tree,
         SearchTree::And(
@@ -466,17 +1004,17 @@ fn test_negation() {
         )
     );
 
-    assert!(!tree.test(&"hello world"));
-    assert!(!tree.test(&"goodbye world"));
+    assert!(!tree.test("hello world"));
+    assert!(!tree.test("goodbye world"));
 
-    assert!(!tree.test(&"hello goodbye"));
-    assert!(!tree.test(&"mellow hello how are you feeling goodbye toby"));
-    assert!(tree.test(&"mellow how are you feeling toby"));
+    assert!(!tree.test("hello goodbye"));
+    assert!(!tree.test("mellow hello how are you feeling goodbye toby"));
+    assert!(tree.test("mellow how are you feeling toby"));
 
-    let fragments = SearchTree::tokenize(&"presence !homer".to_string());
+    let fragments = SearchTree::tokenize("presence !homer");
     assert_eq!(fragments, vec!["presence".to_string(), "!".to_string(), "homer".to_string()]);
 
-    let tree = SearchTree::build_tree(&fragments);
+    let tree = SearchTree::build_tree_from_strings(&fragments);
 
     assert_eq!(tree,
         SearchTree::And(
@@ -496,22 +1034,236 @@ fn test_negation() {
 fn test_negation_more(){
     let search = Search::new("presence !homer");
 
-    assert!(!search.test(&"2023-11-10T04:53:04.096624+00:00 girlboss 09c01c523eef 300704 -  212.102.46.118 - - [10/Nov/2023:04:53:04 +0000] \"POST /homer-man-x/presence/update HTTP/1.1\""));
-    assert!(search.test(&"2023-11-10T04:53:04.096624+00:00 girlboss 09c01c523eef 300704 -  212.102.46.118 - - [10/Nov/2023:04:53:04 +0000] \"POST /presence/update HTTP/1.1\""));
+    assert!(!search.test("2023-11-10T04:53:04.096624+00:00 girlboss 09c01c523eef 300704 -  212.102.46.118 - - [10/Nov/2023:04:53:04 +0000] \"POST /homer-man-x/presence/update HTTP/1.1\""));
+    assert!(search.test("2023-11-10T04:53:04.096624+00:00 girlboss 09c01c523eef 300704 -  212.102.46.118 - - [10/Nov/2023:04:53:04 +0000] \"POST /presence/update HTTP/1.1\""));
 
     let search = Search::new("hats !bats !cats !rats mats");
 
-    assert!(search.test(&"mats hats mats"));
-    assert!(search.test(&"hats mats hats"));
-    assert!(!search.test(&"hats cats hats"));
-    assert!(!search.test(&"hats bats hats"));
-    assert!(!search.test(&"hats rats hats"));
+    assert!(search.test("mats hats mats"));
+    assert!(search.test("hats mats hats"));
+    assert!(!search.test("hats cats hats"));
+    assert!(!search.test("hats bats hats"));
+    assert!(!search.test("hats rats hats"));
 
     let search = Search::new("!bats !cats hats mats !rats");
 
-    assert!(search.test(&"mats hats mats"));
-    assert!(search.test(&"hats mats hats"));
-    assert!(!search.test(&"hats cats hats"));
-    assert!(!search.test(&"hats bats hats"));
-    assert!(!search.test(&"hats rats hats"));
-}
\ No newline at end of file
+    assert!(search.test("mats hats mats"));
+    assert!(search.test("hats mats hats"));
+    assert!(!search.test("hats cats hats"));
+    assert!(!search.test("hats bats hats"));
+    assert!(!search.test("hats rats hats"));
+}
+#[test]
+fn test_field_existence() {
+    let search = Search::new("has:trace_id");
+    assert!(search.test("GET /cart trace_id=abc123 status=200"));
+    assert!(!search.test("GET /cart status=200 trace_id_missing"));
+    assert!(!search.test("GET /cart trace_id= status=200"));
+    assert!(search.test_log("web-1", r#"{"trace_id": "abc123", "msg": "hi"}"#));
+    // field names are as case-insensitive as everything else
+    assert!(search.test("GET /cart Trace_ID=abc123"));
+    assert_eq!(search.tokens(), SearchTree::quick_trigrams("trace_id"));
+
+    let search = Search::new("status=500 missing:user_id");
+    assert!(search.test("GET /cart status=500"));
+    assert!(!search.test("GET /cart status=500 user_id=42"));
+    assert!(!search.test("GET /cart status=200"));
+    // the bloom filter can't rule anything out for a missing field
+    assert_eq!(search.tokens(), SearchTree::quick_trigrams("status=500"));
+
+    let search = Search::new("!has:user_id | missing:trace_id");
+    assert!(search.test("user_id=1"));
+    assert!(!search.test("user_id=1 trace_id=2"));
+
+    // a bare has: is just a token
+    assert_eq!(Search::new("has:").tree, SearchTree::Token(SearchToken{ token: "has:".to_string(), trigrams: SearchTree::quick_trigrams("has:") }));
+}
+#[test]
+fn test_modifiers() {
+    let search = Search::new("cs:Error");
+    assert!(search.test("Error: disk full"));
+    assert!(!search.test("error: disk full"));
+    assert!(!search.test("ERROR: disk full"));
+    // the index only has lowercase trigrams, so that's what we look for
+    assert_eq!(search.tokens(), SearchTree::quick_trigrams("error"));
+
+    let search = Search::new("word:err");
+    assert!(search.test("err: disk full"));
+    assert!(search.test("level=ERR code=28"));
+    assert!(search.test("[err]"));
+    assert!(!search.test("error: disk full"));
+    assert!(!search.test("stderr: disk full"));
+    assert!(!search.test("err_code=28"));
+
+    let search = Search::new("CS:Word:Err !cs:\"Disk Full\"");
+    assert!(search.test("Err: disk full"));
+    assert!(!search.test("Err: Disk Full"));
+    assert!(!search.test("err: disk full"));
+    assert!(!search.test("Errno: disk full"));
+
+    // ?case_sensitive=true and ?whole_word=true: every term, but not has:
+    let modifiers = Modifiers{ case_sensitive: true, whole_word: true };
+    let search = Search::parse_with_modifiers("Err has:trace_id", modifiers).unwrap();
+    assert!(search.test("Err TRACE_ID=1"));
+    assert!(!search.test("err trace_id=1"));
+    assert!(!search.test("Errno trace_id=1"));
+
+    // without a modifier, nothing changes
+    assert_eq!(Search::new("Error").tree, SearchTree::Token(SearchToken{ token: "error".to_string(), trigrams: SearchTree::quick_trigrams("error") }));
+    // a modifier with nothing after it is just a token, and other prefixes don't open quotes
+    assert_eq!(Search::new("cs:").tree, SearchTree::Token(SearchToken{ token: "cs:".to_string(), trigrams: SearchTree::quick_trigrams("cs:") }));
+    assert!(Search::new("url:\"x\"").test("url:\"x\""));
+}
+#[test]
+fn test_bloom_plan() {
+    let mut filter = GrowableBloom::new(0.01, 100);
+    for trigram in SearchTree::quick_trigrams("hello world error") {
+        filter.insert(&trigram);
+    }
+    let searches = ["hello", "hello world", "goodbye", "hello | goodbye", "goodbye | world", "!hello", "hello !goodbye",
+        "(hello | goodbye) world", "has:hello", "cs:Hello", "hel*", "*bye", "", "hello hello hello", "goodbye & (world | error)"];
+    for search in searches {
+        let search = Search::new(search);
+        assert_eq!(search.bloom_plan().test(&filter), search.bloom_test(&filter), "{}", search.search_string);
+    }
+
+    // trigrams that turn up in more than one token only get looked up once
+    let plan = Search::new("hello hellos | cs:Hello").bloom_plan();
+    assert_eq!(plan.trigrams.len(), SearchTree::quick_trigrams("hellos").len());
+
+    let empty = GrowableBloom::new(0.01, 100);
+    assert!(plan.test(&filter));
+    assert!(!plan.test(&empty));
+
+    for (search, narrows) in [("hello", true), ("hello !goodbye", true), ("!hello", false), ("", false), ("hi", false),
+        ("hello | !goodbye", false), ("hello | world", true), ("hi hello", true)] {
+        assert_eq!(Search::new(search).bloom_plan().narrows(), narrows, "{}", search);
+    }
+}
+
+#[test]
+fn test_id_range() {
+    // "hello" is in logs 10-20, "world" in 15-30, "rare" only in 40
+    let lookup = |trigrams: &HashSet<String>| {
+        let ranges: Vec<(i64, i64)> = trigrams.iter().map(|trigram| match trigram.as_str(){
+            "hel" | "ell" | "llo" => Some((10, 20)),
+            "wor" | "orl" | "rld" => Some((15, 30)),
+            "rar" | "are" => Some((40, 40)),
+            _ => None,
+        }).collect::<Option<Vec<(i64, i64)>>>()?;
+        let (min, max) = ranges.iter().fold((i64::MIN, i64::MAX), |(min, max), (low, high)| (min.max(*low), max.min(*high)));
+        Some((min, max)).filter(|(min, max)| min <= max)
+    };
+    assert_eq!(Search::new("hello").id_range(&lookup), Some((10, 20)));
+    assert_eq!(Search::new("hello world").id_range(&lookup), Some((15, 20)));
+    assert_eq!(Search::new("hello | rare").id_range(&lookup), Some((10, 40)));
+    assert_eq!(Search::new("hello rare").id_range(&lookup), None);
+    assert_eq!(Search::new("goodbye").id_range(&lookup), None);
+    assert_eq!(Search::new("goodbye | world").id_range(&lookup), Some((15, 30)));
+    assert_eq!(Search::new("hello !world").id_range(&lookup), Some((10, 20)));
+    assert_eq!(Search::new("!world").id_range(&lookup), Some((i64::MIN, i64::MAX)));
+}
+
+#[test]
+fn test_expensive_operator() {
+    assert_eq!(Search::new("hello world").expensive_operator(), None);
+    assert_eq!(Search::new("cs:Hello word:world").expensive_operator(), None);
+    assert_eq!(Search::new("hello user_*").expensive_operator(), Some("wildcards"));
+    assert_eq!(Search::new("hello missing:trace_id").expensive_operator(), Some("has: and missing:"));
+    assert!(Search::new("!hello").expensive_operator().is_some());
+    assert!(Search::new("").expensive_operator().is_some());
+}
+#[test]
+fn test_wildcards() {
+    let search = Search::new("user_*");
+    assert!(search.test("login user_42 ok"));
+    assert!(search.test("login id=USER_42 ok"));
+    assert!(search.test("login user_"));
+    assert!(!search.test("login superuser_42 ok"));
+    assert!(!search.test("login user 42"));
+    // only the literal part is in the index
+    assert_eq!(search.tokens(), SearchTree::quick_trigrams("user_"));
+
+    let search = Search::new("*timeout");
+    assert!(search.test("read_timeout, retrying"));
+    assert!(search.test("ReadTimeout"));
+    assert!(search.test("timeout"));
+    assert!(!search.test("timeouts"));
+
+    let search = Search::new("conn*refused");
+    assert!(search.test("error: conn_refused"));
+    assert!(search.test("error: connection-refused!"));
+    assert!(!search.test("error: connection was refused"));
+    assert!(!search.test("error: reconnrefused"));
+    assert_eq!(search.tokens(), SearchTree::quick_trigrams("conn refused"));
+
+    // * on both ends is the same old substring search
+    let search = Search::new("*time*");
+    assert!(search.test("runtimeerror"));
+    assert!(!search.test("tim e"));
+
+    // wildcards work with everything else
+    let search = Search::new("cs:User_* !user_bot*");
+    assert!(search.test("User_42 logged in"));
+    assert!(!search.test("user_42 logged in"));
+    assert!(!search.test("User_42 user_bot7"));
+
+    // quoted or escaped, a * is just a *
+    assert!(Search::new("\"SELECT * FROM\"").test("SELECT * FROM users"));
+    assert!(!Search::new("\"SELECT * FROM\"").test("SELECT id FROM users"));
+    assert!(Search::new("a\\*b").test("a*b"));
+    assert!(!Search::new("a\\*b").test("axb"));
+    assert_eq!(Search::new("*").tree, SearchTree::Token(SearchToken{ token: "*".to_string(), trigrams: SearchTree::quick_trigrams("*") }));
+    assert!(Search::new("**").test("**"));
+}
+#[test]
+fn test_precedence() {
+    let token = |token: &str| Box::new(SearchTree::Token(SearchToken{ token: token.to_string(), trigrams: SearchTree::quick_trigrams(token) }));
+
+    // and (written or not) binds tighter than or, and not binds tighter than both
+    assert_eq!(Search::new("a b | c").tree, SearchTree::Or(Box::new(SearchTree::And(token("a"), token("b"))), token("c")));
+    assert_eq!(Search::new("a | b & c").tree, SearchTree::Or(token("a"), Box::new(SearchTree::And(token("b"), token("c")))));
+    assert_eq!(Search::new("!a b").tree, SearchTree::And(Box::new(SearchTree::Not(token("a"))), token("b")));
+    assert_eq!(Search::new("!!a").tree, *token("a"));
+    assert_eq!(Search::new("!(a | b) c").tree, SearchTree::And(Box::new(SearchTree::Not(Box::new(SearchTree::Or(token("a"), token("b"))))), token("c")));
+    assert_eq!(Search::new("a | b | c").tree, SearchTree::Or(token("a"), Box::new(SearchTree::Or(token("b"), token("c")))));
+
+    let search = Search::new("timeout retry | panic");
+    assert!(search.test("panic: at the disco"));
+    assert!(search.test("timeout, retry in 5s"));
+    assert!(!search.test("timeout, giving up"));
+
+    // parentheses in the middle of a word are part of the word; quoted, they're just parentheses
+    assert_eq!(Search::new("foo(bar)").tree, *token("foo(bar)"));
+    assert_eq!(Search::new("(foo(bar) | baz)").tree, SearchTree::Or(token("foo(bar)"), token("baz")));
+    assert_eq!(Search::new("\"(\" \"|\"").tree, SearchTree::And(token("("), token("|")));
+    assert_eq!(SearchTree::tokenize("(hello world)"), vec!["(", "hello", "world", ")"]);
+    assert_eq!(Search::new("  ").tree, SearchTree::None);
+}
+
+#[test]
+fn test_parse_errors() {
+    let error = |search: &str| Search::parse(search).unwrap_err();
+    assert_eq!(error("(timeout retry"), ParseError::new(0, "this ( is never closed"));
+    assert_eq!(error("timeout retry)"), ParseError::new(13, "there's no ( for this )"));
+    assert_eq!(error("(a) b)"), ParseError::new(5, "there's no ( for this )"));
+    assert_eq!(error("| timeout"), ParseError::new(0, "| needs something on both sides"));
+    assert_eq!(error("timeout |"), ParseError::new(8, "| needs something on both sides"));
+    assert_eq!(error("timeout | | retry"), ParseError::new(8, "| needs something on both sides"));
+    assert_eq!(error("timeout &"), ParseError::new(8, "& needs something on both sides"));
+    assert_eq!(error("timeout !"), ParseError::new(8, "! needs something after it"));
+    assert_eq!(error("a ()"), ParseError::new(2, "there's nothing in these parentheses"));
+    assert_eq!(error("a \"unclosed"), ParseError::new(2, "this quote is never closed"));
+    // positions count characters, not bytes
+    assert_eq!(error("ünïcödé )").position, 8);
+    assert_eq!(error("(timeout").to_string(), "this ( is never closed (at character 1)");
+
+    // nothing can get big enough to hurt
+    assert_eq!(error(&"a".repeat(MAX_SEARCH_CHARACTERS + 1)).position, MAX_SEARCH_CHARACTERS);
+    assert!(Search::parse(&"a".repeat(MAX_SEARCH_CHARACTERS)).is_ok());
+    assert_eq!(error(&"a ".repeat(MAX_SEARCH_TERMS + 1)).position, MAX_SEARCH_TERMS * 2);
+    let nested = |depth: usize| format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+    assert!(Search::parse(&nested(MAX_SEARCH_NESTING)).is_ok());
+    assert_eq!(error(&nested(MAX_SEARCH_NESTING + 1)).position, MAX_SEARCH_NESTING);
+    assert!(error(&"a*".repeat(MAX_SEARCH_WILDCARDS + 1)).message.contains("too many *s"));
+}
@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Arc;
+use anyhow::Result;
+use fxhash::FxHashMap as HashMap;
+use growable_bloom_filter::GrowableBloom;
+
+use crate::minute::{Log, Minute};
+use crate::minute_id::MinuteId;
+use crate::write_stats::WriteStats;
+
+///
+/// Everything the writer (ShardedMinute), the read loop and the searches (MinuteDB) need from one minute's worth of logs:
+/// take batches, seal, hand over a bloom filter, and answer searches. A Minute (one SQLite database) is the only one there is,
+/// but anything that can do those things could be one, without MinuteDB or ShardedMinute having to know: see SegmentFormat.
+///
+/// A segment still lives where a minute does (`{day}/{hour}/{minute}-{unique_id}.db`), because that's what the catalog,
+/// retention, archiving and replication know how to find.
+///
+pub trait Segment: Send {
+    fn id(&self) -> MinuteId;
+
+    ///
+    /// Writing options: see Minute::set_dedup, Minute::set_durable, Minute::set_cardinality_fields and Minute::set_compression
+    ///
+    fn set_dedup(&mut self, dedup: bool);
+    fn set_durable(&mut self) -> Result<()>;
+    fn set_cardinality_fields(&mut self, fields: &[String]);
+    fn set_compression(&mut self, compression: crate::minute::LogCompression);
+
+    ///
+    /// One batch from the writer, all at once (or not at all)
+    ///
+    fn write_batch(&mut self, events: Vec<crate::WritableEvent>) -> Result<()>;
+    fn take_write_stats(&mut self) -> WriteStats;
+
+    ///
+    /// Nothing more gets written: build whatever makes it fast to search, including the bloom filter. Sealing twice is fine.
+    ///
+    fn seal(&mut self) -> Result<()>;
+    fn is_sealed(&self) -> Result<bool>;
+
+    ///
+    /// Every search fragment in the segment (see Minute::explode) is in here: only sealed segments have one
+    ///
+    fn bloom(&self) -> Result<GrowableBloom>;
+
+    ///
+    /// Take every log that matches out of a sealed segment, bloom and all, so there's no trace of them left: see Minute::redact
+    ///
+    fn redact(&mut self, search: &crate::search_token::Search) -> Result<u64>;
+
+    ///
+    /// Up to `limit` matching logs, from the `order` end: whatever it's found by `deadline`, if it gets there first
+    ///
+    fn search(&self, search: &crate::search_token::Search, limit: usize, order: crate::minute_db::SortOrder, deadline: crate::minute_db::Deadline) -> Result<Vec<Log>>;
+    fn histogram(&self, search: &crate::search_token::Search, bucket_us: i64) -> Result<BTreeMap<i64, u64>>;
+    fn stats(&self, search: &crate::search_token::Search, by: &crate::minute_db::StatsBy) -> Result<(u64, HashMap<String, u64>)>;
+    fn sketch(&self, field: &str) -> Result<Option<crate::cardinality::HyperLogLog>>;
+    fn host_counts(&self) -> Result<BTreeMap<String, u64>>;
+    fn summary(&self) -> Result<Option<crate::minute::MinuteSummary>>;
+
+    ///
+    /// One log, by the id a search gave it
+    ///
+    fn get(&self, id: i64) -> Result<Option<Log>>;
+}
+
+///
+/// How segments get opened: MinuteDB and ShardedMinute each have one of these (SqliteSegments, unless somebody says otherwise)
+///
+pub trait SegmentFormat: Send + Sync {
+    ///
+    /// Open a segment for writing, making it if it isn't there yet
+    ///
+    fn create(&self, id: &MinuteId, data_directory: &str) -> Result<Box<dyn Segment>>;
+
+    ///
+    /// Open a sealed segment, for searching
+    ///
+    fn open(&self, id: &MinuteId, data_directory: &str) -> Result<Box<dyn Segment>>;
+
+    ///
+    /// Open a segment the writer might still be writing, without getting in its way (and without making it, if it isn't there)
+    ///
+    fn open_unsealed(&self, id: &MinuteId, data_directory: &str) -> Result<Box<dyn Segment>>;
+}
+
+///
+/// Minutes, the way they've always been: one SQLite database each
+///
+pub struct SqliteSegments;
+
+impl SegmentFormat for SqliteSegments{
+    fn create(&self, id: &MinuteId, data_directory: &str) -> Result<Box<dyn Segment>> {
+        Ok(Box::new(Minute::new(id.day, id.hour, id.minute, &id.unique_id, data_directory, true)?))
+    }
+
+    fn open(&self, id: &MinuteId, data_directory: &str) -> Result<Box<dyn Segment>> {
+        Ok(Box::new(Minute::new(id.day, id.hour, id.minute, &id.unique_id, data_directory, false)?))
+    }
+
+    fn open_unsealed(&self, id: &MinuteId, data_directory: &str) -> Result<Box<dyn Segment>> {
+        Ok(Box::new(Minute::open_unsealed(id.day, id.hour, id.minute, &id.unique_id, data_directory)?))
+    }
+}
+
+pub fn default_format() -> Arc<dyn SegmentFormat> {
+    Arc::new(SqliteSegments)
+}
+
+impl Segment for Minute{
+    fn id(&self) -> MinuteId {
+        self.unique_id()
+    }
+
+    fn set_dedup(&mut self, dedup: bool) {
+        Minute::set_dedup(self, dedup)
+    }
+
+    fn set_durable(&mut self) -> Result<()> {
+        Minute::set_durable(self)
+    }
+
+    fn set_cardinality_fields(&mut self, fields: &[String]) {
+        Minute::set_cardinality_fields(self, fields)
+    }
+
+    fn set_compression(&mut self, compression: crate::minute::LogCompression) {
+        Minute::set_compression(self, compression)
+    }
+
+    fn write_batch(&mut self, events: Vec<crate::WritableEvent>) -> Result<()> {
+        self.write_second(events)
+    }
+
+    fn take_write_stats(&mut self) -> WriteStats {
+        Minute::take_write_stats(self)
+    }
+
+    fn seal(&mut self) -> Result<()> {
+        Minute::seal(self)
+    }
+
+    fn is_sealed(&self) -> Result<bool> {
+        Minute::is_sealed(self)
+    }
+
+    fn bloom(&self) -> Result<GrowableBloom> {
+        self.get_bloom_filter()
+    }
+
+    fn redact(&mut self, search: &crate::search_token::Search) -> Result<u64> {
+        Minute::redact(self, search)
+    }
+
+    fn search(&self, search: &crate::search_token::Search, limit: usize, order: crate::minute_db::SortOrder, deadline: crate::minute_db::Deadline) -> Result<Vec<Log>> {
+        self.search_until(search, limit, order, deadline)
+    }
+
+    fn histogram(&self, search: &crate::search_token::Search, bucket_us: i64) -> Result<BTreeMap<i64, u64>> {
+        Minute::histogram(self, search, bucket_us)
+    }
+
+    fn stats(&self, search: &crate::search_token::Search, by: &crate::minute_db::StatsBy) -> Result<(u64, HashMap<String, u64>)> {
+        Minute::stats(self, search, by)
+    }
+
+    fn sketch(&self, field: &str) -> Result<Option<crate::cardinality::HyperLogLog>> {
+        Minute::sketch(self, field)
+    }
+
+    fn host_counts(&self) -> Result<BTreeMap<String, u64>> {
+        Minute::host_counts(self)
+    }
+
+    fn summary(&self) -> Result<Option<crate::minute::MinuteSummary>> {
+        Minute::summary(self)
+    }
+
+    fn get(&self, id: i64) -> Result<Option<Log>> {
+        Minute::get(self, id)
+    }
+}
+
+///
+/// Where a sealed segment's bloom filter gets copied to: right next to it, like SQLite's own sidecars,
+/// so the read loop can have the bloom without opening the segment at all (see MinuteDB::load_bloom)
+///
+pub fn bloom_sidecar_path(segment_path: &str) -> String {
+    format!("{}-bloom", segment_path)
+}
+
+///
+/// How many bytes it came to: about what the bloom costs in memory, too
+///
+pub fn write_bloom_sidecar(segment_path: &str, bloom: &GrowableBloom) -> Result<u64> {
+    let path = bloom_sidecar_path(segment_path);
+    let blob = postcard::to_allocvec(bloom)?;
+    // write it next door and move it over, so nobody reads half of it
+    let swap = format!("{}.swp", path);
+    fs::write(&swap, &blob)?;
+    fs::rename(&swap, &path)?;
+    Ok(blob.len() as u64)
+}
+
+///
+/// The bloom, and how many bytes it was
+///
+pub fn read_bloom_sidecar(segment_path: &str) -> Result<(GrowableBloom, u64)> {
+    let blob = fs::read(bloom_sidecar_path(segment_path))?;
+    Ok((postcard::from_bytes(&blob)?, blob.len() as u64))
+}
+
+#[test]
+fn test_sqlite_segments() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("sqlite_segments");
+    let format = default_format();
+    let id = MinuteId::new(1, 2, 3, "segment");
+    assert!(format.open_unsealed(&id, &data_directory).is_err());
+
+    let mut segment = format.create(&id, &data_directory)?;
+    segment.write_batch(vec![crate::WritableEvent::new("a needle in a segment", 0, "localhost")])?;
+    let search = crate::search_token::Search::new("needle");
+    assert_eq!(format.open_unsealed(&id, &data_directory)?.search(&search, 10, crate::minute_db::SortOrder::Ascending, crate::minute_db::Deadline::never())?.len(), 1);
+    assert!(!segment.is_sealed()?);
+
+    segment.seal()?;
+    assert_eq!(segment.take_write_stats().sealed_minutes, 1);
+    drop(segment);
+    let segment = format.open(&id, &data_directory)?;
+    assert_eq!(segment.id(), id);
+    assert!(segment.is_sealed()?);
+    assert!(segment.bloom()?.contains("eed"));
+    let (sidecar, bytes) = read_bloom_sidecar(&format!("{}/1/2/3-segment.db", data_directory))?;
+    assert!(sidecar.contains("eed"));
+    assert_eq!(bytes, postcard::to_allocvec(&sidecar)?.len() as u64);
+    assert_eq!(segment.histogram(&search, 60_000_000)?.values().sum::<u64>(), 1);
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+use crate::WritableEvent;
+use crate::client::{Client, SearchQuery};
+use crate::minute_db::SortOrder;
+
+///
+/// Results per page when we read a minute back
+///
+const PAGE: usize = 10000;
+
+///
+/// Something for the events to say besides their numbers, so that minutes look (and compress) like real ones
+///
+const FILLER: [&str; 6] = [
+    "level=info GET /api/v1/orders 200 12ms",
+    "level=info user=42 checkout started cart_items=3",
+    "level=warn POST /api/v1/payments 429 retrying in 200ms",
+    "level=error db timeout after 5000ms query=select_orders",
+    "level=debug cache miss key=session:8f3a",
+    "level=info worker=7 job=send_email finished in 340ms",
+];
+
+///
+/// How a soak goes: `rate` events a second for `duration`, each minute checked once `settle` has passed since it ended
+/// (that's max_lateness_seconds, plus time for the minute to be sealed and picked up)
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoakSettings{
+    pub rate: u64,
+    pub duration: Duration,
+    pub settle: Duration,
+    /// how often to say how it's going
+    pub report_every: Duration,
+}
+
+impl Default for SoakSettings{
+    fn default() -> Self {
+        SoakSettings{
+            rate: 1000,
+            duration: Duration::from_secs(3600),
+            settle: Duration::from_secs(180),
+            report_every: Duration::from_secs(60),
+        }
+    }
+}
+
+///
+/// How a soak went. Events the server turned away (after the client's retries) aren't held against it: it said no, and the sender knows.
+/// Anything it said yes to has to come back out of a search exactly once.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SoakReport{
+    pub run: String,
+    pub sent: u64,
+    pub rejected: u64,
+    /// accepted events in minutes we've checked
+    pub checked: u64,
+    /// accepted, and never came back
+    pub missing: u64,
+    /// came back more than once (each extra copy counts)
+    pub duplicated: u64,
+    pub minutes_checked: usize,
+    pub minutes_with_problems: usize,
+    /// accepted events in minutes that haven't been checked yet
+    pub pending: u64,
+}
+
+impl SoakReport{
+    ///
+    /// Of the events we've checked, the fraction that went missing
+    ///
+    pub fn loss_rate(&self) -> f64 {
+        match self.checked{
+            0 => 0.0,
+            checked => self.missing as f64 / checked as f64,
+        }
+    }
+
+    pub fn ok(&self) -> bool {
+        self.missing == 0 && self.duplicated == 0
+    }
+}
+
+impl std::fmt::Display for SoakReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "run {}: {} sent, {} rejected, {} checked in {} minutes: {} missing ({:.4}%), {} duplicated, {} minutes with problems, {} still to check",
+            self.run, self.sent, self.rejected, self.checked, self.minutes_checked, self.missing, self.loss_rate() * 100.0,
+            self.duplicated, self.minutes_with_problems, self.pending)
+    }
+}
+
+///
+/// What checking one minute turned up
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MinuteCheck{
+    pub minute: i64,
+    pub missing: Vec<u64>,
+    pub duplicated: Vec<u64>,
+}
+
+///
+/// Which sequence numbers the server took, by the minute they're in, until we've checked them
+///
+#[derive(Debug, Clone, Default)]
+pub struct SoakLedger{
+    pending: BTreeMap<i64, Vec<u64>>,
+    pub report: SoakReport,
+}
+
+impl SoakLedger{
+    pub fn new(run: &str) -> SoakLedger {
+        SoakLedger{
+            pending: BTreeMap::new(),
+            report: SoakReport{ run: run.to_string(), ..SoakReport::default() },
+        }
+    }
+
+    ///
+    /// The server took these: (sequence number, time in microseconds)
+    ///
+    pub fn accepted(&mut self, events: &[(u64, i64)]) {
+        for (sequence, time) in events {
+            self.pending.entry(time.div_euclid(60 * 1000000) * 60).or_default().push(*sequence);
+        }
+        self.report.sent += events.len() as u64;
+        self.report.pending += events.len() as u64;
+    }
+
+    pub fn rejected(&mut self, n: u64) {
+        self.report.sent += n;
+        self.report.rejected += n;
+    }
+
+    ///
+    /// Minutes that ended at least `settle` seconds before `now`, with what should be in them: they're off the ledger until
+    /// they're `check`ed (or `retry`d, if the search didn't work out)
+    ///
+    pub fn due(&mut self, now: i64, settle: i64) -> Vec<(i64, Vec<u64>)> {
+        let due: Vec<i64> = self.pending.keys().filter(|minute| **minute + 60 + settle <= now).cloned().collect();
+        due.into_iter().filter_map(|minute| self.pending.remove(&minute).map(|expected| (minute, expected))).collect()
+    }
+
+    pub fn retry(&mut self, minute: i64, expected: Vec<u64>) {
+        self.pending.entry(minute).or_default().extend(expected);
+    }
+
+    ///
+    /// Everything that's left, due or not: for the end of the run
+    ///
+    pub fn remaining(&self) -> usize {
+        self.pending.len()
+    }
+
+    ///
+    /// Compare what a search of `minute` found (sequence numbers, in any order) with what the server took
+    ///
+    pub fn check(&mut self, minute: i64, expected: Vec<u64>, found: &[u64]) -> MinuteCheck {
+        let mut counts: HashMap<u64, usize> = HashMap::new();
+        for sequence in found {
+            *counts.entry(*sequence).or_default() += 1;
+        }
+        let mut check = MinuteCheck{ minute, ..MinuteCheck::default() };
+        for sequence in &expected {
+            match counts.get(sequence).copied().unwrap_or(0){
+                0 => check.missing.push(*sequence),
+                1 => {},
+                n => check.duplicated.extend(std::iter::repeat_n(*sequence, n - 1)),
+            }
+        }
+        self.report.pending -= expected.len() as u64;
+        self.report.checked += expected.len() as u64;
+        self.report.missing += check.missing.len() as u64;
+        self.report.duplicated += check.duplicated.len() as u64;
+        self.report.minutes_checked += 1;
+        if !check.missing.is_empty() || !check.duplicated.is_empty() {
+            self.report.minutes_with_problems += 1;
+        }
+        check
+    }
+}
+
+///
+/// Event number `sequence` of `run`: the run and the number are what we search for and read back, the rest is filler
+///
+pub fn soak_event(run: &str, sequence: u64, time: i64) -> WritableEvent {
+    let filler = FILLER[(sequence % FILLER.len() as u64) as usize];
+    WritableEvent::new(&format!("soak={} seq={} {}", run, sequence, filler), time, &format!("soak-{}", sequence % 4))
+}
+
+///
+/// The sequence number in an event from `run`: None for anything else
+///
+pub fn parse_sequence(run: &str, message: &str) -> Option<u64> {
+    let rest = message.strip_prefix(&format!("soak={} seq=", run))?;
+    rest.split(' ').next()?.parse().ok()
+}
+
+///
+/// Send, check, repeat, for `settings.duration`: then wait for the last minutes to settle, check those too, and say how it went.
+/// Every problem gets printed as soon as it's found.
+///
+pub fn run(client: &Client, settings: &SoakSettings) -> Result<SoakReport> {
+    let started = Instant::now();
+    let run = format!("{:x}", SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_micros() as u64);
+    let mut ledger = SoakLedger::new(&run);
+    let mut sequence = 0;
+    let mut last_report = Instant::now();
+    println!("soak run {}: {} events a second for {}s", run, settings.rate, settings.duration.as_secs());
+
+    loop{
+        let second = Instant::now();
+        let sending = started.elapsed() < settings.duration;
+        if sending {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_micros() as i64;
+            let events: Vec<WritableEvent> = (0..settings.rate).map(|i| soak_event(&run, sequence + i, now + i as i64)).collect();
+            match client.ingest(&events){
+                Ok(()) => ledger.accepted(&events.iter().enumerate().map(|(i, event)| (sequence + i as u64, event.time)).collect::<Vec<_>>()),
+                Err(e) => {
+                    println!("events {} to {} were turned away: {}", sequence, sequence + settings.rate - 1, e);
+                    ledger.rejected(settings.rate);
+                },
+            }
+            sequence += settings.rate;
+        }
+        else if ledger.remaining() == 0 {
+            break;
+        }
+
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+        for (minute, expected) in ledger.due(now, settings.settle.as_secs() as i64) {
+            match read_minute(client, &run, minute){
+                Ok(found) => {
+                    let check = ledger.check(minute, expected, &found);
+                    if !check.missing.is_empty() || !check.duplicated.is_empty() {
+                        println!("minute {}: {} missing (first {:?}), {} duplicated (first {:?})", minute,
+                            check.missing.len(), check.missing.first(), check.duplicated.len(), check.duplicated.first());
+                    }
+                },
+                Err(e) => {
+                    println!("couldn't read minute {} back, trying again: {}", minute, e);
+                    ledger.retry(minute, expected);
+                },
+            }
+        }
+
+        if last_report.elapsed() >= settings.report_every {
+            println!("{}", ledger.report);
+            last_report = Instant::now();
+        }
+        if let Some(rest) = Duration::from_secs(1).checked_sub(second.elapsed()) {
+            std::thread::sleep(rest);
+        }
+    }
+    Ok(ledger.report)
+}
+
+///
+/// Every sequence number of `run` in `minute`, a page at a time
+///
+fn read_minute(client: &Client, run: &str, minute: i64) -> Result<Vec<u64>> {
+    let mut found = Vec::new();
+    let mut offset = 0;
+    loop{
+        let query = SearchQuery{ from: Some(minute), to: Some(minute + 59), limit: Some(PAGE), offset: Some(offset), order: Some(SortOrder::Ascending), ..SearchQuery::default() };
+        let logs = client.search(&format!("soak={}", run), &query)?;
+        found.extend(logs.iter()
+            .filter(|log| log.time.div_euclid(60 * 1000000) * 60 == minute)
+            .filter_map(|log| parse_sequence(run, &log.message)));
+        if logs.len() < PAGE {
+            return Ok(found);
+        }
+        offset += PAGE;
+    }
+}
+
+#[test]
+fn test_soak_ledger() {
+    let mut ledger = SoakLedger::new("abc");
+    // two minutes' worth: 0-2 in the minute at 600, 3-4 in the minute at 660
+    ledger.accepted(&[(0, 600_000000), (1, 630_000000), (2, 659_999999), (3, 660_000000), (4, 700_000000)]);
+    ledger.rejected(3);
+    assert_eq!((ledger.report.sent, ledger.report.rejected, ledger.report.pending), (8, 3, 5));
+
+    // nothing's settled yet
+    assert!(ledger.due(700, 60).is_empty());
+    let due = ledger.due(720, 60);
+    assert_eq!(due, vec![(600, vec![0, 1, 2])]);
+    let check = ledger.check(600, vec![0, 1, 2], &[2, 0, 2, 2]);
+    assert_eq!(check, MinuteCheck{ minute: 600, missing: vec![1], duplicated: vec![2, 2] });
+
+    // a search that didn't work out goes back on the ledger
+    let due = ledger.due(800, 60);
+    assert_eq!(due, vec![(660, vec![3, 4])]);
+    ledger.retry(660, vec![3, 4]);
+    assert_eq!(ledger.remaining(), 1);
+    let (minute, expected) = ledger.due(800, 60).pop().unwrap();
+    assert_eq!(ledger.check(minute, expected, &[4, 3]), MinuteCheck{ minute: 660, ..MinuteCheck::default() });
+
+    let report = &ledger.report;
+    assert_eq!((report.checked, report.missing, report.duplicated, report.pending), (5, 1, 2, 0));
+    assert_eq!((report.minutes_checked, report.minutes_with_problems), (2, 1));
+    assert_eq!(report.loss_rate(), 0.2);
+    assert!(!report.ok());
+    assert!(SoakReport::default().ok());
+}
+
+#[test]
+fn test_soak_events() {
+    let event = soak_event("abc", 42, 1710562887000000);
+    assert_eq!(parse_sequence("abc", &event.event), Some(42));
+    assert_eq!(event.time, 1710562887000000);
+    // another run's, or not ours at all
+    assert_eq!(parse_sequence("abd", &event.event), None);
+    assert_eq!(parse_sequence("abc", "level=info soak=abc seq=42"), None);
+    assert_eq!(parse_sequence("abc", "soak=abc seq=x"), None);
+}
@@ -0,0 +1,267 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::minute::Log;
+
+///
+/// Just enough SPL for scripts written against Splunk's `search/jobs/export`:
+/// `search error "disk full" host=web-1 NOT timeout earliest=-15m latest=now | head 100`
+///
+/// Terms and "quoted phrases" search the same way they always do, OR / NOT / AND (and parentheses) become `|`, `!` and nothing,
+/// `host=` and `host!=` pick hosts, `key=value` and `key!=value` are fields, and `index=` is ignored (the token picks the tenant).
+/// earliest / latest take `now`, epoch seconds, `%m/%d/%Y:%H:%M:%S`, or `-15m`-style relative times with an optional `@h`-style snap.
+/// The only command after the search is `head`: no stats, no eval, no rex.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplQuery{
+    /// a logmunch search (hosts not normalized yet: see HostRules::rewrite_search)
+    pub search: String,
+    pub earliest: Option<i64>,
+    pub latest: Option<i64>,
+    pub head: Option<usize>,
+}
+
+impl SplQuery{
+    pub fn parse(spl: &str, now: i64) -> Result<SplQuery> {
+        let mut commands = split_outside_quotes(spl, |c| c == '|').into_iter();
+        let mut words = split_outside_quotes(&commands.next().unwrap_or_default(), char::is_whitespace).into_iter().filter(|word| !word.is_empty()).peekable();
+        if words.peek().is_some_and(|word| word.eq_ignore_ascii_case("search")) {
+            words.next();
+        }
+
+        let mut terms = Vec::new();
+        let mut earliest = None;
+        let mut latest = None;
+        let mut negate = false;
+        for word in words {
+            let term = match word.as_str(){
+                "NOT" => {
+                    negate = !negate;
+                    continue;
+                },
+                "AND" => continue,
+                "OR" => "|".to_string(),
+                _ => match word.split_once('='){
+                    Some((key, value)) if key.eq_ignore_ascii_case("earliest") => {
+                        earliest = Some(parse_time(value, now)?);
+                        continue;
+                    },
+                    Some((key, value)) if key.eq_ignore_ascii_case("latest") => {
+                        latest = Some(parse_time(value, now)?);
+                        continue;
+                    },
+                    Some((key, _)) if key.eq_ignore_ascii_case("index") => continue,
+                    Some((key, value)) => {
+                        let (key, negated) = match key.strip_suffix('!'){
+                            Some(key) => (key, true),
+                            None => (key, false),
+                        };
+                        negate ^= negated;
+                        let value = unquote(value);
+                        match key.eq_ignore_ascii_case("host"){
+                            true => format!("host:{}", value),
+                            false => crate::loki::term(&format!("{}={}", key, value), false),
+                        }
+                    },
+                    None => word,
+                },
+            };
+            terms.push(format!("{}{}", if negate { "!" } else { "" }, term));
+            negate = false;
+        }
+        if negate {
+            return Err(anyhow::anyhow!("NOT needs something after it"));
+        }
+
+        let mut head = None;
+        for command in commands {
+            let words: Vec<&str> = command.split_whitespace().collect();
+            match words.as_slice(){
+                ["head"] => head = Some(10),
+                ["head", count] | ["head", "limit", "=", count] => head = Some(parse_count(count.strip_prefix("limit=").unwrap_or(count))?),
+                _ => return Err(anyhow::anyhow!("The only command we understand after the search is `head`, not `{}`", command.trim())),
+            }
+        }
+
+        Ok(SplQuery{
+            search: terms.join(" "),
+            earliest,
+            latest,
+            head,
+        })
+    }
+}
+
+fn parse_count(count: &str) -> Result<usize> {
+    count.parse().map_err(|_| anyhow::anyhow!("head wants a number, not '{}'", count))
+}
+
+fn unquote(value: &str) -> &str {
+    value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value)
+}
+
+///
+/// Split wherever `split` says, except inside "double quotes" (which stay put)
+///
+fn split_outside_quotes(text: &str, split: impl Fn(char) -> bool) -> Vec<String> {
+    let mut pieces = vec![String::new()];
+    let mut quoted = false;
+    let mut escape = false;
+    for c in text.chars() {
+        if !escape && !quoted && split(c) {
+            pieces.push(String::new());
+            continue;
+        }
+        if !escape && c == '"' {
+            quoted = !quoted;
+        }
+        escape = !escape && c == '\\';
+        pieces.last_mut().unwrap().push(c);
+    }
+    pieces
+}
+
+///
+/// A Splunk time modifier, in seconds since the epoch
+///
+pub fn parse_time(modifier: &str, now: i64) -> Result<i64> {
+    let modifier = unquote(modifier.trim());
+    if modifier.is_empty() || modifier == "now" {
+        return Ok(now);
+    }
+    if let Ok(seconds) = modifier.parse::<f64>() {
+        return Ok(seconds as i64);
+    }
+    if let Ok(time) = chrono::NaiveDateTime::parse_from_str(modifier, "%m/%d/%Y:%H:%M:%S") {
+        return Ok(time.and_utc().timestamp());
+    }
+
+    let (offset, snap) = match modifier.split_once('@'){
+        Some((offset, snap)) => (offset, Some(snap)),
+        None => (modifier, None),
+    };
+    let mut time = now;
+    if !offset.is_empty() {
+        let (sign, rest) = match offset.chars().next(){
+            Some('-') => (-1, &offset[1..]),
+            Some('+') => (1, &offset[1..]),
+            _ => return Err(anyhow::anyhow!("Can't make sense of the time '{}': try now, -15m, -1d@d, or epoch seconds", modifier)),
+        };
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        let count = match digits{
+            0 => 1,
+            _ => rest[..digits].parse::<i64>()?,
+        };
+        time += sign * count * unit_seconds(&rest[digits..])?;
+    }
+    if let Some(snap) = snap {
+        let unit = unit_seconds(snap)?;
+        // weeks start on Sunday: the epoch was a Thursday
+        let shift = if unit == 604800 { 4 * 86400 } else { 0 };
+        time -= (time + shift).rem_euclid(unit);
+    }
+    Ok(time)
+}
+
+///
+/// Months and years aren't a fixed number of seconds, so they're left out
+///
+fn unit_seconds(unit: &str) -> Result<i64> {
+    match unit{
+        "s" | "sec" | "secs" | "second" | "seconds" => Ok(1),
+        "m" | "min" | "mins" | "minute" | "minutes" => Ok(60),
+        "h" | "hr" | "hrs" | "hour" | "hours" => Ok(3600),
+        "d" | "day" | "days" => Ok(86400),
+        "w" | "week" | "weeks" => Ok(604800),
+        _ => Err(anyhow::anyhow!("Time units can be s, m, h, d or w, not '{}'", unit)),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ExportRow<'a>{
+    preview: bool,
+    offset: usize,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    lastrow: bool,
+    result: ExportResult<'a>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportResult<'a>{
+    _raw: &'a str,
+    _time: String,
+    host: &'a str,
+}
+
+///
+/// One line of Splunk's JSON export: `{"preview":false,"offset":0,"result":{"_raw":"...","_time":"...","host":"..."}}`,
+/// with `"lastrow":true` on the last one
+///
+pub fn export_line(log: &Log, offset: usize, last: bool) -> String {
+    let time = chrono::DateTime::from_timestamp_micros(log.time)
+        .map(|time| time.format("%Y-%m-%dT%H:%M:%S%.3f+00:00").to_string())
+        .unwrap_or_default();
+    let row = ExportRow{
+        preview: false,
+        offset,
+        lastrow: last,
+        result: ExportResult{
+            _raw: &log.message,
+            _time: time,
+            host: &log.host,
+        },
+    };
+    format!("{}\n", serde_json::to_string(&row).unwrap_or_default())
+}
+
+#[test]
+fn test_parse_spl() -> Result<()> {
+    let now = 1710561630;
+    let query = SplQuery::parse(r#"search index=main error "disk full" host=web-1 NOT timeout earliest=-15m latest=now | head 5"#, now)?;
+    assert_eq!(query, SplQuery{
+        search: r#"error "disk full" host:web-1 !timeout"#.to_string(),
+        earliest: Some(now - 900),
+        latest: Some(now),
+        head: Some(5),
+    });
+
+    let query = SplQuery::parse(r#"(error OR warn) AND status!=200 message="a | b""#, now)?;
+    assert_eq!(query.search, r#"(error | warn) !status\=200 message\=a\ \|\ b"#);
+    assert_eq!(query.earliest, None);
+    assert_eq!(query.head, None);
+
+    assert!(SplQuery::parse("search error | stats count by host", now).is_err());
+    assert!(SplQuery::parse("search error NOT", now).is_err());
+    assert_eq!(SplQuery::parse("error | head", now)?.head, Some(10));
+    assert_eq!(SplQuery::parse("error | head limit=3", now)?.head, Some(3));
+    Ok(())
+}
+
+#[test]
+fn test_parse_time() -> Result<()> {
+    // Saturday 2024-03-16 04:00:30 UTC
+    let now = 1710561630;
+    assert_eq!(parse_time("now", now)?, now);
+    assert_eq!(parse_time("1710000000", now)?, 1710000000);
+    assert_eq!(parse_time("03/16/2024:04:00:00", now)?, 1710561600);
+    assert_eq!(parse_time("-15m", now)?, now - 900);
+    assert_eq!(parse_time("-h", now)?, now - 3600);
+    assert_eq!(parse_time("+2d", now)?, now + 2 * 86400);
+    assert_eq!(parse_time("@h", now)?, 1710561600);
+    assert_eq!(parse_time("-1d@d", now)?, 1710460800);
+    // the Sunday before
+    assert_eq!(parse_time("@w", now)?, 1710028800);
+    assert!(parse_time("-1mon", now).is_err());
+    assert!(parse_time("yesterday", now).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_export_line() -> Result<()> {
+    let log = Log{ id: 1, message: "oh no".to_string(), time: 1710561600123456, host: "web-1".to_string(), shard: None, event_id: None, minute_id: None };
+    let line: serde_json::Value = serde_json::from_str(&export_line(&log, 3, false))?;
+    assert_eq!(line, serde_json::json!({"preview": false, "offset": 3, "result": {"_raw": "oh no", "_time": "2024-03-16T04:00:00.123+00:00", "host": "web-1"}}));
+    let line: serde_json::Value = serde_json::from_str(&export_line(&log, 4, true))?;
+    assert_eq!(line["lastrow"], true);
+    Ok(())
+}
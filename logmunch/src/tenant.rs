@@ -0,0 +1,215 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::sync::Arc;
+use anyhow::Result;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::Deserialize;
+
+use crate::auth::ApiToken;
+
+///
+/// These are already taken, in the data directory
+///
+const RESERVED_NAMES: [&str; 5] = ["minutes", "metadata", "archive", "scratch", "journal"];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct TenancyConfig{
+    #[serde(default)]
+    tokens: HashMap<String, String>,
+    #[serde(default)]
+    tenants: Vec<String>,
+    #[serde(default)]
+    header: Option<String>,
+}
+
+///
+/// Who belongs to which tenant. Callers whose token isn't in here are in the default tenant, which is
+/// the data directory logmunch has always used: with no TENANTS file, everybody is.
+///
+/// TENANTS points at a JSON file that looks like:
+/// {
+///     "tokens": {"checkout-token": "checkout", "search-team-token": "search"},
+///     "tenants": ["platform"],
+///     "header": "X-Logmunch-Tenant"
+/// }
+/// `tenants` are tenants with no tokens of their own. `header` (optional) lets callers name their tenant in a header:
+/// only turn it on behind a proxy that sets it, because anybody can send a header. A token's tenant always wins over the header.
+///
+#[derive(Debug, Clone, Default)]
+pub struct Tenancy{
+    tokens: HashMap<String, String>,
+    tenants: BTreeSet<String>,
+    header: Option<String>,
+}
+
+impl Tenancy{
+    pub fn from_config(config: &crate::config::Config) -> Result<Tenancy> {
+        match &config.tenants{
+            Some(path) => Self::load(path),
+            None => Ok(Tenancy::default()),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Tenancy> {
+        let contents = fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Could not read tenants from {}: {}", path, e))?;
+        Self::parse(&contents).map_err(|e| anyhow::anyhow!("Could not parse tenants in {}: {}", path, e))
+    }
+
+    pub fn parse(json: &str) -> Result<Tenancy> {
+        let config: TenancyConfig = serde_json::from_str(json)?;
+        let tenants: BTreeSet<String> = config.tokens.values().chain(config.tenants.iter()).cloned().collect();
+        for tenant in &tenants {
+            Self::check_name(tenant)?;
+        }
+        Ok(Tenancy{
+            tokens: config.tokens,
+            tenants,
+            header: config.header,
+        })
+    }
+
+    ///
+    /// Tenant names turn into directory names, so they have to be boring
+    ///
+    fn check_name(tenant: &str) -> Result<()> {
+        if tenant.is_empty() || tenant.len() > 64 || !tenant.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_') {
+            return Err(anyhow::anyhow!("'{}' can't be a tenant: use 1-64 of a-z, 0-9, - and _", tenant));
+        }
+        if RESERVED_NAMES.contains(&tenant) {
+            return Err(anyhow::anyhow!("'{}' can't be a tenant: the data directory already has a {}/", tenant, tenant));
+        }
+        Ok(())
+    }
+
+    pub fn tenants(&self) -> impl Iterator<Item = &String> {
+        self.tenants.iter()
+    }
+
+    pub fn header(&self) -> Option<&str> {
+        self.header.as_deref()
+    }
+
+    ///
+    /// Which tenant the caller is in (None is the default tenant), or an error if they asked for one we haven't got
+    ///
+    pub fn resolve(&self, token: Option<&str>, header: Option<&str>) -> Result<Option<String>> {
+        if let Some(tenant) = token.and_then(|token| self.tokens.get(token)) {
+            return Ok(Some(tenant.clone()));
+        }
+        match (self.header.is_some(), header){
+            (true, Some(tenant)) if self.tenants.contains(tenant) => Ok(Some(tenant.to_string())),
+            (true, Some(tenant)) => Err(anyhow::anyhow!("There's no tenant called '{}'", tenant)),
+            _ => Ok(None),
+        }
+    }
+}
+
+///
+/// Everything a tenant has of its own: somewhere to queue what it sends us, and somewhere to search it
+///
+pub struct Tenant{
+    pub name: Option<String>,
+    pub queue: Arc<crate::ingest::IngestQueue>,
+    pub hec_acks: Option<Arc<crate::hec::HecAcks>>,
+    pub hec_dedup: Option<Arc<crate::hec::HecDedup>>,
+    pub minute_db: Arc<crate::minute_db::MinuteDB>,
+    pub minutes_directory: String,
+    pub write_stats: Arc<crate::write_stats::WriteStatsRecorder>,
+    pub sealer: crate::minute::Sealer,
+    pub minute_labels: Arc<crate::minute_labels::MinuteLabels>,
+    pub markers: Arc<crate::markers::Markers>,
+    pub log_metrics: Arc<crate::log_metrics::LogMetrics>,
+}
+
+pub struct Tenants{
+    tenancy: Tenancy,
+    default: Arc<Tenant>,
+    named: HashMap<String, Arc<Tenant>>,
+}
+
+impl Tenants{
+    pub fn new(tenancy: Tenancy, default: Arc<Tenant>, named: Vec<Arc<Tenant>>) -> Tenants {
+        let named = named.into_iter()
+            .filter_map(|tenant| tenant.name.clone().map(|name| (name, tenant)))
+            .collect();
+        Tenants{
+            tenancy,
+            default,
+            named,
+        }
+    }
+
+    pub fn get(&self, name: Option<&str>) -> Option<Arc<Tenant>> {
+        match name{
+            None => Some(self.default.clone()),
+            Some(name) => self.named.get(name).cloned(),
+        }
+    }
+
+    ///
+    /// The default tenant, then every named one
+    ///
+    pub fn all(&self) -> impl Iterator<Item = &Arc<Tenant>> {
+        std::iter::once(&self.default).chain(self.named.values())
+    }
+
+    pub fn resolve(&self, token: Option<&str>, header: Option<&str>) -> Result<Arc<Tenant>> {
+        let name = self.tenancy.resolve(token, header)?;
+        self.get(name.as_deref()).ok_or_else(|| anyhow::anyhow!("There's no tenant called '{}'", name.unwrap_or_default()))
+    }
+
+    pub fn header(&self) -> Option<&str> {
+        self.tenancy.header()
+    }
+}
+
+///
+/// The tenant whoever's calling belongs to: everything they send goes there, and everything they search comes from there.
+/// Asking (by header) for a tenant that doesn't exist is a 403.
+///
+pub struct CallerTenant(pub Arc<Tenant>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CallerTenant {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let tenants = match request.rocket().state::<Arc<Tenants>>(){
+            Some(tenants) => tenants,
+            None => return Outcome::Error((Status::InternalServerError, ())),
+        };
+        let token = request.headers().get_one("Authorization").and_then(ApiToken::parse);
+        let header = tenants.header().and_then(|header| request.headers().get_one(header));
+        match tenants.resolve(token.as_deref(), header){
+            Ok(tenant) => Outcome::Success(CallerTenant(tenant)),
+            Err(_) => Outcome::Error((Status::Forbidden, ())),
+        }
+    }
+}
+
+#[test]
+fn test_resolve() -> Result<()> {
+    let tenancy = Tenancy::parse(r#"{
+        "tokens": {"checkout-token": "checkout", "search-token": "search"},
+        "tenants": ["platform"],
+        "header": "X-Logmunch-Tenant"
+    }"#)?;
+    assert_eq!(tenancy.tenants().cloned().collect::<Vec<String>>(), vec!["checkout", "platform", "search"]);
+
+    assert_eq!(tenancy.resolve(Some("checkout-token"), None)?, Some("checkout".to_string()));
+    // a token's tenant beats whatever the header says
+    assert_eq!(tenancy.resolve(Some("checkout-token"), Some("search"))?, Some("checkout".to_string()));
+    assert_eq!(tenancy.resolve(None, Some("platform"))?, Some("platform".to_string()));
+    assert!(tenancy.resolve(None, Some("nope")).is_err());
+    assert_eq!(tenancy.resolve(Some("who-knows"), None)?, None);
+
+    // without a header configured, the header means nothing
+    let tenancy = Tenancy::parse(r#"{"tokens": {"checkout-token": "checkout"}}"#)?;
+    assert_eq!(tenancy.resolve(None, Some("checkout"))?, None);
+
+    assert!(Tenancy::parse(r#"{"tenants": ["../etc"]}"#).is_err());
+    assert!(Tenancy::parse(r#"{"tenants": ["minutes"]}"#).is_err());
+    assert!(Tenancy::parse(r#"{"tenants": ["Checkout"]}"#).is_err());
+    Ok(())
+}
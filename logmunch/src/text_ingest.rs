@@ -0,0 +1,199 @@
+use anyhow::Result;
+use chrono::{DateTime, NaiveDateTime};
+use regex::Regex;
+use serde::Serialize;
+
+use crate::WritableEvent;
+
+///
+/// A plain text log file, one event per line: for `curl --data-binary @app.log` in the middle of an incident.
+/// Every line gets `host` and, if there is one, a `source=` field in front, so you can find the upload again.
+///
+/// `timestamp_regex` finds each line's timestamp (its first capture group, or the whole match if it hasn't got one).
+/// Lines it doesn't match (stack traces, mostly) get the timestamp of the line before; lines before the first match get `now`.
+///
+pub struct TextUpload{
+    host: String,
+    timestamp: Option<Regex>,
+    timestamp_format: Option<String>,
+    prefix: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TextIngestReport{
+    pub events: usize,
+    pub timestamped: usize,
+}
+
+impl TextUpload{
+    pub fn new(host: Option<&str>, source: Option<&str>, timestamp_regex: Option<&str>) -> Result<TextUpload> {
+        let timestamp = match timestamp_regex{
+            Some(pattern) => Some(Regex::new(pattern).map_err(|e| anyhow::anyhow!("Bad timestamp_regex {}: {}", pattern, e))?),
+            None => None,
+        };
+        let prefix = source.filter(|source| !source.is_empty()).map(|source| {
+            if source.chars().any(|c| c.is_whitespace() || c == '"') {
+                format!("source={:?} ", source)
+            }
+            else {
+                format!("source={} ", source)
+            }
+        }).unwrap_or_default();
+        Ok(TextUpload{
+            host: host.filter(|host| !host.is_empty()).unwrap_or("unknown").to_string(),
+            timestamp,
+            timestamp_format: None,
+            prefix,
+        })
+    }
+
+    ///
+    /// A chrono format (like "%d/%m/%Y %H:%M:%S") for timestamps parse_timestamp doesn't know: without a timezone, it's UTC
+    ///
+    pub fn set_timestamp_format(&mut self, timestamp_format: Option<&str>) {
+        self.timestamp_format = timestamp_format.filter(|format| !format.is_empty()).map(|format| format.to_string());
+    }
+
+    ///
+    /// `now` is in microseconds, like every other time we store
+    ///
+    pub fn parse(&self, body: &str, now: i64) -> (Vec<WritableEvent>, TextIngestReport) {
+        let mut events = Vec::new();
+        let mut time = now;
+        let mut timestamped = 0;
+        for line in body.lines() {
+            if let Some((event, has_timestamp)) = self.parse_line(line, &mut time) {
+                if has_timestamp {
+                    timestamped += 1;
+                }
+                events.push(event);
+            }
+        }
+        let report = TextIngestReport{
+            events: events.len(),
+            timestamped,
+        };
+        (events, report)
+    }
+
+    ///
+    /// One line (None if it's blank), and whether it had a timestamp of its own.
+    /// `time` is the time of the line before: it moves along if this line has a timestamp.
+    ///
+    pub fn parse_line(&self, line: &str, time: &mut i64) -> Option<(WritableEvent, bool)> {
+        if line.trim().is_empty() {
+            return None;
+        }
+        let found = self.timestamp.as_ref().and_then(|pattern| self.find_timestamp(pattern, line));
+        if let Some(found) = found {
+            *time = found;
+        }
+        Some((WritableEvent{
+            event: format!("{}{}", self.prefix, line),
+            time: *time,
+            host: self.host.clone(),
+        }, found.is_some()))
+    }
+
+    fn find_timestamp(&self, pattern: &Regex, line: &str) -> Option<i64> {
+        let captures = pattern.captures(line)?;
+        let found = captures.get(1).or_else(|| captures.get(0))?;
+        match &self.timestamp_format{
+            Some(format) => parse_timestamp_with_format(found.as_str(), format),
+            None => parse_timestamp(found.as_str()),
+        }
+    }
+}
+
+///
+/// `timestamp` in a chrono `format`, as microseconds since the epoch (UTC, if the format hasn't got a timezone)
+///
+pub fn parse_timestamp_with_format(timestamp: &str, format: &str) -> Option<i64> {
+    let timestamp = timestamp.trim();
+    if let Ok(time) = DateTime::parse_from_str(timestamp, format) {
+        return Some(time.timestamp_micros());
+    }
+    NaiveDateTime::parse_from_str(timestamp, format).ok().map(|time| time.and_utc().timestamp_micros())
+}
+
+///
+/// The timestamps people actually have in their log files, as microseconds since the epoch.
+/// Timestamps without a timezone are UTC.
+///
+pub fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    let timestamp = timestamp.trim();
+    if let Ok(number) = timestamp.parse::<i64>() {
+        // seconds, milliseconds, microseconds or nanoseconds: whichever lands in the last century or so
+        return Some(match number {
+            n if n > 100_000_000_000_000_000 => n / 1000,
+            n if n > 100_000_000_000_000 => n,
+            n if n > 100_000_000_000 => n * 1000,
+            n => n * 1000000,
+        });
+    }
+    if let Ok(seconds) = timestamp.parse::<f64>() {
+        return Some((seconds * 1000000.0) as i64);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(timestamp) {
+        return Some(time.timestamp_micros());
+    }
+    // apache / nginx: 10/Nov/2023:14:55:42 +0000
+    if let Ok(time) = DateTime::parse_from_str(timestamp, "%d/%b/%Y:%H:%M:%S %z") {
+        return Some(time.timestamp_micros());
+    }
+    for format in ["%Y-%m-%d %H:%M:%S%.f%:z", "%Y-%m-%d %H:%M:%S%.f%z"] {
+        if let Ok(time) = DateTime::parse_from_str(timestamp, format) {
+            return Some(time.timestamp_micros());
+        }
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%d %H:%M:%S,%f", "%Y/%m/%d %H:%M:%S%.f"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(timestamp, format) {
+            return Some(time.and_utc().timestamp_micros());
+        }
+    }
+    None
+}
+
+#[test]
+fn test_parse_timestamp() {
+    let expected = Some(1699628141000000);
+    assert_eq!(parse_timestamp("1699628141"), expected);
+    assert_eq!(parse_timestamp("1699628141000"), expected);
+    assert_eq!(parse_timestamp("1699628141000000"), expected);
+    assert_eq!(parse_timestamp("1699628141000000000"), expected);
+    assert_eq!(parse_timestamp("1699628141.5"), Some(1699628141500000));
+    assert_eq!(parse_timestamp("2023-11-10T14:55:41+00:00"), expected);
+    assert_eq!(parse_timestamp("2023-11-10T15:55:41.000+01:00"), expected);
+    assert_eq!(parse_timestamp("2023-11-10T14:55:41Z"), expected);
+    assert_eq!(parse_timestamp("10/Nov/2023:14:55:41 +0000"), expected);
+    assert_eq!(parse_timestamp("2023-11-10 14:55:41"), expected);
+    assert_eq!(parse_timestamp("2023-11-10 14:55:41,000"), expected);
+    assert_eq!(parse_timestamp("2023/11/10 14:55:41"), expected);
+    assert_eq!(parse_timestamp("yesterday"), None);
+}
+
+#[test]
+fn test_parse_upload() -> Result<()> {
+    let body = "2023-11-10 14:55:41 ERROR boom\r\n  at thing.rs:12\n\n2023-11-10 14:55:42 INFO fine\n";
+    let upload = TextUpload::new(Some("web-1"), Some("app.log"), Some(r"^(\d{4}-\d\d-\d\d \d\d:\d\d:\d\d)"))?;
+    let (events, report) = upload.parse(body, 5);
+    assert_eq!(report.events, 3);
+    assert_eq!(report.timestamped, 2);
+    assert_eq!(events[0].event, "source=app.log 2023-11-10 14:55:41 ERROR boom");
+    assert_eq!(events[0].host, "web-1");
+    // the stack trace line goes with the line before it
+    assert_eq!(events[1].time, events[0].time);
+    assert_eq!(events[2].time, 1699628142000000);
+
+    let (events, _) = TextUpload::new(None, Some("my app"), None)?.parse("hello", 5);
+    assert_eq!(events, vec![WritableEvent{ event: "source=\"my app\" hello".to_string(), time: 5, host: "unknown".to_string() }]);
+
+    assert!(TextUpload::new(None, None, Some("(")).is_err());
+
+    let mut upload = TextUpload::new(None, None, Some(r"^\[([^\]]+)\]"))?;
+    upload.set_timestamp_format(Some("%d.%m.%Y %H:%M:%S"));
+    let (events, report) = upload.parse("[10.11.2023 14:55:42] INFO fine", 5);
+    assert_eq!(report.timestamped, 1);
+    assert_eq!(events[0].time, 1699628142000000);
+    Ok(())
+}
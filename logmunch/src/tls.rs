@@ -0,0 +1,274 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use anyhow::Result;
+use rocket::figment::Figment;
+use rocket::tokio::sync::watch;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+use crate::config::Config;
+
+///
+/// Where a listener's cert and key come from, and who it asks for client certs (see Config::tls_cert_path)
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsFiles{
+    pub cert_path: String,
+    pub key_path: String,
+    /// clients that show us a cert have to have one signed by this
+    pub client_ca_path: Option<String>,
+    /// ...and clients that don't show us one get turned away
+    pub client_cert_required: bool,
+}
+
+impl TlsFiles{
+    fn paths(&self) -> Vec<&str> {
+        let mut paths = vec![self.cert_path.as_str(), self.key_path.as_str()];
+        paths.extend(self.client_ca_path.as_deref());
+        paths
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.paths().into_iter().map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok()).collect()
+    }
+
+    ///
+    /// Read them all in and make sure they'll work, before anybody tries to listen with them
+    ///
+    fn read(&self) -> Result<TlsPem> {
+        let read = |what: &str, path: &str| fs::read(path).map_err(|e| anyhow::anyhow!("Could not read the TLS {} from {}: {}", what, path, e));
+        let pem = TlsPem{
+            cert: read("cert", &self.cert_path)?,
+            key: read("key", &self.key_path)?,
+            client_ca: match &self.client_ca_path{
+                Some(path) => Some(read("client CA", path)?),
+                None => None,
+            },
+        };
+        pem.check()?;
+        Ok(pem)
+    }
+}
+
+///
+/// The contents of TlsFiles: listeners are built out of these, not the files, so they get exactly what we checked
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TlsPem{
+    cert: Vec<u8>,
+    key: Vec<u8>,
+    client_ca: Option<Vec<u8>>,
+}
+
+impl TlsPem{
+    ///
+    /// Rocket and tonic each bring their own rustls (and not the same version), so both of them get a look
+    ///
+    fn check(&self) -> Result<()> {
+        use rocket::http::tls::{rustls, util};
+        let certs = util::load_certs(&mut self.cert.as_slice()).map_err(|e| anyhow::anyhow!("bad TLS cert: {}", e))?;
+        let key = util::load_private_key(&mut self.key.as_slice()).map_err(|e| anyhow::anyhow!("bad TLS key: {}", e))?;
+        rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| anyhow::anyhow!("bad TLS cert or key: {}", e))?;
+        if let Some(client_ca) = &self.client_ca {
+            util::load_ca_certs(&mut client_ca.as_slice()).map_err(|e| anyhow::anyhow!("bad TLS client CA: {}", e))?;
+        }
+        tonic::transport::Server::builder().tls_config(self.grpc_config(false))
+            .map_err(|e| anyhow::anyhow!("bad TLS cert or key: {}", std::error::Error::source(&e).unwrap_or(&e)))?;
+        Ok(())
+    }
+
+    fn grpc_config(&self, client_cert_required: bool) -> ServerTlsConfig {
+        let mut config = ServerTlsConfig::new().identity(Identity::from_pem(&self.cert, &self.key));
+        if let Some(client_ca) = &self.client_ca {
+            config = config.client_ca_root(Certificate::from_pem(client_ca)).client_auth_optional(!client_cert_required);
+        }
+        config
+    }
+}
+
+struct Loaded{
+    pem: Arc<TlsPem>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+///
+/// HTTPS for all of our listeners: Rocket's port, and the OTLP/gRPC port if it's on. Neither of them can swap certs
+/// while they're up, so when the files change (they're checked every TLS_RELOAD_SECONDS), the new ones are read and
+/// checked over, and then every listener that's `subscribe`d is told to relaunch with them: whatever's connected gets
+/// the usual graceful shutdown, and for a few seconds new connections are turned away. The rest of the server doesn't notice.
+/// A cert that's broken (or half-copied) leaves everything on the old one.
+///
+pub struct Tls{
+    files: TlsFiles,
+    reload_seconds: u64,
+    loaded: Mutex<Loaded>,
+    changes: watch::Sender<u64>,
+}
+
+impl Tls{
+    ///
+    /// None, if there's no cert configured: the first load has to work, because we'd rather not start than quietly serve plain HTTP
+    ///
+    pub fn from_config(config: &Config) -> Result<Option<Tls>> {
+        let (Some(cert_path), Some(key_path)) = (&config.tls_cert_path, &config.tls_key_path) else {
+            return Ok(None);
+        };
+        let files = TlsFiles{
+            cert_path: cert_path.clone(),
+            key_path: key_path.clone(),
+            client_ca_path: config.tls_client_ca_path.clone(),
+            client_cert_required: config.tls_client_cert_required,
+        };
+        Ok(Some(Self::load(files, config.tls_reload_seconds)?))
+    }
+
+    pub fn load(files: TlsFiles, reload_seconds: u64) -> Result<Tls> {
+        let modified = files.modified();
+        let pem = files.read()?;
+        Ok(Tls{
+            files,
+            reload_seconds,
+            loaded: Mutex::new(Loaded{ pem: Arc::new(pem), modified }),
+            changes: watch::Sender::new(1),
+        })
+    }
+
+    fn current(&self) -> Arc<TlsPem> {
+        self.loaded.lock().unwrap().pem.clone()
+    }
+
+    ///
+    /// How many certs we've had: 1 is the one we booted with
+    ///
+    pub fn version(&self) -> u64 {
+        *self.changes.borrow()
+    }
+
+    ///
+    /// Hears about every new cert from here on: subscribe before building a listener, and relaunch it when this changes
+    ///
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    ///
+    /// Rocket's config, with the current cert in it
+    ///
+    pub fn rocket_figment(&self, figment: Figment) -> Figment {
+        let pem = self.current();
+        let mut tls = rocket::config::TlsConfig::from_bytes(&pem.cert, &pem.key);
+        if let Some(client_ca) = &pem.client_ca {
+            tls = tls.with_mutual(rocket::config::MutualTls::from_bytes(client_ca).mandatory(self.files.client_cert_required));
+        }
+        figment.merge(("tls", tls))
+    }
+
+    ///
+    /// tonic's config, with the current cert in it
+    ///
+    pub fn grpc_config(&self) -> ServerTlsConfig {
+        self.current().grpc_config(self.files.client_cert_required)
+    }
+
+    ///
+    /// Reload if any of the files have changed since we last looked, and tell the listeners if that got us a different cert
+    ///
+    pub fn reload_if_changed(&self) -> bool {
+        let modified = self.files.modified();
+        // one of them is missing: probably halfway through being replaced
+        if modified.iter().any(|modified| modified.is_none()) || modified == self.loaded.lock().unwrap().modified {
+            return false;
+        }
+        // read outside the lock: listeners keep using the old cert in the meantime
+        let result = self.files.read();
+        let mut loaded = self.loaded.lock().unwrap();
+        loaded.modified = modified;
+        match result{
+            Ok(pem) if pem == *loaded.pem => false,
+            Ok(pem) => {
+                loaded.pem = Arc::new(pem);
+                drop(loaded);
+                self.changes.send_modify(|version| *version += 1);
+                tracing::info!("Reloaded the TLS cert from {} (version {}): relaunching the listeners with it", self.files.cert_path, self.version());
+                true
+            },
+            Err(e) => {
+                tracing::error!("Error reloading the TLS cert from {}, keeping the one we've got: {}", self.files.cert_path, e);
+                false
+            }
+        }
+    }
+
+    pub fn reload_loop(&self) {
+        loop {
+            std::thread::sleep(std::time::Duration::from_secs(self.reload_seconds));
+            self.reload_if_changed();
+        }
+    }
+}
+
+#[test]
+fn test_tls_reload() -> Result<()> {
+    let directory = crate::minute::test_data_directory("tls_reload");
+    fs::create_dir_all(&directory)?;
+    let files = TlsFiles{
+        cert_path: format!("{}/cert.pem", directory),
+        key_path: format!("{}/key.pem", directory),
+        client_ca_path: None,
+        client_cert_required: false,
+    };
+    let write_cert = |name: &str| -> Result<()> {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()])?;
+        fs::write(&files.cert_path, cert.cert.pem())?;
+        fs::write(&files.key_path, cert.key_pair.serialize_pem())?;
+        Ok(())
+    };
+    write_cert("logs.example.com")?;
+
+    let tls = Tls::load(files.clone(), 60)?;
+    assert_eq!(tls.version(), 1);
+    let rocket_config = rocket::Config::from(tls.rocket_figment(rocket::Config::figment()));
+    assert!(rocket_config.tls_enabled());
+    assert!(!rocket_config.mtls_enabled());
+    let changes = tls.subscribe();
+    assert!(!tls.reload_if_changed());
+
+    // a key that doesn't go with the cert is no good, and the old cert stays
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    let other = rcgen::KeyPair::generate()?;
+    fs::write(&files.key_path, other.serialize_pem())?;
+    assert!(files.read().is_err());
+    assert!(!tls.reload_if_changed());
+    assert_eq!(tls.version(), 1);
+    assert!(!changes.has_changed()?);
+    fs::write(&files.cert_path, "not a cert")?;
+    assert!(!tls.reload_if_changed());
+
+    // a renewed cert: the listeners hear about it
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    write_cert("logs.example.com")?;
+    assert!(tls.reload_if_changed());
+    assert_eq!(tls.version(), 2);
+    assert!(changes.has_changed()?);
+    // touching the files without changing them doesn't relaunch anything
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    fs::write(&files.cert_path, fs::read(&files.cert_path)?)?;
+    assert!(!tls.reload_if_changed());
+    assert_eq!(tls.version(), 2);
+
+    // client certs signed by a CA we trust
+    let ca = rcgen::generate_simple_self_signed(vec!["logmunch clients".to_string()])?;
+    let client_ca_path = format!("{}/client_ca.pem", directory);
+    fs::write(&client_ca_path, ca.cert.pem())?;
+    let mutual = Tls::load(TlsFiles{ client_ca_path: Some(client_ca_path), client_cert_required: true, ..files.clone() }, 60)?;
+    assert!(rocket::Config::from(mutual.rocket_figment(rocket::Config::figment())).mtls_enabled());
+
+    assert!(Tls::load(TlsFiles{ cert_path: format!("{}/missing.pem", directory), ..files }, 60).is_err());
+    assert!(Tls::from_config(&Config::default())?.is_none());
+    fs::remove_dir_all(&directory)?;
+    Ok(())
+}
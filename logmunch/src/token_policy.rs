@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fs;
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::auth::ApiToken;
+use crate::minute_db::SearchOptions;
+
+///
+/// Defaults and ceilings for searches made with a particular token.
+/// (so that, say, the token baked into a dashboard can't accidentally launch a scan over the whole retention window)
+/// All ranges are in seconds.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct TokenPolicy{
+    pub default_range_seconds: Option<i64>,
+    pub max_range_seconds: Option<i64>,
+    pub default_limit: Option<usize>,
+    pub max_limit: Option<usize>,
+    /// past this many minutes, the search stops and returns what it has, with a hint to narrow things down
+    pub max_minutes_scanned: Option<usize>,
+}
+
+impl TokenPolicy{
+    ///
+    /// Turn the caller's from/to/limit into SearchOptions, filling in this policy's defaults,
+    /// or explain (in words a human can act on) why we won't run the search.
+    ///
+    pub fn admit(&self, from: Option<i64>, to: Option<i64>, limit: Option<usize>, now: i64) -> Result<SearchOptions> {
+        let default_options = SearchOptions::default();
+
+        let limit = limit.or(self.default_limit).unwrap_or(default_options.limit);
+        if let Some(max_limit) = self.max_limit {
+            if limit > max_limit {
+                return Err(anyhow::anyhow!("limit of {} exceeds this token's maximum of {}: ask for fewer results", limit, max_limit));
+            }
+        }
+
+        let mut from = from;
+        if from.is_none() {
+            // no explicit start: fall back to the default window (or, failing that, the biggest window we allow)
+            if let Some(range) = self.default_range_seconds.or(self.max_range_seconds) {
+                from = Some(to.unwrap_or(now) - range);
+            }
+        }
+
+        if let (Some(from), Some(to)) = (from, to) {
+            if from > to {
+                return Err(anyhow::anyhow!("from ({}) is after to ({})", from, to));
+            }
+        }
+
+        if let Some(max_range) = self.max_range_seconds {
+            let range = to.unwrap_or(now) - from.unwrap_or(0);
+            if range > max_range {
+                return Err(anyhow::anyhow!("time range of {}s exceeds this token's maximum of {}s: narrow your from/to", range, max_range));
+            }
+        }
+
+        Ok(SearchOptions{
+            from,
+            to,
+            limit,
+            max_minutes_scanned: self.max_minutes_scanned,
+            ..default_options
+        })
+    }
+
+    ///
+    /// Can this token see a log from the minute starting at `minute_start`? Only if a search with no from/to would have
+    /// covered that minute: a permalink shouldn't get a token past the window its searches are held to.
+    ///
+    pub fn admit_minute(&self, minute_start: i64, now: i64) -> Result<()> {
+        let options = self.admit(None, None, None, now)?;
+        if let Some(from) = options.from {
+            if minute_start + 60 <= from {
+                return Err(anyhow::anyhow!("that log is from {}s ago, and this token can only see the last {}s", now - minute_start, now - from));
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// Every token's policy. Tokens we don't have a policy for get the default policy.
+///
+/// TOKEN_POLICIES points at a JSON file that looks like:
+/// {
+///     "default": {"max_limit": 10000},
+///     "tokens": {
+///         "ui-token": {"default_range_seconds": 3600, "max_range_seconds": 86400, "default_limit": 100, "max_minutes_scanned": 600}
+///     }
+/// }
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct TokenPolicies{
+    #[serde(default)]
+    pub default: TokenPolicy,
+    #[serde(default)]
+    pub tokens: HashMap<String, TokenPolicy>,
+}
+
+impl TokenPolicies{
+    pub fn from_config(config: &crate::config::Config) -> Result<TokenPolicies> {
+        match &config.token_policies{
+            Some(path) => Self::load(path),
+            None => Ok(TokenPolicies::default()),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<TokenPolicies> {
+        let contents = fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Could not read token policies from {}: {}", path, e))?;
+        let policies: TokenPolicies = serde_json::from_str(&contents).map_err(|e| anyhow::anyhow!("Could not parse token policies in {}: {}", path, e))?;
+        Ok(policies)
+    }
+
+    pub fn get(&self, token: &ApiToken) -> &TokenPolicy {
+        match &token.0{
+            Some(token) => self.tokens.get(token).unwrap_or(&self.default),
+            None => &self.default,
+        }
+    }
+}
+
+#[test]
+fn test_unrestricted_policy() {
+    let policy = TokenPolicy::default();
+    let options = policy.admit(None, None, None, 1000000).unwrap();
+    assert_eq!(options, SearchOptions::default());
+
+    let options = policy.admit(Some(5), Some(10), Some(50000), 1000000).unwrap();
+    assert_eq!(options.from, Some(5));
+    assert_eq!(options.to, Some(10));
+    assert_eq!(options.limit, 50000);
+
+    assert!(policy.admit(Some(10), Some(5), None, 1000000).is_err());
+}
+
+#[test]
+fn test_policy_defaults_and_ceilings() {
+    let policy = TokenPolicy{
+        default_range_seconds: Some(3600),
+        max_range_seconds: Some(86400),
+        default_limit: Some(100),
+        max_limit: Some(10000),
+        max_minutes_scanned: Some(60),
+    };
+    let now = 1000000;
+
+    let options = policy.admit(None, None, None, now).unwrap();
+    assert_eq!(options.from, Some(now - 3600));
+    assert_eq!(options.to, None);
+    assert_eq!(options.limit, 100);
+    assert_eq!(options.max_minutes_scanned, Some(60));
+
+    let options = policy.admit(None, Some(now - 100), Some(10000), now).unwrap();
+    assert_eq!(options.from, Some(now - 100 - 3600));
+    assert_eq!(options.limit, 10000);
+
+    let err = policy.admit(None, None, Some(10001), now).unwrap_err();
+    assert!(err.to_string().contains("maximum of 10000"));
+
+    let err = policy.admit(Some(now - 86401), None, None, now).unwrap_err();
+    assert!(err.to_string().contains("maximum of 86400s"));
+
+    assert!(policy.admit(Some(now - 86400), Some(now), None, now).is_ok());
+}
+
+#[test]
+fn test_admit_minute() {
+    let now = 1000020;
+    assert!(TokenPolicy::default().admit_minute(0, now).is_ok());
+
+    let policy = TokenPolicy{
+        default_range_seconds: Some(3600),
+        max_range_seconds: Some(86400),
+        ..TokenPolicy::default()
+    };
+    assert!(policy.admit_minute(now - 60, now).is_ok());
+    // the minute that straddles the start of the window still counts
+    assert!(policy.admit_minute(now - 3600 - 30, now).is_ok());
+    let err = policy.admit_minute(now - 3600 - 60, now).unwrap_err();
+    assert!(err.to_string().contains("last 3600s"));
+    assert!(policy.admit_minute(now - 86400, now).is_err());
+}
+
+#[test]
+fn test_policy_lookup() {
+    let policies: TokenPolicies = serde_json::from_str(r#"{
+        "default": {"max_limit": 500},
+        "tokens": {"ui-token": {"max_range_seconds": 86400}}
+    }"#).unwrap();
+
+    assert_eq!(policies.get(&ApiToken(None)).max_limit, Some(500));
+    assert_eq!(policies.get(&ApiToken(Some("someone-else".to_string()))).max_limit, Some(500));
+    assert_eq!(policies.get(&ApiToken(Some("ui-token".to_string()))).max_range_seconds, Some(86400));
+    assert_eq!(policies.get(&ApiToken(Some("ui-token".to_string()))).max_limit, None);
+}
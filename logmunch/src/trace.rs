@@ -0,0 +1,103 @@
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use rocket::request::{FromRequest, Outcome, Request};
+
+///
+/// A W3C trace context (the `traceparent` header: `00-<trace id>-<parent span id>-<flags>`).
+/// If a caller (or a federated peer) sends us one, we keep their trace id so our work shows up in their trace;
+/// otherwise we start a new trace. Either way, our work gets its own span.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext{
+    pub trace_id: String,
+    pub parent_span_id: Option<String>,
+    pub span_id: String,
+    pub flags: String,
+}
+
+static SPAN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn random_hex() -> String {
+    // this doesn't need to be cryptographically random, it just needs to not collide
+    let mut hasher = fxhash::FxHasher64::default();
+    hasher.write_u128(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos());
+    hasher.write_u64(SPAN_COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u32(std::process::id());
+    format!("{:016x}", hasher.finish())
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+impl Default for TraceContext{
+    fn default() -> TraceContext {
+        TraceContext::new()
+    }
+}
+
+impl TraceContext{
+    pub fn new() -> TraceContext {
+        TraceContext{
+            trace_id: format!("{}{}", random_hex(), random_hex()),
+            parent_span_id: None,
+            span_id: random_hex(),
+            flags: "01".to_string(),
+        }
+    }
+
+    ///
+    /// Continue the trace described by a `traceparent` header, or None if it's not a valid one.
+    ///
+    pub fn from_traceparent(header: &str) -> Option<TraceContext> {
+        let parts = header.trim().split('-').collect::<Vec<&str>>();
+        if parts.len() != 4 || !is_hex(parts[0], 2) || !is_hex(parts[1], 32) || !is_hex(parts[2], 16) || !is_hex(parts[3], 2) {
+            return None;
+        }
+        if parts[1].chars().all(|c| c == '0') || parts[2].chars().all(|c| c == '0') {
+            return None;
+        }
+        Some(TraceContext{
+            trace_id: parts[1].to_lowercase(),
+            parent_span_id: Some(parts[2].to_lowercase()),
+            span_id: random_hex(),
+            flags: parts[3].to_string(),
+        })
+    }
+
+    ///
+    /// The header to send along with requests we make on behalf of this one (or to send back to the caller)
+    ///
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, self.flags)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TraceContext {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let trace = request.headers().get_one("traceparent").and_then(TraceContext::from_traceparent);
+        Outcome::Success(trace.unwrap_or_else(TraceContext::new))
+    }
+}
+
+#[test]
+fn test_traceparent() {
+    let trace = TraceContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+    assert_eq!(trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(trace.parent_span_id, Some("00f067aa0ba902b7".to_string()));
+    assert_ne!(trace.span_id, "00f067aa0ba902b7");
+    assert!(trace.traceparent().starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+    assert!(trace.traceparent().ends_with("-01"));
+
+    assert_eq!(TraceContext::from_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"), None);
+    assert_eq!(TraceContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-01"), None);
+    assert_eq!(TraceContext::from_traceparent("garbage"), None);
+
+    let trace = TraceContext::new();
+    assert_eq!(TraceContext::from_traceparent(&trace.traceparent()).unwrap().trace_id, trace.trace_id);
+    assert_ne!(TraceContext::new().span_id, TraceContext::new().span_id);
+}
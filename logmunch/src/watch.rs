@@ -0,0 +1,222 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use anyhow::Result;
+
+use crate::minute_id::MinuteId;
+
+///
+/// Tells the read loop which minutes just changed on disk, so that a minute is searchable as soon as the writer's done sealing it,
+/// instead of whenever the next scan of the whole data directory gets around to it (see MinuteDB::read_loop).
+/// It's inotify, on the data directory and every day and hour directory in it: anywhere else, `start` is None and the read loop polls.
+///
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct MinuteWatcher{
+    inotify: std::fs::File,
+    /// watch descriptor -> the directory it's watching, relative to the data directory ("", then "{day}", then "{day}/{hour}")
+    directories: HashMap<i32, String>,
+    data_directory: String,
+}
+
+///
+/// How deep `relative` is in the data directory: 0 for the data directory itself, 2 for an hour directory
+///
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn depth(relative: &str) -> usize {
+    relative.split('/').filter(|part| !part.is_empty()).count()
+}
+
+#[cfg(target_os = "linux")]
+impl MinuteWatcher{
+    ///
+    /// Watch `data_directory` (it has to exist already): None if we can't (not enough inotify watches, say), and then the read loop polls
+    ///
+    pub fn start(data_directory: &str) -> Option<MinuteWatcher> {
+        use std::os::fd::FromRawFd;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            tracing::warn!("Can't watch {} for new minutes: {}", data_directory, std::io::Error::last_os_error());
+            return None;
+        }
+        let mut watcher = MinuteWatcher{
+            // the File owns the descriptor from here on, and closes it when we're dropped
+            inotify: unsafe { std::fs::File::from_raw_fd(fd) },
+            directories: HashMap::new(),
+            data_directory: data_directory.to_string(),
+        };
+        match watcher.watch_tree(""){
+            Ok(_) => Some(watcher),
+            Err(e) => {
+                tracing::warn!("Can't watch {} for new minutes: {}", data_directory, e);
+                None
+            }
+        }
+    }
+
+    ///
+    /// Wait up to `timeout` for minutes to change (show up, get sealed, go away), and say which ones did.
+    /// An error means we've lost track (inotify dropped events, or a directory vanished out from under us): time for a full scan.
+    ///
+    pub fn wait(&mut self, timeout: Duration) -> Result<Vec<MinuteId>> {
+        use std::io::Read;
+        use std::os::fd::AsRawFd;
+
+        let mut poll = libc::pollfd{ fd: self.inotify.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let ready = unsafe { libc::poll(&mut poll, 1, timeout.as_millis().min(i32::MAX as u128) as i32) };
+        if ready < 0 {
+            let error = std::io::Error::last_os_error();
+            return match error.kind(){
+                std::io::ErrorKind::Interrupted => Ok(Vec::new()),
+                _ => Err(error.into()),
+            };
+        }
+        if ready == 0 {
+            return Ok(Vec::new());
+        }
+        // sealing a minute is a burst of events (the minute, its WAL going away, ...): let the rest of it arrive
+        std::thread::sleep(Duration::from_millis(20));
+
+        let mut changed = BTreeSet::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop{
+            match self.inotify.read(&mut buffer){
+                Ok(n) => self.read_events(&buffer[..n], &mut changed)?,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(changed.into_iter().collect())
+    }
+
+    ///
+    /// `bytes` is a run of inotify_events: a 16 byte header, then `len` bytes of NUL-padded name
+    ///
+    fn read_events(&mut self, bytes: &[u8], changed: &mut BTreeSet<MinuteId>) -> Result<()> {
+        let mut offset = 0;
+        while offset + 16 <= bytes.len() {
+            let field = |at: usize| u32::from_ne_bytes(bytes[offset + at..offset + at + 4].try_into().unwrap());
+            let (wd, mask, len) = (field(0) as i32, field(4), field(12) as usize);
+            let name = bytes.get(offset + 16..offset + 16 + len).ok_or_else(|| anyhow::anyhow!("inotify cut an event short"))?;
+            let name = std::str::from_utf8(name)?.trim_end_matches('\0');
+            offset += 16 + len;
+            self.event(wd, mask, name, changed)?;
+        }
+        Ok(())
+    }
+
+    fn event(&mut self, wd: i32, mask: u32, name: &str, changed: &mut BTreeSet<MinuteId>) -> Result<()> {
+        if mask & libc::IN_Q_OVERFLOW != 0 {
+            return Err(anyhow::anyhow!("inotify's queue overflowed: some changes got lost"));
+        }
+        if mask & libc::IN_IGNORED != 0 {
+            // the directory's gone (retention emptied it, probably)
+            self.directories.remove(&wd);
+            return Ok(());
+        }
+        let Some(directory) = self.directories.get(&wd) else {
+            return Ok(());
+        };
+        let relative = match directory.is_empty(){
+            true => name.to_string(),
+            false => format!("{}/{}", directory, name),
+        };
+        if mask & libc::IN_ISDIR != 0 {
+            if depth(&relative) <= 2 {
+                changed.extend(self.watch_tree(&relative)?);
+            }
+            return Ok(());
+        }
+        changed.extend(crate::catalog::FileInfo::minute_id_of(&relative));
+        Ok(())
+    }
+
+    ///
+    /// Watch `relative` and every day or hour directory under it. Whatever minutes are already in there come back:
+    /// they could have shown up before the watch did.
+    ///
+    fn watch_tree(&mut self, relative: &str) -> Result<Vec<MinuteId>> {
+        use std::os::fd::AsRawFd;
+
+        let path = std::path::Path::new(&self.data_directory).join(relative);
+        let mask = match depth(relative){
+            // days and hours: new directories are all we care about
+            0 | 1 => libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_ONLYDIR,
+            // minutes: done writing (sealed, or copied in), moved in or out, deleted
+            _ => libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_MOVED_FROM | libc::IN_DELETE | libc::IN_ONLYDIR,
+        };
+        let c_path = std::ffi::CString::new(path.to_str().ok_or_else(|| anyhow::anyhow!("{} isn't UTF-8", path.display()))?)?;
+        let wd = unsafe { libc::inotify_add_watch(self.inotify.as_raw_fd(), c_path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(anyhow::anyhow!("can't watch {}: {}", path.display(), std::io::Error::last_os_error()));
+        }
+        self.directories.insert(wd, relative.to_string());
+
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(&path)? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            let child = match relative.is_empty(){
+                true => name,
+                false => format!("{}/{}", relative, name),
+            };
+            if entry.file_type()?.is_dir() {
+                if depth(&child) <= 2 {
+                    found.extend(self.watch_tree(&child)?);
+                }
+            }
+            else {
+                found.extend(crate::catalog::FileInfo::minute_id_of(&child));
+            }
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl MinuteWatcher{
+    pub fn start(_data_directory: &str) -> Option<MinuteWatcher> {
+        None
+    }
+
+    pub fn wait(&mut self, timeout: Duration) -> Result<Vec<MinuteId>> {
+        std::thread::sleep(timeout);
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_minute_watcher() -> Result<()> {
+    let data_directory = crate::minute::test_data_directory("minute_watcher");
+    std::fs::create_dir_all(format!("{}/20000/13", data_directory))?;
+    let mut watcher = MinuteWatcher::start(&data_directory).unwrap();
+    let wait_for = |watcher: &mut MinuteWatcher| -> Result<Vec<MinuteId>> {
+        for _ in 0..50 {
+            let changed = watcher.wait(Duration::from_millis(100))?;
+            if !changed.is_empty() {
+                return Ok(changed);
+            }
+        }
+        Ok(Vec::new())
+    };
+
+    // an hour directory that was already there
+    let mut minute = crate::minute::Minute::new(20000, 13, 59, "1-0", &data_directory, true)?;
+    minute.seal()?;
+    drop(minute);
+    assert_eq!(wait_for(&mut watcher)?, vec![MinuteId::new(20000, 13, 59, "1-0")]);
+
+    // a brand new day and hour
+    drop(crate::minute::Minute::new(20001, 0, 1, "1-0", &data_directory, true)?);
+    assert_eq!(wait_for(&mut watcher)?, vec![MinuteId::new(20001, 0, 1, "1-0")]);
+
+    // swap files aren't minutes; deleting one that is tells us too
+    std::fs::write(format!("{}/20000/13/58-1-0.db.swp", data_directory), "half a minute")?;
+    std::fs::remove_file(format!("{}/20000/13/59-1-0.db", data_directory))?;
+    assert_eq!(wait_for(&mut watcher)?, vec![MinuteId::new(20000, 13, 59, "1-0")]);
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}
@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+
+///
+/// How many minutes of write stats we keep around for the report
+///
+const RECENT_MINUTES: usize = 60;
+
+///
+/// What it cost to write some logs: what we were sent, what went into each table, and what SQLite actually put on disk.
+/// A Minute keeps one of these for itself (see Minute::write_stats), and the writer adds them up, per minute.
+///
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WriteStats{
+    pub events: u64,
+    pub commits: u64,
+    /// what we were sent: messages, hosts and timestamps
+    pub raw_bytes: u64,
+    /// rows in the log table: compressed messages, hosts, timestamps and ids
+    pub log_bytes: u64,
+    /// rows in the occurrence table (dedup mode only)
+    pub occurrence_bytes: u64,
+    /// rows in the search_fragments table: every trigram and word, once per batch
+    pub fragment_bytes: u64,
+    /// pages SQLite wrote to the WAL while we were writing
+    pub wal_bytes: u64,
+    /// pages SQLite wrote while sealing: the indexes, the bloom filter, and the VACUUM that rewrites the whole file
+    pub seal_bytes: u64,
+    /// how much of the sealed minutes is indexes
+    pub index_bytes: u64,
+    /// how big the sealed minutes are: the WAL gets checkpointed into them one last time, so that's written too
+    pub sealed_bytes: u64,
+    pub sealed_minutes: u64,
+}
+
+impl WriteStats{
+    pub fn add(&mut self, other: &WriteStats) {
+        self.events += other.events;
+        self.commits += other.commits;
+        self.raw_bytes += other.raw_bytes;
+        self.log_bytes += other.log_bytes;
+        self.occurrence_bytes += other.occurrence_bytes;
+        self.fragment_bytes += other.fragment_bytes;
+        self.wal_bytes += other.wal_bytes;
+        self.seal_bytes += other.seal_bytes;
+        self.index_bytes += other.index_bytes;
+        self.sealed_bytes += other.sealed_bytes;
+        self.sealed_minutes += other.sealed_minutes;
+    }
+
+    ///
+    /// Every byte that hit the disk, per byte we were sent
+    ///
+    pub fn amplification(&self) -> Option<f64> {
+        match self.raw_bytes{
+            0 => None,
+            raw_bytes => Some((self.wal_bytes + self.seal_bytes + self.sealed_bytes) as f64 / raw_bytes as f64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MinuteWriteStats{
+    /// the start of the minute, in seconds since the epoch
+    pub time: i64,
+    pub amplification: Option<f64>,
+    #[serde(flatten)]
+    pub stats: WriteStats,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteAmplificationReport{
+    pub amplification: Option<f64>,
+    /// everything written since we started
+    pub total: WriteStats,
+    /// the last hour or so, oldest first: the current minute isn't sealed yet, so it looks cheaper than it is
+    pub minutes: Vec<MinuteWriteStats>,
+    pub recommendations: Vec<String>,
+}
+
+///
+/// Write stats for one tenant, since we started and for each of the last RECENT_MINUTES minutes.
+/// The writer records into this, the admin API reads out of it.
+///
+#[derive(Debug, Default)]
+pub struct WriteStatsRecorder{
+    total: Mutex<WriteStats>,
+    minutes: Mutex<BTreeMap<i64, WriteStats>>,
+}
+
+impl WriteStatsRecorder{
+    pub fn new() -> WriteStatsRecorder {
+        WriteStatsRecorder::default()
+    }
+
+    ///
+    /// `time` is the start of the minute the stats belong to, in seconds since the epoch
+    ///
+    pub fn record(&self, time: i64, stats: &WriteStats) {
+        self.total.lock().unwrap().add(stats);
+        let mut minutes = self.minutes.lock().unwrap();
+        minutes.entry(time).or_default().add(stats);
+        while minutes.len() > RECENT_MINUTES {
+            minutes.pop_first();
+        }
+    }
+
+    pub fn total(&self) -> WriteStats {
+        self.total.lock().unwrap().clone()
+    }
+
+    pub fn report(&self, config: &crate::config::Config) -> WriteAmplificationReport {
+        let total = self.total();
+        let minutes = self.minutes.lock().unwrap().iter().map(|(time, stats)| MinuteWriteStats{
+            time: *time,
+            amplification: stats.amplification(),
+            stats: stats.clone(),
+        }).collect();
+        WriteAmplificationReport{
+            amplification: total.amplification(),
+            recommendations: recommendations(&total, config),
+            total,
+            minutes,
+        }
+    }
+}
+
+///
+/// What we'd change, going by where the bytes went. Only things you can actually change are in here:
+/// the trigrams and the VACUUM are the price of fast searches and small minutes.
+///
+pub fn recommendations(stats: &WriteStats, config: &crate::config::Config) -> Vec<String> {
+    let mut recommendations = Vec::new();
+    if stats.raw_bytes == 0 {
+        return recommendations;
+    }
+    let ratio = |bytes: u64| bytes as f64 / stats.raw_bytes as f64;
+
+    if stats.commits > 0 && stats.wal_bytes > 4 * stats.raw_bytes && config.max_write_threads > 1 {
+        recommendations.push(format!(
+            "Each commit writes {:.1}x what it was sent, at {} events per commit: small commits rewrite the same pages over and over. \
+            Fewer write threads (MAX_WRITE_THREADS, now {}) makes for bigger commits.",
+            ratio(stats.wal_bytes), stats.events / stats.commits, config.max_write_threads));
+    }
+    if stats.fragment_bytes > 2 * stats.log_bytes {
+        recommendations.push(format!(
+            "Search fragments are {:.1}x the size of the logs themselves. That's usually long unique tokens (ids, hashes, base64): \
+            dropping those before they're sent helps more than anything we can do here.",
+            stats.fragment_bytes as f64 / stats.log_bytes.max(1) as f64));
+    }
+    if stats.log_bytes as f64 > 0.9 * stats.raw_bytes as f64 {
+        let zstd = match config.log_compression.as_str(){
+            "lz4" => " If they're short, LOG_COMPRESSION=zstd compresses each sealed minute's logs with a dictionary trained on all of them.",
+            _ => "",
+        };
+        recommendations.push(format!(
+            "lz4 is only getting the logs down to {:.0}% of what was sent: they're short, or already compressed.{}",
+            100.0 * ratio(stats.log_bytes), zstd));
+    }
+    if !config.dedup_messages && stats.log_bytes > 0 && stats.sealed_bytes > 3 * stats.raw_bytes {
+        recommendations.push(
+            "Sealed minutes are more than 3x what was sent: if the same messages show up over and over, DEDUP_MESSAGES=true stores each one once per batch.".to_string());
+    }
+    if config.dedup_messages && stats.events > 0 && stats.occurrence_bytes == 0 {
+        recommendations.push(
+            "DEDUP_MESSAGES is on, but no message has shown up twice in a batch: turning it off saves the grouping, and writes exactly the same bytes.".to_string());
+    }
+    recommendations
+}
+
+#[test]
+fn test_write_stats() -> anyhow::Result<()> {
+    use crate::minute::Minute;
+
+    let data_directory = crate::minute::test_data_directory("write_stats");
+    let mut minute = Minute::new(1, 2, 3, "1-0", &data_directory, true)?;
+    let mut test_data = crate::minute::TestData::new();
+    let events: Vec<crate::WritableEvent> = (0..1000).map(|_| crate::minute::generate_test_data(&mut test_data)).collect();
+    let raw_bytes: usize = events.iter().map(|event| event.get_size_in_bytes()).sum();
+    minute.write_second(events)?;
+
+    let written = minute.write_stats().clone();
+    assert_eq!(written.events, 1000);
+    assert_eq!(written.commits, 1);
+    assert_eq!(written.raw_bytes, raw_bytes as u64);
+    assert!(written.log_bytes > 0 && written.fragment_bytes > 0);
+    assert!(written.wal_bytes > 0);
+    assert_eq!(written.seal_bytes, 0);
+
+    minute.seal()?;
+    let sealed = minute.write_stats().clone();
+    assert_eq!(sealed.sealed_minutes, 1);
+    assert!(sealed.seal_bytes > 0);
+    assert!(sealed.index_bytes > 0 && sealed.index_bytes < sealed.sealed_bytes);
+    assert!(sealed.amplification().unwrap() > 1.0);
+
+    let recorder = WriteStatsRecorder::new();
+    recorder.record(180, &sealed);
+    recorder.record(180, &sealed);
+    for time in 0..RECENT_MINUTES as i64 {
+        recorder.record(240 + time * 60, &WriteStats::default());
+    }
+    assert_eq!(recorder.total().events, 2000);
+    let report = recorder.report(&crate::config::Config::default());
+    assert_eq!(report.minutes.len(), RECENT_MINUTES);
+    assert_eq!(report.minutes[0].time, 240);
+    assert_eq!(report.amplification, sealed.amplification());
+
+    // all the bytes went into fragments
+    let fragments = WriteStats{ events: 10, commits: 1, raw_bytes: 1000, log_bytes: 500, fragment_bytes: 5000, ..WriteStats::default() };
+    let recommendations = recommendations(&fragments, &crate::config::Config::default());
+    assert_eq!(recommendations.len(), 1);
+    assert!(recommendations[0].starts_with("Search fragments are 10.0x"));
+
+    std::fs::remove_dir_all(&data_directory)?;
+    Ok(())
+}